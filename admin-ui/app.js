@@ -142,7 +142,10 @@ document.querySelector('.content').addEventListener('click', (e) => {
             });
             break;
         case 'delete-transfer':
-            api('DELETE', '/admin/transfers/' + id).then(fetchTransfers);
+            showConfirm('Purge this transfer, and any other upload of the same file? This cannot be undone.', async () => {
+                await api('DELETE', '/admin/transfers/' + id);
+                fetchTransfers();
+            });
             break;
         case 'clear-offer':
             api('DELETE', '/admin/offers/' + id).then(fetchOffers);
@@ -370,7 +373,7 @@ function renderTransfers(transfers) {
         container.innerHTML = '<p class="empty-state">No transfers</p>';
         return;
     }
-    let html = '<table><thead><tr><th>ID</th><th>Uploader</th><th>Size</th><th>Received</th><th>Status</th><th>Created</th><th>Actions</th></tr></thead><tbody>';
+    let html = '<table><thead><tr><th>ID</th><th>Uploader</th><th>Size</th><th>Received</th><th>Status</th><th>Created</th><th>Expires</th><th>Actions</th></tr></thead><tbody>';
     transfers.forEach(t => {
         html += '<tr>'
             + '<td title="' + escapeAttr(t.id) + '">' + escapeHtml(truncateId(t.id)) + '</td>'
@@ -379,6 +382,7 @@ function renderTransfers(transfers) {
             + '<td>' + formatBytes(t.bytes_received) + '</td>'
             + '<td>' + escapeHtml(t.status) + '</td>'
             + '<td>' + escapeHtml(t.created_at) + '</td>'
+            + '<td>' + escapeHtml(t.expires_at || '') + '</td>'
             + '<td><button class="danger small" data-action="delete-transfer" data-id="' + escapeAttr(t.id) + '">Delete</button></td>'
             + '</tr>';
     });
@@ -3,8 +3,8 @@ use std::time::{Duration, Instant};
 
 use axum::{
     Json,
-    extract::{Path, Query, State, WebSocketUpgrade, ws::{Message, WebSocket}},
-    http::{StatusCode, header, Request},
+    extract::{Path, Query, RawQuery, State, WebSocketUpgrade, ws::{Message, WebSocket}},
+    http::{HeaderMap, StatusCode, header, Request},
     middleware::Next,
     response::{IntoResponse, Response},
 };
@@ -15,17 +15,11 @@ use uuid::Uuid;
 
 use crate::middleware::JwtSecret;
 use haven_gateway::dispatcher::Dispatcher;
+use haven_types::api::AdminClaims;
+use haven_types::jwt::TokenScope;
 
 // ── Types ────────────────────────────────────────────────────────────────
 
-/// JWT claims for admin sessions. Distinct from user Claims -- admin tokens
-/// carry `admin: true` and no user identity.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct AdminClaims {
-    pub admin: bool,
-    pub exp: usize,
-}
-
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct AdminLoginRequest {
@@ -42,6 +36,7 @@ pub struct AdminLoginResponse {
 pub struct AdminState {
     pub admin_secret: String,
     pub jwt_secret: String,
+    pub token_scope: TokenScope,
     pub db: Arc<haven_db::Database>,
     pub dispatcher: Dispatcher,
     pub http_client: reqwest::Client,
@@ -70,7 +65,8 @@ pub struct AdminWsQuery {
 
 /// POST /admin/login
 /// Validates the provided secret against HAVEN_ADMIN_SECRET env var.
-/// Returns a JWT with `admin: true` claim, 8-hour expiry.
+/// Returns a JWT with `admin: true` claim, 8-hour expiry, stamped with the
+/// configured issuer/audience like user tokens.
 pub async fn admin_login(
     State(state): State<AdminState>,
     Json(req): Json<AdminLoginRequest>,
@@ -79,10 +75,13 @@ pub async fn admin_login(
         return Err(StatusCode::UNAUTHORIZED);
     }
 
-    let claims = AdminClaims {
+    let mut claims = AdminClaims {
         admin: true,
         exp: (chrono::Utc::now() + chrono::Duration::hours(8)).timestamp() as usize,
+        iss: None,
+        aud: None,
     };
+    state.token_scope.apply_admin(&mut claims);
 
     let token = jsonwebtoken::encode(
         &jsonwebtoken::Header::default(),
@@ -95,7 +94,8 @@ pub async fn admin_login(
     Ok(Json(AdminLoginResponse { token }))
 }
 
-/// Middleware: validates JWT has `admin: true` claim.
+/// Middleware: validates JWT has `admin: true` claim and passes the
+/// `TokenScope` extension's issuer/audience checks.
 pub async fn require_admin(req: Request<axum::body::Body>, next: Next) -> Result<Response, StatusCode> {
     let auth_header = req
         .headers()
@@ -112,15 +112,16 @@ pub async fn require_admin(req: Request<axum::body::Body>, next: Next) -> Result
         .get::<JwtSecret>()
         .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?
         .clone();
+    let scope = req
+        .extensions()
+        .get::<TokenScope>()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let token_data = jsonwebtoken::decode::<AdminClaims>(
-        token,
-        &jsonwebtoken::DecodingKey::from_secret(secret.0.as_bytes()),
-        &jsonwebtoken::Validation::default(),
-    )
-    .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let claims = scope
+        .decode_admin(token, &secret.0)
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
 
-    if !token_data.claims.admin {
+    if !claims.admin {
         return Err(StatusCode::FORBIDDEN);
     }
 
@@ -228,6 +229,40 @@ pub async fn kick_user(
     Ok(StatusCode::NO_CONTENT)
 }
 
+// ── Connections ──────────────────────────────────────────────────────────
+
+/// GET /admin/connections
+pub async fn list_connections(State(state): State<AdminState>) -> Json<serde_json::Value> {
+    let connections: Vec<serde_json::Value> = state
+        .dispatcher
+        .list_connections()
+        .await
+        .into_iter()
+        .map(|(user_id, conn_id, connected_at)| {
+            serde_json::json!({
+                "user_id": user_id,
+                "conn_id": conn_id,
+                "connected_at": connected_at.to_rfc3339(),
+            })
+        })
+        .collect();
+
+    Json(serde_json::json!({ "connections": connections }))
+}
+
+/// DELETE /admin/connections/{conn_id}
+/// Closes one connection; the user's other devices stay connected.
+pub async fn kick_connection(
+    State(state): State<AdminState>,
+    Path(conn_id): Path<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    if !state.dispatcher.kick_connection(conn_id).await {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    info!("Admin kicked connection {}", conn_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
 // ── Voice ────────────────────────────────────────────────────────────────
 
 /// GET /admin/voice
@@ -246,6 +281,7 @@ pub async fn get_voice_state(State(state): State<AdminState>) -> Result<Json<ser
                         "session_id": p.session_id,
                         "self_mute": p.self_mute,
                         "self_deaf": p.self_deaf,
+                        "reconnecting": p.reconnecting,
                     })
                 })
                 .collect();
@@ -360,23 +396,34 @@ pub async fn delete_offer(
 
 // ── Transfers (proxied to file server) ───────────────────────────────────
 
-/// GET /admin/transfers
-pub async fn list_transfers(State(state): State<AdminState>) -> Result<Json<serde_json::Value>, StatusCode> {
+/// GET /admin/transfers?limit=&offset=
+///
+/// The file server checks the admin token itself, so it's passed along.
+pub async fn list_transfers(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Result<Json<serde_json::Value>, StatusCode> {
     let base = state
         .file_server_internal_url
         .as_deref()
         .unwrap_or("http://127.0.0.1:3211");
-    let url = format!("{}/transfers/all", base);
+    let url = match query {
+        Some(query) => format!("{}/admin/transfers?{}", base, query),
+        None => format!("{}/admin/transfers", base),
+    };
 
-    let resp = state
-        .http_client
-        .get(&url)
+    let resp = forward_auth(state.http_client.get(&url), &headers)
         .send()
         .await
         .map_err(|e| {
             warn!("Admin transfers proxy failed: {}", e);
             StatusCode::BAD_GATEWAY
         })?;
+    if !resp.status().is_success() {
+        warn!("Admin transfers proxy got {}", resp.status());
+        return Err(StatusCode::from_u16(resp.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY));
+    }
 
     let bytes = resp.bytes().await.map_err(|e| {
         warn!("Admin transfers proxy read failed: {}", e);
@@ -395,24 +442,43 @@ pub async fn list_transfers(State(state): State<AdminState>) -> Result<Json<serd
 pub async fn delete_transfer(
     State(state): State<AdminState>,
     Path(id): Path<Uuid>,
-) -> Result<StatusCode, StatusCode> {
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, StatusCode> {
     let base = state
         .file_server_internal_url
         .as_deref()
         .unwrap_or("http://127.0.0.1:3211");
     let url = format!("{}/admin/transfers/{}", base, id);
 
-    let resp = state
-        .http_client
-        .delete(&url)
+    let resp = forward_auth(state.http_client.delete(&url), &headers)
         .send()
         .await
         .map_err(|e| {
             warn!("Admin delete transfer proxy failed: {}", e);
             StatusCode::BAD_GATEWAY
         })?;
+    if !resp.status().is_success() {
+        return Err(StatusCode::from_u16(resp.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY));
+    }
+
+    // Which transfers went: the one asked for and any sharing its content
+    let bytes = resp.bytes().await.map_err(|e| {
+        warn!("Admin delete transfer proxy read failed: {}", e);
+        StatusCode::BAD_GATEWAY
+    })?;
+    let purged = serde_json::from_slice(&bytes).map_err(|e| {
+        warn!("Admin delete transfer proxy parse failed: {}", e);
+        StatusCode::BAD_GATEWAY
+    })?;
+    Ok(Json(purged))
+}
 
-    Ok(StatusCode::from_u16(resp.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY))
+/// Copy the caller's admin token onto a request to the file server.
+fn forward_auth(req: reqwest::RequestBuilder, headers: &HeaderMap) -> reqwest::RequestBuilder {
+    match headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+        Some(auth) => req.header(reqwest::header::AUTHORIZATION, auth),
+        None => req,
+    }
 }
 
 // ── Config ───────────────────────────────────────────────────────────────
@@ -505,14 +571,12 @@ pub async fn admin_ws(
 ) -> Result<impl IntoResponse, StatusCode> {
     let token = query.token.ok_or(StatusCode::UNAUTHORIZED)?;
 
-    let token_data = jsonwebtoken::decode::<AdminClaims>(
-        &token,
-        &jsonwebtoken::DecodingKey::from_secret(state.jwt_secret.as_bytes()),
-        &jsonwebtoken::Validation::default(),
-    )
-    .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let claims = state
+        .token_scope
+        .decode_admin(&token, &state.jwt_secret)
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
 
-    if !token_data.claims.admin {
+    if !claims.admin {
         return Err(StatusCode::FORBIDDEN);
     }
 
@@ -579,6 +643,7 @@ fn map_to_admin_event(json: &str) -> Option<String> {
                     "data": {
                         "user_id": data.get("user_id")?,
                         "username": data.get("username")?,
+                        "status": data.get("status"),
                     }
                 })
                 .to_string(),
@@ -623,3 +688,58 @@ fn map_to_admin_event(json: &str) -> Option<String> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, middleware, routing::get};
+
+    const SECRET: &str = "test-secret";
+
+    fn admin_state(dir: &std::path::Path, token_scope: TokenScope) -> AdminState {
+        AdminState {
+            admin_secret: "letmein".into(),
+            jwt_secret: SECRET.into(),
+            token_scope,
+            db: Arc::new(haven_db::Database::open(&dir.join("test.db")).unwrap()),
+            dispatcher: Dispatcher::new(),
+            http_client: reqwest::Client::new(),
+            file_server_internal_url: None,
+            start_time: Instant::now(),
+        }
+    }
+
+    async fn login(state: &AdminState) -> String {
+        let req = AdminLoginRequest { secret: "letmein".into() };
+        let response = admin_login(State(state.clone()), Json(req)).await.unwrap().into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()["token"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_admin_token_must_match_the_configured_scope() {
+        let dir = std::env::temp_dir().join(format!("haven-api-admin-scope-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let scope = TokenScope { issuer: Some("haven".into()), audience: Some("haven-chat".into()) };
+        let other_service = TokenScope { audience: Some("haven-files".into()), ..scope.clone() };
+
+        let app = Router::new()
+            .route("/admin/ping", get(|| async { "pong" }))
+            .layer(middleware::from_fn(require_admin))
+            .layer(axum::Extension(JwtSecret(Arc::from(SECRET))))
+            .layer(axum::Extension(scope.clone()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/admin/ping", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = reqwest::Client::new();
+        let status = |token: String| {
+            let request = client.get(&url).bearer_auth(token);
+            async move { request.send().await.unwrap().status() }
+        };
+        assert_eq!(status(login(&admin_state(&dir, scope)).await).await, StatusCode::OK);
+        assert_eq!(status(login(&admin_state(&dir, other_service)).await).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(login(&admin_state(&dir, TokenScope::default())).await).await, StatusCode::UNAUTHORIZED);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -16,6 +16,8 @@ use haven_gateway::dispatcher::Dispatcher;
 use haven_types::api::{LoginRequest, LoginResponse, RegisterRequest, RegisterResponse};
 
 use crate::middleware::Claims;
+use crate::sent::SentMessages;
+use haven_types::jwt::TokenScope;
 
 /// Maximum password length in bytes. Prevents DoS via expensive Argon2 hashing
 /// on extremely long inputs.
@@ -31,10 +33,14 @@ pub type AppState = Arc<AppStateInner>;
 pub struct AppStateInner {
     pub db: Arc<Database>,
     pub jwt_secret: String,
+    /// Issuer/audience stamped into minted tokens.
+    pub token_scope: TokenScope,
     pub dispatcher: Dispatcher,
     pub auth_rate_limiter: AuthRateLimiter,
     /// #14: Configurable uploads directory (absolute path).
     pub uploads_dir: PathBuf,
+    /// Client nonces of recent messages, to dedup resends.
+    pub sent_messages: SentMessages,
 }
 
 /// Simple sliding-window rate limiter keyed by IP address.
@@ -149,7 +155,7 @@ pub async fn register(
             .create_user(&user_id.to_string(), &username, &password_hash)
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-        let token = create_token(&state_clone.jwt_secret, user_id, &username)
+        let token = create_token(&state_clone.jwt_secret, &state_clone.token_scope, user_id, &username)
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
         Ok((user_id, token))
@@ -203,7 +209,7 @@ pub async fn login(
 
         let user_id: Uuid = user.id.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-        let token = create_token(&state_clone.jwt_secret, user_id, &user.username)
+        let token = create_token(&state_clone.jwt_secret, &state_clone.token_scope, user_id, &user.username)
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
         Ok::<_, StatusCode>((user_id, user.username, token))
@@ -224,19 +230,22 @@ pub async fn refresh_token(
     State(state): State<AppState>,
     claims: axum::Extension<Claims>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let token = create_token(&state.jwt_secret, claims.sub, &claims.username)
+    let token = create_token(&state.jwt_secret, &state.token_scope, claims.sub, &claims.username)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     Ok(Json(serde_json::json!({ "token": token })))
 }
 
-fn create_token(secret: &str, user_id: Uuid, username: &str) -> anyhow::Result<String> {
-    let claims = Claims {
+fn create_token(secret: &str, scope: &TokenScope, user_id: Uuid, username: &str) -> anyhow::Result<String> {
+    let mut claims = Claims {
         sub: user_id,
         username: username.to_string(),
         // #7: JWT expiry reduced from 7 days to 24 hours
         exp: (chrono::Utc::now() + chrono::Duration::hours(24)).timestamp() as usize,
+        iss: None,
+        aud: None,
     };
+    scope.apply(&mut claims);
 
     let token = encode(
         &Header::default(),
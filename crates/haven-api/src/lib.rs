@@ -4,3 +4,4 @@ pub mod files;
 pub mod messages;
 pub mod middleware;
 pub mod reactions;
+pub mod sent;
@@ -18,6 +18,7 @@ use haven_types::events::GatewayEvent;
 
 use crate::auth::AppStateInner;
 use crate::middleware::Claims;
+use crate::sent::{Claim, MAX_CLIENT_NONCE_LEN};
 
 #[derive(Debug, Deserialize)]
 pub struct MessageQuery {
@@ -35,28 +36,74 @@ fn default_limit() -> u32 {
 /// #8: Channel authorization model — all authenticated users can access all channels.
 /// This is by design for the current MVP: Haven is a small private server where all
 /// registered users are trusted. Per-channel ACLs are a future feature.
+///
+/// With a `client_nonce` the author also gets `MessageAck` once the message is
+/// stored, and a resend with the same nonce returns the stored message (200)
+/// instead of posting it again; 409 while the first send is still storing it.
 pub async fn send_message(
     State(state): State<Arc<AppStateInner>>,
     Path(channel_id): Path<Uuid>,
     Extension(claims): Extension<Claims>,
     Json(req): Json<SendMessageRequest>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let message_id = Uuid::new_v4();
-
     let ciphertext_bytes = B64.decode(&req.ciphertext).map_err(|_| StatusCode::BAD_REQUEST)?;
     let nonce_bytes = B64.decode(&req.nonce).map_err(|_| StatusCode::BAD_REQUEST)?;
 
+    if let Some(client_nonce) = &req.client_nonce {
+        if client_nonce.is_empty() || client_nonce.len() > MAX_CLIENT_NONCE_LEN {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        match state.sent_messages.claim(claims.sub, client_nonce) {
+            Claim::New => {}
+            Claim::Sent(response) => {
+                // The first response or ack went missing; repeat the ack.
+                send_ack(&state, claims.sub, client_nonce, &response).await;
+                return Ok((StatusCode::OK, Json(response)));
+            }
+            Claim::InFlight => return Err(StatusCode::CONFLICT),
+        }
+    }
+
+    // Store, broadcast and settle the nonce on a task of its own: if the
+    // client disconnects mid-insert this future is dropped, and a claim
+    // left in flight would turn every resend into a 409.
+    let response = tokio::spawn(store_message(state, channel_id, claims, req, ciphertext_bytes, nonce_bytes))
+        .await
+        .map_err(|e| { error!("store_message join error: {}", e); StatusCode::INTERNAL_SERVER_ERROR })??;
+
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+async fn store_message(
+    state: Arc<AppStateInner>,
+    channel_id: Uuid,
+    claims: Claims,
+    req: SendMessageRequest,
+    ciphertext_bytes: Vec<u8>,
+    nonce_bytes: Vec<u8>,
+) -> Result<MessageResponse, StatusCode> {
+    let message_id = Uuid::new_v4();
+
     // Run blocking DB insert off the async runtime
     let db = state.clone();
     let cid = channel_id.to_string();
     let mid = message_id.to_string();
     let aid = claims.sub.to_string();
-    tokio::task::spawn_blocking(move || {
+    let inserted = tokio::task::spawn_blocking(move || {
         db.db.insert_message(&mid, &cid, &aid, &ciphertext_bytes, &nonce_bytes)
     })
     .await
-    .map_err(|e| { error!("spawn_blocking join error: {}", e); StatusCode::INTERNAL_SERVER_ERROR })?
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(|e| { error!("spawn_blocking join error: {}", e); StatusCode::INTERNAL_SERVER_ERROR })
+    .and_then(|r| r.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR));
+    let seq = match inserted {
+        Ok(seq) => seq,
+        Err(status) => {
+            if let Some(client_nonce) = &req.client_nonce {
+                state.sent_messages.release(claims.sub, client_nonce);
+            }
+            return Err(status);
+        }
+    };
 
     let now = chrono::Utc::now();
 
@@ -69,18 +116,36 @@ pub async fn send_message(
         ciphertext: req.ciphertext.clone(),
         nonce: req.nonce.clone(),
         timestamp: now,
+        seq: seq as u64,
     });
 
-    Ok((StatusCode::CREATED, Json(MessageResponse {
+    let response = MessageResponse {
         id: message_id,
         channel_id,
         author_id: claims.sub,
-        author_username: claims.username.clone(),
+        author_username: claims.username,
         ciphertext: req.ciphertext,
         nonce: req.nonce,
         created_at: now,
         reactions: vec![],
-    })))
+        seq: seq as u64,
+    };
+    if let Some(client_nonce) = &req.client_nonce {
+        state.sent_messages.complete(claims.sub, client_nonce, response.clone());
+        send_ack(&state, claims.sub, client_nonce, &response).await;
+    }
+    Ok(response)
+}
+
+/// Tell the author's connections that the message sent as `client_nonce` is stored.
+async fn send_ack(state: &AppStateInner, author: Uuid, client_nonce: &str, message: &MessageResponse) {
+    let ack = GatewayEvent::MessageAck {
+        client_nonce: client_nonce.to_string(),
+        message_id: message.id,
+        channel_id: message.channel_id,
+        seq: message.seq,
+    };
+    state.dispatcher.send_to_user(author, ack).await;
 }
 
 pub async fn get_messages(
@@ -172,9 +237,164 @@ pub async fn get_messages(
                         chrono::DateTime::default()
                     }),
                 reactions,
+                seq: row.seq as u64,
             }
         })
         .collect();
 
     Ok(Json(messages))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use axum::response::Response;
+
+    use haven_db::Database;
+    use haven_gateway::dispatcher::{Dispatcher, UserMessage};
+    use haven_types::jwt::TokenScope;
+
+    use crate::auth::AuthRateLimiter;
+    use crate::sent::SentMessages;
+
+    const GENERAL: &str = "00000000-0000-0000-0000-000000000001";
+
+    fn state(dir: &std::path::Path) -> Arc<AppStateInner> {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        Arc::new(AppStateInner {
+            db: Arc::new(Database::open(&dir.join("test.db")).unwrap()),
+            jwt_secret: "test".into(),
+            token_scope: TokenScope { issuer: None, audience: None },
+            dispatcher: Dispatcher::new(),
+            auth_rate_limiter: AuthRateLimiter::new(),
+            uploads_dir: dir.to_path_buf(),
+            sent_messages: SentMessages::default(),
+        })
+    }
+
+    async fn send(state: &Arc<AppStateInner>, claims: &Claims, client_nonce: Option<&str>) -> Response {
+        let req = SendMessageRequest {
+            ciphertext: B64.encode(b"ciphertext"),
+            nonce: B64.encode([0u8; 12]),
+            client_nonce: client_nonce.map(str::to_string),
+        };
+        match send_message(State(state.clone()), Path(GENERAL.parse().unwrap()), Extension(claims.clone()), Json(req)).await {
+            Ok(response) => response.into_response(),
+            Err(status) => status.into_response(),
+        }
+    }
+
+    async fn body(response: Response) -> serde_json::Value {
+        serde_json::from_slice(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+    }
+
+    fn acks(rx: &mut tokio::sync::mpsc::Receiver<UserMessage>) -> Vec<(String, Uuid, u64)> {
+        let mut acks = Vec::new();
+        while let Ok(UserMessage::Event(event)) = rx.try_recv() {
+            if let GatewayEvent::MessageAck { client_nonce, message_id, seq, .. } = event {
+                acks.push((client_nonce, message_id, seq));
+            }
+        }
+        acks
+    }
+
+    #[tokio::test]
+    async fn test_send_with_client_nonce_is_acked_and_deduped() {
+        let dir = std::env::temp_dir().join(format!("haven-api-ack-{}", std::process::id()));
+        let state = state(&dir);
+        let user = Uuid::new_v4();
+        state.db.create_user(&user.to_string(), "alice", "hash").unwrap();
+        let claims = Claims { sub: user, username: "alice".into(), exp: usize::MAX, iss: None, aud: None };
+        let (_conn, mut user_rx) = state.dispatcher.register_user_channel(user).await;
+        let mut broadcasts = state.dispatcher.subscribe();
+
+        let first = send(&state, &claims, Some("n-1")).await;
+        assert_eq!(first.status(), StatusCode::CREATED);
+        let first = body(first).await;
+        let id: Uuid = first["id"].as_str().unwrap().parse().unwrap();
+        assert_eq!(acks(&mut user_rx), vec![("n-1".to_string(), id, first["seq"].as_u64().unwrap())]);
+
+        // A resend (say the response was lost) gets the same message back
+        // and another ack, and posts nothing.
+        let again = send(&state, &claims, Some("n-1")).await;
+        assert_eq!(again.status(), StatusCode::OK);
+        assert_eq!(body(again).await["id"], first["id"]);
+        assert_eq!(acks(&mut user_rx).len(), 1);
+        assert_eq!(state.db.get_messages(GENERAL, 50, None).unwrap().len(), 1);
+        assert!(broadcasts.try_recv().is_ok());
+        assert!(broadcasts.try_recv().is_err(), "one MessageCreate");
+
+        // A new nonce is a new message; no nonce, no ack.
+        assert_eq!(send(&state, &claims, Some("n-2")).await.status(), StatusCode::CREATED);
+        assert_eq!(send(&state, &claims, None).await.status(), StatusCode::CREATED);
+        assert_eq!(acks(&mut user_rx).iter().map(|(n, ..)| n.as_str()).collect::<Vec<_>>(), ["n-2"]);
+        assert_eq!(state.db.get_messages(GENERAL, 50, None).unwrap().len(), 3);
+
+        let oversized = "x".repeat(MAX_CLIENT_NONCE_LEN + 1);
+        assert_eq!(send(&state, &claims, Some(&oversized)).await.status(), StatusCode::BAD_REQUEST);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_request_still_settles_its_nonce() {
+        let dir = std::env::temp_dir().join(format!("haven-api-ack-drop-{}", std::process::id()));
+        let state = state(&dir);
+        let user = Uuid::new_v4();
+        state.db.create_user(&user.to_string(), "alice", "hash").unwrap();
+        let claims = Claims { sub: user, username: "alice".into(), exp: usize::MAX, iss: None, aud: None };
+
+        // Hold the writer so the insert blocks, then drop the request
+        // mid-insert as a disconnecting client would.
+        let (unblock, blocked) = std::sync::mpsc::channel::<()>();
+        let (held, holding) = std::sync::mpsc::channel::<()>();
+        let db = state.db.clone();
+        let writer = std::thread::spawn(move || {
+            db.with_conn_mut(|_| {
+                held.send(()).unwrap();
+                blocked.recv().ok();
+                Ok(())
+            })
+            .unwrap()
+        });
+        holding.recv().unwrap();
+        let dropped = tokio::time::timeout(Duration::from_millis(100), send(&state, &claims, Some("n-1"))).await;
+        assert!(dropped.is_err(), "insert should still be blocked");
+        assert_eq!(send(&state, &claims, Some("n-1")).await.status(), StatusCode::CONFLICT);
+
+        unblock.send(()).unwrap();
+        writer.join().unwrap();
+
+        // The resend gets the message the dropped request stored.
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        let retry = loop {
+            let retry = send(&state, &claims, Some("n-1")).await;
+            if retry.status() != StatusCode::CONFLICT || tokio::time::Instant::now() > deadline {
+                break retry;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(retry.status(), StatusCode::OK);
+        let stored = state.db.get_messages(GENERAL, 50, None).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(body(retry).await["id"].as_str().unwrap(), stored[0].id);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_failed_insert_releases_the_nonce() {
+        let dir = std::env::temp_dir().join(format!("haven-api-ack-fail-{}", std::process::id()));
+        let state = state(&dir);
+        // Not a registered user, so the insert fails.
+        let user = Uuid::new_v4();
+        let claims = Claims { sub: user, username: "ghost".into(), exp: usize::MAX, iss: None, aud: None };
+        let (_conn, mut user_rx) = state.dispatcher.register_user_channel(user).await;
+
+        assert_eq!(send(&state, &claims, Some("n-1")).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(acks(&mut user_rx).is_empty());
+        assert!(state.sent_messages.is_empty(), "retry must be able to try again");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -6,9 +6,8 @@ use axum::{
     middleware::Next,
     response::Response,
 };
-use jsonwebtoken::{DecodingKey, Validation, decode};
-
 pub use haven_types::api::Claims;
+use haven_types::jwt::TokenScope;
 
 /// Shared JWT secret injected via `axum::Extension` from the server entrypoint.
 /// This avoids every middleware call reading from the environment independently.
@@ -16,8 +15,9 @@ pub use haven_types::api::Claims;
 pub struct JwtSecret(pub Arc<str>);
 
 /// Extract and validate JWT from Authorization header.
-/// The signing secret is obtained from the `JwtSecret` extension layer,
-/// NOT from environment variables — the server sets this once at startup.
+/// The signing secret and expected issuer/audience are obtained from the
+/// `JwtSecret` and `TokenScope` extension layers, NOT from environment
+/// variables — the server sets these once at startup.
 pub async fn require_auth(mut req: Request, next: Next) -> Result<Response, StatusCode> {
     let auth_header = req
         .headers()
@@ -34,14 +34,15 @@ pub async fn require_auth(mut req: Request, next: Next) -> Result<Response, Stat
         .get::<JwtSecret>()
         .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?
         .clone();
+    let scope = req
+        .extensions()
+        .get::<TokenScope>()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.0.as_bytes()),
-        &Validation::default(),
-    )
-    .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let claims = scope
+        .decode(token, &secret.0)
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
 
-    req.extensions_mut().insert(token_data.claims);
+    req.extensions_mut().insert(claims);
     Ok(next.run(req).await)
 }
@@ -0,0 +1,176 @@
+//! Recently sent messages, keyed by the sender's client nonce.
+//!
+//! A client that tags `POST /channels/{id}/messages` with a `client_nonce`
+//! can resend it after losing the response (or never seeing `MessageAck`)
+//! without posting the message twice: within [`SENT_MESSAGE_TTL`] the same
+//! author and nonce get the first message back instead of a new one.
+
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use haven_gateway::ttl::TtlMap;
+use haven_types::api::MessageResponse;
+
+/// How long a nonce is remembered after its message is stored.
+pub const SENT_MESSAGE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How long a claimed nonce may stay in flight before a resend may claim it
+/// again. The handler settles every claim itself; this only bounds an entry
+/// whose request never did (a panic mid-insert).
+pub const IN_FLIGHT_TTL: Duration = Duration::from_secs(60);
+
+/// Longest `client_nonce` accepted.
+pub const MAX_CLIENT_NONCE_LEN: usize = 64;
+
+/// What [`SentMessages::claim`] found for a nonce.
+#[derive(Debug)]
+pub enum Claim {
+    /// First use: store the message, then [`SentMessages::complete`] (or
+    /// [`SentMessages::release`] if storing fails).
+    New,
+    /// Already stored; this is what the first request returned.
+    Sent(MessageResponse),
+    /// The first request is still storing it.
+    InFlight,
+}
+
+enum Entry {
+    InFlight,
+    Sent(MessageResponse),
+}
+
+/// In-memory nonce store, shared by every request.
+#[derive(Clone)]
+pub struct SentMessages {
+    sent: TtlMap<(Uuid, String), Entry>,
+    ttl: Duration,
+    in_flight_ttl: Duration,
+}
+
+impl Default for SentMessages {
+    fn default() -> Self {
+        Self::with_ttls(SENT_MESSAGE_TTL, IN_FLIGHT_TTL)
+    }
+}
+
+impl SentMessages {
+    pub fn with_ttls(ttl: Duration, in_flight_ttl: Duration) -> Self {
+        Self { sent: TtlMap::new(), ttl, in_flight_ttl }
+    }
+
+    /// Look up `nonce` for `author`, reserving it if it's new.
+    pub fn claim(&self, author: Uuid, nonce: &str) -> Claim {
+        let key = (author, nonce.to_string());
+        self.sent.with(|sent| match sent.get(&key) {
+            Some(Entry::InFlight) => Claim::InFlight,
+            Some(Entry::Sent(response)) => Claim::Sent(response.clone()),
+            None => {
+                sent.insert(key, Entry::InFlight, self.in_flight_ttl);
+                Claim::New
+            }
+        })
+    }
+
+    /// Record the stored message for a nonce claimed as [`Claim::New`].
+    pub fn complete(&self, author: Uuid, nonce: &str, response: MessageResponse) {
+        self.sent.with(|sent| sent.insert((author, nonce.to_string()), Entry::Sent(response), self.ttl));
+    }
+
+    /// Give up a [`Claim::New`] whose message wasn't stored, so a retry can
+    /// try again.
+    pub fn release(&self, author: Uuid, nonce: &str) {
+        let key = (author, nonce.to_string());
+        self.sent.with(|sent| {
+            if matches!(sent.get(&key), Some(Entry::InFlight)) {
+                sent.remove(&key);
+            }
+        });
+    }
+
+    /// Forget nonces past their TTL. Returns how many went.
+    pub fn purge_expired(&self) -> usize {
+        self.sent.purge_expired()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sent.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sent.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> MessageResponse {
+        MessageResponse {
+            id: Uuid::new_v4(),
+            channel_id: Uuid::new_v4(),
+            author_id: Uuid::new_v4(),
+            author_username: "tester".into(),
+            ciphertext: "c".into(),
+            nonce: "n".into(),
+            created_at: chrono::Utc::now(),
+            reactions: vec![],
+            seq: 1,
+        }
+    }
+
+    #[test]
+    fn test_nonce_is_claimed_once_per_author() {
+        let sent = SentMessages::default();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(matches!(sent.claim(alice, "n1"), Claim::New));
+        assert!(matches!(sent.claim(alice, "n1"), Claim::InFlight));
+        // Nonces are the author's own: another user's "n1" is unrelated.
+        assert!(matches!(sent.claim(bob, "n1"), Claim::New));
+
+        let first = response();
+        sent.complete(alice, "n1", first.clone());
+        let Claim::Sent(again) = sent.claim(alice, "n1") else { panic!("expected the stored message") };
+        assert_eq!(again.id, first.id);
+    }
+
+    #[test]
+    fn test_released_nonce_can_be_claimed_again() {
+        let sent = SentMessages::default();
+        let alice = Uuid::new_v4();
+        assert!(matches!(sent.claim(alice, "n1"), Claim::New));
+        sent.release(alice, "n1");
+        assert!(sent.is_empty());
+        assert!(matches!(sent.claim(alice, "n1"), Claim::New));
+    }
+
+    #[test]
+    fn test_expired_nonces_are_purged() {
+        let sent = SentMessages::with_ttls(Duration::from_millis(20), Duration::from_secs(60));
+        let alice = Uuid::new_v4();
+        sent.claim(alice, "old");
+        sent.complete(alice, "old", response());
+        sent.claim(alice, "pending");
+        std::thread::sleep(Duration::from_millis(40));
+
+        // Stale ones are new again; a send still in flight is kept.
+        assert_eq!(sent.purge_expired(), 1);
+        assert_eq!(sent.len(), 1);
+        assert!(matches!(sent.claim(alice, "old"), Claim::New));
+    }
+
+    #[test]
+    fn test_abandoned_claim_expires() {
+        let sent = SentMessages::with_ttls(Duration::from_secs(60), Duration::from_millis(20));
+        let alice = Uuid::new_v4();
+        assert!(matches!(sent.claim(alice, "n1"), Claim::New));
+        std::thread::sleep(Duration::from_millis(40));
+
+        // Never completed or released: a resend may take it over.
+        assert!(matches!(sent.claim(alice, "n1"), Claim::New));
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(sent.purge_expired(), 1);
+        assert!(sent.is_empty());
+    }
+}
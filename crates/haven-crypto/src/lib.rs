@@ -12,3 +12,4 @@
 pub mod encrypt;
 
 pub mod keys;
+pub mod nonce_audit;
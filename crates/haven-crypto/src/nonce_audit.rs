@@ -0,0 +1,118 @@
+//! Debug check that no chunk nonce is used twice under one key.
+//!
+//! Fast-transfer chunks use deterministic nonces derived from the key and
+//! the chunk index. That is only safe while every transfer has its own key:
+//! two transfers sharing one would seal different plaintext under the same
+//! nonce, which breaks AES-GCM. With `HAVEN_NONCE_AUDIT=1` set, senders
+//! record every `(key, chunk_index)` they seal in [`NonceAudit::global`]
+//! and fail if one comes round again with different plaintext. Sealing the
+//! same chunk twice (hashing ahead of a send, say) yields the same
+//! ciphertext and is allowed. Deriving keys with
+//! [`derive_transfer_key`](crate::keys::derive_transfer_key) avoids the
+//! problem in the first place.
+//!
+//! The audit remembers every pair for the life of the process, so it is
+//! meant for test runs and debugging, not production.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+use anyhow::{Result, bail};
+use sha2::{Digest, Sha256};
+
+/// Environment variable that turns the audit on when set to `1`.
+pub const NONCE_AUDIT_ENV: &str = "HAVEN_NONCE_AUDIT";
+
+static ENABLED: LazyLock<AtomicBool> =
+    LazyLock::new(|| AtomicBool::new(std::env::var(NONCE_AUDIT_ENV).is_ok_and(|v| v == "1")));
+
+static GLOBAL: LazyLock<NonceAudit> = LazyLock::new(NonceAudit::default);
+
+/// Whether senders should record their nonces in [`NonceAudit::global`].
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Turn the process-wide audit on or off, overriding [`NONCE_AUDIT_ENV`].
+pub fn set_enabled(on: bool) {
+    ENABLED.store(on, Ordering::Relaxed);
+}
+
+/// A key's SHA-256 and a chunk index.
+type SealSite = ([u8; 32], u64);
+
+/// Every `(key, chunk_index)` seen so far, with a hash of the plaintext
+/// sealed under it. Keys are kept as a hash too, never in the clear.
+#[derive(Default)]
+pub struct NonceAudit {
+    seen: Mutex<HashMap<SealSite, [u8; 32]>>,
+}
+
+impl NonceAudit {
+    /// The process-wide audit senders use.
+    pub fn global() -> &'static NonceAudit {
+        &GLOBAL
+    }
+
+    /// Note that `key` is about to seal `plaintext` as chunk `chunk_index`.
+    /// Errors if that pair already sealed something else.
+    pub fn record(&self, key: &[u8; 32], chunk_index: u64, plaintext: &[u8]) -> Result<()> {
+        let fingerprint: [u8; 32] = Sha256::digest(key).into();
+        let digest: [u8; 32] = Sha256::digest(plaintext).into();
+        let mut seen = self.seen.lock().unwrap();
+        let sealed = *seen.entry((fingerprint, chunk_index)).or_insert(digest);
+        if sealed != digest {
+            bail!(
+                "Nonce reuse: chunk {} already sealed under key {}",
+                chunk_index,
+                hex_prefix(&fingerprint)
+            );
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Enough of a key fingerprint to tell keys apart in an error message.
+fn hex_prefix(fingerprint: &[u8; 32]) -> String {
+    fingerprint[..4].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::derive_transfer_key;
+
+    #[test]
+    fn detects_reused_key_and_chunk() {
+        let audit = NonceAudit::default();
+        let master = [7u8; 32];
+        for idx in 0..4 {
+            audit.record(&master, idx, b"first file").unwrap();
+        }
+        audit.record(&[8u8; 32], 0, b"other key").unwrap();
+        // Re-sealing identical plaintext gives identical ciphertext.
+        audit.record(&master, 1, b"first file").unwrap();
+
+        // A second transfer sealing under the same key.
+        let err = audit.record(&master, 2, b"second file").unwrap_err();
+        assert!(err.to_string().contains("chunk 2"), "{}", err);
+
+        // Per-transfer subkeys keep the same chunk indices apart.
+        for (transfer_id, file) in [([1u8; 16], b"first file"), ([2u8; 16], b"other file")] {
+            let key = derive_transfer_key(&master, &transfer_id);
+            for idx in 0..4 {
+                audit.record(&key, idx, file).unwrap();
+            }
+        }
+        assert_eq!(audit.len(), 13);
+    }
+}
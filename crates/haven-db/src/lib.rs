@@ -6,7 +6,7 @@ pub mod queries;
 use anyhow::Result;
 use std::path::Path;
 
-pub use pool::DbPool;
+pub use pool::{DEFAULT_READER_POOL_SIZE, DbPool};
 
 /// Gateway database — wraps DbPool with gateway-specific migrations.
 pub struct Database {
@@ -15,7 +15,12 @@ pub struct Database {
 
 impl Database {
     pub fn open(path: &Path) -> Result<Self> {
-        let pool = DbPool::open(path, "Database", |conn| migrations::run(conn))?;
+        Self::open_with_readers(path, DEFAULT_READER_POOL_SIZE)
+    }
+
+    /// Open with `reader_count` read-only connections in the pool.
+    pub fn open_with_readers(path: &Path, reader_count: usize) -> Result<Self> {
+        let pool = DbPool::open_with_readers(path, "Database", reader_count, |conn| migrations::run(conn))?;
         Ok(Self { pool })
     }
 
@@ -26,10 +31,36 @@ impl Database {
         self.pool.with_conn(f)
     }
 
+    /// See [`DbPool::query_row_cached`].
+    pub fn query_row_cached<T, P, F>(&self, sql: &str, params: P, f: F) -> Result<T>
+    where
+        P: rusqlite::Params,
+        F: FnOnce(&rusqlite::Row<'_>) -> rusqlite::Result<T>,
+    {
+        self.pool.query_row_cached(sql, params, f)
+    }
+
+    /// See [`DbPool::query_all_cached`].
+    pub fn query_all_cached<T, P, F>(&self, sql: &str, params: P, f: F) -> Result<Vec<T>>
+    where
+        P: rusqlite::Params,
+        F: FnMut(&rusqlite::Row<'_>) -> rusqlite::Result<T>,
+    {
+        self.pool.query_all_cached(sql, params, f)
+    }
+
     pub fn with_conn_mut<F, T>(&self, f: F) -> Result<T>
     where
         F: FnOnce(&rusqlite::Connection) -> Result<T>,
     {
         self.pool.with_conn_mut(f)
     }
+
+    /// Run `f` in a single writer transaction (rolled back on `Err`).
+    pub fn with_transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&rusqlite::Connection) -> Result<T>,
+    {
+        self.pool.with_transaction(f)
+    }
 }
@@ -4,7 +4,7 @@ use tracing::info;
 
 /// Current schema version. Increment this and add a new migration function
 /// to the `MIGRATIONS` array when the schema changes.
-const CURRENT_VERSION: u32 = 4;
+const CURRENT_VERSION: u32 = 5;
 
 /// Each migration is a function that takes a connection and applies changes.
 /// Migrations are applied sequentially starting from the current version + 1.
@@ -16,6 +16,7 @@ const MIGRATIONS: &[MigrationFn] = &[
     migrate_v2,
     migrate_v3,
     migrate_v4,
+    migrate_v5,
 ];
 
 pub fn run(conn: &Connection) -> Result<()> {
@@ -200,3 +201,25 @@ fn migrate_v4(conn: &Connection) -> Result<()> {
     )?;
     Ok(())
 }
+
+/// Version 5: Per-channel message sequence numbers so reconnecting gateway
+/// clients can resume from the last message they saw. Existing messages are
+/// numbered in creation order.
+fn migrate_v5(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+        ALTER TABLE messages ADD COLUMN seq INTEGER;
+
+        UPDATE messages SET seq = numbered.seq
+            FROM (
+                SELECT id, ROW_NUMBER() OVER (PARTITION BY channel_id ORDER BY created_at, rowid) AS seq
+                FROM messages
+            ) AS numbered
+            WHERE messages.id = numbered.id;
+
+        CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_channel_seq
+            ON messages(channel_id, seq);
+        ",
+    )?;
+    Ok(())
+}
@@ -16,6 +16,8 @@ pub struct MessageRow {
     pub ciphertext: Vec<u8>,
     pub nonce: Vec<u8>,
     pub created_at: String,
+    /// Per-channel sequence number, increasing by one per message.
+    pub seq: i64,
 }
 
 pub struct ReactionRow {
@@ -47,6 +49,16 @@ pub struct PendingOfferRow {
     pub status: String,
 }
 
+/// One recipient's side of an offer still awaiting an answer or accepted.
+pub struct OpenOfferRow {
+    pub transfer_id: String,
+    pub from_user_id: String,
+    pub to_user_id: String,
+    pub status: String,
+    /// Seconds since the offer was made.
+    pub age_secs: i64,
+}
+
 pub struct PendingFolderOfferRow {
     pub folder_id: String,
     pub from_user_id: String,
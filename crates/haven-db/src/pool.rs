@@ -1,12 +1,23 @@
 use anyhow::Result;
-use rusqlite::Connection;
+use rusqlite::{Connection, Params, Row};
 use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
+use std::time::Duration;
 use tracing::info;
 
-/// Number of read-only connections in the reader pool.
-const READER_POOL_SIZE: usize = 4;
+/// Default number of read-only connections in the reader pool.
+pub const DEFAULT_READER_POOL_SIZE: usize = 4;
+
+/// How long a connection waits on a locked database before returning
+/// SQLITE_BUSY. Covers brief writer locks (checkpoints, migrations) that
+/// would otherwise fail reads outright.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Prepared statements each connection keeps for `prepare_cached`, least
+/// recently used evicted first. Sized above the number of distinct hot
+/// queries so they don't evict each other.
+pub const STATEMENT_CACHE_CAPACITY: usize = 64;
 
 /// Generic SQLite connection pool with reader/writer split.
 ///
@@ -15,6 +26,10 @@ const READER_POOL_SIZE: usize = 4;
 /// using round-robin selection via `AtomicUsize`. WAL mode enables concurrent
 /// reads even while a write is in progress.
 ///
+/// Every connection keeps its own cache of prepared statements (SQLite
+/// statements belong to one connection), so `prepare_cached` and the
+/// `query_*_cached` helpers reuse a statement once each reader has seen it.
+///
 /// Callers provide a migration function to initialize the schema on the writer
 /// connection before the reader pool is created. This keeps migration logic
 /// in the owning crate while sharing the pool mechanics.
@@ -26,25 +41,40 @@ pub struct DbPool {
 
 impl DbPool {
     /// Open a database at `path`, run `migrate` on the writer, then create
-    /// `READER_POOL_SIZE` read-only connections.
+    /// `DEFAULT_READER_POOL_SIZE` read-only connections.
     pub fn open(path: &Path, label: &str, migrate: impl FnOnce(&Connection) -> Result<()>) -> Result<Self> {
+        Self::open_with_readers(path, label, DEFAULT_READER_POOL_SIZE, migrate)
+    }
+
+    /// Like `open`, with `reader_count` read-only connections (at least 1).
+    pub fn open_with_readers(
+        path: &Path,
+        label: &str,
+        reader_count: usize,
+        migrate: impl FnOnce(&Connection) -> Result<()>,
+    ) -> Result<Self> {
+        let reader_count = reader_count.max(1);
         let writer = Connection::open(path)?;
+        writer.busy_timeout(BUSY_TIMEOUT)?;
+        writer.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
         writer.pragma_update(None, "journal_mode", "WAL")?;
         writer.pragma_update(None, "foreign_keys", "ON")?;
 
         migrate(&writer)?;
 
-        let mut readers = Vec::with_capacity(READER_POOL_SIZE);
-        for _ in 0..READER_POOL_SIZE {
+        let mut readers = Vec::with_capacity(reader_count);
+        for _ in 0..reader_count {
             let conn = Connection::open_with_flags(
                 path,
                 rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
             )?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
             conn.pragma_update(None, "journal_mode", "WAL")?;
             readers.push(Mutex::new(conn));
         }
 
-        info!("{} opened at {} (1 writer + {} readers)", label, path.display(), READER_POOL_SIZE);
+        info!("{} opened at {} (1 writer + {} readers)", label, path.display(), reader_count);
         Ok(Self {
             writer: Mutex::new(writer),
             readers,
@@ -66,6 +96,30 @@ impl DbPool {
         f(&conn)
     }
 
+    /// Run a single-row SELECT on a reader through its statement cache.
+    /// Prefer this over `with_conn` + `query_row` on hot paths.
+    pub fn query_row_cached<T, P, F>(&self, sql: &str, params: P, f: F) -> Result<T>
+    where
+        P: Params,
+        F: FnOnce(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        self.with_conn(|conn| Ok(conn.prepare_cached(sql)?.query_row(params, f)?))
+    }
+
+    /// Run a SELECT on a reader through its statement cache and collect
+    /// every row.
+    pub fn query_all_cached<T, P, F>(&self, sql: &str, params: P, f: F) -> Result<Vec<T>>
+    where
+        P: Params,
+        F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare_cached(sql)?;
+            let rows = stmt.query_map(params, f)?.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(rows)
+        })
+    }
+
     /// Acquire the writer connection for INSERT/UPDATE/DELETE queries.
     pub fn with_conn_mut<F, T>(&self, f: F) -> Result<T>
     where
@@ -77,4 +131,157 @@ impl DbPool {
             .map_err(|e| anyhow::anyhow!("Writer lock poisoned: {}", e))?;
         f(&conn)
     }
+
+    /// Run `f` on the writer inside one transaction: committed if `f`
+    /// returns `Ok`, rolled back otherwise. Use for multi-statement writes
+    /// that must land together.
+    pub fn with_transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Connection) -> Result<T>,
+    {
+        let mut conn = self
+            .writer
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Writer lock poisoned: {}", e))?;
+        let tx = conn.transaction()?;
+        let value = f(&tx)?;
+        tx.commit()?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::StatementStatus;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    #[test]
+    fn test_readers_run_concurrently() {
+        let dir = std::env::temp_dir().join(format!("haven-db-pool-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let pool = Arc::new(
+            DbPool::open_with_readers(&dir.join("test.db"), "Test DB", 8, |conn| {
+                conn.execute_batch("CREATE TABLE t (v INTEGER); INSERT INTO t VALUES (1);")?;
+                Ok(())
+            })
+            .unwrap(),
+        );
+        assert_eq!(pool.readers.len(), 8);
+
+        // Each reader holds its connection for 200ms; serialized this would
+        // take 1.6s.
+        let start = Instant::now();
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    pool.with_conn(|conn| {
+                        let v: i64 = conn.query_row("SELECT v FROM t", [], |r| r.get(0))?;
+                        std::thread::sleep(Duration::from_millis(200));
+                        Ok(v)
+                    })
+                    .unwrap()
+                })
+            })
+            .collect();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), 1);
+        }
+        assert!(start.elapsed() < Duration::from_millis(800), "readers serialized: {:?}", start.elapsed());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_cached_statements_are_reused() {
+        let dir = std::env::temp_dir().join(format!("haven-db-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let pool = DbPool::open(&dir.join("test.db"), "Test DB", |conn| {
+            conn.execute_batch(
+                "CREATE TABLE transfers (id TEXT PRIMARY KEY, uploader_id TEXT, status TEXT, file_size INTEGER,
+                     bytes_received INTEGER, chunk_count INTEGER, filename TEXT, content_type TEXT);
+                 CREATE TABLE chunks (transfer_id TEXT, chunk_index INTEGER, byte_offset INTEGER,
+                     byte_length INTEGER, received INTEGER, PRIMARY KEY (transfer_id, chunk_index));
+                 INSERT INTO transfers VALUES ('t', 'u', 'uploading', 1024, 512, 4, 'f.bin', NULL);
+                 INSERT INTO chunks VALUES ('t', 0, 0, 256, 1), ('t', 1, 256, 256, 1),
+                                           ('t', 2, 512, 256, 0), ('t', 3, 768, 256, 0);",
+            )?;
+            Ok(())
+        })
+        .unwrap();
+
+        // The status lookups the upload and download routes run per request.
+        const TRANSFER: &str = "SELECT t.status, t.file_size, t.bytes_received, c.byte_offset, c.received
+             FROM transfers t JOIN chunks c ON c.transfer_id = t.id
+             WHERE t.id = ?1 AND c.chunk_index = ?2";
+        const RECEIVED: &str = "SELECT chunk_index FROM chunks WHERE transfer_id = ?1 AND received = 1 ORDER BY chunk_index";
+        const ROUNDS: i32 = 40;
+        let row = |r: &Row<'_>| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(3)?));
+
+        for i in 0..ROUNDS {
+            pool.query_row_cached(TRANSFER, rusqlite::params!["t", i % 4], row).unwrap();
+            assert_eq!(pool.query_all_cached(RECEIVED, ["t"], |r| r.get::<_, i64>(0)).unwrap(), [0, 1]);
+        }
+
+        // A statement counts the runs made through it, so the cached ones
+        // across all readers account for every round only if each reader
+        // reused the statement it prepared first.
+        let runs = |sql| -> i32 {
+            (0..DEFAULT_READER_POOL_SIZE)
+                .map(|_| {
+                    pool.with_conn(|conn| Ok(conn.prepare_cached(sql)?.get_status(StatementStatus::Run)))
+                        .unwrap()
+                })
+                .sum()
+        };
+        assert_eq!(runs(TRANSFER), ROUNDS);
+        assert_eq!(runs(RECEIVED), ROUNDS);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_transaction_rolls_back_on_error() {
+        let dir = std::env::temp_dir().join(format!("haven-db-tx-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let pool = DbPool::open(&dir.join("test.db"), "Test DB", |conn| {
+            conn.execute_batch("CREATE TABLE t (v INTEGER NOT NULL);")?;
+            Ok(())
+        })
+        .unwrap();
+
+        // Second insert violates NOT NULL after the first already succeeded.
+        let result = pool.with_transaction(|conn| {
+            conn.execute("INSERT INTO t VALUES (1)", [])?;
+            conn.execute("INSERT INTO t VALUES (NULL)", [])?;
+            Ok(())
+        });
+        assert!(result.is_err());
+
+        let count: i64 = pool
+            .with_conn_mut(|conn| Ok(conn.query_row("SELECT COUNT(*) FROM t", [], |r| r.get(0))?))
+            .unwrap();
+        assert_eq!(count, 0);
+
+        pool.with_transaction(|conn| {
+            conn.execute("INSERT INTO t VALUES (1)", [])?;
+            conn.execute("INSERT INTO t VALUES (2)", [])?;
+            Ok(())
+        })
+        .unwrap();
+        let count: i64 = pool
+            .with_conn_mut(|conn| Ok(conn.query_row("SELECT COUNT(*) FROM t", [], |r| r.get(0))?))
+            .unwrap();
+        assert_eq!(count, 2);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
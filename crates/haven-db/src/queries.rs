@@ -1,4 +1,4 @@
-use crate::models::{FileRow, MessageRow, PendingFolderOfferRow, PendingOfferRow, ReactionRow, UserRow};
+use crate::models::{FileRow, MessageRow, OpenOfferRow, PendingFolderOfferRow, PendingOfferRow, ReactionRow, UserRow};
 use crate::Database;
 use anyhow::Result;
 use rusqlite::Connection;
@@ -24,6 +24,8 @@ impl Database {
 
     // -- Messages --
 
+    /// Insert a message and return its per-channel sequence number. The
+    /// single writer connection serializes inserts, so `MAX(seq) + 1` can't race.
     pub fn insert_message(
         &self,
         id: &str,
@@ -31,13 +33,17 @@ impl Database {
         author_id: &str,
         ciphertext: &[u8],
         nonce: &[u8],
-    ) -> Result<()> {
+    ) -> Result<i64> {
         self.with_conn_mut(|conn| {
-            conn.execute(
-                "INSERT INTO messages (id, channel_id, author_id, ciphertext, nonce) VALUES (?1, ?2, ?3, ?4, ?5)",
+            let seq = conn.query_row(
+                "INSERT INTO messages (id, channel_id, author_id, ciphertext, nonce, seq)
+                 VALUES (?1, ?2, ?3, ?4, ?5,
+                         (SELECT COALESCE(MAX(seq), 0) + 1 FROM messages WHERE channel_id = ?2))
+                 RETURNING seq",
                 rusqlite::params![id, channel_id, author_id, ciphertext, nonce],
+                |row| row.get(0),
             )?;
-            Ok(())
+            Ok(seq)
         })
     }
 
@@ -47,6 +53,34 @@ impl Database {
         self.with_conn(|conn| query_messages(conn, channel_id, limit, before))
     }
 
+    /// Messages in `channel_id` with `seq > after_seq`, oldest first.
+    /// Returns `None` if there are more than `limit` of them, or if `after_seq`
+    /// is ahead of the channel (the client's view is from another database),
+    /// in which case the caller should fall back to a full fetch.
+    pub fn get_messages_since(&self, channel_id: &str, after_seq: i64, limit: u32) -> Result<Option<Vec<MessageRow>>> {
+        self.with_conn(|conn| {
+            let latest: i64 = conn.query_row(
+                "SELECT COALESCE(MAX(seq), 0) FROM messages WHERE channel_id = ?1",
+                [channel_id],
+                |row| row.get(0),
+            )?;
+            if after_seq > latest || latest.checked_sub(after_seq).is_none_or(|behind| behind > limit as i64) {
+                return Ok(None);
+            }
+            let mut stmt = conn.prepare(
+                "SELECT m.id, m.channel_id, m.author_id, u.username, m.ciphertext, m.nonce, m.created_at, m.seq
+                 FROM messages m
+                 LEFT JOIN users u ON m.author_id = u.id
+                 WHERE m.channel_id = ?1 AND m.seq > ?2
+                 ORDER BY m.seq",
+            )?;
+            let rows = stmt
+                .query_map(rusqlite::params![channel_id, after_seq], map_message_row)?
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            Ok(Some(rows))
+        })
+    }
+
     pub fn get_username_by_id(&self, id: &str) -> Result<String> {
         self.with_conn(|conn| {
             conn.query_row("SELECT username FROM users WHERE id = ?1", [id], |row| {
@@ -171,6 +205,18 @@ impl Database {
         })
     }
 
+    /// Like [`Self::update_pending_offer_status`], but only for one recipient
+    /// of an offer that went to several.
+    pub fn update_pending_offer_status_for(&self, transfer_id: &str, to_user_id: &str, status: &str) -> Result<()> {
+        self.with_conn_mut(|conn| {
+            conn.execute(
+                "UPDATE pending_offers SET status = ?1 WHERE transfer_id = ?2 AND to_user_id = ?3",
+                rusqlite::params![status, transfer_id, to_user_id],
+            )?;
+            Ok(())
+        })
+    }
+
     pub fn update_pending_offer_hashes(
         &self,
         transfer_id: &str,
@@ -353,7 +399,7 @@ impl Database {
             let (sql, params): (String, Vec<Box<dyn rusqlite::types::ToSql>>) = match channel_id {
                 Some(cid) => (
                     "SELECT m.id, m.channel_id, m.author_id,
-                            COALESCE(u.username, 'unknown'), m.ciphertext, m.nonce, m.created_at
+                            COALESCE(u.username, 'unknown'), m.ciphertext, m.nonce, m.created_at, m.seq
                      FROM messages m LEFT JOIN users u ON m.author_id = u.id
                      WHERE m.channel_id = ?1
                      ORDER BY m.created_at DESC LIMIT ?2"
@@ -365,7 +411,7 @@ impl Database {
                 ),
                 None => (
                     "SELECT m.id, m.channel_id, m.author_id,
-                            COALESCE(u.username, 'unknown'), m.ciphertext, m.nonce, m.created_at
+                            COALESCE(u.username, 'unknown'), m.ciphertext, m.nonce, m.created_at, m.seq
                      FROM messages m LEFT JOIN users u ON m.author_id = u.id
                      ORDER BY m.created_at DESC LIMIT ?1"
                         .into(),
@@ -375,19 +421,7 @@ impl Database {
             let mut stmt = conn.prepare(&sql)?;
             let refs: Vec<&dyn rusqlite::types::ToSql> =
                 params.iter().map(|p| p.as_ref()).collect();
-            let rows = stmt.query_map(refs.as_slice(), |row| {
-                Ok(MessageRow {
-                    id: row.get(0)?,
-                    channel_id: row.get(1)?,
-                    author_id: row.get(2)?,
-                    author_username: row
-                        .get::<_, Option<String>>(3)?
-                        .unwrap_or_else(|| "unknown".to_string()),
-                    ciphertext: row.get(4)?,
-                    nonce: row.get(5)?,
-                    created_at: row.get(6)?,
-                })
-            })?;
+            let rows = stmt.query_map(refs.as_slice(), map_message_row)?;
             rows.collect::<std::result::Result<Vec<_>, _>>()
                 .map_err(Into::into)
         })
@@ -448,6 +482,31 @@ impl Database {
         })
     }
 
+    /// Offers made within the last `max_age_secs` that are still pending or
+    /// were accepted, one row per recipient, oldest first.
+    pub fn list_open_offers(&self, max_age_secs: i64) -> Result<Vec<OpenOfferRow>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT transfer_id, from_user_id, to_user_id, status,
+                        CAST(strftime('%s', 'now') - strftime('%s', created_at) AS INTEGER)
+                 FROM pending_offers
+                 WHERE status IN ('pending', 'accepted')
+                   AND created_at > datetime('now', '-' || ?1 || ' seconds')
+                 ORDER BY created_at ASC, id ASC",
+            )?;
+            let rows = stmt.query_map([max_age_secs], |row| {
+                Ok(OpenOfferRow {
+                    transfer_id: row.get(0)?,
+                    from_user_id: row.get(1)?,
+                    to_user_id: row.get(2)?,
+                    status: row.get(3)?,
+                    age_secs: row.get(4)?,
+                })
+            })?;
+            rows.collect::<std::result::Result<Vec<_>, _>>().map_err(Into::into)
+        })
+    }
+
     /// Delete a pending offer by transfer_id (admin).
     pub fn delete_pending_offer(&self, transfer_id: &str) -> Result<()> {
         self.with_conn_mut(|conn| {
@@ -517,7 +576,7 @@ fn query_user_by_username(conn: &Connection, username: &str) -> Result<Option<Us
 fn query_messages(conn: &Connection, channel_id: &str, limit: u32, before: Option<&str>) -> Result<Vec<MessageRow>> {
     let (sql, params_vec): (String, Vec<Box<dyn rusqlite::types::ToSql>>) = match before {
         Some(before_ts) => (
-            "SELECT m.id, m.channel_id, m.author_id, u.username, m.ciphertext, m.nonce, m.created_at
+            "SELECT m.id, m.channel_id, m.author_id, u.username, m.ciphertext, m.nonce, m.created_at, m.seq
              FROM messages m
              LEFT JOIN users u ON m.author_id = u.id
              WHERE m.channel_id = ?1 AND m.created_at < ?3
@@ -530,7 +589,7 @@ fn query_messages(conn: &Connection, channel_id: &str, limit: u32, before: Optio
             ],
         ),
         None => (
-            "SELECT m.id, m.channel_id, m.author_id, u.username, m.ciphertext, m.nonce, m.created_at
+            "SELECT m.id, m.channel_id, m.author_id, u.username, m.ciphertext, m.nonce, m.created_at, m.seq
              FROM messages m
              LEFT JOIN users u ON m.author_id = u.id
              WHERE m.channel_id = ?1
@@ -547,22 +606,26 @@ fn query_messages(conn: &Connection, channel_id: &str, limit: u32, before: Optio
     let params_refs: Vec<&dyn rusqlite::types::ToSql> = params_vec.iter().map(|p| p.as_ref()).collect();
 
     let rows = stmt
-        .query_map(params_refs.as_slice(), |row| {
-            Ok(MessageRow {
-                id: row.get(0)?,
-                channel_id: row.get(1)?,
-                author_id: row.get(2)?,
-                author_username: row.get::<_, Option<String>>(3)?.unwrap_or_else(|| "unknown".to_string()),
-                ciphertext: row.get(4)?,
-                nonce: row.get(5)?,
-                created_at: row.get(6)?,
-            })
-        })?
+        .query_map(params_refs.as_slice(), map_message_row)?
         .collect::<std::result::Result<Vec<_>, _>>()?;
 
     Ok(rows)
 }
 
+/// Map a row selected as (id, channel_id, author_id, username, ciphertext, nonce, created_at, seq).
+fn map_message_row(row: &rusqlite::Row) -> rusqlite::Result<MessageRow> {
+    Ok(MessageRow {
+        id: row.get(0)?,
+        channel_id: row.get(1)?,
+        author_id: row.get(2)?,
+        author_username: row.get::<_, Option<String>>(3)?.unwrap_or_else(|| "unknown".to_string()),
+        ciphertext: row.get(4)?,
+        nonce: row.get(5)?,
+        created_at: row.get(6)?,
+        seq: row.get(7)?,
+    })
+}
+
 /// Extension trait for optional query results
 trait OptionalExt<T> {
     fn optional(self) -> Result<Option<T>>;
@@ -577,3 +640,98 @@ impl<T> OptionalExt<T> for std::result::Result<T, rusqlite::Error> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GENERAL: &str = "00000000-0000-0000-0000-000000000001";
+
+    #[test]
+    fn test_messages_since_replays_in_order_and_reports_gap() {
+        let dir = std::env::temp_dir().join(format!("haven-db-resume-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::open(&dir.join("test.db")).unwrap();
+        db.create_user("u1", "alice", "hash").unwrap();
+
+        for i in 0..5 {
+            let seq = db.insert_message(&format!("m{i}"), GENERAL, "u1", b"ct", b"nonce").unwrap();
+            assert_eq!(seq, i + 1);
+        }
+        // Sequences are per channel.
+        db.create_channel("c2", "other").unwrap();
+        assert_eq!(db.insert_message("x0", "c2", "u1", b"ct", b"nonce").unwrap(), 1);
+
+        let missed = db.get_messages_since(GENERAL, 2, 10).unwrap().unwrap();
+        assert_eq!(missed.iter().map(|m| m.seq).collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert!(db.get_messages_since(GENERAL, 5, 10).unwrap().unwrap().is_empty());
+
+        // Too far behind, or ahead of what this database has seen.
+        assert!(db.get_messages_since(GENERAL, 0, 3).unwrap().is_none());
+        assert!(db.get_messages_since(GENERAL, 6, 10).unwrap().is_none());
+        assert!(db.get_messages_since(GENERAL, i64::MIN, 10).unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_concurrent_inserts_get_distinct_increasing_seqs() {
+        let dir = std::env::temp_dir().join(format!("haven-db-seq-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = std::sync::Arc::new(Database::open(&dir.join("test.db")).unwrap());
+        db.create_user("u1", "alice", "hash").unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    (0..25)
+                        .map(|i| db.insert_message(&format!("m{t}-{i}"), GENERAL, "u1", b"ct", b"nonce").unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut all = Vec::new();
+        for h in handles {
+            let seqs = h.join().unwrap();
+            // Each writer sees its own inserts in order.
+            assert!(seqs.windows(2).all(|w| w[0] < w[1]), "{seqs:?}");
+            all.extend(seqs);
+        }
+        all.sort();
+        assert_eq!(all, (1..=200).collect::<Vec<i64>>());
+
+        let stored = db.get_messages_since(GENERAL, 0, 200).unwrap().unwrap();
+        assert_eq!(stored.iter().map(|m| m.seq).collect::<Vec<_>>(), all);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_open_offers_skip_answered_and_old_ones() {
+        let dir = std::env::temp_dir().join(format!("haven-db-offers-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::open(&dir.join("test.db")).unwrap();
+
+        for (transfer, to) in [("t1", "u2"), ("t4", "u3"), ("t2", "u2"), ("t3", "u2")] {
+            db.insert_pending_offer(transfer, "u1", to, "f.bin", 1, None, None, None, None).unwrap();
+        }
+        db.update_pending_offer_status_for("t4", "u3", "accepted").unwrap();
+        db.update_pending_offer_status("t2", "rejected").unwrap();
+        db.with_conn_mut(|conn| {
+            conn.execute("UPDATE pending_offers SET created_at = datetime('now', '-2 days') WHERE transfer_id = 't3'", [])?;
+            Ok(())
+        })
+        .unwrap();
+
+        let open = db.list_open_offers(24 * 60 * 60).unwrap();
+        let seen: Vec<_> = open.iter().map(|o| (o.transfer_id.as_str(), o.to_user_id.as_str(), o.status.as_str())).collect();
+        assert_eq!(seen, vec![("t1", "u2", "pending"), ("t4", "u3", "accepted")]);
+        assert!(open.iter().all(|o| (0..5).contains(&o.age_secs)));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,269 @@
+//! Which AEAD seals a transfer's chunks.
+//!
+//! AES-256-GCM is the default and the fastest choice on CPUs with AES
+//! instructions. Without them (older ARM, many phones) it falls back to
+//! constant-time software and ChaCha20-Poly1305 is several times quicker.
+//! Both take a 32-byte key and a 12-byte nonce and append a 16-byte tag, so a
+//! chunk is `[nonce 12][ciphertext + tag]` either way.
+//!
+//! The uploader picks one per transfer and sends its [`AeadAlgorithm::id`]
+//! byte in `FastUploadStart`; the file server stores it with the transfer and
+//! hands it back to downloaders, who open chunks with the same algorithm.
+//! ChaCha20-Poly1305 nonces are derived with their own domain tag, so a file
+//! sent once under each algorithm with one key never reuses a nonce.
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use sha2::{Digest, Sha256};
+
+use crate::compress::compressed_chunk_nonce;
+use crate::protocol::ENCRYPTION_OVERHEAD;
+use crate::sender::chunk_nonce;
+
+/// Nonce domain tag for ChaCha20-Poly1305 chunks.
+const CHACHA_NONCE_CONTEXT: &[u8] = b"chacha20-poly1305";
+
+/// AEAD algorithm of a transfer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum AeadAlgorithm {
+    #[default]
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl AeadAlgorithm {
+    /// The per-transfer algorithm byte sent in `FastUploadStart`.
+    pub const fn id(self) -> u8 {
+        match self {
+            AeadAlgorithm::Aes256Gcm => 0,
+            AeadAlgorithm::ChaCha20Poly1305 => 1,
+        }
+    }
+
+    /// Parse an algorithm byte. `None` for one this build doesn't know.
+    pub const fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(AeadAlgorithm::Aes256Gcm),
+            1 => Some(AeadAlgorithm::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            AeadAlgorithm::Aes256Gcm => "AES-256-GCM",
+            AeadAlgorithm::ChaCha20Poly1305 => "ChaCha20-Poly1305",
+        }
+    }
+
+    /// The faster algorithm on this CPU: AES-256-GCM with hardware AES,
+    /// ChaCha20-Poly1305 without.
+    pub fn preferred() -> Self {
+        if hardware_aes() { AeadAlgorithm::Aes256Gcm } else { AeadAlgorithm::ChaCha20Poly1305 }
+    }
+
+    /// Bytes a sealed chunk adds to its plaintext: nonce plus tag.
+    pub const fn overhead(self) -> usize {
+        match self {
+            AeadAlgorithm::Aes256Gcm | AeadAlgorithm::ChaCha20Poly1305 => ENCRYPTION_OVERHEAD,
+        }
+    }
+
+    /// Sealed size of a chunk with `chunk_size` plaintext bytes.
+    pub const fn encrypted_chunk_size(self, chunk_size: usize) -> usize {
+        chunk_size + self.overhead()
+    }
+
+    /// Nonce for chunk `chunk_index`. AES-256-GCM keeps [`chunk_nonce`], so
+    /// existing transfers hash the same.
+    pub fn chunk_nonce(self, key: &[u8; 32], chunk_index: u32, chunk_size: usize) -> [u8; 12] {
+        match self {
+            AeadAlgorithm::Aes256Gcm => chunk_nonce(key, chunk_index, chunk_size),
+            AeadAlgorithm::ChaCha20Poly1305 => tagged_nonce(key, chunk_index, chunk_size, b""),
+        }
+    }
+
+    /// Nonce for a compressed slot; see [`compressed_chunk_nonce`].
+    pub fn compressed_chunk_nonce(self, key: &[u8; 32], chunk_index: u32, chunk_size: usize) -> [u8; 12] {
+        match self {
+            AeadAlgorithm::Aes256Gcm => compressed_chunk_nonce(key, chunk_index, chunk_size),
+            AeadAlgorithm::ChaCha20Poly1305 => tagged_nonce(key, chunk_index, chunk_size, b"zstd"),
+        }
+    }
+}
+
+fn tagged_nonce(key: &[u8; 32], chunk_index: u32, chunk_size: usize, suffix: &[u8]) -> [u8; 12] {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    hasher.update((chunk_index as u64).to_le_bytes());
+    hasher.update((chunk_size as u64).to_le_bytes());
+    hasher.update(CHACHA_NONCE_CONTEXT);
+    hasher.update(suffix);
+    let hash = hasher.finalize();
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&hash[..12]);
+    nonce
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn hardware_aes() -> bool {
+    std::arch::is_x86_feature_detected!("aes") && std::arch::is_x86_feature_detected!("pclmulqdq")
+}
+
+#[cfg(target_arch = "aarch64")]
+fn hardware_aes() -> bool {
+    std::arch::is_aarch64_feature_detected!("aes")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn hardware_aes() -> bool {
+    false
+}
+
+/// A keyed cipher for one transfer's algorithm. The AES key schedule is
+/// large, so it's boxed.
+#[derive(Clone)]
+pub enum ChunkCipher {
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+}
+
+impl ChunkCipher {
+    pub fn new(algorithm: AeadAlgorithm, key: &[u8; 32]) -> Self {
+        match algorithm {
+            AeadAlgorithm::Aes256Gcm => ChunkCipher::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+            AeadAlgorithm::ChaCha20Poly1305 => ChunkCipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key.into())),
+        }
+    }
+
+    pub fn algorithm(&self) -> AeadAlgorithm {
+        match self {
+            ChunkCipher::Aes256Gcm(_) => AeadAlgorithm::Aes256Gcm,
+            ChunkCipher::ChaCha20Poly1305(_) => AeadAlgorithm::ChaCha20Poly1305,
+        }
+    }
+
+    /// Encrypt `msg`, authenticating `aad` with it. Returns ciphertext + tag.
+    pub fn encrypt(&self, nonce: &[u8; 12], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+        let payload = Payload { msg, aad };
+        match self {
+            ChunkCipher::Aes256Gcm(c) => c.encrypt(nonce.into(), payload),
+            ChunkCipher::ChaCha20Poly1305(c) => c.encrypt(nonce.into(), payload),
+        }
+        .map_err(|e| format!("{} encrypt: {}", self.algorithm().name(), e))
+    }
+
+    /// Decrypt ciphertext + tag, failing if it or `aad` was altered.
+    pub fn decrypt(&self, nonce: &[u8; 12], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+        let payload = Payload { msg: ciphertext, aad };
+        match self {
+            ChunkCipher::Aes256Gcm(c) => c.decrypt(nonce.into(), payload),
+            ChunkCipher::ChaCha20Poly1305(c) => c.decrypt(nonce.into(), payload),
+        }
+        .map_err(|e| format!("{} decrypt: {}", self.algorithm().name(), e))
+    }
+
+    /// Seal chunk `chunk_index` as `[nonce 12][ciphertext + tag]`.
+    pub fn seal_chunk(&self, key: &[u8; 32], chunk_index: u32, chunk_size: usize, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = self.algorithm().chunk_nonce(key, chunk_index, chunk_size);
+        let ciphertext = self
+            .encrypt(&nonce, plaintext, &[])
+            .map_err(|e| format!("Encrypt chunk {}: {}", chunk_index, e))?;
+        let mut sealed = Vec::with_capacity(12 + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Open a chunk sealed by [`seal_chunk`](Self::seal_chunk).
+    pub fn open_chunk(&self, sealed: &[u8]) -> Result<Vec<u8>, String> {
+        if sealed.len() < self.algorithm().overhead() {
+            return Err("Encrypted chunk too short".into());
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        self.decrypt(nonce.try_into().unwrap(), ciphertext, &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::harness::{self, Loopback, KEY};
+    use crate::protocol::MIN_CHUNK_SIZE;
+    use crate::receiver::ReceiverConfig;
+    use crate::sender::{SenderConfig, SenderProgress};
+
+    /// Blast `plaintext` over loopback sealed with `aead` and return what the
+    /// receiver stored.
+    fn blast(aead: AeadAlgorithm, plaintext: &[u8]) -> Vec<u8> {
+        let dir = harness::scratch_dir(&format!("aead-{}", aead.id()));
+        let input = dir.join("in.bin");
+        let output = dir.join("out.bin");
+        std::fs::write(&input, plaintext).unwrap();
+
+        let sealed = harness::seal(plaintext, MIN_CHUNK_SIZE, aead);
+        let receiver = Loopback::start(ReceiverConfig {
+            aead,
+            ..harness::receiver_config(&output, &sealed, aead.encrypted_chunk_size(MIN_CHUNK_SIZE))
+        });
+        let result = receiver
+            .send(
+                SenderConfig { aead, ..harness::sender_config(&input, receiver.addr, MIN_CHUNK_SIZE) },
+                Arc::new(SenderProgress::new()),
+            )
+            .unwrap();
+        receiver.join().unwrap();
+
+        // The sender's own sealing matches the hashes computed up front.
+        let stored = std::fs::read(&output).unwrap();
+        assert_eq!(stored, sealed);
+        assert_eq!(result.encrypted_size, stored.len() as u64);
+        let _ = std::fs::remove_dir_all(&dir);
+        stored
+    }
+
+    #[test]
+    fn test_round_trip_under_each_algorithm() {
+        let plaintext: Vec<u8> = (0..3 * MIN_CHUNK_SIZE as u32 + 777).map(|i| (i * 13 % 241) as u8).collect();
+        for aead in [AeadAlgorithm::Aes256Gcm, AeadAlgorithm::ChaCha20Poly1305] {
+            let stored = blast(aead, &plaintext);
+            let cipher = ChunkCipher::new(aead, &KEY);
+            let opened: Vec<u8> = stored
+                .chunks(aead.encrypted_chunk_size(MIN_CHUNK_SIZE))
+                .flat_map(|chunk| cipher.open_chunk(chunk).unwrap())
+                .collect();
+            assert_eq!(opened, plaintext, "{}", aead.name());
+        }
+    }
+
+    #[test]
+    fn test_ids_round_trip() {
+        for algorithm in [AeadAlgorithm::Aes256Gcm, AeadAlgorithm::ChaCha20Poly1305] {
+            assert_eq!(AeadAlgorithm::from_id(algorithm.id()), Some(algorithm));
+        }
+        assert_eq!(AeadAlgorithm::from_id(2), None);
+        assert_eq!(AeadAlgorithm::default().id(), 0, "absent byte means AES-256-GCM");
+    }
+
+    #[test]
+    fn test_chunk_sealed_under_one_algorithm_fails_under_the_other() {
+        let key = [4u8; 32];
+        let aes = ChunkCipher::new(AeadAlgorithm::Aes256Gcm, &key);
+        let chacha = ChunkCipher::new(AeadAlgorithm::ChaCha20Poly1305, &key);
+
+        let sealed = chacha.seal_chunk(&key, 3, 1024, b"chunk three").unwrap();
+        assert_eq!(sealed.len(), AeadAlgorithm::ChaCha20Poly1305.encrypted_chunk_size(11));
+        assert_eq!(chacha.open_chunk(&sealed).unwrap(), b"chunk three");
+        assert!(aes.open_chunk(&sealed).is_err());
+        assert!(chacha.open_chunk(&aes.seal_chunk(&key, 3, 1024, b"chunk three").unwrap()).is_err());
+
+        // Separate nonces, so the same chunk under each never shares one.
+        assert_ne!(
+            AeadAlgorithm::Aes256Gcm.chunk_nonce(&key, 3, 1024),
+            AeadAlgorithm::ChaCha20Poly1305.chunk_nonce(&key, 3, 1024)
+        );
+    }
+}
@@ -1,17 +1,17 @@
-/// Per-chunk frame tracking using a compact bitfield.
-///
-/// Each chunk can have up to MAX_FRAMES_PER_CHUNK frames (2997 for 4MB chunks).
-/// We use `[u64; 47]` = 3008 bits, enough to track all frames.
-
-use crate::protocol::MAX_FRAMES_PER_CHUNK;
-
-/// Number of u64 words needed: ceil(MAX_FRAMES_PER_CHUNK / 64).
-const BITFIELD_WORDS: usize = (MAX_FRAMES_PER_CHUNK + 63) / 64;
+//! Per-chunk frame tracking using a compact bitfield.
+//!
+//! Chunk size is negotiated per transfer, so the frame count varies (2997 for
+//! 4MB chunks). The bitfield is sized to the chunk: ceil(frame_count / 64) words.
+
+/// Number of u64 words needed to track `frame_count` frames.
+fn words_for(frame_count: u16) -> usize {
+    (frame_count as usize).div_ceil(64)
+}
 
 /// Compact bitfield tracking which frames have been received for a chunk.
 #[derive(Clone)]
 pub struct ChunkBitfield {
-    bits: [u64; BITFIELD_WORDS],
+    bits: Vec<u64>,
     frame_count: u16,
     received_count: u16,
 }
@@ -20,7 +20,7 @@ impl ChunkBitfield {
     /// Create a new bitfield for a chunk with `frame_count` frames.
     pub fn new(frame_count: u16) -> Self {
         Self {
-            bits: [0u64; BITFIELD_WORDS],
+            bits: vec![0u64; words_for(frame_count)],
             frame_count,
             received_count: 0,
         }
@@ -32,7 +32,7 @@ impl ChunkBitfield {
         let idx = frame_index as usize;
         let word = idx / 64;
         let bit = idx % 64;
-        if word >= BITFIELD_WORDS {
+        if word >= self.bits.len() || frame_index >= self.frame_count {
             return false;
         }
         let mask = 1u64 << bit;
@@ -50,7 +50,7 @@ impl ChunkBitfield {
         let idx = frame_index as usize;
         let word = idx / 64;
         let bit = idx % 64;
-        if word >= BITFIELD_WORDS {
+        if word >= self.bits.len() {
             return false;
         }
         self.bits[word] & (1u64 << bit) != 0
@@ -93,7 +93,8 @@ impl ChunkBitfield {
 
     /// Reset the bitfield for reuse.
     pub fn reset(&mut self, frame_count: u16) {
-        self.bits = [0u64; BITFIELD_WORDS];
+        self.bits.clear();
+        self.bits.resize(words_for(frame_count), 0);
         self.frame_count = frame_count;
         self.received_count = 0;
     }
@@ -102,6 +103,7 @@ impl ChunkBitfield {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::MAX_FRAMES_PER_CHUNK;
 
     #[test]
     fn test_basic_operations() {
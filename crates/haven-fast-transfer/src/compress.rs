@@ -0,0 +1,216 @@
+//! Optional per-chunk zstd compression ahead of encryption.
+//!
+//! When a transfer negotiates `compression`, every chunk is stored in a
+//! fixed-size slot so the receiver can keep writing at `chunk_index *
+//! chunk_size`:
+//!
+//! ```text
+//! [nonce 12][header 4][ciphertext + tag][zero padding to slot size]
+//! ```
+//!
+//! The header is a little-endian u32: bit 31 is set when the body is zstd
+//! output, the low bits hold the ciphertext+tag length. It is bound to the
+//! ciphertext as AEAD associated data. Chunks that don't shrink are sealed
+//! raw with the bit clear.
+//!
+//! Chunk and file hashes cover the whole slot, padding included, so the
+//! receiver's integrity checks are unchanged. Only the unpadded prefix is
+//! blasted; the receiver's chunk buffers start zeroed, which restores the
+//! padding before the hash is checked.
+
+use sha2::{Digest, Sha256};
+
+use crate::aead::ChunkCipher;
+use crate::protocol::*;
+
+/// Slot header bytes between the nonce and the ciphertext.
+pub const COMPRESSED_HEADER: usize = 4;
+
+/// Per-chunk overhead of a compressed slot over its plaintext.
+pub const COMPRESSED_CHUNK_OVERHEAD: usize = ENCRYPTION_OVERHEAD + COMPRESSED_HEADER;
+
+/// zstd level: fast enough to keep up with the blaster on one core.
+pub const COMPRESSION_LEVEL: i32 = 3;
+
+const FLAG_ZSTD: u32 = 1 << 31;
+
+/// Slot size of a chunk with `chunk_size` plaintext bytes.
+pub const fn compressed_slot_size(chunk_size: usize) -> usize {
+    chunk_size + COMPRESSED_CHUNK_OVERHEAD
+}
+
+/// A sealed chunk: the full zero-padded slot, and how much of it carries data.
+pub struct SealedChunk {
+    pub slot: Vec<u8>,
+    pub wire_len: usize,
+}
+
+/// Derive the AES-256-GCM nonce for a compressed slot. Separate from
+/// `chunk_nonce` so a file sent both ways under one key never reuses a nonce.
+/// `AeadAlgorithm::compressed_chunk_nonce` picks the one for each algorithm.
+pub fn compressed_chunk_nonce(key: &[u8; 32], chunk_index: u32, chunk_size: usize) -> [u8; 12] {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    hasher.update((chunk_index as u64).to_le_bytes());
+    hasher.update((chunk_size as u64).to_le_bytes());
+    hasher.update(b"zstd");
+    let hash = hasher.finalize();
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&hash[..12]);
+    nonce
+}
+
+/// Compress (if it helps) and encrypt one chunk into its slot. `chunk_size`
+/// is the transfer's plaintext chunk size, used for the nonce.
+pub fn seal_compressed_chunk(
+    cipher: &ChunkCipher,
+    key: &[u8; 32],
+    chunk_index: u32,
+    chunk_size: usize,
+    plaintext: &[u8],
+) -> Result<SealedChunk, String> {
+    let compressed = zstd::bulk::compress(plaintext, COMPRESSION_LEVEL)
+        .map_err(|e| format!("Compress chunk {}: {}", chunk_index, e))?;
+    let (flag, body) = if compressed.len() < plaintext.len() {
+        (FLAG_ZSTD, compressed.as_slice())
+    } else {
+        (0, plaintext)
+    };
+
+    let ct_len = (body.len() + 16) as u32;
+    let header = (flag | ct_len).to_le_bytes();
+    let nonce = cipher.algorithm().compressed_chunk_nonce(key, chunk_index, chunk_size);
+    let ciphertext = cipher
+        .encrypt(&nonce, body, &header)
+        .map_err(|e| format!("Encrypt chunk {}: {}", chunk_index, e))?;
+
+    let mut slot = Vec::with_capacity(compressed_slot_size(plaintext.len()));
+    slot.extend_from_slice(&nonce);
+    slot.extend_from_slice(&header);
+    slot.extend_from_slice(&ciphertext);
+    let wire_len = slot.len();
+    slot.resize(compressed_slot_size(plaintext.len()), 0);
+    Ok(SealedChunk { slot, wire_len })
+}
+
+/// Decrypt and, if flagged, decompress one slot. Output larger than
+/// `max_plaintext` is rejected rather than allocated.
+pub fn open_compressed_chunk(cipher: &ChunkCipher, slot: &[u8], max_plaintext: usize) -> Result<Vec<u8>, String> {
+    if slot.len() < COMPRESSED_CHUNK_OVERHEAD {
+        return Err("Compressed chunk too short".into());
+    }
+    let (nonce, rest) = slot.split_at(12);
+    let (header, rest) = rest.split_at(COMPRESSED_HEADER);
+    let header_bits = u32::from_le_bytes(header.try_into().unwrap());
+    let ct_len = (header_bits & !FLAG_ZSTD) as usize;
+    if ct_len < 16 || ct_len > rest.len() {
+        return Err(format!("Compressed chunk length {} out of range", ct_len));
+    }
+
+    let body = cipher
+        .decrypt(nonce.try_into().unwrap(), &rest[..ct_len], header)
+        .map_err(|e| format!("Decryption failed: {}", e))?;
+    if header_bits & FLAG_ZSTD == 0 {
+        return Ok(body);
+    }
+    zstd::bulk::decompress(&body, max_plaintext).map_err(|e| format!("Decompression failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::aead::AeadAlgorithm;
+    use crate::harness::{self, Loopback, KEY};
+    use crate::receiver::ReceiverConfig;
+    use crate::sender::{SendResult, SenderConfig, SenderProgress};
+
+    /// Send `plaintext` compressed over loopback and return what the receiver
+    /// stored plus the sender's result.
+    fn round_trip(name: &str, plaintext: &[u8], chunk_size: usize, aead: AeadAlgorithm) -> (Vec<u8>, SendResult) {
+        let dir = harness::scratch_dir(&format!("zstd-{}", name));
+        let input = dir.join("in.bin");
+        let output = dir.join("out.bin");
+        std::fs::write(&input, plaintext).unwrap();
+
+        let cipher = ChunkCipher::new(aead, &KEY);
+        let slots: Vec<u8> = plaintext
+            .chunks(chunk_size)
+            .enumerate()
+            .flat_map(|(idx, chunk)| seal_compressed_chunk(&cipher, &KEY, idx as u32, chunk_size, chunk).unwrap().slot)
+            .collect();
+
+        let receiver = Loopback::start(ReceiverConfig {
+            compressed: true,
+            aead,
+            ..harness::receiver_config(&output, &slots, compressed_slot_size(chunk_size))
+        });
+        let result = receiver
+            .send(
+                SenderConfig { compress: true, aead, ..harness::sender_config(&input, receiver.addr, chunk_size) },
+                Arc::new(SenderProgress::new()),
+            )
+            .unwrap();
+        receiver.join().unwrap();
+
+        let stored = std::fs::read(&output).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        (stored, result)
+    }
+
+    fn open_all(stored: &[u8], chunk_size: usize, aead: AeadAlgorithm) -> Vec<u8> {
+        let cipher = ChunkCipher::new(aead, &KEY);
+        stored
+            .chunks(compressed_slot_size(chunk_size))
+            .flat_map(|slot| open_compressed_chunk(&cipher, slot, chunk_size).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_compressed_round_trip() {
+        let chunk_size = MIN_CHUNK_SIZE;
+
+        // Log-like text squeezes to a fraction of a chunk.
+        let text: Vec<u8> = (0..200_000u32)
+            .flat_map(|i| format!("{i:08} INFO request served\n").into_bytes())
+            .take(3 * chunk_size + 1234)
+            .collect();
+        let (stored, result) = round_trip("text", &text, chunk_size, AeadAlgorithm::Aes256Gcm);
+        assert_eq!(result.encrypted_size, stored.len() as u64);
+        assert_eq!(open_all(&stored, chunk_size, AeadAlgorithm::Aes256Gcm), text);
+        let (stored, _) = round_trip("text-chacha", &text, chunk_size, AeadAlgorithm::ChaCha20Poly1305);
+        assert_eq!(open_all(&stored, chunk_size, AeadAlgorithm::ChaCha20Poly1305), text);
+
+        // Pseudo-random bytes don't shrink and go through raw.
+        let mut state = 0x9e3779b9u32;
+        let noise: Vec<u8> = (0..2 * chunk_size + 99)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let (stored, _) = round_trip("noise", &noise, chunk_size, AeadAlgorithm::Aes256Gcm);
+        assert_eq!(open_all(&stored, chunk_size, AeadAlgorithm::Aes256Gcm), noise);
+    }
+
+    #[test]
+    fn test_seal_marks_and_bounds_chunks() {
+        let key = [1u8; 32];
+        let cipher = ChunkCipher::new(AeadAlgorithm::Aes256Gcm, &key);
+
+        let zeros = vec![0u8; MIN_CHUNK_SIZE];
+        let sealed = seal_compressed_chunk(&cipher, &key, 0, MIN_CHUNK_SIZE, &zeros).unwrap();
+        assert_eq!(sealed.slot.len(), compressed_slot_size(MIN_CHUNK_SIZE));
+        assert!(sealed.wire_len < 1024);
+        assert_ne!(sealed.slot[15] & 0x80, 0);
+        // A smaller bound than the real plaintext refuses to inflate it.
+        assert!(open_compressed_chunk(&cipher, &sealed.slot, MIN_CHUNK_SIZE / 2).is_err());
+
+        let mut tampered = sealed.slot.clone();
+        tampered[15] ^= 0x80;
+        assert!(open_compressed_chunk(&cipher, &tampered, MIN_CHUNK_SIZE).is_err());
+    }
+}
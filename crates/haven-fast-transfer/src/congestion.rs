@@ -0,0 +1,397 @@
+//! Send-rate control for the blast sender.
+//!
+//! A transfer starts in slow start at `SLOW_START_RATE_BPS` and doubles its
+//! rate every round trip until the first loss, until round trips stretch
+//! (queues filling), or until it reaches the top rate. It then hands over to
+//! the steady state: back off by `RATE_DECREASE` on heavy loss, creep up by
+//! `RATE_INCREASE` per ACK.
+//!
+//! Round trips are timed from a chunk's blast to its ACK. Until one has been
+//! measured a round counts as `DEFAULT_RTT_MS`, so a receiver that ACKs late
+//! still sees the rate climb.
+//!
+//! That loss-driven scheme is [`CongestionController`]. The sender drives
+//! whichever [`CongestionControl`] its [`CongestionConfig`] picks; the
+//! alternative, [`DelayController`], reacts to round trips stretching
+//! instead of waiting for queues to overflow.
+
+use std::time::{Duration, Instant};
+
+use crate::protocol::{
+    ACK_FLUSH_INTERVAL_MS, DEFAULT_RTT_MS, DELAY_RTT_INFLATION, INITIAL_RATE_BPS, LOSS_THRESHOLD_HIGH,
+    RATE_DECREASE, RATE_INCREASE, SLOW_START_RATE_BPS, SLOW_START_RTT_INFLATION,
+};
+
+/// The events the blaster feeds a rate controller, and the rate it paces
+/// frames at.
+pub trait CongestionControl: Send {
+    /// Called before each chunk goes out.
+    fn on_tick(&mut self, now: Instant);
+
+    /// A chunk's blast-to-ACK time.
+    fn on_rtt_sample(&mut self, rtt: Duration);
+
+    /// A NACK reporting `loss_pct` of a chunk's frames missing.
+    fn on_loss(&mut self, loss_pct: f64);
+
+    /// The receiver's assembler is falling behind (a slowdown NACK).
+    fn on_slowdown(&mut self);
+
+    /// A chunk ACK arrived.
+    fn on_ack(&mut self);
+
+    /// Current send rate in bytes/s.
+    fn rate_bps(&self) -> u64;
+
+    /// Gap between `frame_bytes`-byte frames at the current rate.
+    fn packet_interval(&self, frame_bytes: usize) -> Duration {
+        packet_interval(self.rate_bps(), frame_bytes)
+    }
+}
+
+/// Gap between `frame_bytes`-byte frames sent at `rate_bps`; zero (no
+/// pacing) for a zero rate.
+pub fn packet_interval(rate_bps: u64, frame_bytes: usize) -> Duration {
+    if rate_bps == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_nanos((frame_bytes as u128 * 1_000_000_000 / rate_bps as u128) as u64)
+}
+
+/// Which controller a sender runs (`SenderConfig::congestion`).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CongestionConfig {
+    /// [`CongestionController`]: slow start, then loss-driven AIMD.
+    #[default]
+    Aimd,
+    /// [`DelayController`], backing off once a round trip takes more than
+    /// `rtt_inflation` times the shortest seen.
+    Delay { rtt_inflation: f64 },
+}
+
+impl CongestionConfig {
+    /// [`CongestionConfig::Delay`] at `DELAY_RTT_INFLATION`.
+    pub fn delay() -> Self {
+        CongestionConfig::Delay { rtt_inflation: DELAY_RTT_INFLATION }
+    }
+
+    /// A fresh controller of this kind, starting its first round at `now`.
+    pub fn build(self, now: Instant) -> Box<dyn CongestionControl> {
+        match self {
+            CongestionConfig::Aimd => Box::new(CongestionController::new(now)),
+            CongestionConfig::Delay { rtt_inflation } => Box::new(DelayController::new(rtt_inflation)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    SlowStart,
+    Steady,
+}
+
+pub struct CongestionController {
+    rate_bps: u64,
+    max_rate_bps: u64,
+    phase: Phase,
+    min_rtt: Option<Duration>,
+    round_start: Instant,
+}
+
+impl CongestionController {
+    /// Slow start from `SLOW_START_RATE_BPS` up to `INITIAL_RATE_BPS`.
+    pub fn new(now: Instant) -> Self {
+        Self::with_rates(SLOW_START_RATE_BPS, INITIAL_RATE_BPS, now)
+    }
+
+    pub fn with_rates(start_rate_bps: u64, max_rate_bps: u64, now: Instant) -> Self {
+        Self {
+            rate_bps: start_rate_bps.min(max_rate_bps),
+            max_rate_bps,
+            phase: Phase::SlowStart,
+            min_rtt: None,
+            round_start: now,
+        }
+    }
+
+    /// Current send rate in bytes/s.
+    pub fn rate_bps(&self) -> u64 {
+        self.rate_bps
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    fn round(&self) -> Duration {
+        self.min_rtt.unwrap_or(Duration::from_millis(DEFAULT_RTT_MS))
+    }
+
+    /// Call before each chunk goes out: in slow start, doubles the rate once
+    /// a round trip has passed since the last doubling.
+    pub fn on_tick(&mut self, now: Instant) {
+        if self.phase != Phase::SlowStart || now.duration_since(self.round_start) < self.round() {
+            return;
+        }
+        self.round_start = now;
+        self.rate_bps = self.rate_bps.saturating_mul(2).min(self.max_rate_bps);
+        if self.rate_bps == self.max_rate_bps {
+            self.phase = Phase::Steady;
+        }
+    }
+
+    /// A chunk's blast-to-ACK time. Ends slow start if it is well above the
+    /// shortest seen.
+    pub fn on_rtt_sample(&mut self, rtt: Duration) {
+        let min_rtt = *self.min_rtt.get_or_insert(rtt);
+        if rtt < min_rtt {
+            self.min_rtt = Some(rtt);
+            return;
+        }
+        let inflated = min_rtt.mul_f64(SLOW_START_RTT_INFLATION) + Duration::from_millis(ACK_FLUSH_INTERVAL_MS);
+        if self.phase == Phase::SlowStart && rtt > inflated {
+            self.phase = Phase::Steady;
+        }
+    }
+
+    /// A NACK reporting `loss_pct` of a chunk's frames missing. The first
+    /// loss in slow start falls back to the last rate that didn't lose.
+    pub fn on_loss(&mut self, loss_pct: f64) {
+        match self.phase {
+            Phase::SlowStart if loss_pct > 0.0 => {
+                self.phase = Phase::Steady;
+                self.rate_bps = (self.rate_bps / 2).max(1);
+            }
+            Phase::Steady if loss_pct > LOSS_THRESHOLD_HIGH => {
+                self.rate_bps = (self.rate_bps as f64 * RATE_DECREASE) as u64;
+            }
+            _ => {}
+        }
+    }
+
+    /// The receiver's assembler is falling behind (a slowdown NACK): frames
+    /// are arriving faster than it can place them, whatever the network
+    /// does. Halves the rate and ends slow start.
+    pub fn on_slowdown(&mut self) {
+        self.phase = Phase::Steady;
+        self.rate_bps = (self.rate_bps / 2).max(1);
+    }
+
+    /// A chunk ACK arrived. Steady state edges the rate up.
+    pub fn on_ack(&mut self) {
+        if self.phase == Phase::Steady {
+            self.rate_bps = (self.rate_bps as f64 * RATE_INCREASE).min(self.max_rate_bps as f64) as u64;
+        }
+    }
+}
+
+impl CongestionControl for CongestionController {
+    fn on_tick(&mut self, now: Instant) {
+        CongestionController::on_tick(self, now)
+    }
+
+    fn on_rtt_sample(&mut self, rtt: Duration) {
+        CongestionController::on_rtt_sample(self, rtt)
+    }
+
+    fn on_loss(&mut self, loss_pct: f64) {
+        CongestionController::on_loss(self, loss_pct)
+    }
+
+    fn on_slowdown(&mut self) {
+        CongestionController::on_slowdown(self)
+    }
+
+    fn on_ack(&mut self) {
+        CongestionController::on_ack(self)
+    }
+
+    fn rate_bps(&self) -> u64 {
+        CongestionController::rate_bps(self)
+    }
+}
+
+/// Delay-based control: creeps up by `RATE_INCREASE` per ACK while round
+/// trips stay near the shortest seen, and backs off by `RATE_DECREASE` per
+/// ACK once they stretch past `rtt_inflation` times it (plus the receiver's
+/// ACK batching), before the queue behind them overflows into loss. Heavy
+/// loss and slowdown NACKs still back off as in [`CongestionController`].
+pub struct DelayController {
+    rate_bps: u64,
+    max_rate_bps: u64,
+    rtt_inflation: f64,
+    min_rtt: Option<Duration>,
+    inflated: bool,
+}
+
+impl DelayController {
+    /// From `SLOW_START_RATE_BPS` up to `INITIAL_RATE_BPS`.
+    pub fn new(rtt_inflation: f64) -> Self {
+        Self::with_rates(SLOW_START_RATE_BPS, INITIAL_RATE_BPS, rtt_inflation)
+    }
+
+    pub fn with_rates(start_rate_bps: u64, max_rate_bps: u64, rtt_inflation: f64) -> Self {
+        Self {
+            rate_bps: start_rate_bps.min(max_rate_bps),
+            max_rate_bps,
+            rtt_inflation,
+            min_rtt: None,
+            inflated: false,
+        }
+    }
+}
+
+impl CongestionControl for DelayController {
+    fn on_tick(&mut self, _now: Instant) {}
+
+    fn on_rtt_sample(&mut self, rtt: Duration) {
+        let min_rtt = *self.min_rtt.get_or_insert(rtt);
+        if rtt < min_rtt {
+            self.min_rtt = Some(rtt);
+        }
+        let threshold = min_rtt.mul_f64(self.rtt_inflation) + Duration::from_millis(ACK_FLUSH_INTERVAL_MS);
+        self.inflated = rtt > threshold;
+    }
+
+    fn on_loss(&mut self, loss_pct: f64) {
+        if loss_pct > LOSS_THRESHOLD_HIGH {
+            self.rate_bps = ((self.rate_bps as f64 * RATE_DECREASE) as u64).max(1);
+        }
+    }
+
+    fn on_slowdown(&mut self) {
+        self.rate_bps = (self.rate_bps / 2).max(1);
+    }
+
+    fn on_ack(&mut self) {
+        let factor = if self.inflated { RATE_DECREASE } else { RATE_INCREASE };
+        self.rate_bps = ((self.rate_bps as f64 * factor).min(self.max_rate_bps as f64) as u64).max(1);
+    }
+
+    fn rate_bps(&self) -> u64 {
+        self.rate_bps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slow_start_doubles_each_rtt_then_plateaus() {
+        let start = Instant::now();
+        let rtt = Duration::from_millis(10);
+        let mut cc = CongestionController::with_rates(1_000_000, 64_000_000, start);
+        cc.on_rtt_sample(rtt);
+
+        // A loss-free trace: one chunk per millisecond, each ACKed one RTT later.
+        let mut rates = vec![cc.rate_bps()];
+        for ms in 1..=120u64 {
+            cc.on_tick(start + Duration::from_millis(ms));
+            cc.on_rtt_sample(rtt);
+            cc.on_ack();
+            if ms % 10 == 0 {
+                rates.push(cc.rate_bps());
+            }
+        }
+
+        let doubling: Vec<u64> = (0..=6).map(|i| 1_000_000 << i).collect();
+        assert_eq!(rates[..7], doubling[..]);
+        assert!(rates[7..].iter().all(|&r| r == 64_000_000), "{:?}", rates);
+        assert_eq!(cc.phase(), Phase::Steady);
+    }
+
+    #[test]
+    fn test_slow_start_ends_on_loss_or_rtt_inflation() {
+        let start = Instant::now();
+        let mut cc = CongestionController::with_rates(1_000_000, 64_000_000, start);
+        cc.on_tick(start + Duration::from_millis(DEFAULT_RTT_MS));
+        assert_eq!(cc.rate_bps(), 2_000_000, "no RTT measured yet: default round");
+        cc.on_loss(0.02);
+        assert_eq!((cc.phase(), cc.rate_bps()), (Phase::Steady, 1_000_000));
+        cc.on_tick(start + Duration::from_secs(5));
+        assert_eq!(cc.rate_bps(), 1_000_000, "no more doubling");
+
+        let mut cc = CongestionController::with_rates(1_000_000, 64_000_000, start);
+        cc.on_rtt_sample(Duration::from_millis(5));
+        cc.on_rtt_sample(Duration::from_millis(40));
+        assert_eq!(cc.phase(), Phase::SlowStart, "within ACK batching slack");
+        cc.on_rtt_sample(Duration::from_millis(80));
+        assert_eq!((cc.phase(), cc.rate_bps()), (Phase::Steady, 1_000_000));
+    }
+
+    #[test]
+    fn test_slowdown_halves_even_in_steady_state() {
+        let start = Instant::now();
+        let mut cc = CongestionController::with_rates(8_000_000, 8_000_000, start);
+        cc.on_tick(start + Duration::from_secs(1));
+        assert_eq!(cc.phase(), Phase::Steady);
+        // Loss too small to act on, but the receiver can't keep up.
+        cc.on_loss(0.05);
+        assert_eq!(cc.rate_bps(), 8_000_000);
+        cc.on_slowdown();
+        cc.on_slowdown();
+        assert_eq!(cc.rate_bps(), 2_000_000);
+    }
+
+    /// A synthetic trace: `acks` ACKs timed at `rtt`, then a heavy-loss NACK,
+    /// recording the rate after every event.
+    fn drive(cc: &mut dyn CongestionControl, start: Instant, rtt: Duration, acks: u64) -> Vec<u64> {
+        let mut rates = Vec::new();
+        for ms in 1..=acks {
+            cc.on_tick(start + Duration::from_millis(ms));
+            cc.on_rtt_sample(rtt);
+            cc.on_ack();
+            rates.push(cc.rate_bps());
+        }
+        cc.on_loss(0.5);
+        rates.push(cc.rate_bps());
+        rates
+    }
+
+    #[test]
+    fn test_controllers_climb_without_loss_and_back_off_on_it() {
+        let start = Instant::now();
+        let controllers: Vec<(&str, Box<dyn CongestionControl>)> = vec![
+            ("aimd", Box::new(CongestionController::with_rates(1_000_000, 64_000_000, start))),
+            ("delay", Box::new(DelayController::with_rates(1_000_000, 64_000_000, DELAY_RTT_INFLATION))),
+        ];
+        for (name, mut cc) in controllers {
+            let rates = drive(cc.as_mut(), start, Duration::from_millis(10), 200);
+            let (climb, after_loss) = rates.split_at(rates.len() - 1);
+            assert!(climb.windows(2).all(|w| w[0] <= w[1]), "{name}: {climb:?}");
+            assert_eq!(climb.last(), Some(&64_000_000), "{name}");
+            assert!(after_loss[0] < 64_000_000, "{name}");
+            assert_eq!(cc.packet_interval(1_000), Duration::from_nanos(1_000 * 1_000_000_000 / after_loss[0]));
+        }
+    }
+
+    #[test]
+    fn test_delay_controller_backs_off_on_rtt_inflation_alone() {
+        let start = Instant::now();
+        let mut cc = DelayController::with_rates(8_000_000, 64_000_000, 2.0);
+        let rates = drive(&mut cc, start, Duration::from_millis(10), 5);
+        assert!(rates[..5].windows(2).all(|w| w[0] < w[1]), "{rates:?}");
+
+        // Round trips stretch to 100 ms, well past 2 × 10 ms + ACK batching:
+        // every ACK backs off, with no loss reported.
+        let mut cc = DelayController::with_rates(8_000_000, 64_000_000, 2.0);
+        cc.on_rtt_sample(Duration::from_millis(10));
+        let mut rates = vec![cc.rate_bps()];
+        for _ in 0..5 {
+            cc.on_rtt_sample(Duration::from_millis(100));
+            cc.on_ack();
+            rates.push(cc.rate_bps());
+        }
+        assert!(rates.windows(2).all(|w| w[0] > w[1]), "{rates:?}");
+
+        // The AIMD controller takes the same trace as growth.
+        let mut aimd = CongestionController::with_rates(8_000_000, 64_000_000, start);
+        aimd.on_tick(start + Duration::from_secs(1));
+        aimd.on_rtt_sample(Duration::from_millis(10));
+        let before = aimd.rate_bps();
+        aimd.on_rtt_sample(Duration::from_millis(100));
+        aimd.on_ack();
+        assert!(aimd.rate_bps() >= before);
+    }
+}
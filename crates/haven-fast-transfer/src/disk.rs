@@ -0,0 +1,109 @@
+//! Disk space helpers for the receiver.
+//!
+//! A disk-full error mid-transfer otherwise surfaces as a bare
+//! "Write error: No space left on device (os error 28)" and leaves a large
+//! preallocated file behind. These helpers let the receiver fail fast before
+//! allocating, and recognize ENOSPC specifically when it happens anyway.
+
+use std::io;
+use std::path::Path;
+
+/// Returns true if `err` means the target filesystem is out of space.
+pub fn is_disk_full(err: &io::Error) -> bool {
+    if err.kind() == io::ErrorKind::StorageFull {
+        return true;
+    }
+    match err.raw_os_error() {
+        #[cfg(unix)]
+        Some(code) => code == libc::ENOSPC || code == libc::EDQUOT,
+        // ERROR_HANDLE_DISK_FULL / ERROR_DISK_FULL
+        #[cfg(windows)]
+        Some(code) => code == 39 || code == 112,
+        #[cfg(not(any(unix, windows)))]
+        Some(_) => false,
+        None => false,
+    }
+}
+
+/// Bytes available to an unprivileged writer on the filesystem holding `path`.
+///
+/// `path` may be a file that doesn't exist yet; its nearest existing ancestor
+/// is queried instead.
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    let dir = path
+        .ancestors()
+        .find(|p| !p.as_os_str().is_empty() && p.exists())
+        .unwrap_or(Path::new("."));
+    platform_available_space(dir)
+}
+
+#[cfg(unix)]
+fn platform_available_space(dir: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    let rc = unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)] // field widths differ between platforms
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+fn platform_available_space(dir: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetDiskFreeSpaceExW(
+            directory: *const u16,
+            free_to_caller: *mut u64,
+            total: *mut u64,
+            total_free: *mut u64,
+        ) -> i32;
+    }
+
+    let wide: Vec<u16> = dir.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut free_to_caller = 0u64;
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            wide.as_ptr(),
+            &mut free_to_caller,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(free_to_caller)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn platform_available_space(_dir: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "free space query not supported"))
+}
+
+/// User-facing message for a disk-full failure.
+pub fn disk_full_message(needed: u64, available: u64) -> String {
+    format!(
+        "Not enough disk space: {} needed, {} available",
+        format_bytes(needed),
+        format_bytes(available),
+    )
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+    const MB: f64 = 1024.0 * 1024.0;
+    let b = bytes as f64;
+    if b >= GB {
+        format!("{:.1} GB", b / GB)
+    } else {
+        format!("{:.1} MB", b / MB)
+    }
+}
@@ -0,0 +1,81 @@
+//! Why a transfer failed.
+//!
+//! The sender and receiver pipelines fail with a `TransferError`, so callers
+//! can tell a cancel from a stall from a full disk without reading the
+//! message. `Display` gives the message for logs and the UI.
+
+use std::fmt;
+use std::io;
+
+use crate::disk;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferError {
+    /// A local file couldn't be opened, read or written.
+    Io(String),
+    /// The output filesystem has `available` bytes but the transfer needs
+    /// `needed`.
+    DiskFull { needed: u64, available: u64 },
+    /// A chunk couldn't be sealed, or a control MAC didn't verify.
+    Crypto(String),
+    /// Chunk `chunk` arrived whole but its SHA-256 isn't the expected one.
+    HashMismatch { chunk: u32, expected: String, actual: String },
+    /// A UDP socket couldn't be set up or failed mid-transfer, or the path
+    /// kept dropping a chunk.
+    Network(String),
+    /// The other end refused our credentials.
+    Auth(String),
+    /// The two ends disagree about the transfer, or the pipeline broke.
+    Protocol(String),
+    /// The caller cancelled.
+    Cancelled,
+    /// Nothing was heard from the other end for too long; says what was
+    /// awaited and for how long.
+    Stalled(String),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Io(msg)
+            | TransferError::Crypto(msg)
+            | TransferError::Network(msg)
+            | TransferError::Auth(msg)
+            | TransferError::Protocol(msg) => f.write_str(msg),
+            TransferError::DiskFull { needed, available } => {
+                f.write_str(&disk::disk_full_message(*needed, *available))
+            }
+            TransferError::HashMismatch { chunk, expected, actual } => {
+                write!(f, "Chunk {} hash mismatch: expected {}, got {}", chunk, expected, actual)
+            }
+            TransferError::Cancelled => f.write_str("Cancelled"),
+            TransferError::Stalled(what) => write!(f, "Transfer stalled: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+impl From<io::Error> for TransferError {
+    fn from(e: io::Error) -> Self {
+        TransferError::Io(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        assert_eq!(TransferError::Cancelled.to_string(), "Cancelled");
+        assert_eq!(
+            TransferError::Stalled("no frames for 2s".into()).to_string(),
+            "Transfer stalled: no frames for 2s"
+        );
+        let mismatch = TransferError::HashMismatch { chunk: 3, expected: "aa".into(), actual: "bb".into() };
+        assert_eq!(mismatch.to_string(), "Chunk 3 hash mismatch: expected aa, got bb");
+        let io_err = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        assert_eq!(TransferError::from(io_err), TransferError::Io("denied".into()));
+    }
+}
@@ -0,0 +1,412 @@
+//! Forward error correction for the UDP blast path.
+//!
+//! With FEC on, the blaster follows every group of `data_frames` frames of a
+//! chunk with `parity_frames` Reed-Solomon parity frames. The assembler can
+//! rebuild up to `parity_frames` missing frames per group from whatever else
+//! of the group arrived, so light loss costs no NACK round trip. Anything
+//! beyond that is still NACKed as before.
+//!
+//! Parity frames describe their own group shape, so receivers need no
+//! configuration:
+//!
+//! ```text
+//! frame_index = PARITY_FRAME_FLAG | (group * parity_frames + j)
+//! frame_count = data_frames << 8 | parity_frames
+//! payload     = parity shard j of the group, one frame stride long
+//! ```
+//!
+//! Data shards are the group's frames as sent, the chunk's last frame
+//! zero-padded to the stride. A chunk's last group may be short; it is coded
+//! over the frames it has.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::collections::hash_map::Entry;
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::bitfield::ChunkBitfield;
+use crate::protocol::PARITY_FRAME_FLAG;
+
+/// Parity frames per group of data frames. `DISABLED` (the default) sends
+/// none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FecRatio {
+    data_frames: u8,
+    parity_frames: u8,
+}
+
+impl FecRatio {
+    pub const DISABLED: Self = Self { data_frames: 0, parity_frames: 0 };
+
+    /// Largest group. Keeps a parity frame's `frame_count` below
+    /// `PARITY_FRAME_FLAG`.
+    pub const MAX_DATA_FRAMES: u8 = 127;
+
+    /// `parity_frames` parity frames per `data_frames` data frames. Zero
+    /// parity frames disables FEC; otherwise parity may not outnumber data.
+    pub fn new(parity_frames: u8, data_frames: u8) -> Result<Self, String> {
+        if parity_frames == 0 {
+            return Ok(Self::DISABLED);
+        }
+        if data_frames == 0 || data_frames > Self::MAX_DATA_FRAMES || parity_frames > data_frames {
+            return Err(format!(
+                "FEC ratio {}/{} out of range (parity 1..=data, data 1..={})",
+                parity_frames, data_frames, Self::MAX_DATA_FRAMES
+            ));
+        }
+        Ok(Self { data_frames, parity_frames })
+    }
+
+    /// Parse `"parity/data"` (e.g. `"4/32"`), or `"0"` for disabled.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if s == "0" {
+            return Ok(Self::DISABLED);
+        }
+        let (parity, data) = s
+            .split_once('/')
+            .ok_or_else(|| format!("FEC ratio {:?} is not parity/data", s))?;
+        let num = |v: &str| v.trim().parse::<u8>().map_err(|_| format!("FEC ratio {:?} is not parity/data", s));
+        Self::new(num(parity)?, num(data)?)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.parity_frames > 0
+    }
+
+    pub fn data_frames(&self) -> u8 {
+        self.data_frames
+    }
+
+    pub fn parity_frames(&self) -> u8 {
+        self.parity_frames
+    }
+
+    /// The `frame_count` field of this ratio's parity frames.
+    pub fn frame_count_field(&self) -> u16 {
+        (self.data_frames as u16) << 8 | self.parity_frames as u16
+    }
+
+    /// Read the ratio back from a parity frame's `frame_count` field.
+    pub fn from_frame_count_field(field: u16) -> Option<Self> {
+        Self::new(field as u8, (field >> 8) as u8).ok().filter(Self::is_enabled)
+    }
+
+    /// Number of parity frames sent for a chunk of `frame_count` data frames.
+    pub fn parity_count(&self, frame_count: u16) -> usize {
+        if !self.is_enabled() {
+            return 0;
+        }
+        (frame_count as usize).div_ceil(self.data_frames as usize) * self.parity_frames as usize
+    }
+}
+
+/// The form [`FecRatio::parse`] reads: `"parity/data"`, or `"0"`.
+impl fmt::Display for FecRatio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_enabled() {
+            write!(f, "{}/{}", self.parity_frames, self.data_frames)
+        } else {
+            f.write_str("0")
+        }
+    }
+}
+
+/// Reed-Solomon codecs keyed by group shape. Building one inverts a matrix,
+/// so each pipeline keeps its own and reuses them across chunks.
+#[derive(Default)]
+pub struct FecCodec {
+    codecs: HashMap<(usize, usize), ReedSolomon>,
+}
+
+impl FecCodec {
+    fn get(&mut self, data: usize, parity: usize) -> Result<&ReedSolomon, String> {
+        Ok(match self.codecs.entry((data, parity)) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(
+                ReedSolomon::new(data, parity).map_err(|e| format!("FEC codec {}/{}: {:?}", parity, data, e))?,
+            ),
+        })
+    }
+
+    /// Parity shards for one chunk blasted as `stride`-byte frames, in
+    /// parity sequence order: group 0's shards first, then group 1's, and
+    /// so on.
+    pub fn encode(&mut self, ratio: FecRatio, data: &[u8], stride: usize) -> Result<Vec<Vec<u8>>, String> {
+        let frame_count = data.len().div_ceil(stride);
+        let mut parity = Vec::with_capacity(ratio.parity_count(frame_count as u16));
+        if !ratio.is_enabled() {
+            return Ok(parity);
+        }
+        let group_bytes = ratio.data_frames as usize * stride;
+        for group in data.chunks(group_bytes) {
+            // Only the chunk's last frame can be short.
+            let (whole, tail) = group.split_at(group.len() - group.len() % stride);
+            let mut padded = tail.to_vec();
+            padded.resize(stride, 0);
+            let mut shards: Vec<&[u8]> = whole.chunks(stride).collect();
+            if !tail.is_empty() {
+                shards.push(&padded);
+            }
+            let mut out = vec![vec![0u8; stride]; ratio.parity_frames as usize];
+            self.get(shards.len(), out.len())?
+                .encode_sep(&shards, &mut out)
+                .map_err(|e| format!("FEC encode: {:?}", e))?;
+            parity.append(&mut out);
+        }
+        Ok(parity)
+    }
+
+    /// Rebuild the missing frames of `group` in an assembling chunk from its
+    /// received parity. Does nothing unless enough parity has arrived to
+    /// cover every gap. Recovered frames are written into `buf` at
+    /// `frame_index * stride` and marked in `bitfield`; returns how many.
+    pub fn recover_group(
+        &mut self,
+        parity: &ChunkParity,
+        group: usize,
+        bitfield: &mut ChunkBitfield,
+        buf: &mut [u8],
+        stride: usize,
+    ) -> Result<usize, String> {
+        let Some(ratio) = parity.ratio else {
+            return Ok(0);
+        };
+        let (n, k) = (ratio.data_frames as usize, ratio.parity_frames as usize);
+        let first = group * n;
+        let frame_count = bitfield.total() as usize;
+        if first >= frame_count {
+            return Ok(0);
+        }
+        let frames = first..(first + n).min(frame_count);
+        let missing = frames.clone().filter(|&i| !bitfield.get(i as u16)).count();
+        let seqs = group * k..(group + 1) * k;
+        let have = seqs.clone().filter(|s| parity.shards.contains_key(s)).count();
+        if missing == 0 || missing > have {
+            return Ok(0);
+        }
+
+        let mut shards: Vec<(Vec<u8>, bool)> = Vec::with_capacity(frames.len() + k);
+        for i in frames.clone() {
+            let mut shard = vec![0u8; stride];
+            let present = bitfield.get(i as u16);
+            if present {
+                let offset = (i * stride).min(buf.len());
+                let end = (offset + stride).min(buf.len());
+                shard[..end - offset].copy_from_slice(&buf[offset..end]);
+            }
+            shards.push((shard, present));
+        }
+        for seq in seqs {
+            shards.push(match parity.shards.get(&seq) {
+                Some(shard) => (shard.clone(), true),
+                None => (vec![0u8; stride], false),
+            });
+        }
+        self.get(frames.len(), k)?
+            .reconstruct_data(&mut shards)
+            .map_err(|e| format!("FEC reconstruct: {:?}", e))?;
+
+        for (i, (shard, _)) in frames.zip(shards) {
+            if bitfield.set(i as u16) {
+                let offset = (i * stride).min(buf.len());
+                let end = (offset + stride).min(buf.len());
+                buf[offset..end].copy_from_slice(&shard[..end - offset]);
+            }
+        }
+        Ok(missing)
+    }
+}
+
+/// Parity frames received for one chunk, held until the chunk completes.
+#[derive(Default)]
+pub struct ChunkParity {
+    ratio: Option<FecRatio>,
+    /// Shards by parity sequence number.
+    shards: HashMap<usize, Vec<u8>>,
+}
+
+impl ChunkParity {
+    /// Store a parity frame. Returns the group it belongs to, or `None` if
+    /// it is malformed, disagrees with earlier parity for the chunk, or
+    /// isn't one stride long.
+    pub fn insert(&mut self, frame_index: u16, frame_count: u16, payload: Vec<u8>, stride: usize) -> Option<usize> {
+        let ratio = FecRatio::from_frame_count_field(frame_count)?;
+        if *self.ratio.get_or_insert(ratio) != ratio || payload.len() != stride {
+            return None;
+        }
+        let seq = (frame_index & !PARITY_FRAME_FLAG) as usize;
+        self.shards.entry(seq).or_insert(payload);
+        Some(seq / ratio.parity_frames as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::sync::atomic::Ordering;
+
+    use crate::harness::{self, Loopback};
+    use crate::protocol::*;
+
+    /// Small deterministic PRNG so loss patterns are reproducible.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+
+        /// Pick up to `max` distinct indices below `len`.
+        fn drops(&mut self, len: usize, max: usize) -> Vec<usize> {
+            let mut picked = Vec::new();
+            for _ in 0..self.next() as usize % (max + 1) {
+                let i = self.next() as usize % len;
+                if !picked.contains(&i) {
+                    picked.push(i);
+                }
+            }
+            picked
+        }
+    }
+
+    #[test]
+    fn test_ratio_parsing() {
+        assert_eq!(FecRatio::parse("0").unwrap(), FecRatio::DISABLED);
+        assert!(!FecRatio::default().is_enabled());
+        let r = FecRatio::parse("4/32").unwrap();
+        assert_eq!(FecRatio::parse(&r.to_string()).unwrap(), r);
+        assert_eq!(FecRatio::DISABLED.to_string(), "0");
+        assert_eq!((r.parity_frames(), r.data_frames()), (4, 32));
+        assert_eq!(FecRatio::from_frame_count_field(r.frame_count_field()), Some(r));
+        assert!(r.frame_count_field() < PARITY_FRAME_FLAG);
+        assert_eq!(r.parity_count(2997), 94 * 4);
+
+        assert!(FecRatio::parse("5/4").is_err());
+        assert!(FecRatio::parse("1/128").is_err());
+        assert!(FecRatio::parse("4").is_err());
+    }
+
+    #[test]
+    fn test_recovers_loss_up_to_parity_count() {
+        let mut rng = XorShift(0x2545_F491_4F6C_DD1D);
+        let ratio = FecRatio::new(4, 32).unwrap();
+        let stride = FRAME_PAYLOAD;
+        let mut codec = FecCodec::default();
+
+        for _ in 0..20 {
+            // A short last frame and a short last group.
+            let data = rng.bytes(100 * stride + 777);
+            let frame_count = frames_for_chunk(data.len());
+            let shards = codec.encode(ratio, &data, stride).unwrap();
+            assert_eq!(shards.len(), ratio.parity_count(frame_count));
+
+            let mut bitfield = ChunkBitfield::new(frame_count);
+            let mut buf = vec![0u8; data.len()];
+            let mut parity = ChunkParity::default();
+            let groups = (frame_count as usize).div_ceil(32);
+            for group in 0..groups {
+                // Lose up to 4 of the group's data and parity frames combined.
+                let first = group * 32;
+                let len = (frame_count as usize - first).min(32);
+                let lost = rng.drops(len + 4, 4);
+                for i in 0..len {
+                    if !lost.contains(&i) {
+                        let frame = first + i;
+                        let end = ((frame + 1) * stride).min(data.len());
+                        buf[frame * stride..end].copy_from_slice(&data[frame * stride..end]);
+                        bitfield.set(frame as u16);
+                    }
+                }
+                for j in 0..4 {
+                    if !lost.contains(&(len + j)) {
+                        let seq = (group * 4 + j) as u16;
+                        let shard = shards[seq as usize].clone();
+                        parity.insert(PARITY_FRAME_FLAG | seq, ratio.frame_count_field(), shard, stride);
+                    }
+                }
+                codec.recover_group(&parity, group, &mut bitfield, &mut buf, stride).unwrap();
+            }
+            assert!(bitfield.is_complete());
+            assert_eq!(buf, data);
+        }
+
+        // One loss too many is left for NACKs.
+        let data = rng.bytes(32 * stride);
+        let shards = codec.encode(ratio, &data, stride).unwrap();
+        let mut bitfield = ChunkBitfield::new(32);
+        for frame in 5..32 {
+            bitfield.set(frame);
+        }
+        let mut parity = ChunkParity::default();
+        for (seq, shard) in shards.into_iter().enumerate() {
+            parity.insert(PARITY_FRAME_FLAG | seq as u16, ratio.frame_count_field(), shard, stride);
+        }
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(codec.recover_group(&parity, 0, &mut bitfield, &mut buf, stride).unwrap(), 0);
+        assert_eq!(bitfield.missing_count(), 5);
+    }
+
+    #[test]
+    fn test_receiver_reconstructs_without_retransmits() {
+        let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+        let dir = harness::scratch_dir("fec");
+        let output = dir.join("out.bin");
+
+        // Stands in for encrypted chunks: the receiver only checks hashes.
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE);
+        let file = rng.bytes(2 * chunk_size + 5000);
+        let transfer_id = harness::TRANSFER_ID;
+        // Nobody answers NACKs: every lost frame must come back from parity.
+        let receiver = Loopback::start(harness::receiver_config(&output, &file, chunk_size));
+        let target = receiver.addr;
+
+        let ratio = FecRatio::new(4, 32).unwrap();
+        let mut codec = FecCodec::default();
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buf = vec![0u8; MAX_FRAME];
+        let mut dropped = 0;
+        for (chunk_index, chunk) in file.chunks(chunk_size).enumerate() {
+            let frame_count = frames_for_chunk(chunk.len());
+            let shards = codec.encode(ratio, chunk, FRAME_PAYLOAD).unwrap();
+            for (group, data) in chunk.chunks(32 * FRAME_PAYLOAD).enumerate() {
+                let len = data.len().div_ceil(FRAME_PAYLOAD);
+                // Keep the group's first frame so the stride is always known.
+                let lost: Vec<usize> = rng.drops(len + 3, 4).into_iter().map(|i| i + 1).collect();
+                dropped += lost.len();
+                let mut frames: Vec<(u16, u16, &[u8])> = data
+                    .chunks(FRAME_PAYLOAD)
+                    .enumerate()
+                    .map(|(i, p)| ((group * 32 + i) as u16, frame_count, p))
+                    .collect();
+                for j in 0..4 {
+                    let seq = (group * 4 + j) as u16;
+                    frames.push((PARITY_FRAME_FLAG | seq, ratio.frame_count_field(), &shards[seq as usize]));
+                }
+                for (i, (frame_index, count, payload)) in frames.into_iter().enumerate() {
+                    if !lost.contains(&i) {
+                        let n = encode_frame(&mut buf, &transfer_id, chunk_index as u32, frame_index, count, payload);
+                        tx.send_to(&buf[..n], target).unwrap();
+                    }
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        }
+
+        let progress = receiver.progress.clone();
+        receiver.join().unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), file);
+        assert!(progress.fec_recovered.load(Ordering::Relaxed) > 0);
+        assert!(progress.fec_recovered.load(Ordering::Relaxed) <= dropped as u64);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,393 @@
+//! End-to-end test harness: `run_sender` blasting to `run_receiver` over
+//! loopback, optionally through a relay that drops frames.
+//!
+//! [`Loopback`] runs a receiver on its own thread and wires its NACK and
+//! ACK callbacks straight into the channels a sender reads, as the
+//! WebSocket would carry them (reliably); the config helpers fill in the
+//! transfer's own fields so a test only names what it changes. Other
+//! modules' round-trip tests build on these.
+//!
+//! [`transfer`] adds a relay standing between the two as a plain UDP
+//! socket, so neither pipeline knows it's there: the sender targets the
+//! relay, which forwards each frame to the receiver unless its [`Loss`]
+//! says to drop it. Random loss comes from a seeded generator, so a failing
+//! run drops the same frames again given the same frame order.
+
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossbeam_channel::{Receiver, unbounded};
+use sha2::{Digest, Sha256};
+
+use crate::aead::{AeadAlgorithm, ChunkCipher};
+use crate::error::TransferError;
+use crate::protocol::*;
+use crate::receiver::{ReceiverConfig, ReceiverProgress, run_receiver};
+use crate::sender::{
+    ChunkAckMessage, NackMessage, RawSenderConfig, SendResult, SenderConfig, SenderProgress, run_raw_sender, run_sender,
+};
+
+/// Which frames the relay drops.
+#[derive(Clone)]
+enum Loss {
+    /// Each frame independently with probability `fraction`.
+    Random { fraction: f64, seed: u64 },
+    /// Frames whose sequence number (0 for the first frame relayed) the
+    /// function picks.
+    Pattern(fn(u64) -> bool),
+}
+
+/// SplitMix64: small, seedable, and good enough to pick frames.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_f64(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Forwards datagrams from its socket to `target`, dropping some.
+struct LossyRelay {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    dropped: Arc<AtomicU64>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LossyRelay {
+    fn start(target: SocketAddr, loss: Loss) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let thread = std::thread::spawn({
+            let (stop, dropped) = (stop.clone(), dropped.clone());
+            move || {
+                let mut rng = match loss {
+                    Loss::Random { seed, .. } => SplitMix64(seed),
+                    Loss::Pattern(_) => SplitMix64(0),
+                };
+                let mut buf = vec![0u8; MAX_FRAME + 64];
+                let mut seq = 0u64;
+                while !stop.load(Ordering::Relaxed) {
+                    let Ok(len) = socket.recv(&mut buf) else {
+                        continue;
+                    };
+                    let drop = match loss {
+                        Loss::Random { fraction, .. } => rng.next_f64() < fraction,
+                        Loss::Pattern(pick) => pick(seq),
+                    };
+                    seq += 1;
+                    if drop {
+                        dropped.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        let _ = socket.send_to(&buf[..len], target);
+                    }
+                }
+            }
+        });
+        Self { addr, stop, dropped, thread: Some(thread) }
+    }
+}
+
+impl Drop for LossyRelay {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Transfer id every harness config carries.
+pub(crate) const TRANSFER_ID: [u8; 16] = [0xA5u8; 16];
+
+/// Key [`seal`] and [`sender_config`] encrypt with.
+pub(crate) const KEY: [u8; 32] = [0x5Au8; 32];
+
+/// A fresh, empty directory for one test's files.
+pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("haven-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// `plaintext` sealed chunk by chunk under [`KEY`], as `run_sender` stores it.
+pub(crate) fn seal(plaintext: &[u8], chunk_size: usize, aead: AeadAlgorithm) -> Vec<u8> {
+    let cipher = ChunkCipher::new(aead, &KEY);
+    plaintext
+        .chunks(chunk_size)
+        .enumerate()
+        .flat_map(|(idx, chunk)| cipher.seal_chunk(&KEY, idx as u32, chunk_size, chunk).unwrap())
+        .collect()
+}
+
+/// A loopback receiver writing `output`, expecting `stored` in chunks of
+/// `slot_size`. Receivers are told the chunks' hashes up front, as the
+/// uploader's pre-hash pass would.
+pub(crate) fn receiver_config(output: &Path, stored: &[u8], slot_size: usize) -> ReceiverConfig {
+    let chunk_hashes: Vec<String> = stored.chunks(slot_size).map(|c| hex::encode(Sha256::digest(c))).collect();
+    ReceiverConfig {
+        output_path: output.to_string_lossy().into_owned(),
+        transfer_id: TRANSFER_ID,
+        file_size: stored.len() as u64,
+        chunk_count: chunk_hashes.len() as u32,
+        chunk_size: slot_size as u64,
+        chunk_hashes,
+        file_sha256: hex::encode(Sha256::digest(stored)),
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        ..Default::default()
+    }
+}
+
+/// A sender of `input` in `chunk_size` chunks, encrypting with [`KEY`].
+pub(crate) fn sender_config(input: &Path, target: SocketAddr, chunk_size: usize) -> SenderConfig {
+    SenderConfig {
+        file_path: input.to_string_lossy().into_owned(),
+        target_addr: target,
+        transfer_id: TRANSFER_ID,
+        encryption_key: KEY,
+        chunk_size,
+        ..Default::default()
+    }
+}
+
+/// A raw sender of `input`, already stored in chunks of `slot_size`.
+pub(crate) fn raw_sender_config(input: &Path, target: SocketAddr, file_size: u64, slot_size: usize) -> RawSenderConfig {
+    RawSenderConfig {
+        file_path: input.to_string_lossy().into_owned(),
+        target_addr: target,
+        transfer_id: TRANSFER_ID,
+        file_size,
+        chunk_size: slot_size as u64,
+        chunk_count: file_size.div_ceil(slot_size as u64) as u32,
+        ..Default::default()
+    }
+}
+
+/// A receiver running on its own thread.
+pub(crate) struct Loopback {
+    /// Where the receiver listens.
+    pub addr: SocketAddr,
+    pub progress: Arc<ReceiverProgress>,
+    nack_rx: Receiver<NackMessage>,
+    ack_rx: Receiver<ChunkAckMessage>,
+    thread: JoinHandle<Result<SocketAddr, TransferError>>,
+}
+
+impl Loopback {
+    /// Start a receiver with `config`, its NACKs and ACK bitmaps queued for
+    /// the next [`Loopback::send`] (any `ack_callback` given is replaced).
+    /// Returns once it's bound.
+    pub(crate) fn start(mut config: ReceiverConfig) -> Self {
+        let (nack_tx, nack_rx) = unbounded::<NackMessage>();
+        let (ack_tx, ack_rx) = unbounded::<ChunkAckMessage>();
+        config.ack_callback = Some(Box::new(move |base_chunk, bitmap| {
+            let _ = ack_tx.send(ChunkAckMessage::Bitmap { base_chunk, bitmap });
+        }));
+        let ip = config.bind_addr.ip();
+        let progress = Arc::new(ReceiverProgress::new());
+        let thread = std::thread::spawn({
+            let progress = progress.clone();
+            move || {
+                run_receiver(
+                    config,
+                    progress,
+                    Box::new(move |nacks| {
+                        for nack in nacks {
+                            let _ = nack_tx.send(nack);
+                        }
+                    }),
+                )
+            }
+        });
+        let port = loop {
+            match progress.bound_port.load(Ordering::Relaxed) {
+                0 if thread.is_finished() => panic!("receiver failed: {:?}", thread.join().unwrap()),
+                0 => std::thread::sleep(Duration::from_millis(2)),
+                port => break port,
+            }
+        };
+        Self { addr: SocketAddr::new(ip, port), progress, nack_rx, ack_rx, thread }
+    }
+
+    /// Run `run_sender` against this receiver's NACKs and ACKs.
+    pub(crate) fn send(&self, config: SenderConfig, progress: Arc<SenderProgress>) -> Result<SendResult, TransferError> {
+        run_sender(config, progress, self.nack_rx.clone(), self.ack_rx.clone())
+    }
+
+    /// Run `run_raw_sender` against this receiver's NACKs and ACKs.
+    pub(crate) fn send_raw(&self, config: RawSenderConfig, progress: Arc<SenderProgress>) -> Result<(), TransferError> {
+        run_raw_sender(config, progress, self.nack_rx.clone(), self.ack_rx.clone())
+    }
+
+    /// Wait for the receiver to finish.
+    pub(crate) fn join(self) -> Result<SocketAddr, TransferError> {
+        self.thread.join().unwrap()
+    }
+}
+
+/// What a harness run saw.
+struct Outcome {
+    /// The receiver's file, decrypted.
+    plaintext: Vec<u8>,
+    /// Frames the relay dropped.
+    dropped: u64,
+    sender: Arc<SenderProgress>,
+    receiver: Arc<ReceiverProgress>,
+}
+
+/// Send `data` from a file through a relay with `loss` and return what the
+/// receiver wrote. Panics if either side fails.
+fn transfer(name: &str, data: &[u8], chunk_size: usize, loss: Loss) -> Outcome {
+    let dir = scratch_dir(&format!("harness-{}", name));
+    let input = dir.join("in.bin");
+    let output = dir.join("out.bin");
+    std::fs::write(&input, data).unwrap();
+
+    let aead = AeadAlgorithm::Aes256Gcm;
+    let slot_size = encrypted_chunk_size(chunk_size);
+    let receiver = Loopback::start(ReceiverConfig {
+        stall_timeout: Duration::from_secs(10),
+        ..receiver_config(&output, &seal(data, chunk_size, aead), slot_size)
+    });
+    let relay = LossyRelay::start(receiver.addr, loss);
+
+    let sender_progress = Arc::new(SenderProgress::new());
+    let sent = receiver.send(
+        SenderConfig {
+            // Probe echoes would go back to the relay, not the sender.
+            probe_mtu: false,
+            encrypt_workers: 1,
+            stall_timeout: Duration::from_secs(10),
+            ..sender_config(&input, relay.addr, chunk_size)
+        },
+        sender_progress.clone(),
+    );
+    let receiver_progress = receiver.progress.clone();
+    let received = receiver.join();
+    let dropped = relay.dropped.load(Ordering::Relaxed);
+    drop(relay);
+    sent.unwrap();
+    received.unwrap();
+
+    let cipher = ChunkCipher::new(aead, &KEY);
+    let plaintext = std::fs::read(&output)
+        .unwrap()
+        .chunks(slot_size)
+        .flat_map(|chunk| cipher.open_chunk(chunk).unwrap())
+        .collect();
+    let _ = std::fs::remove_dir_all(&dir);
+    Outcome { plaintext, dropped, sender: sender_progress, receiver: receiver_progress }
+}
+
+/// A few chunks, the last one short but still many frames long, so no
+/// chunk is likely to lose every frame (a chunk nothing arrived for is
+/// never NACKed).
+fn sample(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 37 % 251) as u8).collect()
+}
+
+#[test]
+fn test_lossless_transfer_matches_source() {
+    let data = sample(6 * MIN_CHUNK_SIZE + 30_000);
+    let outcome = transfer("lossless", &data, MIN_CHUNK_SIZE, Loss::Random { fraction: 0.0, seed: 1 });
+
+    assert!(outcome.plaintext == data);
+    assert_eq!(outcome.dropped, 0);
+    assert_eq!(outcome.sender.retransmits.load(Ordering::Relaxed), 0);
+    assert_eq!(outcome.receiver.chunks_complete.load(Ordering::Relaxed), 7);
+}
+
+#[test]
+fn test_ten_percent_loss_is_repaired() {
+    let data = sample(6 * MIN_CHUNK_SIZE + 30_000);
+    let outcome = transfer("loss10", &data, MIN_CHUNK_SIZE, Loss::Random { fraction: 0.10, seed: 0x10 });
+
+    assert!(outcome.plaintext == data);
+    assert!(outcome.dropped > 0);
+    // Every dropped frame was NACKed and sent again.
+    assert!(outcome.sender.retransmits.load(Ordering::Relaxed) >= outcome.dropped);
+}
+
+#[test]
+fn test_half_the_frames_lost_is_repaired() {
+    let data = sample(6 * MIN_CHUNK_SIZE + 30_000);
+    let outcome = transfer("loss50", &data, MIN_CHUNK_SIZE, Loss::Random { fraction: 0.5, seed: 0x50 });
+
+    assert!(outcome.plaintext == data);
+    // Retransmits are lost too, so chunks take several rounds.
+    assert!(outcome.sender.max_chunk_retransmits.load(Ordering::Relaxed) > 1);
+}
+
+#[test]
+fn test_patterned_loss_is_repaired() {
+    // Every other frame: each chunk's bitfield has gaps all the way through.
+    let data = sample(3 * MIN_CHUNK_SIZE);
+    let outcome = transfer("pattern", &data, MIN_CHUNK_SIZE, Loss::Pattern(|seq| seq % 2 == 1));
+
+    assert!(outcome.plaintext == data);
+    assert!(outcome.sender.retransmits.load(Ordering::Relaxed) >= outcome.dropped);
+}
+
+#[test]
+fn test_seeded_loss_repeats() {
+    let picks = |seed| {
+        let mut rng = SplitMix64(seed);
+        (0..1000).map(|_| rng.next_f64() < 0.1).collect::<Vec<_>>()
+    };
+    assert_eq!(picks(7), picks(7));
+    assert_ne!(picks(7), picks(8));
+    let dropped = picks(7).into_iter().filter(|&d| d).count();
+    assert!((60..140).contains(&dropped), "{}", dropped);
+}
+
+#[test]
+fn test_pause_longer_than_stall_timeout_completes() {
+    let dir = scratch_dir("harness-paused");
+    let input = dir.join("in.bin");
+    let output = dir.join("out.bin");
+    let data = sample(2 * MIN_CHUNK_SIZE);
+    std::fs::write(&input, &data).unwrap();
+
+    let stall_timeout = Duration::from_secs(2);
+    let aead = AeadAlgorithm::Aes256Gcm;
+    let stored = seal(&data, MIN_CHUNK_SIZE, aead);
+    let receiver = Loopback::start(ReceiverConfig {
+        stall_timeout,
+        ..receiver_config(&output, &stored, encrypted_chunk_size(MIN_CHUNK_SIZE))
+    });
+
+    // Held paused from the start, for longer than the receiver will wait
+    // without hearing anything.
+    let progress = Arc::new(SenderProgress::new());
+    progress.paused.store(1, Ordering::Relaxed);
+    let resume = std::thread::spawn({
+        let progress = progress.clone();
+        move || {
+            std::thread::sleep(stall_timeout + Duration::from_secs(1));
+            progress.paused.store(0, Ordering::Relaxed);
+        }
+    });
+    receiver.send(sender_config(&input, receiver.addr, MIN_CHUNK_SIZE), progress).unwrap();
+    let receiver_progress = receiver.progress.clone();
+    receiver.join().unwrap();
+    resume.join().unwrap();
+
+    assert_eq!(std::fs::read(&output).unwrap(), stored);
+    // Keepalives aren't data frames.
+    let frames = 2 * frames_for_chunk(encrypted_chunk_size(MIN_CHUNK_SIZE)) as u64;
+    assert_eq!(receiver_progress.frames_received.load(Ordering::Relaxed), frames);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,160 @@
+//! Time-bucketed loss histogram for post-transfer diagnostics.
+//!
+//! The receiver records how many frames it had to NACK in each time bucket
+//! (1 second initially). A single aggregate retransmit count can't tell a
+//! short Wi-Fi hiccup from a consistently lossy link; the shape of this
+//! histogram can.
+//!
+//! Memory is bounded: once `MAX_LOSS_BUCKETS` is reached, adjacent buckets are
+//! merged pairwise and the bucket width doubles, so a long transfer still
+//! covers its whole duration at coarser resolution.
+
+use std::time::Duration;
+
+/// Initial bucket width in milliseconds.
+pub const LOSS_BUCKET_MS: u64 = 1000;
+
+/// Maximum number of buckets kept before coarsening.
+pub const MAX_LOSS_BUCKETS: usize = 256;
+
+/// Retransmit counts per time bucket since the start of the transfer.
+#[derive(Debug, Clone)]
+pub struct LossHistogram {
+    bucket_ms: u64,
+    buckets: Vec<u64>,
+}
+
+impl LossHistogram {
+    pub fn new() -> Self {
+        Self {
+            bucket_ms: LOSS_BUCKET_MS,
+            buckets: Vec::new(),
+        }
+    }
+
+    /// Record `count` lost frames at `elapsed` since the transfer started.
+    pub fn record(&mut self, elapsed: Duration, count: u64) {
+        let mut idx = (elapsed.as_millis() as u64 / self.bucket_ms) as usize;
+        while idx >= MAX_LOSS_BUCKETS {
+            self.coarsen();
+            idx = (elapsed.as_millis() as u64 / self.bucket_ms) as usize;
+        }
+        if idx >= self.buckets.len() {
+            self.buckets.resize(idx + 1, 0);
+        }
+        self.buckets[idx] += count;
+    }
+
+    /// Merge adjacent buckets pairwise and double the bucket width.
+    fn coarsen(&mut self) {
+        self.buckets = self
+            .buckets
+            .chunks(2)
+            .map(|pair| pair.iter().sum())
+            .collect();
+        self.bucket_ms *= 2;
+    }
+
+    /// Current bucket width in milliseconds.
+    pub fn bucket_ms(&self) -> u64 {
+        self.bucket_ms
+    }
+
+    /// Retransmit counts per bucket, oldest first.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// Total lost frames across all buckets.
+    pub fn total(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Largest single-bucket loss count.
+    pub fn peak(&self) -> u64 {
+        self.buckets.iter().copied().max().unwrap_or(0)
+    }
+
+    /// Fraction of buckets that saw any loss (0.0–1.0).
+    ///
+    /// Near 1.0 means steady loss across the whole transfer; near 0.0 with a
+    /// non-zero total means the loss was concentrated in a few bursts.
+    pub fn lossy_fraction(&self) -> f64 {
+        if self.buckets.is_empty() {
+            return 0.0;
+        }
+        let lossy = self.buckets.iter().filter(|&&c| c > 0).count();
+        lossy as f64 / self.buckets.len() as f64
+    }
+
+    /// Serialize as `{"bucket_ms":N,"total":N,"peak":N,"lossy_fraction":F,"buckets":[...]}`.
+    pub fn to_json(&self) -> String {
+        let buckets = self
+            .buckets
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            r#"{{"bucket_ms":{},"total":{},"peak":{},"lossy_fraction":{:.3},"buckets":[{}]}}"#,
+            self.bucket_ms,
+            self.total(),
+            self.peak(),
+            self.lossy_fraction(),
+            buckets,
+        )
+    }
+}
+
+impl Default for LossHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bursty_vs_steady() {
+        // Same total loss over a 20 second transfer.
+        let mut bursty = LossHistogram::new();
+        bursty.record(Duration::from_millis(7_200), 200);
+        bursty.record(Duration::from_millis(7_900), 200);
+        bursty.record(Duration::from_millis(19_500), 0);
+
+        let mut steady = LossHistogram::new();
+        for sec in 0..20 {
+            steady.record(Duration::from_millis(sec * 1000 + 500), 20);
+        }
+
+        assert_eq!(bursty.total(), steady.total());
+        assert_eq!(bursty.buckets().len(), steady.buckets().len());
+        assert_eq!(bursty.peak(), 400);
+        assert_eq!(steady.peak(), 20);
+        assert!(bursty.lossy_fraction() < 0.1);
+        assert!(steady.lossy_fraction() > 0.9);
+    }
+
+    #[test]
+    fn test_bounded_size() {
+        let mut h = LossHistogram::new();
+        for sec in 0..10_000u64 {
+            h.record(Duration::from_secs(sec), 1);
+        }
+        assert!(h.buckets().len() <= MAX_LOSS_BUCKETS);
+        assert!(h.bucket_ms() > LOSS_BUCKET_MS);
+        assert_eq!(h.total(), 10_000);
+    }
+
+    #[test]
+    fn test_json() {
+        let mut h = LossHistogram::new();
+        h.record(Duration::from_millis(1500), 3);
+        assert_eq!(
+            h.to_json(),
+            r#"{"bucket_ms":1000,"total":3,"peak":3,"lossy_fraction":0.500,"buckets":[0,3]}"#
+        );
+    }
+}
@@ -0,0 +1,91 @@
+//! End-to-end integrity for fast-transfer control fields.
+//!
+//! Control messages (`FastUploadStart` etc.) travel as plain JSON through the
+//! file server, which never sees the file key. Without a binding between the
+//! control plane and the encrypted data plane, a compromised relay could
+//! rewrite e.g. `chunk_size` and make the receiver assemble garbage.
+//!
+//! The uploader MACs the fields that configure the receiver pipeline with a
+//! key derived from the transfer key; the receiver recomputes it before
+//! allocating anything.
+
+use haven_crypto::keys::derive_control_key;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Domain separation so this MAC can't be confused with other uses of the key.
+const CONTROL_MAC_CONTEXT: &[u8] = b"haven-fast-transfer control v2";
+
+/// Security-critical fields of a transfer's control messages.
+#[derive(Debug, Clone, Copy)]
+pub struct ControlFields<'a> {
+    pub transfer_id: &'a [u8; 16],
+    pub file_size: u64,
+    pub chunk_size: u64,
+    pub chunk_count: u32,
+    pub file_sha256: &'a str,
+    /// Chunks stored as zero slots (see `sparse`). A reader only takes a
+    /// zero slot for zeros if its chunk is listed here under a valid MAC.
+    pub sparse_chunks: &'a [u32],
+}
+
+impl ControlFields<'_> {
+    fn hmac(&self, key: &[u8; 32]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&derive_control_key(key)).expect("HMAC accepts any key length");
+        mac.update(CONTROL_MAC_CONTEXT);
+        mac.update(self.transfer_id);
+        mac.update(&self.file_size.to_be_bytes());
+        mac.update(&self.chunk_size.to_be_bytes());
+        mac.update(&self.chunk_count.to_be_bytes());
+        mac.update(&(self.file_sha256.len() as u32).to_be_bytes());
+        mac.update(self.file_sha256.as_bytes());
+        // Appended only when there are any, so other transfers' MACs are
+        // unchanged.
+        if !self.sparse_chunks.is_empty() {
+            mac.update(&(self.sparse_chunks.len() as u32).to_be_bytes());
+            for chunk in self.sparse_chunks {
+                mac.update(&chunk.to_be_bytes());
+            }
+        }
+        mac
+    }
+
+    /// Hex-encoded HMAC-SHA256 over the fields, keyed by the control key
+    /// derived from the transfer key `key`.
+    pub fn mac(&self, key: &[u8; 32]) -> String {
+        hex::encode(self.hmac(key).finalize().into_bytes())
+    }
+
+    /// Constant-time check of a hex MAC produced by [`ControlFields::mac`].
+    pub fn verify(&self, key: &[u8; 32], mac_hex: &str) -> bool {
+        match hex::decode(mac_hex) {
+            Ok(tag) => self.hmac(key).verify_slice(&tag).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sparse_chunks_are_bound_into_the_mac() {
+        let key = [7u8; 32];
+        let fields = |sparse_chunks| ControlFields {
+            transfer_id: &[1; 16],
+            file_size: 3 * 100,
+            chunk_size: 100,
+            chunk_count: 3,
+            file_sha256: "ab",
+            sparse_chunks,
+        };
+        let mac = fields(&[1]).mac(&key);
+        assert!(fields(&[1]).verify(&key, &mac));
+        // A relay can neither add a sparse chunk nor drop one.
+        assert!(!fields(&[1, 2]).verify(&key, &mac));
+        assert!(!fields(&[]).verify(&key, &mac));
+    }
+}
@@ -4,27 +4,75 @@
 /// - 3-thread sender pipeline: reader → encryptor → blaster
 /// - 3-thread receiver pipeline: UDP vacuum → assembler → writer
 /// - Per-chunk bitfield frame tracking
-/// - NACK-based retransmission
-/// - Rate control with loss-based backoff
-/// - AES-256-GCM encryption with deterministic nonces
+/// - NACK-based retransmission, coalesced per scan and paced to the NACK round trip
+/// - Optional Reed-Solomon FEC parity frames to recover light loss without NACKs
+/// - Rate control: slow start, then loss-based backoff, or delay-based backoff
+/// - Frame pacing by busy-spin, hybrid sleep+spin, or token bucket
+/// - Path MTU probing for larger frames on jumbo-frame links
+/// - IPv4 and IPv6 (dual-stack where the OS allows) UDP sockets
+/// - Configurable receive buffer, with kernel drop counts reported on Linux
+/// - Pooled ephemeral UDP sockets reused across transfers
+/// - AES-256-GCM or ChaCha20-Poly1305 encryption, chosen per transfer, with deterministic nonces
+/// - Optional per-chunk zstd compression ahead of encryption
 /// - SHA-256 integrity verification
+/// - HMAC over security-critical control fields
+/// - Resumable receives: completed chunks persisted beside the partial file
+/// - Time-bucketed loss histogram for post-transfer diagnostics
+/// - Dependency-free frame codec (`wire`)
 
+pub mod aead;
 pub mod bitfield;
+pub mod compress;
+pub mod congestion;
+pub mod disk;
+pub mod error;
+pub mod fec;
+#[cfg(test)]
+mod harness;
+pub mod histogram;
+pub mod integrity;
 pub mod logging;
+pub mod mtu;
+pub mod nack;
+pub mod net;
+pub mod pacing;
+pub mod pool;
 pub mod protocol;
 pub mod receiver;
+pub mod resume;
 pub mod sender;
+pub mod sparse;
+pub mod wire;
 
 // Re-export key types for convenience.
+pub use aead::{AeadAlgorithm, ChunkCipher};
 pub use bitfield::ChunkBitfield;
-pub use logging::{NullLogger, TracingLogger, TransferLogger};
+pub use congestion::{
+    CongestionConfig, CongestionControl, CongestionController, DelayController, Phase as CongestionPhase,
+};
+pub use compress::{
+    COMPRESSED_CHUNK_OVERHEAD, SealedChunk, compressed_chunk_nonce, compressed_slot_size,
+    open_compressed_chunk, seal_compressed_chunk,
+};
+pub use error::TransferError;
+pub use fec::FecRatio;
+pub use histogram::LossHistogram;
+pub use integrity::ControlFields;
+pub use logging::{NullLogger, RingBufferLogger, TracingLogger, TransferLogger};
+pub use pacing::Pacing;
+pub use pool::{PooledSocket, SocketPool, SocketSpec};
 pub use protocol::{
-    decode_frame_header, encode_frame, frame_payload, frames_for_chunk, FrameHeader,
+    decode_frame, decode_frame_header, encode_frame, frame_payload, frames_for_chunk, FrameHeader,
     CHUNK_SIZE, ENCRYPTED_CHUNK_SIZE, ENCRYPTION_OVERHEAD, FRAME_HEADER, FRAME_MAX,
-    FRAME_PAYLOAD, MAX_FRAMES_PER_CHUNK,
+    FRAME_PAYLOAD, MAX_CHUNK_SIZE, MAX_FRAME, MAX_FRAME_PAYLOAD, MAX_FRAMES_PER_CHUNK, MIN_FRAME_PAYLOAD,
+    MIN_CHUNK_SIZE, PARITY_FRAME_FLAG, PROBE_CHUNK_INDEX, KEEPALIVE_CHUNK_INDEX, SLOWDOWN_CHUNK_INDEX, MAX_CHUNK_RETRANSMITS, SENDER_CACHE_SIZE, STALL_TIMEOUT_SECS, WireError, check_chunk_layout, chunk_count, chunk_size_in_range,
+    decode_ack_bitmap, encode_ack_bitmap, encrypted_chunk_size, frames_for_chunk_with,
+    max_frames_per_chunk, slot_size,
+    try_encode_frame,
 };
-pub use receiver::{NackCallback, ReceiverConfig, ReceiverProgress, run_receiver};
+pub use receiver::{AckCallback, NackCallback, ReceiverConfig, ReceiverProgress, SyncCadence, run_receiver};
+pub use resume::ChunkProgress;
 pub use sender::{
     ChunkAckMessage, NackMessage, RawSenderConfig, SendResult, SenderConfig, SenderProgress,
-    run_raw_sender, run_sender,
+    chunk_nonce, run_raw_sender, run_sender,
 };
@@ -3,7 +3,12 @@
 /// Components (sender, file server, receiver) send structured logs
 /// to a logging endpoint for real-time debugging.
 
+use std::collections::VecDeque;
 use std::fmt;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Structured log entry for a transfer operation.
 #[derive(Debug, Clone)]
@@ -102,6 +107,32 @@ pub enum TransferEvent {
         got: [u8; 16],
         from: String,
     },
+    /// Receiver: frames NACKed per time bucket, logged at transfer end
+    LossHistogram {
+        bucket_ms: u64,
+        buckets: Vec<u64>,
+    },
+    /// Sender: MTU probe finished, frames will carry this many payload bytes
+    MtuProbed {
+        frame_payload: usize,
+    },
+    /// Receiver: frames rebuilt from FEC parity instead of NACKed
+    FecRecovered {
+        chunk_idx: u32,
+        frame_count: u16,
+    },
+    /// Receiver: the kernel dropped datagrams on a full socket buffer
+    SocketDrops {
+        /// Since the transfer began
+        dropped: u64,
+        /// Since the last report
+        new: u64,
+    },
+    /// Receiver: frames are piling up ahead of the assembler; asked the
+    /// sender to slow down
+    AssemblerLagging {
+        queued: u64,
+    },
 }
 
 impl fmt::Display for TransferEvent {
@@ -155,6 +186,23 @@ impl fmt::Display for TransferEvent {
             Self::TransferIdMismatch { got, from } => {
                 write!(f, "transfer_id_mismatch got={} from={}", hex::encode(got), from)
             }
+            Self::LossHistogram { bucket_ms, buckets } => {
+                let total: u64 = buckets.iter().sum();
+                let peak = buckets.iter().copied().max().unwrap_or(0);
+                write!(f, "loss_histogram bucket_ms={} total={} peak={} buckets={:?}", bucket_ms, total, peak, buckets)
+            }
+            Self::MtuProbed { frame_payload } => {
+                write!(f, "mtu_probed frame_payload={}", frame_payload)
+            }
+            Self::FecRecovered { chunk_idx, frame_count } => {
+                write!(f, "fec_recovered idx={} frames={}", chunk_idx, frame_count)
+            }
+            Self::SocketDrops { dropped, new } => {
+                write!(f, "socket_drops total={} new={}", dropped, new)
+            }
+            Self::AssemblerLagging { queued } => {
+                write!(f, "assembler_lagging queued={}", queued)
+            }
         }
     }
 }
@@ -165,6 +213,15 @@ pub trait TransferLogger: Send + Sync {
     fn log(&self, entry: TransferLog);
 }
 
+static STRUCTURED: AtomicBool = AtomicBool::new(false);
+
+/// Make `TracingLogger` emit each event's fields as tracing fields (plus an
+/// `event` name) instead of one formatted message. Servers turn this on
+/// alongside JSON log output.
+pub fn set_structured(on: bool) {
+    STRUCTURED.store(on, Ordering::Relaxed);
+}
+
 /// Logger that uses the `tracing` crate.
 pub struct TracingLogger;
 
@@ -172,33 +229,120 @@ impl TransferLogger for TracingLogger {
     fn log(&self, entry: TransferLog) {
         let tid = hex::encode(entry.transfer_id);
         // Use info for key lifecycle events, debug for per-chunk spam
-        match &entry.event {
+        let lifecycle = matches!(
+            &entry.event,
             TransferEvent::VacuumStarted { .. }
-            | TransferEvent::VacuumProgress { .. }
-            | TransferEvent::TransferIdMismatch { .. }
-            | TransferEvent::TransferComplete { .. }
-            | TransferEvent::BlastStarted { .. }
-            | TransferEvent::BlastProgress { .. }
-            | TransferEvent::BlastComplete { .. }
-            | TransferEvent::NackSent { .. }
-            | TransferEvent::RateAdjusted { .. }
-            | TransferEvent::RetransmitSent { .. }
-            | TransferEvent::Error { .. } => {
-                tracing::info!(
-                    component = entry.component,
-                    transfer_id = %tid,
-                    "{}",
-                    entry.event,
-                );
-            }
-            _ => {
-                tracing::debug!(
-                    component = entry.component,
-                    transfer_id = %tid,
-                    "{}",
-                    entry.event,
-                );
-            }
+                | TransferEvent::VacuumProgress { .. }
+                | TransferEvent::TransferIdMismatch { .. }
+                | TransferEvent::TransferComplete { .. }
+                | TransferEvent::LossHistogram { .. }
+                | TransferEvent::MtuProbed { .. }
+                | TransferEvent::SocketDrops { .. }
+                | TransferEvent::AssemblerLagging { .. }
+                | TransferEvent::BlastStarted { .. }
+                | TransferEvent::BlastProgress { .. }
+                | TransferEvent::BlastComplete { .. }
+                | TransferEvent::NackSent { .. }
+                | TransferEvent::RateAdjusted { .. }
+                | TransferEvent::RetransmitSent { .. }
+                | TransferEvent::Error { .. }
+        );
+        if STRUCTURED.load(Ordering::Relaxed) {
+            log_structured(lifecycle, entry.component, &tid, &entry.event);
+        } else if lifecycle {
+            tracing::info!(
+                component = entry.component,
+                transfer_id = %tid,
+                "{}",
+                entry.event,
+            );
+        } else {
+            tracing::debug!(
+                component = entry.component,
+                transfer_id = %tid,
+                "{}",
+                entry.event,
+            );
+        }
+    }
+}
+
+/// Emit one event at info (lifecycle) or debug, with `event` set to its
+/// name and its fields alongside `component` and `transfer_id`.
+macro_rules! emit {
+    ($lifecycle:expr, $component:expr, $tid:expr, $name:literal $(, $field:ident = $value:expr)* $(,)?) => {
+        if $lifecycle {
+            tracing::info!(component = $component, transfer_id = $tid, event = $name, $($field = $value,)* $name);
+        } else {
+            tracing::debug!(component = $component, transfer_id = $tid, event = $name, $($field = $value,)* $name);
+        }
+    };
+}
+
+fn log_structured(lifecycle: bool, component: &'static str, tid: &str, event: &TransferEvent) {
+    match event {
+        TransferEvent::ChunkEncrypted { chunk_idx, size, duration_ms } => {
+            emit!(lifecycle, component, tid, "chunk_encrypted", chunk_idx = chunk_idx, size = size, duration_ms = duration_ms)
+        }
+        TransferEvent::FramesBlasted { chunk_idx, frame_count } => {
+            emit!(lifecycle, component, tid, "frames_blasted", chunk_idx = chunk_idx, frame_count = frame_count)
+        }
+        TransferEvent::RetransmitRequest { chunk_idx, missing_count } => {
+            emit!(lifecycle, component, tid, "retransmit_request", chunk_idx = chunk_idx, missing_count = missing_count)
+        }
+        TransferEvent::RateAdjusted { old_rate_bps, new_rate_bps, loss_pct } => {
+            emit!(lifecycle, component, tid, "rate_adjusted", old_rate_bps = old_rate_bps, new_rate_bps = new_rate_bps, loss_pct = loss_pct)
+        }
+        TransferEvent::ChunkAssembled { chunk_idx, hash_match } => {
+            emit!(lifecycle, component, tid, "chunk_assembled", chunk_idx = chunk_idx, hash_match = hash_match)
+        }
+        TransferEvent::ChunkWritten { chunk_idx, duration_ms } => {
+            emit!(lifecycle, component, tid, "chunk_written", chunk_idx = chunk_idx, duration_ms = duration_ms)
+        }
+        TransferEvent::NackSent { chunk_idx, missing_count } => {
+            emit!(lifecycle, component, tid, "nack_sent", chunk_idx = chunk_idx, missing_count = missing_count)
+        }
+        TransferEvent::BlastStarted { target, rate_bps, chunk_count, file_size } => {
+            emit!(lifecycle, component, tid, "blast_started", target = target.as_str(), rate_bps = rate_bps, chunk_count = chunk_count, file_size = file_size)
+        }
+        TransferEvent::BlastProgress { chunks_sent, chunks_total, rate_bps } => {
+            emit!(lifecycle, component, tid, "blast_progress", chunks_sent = chunks_sent, chunks_total = chunks_total, rate_bps = rate_bps)
+        }
+        TransferEvent::BlastComplete { chunks_sent, duration_ms, retransmits, effective_mbps } => {
+            emit!(lifecycle, component, tid, "blast_complete", chunks_sent = chunks_sent, duration_ms = duration_ms, retransmits = retransmits, effective_mbps = effective_mbps)
+        }
+        TransferEvent::RetransmitSent { chunk_idx, frame_count } => {
+            emit!(lifecycle, component, tid, "retransmit_sent", chunk_idx = chunk_idx, frame_count = frame_count)
+        }
+        TransferEvent::TransferComplete { total_bytes, duration_ms, retransmits } => {
+            emit!(lifecycle, component, tid, "transfer_complete", total_bytes = total_bytes, duration_ms = duration_ms, retransmits = retransmits)
+        }
+        TransferEvent::Error { message } => {
+            emit!(lifecycle, component, tid, "error", error = message.as_str())
+        }
+        TransferEvent::VacuumStarted { bind_addr } => {
+            emit!(lifecycle, component, tid, "vacuum_started", bind_addr = bind_addr.as_str())
+        }
+        TransferEvent::VacuumProgress { frames_received, from } => {
+            emit!(lifecycle, component, tid, "vacuum_progress", frames_received = frames_received, from = from.as_str())
+        }
+        TransferEvent::TransferIdMismatch { got, from } => {
+            emit!(lifecycle, component, tid, "transfer_id_mismatch", got = hex::encode(got).as_str(), from = from.as_str())
+        }
+        TransferEvent::LossHistogram { bucket_ms, buckets } => {
+            emit!(lifecycle, component, tid, "loss_histogram", bucket_ms = bucket_ms, buckets = tracing::field::debug(buckets))
+        }
+        TransferEvent::MtuProbed { frame_payload } => {
+            emit!(lifecycle, component, tid, "mtu_probed", frame_payload = frame_payload)
+        }
+        TransferEvent::FecRecovered { chunk_idx, frame_count } => {
+            emit!(lifecycle, component, tid, "fec_recovered", chunk_idx = chunk_idx, frame_count = frame_count)
+        }
+        TransferEvent::SocketDrops { dropped, new } => {
+            emit!(lifecycle, component, tid, "socket_drops", dropped = dropped, new = new)
+        }
+        TransferEvent::AssemblerLagging { queued } => {
+            emit!(lifecycle, component, tid, "assembler_lagging", queued = queued)
         }
     }
 }
@@ -209,3 +353,141 @@ pub struct NullLogger;
 impl TransferLogger for NullLogger {
     fn log(&self, _entry: TransferLog) {}
 }
+
+/// Default number of events a `RingBufferLogger` holds.
+pub const RING_LOG_CAPACITY: usize = 1024;
+
+/// Upper bound on `RingBufferLogger` capacity, whatever it is built with.
+pub const MAX_RING_LOG_CAPACITY: usize = 16 * 1024;
+
+/// Logger that keeps the most recent events in memory for the caller to
+/// fetch, for apps with no console to read tracing output from. Once full,
+/// each new event evicts the oldest.
+pub struct RingBufferLogger {
+    capacity: usize,
+    ring: Mutex<Ring>,
+}
+
+#[derive(Default)]
+struct Ring {
+    /// `(unix_ms, entry)`, oldest first.
+    entries: VecDeque<(u64, TransferLog)>,
+    /// Events evicted since the last drain.
+    dropped: u64,
+}
+
+impl RingBufferLogger {
+    /// Buffer up to `capacity` events, clamped to `1..=MAX_RING_LOG_CAPACITY`.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.clamp(1, MAX_RING_LOG_CAPACITY),
+            ring: Mutex::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take every buffered event, oldest first, and clear the buffer:
+    /// `{"dropped":N,"events":[{"ts_ms":N,"component":"...","transfer_id":"<hex>","event":"..."},...]}`.
+    /// `event` is the event's text form; `dropped` counts events evicted
+    /// since the previous drain.
+    pub fn drain_json(&self) -> String {
+        let (entries, dropped) = {
+            let mut ring = self.ring.lock().unwrap();
+            (std::mem::take(&mut ring.entries), std::mem::take(&mut ring.dropped))
+        };
+        let mut json = format!(r#"{{"dropped":{},"events":["#, dropped);
+        for (i, (ts_ms, entry)) in entries.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                r#"{{"ts_ms":{},"component":"{}","transfer_id":"{}","event":"#,
+                ts_ms,
+                entry.component,
+                hex::encode(entry.transfer_id),
+            );
+            push_json_string(&mut json, &entry.event.to_string());
+            json.push('}');
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+impl Default for RingBufferLogger {
+    fn default() -> Self {
+        Self::new(RING_LOG_CAPACITY)
+    }
+}
+
+impl TransferLogger for RingBufferLogger {
+    fn log(&self, entry: TransferLog) {
+        let ts_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let mut ring = self.ring.lock().unwrap();
+        if ring.entries.len() == self.capacity {
+            ring.entries.pop_front();
+            ring.dropped += 1;
+        }
+        ring.entries.push_back((ts_ms, entry));
+    }
+}
+
+/// Append `s` as a quoted, escaped JSON string.
+fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(message: &str) -> TransferLog {
+        TransferLog {
+            component: "receiver",
+            transfer_id: [0xab; 16],
+            event: TransferEvent::Error { message: message.into() },
+        }
+    }
+
+    #[test]
+    fn test_ring_buffer_evicts_and_drains() {
+        let logger = RingBufferLogger::new(2);
+        logger.log(error("first"));
+        logger.log(error("second"));
+        logger.log(error("say \"third\"\n"));
+        assert_eq!(logger.len(), 2);
+
+        let json = logger.drain_json();
+        assert!(json.starts_with(r#"{"dropped":1,"events":[{"ts_ms":"#), "{}", json);
+        assert!(!json.contains("first"));
+        assert!(json.contains(r#""component":"receiver","transfer_id":"abababababababababababababababab","event":"error: second"}"#));
+        assert!(json.contains(r#""event":"error: say \"third\"\n"}"#), "{}", json);
+
+        // Draining clears the buffer and the dropped count.
+        assert!(logger.is_empty());
+        assert_eq!(logger.drain_json(), r#"{"dropped":0,"events":[]}"#);
+    }
+}
@@ -0,0 +1,350 @@
+//! Path MTU probing for the UDP blast.
+//!
+//! Before the first chunk goes out, the sender sends don't-fragment probe
+//! frames of increasing size (chunk index `PROBE_CHUNK_INDEX`, payload padded
+//! to the candidate size) and the receiver echoes each probe's header back.
+//! The largest echoed size becomes the transfer's frame payload, which on a
+//! tunnel with a small MTU can be below `FRAME_PAYLOAD`. If even the smallest
+//! candidate goes unanswered, the conservative `FRAME_PAYLOAD` stays.
+//!
+//! Receivers aren't told the result: every frame but a chunk's last carries
+//! exactly one payload, so the assembler learns the stride from the data.
+//! Receivers that predate probing never echo, so senders fall back to the
+//! default against them.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::protocol::*;
+
+/// Path MTUs to try, smallest first: the IPv6 minimum, a WireGuard tunnel,
+/// Ethernet, a step for tunnels and baby-jumbo links, and a full jumbo frame.
+pub const PROBE_MTUS: [usize; 5] = [1280, 1420, 1500, 4000, 9000];
+
+/// Probes sent per candidate size before giving up on it.
+pub const PROBE_ATTEMPTS: u32 = 3;
+
+/// How long to wait for each probe's echo.
+pub const PROBE_TIMEOUT_MS: u64 = 150;
+
+/// IPv4 + UDP header bytes in front of every frame.
+const IP_UDP_OVERHEAD: usize = 28;
+
+/// IPv6 + UDP header bytes in front of every frame.
+const IPV6_UDP_OVERHEAD: usize = 48;
+
+/// Frame payload that fits in a path MTU of `mtu` bytes over IPv4.
+pub const fn payload_for_mtu(mtu: usize) -> usize {
+    mtu - IP_UDP_OVERHEAD - FRAME_HEADER
+}
+
+/// Frame payload that fits in a path MTU of `mtu` bytes to `target`.
+fn payload_for_path(mtu: usize, target: SocketAddr) -> usize {
+    let overhead = match target {
+        SocketAddr::V6(v6) if v6.ip().to_ipv4_mapped().is_none() => IPV6_UDP_OVERHEAD,
+        _ => IP_UDP_OVERHEAD,
+    };
+    mtu - overhead - FRAME_HEADER
+}
+
+/// Probe the path to `target` and return the frame payload size to use.
+///
+/// Never fails: socket errors, missing echoes, and platforms without a
+/// don't-fragment option all yield `FRAME_PAYLOAD`. The socket's read timeout
+/// and fragmentation setting are restored afterwards.
+pub fn probe_frame_payload(socket: &UdpSocket, target: SocketAddr, transfer_id: &[u8; 16]) -> usize {
+    if set_dont_fragment(socket, true).is_err() {
+        return FRAME_PAYLOAD;
+    }
+    let previous_timeout = socket.read_timeout().ok().flatten();
+
+    let padding = vec![0u8; MAX_FRAME_PAYLOAD];
+    let mut send_buf = vec![0u8; MAX_FRAME];
+    let mut recv_buf = vec![0u8; MAX_FRAME];
+    let mut best = None;
+
+    for (probe_idx, &mtu) in PROBE_MTUS.iter().enumerate() {
+        let payload = payload_for_path(mtu, target);
+        let len = encode_frame(
+            &mut send_buf,
+            transfer_id,
+            PROBE_CHUNK_INDEX,
+            probe_idx as u16,
+            0,
+            &padding[..payload],
+        );
+        if !probe(socket, target, transfer_id, probe_idx as u16, &send_buf[..len], &mut recv_buf) {
+            break;
+        }
+        best = Some(payload);
+    }
+
+    let _ = socket.set_read_timeout(previous_timeout);
+    let _ = set_dont_fragment(socket, false);
+    // No echo at all is an older receiver, not a 1280-byte path.
+    best.unwrap_or(FRAME_PAYLOAD)
+}
+
+/// Send one probe frame until its echo comes back or attempts run out.
+fn probe(
+    socket: &UdpSocket,
+    target: SocketAddr,
+    transfer_id: &[u8; 16],
+    probe_idx: u16,
+    frame: &[u8],
+    recv_buf: &mut [u8],
+) -> bool {
+    for _ in 0..PROBE_ATTEMPTS {
+        // EMSGSIZE here means the local link or a cached path MTU already
+        // rules this size out.
+        if socket.send_to(frame, target).is_err() {
+            return false;
+        }
+        let deadline = Instant::now() + Duration::from_millis(PROBE_TIMEOUT_MS);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() || socket.set_read_timeout(Some(left)).is_err() {
+                break;
+            }
+            match socket.recv_from(recv_buf) {
+                // Echoes of earlier, smaller probes carry a different index.
+                Ok((len, from)) if from == target => {
+                    let echoed = decode_frame_header(&recv_buf[..len]).is_some_and(|h| {
+                        h.chunk_index == PROBE_CHUNK_INDEX
+                            && h.frame_index == probe_idx
+                            && h.transfer_id == *transfer_id
+                    });
+                    if echoed {
+                        return true;
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    }
+    false
+}
+
+/// Receiver side: answer a probe frame by echoing its header to the sender.
+pub fn echo_probe(socket: &UdpSocket, frame: &[u8], from: SocketAddr) {
+    if frame.len() >= FRAME_HEADER {
+        let _ = socket.send_to(&frame[..FRAME_HEADER], from);
+    }
+}
+
+/// Whether `socket` is an IPv6 (possibly dual-stack) socket.
+fn is_ipv6(socket: &UdpSocket) -> io::Result<bool> {
+    Ok(socket.local_addr()?.is_ipv6())
+}
+
+/// Toggle don't-fragment on outgoing datagrams: the IPv4 DF bit, or on an
+/// IPv6 socket no fragmentation at the source. A dual-stack socket also
+/// gets the IPv4 option, for peers reached over v4-mapped addresses.
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &UdpSocket, on: bool) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let fd = socket.as_raw_fd();
+    let mode = if on { libc::IP_PMTUDISC_DO } else { libc::IP_PMTUDISC_WANT };
+    if !is_ipv6(socket)? {
+        return setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, mode);
+    }
+    let mode6 = if on { libc::IPV6_PMTUDISC_DO } else { libc::IPV6_PMTUDISC_WANT };
+    setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, mode6)?;
+    let _ = setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, mode);
+    Ok(())
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn set_dont_fragment(socket: &UdpSocket, on: bool) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // <netinet6/in6.h>; not exported by libc for Apple targets.
+    const IPV6_DONTFRAG: libc::c_int = 62;
+
+    let fd = socket.as_raw_fd();
+    if !is_ipv6(socket)? {
+        return setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_DONTFRAG, on as libc::c_int);
+    }
+    setsockopt_int(fd, libc::IPPROTO_IPV6, IPV6_DONTFRAG, on as libc::c_int)?;
+    let _ = setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_DONTFRAG, on as libc::c_int);
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios"))]
+fn setsockopt_int(fd: libc::c_int, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_dont_fragment(socket: &UdpSocket, on: bool) -> io::Result<()> {
+    use std::os::windows::io::AsRawSocket;
+
+    const IPPROTO_IP: i32 = 0;
+    const IP_DONTFRAGMENT: i32 = 14;
+    const IPPROTO_IPV6: i32 = 41;
+    const IPV6_DONTFRAG: i32 = 14;
+
+    #[link(name = "ws2_32")]
+    unsafe extern "system" {
+        fn setsockopt(s: usize, level: i32, optname: i32, optval: *const u8, optlen: i32) -> i32;
+    }
+
+    let set = |level: i32, name: i32| {
+        let value = on as u32;
+        let rc = unsafe {
+            setsockopt(
+                socket.as_raw_socket() as usize,
+                level,
+                name,
+                &value as *const u32 as *const u8,
+                std::mem::size_of::<u32>() as i32,
+            )
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    if !is_ipv6(socket)? {
+        return set(IPPROTO_IP, IP_DONTFRAGMENT);
+    }
+    set(IPPROTO_IPV6, IPV6_DONTFRAG)?;
+    let _ = set(IPPROTO_IP, IP_DONTFRAGMENT);
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios", windows)))]
+fn set_dont_fragment(_socket: &UdpSocket, _on: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "don't-fragment not supported"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+
+    use crate::aead::AeadAlgorithm;
+    use crate::harness::{self, Loopback};
+    use crate::sender::{SenderConfig, SenderProgress};
+
+    #[test]
+    fn test_probe_and_assemble_over_loopback() {
+        let dir = harness::scratch_dir("mtu");
+        let input = dir.join("in.bin");
+        let output = dir.join("out.bin");
+
+        let plaintext: Vec<u8> = (0..150_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        std::fs::write(&input, &plaintext).unwrap();
+        let chunk_size = MIN_CHUNK_SIZE;
+
+        // The receiver checks hashes up front, so encrypt the way the sender will.
+        let encrypted_file = harness::seal(&plaintext, chunk_size, AeadAlgorithm::Aes256Gcm);
+        let receiver = Loopback::start(harness::receiver_config(&output, &encrypted_file, encrypted_chunk_size(chunk_size)));
+        let result = receiver
+            .send(
+                SenderConfig { probe_mtu: true, ..harness::sender_config(&input, receiver.addr, chunk_size) },
+                Arc::new(SenderProgress::new()),
+            )
+            .unwrap();
+        let rx_progress = receiver.progress.clone();
+        receiver.join().unwrap();
+
+        // Loopback has no 9000-byte limit, so the probe tops out.
+        assert_eq!(result.frame_payload, MAX_FRAME_PAYLOAD);
+        assert_eq!(rx_progress.frame_payload.load(Ordering::Relaxed), MAX_FRAME_PAYLOAD as u64);
+        assert_eq!(std::fs::read(&output).unwrap(), encrypted_file);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_payload_leaves_room_for_the_ip_header() {
+        let v4: SocketAddr = "192.0.2.1:9".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:9".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:9".parse().unwrap();
+        assert_eq!(payload_for_path(1500, v4), 1448);
+        assert_eq!(payload_for_path(1500, mapped), 1448);
+        assert_eq!(payload_for_path(1500, v6), 1428);
+        assert_eq!(payload_for_path(9000, v4), MAX_FRAME_PAYLOAD);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dont_fragment_follows_the_socket_family() {
+        use std::os::fd::AsRawFd;
+
+        let get = |socket: &UdpSocket, level, name| {
+            let mut value: libc::c_int = -1;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let rc = unsafe {
+                libc::getsockopt(socket.as_raw_fd(), level, name, &mut value as *mut _ as *mut libc::c_void, &mut len)
+            };
+            assert_eq!(rc, 0);
+            value
+        };
+
+        let v4 = UdpSocket::bind("127.0.0.1:0").unwrap();
+        set_dont_fragment(&v4, true).unwrap();
+        assert_eq!(get(&v4, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER), libc::IP_PMTUDISC_DO);
+
+        let Ok(v6) = UdpSocket::bind("[::1]:0") else { return };  // no IPv6 here
+        set_dont_fragment(&v6, true).unwrap();
+        assert_eq!(get(&v6, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER), libc::IPV6_PMTUDISC_DO);
+        set_dont_fragment(&v6, false).unwrap();
+        assert_eq!(get(&v6, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER), libc::IPV6_PMTUDISC_WANT);
+    }
+
+    /// Echo probes up to `max_datagram` bytes, like a receiver behind a path
+    /// that drops anything bigger. Stops once `probes` have been seen.
+    fn capped_echoer(max_datagram: usize, probes: usize) -> (SocketAddr, std::thread::JoinHandle<()>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let thread = std::thread::spawn(move || {
+            socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut buf = vec![0u8; MAX_FRAME];
+            for _ in 0..probes {
+                let Ok((len, from)) = socket.recv_from(&mut buf) else { return };
+                if len + IP_UDP_OVERHEAD <= max_datagram {
+                    echo_probe(&socket, &buf[..len], from);
+                }
+            }
+        });
+        (addr, thread)
+    }
+
+    #[test]
+    fn test_probe_settles_below_frame_payload_on_a_small_mtu_path() {
+        let id = [3u8; 16];
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        // A 1420-byte tunnel: 1280 and 1420 echo, then three 1500s are lost.
+        let (target, echoer) = capped_echoer(1420, 5);
+        assert_eq!(probe_frame_payload(&socket, target, &id), payload_for_mtu(1420));
+        assert!(payload_for_mtu(1420) < FRAME_PAYLOAD);
+        // Receivers still take chunks cut that fine.
+        let encrypted = ENCRYPTED_CHUNK_SIZE;
+        assert!(frames_for_chunk_with(encrypted, payload_for_mtu(1420)) <= max_frames_per_chunk(encrypted));
+        echoer.join().unwrap();
+
+        // A receiver that never echoes keeps the default.
+        let (target, echoer) = capped_echoer(0, PROBE_ATTEMPTS as usize);
+        assert_eq!(probe_frame_payload(&socket, target, &id), FRAME_PAYLOAD);
+        echoer.join().unwrap();
+    }
+}
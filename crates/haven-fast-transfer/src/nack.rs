@@ -0,0 +1,228 @@
+//! When the receiver may NACK a chunk.
+//!
+//! The assembler scans for missing frames every `NACK_SCAN_INTERVAL_MS`.
+//! NACKing every incomplete chunk on every scan floods the control channel
+//! on a lossy path, and asks again for frames whose retransmits are still on
+//! their way, so a chunk is only NACKed once:
+//!
+//! - no frame of it has arrived for a scan interval (the rest of its blast
+//!   may still be coming), and
+//! - a retransmit timeout has passed since its last NACK.
+//!
+//! The timeout tracks the NACK round trip, from a chunk's NACK to the next
+//! frame of it to arrive, smoothed as in RFC 6298. Until one has been timed
+//! it is `DEFAULT_RTT_MS`. The assembler hands every chunk due in a scan to
+//! the NACK callback at once.
+
+use std::time::{Duration, Instant};
+
+use crate::protocol::{DEFAULT_RTT_MS, MAX_NACK_BACKOFF_MS, NACK_SCAN_INTERVAL_MS};
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ChunkState {
+    last_frame: Option<Instant>,
+    last_nack: Option<Instant>,
+    /// First NACK no frame has answered yet; the round trip is timed from
+    /// here, so repeated NACKs only make it look longer.
+    unanswered_since: Option<Instant>,
+}
+
+pub struct NackPacer {
+    chunks: Vec<ChunkState>,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl NackPacer {
+    pub fn new(chunk_count: u32) -> Self {
+        Self {
+            chunks: vec![ChunkState::default(); chunk_count as usize],
+            srtt: None,
+            rttvar: Duration::ZERO,
+        }
+    }
+
+    /// A frame (data or parity) of `chunk` arrived.
+    pub fn frame_arrived(&mut self, chunk: u32, now: Instant) {
+        let Some(state) = self.chunks.get_mut(chunk as usize) else {
+            return;
+        };
+        state.last_frame = Some(now);
+        if let Some(nacked) = state.unanswered_since.take() {
+            self.sample(now.saturating_duration_since(nacked));
+        }
+    }
+
+    /// Whether `chunk` may be NACKed now.
+    pub fn due(&self, chunk: u32, now: Instant) -> bool {
+        let Some(state) = self.chunks.get(chunk as usize) else {
+            return false;
+        };
+        let quiet = state
+            .last_frame
+            .is_none_or(|t| now.saturating_duration_since(t) >= Duration::from_millis(NACK_SCAN_INTERVAL_MS));
+        let backed_off = state.last_nack.is_none_or(|t| now.saturating_duration_since(t) >= self.timeout());
+        quiet && backed_off
+    }
+
+    /// `chunk` was NACKed.
+    pub fn nacked(&mut self, chunk: u32, now: Instant) {
+        if let Some(state) = self.chunks.get_mut(chunk as usize) {
+            state.last_nack = Some(now);
+            state.unanswered_since.get_or_insert(now);
+        }
+    }
+
+    /// How long after a chunk's NACK it may be NACKed again: the smoothed
+    /// round trip plus four deviations, between one scan interval and
+    /// `MAX_NACK_BACKOFF_MS`.
+    pub fn timeout(&self) -> Duration {
+        let timeout = match self.srtt {
+            Some(srtt) => srtt + self.rttvar * 4,
+            None => Duration::from_millis(DEFAULT_RTT_MS),
+        };
+        timeout.clamp(Duration::from_millis(NACK_SCAN_INTERVAL_MS), Duration::from_millis(MAX_NACK_BACKOFF_MS))
+    }
+
+    /// Smoothed NACK round trip, once one has been timed.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::UdpSocket;
+    use std::sync::Arc;
+
+    use crate::harness;
+    use crate::protocol::{FRAME_PAYLOAD, MAX_FRAME, MIN_CHUNK_SIZE, encode_frame, encrypted_chunk_size, frames_for_chunk};
+    use crate::receiver::{ReceiverConfig, ReceiverProgress, run_receiver};
+    use crate::sender::NackMessage;
+
+    #[test]
+    fn test_waits_for_quiet_and_a_round_trip() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut pacer = NackPacer::new(2);
+        assert_eq!(pacer.timeout(), Duration::from_millis(DEFAULT_RTT_MS));
+
+        // Still receiving: not yet.
+        pacer.frame_arrived(0, ms(0));
+        assert!(!pacer.due(0, ms(10)));
+        assert!(pacer.due(0, ms(NACK_SCAN_INTERVAL_MS)));
+
+        // NACKed at 50; the retransmit is back 200 ms later.
+        pacer.nacked(0, ms(50));
+        assert!(!pacer.due(0, ms(100)));
+        pacer.frame_arrived(0, ms(250));
+        assert_eq!(pacer.srtt(), Some(Duration::from_millis(200)));
+        assert_eq!(pacer.timeout(), Duration::from_millis(600));
+        assert!(!pacer.due(0, ms(600)));
+        assert!(pacer.due(0, ms(650)));
+
+        // The other chunk was never heard from or NACKed.
+        assert!(pacer.due(1, ms(0)));
+        assert!(!pacer.due(2, ms(0)), "out of range");
+    }
+
+    #[test]
+    fn test_heavy_loss_nacks_stay_bounded() {
+        /// Round trip the stand-in sender takes to answer a NACK.
+        const RTT: Duration = Duration::from_millis(80);
+
+        let dir = harness::scratch_dir("nack");
+        let output = dir.join("out.bin");
+
+        // Stands in for encrypted chunks: the receiver only checks hashes.
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE);
+        let file: Vec<u8> = (0..4 * chunk_size).map(|i| (i * 7 % 251) as u8).collect();
+        let transfer_id = harness::TRANSFER_ID;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = socket.local_addr().unwrap();
+        let rx_config = ReceiverConfig {
+            pre_bound_socket: Some(socket.into()),
+            ..harness::receiver_config(&output, &file, chunk_size)
+        };
+        // Timestamped as sent: the stand-in sender answers each an RTT later.
+        let (nack_tx, nack_rx) = crossbeam_channel::unbounded::<(Instant, Vec<NackMessage>)>();
+        let receiver = std::thread::spawn(move || {
+            run_receiver(
+                rx_config,
+                Arc::new(ReceiverProgress::new()),
+                Box::new(move |nacks| {
+                    let _ = nack_tx.send((Instant::now(), nacks));
+                }),
+            )
+        });
+
+        // Every other frame is lost, first sends and retransmits alike
+        // (bar each chunk's first, so the stride is known early).
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buf = vec![0u8; MAX_FRAME];
+        let mut sent = 0usize;
+        let mut send = |chunk_index: u32, frame_index: u16| {
+            sent += 1;
+            if frame_index > 0 && sent.is_multiple_of(2) {
+                return;
+            }
+            let chunk = &file[chunk_index as usize * chunk_size..][..chunk_size];
+            let offset = frame_index as usize * FRAME_PAYLOAD;
+            let payload = &chunk[offset..(offset + FRAME_PAYLOAD).min(chunk.len())];
+            let frame_count = frames_for_chunk(chunk.len());
+            let n = encode_frame(&mut buf, &transfer_id, chunk_index, frame_index, frame_count, payload);
+            tx.send_to(&buf[..n], target).unwrap();
+        };
+
+        let started = Instant::now();
+        for chunk_index in 0..4 {
+            for frame_index in 0..frames_for_chunk(chunk_size) {
+                send(chunk_index, frame_index);
+            }
+        }
+        let mut messages = 0u128;
+        let mut nacked_at: HashMap<u32, Vec<Instant>> = HashMap::new();
+        while !receiver.is_finished() {
+            let Ok((at, nacks)) = nack_rx.recv_timeout(Duration::from_millis(20)) else {
+                continue;
+            };
+            messages += 1;
+            std::thread::sleep((at + RTT).saturating_duration_since(Instant::now()));
+            for nack in nacks {
+                nacked_at.entry(nack.chunk_index).or_default().push(at);
+                for frame_index in nack.missing_frames {
+                    send(nack.chunk_index, frame_index);
+                }
+            }
+        }
+        receiver.join().unwrap().unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), file);
+
+        // At most one message per scan, and no chunk NACKed again before
+        // its retransmits could have come back.
+        assert!(!nacked_at.is_empty());
+        assert!(messages <= started.elapsed().as_millis() / NACK_SCAN_INTERVAL_MS as u128 + 1);
+        for times in nacked_at.values() {
+            for pair in times.windows(2) {
+                assert!(pair[1] - pair[0] >= RTT, "re-NACKed after {:?}", pair[1] - pair[0]);
+            }
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,262 @@
+//! UDP socket setup for either address family.
+//!
+//! Sockets take their domain from the address they bind or send to, so
+//! IPv6-only hosts work. A socket bound to `[::]` is made dual-stack where
+//! the OS allows it, so one server socket still serves IPv4 peers (seen as
+//! `::ffff:a.b.c.d`).
+
+use std::io;
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::protocol::UDP_RECV_BUFFER;
+
+/// An unbound UDP socket in `addr`'s family, dual-stack if `addr` is the
+/// IPv6 wildcard.
+pub fn udp_socket_for(addr: SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        // Some platforms pin this; v6 peers still work if it fails.
+        let _ = socket.set_only_v6(false);
+    }
+    Ok(socket)
+}
+
+/// [`udp_socket_for`] bound to `addr`.
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = udp_socket_for(addr)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// The wildcard address in `peer`'s family with port 0: where a socket that
+/// talks to `peer` binds.
+pub fn unspecified_for(peer: SocketAddr) -> SocketAddr {
+    let ip = match peer {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    SocketAddr::new(ip, 0)
+}
+
+/// Join an IP literal and a port. The host may be IPv4, bare IPv6 (`::1`)
+/// or bracketed IPv6 (`[::1]`, as in URLs).
+pub fn host_port(host: &str, port: u16) -> Result<SocketAddr, AddrParseError> {
+    let host = host.trim();
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    Ok(SocketAddr::new(host.parse()?, port))
+}
+
+/// Environment variable overriding [`UDP_RECV_BUFFER`], in bytes.
+pub const RECV_BUFFER_ENV: &str = "HAVEN_UDP_RECV_BUFFER";
+
+/// The receive buffer to ask for: [`RECV_BUFFER_ENV`] if set to a positive
+/// number, else [`UDP_RECV_BUFFER`]. Linux caps the request at
+/// `net.core.rmem_max`; raise that too on fast NICs.
+pub fn recv_buffer_from_env() -> usize {
+    match std::env::var(RECV_BUFFER_ENV) {
+        Ok(v) => match v.trim().parse::<usize>() {
+            Ok(bytes) if bytes > 0 => bytes,
+            _ => {
+                tracing::warn!("Ignoring {}={:?}: not a positive byte count", RECV_BUFFER_ENV, v);
+                UDP_RECV_BUFFER
+            }
+        },
+        Err(_) => UDP_RECV_BUFFER,
+    }
+}
+
+/// Environment variable for how many sockets the file server binds on its
+/// UDP port, each drained by its own receiver thread.
+pub const RECV_WORKERS_ENV: &str = "HAVEN_UDP_RECV_WORKERS";
+
+/// [`RECV_WORKERS_ENV`] if set to a positive number, else 1.
+pub fn recv_workers_from_env() -> usize {
+    match std::env::var(RECV_WORKERS_ENV) {
+        Ok(v) => match v.trim().parse::<usize>() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                tracing::warn!("Ignoring {}={:?}: not a positive count", RECV_WORKERS_ENV, v);
+                1
+            }
+        },
+        Err(_) => 1,
+    }
+}
+
+/// Bind `count` UDP sockets to the same `addr` with `SO_REUSEPORT`, so the
+/// kernel spreads incoming datagrams across them and each can be drained by
+/// its own thread. `configure` runs on each socket before it binds. The
+/// first socket binds `addr`; the rest bind whatever port it got, so port 0
+/// works.
+///
+/// The kernel picks a socket by hashing the datagram's source and
+/// destination, so one sender's frames all land on the same socket: this
+/// spreads concurrent uploads across cores, not a single flow. Only Linux
+/// balances this way; elsewhere one socket is bound whatever `count` is.
+pub fn bind_udp_group(
+    addr: SocketAddr,
+    count: usize,
+    configure: impl Fn(&Socket) -> io::Result<()>,
+) -> io::Result<Vec<UdpSocket>> {
+    let count = if cfg!(target_os = "linux") { count.max(1) } else { 1 };
+    let mut sockets: Vec<UdpSocket> = Vec::with_capacity(count);
+    for _ in 0..count {
+        let bind_addr = match sockets.first() {
+            Some(first) => first.local_addr()?,
+            None => addr,
+        };
+        let socket = udp_socket_for(bind_addr)?;
+        if count > 1 {
+            set_reuse_port(&socket)?;
+        }
+        configure(&socket)?;
+        socket.bind(&bind_addr.into())?;
+        sockets.push(socket.into());
+    }
+    Ok(sockets)
+}
+
+#[cfg(target_os = "linux")]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let on: libc::c_int = 1;
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_REUSEPORT,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Ok(())
+}
+
+/// Datagrams the kernel has dropped on `socket` because its receive buffer
+/// was full (the `drops` column of `/proc/net/udp`, the same counter
+/// `SO_RXQ_OVFL` reports). Rising drops with an idle CPU mean the reader
+/// isn't draining the socket fast enough. `None` where the OS doesn't
+/// expose it.
+#[cfg(target_os = "linux")]
+pub fn udp_drops(socket: &UdpSocket) -> Option<u64> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::MetadataExt;
+
+    let inode = std::fs::metadata(format!("/proc/self/fd/{}", socket.as_raw_fd())).ok()?.ino().to_string();
+    ["/proc/net/udp", "/proc/net/udp6"].iter().find_map(|table| {
+        let table = std::fs::read_to_string(table).ok()?;
+        table.lines().skip(1).find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            (fields.get(9) == Some(&inode.as_str())).then(|| fields.get(12)?.parse().ok())?
+        })
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn udp_drops(_socket: &UdpSocket) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::aead::AeadAlgorithm;
+    use crate::harness::{self, Loopback};
+    use crate::pool::SocketSpec;
+    use crate::protocol::{MIN_CHUNK_SIZE, encrypted_chunk_size};
+    use crate::receiver::ReceiverConfig;
+    use crate::sender::SenderProgress;
+
+    #[test]
+    fn test_host_port_forms() {
+        assert_eq!(host_port("127.0.0.1", 80).unwrap(), "127.0.0.1:80".parse().unwrap());
+        assert_eq!(host_port("::1", 80).unwrap(), "[::1]:80".parse().unwrap());
+        assert_eq!(host_port("[::1]", 80).unwrap(), "[::1]:80".parse().unwrap());
+        assert_eq!(host_port("::", 3211).unwrap(), "[::]:3211".parse().unwrap());
+        assert!(host_port("example.com", 80).is_err());
+        assert!(host_port("[127.0.0.1", 80).is_err());
+
+        assert_eq!(unspecified_for("10.0.0.1:9".parse().unwrap()), "0.0.0.0:0".parse().unwrap());
+        assert_eq!(unspecified_for("[fe80::1]:9".parse().unwrap()), "[::]:0".parse().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_tiny_recv_buffer_counts_drops() {
+        // The kernel rounds a 1-byte request up to its minimum, a few KB.
+        let spec = SocketSpec { recv_buffer: 1, ..SocketSpec::receiver("127.0.0.1:0".parse().unwrap()) };
+        let rx = spec.open().unwrap();
+        assert_eq!(udp_drops(&rx), Some(0));
+
+        let tx = bind_udp(unspecified_for(rx.local_addr().unwrap())).unwrap();
+        let datagram = [0u8; 1200];
+        for _ in 0..500 {
+            tx.send_to(&datagram, rx.local_addr().unwrap()).unwrap();
+        }
+        let drops = udp_drops(&rx).unwrap();
+        assert!(drops >= 250, "only {drops} of 500 dropped");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_reuseport_group_spreads_flows() {
+        let group = bind_udp_group("127.0.0.1:0".parse().unwrap(), 4, |_| Ok(())).unwrap();
+        let addr = group[0].local_addr().unwrap();
+        assert!(group.iter().all(|s| s.local_addr().unwrap() == addr));
+
+        for _ in 0..32 {
+            bind_udp("127.0.0.1:0".parse().unwrap()).unwrap().send_to(b"hi", addr).unwrap();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let counts: Vec<usize> = group
+            .iter()
+            .map(|s| {
+                s.set_nonblocking(true).unwrap();
+                std::iter::from_fn(|| s.recv(&mut [0u8; 8]).ok()).count()
+            })
+            .collect();
+        assert_eq!(counts.iter().sum::<usize>(), 32);
+        assert!(counts.iter().filter(|&&n| n > 0).count() > 1, "{:?}", counts);
+    }
+
+    #[test]
+    fn test_transfer_over_ipv6_loopback() {
+        if std::net::UdpSocket::bind("[::1]:0").is_err() {
+            return;  // no IPv6 here
+        }
+        let dir = harness::scratch_dir("v6");
+        let input = dir.join("in.bin");
+        let output = dir.join("out.bin");
+
+        let plaintext: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&input, &plaintext).unwrap();
+
+        // No pre-bound socket: the receiver binds [::1] itself.
+        let encrypted_file = harness::seal(&plaintext, MIN_CHUNK_SIZE, AeadAlgorithm::Aes256Gcm);
+        let receiver = Loopback::start(ReceiverConfig {
+            bind_addr: "[::1]:0".parse().unwrap(),
+            ..harness::receiver_config(&output, &encrypted_file, encrypted_chunk_size(MIN_CHUNK_SIZE))
+        });
+        assert!(receiver.addr.is_ipv6());
+        receiver
+            .send(harness::sender_config(&input, receiver.addr, MIN_CHUNK_SIZE), Arc::new(SenderProgress::new()))
+            .unwrap();
+        receiver.join().unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), encrypted_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,229 @@
+//! Frame pacing: how the blaster waits between frames to hold its rate.
+//!
+//! Every strategy keeps the same schedule, one frame per interval measured
+//! from the start of the chunk, so a slow `send_to` doesn't push the rate
+//! down; they differ only in how they wait for the next slot.
+//!
+//! - [`Pacing::BusySpin`] spins the whole interval: the most precise, but
+//!   it keeps a core at 100% for the length of the blast even when the rate
+//!   is a trickle.
+//! - [`Pacing::Hybrid`] sleeps all but the last [`SPIN_TAIL`] of an
+//!   interval and spins the rest, so it is as precise as spinning but idles
+//!   through long intervals. At multi-gigabit rates the interval is shorter
+//!   than the tail and it spins just like `BusySpin`.
+//! - [`Pacing::TokenBucket`] never spins: frames go out back to back until
+//!   the schedule is [`BURST`] ahead of the clock, then it sleeps until the
+//!   clock catches up. The average rate holds; individual frames are bursty.
+
+use std::time::{Duration, Instant};
+
+/// How close to a frame's slot `Pacing::Hybrid` stops sleeping and spins,
+/// covering the OS's usual timer overshoot.
+pub const SPIN_TAIL: Duration = Duration::from_micros(200);
+
+/// How far `Pacing::TokenBucket` lets the schedule run ahead before
+/// sleeping.
+pub const BURST: Duration = Duration::from_millis(2);
+
+/// How far behind schedule (an oversleep, a descheduled thread, a full
+/// send buffer) the pacer still catches up by sending without waiting.
+/// Further behind, it starts the schedule over rather than burst.
+pub const MAX_LAG: Duration = Duration::from_millis(10);
+
+/// How the sender waits between frames (`SenderConfig::pacing`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Pacing {
+    /// Spin for the whole interval.
+    BusySpin,
+    /// Sleep most of the interval, spin the tail.
+    #[default]
+    Hybrid,
+    /// Send in bursts of up to `BURST`, then sleep.
+    TokenBucket,
+}
+
+/// Spaces frames `interval` apart using a [`Pacing`] strategy.
+pub struct Pacer {
+    pacing: Pacing,
+    interval: Duration,
+    next: Instant,
+}
+
+impl Pacer {
+    /// A pacer whose first frame is due now. A zero `interval` doesn't pace.
+    pub fn new(pacing: Pacing, interval: Duration) -> Self {
+        Self::starting_at(pacing, interval, Instant::now())
+    }
+
+    fn starting_at(pacing: Pacing, interval: Duration, start: Instant) -> Self {
+        Self { pacing, interval, next: start }
+    }
+
+    /// Call after each frame is sent: waits until the next frame's slot.
+    pub fn pace(&mut self) {
+        let wait = self.schedule(Instant::now());
+        if !wait.sleep.is_zero() {
+            std::thread::sleep(wait.sleep);
+        }
+        if let Some(deadline) = wait.spin_until {
+            spin_until(deadline);
+        }
+    }
+
+    /// Advance the schedule by one frame sent at `now` and say how to wait
+    /// for the next slot.
+    fn schedule(&mut self, now: Instant) -> Wait {
+        let go = Wait { sleep: Duration::ZERO, spin_until: None };
+        if self.interval.is_zero() {
+            return go;
+        }
+        self.next += self.interval;
+        let Some(ahead) = self.next.checked_duration_since(now).filter(|d| !d.is_zero()) else {
+            // Behind schedule: send without waiting to catch up.
+            if now - self.next > MAX_LAG {
+                self.next = now;
+            }
+            return go;
+        };
+        match self.pacing {
+            Pacing::BusySpin => Wait { sleep: Duration::ZERO, spin_until: Some(self.next) },
+            Pacing::Hybrid => Wait { sleep: ahead.saturating_sub(SPIN_TAIL), spin_until: Some(self.next) },
+            Pacing::TokenBucket if ahead > BURST => Wait { sleep: ahead, spin_until: None },
+            Pacing::TokenBucket => go,
+        }
+    }
+}
+
+/// How [`Pacer::pace`] waits: sleep, then spin until a deadline.
+#[derive(Debug, PartialEq, Eq)]
+struct Wait {
+    sleep: Duration,
+    spin_until: Option<Instant>,
+}
+
+fn spin_until(deadline: Instant) {
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CPU time the calling thread has used.
+    #[cfg(unix)]
+    fn thread_cpu_time() -> Duration {
+        let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+        let rc = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+        assert_eq!(rc, 0);
+        Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+    }
+
+    /// Run `frames` frames through a pacer on a simulated clock where sleeps
+    /// and spins end exactly on time and sending takes `send_time`. Returns
+    /// the clock at the end and every wait.
+    fn simulate(pacing: Pacing, interval: Duration, frames: u32, send_time: Duration) -> (Duration, Vec<Wait>) {
+        let start = Instant::now();
+        let mut pacer = Pacer::starting_at(pacing, interval, start);
+        let mut now = start;
+        let mut waits = Vec::new();
+        for _ in 0..frames {
+            now += send_time;
+            let wait = pacer.schedule(now);
+            now += wait.sleep;
+            now = now.max(wait.spin_until.unwrap_or(now));
+            waits.push(wait);
+        }
+        (now - start, waits)
+    }
+
+    #[test]
+    fn test_strategies_keep_the_same_schedule() {
+        let interval = Duration::from_micros(500);
+        let send_time = Duration::from_micros(20);
+        let expected = interval * 400;
+        for pacing in [Pacing::BusySpin, Pacing::Hybrid, Pacing::TokenBucket] {
+            let (elapsed, waits) = simulate(pacing, interval, 400, send_time);
+            match pacing {
+                // Never sleeps, lands on every slot.
+                Pacing::BusySpin => {
+                    assert_eq!(elapsed, expected);
+                    assert!(waits.iter().all(|w| w.sleep.is_zero() && w.spin_until.is_some()));
+                }
+                // Sleeps all but the tail of each gap, then spins onto the slot.
+                Pacing::Hybrid => {
+                    assert_eq!(elapsed, expected);
+                    assert!(waits.iter().all(|w| w.sleep == interval - send_time - SPIN_TAIL));
+                }
+                // Runs up to a burst ahead, then sleeps it off; never spins.
+                Pacing::TokenBucket => {
+                    assert!(elapsed <= expected && expected - elapsed <= BURST, "{:?}", elapsed);
+                    assert!(waits.iter().all(|w| w.spin_until.is_none()));
+                    assert!(waits.iter().any(|w| w.sleep > BURST));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_late_sender_catches_up_then_restarts_the_schedule() {
+        let interval = Duration::from_micros(500);
+        let start = Instant::now();
+        let mut pacer = Pacer::starting_at(Pacing::Hybrid, interval, start);
+        let go = Wait { sleep: Duration::ZERO, spin_until: None };
+
+        // 3 ms late: within MAX_LAG, so the next frames go straight out.
+        assert_eq!(pacer.schedule(start + Duration::from_millis(3)), go);
+        assert_eq!(pacer.schedule(start + Duration::from_millis(3)), go);
+
+        // 50 ms late: the missed slots are written off, not burst through.
+        let late = start + Duration::from_millis(50);
+        assert_eq!(pacer.schedule(late), go);
+        let wait = pacer.schedule(late);
+        assert_eq!(wait.spin_until, Some(late + interval));
+    }
+
+    #[test]
+    #[ignore = "wall-clock and CPU timing; run alone on an idle machine"]
+    fn test_strategies_hold_rate_and_hybrid_saves_cpu() {
+        // 400 frames 500 µs apart: 200 ms at 2000 frames/s.
+        let interval = Duration::from_micros(500);
+        let frames = 400u32;
+        let mut cpu = Vec::new();
+        for pacing in [Pacing::BusySpin, Pacing::Hybrid, Pacing::TokenBucket] {
+            #[cfg(unix)]
+            let cpu_before = thread_cpu_time();
+            let started = Instant::now();
+            let mut pacer = Pacer::new(pacing, interval);
+            for _ in 0..frames {
+                pacer.pace();
+            }
+            let elapsed = started.elapsed();
+            #[cfg(unix)]
+            cpu.push(thread_cpu_time() - cpu_before);
+
+            // A thread descheduled past `MAX_LAG` loses that time, so a busy
+            // machine may run a little slow, but pacing never runs fast.
+            let expected = interval * frames;
+            let rate = expected.as_secs_f64() / elapsed.as_secs_f64();
+            assert!((0.8..=1.05).contains(&rate), "{:?}: {:?} for {:?} of frames", pacing, elapsed, expected);
+        }
+
+        // Spinning burns the whole blast; the others mostly sleep.
+        if let [spin, hybrid, bucket] = cpu[..] {
+            assert!(hybrid < spin * 2 / 3, "hybrid used {:?} of CPU, busy-spin {:?}", hybrid, spin);
+            assert!(bucket < spin * 2 / 3, "token bucket used {:?} of CPU, busy-spin {:?}", bucket, spin);
+        }
+    }
+
+    #[test]
+    fn test_zero_interval_does_not_wait() {
+        let started = Instant::now();
+        let mut pacer = Pacer::new(Pacing::BusySpin, Duration::ZERO);
+        for _ in 0..1000 {
+            pacer.pace();
+        }
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+}
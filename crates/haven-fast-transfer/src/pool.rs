@@ -0,0 +1,266 @@
+//! Reusable UDP sockets for fast transfers.
+//!
+//! Each transfer used to bind a fresh ephemeral socket and close it when done.
+//! Under many short concurrent transfers that churns through ports, and on
+//! Windows rapid bind/unbind can run the ephemeral range dry. The pool keeps
+//! sockets it has handed out once the transfer drops them, keyed by their
+//! [`SocketSpec`] (bind address and buffer sizes), and hands them out again.
+//!
+//! Sockets are drained of stray datagrams both when returned and when handed
+//! out, and handed out blocking with no read timeout. Leftovers from an
+//! earlier transfer would be rejected anyway, since every frame and probe
+//! carries its transfer ID.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::protocol::UDP_RECV_BUFFER;
+
+/// Idle sockets kept per spec by [`SocketPool::global`]; more are closed.
+pub const POOL_IDLE_PER_SPEC: usize = 8;
+
+/// Most datagrams read off a socket when draining it.
+const DRAIN_LIMIT: usize = 4096;
+
+/// How a pooled socket is set up. Only sockets bound to port 0 are pooled;
+/// a fixed port can only be bound once anyway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SocketSpec {
+    pub bind_addr: SocketAddr,
+    /// `SO_SNDBUF` in bytes; 0 leaves the OS default.
+    pub send_buffer: usize,
+    /// `SO_RCVBUF` in bytes; 0 leaves the OS default.
+    pub recv_buffer: usize,
+}
+
+impl SocketSpec {
+    /// A blaster socket for sending to `target`.
+    pub fn sender(target: SocketAddr) -> Self {
+        Self { bind_addr: crate::net::unspecified_for(target), send_buffer: UDP_RECV_BUFFER, recv_buffer: 0 }
+    }
+
+    /// A receiver socket bound to `bind_addr`.
+    pub fn receiver(bind_addr: SocketAddr) -> Self {
+        Self { bind_addr, send_buffer: 0, recv_buffer: UDP_RECV_BUFFER }
+    }
+
+    /// Create and bind a new socket to this spec.
+    pub fn open(&self) -> io::Result<UdpSocket> {
+        let socket = crate::net::udp_socket_for(self.bind_addr)?;
+        if self.send_buffer > 0 {
+            socket.set_send_buffer_size(self.send_buffer)?;
+        }
+        if self.recv_buffer > 0 {
+            socket.set_recv_buffer_size(self.recv_buffer)?;
+        }
+        socket.bind(&self.bind_addr.into())?;
+        Ok(socket.into())
+    }
+}
+
+/// Idle sockets by spec.
+pub struct SocketPool {
+    idle: Mutex<HashMap<SocketSpec, Vec<UdpSocket>>>,
+    max_idle_per_spec: usize,
+    created: AtomicU64,
+}
+
+impl SocketPool {
+    pub fn new(max_idle_per_spec: usize) -> Arc<Self> {
+        Arc::new(Self { idle: Mutex::new(HashMap::new()), max_idle_per_spec, created: AtomicU64::new(0) })
+    }
+
+    /// The process-wide pool `run_sender`, `run_raw_sender` and `run_receiver`
+    /// take their sockets from.
+    pub fn global() -> &'static Arc<SocketPool> {
+        static GLOBAL: OnceLock<Arc<SocketPool>> = OnceLock::new();
+        GLOBAL.get_or_init(|| SocketPool::new(POOL_IDLE_PER_SPEC))
+    }
+
+    /// An idle socket matching `spec`, or a new one if there is none (or the
+    /// spec has a fixed port). It returns here when dropped.
+    pub fn checkout(self: &Arc<Self>, spec: SocketSpec) -> io::Result<PooledSocket> {
+        if spec.bind_addr.port() != 0 {
+            return Ok(PooledSocket::detached(spec.open()?));
+        }
+        let idle = self.idle.lock().unwrap().get_mut(&spec).and_then(Vec::pop);
+        let socket = match idle {
+            Some(socket) => {
+                drain(&socket);
+                socket
+            }
+            None => {
+                self.created.fetch_add(1, Ordering::Relaxed);
+                spec.open()?
+            }
+        };
+        socket.set_nonblocking(false)?;
+        socket.set_read_timeout(None)?;
+        Ok(PooledSocket { socket: Some(socket), home: Some((self.clone(), spec)) })
+    }
+
+    /// Sockets this pool has opened, reused ones not counted again.
+    pub fn created(&self) -> u64 {
+        self.created.load(Ordering::Relaxed)
+    }
+
+    /// Sockets waiting to be handed out.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    fn put_back(&self, spec: SocketSpec, socket: UdpSocket) {
+        drain(&socket);
+        let mut idle = self.idle.lock().unwrap();
+        let sockets = idle.entry(spec).or_default();
+        if sockets.len() < self.max_idle_per_spec {
+            sockets.push(socket);
+        }
+    }
+}
+
+/// Read off whatever is queued on `socket` and leave it non-blocking.
+fn drain(socket: &UdpSocket) {
+    if socket.set_nonblocking(true).is_err() {
+        return;
+    }
+    // Truncated reads still dequeue the datagram.
+    let mut buf = [0u8; 64];
+    for _ in 0..DRAIN_LIMIT {
+        match socket.recv_from(&mut buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            _ => {}
+        }
+    }
+}
+
+/// A socket from a [`SocketPool`], returned to it on drop. Sockets bound
+/// elsewhere (a caller's pre-bound socket) are wrapped detached and simply
+/// closed.
+pub struct PooledSocket {
+    socket: Option<UdpSocket>,
+    home: Option<(Arc<SocketPool>, SocketSpec)>,
+}
+
+impl PooledSocket {
+    pub fn detached(socket: UdpSocket) -> Self {
+        Self { socket: Some(socket), home: None }
+    }
+}
+
+impl From<UdpSocket> for PooledSocket {
+    fn from(socket: UdpSocket) -> Self {
+        Self::detached(socket)
+    }
+}
+
+impl Deref for PooledSocket {
+    type Target = UdpSocket;
+
+    fn deref(&self) -> &UdpSocket {
+        self.socket.as_ref().expect("socket taken before drop")
+    }
+}
+
+impl Drop for PooledSocket {
+    fn drop(&mut self) {
+        if let (Some(socket), Some((pool, spec))) = (self.socket.take(), self.home.take()) {
+            pool.put_back(spec, socket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use crate::harness::{self, Loopback};
+    use crate::protocol::{MIN_CHUNK_SIZE, encrypted_chunk_size};
+    use crate::sender::SenderProgress;
+
+    #[test]
+    fn test_sockets_are_reused_and_drained() {
+        let pool = SocketPool::new(2);
+        let spec = SocketSpec::receiver("127.0.0.1:0".parse().unwrap());
+
+        let first = pool.checkout(spec).unwrap();
+        let port = first.local_addr().unwrap().port();
+        first.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.send_to(b"stale", first.local_addr().unwrap()).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        drop(first);
+        assert_eq!(pool.idle(), 1);
+
+        // Same port back, with the old datagram gone and the timeout reset.
+        let again = pool.checkout(spec).unwrap();
+        assert_eq!(again.local_addr().unwrap().port(), port);
+        assert_eq!(again.read_timeout().unwrap(), None);
+        again.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(again.recv_from(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(pool.created(), 1);
+
+        // Past the idle cap, returned sockets are closed.
+        let held: Vec<_> = (0..3).map(|_| pool.checkout(spec).unwrap()).collect();
+        drop(again);
+        drop(held);
+        assert_eq!(pool.idle(), 2);
+
+        // Fixed ports are never pooled.
+        let fixed = pool.checkout(SocketSpec::receiver(peer.local_addr().unwrap()));
+        assert!(fixed.is_err());
+    }
+
+    /// One loopback download through the global pool; returns the receiver's port.
+    fn short_transfer(dir: &std::path::Path, n: usize) -> u16 {
+        let input = dir.join(format!("in-{}.bin", n));
+        let output = dir.join(format!("out-{}.bin", n));
+        // Raw sends go out as stored, so any bytes do in an encrypted layout.
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE);
+        let data: Vec<u8> = (0..3 * chunk_size).map(|i| (i * 13 + n) as u8).collect();
+        std::fs::write(&input, &data).unwrap();
+
+        let receiver = Loopback::start(harness::receiver_config(&output, &data, chunk_size));
+        let port = receiver.addr.port();
+        receiver
+            .send_raw(
+                harness::raw_sender_config(&input, receiver.addr, data.len() as u64, chunk_size),
+                Arc::new(SenderProgress::new()),
+            )
+            .unwrap();
+        receiver.join().unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        port
+    }
+
+    #[test]
+    fn test_many_short_transfers_reuse_ports() {
+        let dir = std::env::temp_dir().join(format!("haven-pool-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        const WORKERS: usize = 4;
+        const PER_WORKER: usize = 15;
+        let workers: Vec<_> = (0..WORKERS)
+            .map(|w| {
+                let dir = dir.clone();
+                std::thread::spawn(move || {
+                    (0..PER_WORKER).map(|i| short_transfer(&dir, w * PER_WORKER + i)).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let ports: HashSet<u16> = workers.into_iter().flat_map(|w| w.join().unwrap()).collect();
+
+        // 60 transfers, but only a few receiver ports per concurrent worker:
+        // a receiver's socket comes back up to a recv timeout after it
+        // returns, and other tests share the global pool.
+        assert!(ports.len() <= 3 * WORKERS, "{} ports for {} transfers", ports.len(), WORKERS * PER_WORKER);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,52 +1,156 @@
-/// UDP frame format for fast file transfer.
+/// Transfer tuning constants.
 ///
-/// ```text
-/// [0..16]   Transfer ID (UUID, 16 bytes)
-/// [16..20]  Chunk index (u32 BE)
-/// [20..22]  Frame index within chunk (u16 BE)
-/// [22..24]  Frame count for this chunk (u16 BE)
-/// [24..]    Encrypted payload slice (up to 1400 bytes)
-/// ```
-///
-/// 24-byte header + up to 1400 bytes payload = 1424 bytes max.
-/// Well within 1472-byte MTU limit (1500 - 20 IP - 8 UDP).
+/// The frame format and its codec live in `wire`, which has no dependencies
+/// beyond `core`; they are re-exported here so existing `protocol::` paths
+/// keep working.
 
-/// Maximum payload bytes per UDP frame.
-pub const FRAME_PAYLOAD: usize = 1400;
+pub use crate::wire::*;
 
-/// Header size in bytes.
-pub const FRAME_HEADER: usize = 24;
+use crate::aead::AeadAlgorithm;
+use crate::compress::COMPRESSED_HEADER;
 
-/// Maximum UDP frame size (header + payload).
-pub const FRAME_MAX: usize = FRAME_HEADER + FRAME_PAYLOAD;
+/// Chunks a file of `file_size` bytes splits into at `chunk_size` per chunk.
+/// An empty file is still one (empty) chunk, so it has a hash to verify and,
+/// once sealed, a nonce and tag to carry.
+pub fn chunk_count(file_size: u64, chunk_size: u64) -> u32 {
+    file_size.div_ceil(chunk_size).max(1) as u32
+}
 
-/// Chunk size: 4 MB plaintext. Encrypted = plaintext + 28 (12 nonce + 16 tag).
-pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// Bytes a full chunk of `chunk_size` plaintext bytes takes once stored:
+/// `aead`'s nonce and tag, plus the slot header when `compressed`. Every
+/// chunk of a transfer but the last fills exactly one slot.
+pub fn slot_size(chunk_size: usize, compressed: bool, aead: AeadAlgorithm) -> usize {
+    chunk_size + slot_overhead(compressed, aead)
+}
+
+fn slot_overhead(compressed: bool, aead: AeadAlgorithm) -> usize {
+    aead.overhead() + if compressed { COMPRESSED_HEADER } else { 0 }
+}
+
+/// Check that an encrypted chunk layout is self-consistent: `chunk_size` must
+/// be the `slot_size` of an in-range plaintext size, and `chunk_count` must
+/// be exactly what `file_size` splits into at that size.
+///
+/// Both ends of a transfer run this on the negotiated `FastUploadStart`
+/// fields so a sender/receiver chunk size disagreement fails up front instead
+/// of assembling frames at the wrong offsets.
+pub fn check_chunk_layout(
+    file_size: u64,
+    chunk_count: u32,
+    chunk_size: u64,
+    compressed: bool,
+    aead: AeadAlgorithm,
+) -> Result<(), String> {
+    let plain = chunk_size.saturating_sub(slot_overhead(compressed, aead) as u64);
+    if !chunk_size_in_range(plain as usize) {
+        return Err(format!(
+            "Chunk size {} out of range ({}..={} plaintext bytes)",
+            plain, MIN_CHUNK_SIZE, MAX_CHUNK_SIZE
+        ));
+    }
+    let expected = file_size.div_ceil(chunk_size).max(1);
+    if expected != chunk_count as u64 {
+        return Err(format!(
+            "Chunk size disagrees with layout: {} bytes at {} per chunk is {} chunks, got {}",
+            file_size, chunk_size, expected, chunk_count
+        ));
+    }
+    Ok(())
+}
 
-/// Encrypted chunk overhead: 12-byte nonce + 16-byte GCM tag.
-pub const ENCRYPTION_OVERHEAD: usize = 28;
+/// Largest ack bitmap accepted or produced, in bytes (65536 chunks).
+pub const MAX_ACK_BITMAP_BYTES: usize = 8192;
+
+/// Pack done chunk indices into `(base_chunk, bitmap)` for a single ack
+/// message. Bit `i` of the bitmap (LSB-first within each byte) stands for
+/// chunk `base_chunk + i`. Returns `None` for an empty set; indices must span
+/// fewer than `MAX_ACK_BITMAP_BYTES * 8` chunks.
+pub fn encode_ack_bitmap(chunks: &[u32]) -> Option<(u32, Vec<u8>)> {
+    let base = *chunks.iter().min()?;
+    let span = (chunks.iter().max()? - base) as usize + 1;
+    debug_assert!(span <= MAX_ACK_BITMAP_BYTES * 8);
+    let mut bitmap = vec![0u8; span.div_ceil(8)];
+    for &idx in chunks {
+        let bit = (idx - base) as usize;
+        bitmap[bit / 8] |= 1 << (bit % 8);
+    }
+    Some((base, bitmap))
+}
 
-/// Maximum encrypted chunk size.
-pub const ENCRYPTED_CHUNK_SIZE: usize = CHUNK_SIZE + ENCRYPTION_OVERHEAD;
+/// Chunk indices set in an ack bitmap. Bytes past `MAX_ACK_BITMAP_BYTES` and
+/// indices that would overflow `u32` are ignored.
+pub fn decode_ack_bitmap(base_chunk: u32, bitmap: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    let bitmap = &bitmap[..bitmap.len().min(MAX_ACK_BITMAP_BYTES)];
+    bitmap.iter().enumerate().flat_map(move |(byte_idx, &byte)| {
+        (0..8u32)
+            .filter(move |bit| byte & (1 << bit) != 0)
+            .filter_map(move |bit| base_chunk.checked_add(byte_idx as u32 * 8 + bit))
+    })
+}
 
-/// Maximum frames per chunk: ceil(4_194_332 / 1400) = 2997.
-pub const MAX_FRAMES_PER_CHUNK: usize = (ENCRYPTED_CHUNK_SIZE + FRAME_PAYLOAD - 1) / FRAME_PAYLOAD;
+/// How often the receiver flushes pending chunk acks, in milliseconds.
+pub const ACK_FLUSH_INTERVAL_MS: u64 = 50;
 
-/// Number of encrypted chunks to cache in sender for retransmit.
+/// Default number of un-ACKed encrypted chunks the sender keeps for
+/// retransmit before it stops reading ahead.
 pub const SENDER_CACHE_SIZE: usize = 8;
 
+/// Default ceiling on retransmit rounds (NACKs served) for any one chunk.
+/// A chunk still missing after this many is being dropped by the path, not
+/// by chance, and the transfer fails rather than resend it forever.
+pub const MAX_CHUNK_RETRANSMITS: u32 = 64;
+
 /// Receiver ring buffer size in frames.
 pub const RING_BUFFER_FRAMES: usize = 16384;
 
+/// Frames queued for the assembler at which the receiver asks the sender to
+/// slow down (see `NackMessage::slowdown`), well before a full ring blocks
+/// the vacuum and the kernel starts dropping.
+pub const ASSEMBLER_LAG_FRAMES: usize = RING_BUFFER_FRAMES / 4;
+
+/// Chunk index of a slowdown NACK. Never a real chunk, so senders that
+/// predate slowdowns find nothing to resend and ignore it.
+pub const SLOWDOWN_CHUNK_INDEX: u32 = u32::MAX;
+
 /// OS receive buffer size (32 MB).
 pub const UDP_RECV_BUFFER: usize = 32 * 1024 * 1024;
 
+/// How often the receiver reads its socket's kernel drop counter.
+pub const SOCKET_DROP_CHECK_INTERVAL_MS: u64 = 1000;
+
 /// NACK scan interval in milliseconds.
 pub const NACK_SCAN_INTERVAL_MS: u64 = 50;
 
-/// Initial send rate in bytes per second (800 Mbps).
+/// Longest the receiver waits before NACKing a chunk again, however slow
+/// NACK round trips have been (see `nack`).
+pub const MAX_NACK_BACKOFF_MS: u64 = 2000;
+
+/// Default stall window in seconds: a transfer that makes no progress for
+/// this long fails instead of waiting for the user to cancel it.
+pub const STALL_TIMEOUT_SECS: u64 = 30;
+
+/// How often a paused sender sends a keepalive frame (see
+/// `KEEPALIVE_CHUNK_INDEX`), well inside any sensible stall window.
+pub const PAUSE_KEEPALIVE_INTERVAL_MS: u64 = 1000;
+
+/// Top send rate in bytes per second (800 Mbps). Slow start climbs to it
+/// from `SLOW_START_RATE_BPS`; see `congestion`.
 pub const INITIAL_RATE_BPS: u64 = 800_000_000 / 8;
 
+/// Send rate slow start begins at, in bytes per second (12.5 Mbps).
+pub const SLOW_START_RATE_BPS: u64 = INITIAL_RATE_BPS / 64;
+
+/// Slow start ends once a round trip takes this many times the shortest
+/// one seen (plus the receiver's ACK batching), a sign queues are filling.
+pub const SLOW_START_RTT_INFLATION: f64 = 1.5;
+
+/// `CongestionConfig::delay()` backs off once a round trip takes this many
+/// times the shortest one seen (plus the receiver's ACK batching).
+pub const DELAY_RTT_INFLATION: f64 = 1.25;
+
+/// Round-trip time slow start assumes until the first ACK is timed.
+pub const DEFAULT_RTT_MS: u64 = 100;
+
 /// Rate decrease factor when loss > 10%.
 pub const RATE_DECREASE: f64 = 0.80;
 
@@ -59,64 +163,46 @@ pub const LOSS_THRESHOLD_HIGH: f64 = 0.10;
 /// Loss threshold below which we increase rate.
 pub const LOSS_THRESHOLD_LOW: f64 = 0.01;
 
-/// Encode a UDP frame into the provided buffer. Returns bytes written.
-///
-/// # Panics
-/// Panics if `buf` is smaller than `FRAME_HEADER + payload.len()`.
-pub fn encode_frame(
-    buf: &mut [u8],
-    transfer_id: &[u8; 16],
-    chunk_index: u32,
-    frame_index: u16,
-    frame_count: u16,
-    payload: &[u8],
-) -> usize {
-    let total = FRAME_HEADER + payload.len();
-    assert!(buf.len() >= total);
-    assert!(payload.len() <= FRAME_PAYLOAD);
-
-    buf[0..16].copy_from_slice(transfer_id);
-    buf[16..20].copy_from_slice(&chunk_index.to_be_bytes());
-    buf[20..22].copy_from_slice(&frame_index.to_be_bytes());
-    buf[22..24].copy_from_slice(&frame_count.to_be_bytes());
-    buf[FRAME_HEADER..total].copy_from_slice(payload);
-    total
-}
-
-/// Parsed UDP frame header.
-#[derive(Debug, Clone, Copy)]
-pub struct FrameHeader {
-    pub transfer_id: [u8; 16],
-    pub chunk_index: u32,
-    pub frame_index: u16,
-    pub frame_count: u16,
-}
-
-/// Decode a frame header from raw bytes. Returns None if too short.
-pub fn decode_frame_header(data: &[u8]) -> Option<FrameHeader> {
-    if data.len() < FRAME_HEADER {
-        return None;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_chunk_layout() {
+        const AES: AeadAlgorithm = AeadAlgorithm::Aes256Gcm;
+        let enc = ENCRYPTED_CHUNK_SIZE as u64;
+        assert!(check_chunk_layout(3 * enc, 3, enc, false, AES).is_ok());
+        assert!(check_chunk_layout(2 * enc + 29, 3, enc, false, AES).is_ok());
+        assert!(check_chunk_layout(ENCRYPTION_OVERHEAD as u64, 1, enc, false, AES).is_ok());
+
+        // Sender chunked at 1 MB, receiver told 4 MB.
+        let small = encrypted_chunk_size(1024 * 1024) as u64;
+        assert!(check_chunk_layout(8 * small, 8, enc, false, AES).is_err());
+        assert!(check_chunk_layout(8 * small, 8, small, false, AES).is_ok());
+
+        assert!(check_chunk_layout(1024, 1, 1024, false, AES).is_err());
+        assert!(check_chunk_layout(enc, 1, encrypted_chunk_size(MAX_CHUNK_SIZE + 1) as u64, false, AES).is_err());
+
+        // Compressed slots carry a header on top of the usual overhead.
+        let slot = crate::compress::compressed_slot_size(MAX_CHUNK_SIZE) as u64;
+        assert!(check_chunk_layout(slot, 1, slot, true, AES).is_ok());
+        assert!(check_chunk_layout(slot, 1, slot, false, AES).is_err());
+        assert!(check_chunk_layout(slot, 1, slot, true, AeadAlgorithm::ChaCha20Poly1305).is_ok());
+        assert_eq!(slot_size(MAX_CHUNK_SIZE, true, AES) as u64, slot);
+        assert_eq!(slot_size(CHUNK_SIZE, false, AES) as u64, enc);
     }
-    let mut transfer_id = [0u8; 16];
-    transfer_id.copy_from_slice(&data[0..16]);
-    let chunk_index = u32::from_be_bytes(data[16..20].try_into().unwrap());
-    let frame_index = u16::from_be_bytes(data[20..22].try_into().unwrap());
-    let frame_count = u16::from_be_bytes(data[22..24].try_into().unwrap());
-
-    Some(FrameHeader {
-        transfer_id,
-        chunk_index,
-        frame_index,
-        frame_count,
-    })
-}
 
-/// Get the payload slice from a raw frame.
-pub fn frame_payload(data: &[u8]) -> &[u8] {
-    &data[FRAME_HEADER..]
-}
+    #[test]
+    fn test_ack_bitmap_round_trip() {
+        assert_eq!(encode_ack_bitmap(&[]), None);
 
-/// Calculate number of frames needed for a chunk of given encrypted size.
-pub fn frames_for_chunk(encrypted_size: usize) -> u16 {
-    ((encrypted_size + FRAME_PAYLOAD - 1) / FRAME_PAYLOAD) as u16
+        let chunks = [100, 101, 103, 108, 115];
+        let (base, bitmap) = encode_ack_bitmap(&chunks).unwrap();
+        assert_eq!(base, 100);
+        assert_eq!(bitmap, vec![0b0000_1011, 0b1000_0001]);
+        assert_eq!(decode_ack_bitmap(base, &bitmap).collect::<Vec<_>>(), chunks);
+
+        // Out-of-range bits near u32::MAX are dropped, not wrapped.
+        assert_eq!(decode_ack_bitmap(u32::MAX, &[0b11]).collect::<Vec<_>>(), vec![u32::MAX]);
+    }
 }
@@ -8,20 +8,35 @@
 /// ```
 ///
 /// Used by both the file server (receiving uploads) and the download client.
+/// Each receive socket gets its own vacuum thread: one normally, more when
+/// the caller hands over a `SO_REUSEPORT` group.
 
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::net::SocketAddr;
 use std::path::Path;
-use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use crossbeam_channel::bounded;
+use crossbeam_channel::{RecvTimeoutError, bounded};
 use sha2::{Digest, Sha256};
 
+use crate::aead::AeadAlgorithm;
 use crate::bitfield::ChunkBitfield;
+use crate::disk;
+use crate::error::TransferError;
+use crate::fec::{ChunkParity, FecCodec};
+use crate::histogram::LossHistogram;
+use crate::integrity::ControlFields;
 use crate::logging::{TransferEvent, TransferLog, TransferLogger};
+use crate::mtu;
+use crate::nack::NackPacer;
+use crate::net;
+use crate::pool::{PooledSocket, SocketPool, SocketSpec};
 use crate::protocol::*;
+use crate::resume::{self, ChunkProgress};
+use crate::sender::NackMessage;
 
 /// Receiver progress tracking.
 pub struct ReceiverProgress {
@@ -34,6 +49,38 @@ pub struct ReceiverProgress {
     pub retransmits: AtomicU64,
     pub rate_bps: AtomicU64,
     pub last_error: std::sync::Mutex<Option<String>>,
+    /// Frames NACKed per time bucket, for post-transfer loss diagnostics.
+    pub loss_histogram: std::sync::Mutex<LossHistogram>,
+    /// Category of the failure when `state` is `STATE_ERROR` (`ERROR_KIND_*`).
+    pub error_kind: AtomicU8,
+    /// Payload bytes per frame, learned from the sender's frames (0 until a
+    /// multi-frame chunk arrives).
+    pub frame_payload: AtomicU64,
+    /// Frames rebuilt from FEC parity (see `fec`) rather than NACKed.
+    pub fec_recovered: AtomicU64,
+    /// Local UDP port, set once the socket is bound (0 before). `run_receiver`
+    /// only returns the bound address when the transfer ends.
+    pub bound_port: AtomicU16,
+    /// Datagrams the kernel dropped on the socket since the transfer began
+    /// because the vacuum thread fell behind (see `net::udp_drops`; stays 0
+    /// where the OS doesn't report it). Counts the whole socket, so on a
+    /// shared server socket it includes other transfers' drops.
+    pub socket_drops: AtomicU64,
+    /// Times the writer has `sync_data`ed the output file (see `SyncCadence`).
+    pub syncs: AtomicU64,
+    /// Data frames for this transfer taken off the socket, across all
+    /// vacuum threads. Zero a while into a transfer means UDP isn't getting
+    /// through at all.
+    pub frames_received: AtomicU64,
+    /// Most frames seen queued between the vacuums and the assembler,
+    /// sampled every NACK scan. At `RING_BUFFER_FRAMES` the vacuums block
+    /// and the kernel drops what arrives.
+    pub assembler_backlog: AtomicU64,
+    /// Slowdown NACKs sent because the backlog passed `ASSEMBLER_LAG_FRAMES`.
+    pub slowdowns: AtomicU64,
+    /// Frames for this transfer dropped unread because their header claimed
+    /// more frames than any chunk of it has (see `max_frames_per_chunk`).
+    pub frames_rejected: AtomicU64,
 }
 
 /// Receiver state constants (same as sender for consistency).
@@ -43,6 +90,10 @@ pub const STATE_COMPLETE: u8 = 3;
 pub const STATE_ERROR: u8 = 4;
 pub const STATE_CANCELLED: u8 = 5;
 
+/// Receiver error categories, so callers can react without parsing messages.
+pub const ERROR_KIND_NONE: u8 = 0;
+pub const ERROR_KIND_DISK_FULL: u8 = 1;
+
 impl ReceiverProgress {
     pub fn new() -> Self {
         Self {
@@ -55,12 +106,30 @@ impl ReceiverProgress {
             retransmits: AtomicU64::new(0),
             rate_bps: AtomicU64::new(0),
             last_error: std::sync::Mutex::new(None),
+            loss_histogram: std::sync::Mutex::new(LossHistogram::new()),
+            error_kind: AtomicU8::new(ERROR_KIND_NONE),
+            frame_payload: AtomicU64::new(0),
+            fec_recovered: AtomicU64::new(0),
+            bound_port: AtomicU16::new(0),
+            socket_drops: AtomicU64::new(0),
+            syncs: AtomicU64::new(0),
+            frames_received: AtomicU64::new(0),
+            assembler_backlog: AtomicU64::new(0),
+            slowdowns: AtomicU64::new(0),
+            frames_rejected: AtomicU64::new(0),
         }
     }
 
     pub fn is_cancelled(&self) -> bool {
         self.cancelled.load(Ordering::Relaxed) != 0
     }
+
+    /// Record a disk-full failure and return it.
+    fn fail_disk_full(&self, needed: u64, available: u64) -> TransferError {
+        self.error_kind.store(ERROR_KIND_DISK_FULL, Ordering::Relaxed);
+        self.state.store(STATE_ERROR, Ordering::Relaxed);
+        TransferError::DiskFull { needed, available }
+    }
 }
 
 /// Configuration for the receiver.
@@ -69,6 +138,8 @@ pub struct ReceiverConfig {
     pub transfer_id: [u8; 16],
     pub file_size: u64,
     pub chunk_count: u32,
+    /// Encrypted bytes per full chunk, as negotiated in `FastUploadStart`
+    /// (`ENCRYPTED_CHUNK_SIZE` unless the sender picked another size).
     pub chunk_size: u64,
     pub chunk_hashes: Vec<String>,
     pub file_sha256: String,
@@ -77,7 +148,164 @@ pub struct ReceiverConfig {
     /// Optional pre-bound UDP socket. If provided, the receiver uses this socket
     /// instead of creating a new one. This avoids port race conditions when the
     /// caller needs to know the bound port before starting the receiver.
-    pub pre_bound_socket: Option<std::net::UdpSocket>,
+    pub pre_bound_socket: Option<PooledSocket>,
+    /// More sockets on the same port (see `net::bind_udp_group`), each
+    /// drained by its own vacuum thread into the one assembler.
+    pub extra_sockets: Vec<PooledSocket>,
+    /// Transfer key for checking `control_mac`. When set, the receiver refuses
+    /// to start unless `control_mac` matches the size/count/hash fields above.
+    /// `None` skips the check (e.g. the file server, which never holds the key).
+    pub control_key: Option<[u8; 32]>,
+    /// Uploader's HMAC over the control fields (see [`ControlFields`]).
+    pub control_mac: Option<String>,
+    /// Reports written-and-verified chunks back to the sender, batched as
+    /// ack bitmaps so it can evict them from its retransmit cache.
+    pub ack_callback: Option<AckCallback>,
+    /// The sender compresses (see `compress`): `chunk_size` is a slot size,
+    /// and chunks may arrive in fewer frames than the slot needs, the rest
+    /// being zero padding.
+    pub compressed: bool,
+    /// Algorithm the sender seals chunks with. The receiver never decrypts;
+    /// it only needs the algorithm's overhead to check `chunk_size`.
+    pub aead: AeadAlgorithm,
+    /// Fail with "Transfer stalled" once no frame has arrived for this long
+    /// (`STALL_TIMEOUT_SECS` by default). A paused sender's keepalives count
+    /// as frames here, so a pause alone never trips it.
+    pub stall_timeout: Duration,
+    /// `SO_RCVBUF` for the socket the receiver binds (`UDP_RECV_BUFFER` by
+    /// default, see `net::recv_buffer_from_env`). `None` with
+    /// `pre_bound_socket`, whose owner sized it; binding without one then
+    /// falls back to `UDP_RECV_BUFFER`.
+    pub recv_buffer: Option<usize>,
+    /// Record written chunks in a sidecar beside `output_path` (see
+    /// `resume`), and if one from an earlier run of this transfer is there,
+    /// keep the output file's contents and only wait for the chunks it lacks.
+    pub resume: bool,
+    /// How often to sync the output file to disk mid-transfer. It's synced
+    /// once more before the transfer is marked complete either way.
+    pub sync: SyncCadence,
+    /// All-zero chunks the sender won't send (see `sparse`). Their slots are
+    /// zeroed here instead, once their hashes check out as zeros'.
+    pub sparse_chunks: Vec<u32>,
+}
+
+/// The tuning fields at their documented defaults, bound to any local
+/// address. The transfer's own fields (path, id, sizes and hashes) are empty
+/// and must be filled in.
+impl Default for ReceiverConfig {
+    fn default() -> Self {
+        Self {
+            output_path: String::new(),
+            transfer_id: [0u8; 16],
+            file_size: 0,
+            chunk_count: 0,
+            chunk_size: ENCRYPTED_CHUNK_SIZE as u64,
+            chunk_hashes: Vec::new(),
+            file_sha256: String::new(),
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            logger: None,
+            pre_bound_socket: None,
+            extra_sockets: Vec::new(),
+            control_key: None,
+            control_mac: None,
+            ack_callback: None,
+            compressed: false,
+            aead: AeadAlgorithm::default(),
+            stall_timeout: Duration::from_secs(STALL_TIMEOUT_SECS),
+            recv_buffer: Some(UDP_RECV_BUFFER),
+            resume: false,
+            sync: SyncCadence::default(),
+            sparse_chunks: Vec::new(),
+        }
+    }
+}
+
+/// Environment variable setting [`SyncCadence::every_chunks`].
+pub const SYNC_CHUNKS_ENV: &str = "HAVEN_FAST_SYNC_CHUNKS";
+/// Environment variable setting [`SyncCadence::every_bytes`].
+pub const SYNC_BYTES_ENV: &str = "HAVEN_FAST_SYNC_BYTES";
+
+/// When the writer calls `sync_data` on the output file: after
+/// `every_chunks` chunks or `every_bytes` bytes written since the last sync,
+/// whichever comes first. `0` turns a limit off; the default syncs only at
+/// the end. Written chunks survive a crash of the process either way, but
+/// not a power loss until synced, so a tighter cadence loses less there at
+/// some cost in throughput.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncCadence {
+    pub every_chunks: u64,
+    pub every_bytes: u64,
+}
+
+impl SyncCadence {
+    /// Read the limits from [`SYNC_CHUNKS_ENV`] and [`SYNC_BYTES_ENV`];
+    /// unset ones are off.
+    pub fn from_env() -> Self {
+        let limit = |name: &str| match std::env::var(name) {
+            Ok(v) => v.trim().parse::<u64>().unwrap_or_else(|_| {
+                tracing::warn!("Ignoring {}={:?}: not a count", name, v);
+                0
+            }),
+            Err(_) => 0,
+        };
+        Self {
+            every_chunks: limit(SYNC_CHUNKS_ENV),
+            every_bytes: limit(SYNC_BYTES_ENV),
+        }
+    }
+
+    fn due(&self, chunks: u64, bytes: u64) -> bool {
+        (self.every_chunks > 0 && chunks >= self.every_chunks)
+            || (self.every_bytes > 0 && bytes >= self.every_bytes)
+    }
+}
+
+/// Check `config.sparse_chunks` against their hashes and zero the slots of
+/// those not already written: a fresh output file reads as zeros already, so
+/// only a resumed one is written to. Returns `(chunk, slot length)` for each.
+fn fill_sparse_chunks(
+    config: &ReceiverConfig,
+    already_written: &[u32],
+    resuming: bool,
+) -> Result<Vec<(u32, u64)>, TransferError> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let chunk_len = |c: u32| config.chunk_size.min(config.file_size - c as u64 * config.chunk_size);
+    let mut zero_hashes: HashMap<u64, String> = HashMap::new();
+    let mut skip: HashSet<u32> = already_written.iter().copied().collect();
+    let mut filled = Vec::new();
+    let mut file = None;
+    for &c in &config.sparse_chunks {
+        if c >= config.chunk_count {
+            return Err(TransferError::Protocol(format!("Sparse chunk {} out of range", c)));
+        }
+        if !skip.insert(c) {
+            continue;
+        }
+        let len = chunk_len(c);
+        let zero_hash = zero_hashes
+            .entry(len)
+            .or_insert_with(|| hex::encode(Sha256::digest(vec![0u8; len as usize])));
+        let expected = config.chunk_hashes.get(c as usize).cloned().unwrap_or_default();
+        if *zero_hash != expected {
+            return Err(TransferError::HashMismatch { chunk: c, expected, actual: zero_hash.clone() });
+        }
+        if resuming {
+            if file.is_none() {
+                let opened = std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&config.output_path)
+                    .map_err(|e| TransferError::Io(format!("Cannot open output file: {}", e)))?;
+                file = Some(opened);
+            }
+            let f = file.as_mut().unwrap();
+            f.seek(SeekFrom::Start(c as u64 * config.chunk_size))
+                .and_then(|_| f.write_all(&vec![0u8; len as usize]))
+                .map_err(|e| TransferError::Io(format!("Write error at chunk {}: {}", c, e)))?;
+        }
+        filled.push((c, len));
+    }
+    Ok(filled)
 }
 
 /// Internal message from assembler to writer.
@@ -86,21 +314,27 @@ struct AssembledChunk {
     data: Vec<u8>,
 }
 
-/// NACK callback: called by assembler when frames are missing.
-/// The caller should send these over WebSocket to the sender/server.
-pub type NackCallback = Box<dyn Fn(u32, Vec<u16>) + Send + Sync>;
+/// NACK callback: called by the assembler with every chunk it is NACKing
+/// in one scan, at most once per scan (see `nack`). The caller should send
+/// them over WebSocket to the sender/server as one FastNackBatch.
+pub type NackCallback = Box<dyn Fn(Vec<NackMessage>) + Send + Sync>;
+
+/// ACK callback: called by the writer with `(base_chunk, bitmap)` for a
+/// batch of chunks it has written (see `encode_ack_bitmap`). The caller
+/// should send a FastChunkAckBitmap over WebSocket.
+pub type AckCallback = Box<dyn Fn(u32, Vec<u8>) + Send + Sync>;
 
 /// Run the receiver pipeline. Blocks until complete, error, or cancellation.
 ///
 /// `nack_callback` is called when the assembler detects missing frames.
-/// The callback should send a FastNack over WebSocket.
+/// The callback should send a FastNackBatch over WebSocket.
 ///
 /// Returns the bound UDP address (for the caller to communicate back).
 pub fn run_receiver(
     config: ReceiverConfig,
     progress: Arc<ReceiverProgress>,
     nack_callback: NackCallback,
-) -> Result<SocketAddr, String> {
+) -> Result<SocketAddr, TransferError> {
     let file_size = config.file_size;
     let chunk_count = config.chunk_count;
 
@@ -110,148 +344,194 @@ pub fn run_receiver(
         .store(chunk_count as u64, Ordering::Relaxed);
     progress.state.store(STATE_RECEIVING, Ordering::Relaxed);
 
-    // Pre-allocate output file
-    {
+    // Verify control fields before configuring anything from them
+    if let Some(key) = config.control_key {
+        let fields = ControlFields {
+            transfer_id: &config.transfer_id,
+            file_size,
+            chunk_size: config.chunk_size,
+            chunk_count,
+            file_sha256: &config.file_sha256,
+            sparse_chunks: &config.sparse_chunks,
+        };
+        let ok = config
+            .control_mac
+            .as_deref()
+            .is_some_and(|mac| fields.verify(&key, mac));
+        if !ok {
+            progress.state.store(STATE_ERROR, Ordering::Relaxed);
+            return Err(TransferError::Crypto("Control message integrity check failed".into()));
+        }
+    }
+
+    if let Err(e) = check_chunk_layout(file_size, chunk_count, config.chunk_size, config.compressed, config.aead) {
+        progress.state.store(STATE_ERROR, Ordering::Relaxed);
+        return Err(TransferError::Protocol(e));
+    }
+
+    // Chunks an earlier run already wrote, if it left a matching record
+    // and the output file it wrote them to.
+    let state_path = resume::state_path(Path::new(&config.output_path));
+    let restored = if config.resume {
+        ChunkProgress::load(&state_path)
+            .ok()
+            .flatten()
+            .filter(|p| p.fits(&config.transfer_id, file_size, config.chunk_size, chunk_count))
+            .filter(|_| std::fs::metadata(&config.output_path).is_ok_and(|m| m.len() == file_size))
+    } else {
+        None
+    };
+    let mut chunk_record = config
+        .resume
+        .then(|| ChunkProgress::new(config.transfer_id, file_size, config.chunk_size, chunk_count));
+    if let Some(p) = restored {
+        let chunk_len = |c: u32| config.chunk_size.min(file_size - c as u64 * config.chunk_size);
+        let bytes: u64 = p.completed().into_iter().map(chunk_len).sum();
+        progress.bytes_done.store(bytes, Ordering::Relaxed);
+        progress.chunks_complete.store(p.done() as u64, Ordering::Relaxed);
+        chunk_record = Some(p);
+    } else if config.resume {
+        // Stale or someone else's; it must not outlive the file we recreate.
+        let _ = std::fs::remove_file(&state_path);
+    }
+    let resuming = chunk_record.as_ref().is_some_and(|p| p.done() > 0);
+
+    // Pre-allocate output file (a resumed one already is)
+    if !resuming {
         let path = Path::new(&config.output_path);
         if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent)
-                .map_err(|e| format!("Cannot create output dir: {}", e))?;
+                .map_err(|e| TransferError::Io(format!("Cannot create output dir: {}", e)))?;
         }
+
+        // Fail fast if the file can't possibly fit. Best-effort: if the
+        // query itself fails, fall through and let the writer detect ENOSPC.
+        // An existing output file (e.g. preallocated by the caller) already
+        // holds its space.
+        let existing = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        if let Ok(available) = disk::available_space(path) {
+            let needed = file_size.saturating_sub(existing);
+            if needed > available {
+                return Err(progress.fail_disk_full(needed, available));
+            }
+        }
+
         let file = std::fs::File::create(path)
-            .map_err(|e| format!("Cannot create output file: {}", e))?;
-        file.set_len(file_size)
-            .map_err(|e| format!("Cannot allocate output file: {}", e))?;
+            .map_err(|e| TransferError::Io(format!("Cannot create output file: {}", e)))?;
+        if let Err(e) = file.set_len(file_size) {
+            drop(file);
+            if disk::is_disk_full(&e) {
+                let _ = std::fs::remove_file(path);
+                let available = disk::available_space(path).unwrap_or(0);
+                return Err(progress.fail_disk_full(file_size, available));
+            }
+            return Err(TransferError::Io(format!("Cannot allocate output file: {}", e)));
+        }
+    }
+
+    let mut already_written: Vec<u32> = chunk_record.as_ref().map(ChunkProgress::completed).unwrap_or_default();
+    let sparse = fill_sparse_chunks(&config, &already_written, resuming).inspect_err(|_| {
+        progress.state.store(STATE_ERROR, Ordering::Relaxed);
+    })?;
+    for &(c, len) in &sparse {
+        if let Some(ref mut record) = chunk_record {
+            record.set(c);
+        }
+        already_written.push(c);
+        progress.bytes_done.fetch_add(len, Ordering::Relaxed);
+        progress.chunks_complete.fetch_add(1, Ordering::Relaxed);
     }
+    let chunks_already_written = already_written.len() as u32;
 
+    // Configure a caller's socket for receiving; its recv buffer should
+    // already be set.
+    let prepare = |s: PooledSocket| -> Result<PooledSocket, TransferError> {
+        s.set_nonblocking(false).map_err(|e| TransferError::Network(format!("Socket config: {}", e)))?;
+        s.set_read_timeout(Some(std::time::Duration::from_millis(100)))
+            .map_err(|e| TransferError::Network(format!("Socket timeout: {}", e)))?;
+        Ok(s)
+    };
     // Create UDP socket (or use pre-bound one)
     let socket = match config.pre_bound_socket {
-        Some(s) => {
-            // Configure the pre-bound socket for receiving
-            s.set_nonblocking(false).map_err(|e| format!("Socket config: {}", e))?;
-            s.set_read_timeout(Some(std::time::Duration::from_millis(100)))
-                .map_err(|e| format!("Socket timeout: {}", e))?;
-            // Recv buffer should already be set by caller
-            s
-        }
-        None => create_recv_socket(config.bind_addr)
-            .map_err(|e| format!("UDP bind error: {}", e))?,
+        Some(s) => prepare(s)?,
+        None => create_recv_socket(config.bind_addr, config.recv_buffer.unwrap_or(UDP_RECV_BUFFER))
+            .map_err(|e| TransferError::Network(format!("UDP bind error: {}", e)))?,
     };
+    let extra_sockets = config.extra_sockets.into_iter().map(prepare).collect::<Result<Vec<_>, _>>()?;
     let bound_addr = socket
         .local_addr()
-        .map_err(|e| format!("Cannot get bound addr: {}", e))?;
+        .map_err(|e| TransferError::Network(format!("Cannot get bound addr: {}", e)))?;
+    progress.bound_port.store(bound_addr.port(), Ordering::Relaxed);
 
     // Channels
     let (frame_tx, frame_rx) = bounded::<(FrameHeader, Vec<u8>)>(RING_BUFFER_FRAMES);
     let (assembled_tx, assembled_rx) = bounded::<AssembledChunk>(4);
 
     let transfer_id = config.transfer_id;
+    let max_frames = max_frames_per_chunk(config.chunk_size as usize);
 
-    // ── Thread 1: UDP Vacuum ───────────────────────────────────────────
-    let progress_vacuum = progress.clone();
-    let logger_vacuum = config.logger.clone();
-    let vacuum_handle = std::thread::spawn(move || -> Result<(), String> {
-        let mut recv_buf = vec![0u8; FRAME_MAX + 64]; // extra safety margin
-        let mut frames_received: u64 = 0;
-        let mut frames_rejected: u64 = 0;
-        let local_addr = socket.local_addr().map(|a| a.to_string()).unwrap_or_else(|_| "?".into());
-
-        if let Some(ref logger) = logger_vacuum {
-            logger.log(TransferLog {
-                component: "receiver",
-                transfer_id,
-                event: TransferEvent::VacuumStarted { bind_addr: local_addr.clone() },
-            });
-        }
-
-        loop {
-            if progress_vacuum.is_cancelled() {
-                return Err("Cancelled".into());
-            }
-
-            // Check if we're done
-            if progress_vacuum.state.load(Ordering::Relaxed) == STATE_COMPLETE {
-                return Ok(());
-            }
-
-            match socket.recv_from(&mut recv_buf) {
-                Ok((len, src)) => {
-                    if len < FRAME_HEADER {
-                        continue;
-                    }
-
-                    if let Some(header) = decode_frame_header(&recv_buf[..len]) {
-                        // Verify transfer ID
-                        if header.transfer_id != transfer_id {
-                            frames_rejected += 1;
-                            if frames_rejected <= 3 {
-                                if let Some(ref logger) = logger_vacuum {
-                                    logger.log(TransferLog {
-                                        component: "receiver",
-                                        transfer_id,
-                                        event: TransferEvent::TransferIdMismatch {
-                                            got: header.transfer_id,
-                                            from: src.to_string(),
-                                        },
-                                    });
-                                }
-                            }
-                            continue;
-                        }
-
-                        frames_received += 1;
-                        if frames_received == 1 || frames_received % 10000 == 0 {
-                            if let Some(ref logger) = logger_vacuum {
-                                logger.log(TransferLog {
-                                    component: "receiver",
-                                    transfer_id,
-                                    event: TransferEvent::VacuumProgress {
-                                        frames_received,
-                                        from: src.to_string(),
-                                    },
-                                });
-                            }
-                        }
+    // Pipeline threads log inside the caller's span (e.g. a server session).
+    let span = tracing::Span::current();
 
-                        let payload = recv_buf[FRAME_HEADER..len].to_vec();
-                        if frame_tx.send((header, payload)).is_err() {
-                            return Ok(()); // Channel closed, assembler done
-                        }
-                    }
-                }
-                Err(ref e)
-                    if e.kind() == io::ErrorKind::WouldBlock
-                        || e.kind() == io::ErrorKind::TimedOut =>
-                {
-                    // Timeout on recv — check cancellation and continue
-                    // Note: Windows returns TimedOut, Unix returns WouldBlock
-                    continue;
-                }
-                Err(e) => {
-                    return Err(format!("UDP recv error: {}", e));
-                }
-            }
-        }
-    });
+    // ── Thread 1: UDP Vacuum, one per socket ──────────────────────────
+    let vacuum_handles: Vec<_> = std::iter::once(socket)
+        .chain(extra_sockets)
+        .map(|socket| {
+            let frame_tx = frame_tx.clone();
+            let progress_vacuum = progress.clone();
+            let logger_vacuum = config.logger.clone();
+            let span_vacuum = span.clone();
+            std::thread::spawn(move || {
+                let _span = span_vacuum.entered();
+                vacuum(socket, frame_tx, &progress_vacuum, logger_vacuum, transfer_id, max_frames)
+            })
+        })
+        .collect();
+    // The vacuums hold the only senders, so the assembler sees the channel
+    // close once they've all stopped.
+    drop(frame_tx);
 
     // ── Thread 2: Assembler ────────────────────────────────────────────
     let progress_asm = progress.clone();
     let logger_asm = config.logger.clone();
     let _chunk_hashes = config.chunk_hashes.clone();
     let chunk_size = config.chunk_size;
+    let compressed = config.compressed;
+    let stall_timeout = config.stall_timeout;
     let nack_cb = Arc::new(nack_callback);
 
-    let assembler_handle = std::thread::spawn(move || -> Result<(), String> {
+    let span_assembler = span.clone();
+    let assembler_handle = std::thread::spawn(move || -> Result<(), TransferError> {
+        let _span = span_assembler.entered();
         // Per-chunk assembly state
         let mut bitfields: Vec<Option<ChunkBitfield>> = vec![None; chunk_count as usize];
         let mut buffers: Vec<Option<Vec<u8>>> = vec![None; chunk_count as usize];
         let mut completed = vec![false; chunk_count as usize];
-        let mut completed_count = 0u32;
+        for &c in &already_written {
+            completed[c as usize] = true;
+        }
+        let mut completed_count = already_written.len() as u32;
+        // Every frame but a chunk's last carries exactly this many bytes;
+        // learned from the first such frame (see `mtu`).
+        let mut frame_payload: Option<usize> = None;
+        // A compressed chunk may end anywhere in its slot, so its last frame
+        // says nothing about the stride. One that arrives before the stride
+        // is known waits here as (chunk, frame, payload) until it is.
+        let mut deferred: Vec<(usize, usize, Vec<u8>)> = Vec::new();
+        // FEC parity per chunk still assembling.
+        let mut parity: HashMap<usize, ChunkParity> = HashMap::new();
+        let mut fec = FecCodec::default();
 
+        let started = Instant::now();
         let mut last_nack_scan = Instant::now();
+        let mut last_frame = Instant::now();
+        let mut pacer = NackPacer::new(chunk_count);
+        // Frames queued for this thread at the last NACK scan.
+        let mut last_backlog = 0;
 
         loop {
             if progress_asm.is_cancelled() {
-                return Err("Cancelled".into());
+                return Err(TransferError::Cancelled);
             }
 
             if completed_count >= chunk_count {
@@ -263,42 +543,143 @@ pub fn run_receiver(
             // Try to receive a frame with timeout for NACK scanning
             match frame_rx.recv_timeout(std::time::Duration::from_millis(NACK_SCAN_INTERVAL_MS)) {
                 Ok((header, payload)) => {
+                    last_frame = Instant::now();
                     let cidx = header.chunk_index as usize;
-                    if cidx >= chunk_count as usize || completed[cidx] {
+                    if cidx >= chunk_count as usize
+                        || completed[cidx]
+                        || (!header.is_parity() && header.frame_index >= header.frame_count)
+                    {
                         continue;
                     }
+                    pacer.frame_arrived(header.chunk_index, last_frame);
 
-                    // Initialize bitfield and buffer on first frame for this chunk
-                    if bitfields[cidx].is_none() {
-                        bitfields[cidx] = Some(ChunkBitfield::new(header.frame_count));
-                        // Calculate expected chunk size.
+                    if header.is_parity() {
+                        // Usable once the chunk is under way and the stride
+                        // known; earlier parity is dropped.
+                        let (Some(stride), Some(bf), Some(buf)) =
+                            (frame_payload, bitfields[cidx].as_mut(), buffers[cidx].as_mut())
+                        else {
+                            continue;
+                        };
+                        let chunk_parity = parity.entry(cidx).or_default();
+                        let Some(group) = chunk_parity.insert(header.frame_index, header.frame_count, payload, stride)
+                        else {
+                            continue;
+                        };
+                        let recovered = fec
+                            .recover_group(chunk_parity, group, bf, buf, stride)
+                            .map_err(TransferError::Protocol)?;
+                        if recovered == 0 {
+                            continue;
+                        }
+                        progress_asm.fec_recovered.fetch_add(recovered as u64, Ordering::Relaxed);
+                        if let Some(ref logger) = logger_asm {
+                            logger.log(TransferLog {
+                                component: "receiver",
+                                transfer_id,
+                                event: TransferEvent::FecRecovered {
+                                    chunk_idx: cidx as u32,
+                                    frame_count: recovered as u16,
+                                },
+                            });
+                        }
+                    } else {
                         // file_size and chunk_size are both encrypted sizes.
                         let this_chunk_size = if cidx == chunk_count as usize - 1 {
                             (file_size - cidx as u64 * chunk_size) as usize
                         } else {
                             chunk_size as usize
                         };
-                        buffers[cidx] = Some(vec![0u8; this_chunk_size]);
-                    }
 
-                    let bf = bitfields[cidx].as_mut().unwrap();
-                    let buf = buffers[cidx].as_mut().unwrap();
+                        // Every frame but a chunk's last carries exactly one stride;
+                        // the last one covers the rest, which also pins the stride
+                        // unless the chunk is compressed.
+                        let idx = header.frame_index as usize;
+                        let implied_stride = if header.frame_index + 1 < header.frame_count {
+                            Some(payload.len())
+                        } else if idx > 0 && !compressed {
+                            this_chunk_size
+                                .checked_sub(payload.len())
+                                .filter(|rest| rest % idx == 0)
+                                .map(|rest| rest / idx)
+                                .or(Some(0)) // no whole stride fits: reported below
+                        } else {
+                            None
+                        };
+                        if let Some(stride) = implied_stride {
+                            match frame_payload {
+                                None if (1..=MAX_FRAME_PAYLOAD).contains(&stride) => {
+                                    frame_payload = Some(stride);
+                                    progress_asm.frame_payload.store(stride as u64, Ordering::Relaxed);
+                                    for (c, i, early) in deferred.drain(..) {
+                                        if let Some(buf) = buffers[c].as_mut() {
+                                            let offset = (i * stride).min(buf.len());
+                                            let end = (offset + early.len()).min(buf.len());
+                                            buf[offset..end].copy_from_slice(&early[..end - offset]);
+                                        }
+                                    }
+                                }
+                                Some(known) if known == stride => {}
+                                _ => {
+                                    progress_asm.state.store(STATE_ERROR, Ordering::Relaxed);
+                                    return Err(TransferError::Protocol(format!(
+                                        "Frame size mismatch: chunk {} frame {} implies {} bytes per frame, expected {:?}",
+                                        cidx, idx, stride, frame_payload
+                                    )));
+                                }
+                            }
+                        }
+
+                        // Initialize bitfield and buffer on first frame for this chunk
+                        if bitfields[cidx].is_none() {
+                            // A sender chunking at a different size would have its
+                            // frames copied to the wrong offsets; fail loudly instead.
+                            let consistent = match frame_payload {
+                                Some(stride) if compressed => {
+                                    header.frame_count <= frames_for_chunk_with(this_chunk_size, stride)
+                                }
+                                Some(stride) => frames_for_chunk_with(this_chunk_size, stride) == header.frame_count,
+                                // Before the stride is known, only a chunk's sole frame
+                                // arrives, or a compressed chunk's last one.
+                                None if compressed && idx > 0 => header.frame_count as usize <= this_chunk_size,
+                                None if compressed => header.frame_count == 1 && payload.len() <= this_chunk_size,
+                                None => header.frame_count == 1 && payload.len() == this_chunk_size,
+                            };
+                            if !consistent {
+                                progress_asm.state.store(STATE_ERROR, Ordering::Relaxed);
+                                return Err(TransferError::Protocol(format!(
+                                    "Chunk size mismatch: chunk {} of {} bytes arrived in {} frames",
+                                    cidx, this_chunk_size, header.frame_count
+                                )));
+                            }
+                            bitfields[cidx] = Some(ChunkBitfield::new(header.frame_count));
+                            buffers[cidx] = Some(vec![0u8; this_chunk_size]);
+                        }
+
+                        let offset = idx * frame_payload.unwrap_or(0);
+                        let bf = bitfields[cidx].as_mut().unwrap();
+                        let buf = buffers[cidx].as_mut().unwrap();
 
-                    // Copy payload into buffer at frame_index * FRAME_PAYLOAD
-                    if bf.set(header.frame_index) {
-                        let offset = header.frame_index as usize * FRAME_PAYLOAD;
-                        let end = (offset + payload.len()).min(buf.len());
-                        let copy_len = end - offset;
-                        buf[offset..offset + copy_len].copy_from_slice(&payload[..copy_len]);
+                        // Copy payload into buffer at frame_index * frame_payload
+                        if frame_payload.is_none() && idx > 0 {
+                            if bf.set(header.frame_index) {
+                                deferred.push((cidx, idx, payload));
+                            }
+                        } else if offset < buf.len() && bf.set(header.frame_index) {
+                            let end = (offset + payload.len()).min(buf.len());
+                            let copy_len = end - offset;
+                            buf[offset..offset + copy_len].copy_from_slice(&payload[..copy_len]);
+                        }
                     }
 
                     // Check if chunk is complete
-                    if bf.is_complete() {
+                    if bitfields[cidx].as_ref().is_some_and(ChunkBitfield::is_complete) {
                         completed[cidx] = true;
                         completed_count += 1;
 
                         let data = buffers[cidx].take().unwrap();
                         bitfields[cidx] = None;
+                        parity.remove(&cidx);
 
                         if assembled_tx
                             .send(AssembledChunk {
@@ -307,24 +688,57 @@ pub fn run_receiver(
                             })
                             .is_err()
                         {
-                            return Err("Assembled channel closed".into());
+                            return Err(TransferError::Protocol("Assembled channel closed".into()));
                         }
                     }
                 }
                 Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    if last_frame.elapsed() >= stall_timeout {
+                        progress_asm.state.store(STATE_ERROR, Ordering::Relaxed);
+                        return Err(TransferError::Stalled(format!("no frames for {:?}", stall_timeout)));
+                    }
                     // NACK scan timeout — fall through to scan below
                 }
                 Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                    // The vacuum hangs up when cancelled too, often before
+                    // this loop's next check sees it.
+                    if progress_asm.is_cancelled() {
+                        return Err(TransferError::Cancelled);
+                    }
                     return Ok(());
                 }
             }
 
-            // Periodic NACK scan
+            // Periodic NACK scan: every chunk due goes out in one batch
             if last_nack_scan.elapsed().as_millis() >= NACK_SCAN_INTERVAL_MS as u128 {
                 last_nack_scan = Instant::now();
+                let now = last_nack_scan;
 
+                let mut nacks = Vec::new();
+                // Frames piling up here means the sender outruns this thread
+                // (or the writer behind it), not the network: ask it to slow
+                // down before the ring fills and the kernel drops, again each
+                // scan the pile is still growing. Until it drains, frames
+                // missing from a chunk may just be queued, so nothing is
+                // NACKed.
+                let backlog = frame_rx.len();
+                progress_asm.assembler_backlog.fetch_max(backlog as u64, Ordering::Relaxed);
+                let lagging = backlog >= ASSEMBLER_LAG_FRAMES;
+                let growing = backlog > last_backlog;
+                last_backlog = backlog;
+                if lagging && growing {
+                    progress_asm.slowdowns.fetch_add(1, Ordering::Relaxed);
+                    if let Some(ref logger) = logger_asm {
+                        logger.log(TransferLog {
+                            component: "receiver",
+                            transfer_id,
+                            event: TransferEvent::AssemblerLagging { queued: backlog as u64 },
+                        });
+                    }
+                    nacks.push(NackMessage::slowdown());
+                }
                 for cidx in 0..chunk_count as usize {
-                    if completed[cidx] {
+                    if lagging || completed[cidx] || !pacer.due(cidx as u32, now) {
                         continue;
                     }
                     if let Some(ref bf) = bitfields[cidx] {
@@ -334,6 +748,11 @@ pub fn run_receiver(
                                 progress_asm
                                     .retransmits
                                     .fetch_add(missing.len() as u64, Ordering::Relaxed);
+                                progress_asm
+                                    .loss_histogram
+                                    .lock()
+                                    .unwrap()
+                                    .record(started.elapsed(), missing.len() as u64);
 
                                 if let Some(ref logger) = logger_asm {
                                     logger.log(TransferLog {
@@ -346,11 +765,15 @@ pub fn run_receiver(
                                     });
                                 }
 
-                                nack_cb(cidx as u32, missing);
+                                pacer.nacked(cidx as u32, now);
+                                nacks.push(NackMessage { chunk_index: cidx as u32, missing_frames: missing });
                             }
                         }
                     }
                 }
+                if !nacks.is_empty() {
+                    nack_cb(nacks);
+                }
             }
         }
     });
@@ -361,19 +784,80 @@ pub fn run_receiver(
     let output_path = config.output_path.clone();
     let chunk_hashes_w = config.chunk_hashes.clone();
     let _file_sha256_expected = config.file_sha256.clone();
+    let ack_callback = config.ack_callback;
+    let state_path_writer = state_path.clone();
+    let sync_cadence = config.sync;
 
-    let writer_handle = std::thread::spawn(move || -> Result<(), String> {
+    let span_writer = span.clone();
+    let writer_handle = std::thread::spawn(move || -> Result<(), TransferError> {
         use std::io::{Seek, SeekFrom, Write};
+        let _span = span_writer.entered();
         let mut file = std::fs::OpenOptions::new()
             .write(true)
             .open(&output_path)
-            .map_err(|e| format!("Cannot open output file: {}", e))?;
+            .map_err(|e| TransferError::Io(format!("Cannot open output file: {}", e)))?;
+
+        // Out of space shows up on write or, with delayed allocation, on sync.
+        let io_failure = |e: io::Error, what: String| {
+            if disk::is_disk_full(&e) {
+                let needed = file_size.saturating_sub(progress_writer.bytes_done.load(Ordering::Relaxed));
+                let available = disk::available_space(Path::new(&output_path)).unwrap_or(0);
+                return progress_writer.fail_disk_full(needed, available);
+            }
+            TransferError::Io(format!("{}: {}", what, e))
+        };
+        let sync = |file: &std::fs::File| {
+            file.sync_data().map_err(|e| io_failure(e, "Sync error".into()))?;
+            progress_writer.syncs.fetch_add(1, Ordering::Relaxed);
+            Ok::<_, TransferError>(())
+        };
+        let mut unsynced_chunks = 0u64;
+        let mut unsynced_bytes = 0u64;
 
-        let mut chunks_written = 0u32;
+        let mut chunks_written = chunks_already_written;
+        let mut pending_acks: Vec<u32> = Vec::new();
+        let mut last_ack_flush = Instant::now();
+        // Chunks are recorded before they're ACKed, so a sender resuming
+        // from its ACKs never skips one the record lacks.
+        let mut flush_acks = |pending: &mut Vec<u32>| {
+            if let Some(ref mut record) = chunk_record
+                && !pending.is_empty()
+            {
+                for &c in pending.iter() {
+                    record.set(c);
+                }
+                if let Err(e) = record.save(&state_path_writer) {
+                    tracing::warn!("Cannot record written chunks in {}: {}", state_path_writer.display(), e);
+                }
+            }
+            if let Some(ref cb) = ack_callback
+                && let Some((base, bitmap)) = encode_ack_bitmap(pending)
+            {
+                cb(base, bitmap);
+            }
+            pending.clear();
+        };
 
-        for assembled in assembled_rx {
+        let flush_interval = Duration::from_millis(ACK_FLUSH_INTERVAL_MS);
+        loop {
+            let assembled = match assembled_rx.recv_timeout(flush_interval) {
+                Ok(assembled) => assembled,
+                Err(RecvTimeoutError::Timeout) => {
+                    // No chunk for a while: ACK what's pending anyway. A sender
+                    // whose cache is full sends nothing more until it hears.
+                    if !pending_acks.is_empty() {
+                        flush_acks(&mut pending_acks);
+                        last_ack_flush = Instant::now();
+                    }
+                    if progress_writer.is_cancelled() {
+                        return Err(TransferError::Cancelled);
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
             if progress_writer.is_cancelled() {
-                return Err("Cancelled".into());
+                return Err(TransferError::Cancelled);
             }
 
             let start = Instant::now();
@@ -402,20 +886,28 @@ pub fn run_receiver(
             }
 
             if !hash_match {
-                return Err(format!(
-                    "Chunk {} hash mismatch: expected {}, got {}",
-                    cidx,
-                    chunk_hashes_w.get(cidx).unwrap_or(&String::new()),
-                    actual_hash,
-                ));
+                return Err(TransferError::HashMismatch {
+                    chunk: assembled.chunk_index,
+                    expected: chunk_hashes_w.get(cidx).cloned().unwrap_or_default(),
+                    actual: actual_hash,
+                });
             }
 
             // Write at chunk offset
             let offset = cidx as u64 * chunk_size;
-            file.seek(SeekFrom::Start(offset))
-                .map_err(|e| format!("Seek error: {}", e))?;
-            file.write_all(&assembled.data)
-                .map_err(|e| format!("Write error: {}", e))?;
+            let written = file
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| file.write_all(&assembled.data));
+            if let Err(e) = written {
+                return Err(io_failure(e, format!("Write error at chunk {}", cidx)));
+            }
+            unsynced_chunks += 1;
+            unsynced_bytes += assembled.data.len() as u64;
+            if sync_cadence.due(unsynced_chunks, unsynced_bytes) {
+                sync(&file)?;
+                unsynced_chunks = 0;
+                unsynced_bytes = 0;
+            }
 
             let duration_ms = start.elapsed().as_millis() as u64;
             if let Some(ref logger) = logger_writer {
@@ -435,10 +927,26 @@ pub fn run_receiver(
             progress_writer.chunks_complete.fetch_add(1, Ordering::Relaxed);
             chunks_written += 1;
 
+            // Keep each batch within one bitmap's span.
+            let span_limit = (MAX_ACK_BITMAP_BYTES * 8) as u32;
+            if pending_acks.iter().any(|&c| c.abs_diff(assembled.chunk_index) >= span_limit) {
+                flush_acks(&mut pending_acks);
+            }
+            pending_acks.push(assembled.chunk_index);
+            if last_ack_flush.elapsed().as_millis() >= ACK_FLUSH_INTERVAL_MS as u128 {
+                flush_acks(&mut pending_acks);
+                last_ack_flush = Instant::now();
+            }
+
             if chunks_written >= chunk_count {
                 break;
             }
         }
+        // Complete means on disk: the server serves the file from here on.
+        // Synced even when nothing's pending, as a resumed run may have
+        // inherited chunks an earlier one never synced.
+        sync(&file)?;
+        flush_acks(&mut pending_acks);
 
         progress_writer.state.store(STATE_COMPLETE, Ordering::Relaxed);
         Ok(())
@@ -446,34 +954,572 @@ pub fn run_receiver(
 
     // Wait for all threads
     // Vacuum might still be running when assembler completes; signal it via progress state.
-    assembler_handle
+    let assembler_result = assembler_handle
         .join()
-        .map_err(|_| "Assembler thread panicked".to_string())??;
+        .map_err(|_| TransferError::Protocol("Assembler thread panicked".into()))?;
 
     // Signal vacuum to stop
-    progress.state.store(STATE_COMPLETE, Ordering::Relaxed);
+    if assembler_result.is_ok() {
+        progress.state.store(STATE_COMPLETE, Ordering::Relaxed);
+    }
 
-    writer_handle
+    let writer_result = writer_handle
         .join()
-        .map_err(|_| "Writer thread panicked".to_string())??;
+        .map_err(|_| TransferError::Protocol("Writer thread panicked".into()))?;
+
+    // A writer failure closes the assembled channel, so the assembler only
+    // sees "channel closed" — report the writer's error, which says why.
+    if let Err(e) = writer_result.and(assembler_result) {
+        // The writer marks itself complete once its channel closes, even
+        // when that was the assembler giving up.
+        progress.state.store(STATE_ERROR, Ordering::Relaxed);
+        if progress.error_kind.load(Ordering::Relaxed) == ERROR_KIND_DISK_FULL {
+            // Free the space we did manage to take; the partial file is useless.
+            let _ = std::fs::remove_file(&config.output_path);
+            let _ = std::fs::remove_file(&state_path);
+        }
+        return Err(e);
+    }
+    if config.resume {
+        let _ = std::fs::remove_file(&state_path);
+    }
 
-    // Don't wait for vacuum — it will exit on next recv timeout when it sees STATE_COMPLETE.
-    // We can't join it because recv_from might block. Drop it instead.
-    drop(vacuum_handle);
+    if let Some(ref logger) = config.logger {
+        let histogram = progress.loss_histogram.lock().unwrap().clone();
+        logger.log(TransferLog {
+            component: "receiver",
+            transfer_id,
+            event: TransferEvent::LossHistogram {
+                bucket_ms: histogram.bucket_ms(),
+                buckets: histogram.buckets().to_vec(),
+            },
+        });
+    }
+
+    // Don't wait for the vacuums — each exits on its next recv timeout when it
+    // sees STATE_COMPLETE. We can't join them because recv_from might block.
+    drop(vacuum_handles);
 
     Ok(bound_addr)
 }
 
-/// Create a UDP socket bound to the given address with large recv buffer.
-fn create_recv_socket(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
-    use socket2::{Domain, Protocol, Socket, Type};
+/// Drain `socket` into the assembler's `frame_tx` until the transfer ends,
+/// dropping frames for other transfers or claiming more than `max_frames`
+/// per chunk, and echoing MTU probes.
+fn vacuum(
+    socket: PooledSocket,
+    frame_tx: crossbeam_channel::Sender<(FrameHeader, Vec<u8>)>,
+    progress: &ReceiverProgress,
+    logger: Option<Arc<dyn TransferLogger>>,
+    transfer_id: [u8; 16],
+    max_frames: u16,
+) -> Result<(), TransferError> {
+    let mut recv_buf = vec![0u8; MAX_FRAME + 64]; // extra safety margin
+    let mut frames_received: u64 = 0;
+    let mut frames_rejected: u64 = 0;
+    let local_addr = socket.local_addr().map(|a| a.to_string()).unwrap_or_else(|_| "?".into());
+    let drops_at_start = net::udp_drops(&socket);
+    let mut last_drop_check = Instant::now();
+    let mut dropped_here: u64 = 0;
+
+    if let Some(ref logger) = logger {
+        logger.log(TransferLog {
+            component: "receiver",
+            transfer_id,
+            event: TransferEvent::VacuumStarted { bind_addr: local_addr.clone() },
+        });
+    }
+
+    loop {
+        if progress.is_cancelled() {
+            return Err(TransferError::Cancelled);
+        }
+
+        // Check if we're done (or the writer gave up)
+        let state = progress.state.load(Ordering::Relaxed);
+        if state == STATE_COMPLETE || state == STATE_ERROR {
+            return Ok(());
+        }
+
+        if let Some(start) = drops_at_start
+            && last_drop_check.elapsed() >= Duration::from_millis(SOCKET_DROP_CHECK_INTERVAL_MS)
+        {
+            last_drop_check = Instant::now();
+            // Each vacuum adds its own socket's drops to the total.
+            let dropped = net::udp_drops(&socket).unwrap_or(start).saturating_sub(start);
+            if dropped > dropped_here {
+                let new = dropped - dropped_here;
+                dropped_here = dropped;
+                let total = progress.socket_drops.fetch_add(new, Ordering::Relaxed) + new;
+                if let Some(ref logger) = logger {
+                    logger.log(TransferLog {
+                        component: "receiver",
+                        transfer_id,
+                        event: TransferEvent::SocketDrops { dropped: total, new },
+                    });
+                }
+            }
+        }
+
+        match socket.recv_from(&mut recv_buf) {
+            Ok((len, src)) => {
+                if len < FRAME_HEADER {
+                    continue;
+                }
+
+                if let Some(header) = decode_frame_header(&recv_buf[..len]) {
+                    // Verify transfer ID
+                    if header.transfer_id != transfer_id {
+                        frames_rejected += 1;
+                        if frames_rejected <= 3 {
+                            if let Some(ref logger) = logger {
+                                logger.log(TransferLog {
+                                    component: "receiver",
+                                    transfer_id,
+                                    event: TransferEvent::TransferIdMismatch {
+                                        got: header.transfer_id,
+                                        from: src.to_string(),
+                                    },
+                                });
+                            }
+                        }
+                        continue;
+                    }
+
+                    if header.chunk_index == PROBE_CHUNK_INDEX {
+                        mtu::echo_probe(&socket, &recv_buf[..len], src);
+                        continue;
+                    }
+
+                    // Nothing to assemble, but it restarts the stall clock.
+                    if header.chunk_index == KEEPALIVE_CHUNK_INDEX {
+                        if frame_tx.send((header, Vec::new())).is_err() {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+
+                    // Before the payload is copied or the assembler sizes
+                    // anything from the header. Parity frames carry the FEC
+                    // ratio in `frame_count` instead.
+                    if !header.is_parity() && header.frame_count > max_frames {
+                        progress.frames_rejected.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+
+                    frames_received += 1;
+                    progress.frames_received.fetch_add(1, Ordering::Relaxed);
+                    if frames_received == 1 || frames_received % 10000 == 0 {
+                        if let Some(ref logger) = logger {
+                            logger.log(TransferLog {
+                                component: "receiver",
+                                transfer_id,
+                                event: TransferEvent::VacuumProgress {
+                                    frames_received,
+                                    from: src.to_string(),
+                                },
+                            });
+                        }
+                    }
 
-    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-    socket.set_nonblocking(false)?;
-    socket.set_recv_buffer_size(UDP_RECV_BUFFER)?;
+                    let payload = recv_buf[FRAME_HEADER..len].to_vec();
+                    if frame_tx.send((header, payload)).is_err() {
+                        return Ok(()); // Channel closed, assembler done
+                    }
+                }
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                // Timeout on recv — check cancellation and continue
+                // Note: Windows returns TimedOut, Unix returns WouldBlock
+                continue;
+            }
+            Err(e) => {
+                return Err(TransferError::Network(format!("UDP recv error: {}", e)));
+            }
+        }
+    }
+}
+
+/// Create a UDP socket bound to the given address with large recv buffer.
+/// `[::]` also accepts IPv4 senders where the OS allows.
+fn create_recv_socket(addr: SocketAddr, recv_buffer: usize) -> io::Result<PooledSocket> {
+    let spec = SocketSpec { recv_buffer, ..SocketSpec::receiver(addr) };
+    let socket = SocketPool::global().checkout(spec)?;
     // Set recv timeout so vacuum thread can check cancellation periodically
     socket.set_read_timeout(Some(std::time::Duration::from_millis(100)))?;
-    socket.bind(&addr.into())?;
 
-    Ok(socket.into())
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(output_path: &Path, file_size: u64) -> ReceiverConfig {
+        ReceiverConfig {
+            output_path: output_path.to_string_lossy().into_owned(),
+            transfer_id: [7u8; 16],
+            file_size,
+            chunk_count: 1,
+            chunk_hashes: vec![String::new()],
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_disk_space_precheck_fails_cleanly() {
+        let dir = std::env::temp_dir().join(format!("haven-rx-diskfull-{}", std::process::id()));
+        let output = dir.join("huge.bin");
+
+        // Far larger than any test machine's free space.
+        let mut config = test_config(&output, 1 << 50);
+        config.chunk_size = encrypted_chunk_size(MAX_CHUNK_SIZE) as u64;
+        config.chunk_count = config.file_size.div_ceil(config.chunk_size) as u32;
+        let progress = Arc::new(ReceiverProgress::new());
+        let err = run_receiver(config, progress.clone(), Box::new(|_| {})).unwrap_err();
+
+        assert!(matches!(err, TransferError::DiskFull { needed, .. } if needed == 1 << 50), "{}", err);
+        assert!(err.to_string().starts_with("Not enough disk space"), "{}", err);
+        assert_eq!(progress.error_kind.load(Ordering::Relaxed), ERROR_KIND_DISK_FULL);
+        assert_eq!(progress.state.load(Ordering::Relaxed), STATE_ERROR);
+        assert!(!output.exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_tampered_chunk_size_rejected() {
+        let dir = std::env::temp_dir().join(format!("haven-rx-mac-{}", std::process::id()));
+        let output = dir.join("out.bin");
+        let key = [42u8; 32];
+
+        // Uploader MACs the FastUploadStart fields...
+        let mut config = test_config(&output, 1024);
+        config.file_sha256 = "ab".repeat(32);
+        let mac = ControlFields {
+            transfer_id: &config.transfer_id,
+            file_size: config.file_size,
+            chunk_size: config.chunk_size,
+            chunk_count: config.chunk_count,
+            file_sha256: &config.file_sha256,
+            sparse_chunks: &config.sparse_chunks,
+        }
+        .mac(&key);
+
+        // ...and a relay rewrites chunk_size in transit.
+        config.chunk_size = 1024;
+        config.control_key = Some(key);
+        config.control_mac = Some(mac);
+
+        let progress = Arc::new(ReceiverProgress::new());
+        let err = run_receiver(config, progress.clone(), Box::new(|_| {})).unwrap_err();
+
+        assert_eq!(err, TransferError::Crypto("Control message integrity check failed".into()));
+        assert_eq!(progress.state.load(Ordering::Relaxed), STATE_ERROR);
+        assert!(!output.exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_stalls_without_sender() {
+        let dir = std::env::temp_dir().join(format!("haven-rx-stall-{}", std::process::id()));
+        let output = dir.join("out.bin");
+
+        // Nothing ever blasts to this receiver.
+        let mut config = test_config(&output, 1024 * 1024);
+        config.stall_timeout = Duration::from_millis(300);
+        let progress = Arc::new(ReceiverProgress::new());
+        let started = Instant::now();
+        let err = run_receiver(config, progress.clone(), Box::new(|_| {})).unwrap_err();
+
+        assert!(matches!(err, TransferError::Stalled(_)), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(progress.state.load(Ordering::Relaxed), STATE_ERROR);
+        assert_eq!(progress.frames_received.load(Ordering::Relaxed), 0);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_acks_flush_while_sender_waits_on_window() {
+        use crate::harness::{self, Loopback};
+        use crate::sender::{RawSenderConfig, SenderProgress};
+
+        let dir = harness::scratch_dir("rx-ackflush");
+        let input = dir.join("in.bin");
+        let output = dir.join("out.bin");
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE);
+        let data: Vec<u8> = (0..3 * chunk_size).map(|i| (i % 241) as u8).collect();
+        std::fs::write(&input, &data).unwrap();
+
+        // One chunk in flight: each next chunk waits on the last one's ACK,
+        // which only comes if the receiver flushes without a newer chunk.
+        let receiver = Loopback::start(harness::receiver_config(&output, &data, chunk_size));
+        receiver
+            .send_raw(
+                RawSenderConfig {
+                    stall_timeout: Duration::from_secs(2),
+                    cache_size: 1,
+                    ..harness::raw_sender_config(&input, receiver.addr, data.len() as u64, chunk_size)
+                },
+                Arc::new(SenderProgress::new()),
+            )
+            .unwrap();
+        receiver.join().unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Sleeps in the writer thread after every chunk it writes.
+    struct SlowWriter(Duration);
+
+    impl TransferLogger for SlowWriter {
+        fn log(&self, entry: TransferLog) {
+            if let TransferEvent::ChunkWritten { .. } = entry.event {
+                std::thread::sleep(self.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_slow_writer_slows_the_sender() {
+        use crate::harness::{self, Loopback};
+        use crate::sender::{RawSenderConfig, SenderProgress};
+
+        let dir = harness::scratch_dir("rx-slowdown");
+        let input = dir.join("in.bin");
+        let output = dir.join("out.bin");
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE);
+        let chunk_count = 400;
+        let data: Vec<u8> = (0..chunk_count * chunk_size).map(|i| (i % 239) as u8).collect();
+        std::fs::write(&input, &data).unwrap();
+
+        // Some 30 MB/s at most, and a file larger than the ring: without
+        // backing off, the sender would fill it.
+        let receiver = Loopback::start(ReceiverConfig {
+            logger: Some(Arc::new(SlowWriter(Duration::from_millis(2)))),
+            ..harness::receiver_config(&output, &data, chunk_size)
+        });
+
+        // A cache holding the whole file: only the receiver's slowdowns can
+        // hold the sender back.
+        let sender_progress = Arc::new(SenderProgress::new());
+        receiver
+            .send_raw(
+                RawSenderConfig {
+                    stall_timeout: Duration::from_secs(10),
+                    cache_size: chunk_count,
+                    ..harness::raw_sender_config(&input, receiver.addr, data.len() as u64, chunk_size)
+                },
+                sender_progress.clone(),
+            )
+            .unwrap();
+        let progress = receiver.progress.clone();
+        receiver.join().unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        // Those sent after the last chunk went out have nothing to slow.
+        assert!(progress.slowdowns.load(Ordering::Relaxed) > 0);
+        assert!(sender_progress.slowdowns.load(Ordering::Relaxed) > 0);
+        // The sender backed off before the ring filled and blocked the vacuum.
+        let backlog = progress.assembler_backlog.load(Ordering::Relaxed);
+        assert!((ASSEMBLER_LAG_FRAMES as u64..RING_BUFFER_FRAMES as u64).contains(&backlog), "{}", backlog);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_reuseport_workers_assemble_one_file() {
+        let dir = std::env::temp_dir().join(format!("haven-rx-reuseport-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("out.bin");
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE);
+        let data: Arc<Vec<u8>> = Arc::new((0..12 * chunk_size - 500).map(|i| (i % 251) as u8).collect());
+
+        let mut sockets = net::bind_udp_group("127.0.0.1:0".parse().unwrap(), 3, |s| {
+            s.set_recv_buffer_size(UDP_RECV_BUFFER)
+        })
+        .unwrap()
+        .into_iter()
+        .map(PooledSocket::from);
+        let mut config = test_config(&output, data.len() as u64);
+        config.chunk_count = data.len().div_ceil(chunk_size) as u32;
+        config.chunk_size = chunk_size as u64;
+        config.chunk_hashes = data.chunks(chunk_size).map(|c| hex::encode(Sha256::digest(c))).collect();
+        config.pre_bound_socket = sockets.next();
+        config.extra_sockets = sockets.collect();
+        let target = config.pre_bound_socket.as_ref().unwrap().local_addr().unwrap();
+
+        // Frames for `frames` of `chunk`, sent from `from`.
+        let send = move |from: &std::net::UdpSocket, data: &[u8], chunk: u32, frames: &[u16]| {
+            let bytes = &data[chunk as usize * chunk_size..((chunk as usize + 1) * chunk_size).min(data.len())];
+            let frame_count = frames_for_chunk_with(bytes.len(), FRAME_PAYLOAD);
+            let mut buf = vec![0u8; MAX_FRAME];
+            for &frame in frames {
+                let start = frame as usize * FRAME_PAYLOAD;
+                let payload = &bytes[start..(start + FRAME_PAYLOAD).min(bytes.len())];
+                let len = encode_frame(&mut buf, &[7u8; 16], chunk, frame, frame_count, payload);
+                from.send_to(&buf[..len], target).unwrap();
+            }
+        };
+        let resend_from = net::bind_udp("127.0.0.1:0".parse().unwrap()).unwrap();
+        let resend_data = data.clone();
+        let nack_callback: NackCallback = Box::new(move |nacks| {
+            for nack in nacks {
+                send(&resend_from, &resend_data, nack.chunk_index, &nack.missing_frames);
+            }
+        });
+        let progress = Arc::new(ReceiverProgress::new());
+        let receiver = std::thread::spawn({
+            let progress = progress.clone();
+            move || run_receiver(config, progress, nack_callback)
+        });
+
+        // Each chunk comes from its own source port, so the kernel spreads
+        // them over the group.
+        let mut frames_sent = 0;
+        for chunk in 0..data.len().div_ceil(chunk_size) as u32 {
+            let from = net::bind_udp("127.0.0.1:0".parse().unwrap()).unwrap();
+            let frame_count = frames_for_chunk_with(chunk_size.min(data.len() - chunk as usize * chunk_size), FRAME_PAYLOAD);
+            send(&from, &data, chunk, &(0..frame_count).collect::<Vec<_>>());
+            frames_sent += frame_count as u64;
+        }
+        receiver.join().unwrap().unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), *data);
+        assert_eq!(progress.state.load(Ordering::Relaxed), STATE_COMPLETE);
+        // Every worker counts into the one total; NACKed resends add to it.
+        assert!(progress.frames_received.load(Ordering::Relaxed) >= frames_sent);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_absurd_frame_count_is_dropped_before_assembly() {
+        let dir = std::env::temp_dir().join(format!("haven-rx-framecount-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("out.bin");
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE);
+        let data: Vec<u8> = (0..chunk_size).map(|i| (i % 249) as u8).collect();
+
+        let mut config = test_config(&output, data.len() as u64);
+        config.chunk_size = chunk_size as u64;
+        config.chunk_hashes = vec![hex::encode(Sha256::digest(&data))];
+        let progress = Arc::new(ReceiverProgress::new());
+        let receiver = std::thread::spawn({
+            let progress = progress.clone();
+            move || run_receiver(config, progress, Box::new(|_| {}))
+        });
+        let port = loop {
+            match progress.bound_port.load(Ordering::Relaxed) {
+                0 => std::thread::sleep(Duration::from_millis(2)),
+                port => break port,
+            }
+        };
+        let target = SocketAddr::new([127, 0, 0, 1].into(), port);
+        let from = net::bind_udp("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut buf = vec![0u8; MAX_FRAME];
+
+        // Forged first frames claiming the chunk spans 65535 frames, which
+        // would otherwise size the chunk's bitfield (and fail it as a size
+        // mismatch). One has the wrong transfer ID and is dropped for that.
+        for tid in [[7u8; 16], [8u8; 16]] {
+            let len = encode_frame(&mut buf, &tid, 0, 0, u16::MAX, &data[..FRAME_PAYLOAD]);
+            from.send_to(&buf[..len], target).unwrap();
+        }
+        let frame_count = frames_for_chunk(chunk_size);
+        for (frame, payload) in data.chunks(FRAME_PAYLOAD).enumerate() {
+            let len = encode_frame(&mut buf, &[7u8; 16], 0, frame as u16, frame_count, payload);
+            from.send_to(&buf[..len], target).unwrap();
+        }
+        receiver.join().unwrap().unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        assert_eq!(progress.frames_rejected.load(Ordering::Relaxed), 1);
+        assert_eq!(progress.frames_received.load(Ordering::Relaxed), frame_count as u64);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_syncs_on_cadence_and_before_complete() {
+        let dir = std::env::temp_dir().join(format!("haven-rx-sync-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("out.bin");
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE);
+        let data: Arc<Vec<u8>> = Arc::new((0..5 * chunk_size - 300).map(|i| (i % 253) as u8).collect());
+
+        let socket = net::bind_udp_group("127.0.0.1:0".parse().unwrap(), 1, |s| s.set_recv_buffer_size(UDP_RECV_BUFFER))
+            .unwrap()
+            .pop()
+            .map(PooledSocket::from)
+            .unwrap();
+        let target = socket.local_addr().unwrap();
+        let mut config = test_config(&output, data.len() as u64);
+        config.chunk_count = data.len().div_ceil(chunk_size) as u32;
+        config.chunk_size = chunk_size as u64;
+        config.chunk_hashes = data.chunks(chunk_size).map(|c| hex::encode(Sha256::digest(c))).collect();
+        config.pre_bound_socket = Some(socket);
+        config.sync = SyncCadence { every_chunks: 2, every_bytes: 0 };
+
+        let from = Arc::new(net::bind_udp("127.0.0.1:0".parse().unwrap()).unwrap());
+        let send = {
+            let (from, data) = (from.clone(), data.clone());
+            move |chunk: u32, frames: &[u16]| {
+                let bytes = &data[chunk as usize * chunk_size..((chunk as usize + 1) * chunk_size).min(data.len())];
+                let frame_count = frames_for_chunk_with(bytes.len(), FRAME_PAYLOAD);
+                let mut buf = vec![0u8; MAX_FRAME];
+                for &frame in frames {
+                    let start = frame as usize * FRAME_PAYLOAD;
+                    let payload = &bytes[start..(start + FRAME_PAYLOAD).min(bytes.len())];
+                    let len = encode_frame(&mut buf, &[7u8; 16], chunk, frame, frame_count, payload);
+                    from.send_to(&buf[..len], target).unwrap();
+                }
+            }
+        };
+        let resend = send.clone();
+        let nack_callback: NackCallback = Box::new(move |nacks| {
+            for nack in nacks {
+                resend(nack.chunk_index, &nack.missing_frames);
+            }
+        });
+        let progress = Arc::new(ReceiverProgress::new());
+        let receiver = std::thread::spawn({
+            let progress = progress.clone();
+            move || run_receiver(config, progress, nack_callback)
+        });
+        for chunk in 0..data.len().div_ceil(chunk_size) as u32 {
+            let frame_count = frames_for_chunk_with(chunk_size.min(data.len() - chunk as usize * chunk_size), FRAME_PAYLOAD);
+            send(chunk, &(0..frame_count).collect::<Vec<_>>());
+        }
+        receiver.join().unwrap().unwrap();
+
+        // After chunks 2 and 4, then once more before completing.
+        assert_eq!(progress.syncs.load(Ordering::Relaxed), 3);
+        assert_eq!(progress.state.load(Ordering::Relaxed), STATE_COMPLETE);
+        // Best effort: a fresh handle reads what was synced.
+        assert_eq!(std::fs::read(&output).unwrap(), *data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_sync_cadence_limits() {
+        assert!(!SyncCadence::default().due(u64::MAX, u64::MAX));
+        let cadence = SyncCadence { every_chunks: 4, every_bytes: 1000 };
+        assert!(!cadence.due(3, 999));
+        assert!(cadence.due(4, 0));
+        assert!(cadence.due(1, 1000));
+    }
+
+    #[test]
+    fn test_enospc_detection() {
+        assert!(disk::is_disk_full(&io::Error::from(io::ErrorKind::StorageFull)));
+        #[cfg(unix)]
+        assert!(disk::is_disk_full(&io::Error::from_raw_os_error(libc::ENOSPC)));
+        assert!(!disk::is_disk_full(&io::Error::from(io::ErrorKind::PermissionDenied)));
+    }
 }
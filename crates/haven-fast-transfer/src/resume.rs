@@ -0,0 +1,231 @@
+//! Which chunks of a partial transfer are already on disk.
+//!
+//! A receiver run with `ReceiverConfig::resume` keeps a [`ChunkProgress`]
+//! in a sidecar file beside its output (see [`state_path`]), rewritten each
+//! time it ACKs a batch of chunks. If the transfer is cut off, the output
+//! file still holds those chunks at their offsets, so a later run loads the
+//! sidecar, skips them, and only the missing ones need blasting again
+//! (`SenderConfig::skip_chunks`).
+//!
+//! Layout: magic, transfer ID, file size, chunk size and chunk count (so a
+//! sidecar left by a different upload under the same path is ignored), then
+//! one bit per chunk, LSB-first like the ACK bitmaps.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"HVCP";
+const HEADER_LEN: usize = 4 + 16 + 8 + 8 + 4;
+
+/// Sidecar holding the chunk record for the transfer written to `output`.
+pub fn state_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".chunks");
+    PathBuf::from(path)
+}
+
+/// Completed chunks of one transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkProgress {
+    transfer_id: [u8; 16],
+    file_size: u64,
+    chunk_size: u64,
+    chunk_count: u32,
+    bits: Vec<u8>,
+    done: u32,
+}
+
+impl ChunkProgress {
+    /// An empty record for a transfer with this layout.
+    pub fn new(transfer_id: [u8; 16], file_size: u64, chunk_size: u64, chunk_count: u32) -> Self {
+        Self {
+            transfer_id,
+            file_size,
+            chunk_size,
+            chunk_count,
+            bits: vec![0; (chunk_count as usize).div_ceil(8)],
+            done: 0,
+        }
+    }
+
+    /// Whether this record was made for a transfer with this layout.
+    pub fn fits(&self, transfer_id: &[u8; 16], file_size: u64, chunk_size: u64, chunk_count: u32) -> bool {
+        self.transfer_id == *transfer_id
+            && self.file_size == file_size
+            && self.chunk_size == chunk_size
+            && self.chunk_count == chunk_count
+    }
+
+    /// Mark a chunk written. Returns true if it wasn't already.
+    pub fn set(&mut self, chunk_index: u32) -> bool {
+        if chunk_index >= self.chunk_count || self.contains(chunk_index) {
+            return false;
+        }
+        self.bits[chunk_index as usize / 8] |= 1 << (chunk_index % 8);
+        self.done += 1;
+        true
+    }
+
+    pub fn contains(&self, chunk_index: u32) -> bool {
+        chunk_index < self.chunk_count && self.bits[chunk_index as usize / 8] & (1 << (chunk_index % 8)) != 0
+    }
+
+    /// Number of chunks written.
+    pub fn done(&self) -> u32 {
+        self.done
+    }
+
+    /// Chunks written, in order.
+    pub fn completed(&self) -> Vec<u32> {
+        (0..self.chunk_count).filter(|&i| self.contains(i)).collect()
+    }
+
+    /// Chunks still needed, in order.
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.chunk_count).filter(|&i| !self.contains(i)).collect()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.bits.len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&self.transfer_id);
+        out.extend_from_slice(&self.file_size.to_le_bytes());
+        out.extend_from_slice(&self.chunk_size.to_le_bytes());
+        out.extend_from_slice(&self.chunk_count.to_le_bytes());
+        out.extend_from_slice(&self.bits);
+        out
+    }
+
+    /// Parse [`encode`](Self::encode)'s output. `None` if it's malformed.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+            return None;
+        }
+        let transfer_id: [u8; 16] = bytes[4..20].try_into().ok()?;
+        let file_size = u64::from_le_bytes(bytes[20..28].try_into().ok()?);
+        let chunk_size = u64::from_le_bytes(bytes[28..36].try_into().ok()?);
+        let chunk_count = u32::from_le_bytes(bytes[36..40].try_into().ok()?);
+        let bits = &bytes[HEADER_LEN..];
+        if bits.len() != (chunk_count as usize).div_ceil(8) {
+            return None;
+        }
+        let mut progress = Self::new(transfer_id, file_size, chunk_size, chunk_count);
+        for chunk_index in 0..chunk_count {
+            if bits[chunk_index as usize / 8] & (1 << (chunk_index % 8)) != 0 {
+                progress.set(chunk_index);
+            }
+        }
+        Some(progress)
+    }
+
+    /// Read a sidecar. `Ok(None)` if there is none or it's malformed.
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(Self::decode(&bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write the sidecar, replacing any previous one whole: a crash mid-write
+    /// leaves the old record, never a torn one.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.encode())?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use crate::aead::AeadAlgorithm;
+    use crate::error::TransferError;
+    use crate::harness::{self, Loopback, TRANSFER_ID};
+    use crate::protocol::{MIN_CHUNK_SIZE, encrypted_chunk_size};
+    use crate::receiver::ReceiverConfig;
+    use crate::sender::{SenderConfig, SenderProgress};
+
+    #[test]
+    fn test_round_trip_and_layout_check() {
+        let mut progress = ChunkProgress::new([3u8; 16], 1000, 100, 10);
+        assert!(progress.set(0));
+        assert!(progress.set(9));
+        assert!(!progress.set(9));
+        assert!(!progress.set(10));
+        assert_eq!(progress.done(), 2);
+        assert_eq!(progress.missing(), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let decoded = ChunkProgress::decode(&progress.encode()).unwrap();
+        assert_eq!(decoded, progress);
+        assert!(decoded.fits(&[3u8; 16], 1000, 100, 10));
+        assert!(!decoded.fits(&[3u8; 16], 1000, 200, 10));
+
+        let encoded = progress.encode();
+        assert_eq!(ChunkProgress::decode(&encoded[..encoded.len() - 1]), None);
+        assert_eq!(ChunkProgress::decode(b"nope"), None);
+    }
+
+    /// Run a receiver with `resume` on while the sender blasts all but
+    /// `skip_chunks`. With `cancel_after_send`, the receiver is cancelled
+    /// once the sender has been ACKed for everything it sent.
+    fn blast(
+        input: &Path,
+        output: &Path,
+        encrypted_file: &[u8],
+        skip_chunks: Vec<u32>,
+        cancel_after_send: bool,
+    ) -> Result<SocketAddr, TransferError> {
+        let receiver = Loopback::start(ReceiverConfig {
+            stall_timeout: Duration::from_secs(5),
+            resume: true,
+            ..harness::receiver_config(output, encrypted_file, encrypted_chunk_size(MIN_CHUNK_SIZE))
+        });
+        receiver
+            .send(
+                SenderConfig {
+                    stall_timeout: Duration::from_secs(5),
+                    skip_chunks,
+                    ..harness::sender_config(input, receiver.addr, MIN_CHUNK_SIZE)
+                },
+                Arc::new(SenderProgress::new()),
+            )
+            .unwrap();
+        if cancel_after_send {
+            receiver.progress.cancelled.store(1, Ordering::Relaxed);
+        }
+        receiver.join()
+    }
+
+    #[test]
+    fn test_interrupted_transfer_resumes_missing_chunks() {
+        let dir = harness::scratch_dir("resume");
+        let input = dir.join("in.bin");
+        let output = dir.join("out.bin");
+
+        let plaintext: Vec<u8> = (0..6 * MIN_CHUNK_SIZE as u32 - 100).map(|i| (i % 239) as u8).collect();
+        std::fs::write(&input, &plaintext).unwrap();
+        let encrypted_file = harness::seal(&plaintext, MIN_CHUNK_SIZE, AeadAlgorithm::Aes256Gcm);
+        let file_size = encrypted_file.len() as u64;
+
+        // The connection drops after chunks 0, 2 and 3 made it.
+        let err = blast(&input, &output, &encrypted_file, vec![1, 4, 5], true).unwrap_err();
+        assert_eq!(err, TransferError::Cancelled);
+        let recorded = ChunkProgress::load(&state_path(&output)).unwrap().unwrap();
+        assert!(recorded.fits(&TRANSFER_ID, file_size, encrypted_chunk_size(MIN_CHUNK_SIZE) as u64, 6));
+        assert_eq!(recorded.missing(), vec![1, 4, 5]);
+
+        // The retry sends only those; the rest must already be on disk.
+        blast(&input, &output, &encrypted_file, recorded.completed(), false).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), encrypted_file);
+        assert!(!state_path(&output).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
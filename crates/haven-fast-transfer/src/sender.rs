@@ -2,25 +2,35 @@
 ///
 /// ```text
 /// [Reader] ---> [Encryptor] ---> [Blaster]
-/// Read 4MB       AES-256-GCM     Slice into 1400B frames
+/// Read 4MB       AEAD encrypt    Slice into 1400B frames
 /// from disk      encrypt+SHA256  Blast via UDP to server
 ///                Reuse cipher!   Cache encrypted chunks for retransmit
 /// ```
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
 use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead};
-use crossbeam_channel::{bounded, Receiver};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use haven_crypto::nonce_audit::{self, NonceAudit};
 use sha2::{Digest, Sha256};
 
+use crate::aead::{AeadAlgorithm, ChunkCipher};
+use crate::compress::seal_compressed_chunk;
+use crate::congestion::{self, CongestionConfig};
+use crate::error::TransferError;
+use crate::fec::{FecCodec, FecRatio};
 use crate::logging::{TransferEvent, TransferLog, TransferLogger};
+use crate::mtu;
+use crate::pacing::{Pacer, Pacing};
+use crate::pool::{PooledSocket, SocketPool, SocketSpec};
 use crate::protocol::*;
+use crate::sparse;
 
 /// Transfer state constants.
 pub const STATE_IDLE: u8 = 0;
@@ -36,10 +46,31 @@ pub struct SenderProgress {
     pub bytes_total: AtomicU64,
     pub state: AtomicU8,
     pub cancelled: AtomicU8,
+    /// Non-zero holds the blaster between chunks; no frames go out until cleared.
+    pub paused: AtomicU8,
     pub chunks_complete: AtomicU64,
     pub chunks_total: AtomicU64,
     pub retransmits: AtomicU64,
+    /// Retransmit rounds per chunk index, for chunks retransmitted at least
+    /// once. See [`SenderProgress::worst_chunks`].
+    pub chunk_retransmits: std::sync::Mutex<HashMap<u32, u32>>,
+    /// Most retransmit rounds any single chunk has needed so far.
+    pub max_chunk_retransmits: AtomicU64,
+    /// Un-ACKed chunks held for retransmit, at most the configured cache size.
+    pub cache_chunks: AtomicU64,
+    /// Times the sender stopped reading ahead because the cache was full of
+    /// un-ACKed chunks.
+    pub cache_full_waits: AtomicU64,
+    /// Slowdown NACKs from a receiver whose assembler fell behind, each of
+    /// which halved the rate (see `NackMessage::slowdown`).
+    pub slowdowns: AtomicU64,
     pub rate_bps: AtomicU64,
+    /// Ceiling on the pacing rate in bytes/s, 0 for none. Starts at the
+    /// config's `max_rate_bps`; see [`SenderProgress::set_rate_cap`].
+    pub rate_cap_bps: AtomicU64,
+    /// Payload bytes per frame: `FRAME_PAYLOAD`, or larger once an MTU probe
+    /// succeeds.
+    pub frame_payload: AtomicU64,
     pub last_error: std::sync::Mutex<Option<String>>,
     /// Set after encryption pass: JSON `{"file_sha256":"...","chunk_hashes":[...]}`.
     pub hashes_json: std::sync::Mutex<Option<String>>,
@@ -52,10 +83,18 @@ impl SenderProgress {
             bytes_total: AtomicU64::new(0),
             state: AtomicU8::new(STATE_IDLE),
             cancelled: AtomicU8::new(0),
+            paused: AtomicU8::new(0),
             chunks_complete: AtomicU64::new(0),
             chunks_total: AtomicU64::new(0),
             retransmits: AtomicU64::new(0),
-            rate_bps: AtomicU64::new(INITIAL_RATE_BPS),
+            chunk_retransmits: std::sync::Mutex::new(HashMap::new()),
+            max_chunk_retransmits: AtomicU64::new(0),
+            cache_chunks: AtomicU64::new(0),
+            cache_full_waits: AtomicU64::new(0),
+            slowdowns: AtomicU64::new(0),
+            rate_bps: AtomicU64::new(SLOW_START_RATE_BPS),
+            rate_cap_bps: AtomicU64::new(0),
+            frame_payload: AtomicU64::new(FRAME_PAYLOAD as u64),
             last_error: std::sync::Mutex::new(None),
             hashes_json: std::sync::Mutex::new(None),
         }
@@ -64,15 +103,384 @@ impl SenderProgress {
     pub fn is_cancelled(&self) -> bool {
         self.cancelled.load(Ordering::Relaxed) != 0
     }
+
+    /// Cap the send rate at `bps` bytes/s (0 lifts the cap). Safe to call
+    /// mid-transfer; it applies from the next chunk blasted.
+    pub fn set_rate_cap(&self, bps: u64) {
+        self.rate_cap_bps.store(bps, Ordering::Relaxed);
+    }
+
+    /// The `n` chunks retransmitted most often, as `(chunk_index, rounds)`,
+    /// worst first.
+    pub fn worst_chunks(&self, n: usize) -> Vec<(u32, u32)> {
+        let mut chunks: Vec<(u32, u32)> =
+            self.chunk_retransmits.lock().unwrap().iter().map(|(&idx, &rounds)| (idx, rounds)).collect();
+        chunks.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        chunks.truncate(n);
+        chunks
+    }
+
+    /// Count a retransmit round of `frames` frames for `chunk_index`, before
+    /// it is sent. Fails instead (setting `STATE_ERROR`) if the chunk has
+    /// already had `ceiling` rounds; a ceiling of 0 never fails.
+    fn record_retransmit(&self, chunk_index: u32, frames: u64, ceiling: u32) -> Result<(), TransferError> {
+        let rounds = {
+            let mut counts = self.chunk_retransmits.lock().unwrap();
+            let rounds = counts.entry(chunk_index).or_insert(0);
+            if ceiling > 0 && *rounds >= ceiling {
+                self.state.store(STATE_ERROR, Ordering::Relaxed);
+                return Err(TransferError::Network(format!(
+                    "Chunk {} still missing after {} retransmits; the path keeps dropping it",
+                    chunk_index, rounds
+                )));
+            }
+            *rounds += 1;
+            *rounds
+        };
+        self.retransmits.fetch_add(frames, Ordering::Relaxed);
+        self.max_chunk_retransmits.fetch_max(rounds as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The congestion controller's `rate_bps`, clamped to the cap.
+    fn paced_rate(&self, rate_bps: u64) -> u64 {
+        match self.rate_cap_bps.load(Ordering::Relaxed) {
+            0 => rate_bps,
+            cap => rate_bps.min(cap),
+        }
+    }
+
+    /// Block the calling pipeline thread while paused (returns early on
+    /// cancel), calling `keepalive` every `PAUSE_KEEPALIVE_INTERVAL_MS` so the
+    /// receiver's stall clock keeps running from the last one instead.
+    fn wait_while_paused(&self, mut keepalive: impl FnMut()) {
+        let mut last_keepalive = Instant::now();
+        while self.paused.load(Ordering::Relaxed) != 0 && !self.is_cancelled() {
+            if last_keepalive.elapsed() >= Duration::from_millis(PAUSE_KEEPALIVE_INTERVAL_MS) {
+                keepalive();
+                last_keepalive = Instant::now();
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+    }
+}
+
+/// Chunks kept for retransmit until the receiver ACKs them. Holding only
+/// un-ACKed chunks, capped at `capacity`: once full the sender waits in
+/// [`RetransmitCache::wait_for_room`] rather than reading further ahead, so
+/// a slow or silent receiver bounds memory instead of growing it.
+struct RetransmitCache<T> {
+    chunks: HashMap<u32, T>,
+    /// When each cached chunk was first blasted, for RTT samples.
+    sent_at: HashMap<u32, Instant>,
+    /// Shortest blast-to-ACK time since the last `take_rtt`.
+    rtt: Option<Duration>,
+    acked: HashSet<u32>,
+    capacity: usize,
+    chunk_count: u32,
+}
+
+impl<T: AsRef<[u8]>> RetransmitCache<T> {
+    fn new(capacity: usize, chunk_count: u32) -> Self {
+        Self {
+            chunks: HashMap::new(),
+            sent_at: HashMap::new(),
+            rtt: None,
+            acked: HashSet::new(),
+            capacity: capacity.max(1),
+            chunk_count,
+        }
+    }
+
+    fn get(&self, chunk_index: u32) -> Option<&T> {
+        self.chunks.get(&chunk_index)
+    }
+
+    fn insert(&mut self, chunk_index: u32, data: T, progress: &SenderProgress) {
+        if !self.acked.contains(&chunk_index) {
+            self.chunks.insert(chunk_index, data);
+            self.sent_at.insert(chunk_index, Instant::now());
+        }
+        progress.cache_chunks.store(self.chunks.len() as u64, Ordering::Relaxed);
+    }
+
+    fn acked_count(&self) -> usize {
+        self.acked.len()
+    }
+
+    /// Record an ACK and drop the chunks it covers. Returns how many chunks
+    /// are newly ACKed.
+    fn ack(&mut self, ack: &ChunkAckMessage, progress: &SenderProgress) -> u64 {
+        let newly = ack.apply_to(&mut self.acked, self.chunk_count);
+        if newly > 0 {
+            let acked = &self.acked;
+            self.chunks.retain(|idx, _| !acked.contains(idx));
+            let now = Instant::now();
+            let mut rtt = self.rtt;
+            self.sent_at.retain(|idx, sent| {
+                if !acked.contains(idx) {
+                    return true;
+                }
+                let sample = now.duration_since(*sent);
+                rtt = Some(rtt.map_or(sample, |r| r.min(sample)));
+                false
+            });
+            self.rtt = rtt;
+            progress.cache_chunks.store(self.chunks.len() as u64, Ordering::Relaxed);
+        }
+        newly
+    }
+
+    /// The shortest round trip ACKed since the last call, if any.
+    fn take_rtt(&mut self) -> Option<Duration> {
+        self.rtt.take()
+    }
+
+    /// Block while the cache is full, serving NACKs through `retransmit` and
+    /// taking ACKs until a chunk is ACKed. Fails on cancel, or once the
+    /// receiver has sent nothing for `stall_timeout`.
+    fn wait_for_room(
+        &mut self,
+        progress: &SenderProgress,
+        nack_rx: &Receiver<NackMessage>,
+        ack_rx: &Receiver<ChunkAckMessage>,
+        stall_timeout: Duration,
+        mut retransmit: impl FnMut(&NackMessage, &[u8]) -> Result<(), TransferError>,
+    ) -> Result<(), TransferError> {
+        if self.chunks.len() < self.capacity {
+            return Ok(());
+        }
+        progress.cache_full_waits.fetch_add(1, Ordering::Relaxed);
+        let mut last_progress = Instant::now();
+        while self.chunks.len() >= self.capacity {
+            if progress.is_cancelled() {
+                return Err(TransferError::Cancelled);
+            }
+            if last_progress.elapsed() >= stall_timeout {
+                progress.state.store(STATE_ERROR, Ordering::Relaxed);
+                return Err(TransferError::Stalled(format!(
+                    "{} chunks un-ACKed and no NACK or ACK for {:?}",
+                    self.chunks.len(),
+                    stall_timeout
+                )));
+            }
+            if let Ok(nack) = nack_rx.recv_timeout(Duration::from_millis(100)) {
+                last_progress = Instant::now();
+                if let Some(data) = self.chunks.get(&nack.chunk_index) {
+                    retransmit(&nack, data.as_ref())?;
+                }
+            }
+            while let Ok(ack) = ack_rx.try_recv() {
+                let newly = self.ack(&ack, progress);
+                if newly > 0 {
+                    last_progress = Instant::now();
+                }
+                progress.chunks_complete.fetch_add(newly, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Message from encryptor to blaster.
 struct EncryptedChunk {
     chunk_index: u32,
+    /// Bytes to blast: the whole encrypted chunk, or a compressed slot's
+    /// unpadded prefix.
     data: Vec<u8>,
+    /// Bytes the chunk occupies on the receiver's disk.
+    slot_len: usize,
     #[allow(dead_code)]
     sha256: String,
-    frame_count: u16,
+}
+
+/// Most encryption workers `encrypt_workers: 0` will pick.
+pub const MAX_ENCRYPT_WORKERS: usize = 8;
+
+/// Resolve `SenderConfig::encrypt_workers`.
+fn encrypt_worker_count(configured: usize) -> usize {
+    match configured {
+        0 => std::thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(MAX_ENCRYPT_WORKERS),
+        n => n,
+    }
+}
+
+/// Credits bounding the chunks between the reader and the blaster channel:
+/// enough to keep every worker busy while the sequencer waits on the
+/// slowest one. Filled up front; the sequencer returns one per chunk it
+/// passes on.
+fn encrypt_window(workers: usize) -> (Sender<()>, Receiver<()>) {
+    let window = workers * 2 + 2;
+    let (credit_tx, credit_rx) = bounded(window);
+    for _ in 0..window {
+        let _ = credit_tx.send(());
+    }
+    (credit_tx, credit_rx)
+}
+
+/// Encrypts single chunks; shared by the encryption workers.
+struct ChunkSealer {
+    cipher: ChunkCipher,
+    key: [u8; 32],
+    chunk_size: usize,
+    compress: bool,
+    sparse: bool,
+}
+
+impl ChunkSealer {
+    fn new(key: [u8; 32], chunk_size: usize, compress: bool, aead: AeadAlgorithm, sparse: bool) -> Self {
+        Self { cipher: ChunkCipher::new(aead, &key), key, chunk_size, compress, sparse: sparse && !compress }
+    }
+
+    /// Encrypt chunk `idx`. Returns the slot and how many of its leading
+    /// bytes go on the wire; compressed slots are hashed whole but only
+    /// their prefix is sent, and sparse ones (see `sparse`) not at all.
+    fn seal(&self, idx: u32, plaintext: &[u8]) -> Result<(Vec<u8>, usize), TransferError> {
+        if self.sparse && sparse::is_zero(plaintext) {
+            return Ok((sparse::zero_slot(plaintext.len()), 0));
+        }
+        if nonce_audit::enabled() {
+            NonceAudit::global()
+                .record(&self.key, idx as u64, plaintext)
+                .map_err(|e| TransferError::Crypto(e.to_string()))?;
+        }
+        if self.compress {
+            let sealed = seal_compressed_chunk(&self.cipher, &self.key, idx, self.chunk_size, plaintext)
+                .map_err(TransferError::Crypto)?;
+            return Ok((sealed.slot, sealed.wire_len));
+        }
+
+        // Output = nonce(12) + ciphertext+tag
+        let encrypted = self
+            .cipher
+            .seal_chunk(&self.key, idx, self.chunk_size, plaintext)
+            .map_err(TransferError::Crypto)?;
+        let len = encrypted.len();
+        Ok((encrypted, len))
+    }
+}
+
+/// A chunk a worker has sealed, waiting in the reorder buffer for its turn.
+struct SealedChunk {
+    idx: u32,
+    slot: Vec<u8>,
+    wire_len: usize,
+    sha256: String,
+    duration_ms: u64,
+}
+
+/// The sender's encryption stage: `workers` threads seal chunks in
+/// parallel, and the calling thread passes them on in chunk order.
+struct EncryptStage {
+    sealer: ChunkSealer,
+    workers: usize,
+    transfer_id: [u8; 16],
+    logger: Option<Arc<dyn TransferLogger>>,
+}
+
+impl EncryptStage {
+    /// Encrypt everything from `read_rx`, handing chunks to `enc_tx` in
+    /// index order and returning a credit for each. The full-file hash is
+    /// fed in that same order, so `(file_sha256, chunk_hashes,
+    /// encrypted_size)` does not depend on the worker count.
+    fn run(
+        &self,
+        read_rx: Receiver<(u32, Vec<u8>)>,
+        enc_tx: Sender<EncryptedChunk>,
+        credit_tx: Sender<()>,
+        progress: &SenderProgress,
+    ) -> Result<(String, Vec<String>, u64), TransferError> {
+        let (sealed_tx, sealed_rx) = bounded::<Result<SealedChunk, TransferError>>(self.workers);
+
+        // `move`, so an early return drops the channels: the reader then
+        // stops waiting for credits and the workers for chunks, and the
+        // scope can join them.
+        std::thread::scope(move |scope| {
+            let span = tracing::Span::current();
+            for _ in 0..self.workers {
+                let read_rx = read_rx.clone();
+                let sealed_tx = sealed_tx.clone();
+                let span = span.clone();
+                scope.spawn(move || {
+                    let _span = span.entered();
+                    for (idx, plaintext) in read_rx {
+                        if progress.is_cancelled() {
+                            return;
+                        }
+                        let start = Instant::now();
+                        let sealed = self.sealer.seal(idx, &plaintext).map(|(slot, wire_len)| SealedChunk {
+                            idx,
+                            sha256: hex::encode(Sha256::digest(&slot)),
+                            slot,
+                            wire_len,
+                            duration_ms: start.elapsed().as_millis() as u64,
+                        });
+                        let failed = sealed.is_err();
+                        if sealed_tx.send(sealed).is_err() || failed {
+                            return;
+                        }
+                    }
+                });
+            }
+            // Workers hold the only senders, so the loop below ends with them.
+            drop(sealed_tx);
+
+            let mut full_hasher = Sha256::new();
+            let mut chunk_hashes = Vec::new();
+            let mut encrypted_size: u64 = 0;
+            let mut reorder: BTreeMap<u32, SealedChunk> = BTreeMap::new();
+            let mut next_idx = 0u32;
+
+            for sealed in sealed_rx {
+                if progress.is_cancelled() {
+                    return Err(TransferError::Cancelled);
+                }
+                let sealed = sealed?;
+                reorder.insert(sealed.idx, sealed);
+
+                while let Some(mut chunk) = reorder.remove(&next_idx) {
+                    full_hasher.update(&chunk.slot);
+                    encrypted_size += chunk.slot.len() as u64;
+
+                    if let Some(ref logger) = self.logger {
+                        logger.log(TransferLog {
+                            component: "sender",
+                            transfer_id: self.transfer_id,
+                            event: TransferEvent::ChunkEncrypted {
+                                chunk_idx: chunk.idx,
+                                size: chunk.wire_len,
+                                duration_ms: chunk.duration_ms,
+                            },
+                        });
+                    }
+
+                    chunk_hashes.push(chunk.sha256.clone());
+                    let slot_len = chunk.slot.len();
+                    chunk.slot.truncate(chunk.wire_len);
+
+                    if enc_tx
+                        .send(EncryptedChunk {
+                            chunk_index: chunk.idx,
+                            data: chunk.slot,
+                            slot_len,
+                            sha256: chunk.sha256,
+                        })
+                        .is_err()
+                    {
+                        return Err(TransferError::Protocol("Encryptor channel closed".into()));
+                    }
+                    // The reader may be gone already; nothing left to pace.
+                    let _ = credit_tx.send(());
+                    next_idx += 1;
+                }
+            }
+
+            let file_sha256 = hex::encode(full_hasher.finalize());
+            Ok((file_sha256, chunk_hashes, encrypted_size))
+        })
+    }
 }
 
 /// NACK message received from remote (fed via control channel).
@@ -82,10 +490,44 @@ pub struct NackMessage {
     pub missing_frames: Vec<u16>,
 }
 
-/// Chunk ACK from remote.
+impl NackMessage {
+    /// Not a NACK for any chunk but a request to send slower: the
+    /// receiver's assembler is falling behind the frames arriving. It
+    /// travels as a NACK of `SLOWDOWN_CHUNK_INDEX` with no frames, so it
+    /// needs nothing new on the control channel.
+    pub fn slowdown() -> Self {
+        Self { chunk_index: SLOWDOWN_CHUNK_INDEX, missing_frames: Vec::new() }
+    }
+
+    pub fn is_slowdown(&self) -> bool {
+        self.chunk_index == SLOWDOWN_CHUNK_INDEX
+    }
+}
+
+/// Chunk ACK from remote: one chunk, or a run of them packed as a bitmap
+/// (see `encode_ack_bitmap`).
 #[derive(Debug, Clone)]
-pub struct ChunkAckMessage {
-    pub chunk_index: u32,
+pub enum ChunkAckMessage {
+    Chunk { chunk_index: u32 },
+    Bitmap { base_chunk: u32, bitmap: Vec<u8> },
+}
+
+impl ChunkAckMessage {
+    /// Add the acked chunks below `chunk_count` to `acked`, returning how
+    /// many weren't already there.
+    pub fn apply_to(&self, acked: &mut std::collections::HashSet<u32>, chunk_count: u32) -> u64 {
+        let mut newly = 0;
+        let mut add = |idx: u32| {
+            if idx < chunk_count && acked.insert(idx) {
+                newly += 1;
+            }
+        };
+        match self {
+            Self::Chunk { chunk_index } => add(*chunk_index),
+            Self::Bitmap { base_chunk, bitmap } => decode_ack_bitmap(*base_chunk, bitmap).for_each(add),
+        }
+        newly
+    }
 }
 
 /// Configuration for the sender.
@@ -94,15 +536,90 @@ pub struct SenderConfig {
     pub target_addr: SocketAddr,
     pub transfer_id: [u8; 16],
     pub encryption_key: [u8; 32],
+    /// Plaintext bytes per chunk; `CHUNK_SIZE` unless the caller negotiated
+    /// another size. Must satisfy `chunk_size_in_range`.
+    pub chunk_size: usize,
+    /// Probe the path MTU before blasting and use larger frames if it allows
+    /// (see `mtu`). Falls back to `FRAME_PAYLOAD` against older receivers.
+    pub probe_mtu: bool,
+    /// zstd-compress each chunk before encryption (see `compress`). The
+    /// receiver must be configured to match.
+    pub compress: bool,
+    /// Algorithm sealing each chunk (see `aead`). The receiver learns it from
+    /// `FastUploadStart`'s algorithm byte.
+    pub aead: AeadAlgorithm,
+    /// Parity frames to send per group of data frames (see `fec`).
+    /// Receivers pick them up without configuration.
+    pub fec: FecRatio,
+    /// Threads encrypting chunks in parallel; 0 picks one per core, up to
+    /// `MAX_ENCRYPT_WORKERS`. Output is identical whatever the count.
+    pub encrypt_workers: usize,
+    /// Most bytes/s to send, whatever the congestion controller allows; 0
+    /// for no cap. Adjustable mid-transfer through `SenderProgress::set_rate_cap`.
+    pub max_rate_bps: u64,
+    /// Fail with "Transfer stalled" once the receiver has sent no NACK or
+    /// new ACK for this long after the last chunk went out
+    /// (`STALL_TIMEOUT_SECS` by default).
+    pub stall_timeout: Duration,
+    /// Most un-ACKed chunks kept for retransmit (`SENDER_CACHE_SIZE` by
+    /// default). With that many outstanding the sender stops reading ahead
+    /// until the receiver ACKs one, failing after `stall_timeout` of silence.
+    pub cache_size: usize,
+    /// Fail the transfer once any chunk has been retransmitted this many
+    /// times and is NACKed again (`MAX_CHUNK_RETRANSMITS` by default); 0 for
+    /// no limit.
+    pub max_chunk_retransmits: u32,
+    /// Chunks the receiver already holds, e.g. from an interrupted upload
+    /// (see `resume`). They're still read and encrypted, since the file hash
+    /// covers them, but never blasted, and count as ACKed from the start.
+    pub skip_chunks: Vec<u32>,
+    /// Store all-zero chunks as zeros and don't blast them (see `sparse`).
+    /// The receiver must be given the same chunks as `sparse_chunks`.
+    /// Ignored with `compress`.
+    pub sparse: bool,
+    /// How the blaster waits between frames (see `pacing`).
+    pub pacing: Pacing,
+    /// Which rate controller the blaster runs (see `congestion`).
+    pub congestion: CongestionConfig,
     pub logger: Option<Arc<dyn TransferLogger>>,
 }
 
+/// The tuning fields at their documented defaults. The transfer's own
+/// fields (path, target, id and key) are zeroed and must be filled in.
+impl Default for SenderConfig {
+    fn default() -> Self {
+        Self {
+            file_path: String::new(),
+            target_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            transfer_id: [0u8; 16],
+            encryption_key: [0u8; 32],
+            chunk_size: CHUNK_SIZE,
+            probe_mtu: false,
+            compress: false,
+            aead: AeadAlgorithm::default(),
+            fec: FecRatio::DISABLED,
+            encrypt_workers: 0,
+            max_rate_bps: 0,
+            stall_timeout: Duration::from_secs(STALL_TIMEOUT_SECS),
+            cache_size: SENDER_CACHE_SIZE,
+            max_chunk_retransmits: MAX_CHUNK_RETRANSMITS,
+            skip_chunks: Vec::new(),
+            sparse: false,
+            pacing: Pacing::default(),
+            congestion: CongestionConfig::default(),
+            logger: None,
+        }
+    }
+}
+
 /// Result of a completed send operation.
 pub struct SendResult {
     pub file_sha256: String,
     pub chunk_hashes: Vec<String>,
     pub encrypted_size: u64,
     pub chunk_count: u32,
+    /// Payload bytes per frame the transfer ran with.
+    pub frame_payload: usize,
 }
 
 /// Run the sender pipeline. Blocks until complete, error, or cancellation.
@@ -114,124 +631,86 @@ pub fn run_sender(
     progress: Arc<SenderProgress>,
     nack_rx: Receiver<NackMessage>,
     ack_rx: Receiver<ChunkAckMessage>,
-) -> Result<SendResult, String> {
+) -> Result<SendResult, TransferError> {
     let file_path = Path::new(&config.file_path);
     let file_size = std::fs::metadata(file_path)
-        .map_err(|e| format!("Cannot read file: {}", e))?
+        .map_err(|e| TransferError::Io(format!("Cannot read file: {}", e)))?
         .len();
 
-    let chunk_count = if file_size == 0 {
-        1u32
-    } else {
-        ((file_size as usize + CHUNK_SIZE - 1) / CHUNK_SIZE) as u32
-    };
+    let chunk_size = config.chunk_size;
+    if !chunk_size_in_range(chunk_size) {
+        return Err(TransferError::Protocol(format!(
+            "Chunk size {} out of range ({}..={} plaintext bytes)",
+            chunk_size, MIN_CHUNK_SIZE, MAX_CHUNK_SIZE
+        )));
+    }
+
+    let chunk_count = chunk_count(file_size, chunk_size as u64);
 
     progress.bytes_total.store(file_size, Ordering::Relaxed);
     progress.chunks_total.store(chunk_count as u64, Ordering::Relaxed);
+    if config.max_rate_bps > 0 {
+        progress.set_rate_cap(config.max_rate_bps);
+    }
     progress.state.store(STATE_ENCRYPTING, Ordering::Relaxed);
 
-    // Channels between pipeline stages (bounded for backpressure).
+    // Channels between pipeline stages (bounded for backpressure). The
+    // reader also needs a credit per chunk, which caps how far it and the
+    // encryption workers can run ahead of the chunk the blaster needs next.
     let (read_tx, read_rx) = bounded::<(u32, Vec<u8>)>(4);
     let (enc_tx, enc_rx) = bounded::<EncryptedChunk>(4);
+    let (credit_tx, credit_rx) = encrypt_window(encrypt_worker_count(config.encrypt_workers));
 
     let transfer_id = config.transfer_id;
     let key = config.encryption_key;
+    let compress = config.compress;
+    let aead = config.aead;
+
+    // Pipeline threads log inside the caller's span (e.g. a server session).
+    let span = tracing::Span::current();
 
     // ── Reader thread ──────────────────────────────────────────────────
     let progress_reader = progress.clone();
     let file_path_owned = config.file_path.clone();
-    let reader_handle = std::thread::spawn(move || -> Result<(), String> {
+    let span_reader = span.clone();
+    let reader_handle = std::thread::spawn(move || -> Result<(), TransferError> {
         use std::io::Read;
+        let _span = span_reader.entered();
         let mut file = std::fs::File::open(&file_path_owned)
-            .map_err(|e| format!("Cannot open file: {}", e))?;
+            .map_err(|e| TransferError::Io(format!("Cannot open file: {}", e)))?;
 
-        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut buf = vec![0u8; chunk_size];
         for idx in 0..chunk_count {
             if progress_reader.is_cancelled() {
-                return Err("Cancelled".into());
+                return Err(TransferError::Cancelled);
             }
-            let remaining = file_size - idx as u64 * CHUNK_SIZE as u64;
-            let to_read = (remaining as usize).min(CHUNK_SIZE);
+            let remaining = file_size - idx as u64 * chunk_size as u64;
+            let to_read = (remaining as usize).min(chunk_size);
 
             file.read_exact(&mut buf[..to_read])
-                .map_err(|e| format!("Read error at chunk {}: {}", idx, e))?;
+                .map_err(|e| TransferError::Io(format!("Read error at chunk {}: {}", idx, e)))?;
 
-            if read_tx.send((idx, buf[..to_read].to_vec())).is_err() {
-                return Err("Reader channel closed".into());
+            if credit_rx.recv().is_err() || read_tx.send((idx, buf[..to_read].to_vec())).is_err() {
+                return Err(TransferError::Protocol("Reader channel closed".into()));
             }
         }
         Ok(())
     });
 
     // ── Encryptor thread ───────────────────────────────────────────────
+    // Runs the encryption workers and puts their output back in chunk order.
+    let sealer = ChunkSealer::new(key, chunk_size, compress, aead, config.sparse);
+    let stage = EncryptStage {
+        sealer,
+        workers: encrypt_worker_count(config.encrypt_workers),
+        transfer_id,
+        logger: config.logger.clone(),
+    };
     let progress_enc = progress.clone();
-    let logger_enc = config.logger.clone();
-    let encryptor_handle = std::thread::spawn(move || -> Result<(String, Vec<String>, u64), String> {
-        let cipher = Aes256Gcm::new_from_slice(&key)
-            .map_err(|e| format!("Cipher init failed: {}", e))?;
-        let mut full_hasher = Sha256::new();
-        let mut chunk_hashes = Vec::with_capacity(chunk_count as usize);
-        let mut encrypted_size: u64 = 0;
-
-        for (idx, plaintext) in read_rx {
-            if progress_enc.is_cancelled() {
-                return Err("Cancelled".into());
-            }
-
-            let start = Instant::now();
-
-            // Derive deterministic nonce: SHA-256(key || chunk_index_le)[..12]
-            let nonce = derive_chunk_nonce(&key, idx);
-
-            // Encrypt: output = nonce(12) + ciphertext+tag
-            let ciphertext = cipher
-                .encrypt(Nonce::from_slice(&nonce), plaintext.as_ref())
-                .map_err(|e| format!("Encrypt chunk {}: {}", idx, e))?;
-
-            let mut encrypted = Vec::with_capacity(12 + ciphertext.len());
-            encrypted.extend_from_slice(&nonce);
-            encrypted.extend_from_slice(&ciphertext);
-
-            // Hash the encrypted chunk
-            let mut chunk_hasher = Sha256::new();
-            chunk_hasher.update(&encrypted);
-            let hash = hex::encode(chunk_hasher.finalize());
-
-            full_hasher.update(&encrypted);
-            encrypted_size += encrypted.len() as u64;
-
-            let frame_count = frames_for_chunk(encrypted.len());
-            let duration_ms = start.elapsed().as_millis() as u64;
-
-            if let Some(ref logger) = logger_enc {
-                logger.log(TransferLog {
-                    component: "sender",
-                    transfer_id,
-                    event: TransferEvent::ChunkEncrypted {
-                        chunk_idx: idx,
-                        size: encrypted.len(),
-                        duration_ms,
-                    },
-                });
-            }
-
-            chunk_hashes.push(hash.clone());
-
-            if enc_tx
-                .send(EncryptedChunk {
-                    chunk_index: idx,
-                    data: encrypted,
-                    sha256: hash,
-                    frame_count,
-                })
-                .is_err()
-            {
-                return Err("Encryptor channel closed".into());
-            }
-        }
-
-        let file_sha256 = hex::encode(full_hasher.finalize());
-        Ok((file_sha256, chunk_hashes, encrypted_size))
+    let span_encryptor = span.clone();
+    let encryptor_handle = std::thread::spawn(move || -> Result<(String, Vec<String>, u64), TransferError> {
+        let _span = span_encryptor.entered();
+        stage.run(read_rx, enc_tx, credit_tx, &progress_enc)
     });
 
     // ── Blaster thread ─────────────────────────────────────────────────
@@ -239,9 +718,22 @@ pub fn run_sender(
     let progress_blast = progress.clone();
     let logger_blast = config.logger.clone();
     let target_addr = config.target_addr;
-    let blaster_handle = std::thread::spawn(move || -> Result<(), String> {
-        let socket = create_udp_socket()
-            .map_err(|e| format!("UDP socket error: {}", e))?;
+    let probe_mtu = config.probe_mtu;
+    let fec_ratio = config.fec;
+    let pacing = config.pacing;
+    let congestion = config.congestion;
+    let stall_timeout = config.stall_timeout;
+    let cache_size = config.cache_size;
+    let retry_ceiling = config.max_chunk_retransmits;
+    let skip_chunks: HashSet<u32> = config.skip_chunks.into_iter().collect();
+    let span_blaster = span.clone();
+    let blaster_handle = std::thread::spawn(move || -> Result<(), TransferError> {
+        let _span = span_blaster.entered();
+        let socket = create_udp_socket(target_addr)
+            .map_err(|e| TransferError::Network(format!("UDP socket error: {}", e)))?;
+        let frame_payload = negotiate_frame_payload(
+            &socket, target_addr, &transfer_id, probe_mtu, &progress_blast, &logger_blast,
+        );
 
         if let Some(ref logger) = logger_blast {
             let local = socket.local_addr().map(|a| a.to_string()).unwrap_or_else(|_| "?".into());
@@ -252,20 +744,31 @@ pub fn run_sender(
             });
         }
 
-        // Cache of recently encrypted chunks for retransmit.
-        let mut cache: HashMap<u32, Vec<u8>> = HashMap::new();
-        let mut cache_order: Vec<u32> = Vec::new();
+        // Encrypted chunks awaiting ACK, for retransmit.
+        let mut cache = RetransmitCache::new(cache_size, chunk_count);
+        for &chunk_index in &skip_chunks {
+            let newly = cache.ack(&ChunkAckMessage::Chunk { chunk_index }, &progress_blast);
+            progress_blast.chunks_complete.fetch_add(newly, Ordering::Relaxed);
+        }
 
-        let mut send_buf = vec![0u8; FRAME_MAX];
-        let mut rate_bps = INITIAL_RATE_BPS;
+        let send = Outbound {
+            socket: &socket,
+            target: target_addr,
+            transfer_id: &transfer_id,
+            frame_payload,
+            fec: fec_ratio,
+            pacing,
+        };
+        let mut send_buf = vec![0u8; MAX_FRAME];
+        let mut cc = congestion.build(Instant::now());
+        progress_blast.rate_bps.store(cc.rate_bps(), Ordering::Relaxed);
         let mut total_retransmits: u64 = 0;
-
-        // Track which chunks are ACKed
-        let mut acked: std::collections::HashSet<u32> = std::collections::HashSet::new();
+        let mut fec = FecCodec::default();
 
         for chunk in enc_rx {
+            progress_blast.wait_while_paused(|| send.keepalive(&mut send_buf));
             if progress_blast.is_cancelled() {
-                return Err("Cancelled".into());
+                return Err(TransferError::Cancelled);
             }
 
             // First encrypted chunk arriving means we can switch to BLASTING state.
@@ -278,33 +781,37 @@ pub fn run_sender(
                     .store(STATE_BLASTING, Ordering::Relaxed);
             }
 
-            // Cache the encrypted chunk
-            cache.insert(chunk.chunk_index, chunk.data.clone());
-            cache_order.push(chunk.chunk_index);
-            // Evict old entries
-            while cache.len() > SENDER_CACHE_SIZE {
-                if let Some(old_idx) = cache_order.first().copied() {
-                    // Only evict if it's been ACKed
-                    if acked.contains(&old_idx) {
-                        cache.remove(&old_idx);
-                        cache_order.remove(0);
-                    } else {
-                        break; // Don't evict un-ACKed chunks
-                    }
-                }
+            // Sparse: the receiver zeroes it without being sent anything.
+            if chunk.data.is_empty() {
+                let newly = cache.ack(&ChunkAckMessage::Chunk { chunk_index: chunk.chunk_index }, &progress_blast);
+                progress_blast.chunks_complete.fetch_add(newly, Ordering::Relaxed);
+            }
+            // Already on the receiver's disk from an earlier attempt.
+            if chunk.data.is_empty() || skip_chunks.contains(&chunk.chunk_index) {
+                progress_blast
+                    .bytes_done
+                    .fetch_add(chunk.slot_len as u64, Ordering::Relaxed);
+                continue;
             }
 
+            // Not taking the next chunk holds back the encryptor and reader.
+            cache.wait_for_room(&progress_blast, &nack_rx, &ack_rx, stall_timeout, |nack, data| {
+                progress_blast.record_retransmit(nack.chunk_index, nack.missing_frames.len() as u64, retry_ceiling)?;
+                let fc = frames_for_chunk_with(data.len(), frame_payload);
+                send.retransmit(nack.chunk_index, data, fc, &nack.missing_frames, &mut send_buf)?;
+                total_retransmits += nack.missing_frames.len() as u64;
+                Ok(())
+            })?;
+            cache.insert(chunk.chunk_index, chunk.data.clone(), &progress_blast);
+
+            let old_rate = cc.rate_bps();
+            cc.on_tick(Instant::now());
+            publish_rate(&progress_blast, &logger_blast, "sender", transfer_id, old_rate, cc.rate_bps(), 0.0);
+
             // Blast all frames for this chunk
-            blast_chunk(
-                &socket,
-                target_addr,
-                &transfer_id,
-                chunk.chunk_index,
-                &chunk.data,
-                chunk.frame_count,
-                &mut send_buf,
-                rate_bps,
-            )?;
+            let frame_count = frames_for_chunk_with(chunk.data.len(), frame_payload);
+            let parity = fec.encode(fec_ratio, &chunk.data, frame_payload).map_err(TransferError::Protocol)?;
+            send.blast(chunk.chunk_index, &chunk.data, frame_count, &parity, &mut send_buf, progress_blast.paced_rate(cc.rate_bps()))?;
 
             if let Some(ref logger) = logger_blast {
                 logger.log(TransferLog {
@@ -312,110 +819,87 @@ pub fn run_sender(
                     transfer_id,
                     event: TransferEvent::FramesBlasted {
                         chunk_idx: chunk.chunk_index,
-                        frame_count: chunk.frame_count,
+                        frame_count,
                     },
                 });
             }
 
             progress_blast
                 .bytes_done
-                .fetch_add(chunk.data.len() as u64, Ordering::Relaxed);
+                .fetch_add(chunk.slot_len as u64, Ordering::Relaxed);
 
             // Process any pending NACKs (non-blocking)
             while let Ok(nack) = nack_rx.try_recv() {
-                if let Some(cached_data) = cache.get(&nack.chunk_index) {
-                    let fc = frames_for_chunk(cached_data.len());
-                    retransmit_frames(
-                        &socket,
-                        target_addr,
-                        &transfer_id,
-                        nack.chunk_index,
-                        cached_data,
-                        fc,
-                        &nack.missing_frames,
-                        &mut send_buf,
-                    )?;
+                if nack.is_slowdown() {
+                    progress_blast.slowdowns.fetch_add(1, Ordering::Relaxed);
+                    let old_rate = cc.rate_bps();
+                    cc.on_slowdown();
+                    publish_rate(&progress_blast, &logger_blast, "sender", transfer_id, old_rate, cc.rate_bps(), 0.0);
+                    continue;
+                }
+                if let Some(cached_data) = cache.get(nack.chunk_index) {
+                    progress_blast.record_retransmit(nack.chunk_index, nack.missing_frames.len() as u64, retry_ceiling)?;
+                    let fc = frames_for_chunk_with(cached_data.len(), frame_payload);
+                    send.retransmit(nack.chunk_index, cached_data, fc, &nack.missing_frames, &mut send_buf)?;
                     total_retransmits += nack.missing_frames.len() as u64;
-                    progress_blast
-                        .retransmits
-                        .fetch_add(nack.missing_frames.len() as u64, Ordering::Relaxed);
 
                     // Rate control: check loss
                     let loss_pct = nack.missing_frames.len() as f64 / fc as f64;
-                    let old_rate = rate_bps;
-                    if loss_pct > LOSS_THRESHOLD_HIGH {
-                        rate_bps = (rate_bps as f64 * RATE_DECREASE) as u64;
-                    }
-                    if old_rate != rate_bps {
-                        progress_blast.rate_bps.store(rate_bps, Ordering::Relaxed);
-                        if let Some(ref logger) = logger_blast {
-                            logger.log(TransferLog {
-                                component: "sender",
-                                transfer_id,
-                                event: TransferEvent::RateAdjusted {
-                                    old_rate_bps: old_rate,
-                                    new_rate_bps: rate_bps,
-                                    loss_pct,
-                                },
-                            });
-                        }
-                    }
+                    let old_rate = cc.rate_bps();
+                    cc.on_loss(loss_pct);
+                    publish_rate(&progress_blast, &logger_blast, "sender", transfer_id, old_rate, cc.rate_bps(), loss_pct);
                 }
             }
 
             // Process ACKs (non-blocking)
             while let Ok(ack) = ack_rx.try_recv() {
-                acked.insert(ack.chunk_index);
+                let newly = cache.ack(&ack, &progress_blast);
                 progress_blast
                     .chunks_complete
-                    .fetch_add(1, Ordering::Relaxed);
+                    .fetch_add(newly, Ordering::Relaxed);
 
-                // Rate increase on successful chunk with low loss
-                let old_rate = rate_bps;
-                rate_bps = (rate_bps as f64 * RATE_INCREASE).min(INITIAL_RATE_BPS as f64) as u64;
-                if old_rate != rate_bps {
-                    progress_blast.rate_bps.store(rate_bps, Ordering::Relaxed);
+                let old_rate = cc.rate_bps();
+                if let Some(rtt) = cache.take_rtt() {
+                    cc.on_rtt_sample(rtt);
                 }
+                cc.on_ack();
+                publish_rate(&progress_blast, &logger_blast, "sender", transfer_id, old_rate, cc.rate_bps(), 0.0);
             }
         }
 
         // All chunks blasted. Now wait for remaining NACKs and ACKs until all chunks ACKed.
         // This loop handles retransmits for the tail end of the transfer.
-        let deadline = Instant::now() + std::time::Duration::from_secs(60);
-        let chunk_count = cache_order.last().map(|&x| x + 1).unwrap_or(0);
+        let mut last_progress = Instant::now();
 
-        while acked.len() < chunk_count as usize && Instant::now() < deadline {
+        while cache.acked_count() < chunk_count as usize {
             if progress_blast.is_cancelled() {
-                return Err("Cancelled".into());
+                return Err(TransferError::Cancelled);
+            }
+            if last_progress.elapsed() >= stall_timeout {
+                progress_blast.state.store(STATE_ERROR, Ordering::Relaxed);
+                return Err(TransferError::Stalled(format!("no NACK or ACK for {:?}", stall_timeout)));
             }
 
             // Process NACKs with timeout
             if let Ok(nack) = nack_rx.recv_timeout(std::time::Duration::from_millis(100)) {
-                if let Some(cached_data) = cache.get(&nack.chunk_index) {
-                    let fc = frames_for_chunk(cached_data.len());
-                    retransmit_frames(
-                        &socket,
-                        target_addr,
-                        &transfer_id,
-                        nack.chunk_index,
-                        cached_data,
-                        fc,
-                        &nack.missing_frames,
-                        &mut send_buf,
-                    )?;
+                last_progress = Instant::now();
+                if let Some(cached_data) = cache.get(nack.chunk_index) {
+                    progress_blast.record_retransmit(nack.chunk_index, nack.missing_frames.len() as u64, retry_ceiling)?;
+                    let fc = frames_for_chunk_with(cached_data.len(), frame_payload);
+                    send.retransmit(nack.chunk_index, cached_data, fc, &nack.missing_frames, &mut send_buf)?;
                     total_retransmits += nack.missing_frames.len() as u64;
-                    progress_blast
-                        .retransmits
-                        .fetch_add(nack.missing_frames.len() as u64, Ordering::Relaxed);
                 }
             }
 
             // Process ACKs
             while let Ok(ack) = ack_rx.try_recv() {
-                acked.insert(ack.chunk_index);
+                let newly = cache.ack(&ack, &progress_blast);
+                if newly > 0 {
+                    last_progress = Instant::now();
+                }
                 progress_blast
                     .chunks_complete
-                    .fetch_add(1, Ordering::Relaxed);
+                    .fetch_add(newly, Ordering::Relaxed);
             }
         }
 
@@ -437,11 +921,11 @@ pub fn run_sender(
     // ── Wait for pipeline to complete ──────────────────────────────────
     reader_handle
         .join()
-        .map_err(|_| "Reader thread panicked".to_string())??;
+        .map_err(|_| TransferError::Protocol("Reader thread panicked".into()))??;
 
     let (file_sha256, chunk_hashes, encrypted_size) = encryptor_handle
         .join()
-        .map_err(|_| "Encryptor thread panicked".to_string())??;
+        .map_err(|_| TransferError::Protocol("Encryptor thread panicked".into()))??;
 
     // Store hashes so they can be read before blasting finishes
     {
@@ -455,7 +939,7 @@ pub fn run_sender(
 
     blaster_handle
         .join()
-        .map_err(|_| "Blaster thread panicked".to_string())??;
+        .map_err(|_| TransferError::Protocol("Blaster thread panicked".into()))??;
 
     progress.state.store(STATE_COMPLETE, Ordering::Relaxed);
 
@@ -464,148 +948,280 @@ pub fn run_sender(
         chunk_hashes,
         encrypted_size,
         chunk_count,
+        frame_payload: progress.frame_payload.load(Ordering::Relaxed) as usize,
     })
 }
 
-/// Derive deterministic chunk nonce: SHA-256(key || chunk_index_le)[..12].
-fn derive_chunk_nonce(key: &[u8; 32], chunk_index: u32) -> [u8; 12] {
+/// Derive the deterministic nonce for a chunk.
+///
+/// At the default `CHUNK_SIZE` this is SHA-256(key || chunk_index_le)[..12],
+/// unchanged from before chunk size was configurable. Other sizes also mix in
+/// chunk_size_le, so re-chunking the same file under the same key never reuses
+/// a nonce for different plaintext. Clients that pre-hash chunks must use this
+/// same function.
+pub fn chunk_nonce(key: &[u8; 32], chunk_index: u32, chunk_size: usize) -> [u8; 12] {
     let mut hasher = Sha256::new();
     hasher.update(key);
     hasher.update(&(chunk_index as u64).to_le_bytes());
+    if chunk_size != CHUNK_SIZE {
+        hasher.update((chunk_size as u64).to_le_bytes());
+    }
     let hash = hasher.finalize();
     let mut nonce = [0u8; 12];
     nonce.copy_from_slice(&hash[..12]);
     nonce
 }
 
-/// Blast all frames for one encrypted chunk over UDP.
-fn blast_chunk(
+/// Store a congestion controller's new rate in `progress` and log the change.
+fn publish_rate(
+    progress: &SenderProgress,
+    logger: &Option<Arc<dyn TransferLogger>>,
+    component: &'static str,
+    transfer_id: [u8; 16],
+    old_rate_bps: u64,
+    new_rate_bps: u64,
+    loss_pct: f64,
+) {
+    if old_rate_bps == new_rate_bps {
+        return;
+    }
+    progress.rate_bps.store(new_rate_bps, Ordering::Relaxed);
+    if let Some(logger) = logger {
+        logger.log(TransferLog {
+            component,
+            transfer_id,
+            event: TransferEvent::RateAdjusted { old_rate_bps, new_rate_bps, loss_pct },
+        });
+    }
+}
+
+/// Pick the frame payload for a transfer, probing the path MTU if asked, and
+/// record it in `progress`.
+fn negotiate_frame_payload(
     socket: &std::net::UdpSocket,
     target: SocketAddr,
     transfer_id: &[u8; 16],
-    chunk_index: u32,
-    encrypted_data: &[u8],
-    frame_count: u16,
-    send_buf: &mut [u8],
-    rate_bps: u64,
-) -> Result<(), String> {
-    // Calculate inter-frame delay for rate limiting.
-    // bytes_per_frame ~= 1424, so frames_per_sec = rate_bps / 1424
-    let nanos_per_frame = if rate_bps > 0 {
-        (FRAME_MAX as u128 * 1_000_000_000) / rate_bps as u128
-    } else {
-        0
-    };
+    probe_mtu: bool,
+    progress: &SenderProgress,
+    logger: &Option<Arc<dyn TransferLogger>>,
+) -> usize {
+    if !probe_mtu {
+        return FRAME_PAYLOAD;
+    }
+    let frame_payload = mtu::probe_frame_payload(socket, target, transfer_id);
+    progress.frame_payload.store(frame_payload as u64, Ordering::Relaxed);
+    if let Some(logger) = logger {
+        logger.log(TransferLog {
+            component: "sender",
+            transfer_id: *transfer_id,
+            event: TransferEvent::MtuProbed { frame_payload },
+        });
+    }
+    frame_payload
+}
 
-    let mut offset = 0usize;
-    for frame_idx in 0..frame_count {
-        let end = (offset + FRAME_PAYLOAD).min(encrypted_data.len());
-        let payload = &encrypted_data[offset..end];
+/// What every frame of one transfer is sent with: where to, under which
+/// id, cut to which payload size, with which FEC and pacing.
+struct Outbound<'a> {
+    socket: &'a std::net::UdpSocket,
+    target: SocketAddr,
+    transfer_id: &'a [u8; 16],
+    frame_payload: usize,
+    fec: FecRatio,
+    pacing: Pacing,
+}
 
-        let len = encode_frame(
-            send_buf,
-            transfer_id,
-            chunk_index,
-            frame_idx,
-            frame_count,
-            payload,
-        );
+impl Outbound<'_> {
+    /// Blast all frames for one encrypted chunk over UDP, each group of data
+    /// frames followed by its `parity` frames when FEC is on.
+    fn blast(
+        &self,
+        chunk_index: u32,
+        encrypted_data: &[u8],
+        frame_count: u16,
+        parity: &[Vec<u8>],
+        send_buf: &mut [u8],
+        rate_bps: u64,
+    ) -> Result<(), TransferError> {
+        let Outbound { socket, target, transfer_id, frame_payload, fec, pacing } = *self;
+        // Inter-frame delay for rate limiting; a frame is header + payload
+        // (1424 bytes by default).
+        let mut pacer = Pacer::new(pacing, congestion::packet_interval(rate_bps, FRAME_HEADER + frame_payload));
+
+        let mut send = |frame_index: u16, frame_count: u16, payload: &[u8]| -> Result<(), TransferError> {
+            let len = encode_frame(
+                send_buf,
+                transfer_id,
+                chunk_index,
+                frame_index,
+                frame_count,
+                payload,
+            );
+
+            // Retry on ENOBUFS / WSAENOBUFS (OS error 10055 on Windows)
+            // which means the send buffer is full — back off briefly and retry.
+            let mut retries = 0;
+            loop {
+                match socket.send_to(&send_buf[..len], target) {
+                    Ok(_) => break,
+                    Err(ref e) if retries < 50 && (
+                        e.kind() == io::ErrorKind::WouldBlock
+                        || e.raw_os_error() == Some(10055) // WSAENOBUFS
+                        || e.raw_os_error() == Some(105)   // ENOBUFS (Linux)
+                    ) => {
+                        retries += 1;
+                        std::thread::sleep(std::time::Duration::from_millis(1));
+                    }
+                    Err(e) => return Err(TransferError::Network(format!("UDP send error: {}", e))),
+                }
+            }
 
-        // Retry on ENOBUFS / WSAENOBUFS (OS error 10055 on Windows)
-        // which means the send buffer is full — back off briefly and retry.
-        let mut retries = 0;
-        loop {
-            match socket.send_to(&send_buf[..len], target) {
-                Ok(_) => break,
-                Err(ref e) if retries < 50 && (
-                    e.kind() == io::ErrorKind::WouldBlock
-                    || e.raw_os_error() == Some(10055) // WSAENOBUFS
-                    || e.raw_os_error() == Some(105)   // ENOBUFS (Linux)
-                ) => {
-                    retries += 1;
-                    std::thread::sleep(std::time::Duration::from_millis(1));
+            pacer.pace();
+            Ok(())
+        };
+
+        let mut offset = 0usize;
+        for frame_idx in 0..frame_count {
+            let end = (offset + frame_payload).min(encrypted_data.len());
+            send(frame_idx, frame_count, &encrypted_data[offset..end])?;
+            offset = end;
+
+            // Close each group with its parity, sequence numbers running on
+            // across groups.
+            if fec.is_enabled() {
+                let (n, k) = (fec.data_frames() as u16, fec.parity_frames() as u16);
+                if (frame_idx + 1).is_multiple_of(n) || frame_idx + 1 == frame_count {
+                    let group = frame_idx / n;
+                    for seq in group * k..(group + 1) * k {
+                        if let Some(shard) = parity.get(seq as usize) {
+                            send(PARITY_FRAME_FLAG | seq, fec.frame_count_field(), shard)?;
+                        }
+                    }
                 }
-                Err(e) => return Err(format!("UDP send error: {}", e)),
             }
         }
 
-        offset = end;
+        Ok(())
+    }
+
+    /// Retransmit specific frames from cached encrypted chunk data.
+    fn retransmit(
+        &self,
+        chunk_index: u32,
+        encrypted_data: &[u8],
+        frame_count: u16,
+        missing_frames: &[u16],
+        send_buf: &mut [u8],
+    ) -> Result<(), TransferError> {
+        let Outbound { socket, target, transfer_id, frame_payload, .. } = *self;
+        for &frame_idx in missing_frames {
+            if frame_idx >= frame_count {
+                continue;
+            }
+            let offset = frame_idx as usize * frame_payload;
+            let end = (offset + frame_payload).min(encrypted_data.len());
+            if offset >= encrypted_data.len() {
+                continue;
+            }
+            let payload = &encrypted_data[offset..end];
 
-        // Rate limiting via busy-spin for sub-microsecond precision
-        if nanos_per_frame > 0 {
-            let target_time = std::time::Instant::now()
-                + std::time::Duration::from_nanos(nanos_per_frame as u64);
-            while std::time::Instant::now() < target_time {
-                std::hint::spin_loop();
+            let len = encode_frame(
+                send_buf,
+                transfer_id,
+                chunk_index,
+                frame_idx,
+                frame_count,
+                payload,
+            );
+
+            let mut retries = 0;
+            loop {
+                match socket.send_to(&send_buf[..len], target) {
+                    Ok(_) => break,
+                    Err(ref e) if retries < 50 && (
+                        e.kind() == io::ErrorKind::WouldBlock
+                        || e.raw_os_error() == Some(10055)
+                        || e.raw_os_error() == Some(105)
+                    ) => {
+                        retries += 1;
+                        std::thread::sleep(std::time::Duration::from_millis(1));
+                    }
+                    Err(e) => return Err(TransferError::Network(format!("UDP retransmit error: {}", e))),
+                }
             }
         }
+        Ok(())
     }
 
-    Ok(())
+    /// Tell the receiver a paused sender is still there. Best effort: a lost
+    /// keepalive is covered by the next.
+    fn keepalive(&self, send_buf: &mut [u8]) {
+        let len = encode_frame(send_buf, self.transfer_id, KEEPALIVE_CHUNK_INDEX, 0, 0, &[]);
+        let _ = self.socket.send_to(&send_buf[..len], self.target);
+    }
 }
 
-/// Retransmit specific frames from cached encrypted chunk data.
-fn retransmit_frames(
-    socket: &std::net::UdpSocket,
-    target: SocketAddr,
-    transfer_id: &[u8; 16],
-    chunk_index: u32,
-    encrypted_data: &[u8],
-    frame_count: u16,
-    missing_frames: &[u16],
-    send_buf: &mut [u8],
-) -> Result<(), String> {
-    for &frame_idx in missing_frames {
-        if frame_idx >= frame_count {
-            continue;
-        }
-        let offset = frame_idx as usize * FRAME_PAYLOAD;
-        let end = (offset + FRAME_PAYLOAD).min(encrypted_data.len());
-        if offset >= encrypted_data.len() {
-            continue;
-        }
-        let payload = &encrypted_data[offset..end];
-
-        let len = encode_frame(
-            send_buf,
-            transfer_id,
-            chunk_index,
-            frame_idx,
-            frame_count,
-            payload,
-        );
+/// Create a UDP socket in `target`'s address family with appropriate buffer sizes.
+fn create_udp_socket(target: SocketAddr) -> io::Result<PooledSocket> {
+    SocketPool::global().checkout(SocketSpec::sender(target))
+}
 
-        let mut retries = 0;
-        loop {
-            match socket.send_to(&send_buf[..len], target) {
-                Ok(_) => break,
-                Err(ref e) if retries < 50 && (
-                    e.kind() == io::ErrorKind::WouldBlock
-                    || e.raw_os_error() == Some(10055)
-                    || e.raw_os_error() == Some(105)
-                ) => {
-                    retries += 1;
-                    std::thread::sleep(std::time::Duration::from_millis(1));
-                }
-                Err(e) => return Err(format!("UDP retransmit error: {}", e)),
+/// Where `run_raw_sender` gets chunk bytes: reads into owned buffers, or,
+/// with the `mmap` feature, windows into a map of the whole file so chunks
+/// and retransmits go out without a copy.
+enum ChunkSource {
+    Read(std::fs::File),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl ChunkSource {
+    fn open(path: &str, file_size: u64) -> Result<Self, TransferError> {
+        let file = std::fs::File::open(path).map_err(|e| TransferError::Io(format!("Cannot open file: {}", e)))?;
+        // Empty files can't be mapped on every platform, and have no chunks
+        // worth mapping anyway.
+        #[cfg(feature = "mmap")]
+        if file_size > 0 {
+            match Self::map(&file, file_size) {
+                Ok(source) => return Ok(source),
+                Err(e) => tracing::warn!("{}, reading instead", e),
             }
         }
+        #[cfg(not(feature = "mmap"))]
+        let _ = file_size;
+        Ok(Self::Read(file))
     }
-    Ok(())
-}
-
-/// Create a UDP socket with appropriate buffer sizes.
-fn create_udp_socket() -> io::Result<std::net::UdpSocket> {
-    use socket2::{Domain, Protocol, Socket, Type};
 
-    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-    socket.set_nonblocking(false)?;
-    // Bind to any available port
-    socket.bind(&"0.0.0.0:0".parse::<SocketAddr>().unwrap().into())?;
-    // Set send buffer size
-    socket.set_send_buffer_size(UDP_RECV_BUFFER)?;
+    #[cfg(feature = "mmap")]
+    fn map(file: &std::fs::File, file_size: u64) -> Result<Self, TransferError> {
+        // SAFETY: raw senders serve completed transfers, whose files are
+        // never written again. Truncating one mid-send would fault here.
+        let map = unsafe { memmap2::Mmap::map(file) }.map_err(|e| TransferError::Io(format!("Cannot map file: {}", e)))?;
+        if (map.len() as u64) < file_size {
+            return Err(TransferError::Io(format!("File is {} bytes, expected {}", map.len(), file_size)));
+        }
+        Ok(Self::Mapped(map))
+    }
 
-    Ok(socket.into())
+    /// Chunk `idx`: `len` bytes at `offset`.
+    fn chunk(&self, idx: u32, offset: u64, len: usize) -> Result<Cow<'_, [u8]>, TransferError> {
+        match self {
+            Self::Read(file) => {
+                use std::io::{Read, Seek, SeekFrom};
+                let mut buf = vec![0u8; len];
+                let mut file = file;
+                file.seek(SeekFrom::Start(offset))
+                    .and_then(|_| file.read_exact(&mut buf))
+                    .map_err(|e| TransferError::Io(format!("Read error at chunk {}: {}", idx, e)))?;
+                Ok(Cow::Owned(buf))
+            }
+            #[cfg(feature = "mmap")]
+            Self::Mapped(map) => {
+                let start = offset as usize;
+                Ok(Cow::Borrowed(&map[start..start + len]))
+            }
+        }
+    }
 }
 
 /// Configuration for the raw sender (blasts pre-encrypted data from file).
@@ -616,9 +1232,49 @@ pub struct RawSenderConfig {
     pub file_size: u64,
     pub chunk_size: u64,
     pub chunk_count: u32,
+    /// See `SenderConfig::probe_mtu`.
+    pub probe_mtu: bool,
+    /// See `SenderConfig::fec`.
+    pub fec: FecRatio,
+    /// See `SenderConfig::stall_timeout`.
+    pub stall_timeout: Duration,
+    /// See `SenderConfig::cache_size`.
+    pub cache_size: usize,
+    /// See `SenderConfig::max_rate_bps`.
+    pub max_rate_bps: u64,
+    /// See `SenderConfig::max_chunk_retransmits`.
+    pub max_chunk_retransmits: u32,
+    /// See `SenderConfig::pacing`.
+    pub pacing: Pacing,
+    /// See `SenderConfig::congestion`.
+    pub congestion: CongestionConfig,
     pub logger: Option<Arc<dyn TransferLogger>>,
 }
 
+/// As for [`SenderConfig`]: the file's path, target, id and layout must be
+/// filled in.
+impl Default for RawSenderConfig {
+    fn default() -> Self {
+        Self {
+            file_path: String::new(),
+            target_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            transfer_id: [0u8; 16],
+            file_size: 0,
+            chunk_size: ENCRYPTED_CHUNK_SIZE as u64,
+            chunk_count: 0,
+            probe_mtu: false,
+            fec: FecRatio::DISABLED,
+            stall_timeout: Duration::from_secs(STALL_TIMEOUT_SECS),
+            cache_size: SENDER_CACHE_SIZE,
+            max_rate_bps: 0,
+            max_chunk_retransmits: MAX_CHUNK_RETRANSMITS,
+            pacing: Pacing::default(),
+            congestion: CongestionConfig::default(),
+            logger: None,
+        }
+    }
+}
+
 /// Run the raw sender pipeline. Reads pre-encrypted data from file and blasts
 /// as UDP frames. Used by the file server to send downloads.
 ///
@@ -628,30 +1284,42 @@ pub fn run_raw_sender(
     progress: Arc<SenderProgress>,
     nack_rx: Receiver<NackMessage>,
     ack_rx: Receiver<ChunkAckMessage>,
-) -> Result<(), String> {
-    use std::io::Read;
-
+) -> Result<(), TransferError> {
     progress
         .bytes_total
         .store(config.file_size, Ordering::Relaxed);
     progress
         .chunks_total
         .store(config.chunk_count as u64, Ordering::Relaxed);
+    if config.max_rate_bps > 0 {
+        progress.set_rate_cap(config.max_rate_bps);
+    }
     progress.state.store(STATE_BLASTING, Ordering::Relaxed);
 
-    let socket = create_udp_socket().map_err(|e| format!("UDP socket error: {}", e))?;
+    let socket = create_udp_socket(config.target_addr).map_err(|e| TransferError::Network(format!("UDP socket error: {}", e)))?;
+    let frame_payload = negotiate_frame_payload(
+        &socket, config.target_addr, &config.transfer_id, config.probe_mtu, &progress, &config.logger,
+    );
 
-    let mut file = std::fs::File::open(&config.file_path)
-        .map_err(|e| format!("Cannot open file: {}", e))?;
+    let source = ChunkSource::open(&config.file_path, config.file_size)?;
 
-    let mut cache: HashMap<u32, Vec<u8>> = HashMap::new();
-    let mut cache_order: Vec<u32> = Vec::new();
-    let mut acked: std::collections::HashSet<u32> = std::collections::HashSet::new();
-    let mut send_buf = vec![0u8; FRAME_MAX];
-    let mut rate_bps = INITIAL_RATE_BPS;
+    // Mapped chunks are cached as slices of the map, read ones as buffers.
+    let mut cache: RetransmitCache<Cow<'_, [u8]>> = RetransmitCache::new(config.cache_size, config.chunk_count);
+    let mut send_buf = vec![0u8; MAX_FRAME];
     let transfer_id = config.transfer_id;
+    let send = Outbound {
+        socket: &socket,
+        target: config.target_addr,
+        transfer_id: &transfer_id,
+        frame_payload,
+        fec: config.fec,
+        pacing: config.pacing,
+    };
     let blast_start = Instant::now();
+    let mut cc = config.congestion.build(blast_start);
+    progress.rate_bps.store(cc.rate_bps(), Ordering::Relaxed);
     let mut total_retransmits: u64 = 0;
+    let mut fec = FecCodec::default();
 
     if let Some(ref logger) = config.logger {
         logger.log(TransferLog {
@@ -659,7 +1327,7 @@ pub fn run_raw_sender(
             transfer_id,
             event: TransferEvent::BlastStarted {
                 target: config.target_addr.to_string(),
-                rate_bps,
+                rate_bps: cc.rate_bps(),
                 chunk_count: config.chunk_count,
                 file_size: config.file_size,
             },
@@ -667,45 +1335,33 @@ pub fn run_raw_sender(
     }
 
     for idx in 0..config.chunk_count {
+        progress.wait_while_paused(|| send.keepalive(&mut send_buf));
         if progress.is_cancelled() {
-            return Err("Cancelled".into());
+            return Err(TransferError::Cancelled);
         }
 
         // Calculate this chunk's size
-        let remaining = config.file_size - idx as u64 * config.chunk_size;
-        let this_chunk_size = remaining.min(config.chunk_size) as usize;
-
-        let mut chunk_data = vec![0u8; this_chunk_size];
-        file.read_exact(&mut chunk_data)
-            .map_err(|e| format!("Read error at chunk {}: {}", idx, e))?;
-
-        let frame_count = frames_for_chunk(chunk_data.len());
-
-        // Cache for retransmit
-        cache.insert(idx, chunk_data.clone());
-        cache_order.push(idx);
-        while cache.len() > SENDER_CACHE_SIZE {
-            if let Some(&old_idx) = cache_order.first() {
-                if acked.contains(&old_idx) {
-                    cache.remove(&old_idx);
-                    cache_order.remove(0);
-                } else {
-                    break;
-                }
-            }
-        }
+        let offset = idx as u64 * config.chunk_size;
+        let this_chunk_size = (config.file_size - offset).min(config.chunk_size) as usize;
+
+        // Wait for room in the retransmit cache before reading further ahead
+        cache.wait_for_room(&progress, &nack_rx, &ack_rx, config.stall_timeout, |nack, data| {
+            progress.record_retransmit(nack.chunk_index, nack.missing_frames.len() as u64, config.max_chunk_retransmits)?;
+            let fc = frames_for_chunk_with(data.len(), frame_payload);
+            send.retransmit(nack.chunk_index, data, fc, &nack.missing_frames, &mut send_buf)?;
+            total_retransmits += nack.missing_frames.len() as u64;
+            Ok(())
+        })?;
+        let chunk_data = source.chunk(idx, offset, this_chunk_size)?;
+
+        let old_rate = cc.rate_bps();
+        cc.on_tick(Instant::now());
+        publish_rate(&progress, &config.logger, "raw_sender", transfer_id, old_rate, cc.rate_bps(), 0.0);
 
         // Blast
-        blast_chunk(
-            &socket,
-            config.target_addr,
-            &transfer_id,
-            idx,
-            &chunk_data,
-            frame_count,
-            &mut send_buf,
-            rate_bps,
-        )?;
+        let frame_count = frames_for_chunk_with(chunk_data.len(), frame_payload);
+        let parity = fec.encode(config.fec, &chunk_data, frame_payload).map_err(TransferError::Protocol)?;
+        send.blast(idx, &chunk_data, frame_count, &parity, &mut send_buf, progress.paced_rate(cc.rate_bps()))?;
 
         if let Some(ref logger) = config.logger {
             // Log per-chunk at debug, but progress every 50 chunks at info
@@ -724,7 +1380,7 @@ pub fn run_raw_sender(
                     event: TransferEvent::BlastProgress {
                         chunks_sent: idx + 1,
                         chunks_total: config.chunk_count,
-                        rate_bps,
+                        rate_bps: cc.rate_bps(),
                     },
                 });
             }
@@ -733,26 +1389,23 @@ pub fn run_raw_sender(
         progress
             .bytes_done
             .fetch_add(chunk_data.len() as u64, Ordering::Relaxed);
+        cache.insert(idx, chunk_data, &progress);
 
         // Process NACKs
         while let Ok(nack) = nack_rx.try_recv() {
-            if let Some(cached) = cache.get(&nack.chunk_index) {
-                let fc = frames_for_chunk(cached.len());
-                retransmit_frames(
-                    &socket,
-                    config.target_addr,
-                    &transfer_id,
-                    nack.chunk_index,
-                    cached,
-                    fc,
-                    &nack.missing_frames,
-                    &mut send_buf,
-                )?;
+            if nack.is_slowdown() {
+                progress.slowdowns.fetch_add(1, Ordering::Relaxed);
+                let old_rate = cc.rate_bps();
+                cc.on_slowdown();
+                publish_rate(&progress, &config.logger, "raw_sender", transfer_id, old_rate, cc.rate_bps(), 0.0);
+                continue;
+            }
+            if let Some(cached) = cache.get(nack.chunk_index) {
+                progress.record_retransmit(nack.chunk_index, nack.missing_frames.len() as u64, config.max_chunk_retransmits)?;
+                let fc = frames_for_chunk_with(cached.len(), frame_payload);
+                send.retransmit(nack.chunk_index, cached, fc, &nack.missing_frames, &mut send_buf)?;
                 let retransmit_count = nack.missing_frames.len() as u64;
                 total_retransmits += retransmit_count;
-                progress
-                    .retransmits
-                    .fetch_add(retransmit_count, Ordering::Relaxed);
 
                 if let Some(ref logger) = config.logger {
                     logger.log(TransferLog {
@@ -766,45 +1419,22 @@ pub fn run_raw_sender(
                 }
 
                 let loss_pct = nack.missing_frames.len() as f64 / fc as f64;
-                if loss_pct > LOSS_THRESHOLD_HIGH {
-                    let old_rate = rate_bps;
-                    rate_bps = (rate_bps as f64 * RATE_DECREASE) as u64;
-                    progress.rate_bps.store(rate_bps, Ordering::Relaxed);
-                    if let Some(ref logger) = config.logger {
-                        logger.log(TransferLog {
-                            component: "raw_sender",
-                            transfer_id,
-                            event: TransferEvent::RateAdjusted {
-                                old_rate_bps: old_rate,
-                                new_rate_bps: rate_bps,
-                                loss_pct,
-                            },
-                        });
-                    }
-                }
+                let old_rate = cc.rate_bps();
+                cc.on_loss(loss_pct);
+                publish_rate(&progress, &config.logger, "raw_sender", transfer_id, old_rate, cc.rate_bps(), loss_pct);
             }
         }
 
         // Process ACKs
         while let Ok(ack) = ack_rx.try_recv() {
-            acked.insert(ack.chunk_index);
-            progress.chunks_complete.fetch_add(1, Ordering::Relaxed);
-            let old_rate = rate_bps;
-            rate_bps = (rate_bps as f64 * RATE_INCREASE).min(INITIAL_RATE_BPS as f64) as u64;
-            progress.rate_bps.store(rate_bps, Ordering::Relaxed);
-            if rate_bps != old_rate {
-                if let Some(ref logger) = config.logger {
-                    logger.log(TransferLog {
-                        component: "raw_sender",
-                        transfer_id,
-                        event: TransferEvent::RateAdjusted {
-                            old_rate_bps: old_rate,
-                            new_rate_bps: rate_bps,
-                            loss_pct: 0.0,
-                        },
-                    });
-                }
+            let newly = cache.ack(&ack, &progress);
+            progress.chunks_complete.fetch_add(newly, Ordering::Relaxed);
+            let old_rate = cc.rate_bps();
+            if let Some(rtt) = cache.take_rtt() {
+                cc.on_rtt_sample(rtt);
             }
+            cc.on_ack();
+            publish_rate(&progress, &config.logger, "raw_sender", transfer_id, old_rate, cc.rate_bps(), 0.0);
         }
     }
 
@@ -816,34 +1446,28 @@ pub fn run_raw_sender(
             event: TransferEvent::BlastProgress {
                 chunks_sent: config.chunk_count,
                 chunks_total: config.chunk_count,
-                rate_bps,
+                rate_bps: cc.rate_bps(),
             },
         });
     }
 
-    let deadline = Instant::now() + std::time::Duration::from_secs(60);
-    while acked.len() < config.chunk_count as usize && Instant::now() < deadline {
+    let mut last_progress = Instant::now();
+    while cache.acked_count() < config.chunk_count as usize {
         if progress.is_cancelled() {
-            return Err("Cancelled".into());
+            return Err(TransferError::Cancelled);
+        }
+        if last_progress.elapsed() >= config.stall_timeout {
+            progress.state.store(STATE_ERROR, Ordering::Relaxed);
+            return Err(TransferError::Stalled(format!("no NACK or ACK for {:?}", config.stall_timeout)));
         }
         if let Ok(nack) = nack_rx.recv_timeout(std::time::Duration::from_millis(100)) {
-            if let Some(cached) = cache.get(&nack.chunk_index) {
-                let fc = frames_for_chunk(cached.len());
-                retransmit_frames(
-                    &socket,
-                    config.target_addr,
-                    &transfer_id,
-                    nack.chunk_index,
-                    cached,
-                    fc,
-                    &nack.missing_frames,
-                    &mut send_buf,
-                )?;
+            last_progress = Instant::now();
+            if let Some(cached) = cache.get(nack.chunk_index) {
+                progress.record_retransmit(nack.chunk_index, nack.missing_frames.len() as u64, config.max_chunk_retransmits)?;
+                let fc = frames_for_chunk_with(cached.len(), frame_payload);
+                send.retransmit(nack.chunk_index, cached, fc, &nack.missing_frames, &mut send_buf)?;
                 let retransmit_count = nack.missing_frames.len() as u64;
                 total_retransmits += retransmit_count;
-                progress
-                    .retransmits
-                    .fetch_add(retransmit_count, Ordering::Relaxed);
 
                 if let Some(ref logger) = config.logger {
                     logger.log(TransferLog {
@@ -858,8 +1482,11 @@ pub fn run_raw_sender(
             }
         }
         while let Ok(ack) = ack_rx.try_recv() {
-            acked.insert(ack.chunk_index);
-            progress.chunks_complete.fetch_add(1, Ordering::Relaxed);
+            let newly = cache.ack(&ack, &progress);
+            if newly > 0 {
+                last_progress = Instant::now();
+            }
+            progress.chunks_complete.fetch_add(newly, Ordering::Relaxed);
         }
     }
 
@@ -901,3 +1528,228 @@ fn serde_json_mini(hashes: &[String]) -> String {
     s.push(']');
     s
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run the encryption stage over `data` as the sender would, returning
+    /// its result and the order chunks reached the blaster channel.
+    fn encrypt_with(workers: usize, data: &[u8], chunk_size: usize) -> ((String, Vec<String>, u64), Vec<u32>) {
+        let stage = EncryptStage {
+            sealer: ChunkSealer::new([7u8; 32], chunk_size, false, AeadAlgorithm::Aes256Gcm, false),
+            workers,
+            transfer_id: [1u8; 16],
+            logger: None,
+        };
+        let (read_tx, read_rx) = bounded::<(u32, Vec<u8>)>(4);
+        let (enc_tx, enc_rx) = bounded::<EncryptedChunk>(4);
+        let (credit_tx, credit_rx) = encrypt_window(workers);
+        let chunks: Vec<Vec<u8>> = data.chunks(chunk_size).map(<[u8]>::to_vec).collect();
+
+        let reader = std::thread::spawn(move || {
+            for (idx, chunk) in chunks.into_iter().enumerate() {
+                credit_rx.recv().unwrap();
+                read_tx.send((idx as u32, chunk)).unwrap();
+            }
+        });
+        let blaster = std::thread::spawn(move || enc_rx.iter().map(|c| c.chunk_index).collect::<Vec<_>>());
+
+        let result = stage.run(read_rx, enc_tx, credit_tx, &SenderProgress::new()).unwrap();
+        reader.join().unwrap();
+        (result, blaster.join().unwrap())
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mapped_chunks_match_read_chunks() {
+        let path = std::env::temp_dir().join(format!("haven-raw-src-{}.bin", std::process::id()));
+        let chunk_size = 1000u64;
+        let data: Vec<u8> = (0..4321u32).map(|i| (i * 7 % 256) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let file_size = data.len() as u64;
+
+        let read = ChunkSource::Read(std::fs::File::open(&path).unwrap());
+        let mapped = ChunkSource::map(&std::fs::File::open(&path).unwrap(), file_size).unwrap();
+        assert!(matches!(mapped, ChunkSource::Mapped(_)));
+
+        // Out of order, as retransmits would ask for them.
+        for idx in [0u32, 3, 1, 4, 2] {
+            let offset = idx as u64 * chunk_size;
+            let len = (file_size - offset).min(chunk_size) as usize;
+            let from_read = read.chunk(idx, offset, len).unwrap();
+            let from_map = mapped.chunk(idx, offset, len).unwrap();
+            assert!(matches!(from_map, Cow::Borrowed(_)));
+            assert_eq!(from_read, from_map, "chunk {}", idx);
+            assert_eq!(&*from_map, &data[offset as usize..offset as usize + len]);
+        }
+
+        // A file shorter than its transfer record is refused, not sliced past.
+        let short = ChunkSource::map(&std::fs::File::open(&path).unwrap(), file_size + 1);
+        assert!(short.is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_parallel_encryption_matches_single_worker() {
+        let chunk_size = MIN_CHUNK_SIZE;
+        let data: Vec<u8> = (0..chunk_size * 13 + 1234).map(|i| (i * 31 % 251) as u8).collect();
+
+        let (single, order) = encrypt_with(1, &data, chunk_size);
+        assert_eq!(single.1.len(), 14);
+        assert_eq!(order, (0..14).collect::<Vec<_>>());
+
+        for workers in [2, 5, MAX_ENCRYPT_WORKERS] {
+            let (parallel, order) = encrypt_with(workers, &data, chunk_size);
+            assert_eq!(parallel, single, "{} workers", workers);
+            assert_eq!(order, (0..14).collect::<Vec<_>>(), "{} workers", workers);
+        }
+    }
+
+    #[test]
+    fn test_silent_receiver_fills_cache_then_stalls() {
+        let path = std::env::temp_dir().join(format!("haven-raw-silent-{}.bin", std::process::id()));
+        let chunk_size = 1000u64;
+        std::fs::write(&path, vec![9u8; 20_000]).unwrap();
+
+        // Nothing reads from this socket, so no NACK or ACK ever comes back.
+        let sink = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (_nack_tx, nack_rx) = bounded::<NackMessage>(16);
+        let (_ack_tx, ack_rx) = bounded::<ChunkAckMessage>(16);
+        let progress = Arc::new(SenderProgress::new());
+        let result = run_raw_sender(
+            RawSenderConfig {
+                file_path: path.to_string_lossy().into_owned(),
+                target_addr: sink.local_addr().unwrap(),
+                transfer_id: [3u8; 16],
+                file_size: 20_000,
+                chunk_size,
+                chunk_count: 20,
+                stall_timeout: Duration::from_millis(300),
+                cache_size: 3,
+                ..Default::default()
+            },
+            progress.clone(),
+            nack_rx,
+            ack_rx,
+        );
+
+        let err = result.unwrap_err();
+        assert!(matches!(err, TransferError::Stalled(_)), "{}", err);
+        // It stopped reading at a full cache instead of sending the whole file.
+        assert_eq!(progress.bytes_done.load(Ordering::Relaxed), 3 * chunk_size);
+        assert_eq!(progress.cache_chunks.load(Ordering::Relaxed), 3);
+        assert_eq!(progress.cache_full_waits.load(Ordering::Relaxed), 1);
+        assert_eq!(progress.state.load(Ordering::Relaxed), STATE_ERROR);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_chunk_that_is_always_lost_hits_retry_ceiling() {
+        let path = std::env::temp_dir().join(format!("haven-raw-lossy-{}.bin", std::process::id()));
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE) as u64;
+        let chunk_count = 4u32;
+        let file_size = chunk_size * chunk_count as u64;
+        std::fs::write(&path, vec![5u8; file_size as usize]).unwrap();
+
+        // The receiver gets every chunk but 2, which it NACKs over and over.
+        let sink = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (nack_tx, nack_rx) = bounded::<NackMessage>(64);
+        let (ack_tx, ack_rx) = bounded::<ChunkAckMessage>(16);
+        for chunk_index in [0, 1, 3] {
+            ack_tx.send(ChunkAckMessage::Chunk { chunk_index }).unwrap();
+        }
+        let nacker = std::thread::spawn(move || {
+            while nack_tx.send(NackMessage { chunk_index: 2, missing_frames: vec![0, 1] }).is_ok() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        let progress = Arc::new(SenderProgress::new());
+        let err = run_raw_sender(
+            RawSenderConfig {
+                file_path: path.to_string_lossy().into_owned(),
+                target_addr: sink.local_addr().unwrap(),
+                transfer_id: [5u8; 16],
+                file_size,
+                chunk_size,
+                chunk_count,
+                max_chunk_retransmits: 5,
+                ..Default::default()
+            },
+            progress.clone(),
+            nack_rx,
+            ack_rx,
+        )
+        .unwrap_err();
+        nacker.join().unwrap();
+
+        assert!(matches!(err, TransferError::Network(_)), "{}", err);
+        assert!(err.to_string().contains("Chunk 2") && err.to_string().contains("5 retransmits"), "{}", err);
+        assert_eq!(progress.state.load(Ordering::Relaxed), STATE_ERROR);
+        assert_eq!(progress.max_chunk_retransmits.load(Ordering::Relaxed), 5);
+        assert_eq!(progress.retransmits.load(Ordering::Relaxed), 10);
+        assert_eq!(progress.worst_chunks(3), vec![(2, 5)]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_rate_cap_bounds_throughput() {
+        let path = std::env::temp_dir().join(format!("haven-raw-capped-{}.bin", std::process::id()));
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE) as u64;
+        let chunk_count = 16u32;
+        let file_size = chunk_size * chunk_count as u64;
+        std::fs::write(&path, vec![1u8; file_size as usize]).unwrap();
+
+        // Pre-ACK everything so only pacing decides how long the blast takes.
+        let sink = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (_nack_tx, nack_rx) = bounded::<NackMessage>(16);
+        let (ack_tx, ack_rx) = bounded::<ChunkAckMessage>(chunk_count as usize);
+        for chunk_index in 0..chunk_count {
+            ack_tx.send(ChunkAckMessage::Chunk { chunk_index }).unwrap();
+        }
+        let cap = 2_000_000u64;
+        let started = Instant::now();
+        run_raw_sender(
+            RawSenderConfig {
+                file_path: path.to_string_lossy().into_owned(),
+                target_addr: sink.local_addr().unwrap(),
+                transfer_id: [4u8; 16],
+                file_size,
+                chunk_size,
+                chunk_count,
+                max_rate_bps: cap,
+                ..Default::default()
+            },
+            Arc::new(SenderProgress::new()),
+            nack_rx,
+            ack_rx,
+        )
+        .unwrap();
+
+        // Frame headers count toward the cap, so payload alone lands under it.
+        let throughput = file_size as f64 / started.elapsed().as_secs_f64();
+        assert!(throughput <= cap as f64 * 1.05, "{:.0} B/s over a cap of {}", throughput, cap);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_chunk_nonce_hashes_little_endian_fields() {
+        // Pinned to literal bytes, so a host of either byte order (and any
+        // client) must derive the same nonce.
+        let key = [0x11u8; 32];
+        let nonce_over = |tail: &[u8]| {
+            let hash = Sha256::new().chain_update(key).chain_update(tail).finalize();
+            <[u8; 12]>::try_from(&hash[..12]).unwrap()
+        };
+        assert_eq!(chunk_nonce(&key, 0x0102_0304, CHUNK_SIZE), nonce_over(&[4, 3, 2, 1, 0, 0, 0, 0]));
+        assert_eq!(
+            chunk_nonce(&key, 0x0102_0304, MIN_CHUNK_SIZE),
+            nonce_over(&[4, 3, 2, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0])
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! Sparse transfers: all-zero chunks that never cross the wire.
+//!
+//! With `SenderConfig::sparse`, a chunk whose plaintext is all zeros is
+//! stored as a slot of zeros as long as its sealed form would be, not as
+//! ciphertext. The sender doesn't blast it, the receiver writes the zeros
+//! itself (`ReceiverConfig::sparse_chunks`), and a reader with the key turns
+//! the zero slot back into zero plaintext with [`open_chunk`].
+//!
+//! A zero slot carries no tag, so nothing in it says it stands for zeros:
+//! readers take one for zeros only in a chunk the uploader listed as sparse,
+//! with the list bound into the control MAC (see `integrity`). Anywhere else
+//! a zero slot fails to open like any other forgery.
+//!
+//! Compressed transfers don't use it: zeros compress to almost nothing
+//! anyway, and a compressed slot doesn't record its plaintext length.
+
+use crate::aead::ChunkCipher;
+use crate::protocol::ENCRYPTION_OVERHEAD;
+
+/// Whether every byte is zero. True for an empty chunk.
+pub fn is_zero(bytes: &[u8]) -> bool {
+    bytes.iter().all(|&b| b == 0)
+}
+
+/// The stored form of a sparse chunk of `plain_len` bytes.
+pub fn zero_slot(plain_len: usize) -> Vec<u8> {
+    vec![0; plain_len + ENCRYPTION_OVERHEAD]
+}
+
+/// Open a stored, uncompressed chunk. `listed` says the chunk is one of the
+/// transfer's authenticated sparse chunks; only then does a zero slot read
+/// as the zeros it stands for.
+pub fn open_chunk(cipher: &ChunkCipher, slot: &[u8], listed: bool) -> Result<Vec<u8>, String> {
+    if listed && slot.len() >= ENCRYPTION_OVERHEAD && is_zero(slot) {
+        return Ok(vec![0; slot.len() - ENCRYPTION_OVERHEAD]);
+    }
+    cipher.open_chunk(slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::aead::AeadAlgorithm;
+    use crate::harness::{self, KEY, Loopback, TRANSFER_ID};
+    use crate::logging::{TransferEvent, TransferLog, TransferLogger};
+    use crate::protocol::*;
+    use crate::receiver::ReceiverConfig;
+    use crate::sender::{SendResult, SenderConfig, SenderProgress};
+
+    /// Chunks the sender blasted frames for.
+    #[derive(Default)]
+    struct Blasted(std::sync::Mutex<Vec<u32>>);
+
+    impl TransferLogger for Blasted {
+        fn log(&self, entry: TransferLog) {
+            if let TransferEvent::FramesBlasted { chunk_idx, .. } = entry.event {
+                self.0.lock().unwrap().push(chunk_idx);
+            }
+        }
+    }
+
+    /// Send `input` to a receiver writing `output`, in sparse mode if
+    /// `sparse_chunks` is `Some` (given to the receiver up front). Returns
+    /// the chunks blasted.
+    fn transfer(input: &Path, output: &Path, expected: &SendResult, sparse_chunks: Option<Vec<u32>>) -> Vec<u32> {
+        let sparse = sparse_chunks.is_some();
+        let receiver = Loopback::start(ReceiverConfig {
+            output_path: output.to_string_lossy().into_owned(),
+            transfer_id: TRANSFER_ID,
+            file_size: expected.encrypted_size,
+            chunk_count: expected.chunk_count,
+            chunk_size: encrypted_chunk_size(MIN_CHUNK_SIZE) as u64,
+            chunk_hashes: expected.chunk_hashes.clone(),
+            file_sha256: expected.file_sha256.clone(),
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            stall_timeout: Duration::from_secs(5),
+            sparse_chunks: sparse_chunks.unwrap_or_default(),
+            ..Default::default()
+        });
+
+        let tx_progress = Arc::new(SenderProgress::new());
+        let blasted = Arc::new(Blasted::default());
+        let sent = receiver
+            .send(
+                SenderConfig {
+                    stall_timeout: Duration::from_secs(5),
+                    logger: Some(blasted.clone()),
+                    sparse,
+                    ..harness::sender_config(input, receiver.addr, MIN_CHUNK_SIZE)
+                },
+                tx_progress.clone(),
+            )
+            .unwrap();
+        let rx_progress = receiver.progress.clone();
+        receiver.join().unwrap();
+        assert_eq!(sent.chunk_hashes, expected.chunk_hashes);
+        assert_eq!(tx_progress.chunks_complete.load(Ordering::Relaxed), expected.chunk_count as u64);
+        assert_eq!(rx_progress.bytes_done.load(Ordering::Relaxed), expected.encrypted_size);
+        let mut blasted = std::mem::take(&mut *blasted.0.lock().unwrap());
+        blasted.sort_unstable();
+        blasted.dedup();
+        blasted
+    }
+
+    /// What an upload of `plaintext` stores: per-chunk slots, hashes and the
+    /// sparse chunk list, as an uploader computes them up front.
+    fn expected(plaintext: &[u8], sparse: bool) -> (SendResult, Vec<u8>, Vec<u32>) {
+        let cipher = ChunkCipher::new(AeadAlgorithm::Aes256Gcm, &KEY);
+        let chunk_count = chunk_count(plaintext.len() as u64, MIN_CHUNK_SIZE as u64);
+        let (mut stored, mut chunk_hashes, mut sparse_chunks) = (Vec::new(), Vec::new(), Vec::new());
+        for idx in 0..chunk_count {
+            let start = idx as usize * MIN_CHUNK_SIZE;
+            let chunk = &plaintext[start..(start + MIN_CHUNK_SIZE).min(plaintext.len())];
+            let slot = if sparse && is_zero(chunk) {
+                sparse_chunks.push(idx);
+                zero_slot(chunk.len())
+            } else {
+                cipher.seal_chunk(&KEY, idx, MIN_CHUNK_SIZE, chunk).unwrap()
+            };
+            chunk_hashes.push(hex::encode(Sha256::digest(&slot)));
+            stored.extend(slot);
+        }
+        let result = SendResult {
+            file_sha256: hex::encode(Sha256::digest(&stored)),
+            chunk_hashes,
+            encrypted_size: stored.len() as u64,
+            chunk_count,
+            frame_payload: FRAME_PAYLOAD,
+        };
+        (result, stored, sparse_chunks)
+    }
+
+    fn decrypt(stored: &[u8], sparse_chunks: &[u32]) -> Vec<u8> {
+        let cipher = ChunkCipher::new(AeadAlgorithm::Aes256Gcm, &KEY);
+        let slot_len = encrypted_chunk_size(MIN_CHUNK_SIZE);
+        stored
+            .chunks(slot_len)
+            .enumerate()
+            .flat_map(|(idx, slot)| open_chunk(&cipher, slot, sparse_chunks.contains(&(idx as u32))).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_empty_file_round_trips() {
+        let dir = harness::scratch_dir("sparse-empty");
+        let (input, output) = (dir.join("in.bin"), dir.join("out.bin"));
+        std::fs::write(&input, b"").unwrap();
+
+        // One empty chunk: a bare nonce and tag, blasted as one frame...
+        let (expected_sealed, sealed, _) = expected(b"", false);
+        assert_eq!((expected_sealed.chunk_count, expected_sealed.encrypted_size), (1, ENCRYPTION_OVERHEAD as u64));
+        assert_eq!(transfer(&input, &output, &expected_sealed, None), vec![0]);
+        assert_eq!(std::fs::read(&output).unwrap(), sealed);
+        assert!(decrypt(&sealed, &[]).is_empty());
+
+        // ...or in sparse mode as that many zeros, not blasted at all.
+        let (expected, stored, sparse) = expected(b"", true);
+        assert_eq!(sparse, vec![0]);
+        assert_eq!(expected.encrypted_size, ENCRYPTION_OVERHEAD as u64);
+        assert!(transfer(&input, &output, &expected, Some(sparse.clone())).is_empty());
+        assert_eq!(std::fs::read(&output).unwrap(), stored);
+        assert!(decrypt(&stored, &sparse).is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_sparse_file_sends_only_its_data() {
+        let dir = harness::scratch_dir("sparse");
+        let (input, output) = (dir.join("in.bin"), dir.join("out.bin"));
+
+        // 64 chunks of zeros with data in chunk 5, in the middle of chunk 40
+        // and at the very end.
+        let mut plaintext = vec![0u8; 64 * MIN_CHUNK_SIZE - 10];
+        plaintext[5 * MIN_CHUNK_SIZE..6 * MIN_CHUNK_SIZE].fill(0xab);
+        plaintext[40 * MIN_CHUNK_SIZE + 100] = 1;
+        *plaintext.last_mut().unwrap() = 2;
+        std::fs::write(&input, &plaintext).unwrap();
+
+        let (expected, stored, sparse) = expected(&plaintext, true);
+        assert_eq!(sparse.len(), 61);
+        assert_eq!(transfer(&input, &output, &expected, Some(sparse.clone())), vec![5, 40, 63]);
+        assert_eq!(std::fs::read(&output).unwrap(), stored);
+        assert_eq!(decrypt(&stored, &sparse), plaintext);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_zero_slot_opens_as_zeros_only_when_listed() {
+        let cipher = ChunkCipher::new(AeadAlgorithm::ChaCha20Poly1305, &[1u8; 32]);
+        assert_eq!(open_chunk(&cipher, &zero_slot(100), true).unwrap(), vec![0; 100]);
+        assert!(open_chunk(&cipher, &zero_slot(100), false).is_err());
+        let sealed = cipher.seal_chunk(&[1u8; 32], 0, MIN_CHUNK_SIZE, &[0; 100]).unwrap();
+        assert!(!is_zero(&sealed));
+        assert_eq!(open_chunk(&cipher, &sealed, false).unwrap(), vec![0; 100]);
+        assert_eq!(open_chunk(&cipher, &sealed, true).unwrap(), vec![0; 100]);
+        assert!(open_chunk(&cipher, &[0; 10], true).is_err());
+    }
+}
@@ -0,0 +1,409 @@
+//! UDP frame wire format for fast file transfer.
+//!
+//! ```text
+//! [0..16]   Transfer ID (UUID, 16 bytes)
+//! [16..20]  Chunk index (u32 BE)
+//! [20..22]  Frame index within chunk (u16 BE)
+//! [22..24]  Frame count for this chunk (u16 BE)
+//! [24..]    Encrypted payload slice (1400 bytes by default)
+//! ```
+//!
+//! 24-byte header + 1400 bytes payload = 1424 bytes by default.
+//! Well within 1472-byte MTU limit (1500 - 20 IP - 8 UDP). Senders that
+//! probe the path MTU may use payloads up to `MAX_FRAME_PAYLOAD`; every frame
+//! of a chunk except the last carries exactly that many bytes.
+//!
+//! # Byte order
+//!
+//! Every multi-byte header field is big-endian (network order), written
+//! and read with `to_be_bytes`/`from_be_bytes` so the layout is the same on
+//! any host. Nothing is ever copied to or from the wire as a native integer.
+//!
+//! Other fixed encodings in the crate pick their own order, and both ends
+//! must agree on it:
+//!
+//! - Nonce derivation (`sender::chunk_nonce`, `compress::compressed_chunk_nonce`,
+//!   `aead`) hashes the chunk index and chunk size as little-endian u64s.
+//!   Clients that pre-hash sealed chunks derive the same nonces.
+//! - The compressed slot header (`compress`) is a little-endian u32.
+//! - The control MAC (`integrity`) covers its integer fields big-endian.
+//! - The resume sidecar (`resume`) is little-endian; it never leaves the
+//!   receiving host.
+//!
+//! This module only depends on `core` — no sockets, threads, or allocation —
+//! so the codec can be reused by tools, fuzzers, and other runtimes without
+//! pulling in the sender/receiver pipelines.
+
+use core::fmt;
+
+/// Maximum payload bytes per UDP frame.
+pub const FRAME_PAYLOAD: usize = 1400;
+
+/// Header size in bytes.
+pub const FRAME_HEADER: usize = 24;
+
+/// Maximum UDP frame size (header + payload).
+pub const FRAME_MAX: usize = FRAME_HEADER + FRAME_PAYLOAD;
+
+/// Largest payload after MTU probing: a 9000-byte jumbo frame minus IP, UDP,
+/// and frame headers.
+pub const MAX_FRAME_PAYLOAD: usize = 9000 - 20 - 8 - FRAME_HEADER;
+
+/// Smallest payload after MTU probing: the IPv6 minimum MTU of 1280 bytes
+/// minus IPv6, UDP, and frame headers. Tunnels can leave less room than
+/// `FRAME_PAYLOAD` needs.
+pub const MIN_FRAME_PAYLOAD: usize = 1280 - 40 - 8 - FRAME_HEADER;
+
+/// Largest UDP frame any sender may emit; size receive buffers to this.
+pub const MAX_FRAME: usize = FRAME_HEADER + MAX_FRAME_PAYLOAD;
+
+/// Chunk index reserved for MTU probe frames. Never a real chunk, so
+/// receivers that predate probing drop them as out of range.
+pub const PROBE_CHUNK_INDEX: u32 = u32::MAX;
+
+/// Chunk index of the empty frames a paused sender sends now and then so
+/// the receiver doesn't take the pause for a stall. Never a real chunk:
+/// receivers that predate keepalives drop them as out of range, after
+/// counting them as a frame arriving all the same.
+pub const KEEPALIVE_CHUNK_INDEX: u32 = u32::MAX - 1;
+
+/// Set in `frame_index` on FEC parity frames (see `fec`). Data frame indices
+/// never reach it, and parity frames keep `frame_count` below it, so
+/// receivers that predate FEC drop them as out of range.
+pub const PARITY_FRAME_FLAG: u16 = 0x8000;
+
+/// Chunk size: 4 MB plaintext. Encrypted = plaintext + 28 (12 nonce + 16 tag).
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Encrypted chunk overhead: 12-byte nonce + 16-byte tag. The same for every
+/// algorithm in `aead`.
+pub const ENCRYPTION_OVERHEAD: usize = 28;
+
+/// Maximum encrypted chunk size.
+pub const ENCRYPTED_CHUNK_SIZE: usize = CHUNK_SIZE + ENCRYPTION_OVERHEAD;
+
+/// Most frames a full chunk at the default size can take, at the smallest
+/// stride: ceil(4_194_332 / 1208) = 3473.
+pub const MAX_FRAMES_PER_CHUNK: usize = ENCRYPTED_CHUNK_SIZE.div_ceil(MIN_FRAME_PAYLOAD);
+
+/// Smallest plaintext chunk size a transfer may negotiate (64 KB).
+pub const MIN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest plaintext chunk size a transfer may negotiate (32 MB).
+/// Keeps the per-chunk frame count well inside the u16 header field.
+pub const MAX_CHUNK_SIZE: usize = 32 * 1024 * 1024;
+
+/// Whether `chunk_size` (plaintext) is within `MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE`.
+pub const fn chunk_size_in_range(chunk_size: usize) -> bool {
+    chunk_size >= MIN_CHUNK_SIZE && chunk_size <= MAX_CHUNK_SIZE
+}
+
+/// Encrypted size of a full chunk with `chunk_size` plaintext bytes.
+pub const fn encrypted_chunk_size(chunk_size: usize) -> usize {
+    chunk_size + ENCRYPTION_OVERHEAD
+}
+
+/// Errors from encoding a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireError {
+    /// Output buffer can't hold header + payload.
+    BufferTooSmall { needed: usize, got: usize },
+    /// Payload exceeds `MAX_FRAME_PAYLOAD`.
+    PayloadTooLarge { len: usize },
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BufferTooSmall { needed, got } => {
+                write!(f, "frame buffer too small: need {} bytes, got {}", needed, got)
+            }
+            Self::PayloadTooLarge { len } => {
+                write!(f, "frame payload too large: {} > {}", len, MAX_FRAME_PAYLOAD)
+            }
+        }
+    }
+}
+
+/// Parsed UDP frame header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub transfer_id: [u8; 16],
+    pub chunk_index: u32,
+    pub frame_index: u16,
+    pub frame_count: u16,
+}
+
+impl FrameHeader {
+    /// Write the 24-byte header into the start of `buf`.
+    pub fn write_to(&self, buf: &mut [u8]) -> Result<(), WireError> {
+        if buf.len() < FRAME_HEADER {
+            return Err(WireError::BufferTooSmall { needed: FRAME_HEADER, got: buf.len() });
+        }
+        buf[0..16].copy_from_slice(&self.transfer_id);
+        buf[16..20].copy_from_slice(&self.chunk_index.to_be_bytes());
+        buf[20..22].copy_from_slice(&self.frame_index.to_be_bytes());
+        buf[22..24].copy_from_slice(&self.frame_count.to_be_bytes());
+        Ok(())
+    }
+
+    /// Whether this is an FEC parity frame rather than chunk data.
+    pub fn is_parity(&self) -> bool {
+        self.frame_index & PARITY_FRAME_FLAG != 0
+    }
+
+    /// Read a header from the start of `data`. Returns None if too short.
+    pub fn read_from(data: &[u8]) -> Option<Self> {
+        let header: &[u8; FRAME_HEADER] = data.get(..FRAME_HEADER)?.try_into().ok()?;
+        let mut transfer_id = [0u8; 16];
+        transfer_id.copy_from_slice(&header[0..16]);
+        Some(Self {
+            transfer_id,
+            chunk_index: u32::from_be_bytes([header[16], header[17], header[18], header[19]]),
+            frame_index: u16::from_be_bytes([header[20], header[21]]),
+            frame_count: u16::from_be_bytes([header[22], header[23]]),
+        })
+    }
+}
+
+/// Encode a frame into `buf` without panicking. Returns bytes written.
+pub fn try_encode_frame(
+    buf: &mut [u8],
+    transfer_id: &[u8; 16],
+    chunk_index: u32,
+    frame_index: u16,
+    frame_count: u16,
+    payload: &[u8],
+) -> Result<usize, WireError> {
+    if payload.len() > MAX_FRAME_PAYLOAD {
+        return Err(WireError::PayloadTooLarge { len: payload.len() });
+    }
+    let total = FRAME_HEADER + payload.len();
+    if buf.len() < total {
+        return Err(WireError::BufferTooSmall { needed: total, got: buf.len() });
+    }
+    FrameHeader {
+        transfer_id: *transfer_id,
+        chunk_index,
+        frame_index,
+        frame_count,
+    }
+    .write_to(buf)?;
+    buf[FRAME_HEADER..total].copy_from_slice(payload);
+    Ok(total)
+}
+
+/// Encode a UDP frame into the provided buffer. Returns bytes written.
+///
+/// # Panics
+/// Panics if `buf` is smaller than `FRAME_HEADER + payload.len()`, or the
+/// payload exceeds `MAX_FRAME_PAYLOAD`.
+pub fn encode_frame(
+    buf: &mut [u8],
+    transfer_id: &[u8; 16],
+    chunk_index: u32,
+    frame_index: u16,
+    frame_count: u16,
+    payload: &[u8],
+) -> usize {
+    match try_encode_frame(buf, transfer_id, chunk_index, frame_index, frame_count, payload) {
+        Ok(n) => n,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// Decode a frame header from raw bytes. Returns None if too short.
+pub fn decode_frame_header(data: &[u8]) -> Option<FrameHeader> {
+    FrameHeader::read_from(data)
+}
+
+/// Decode a whole frame into its header and a borrowed payload slice.
+pub fn decode_frame(data: &[u8]) -> Option<(FrameHeader, &[u8])> {
+    let header = FrameHeader::read_from(data)?;
+    Some((header, &data[FRAME_HEADER..]))
+}
+
+/// Get the payload slice from a raw frame (empty if shorter than a header).
+pub fn frame_payload(data: &[u8]) -> &[u8] {
+    data.get(FRAME_HEADER..).unwrap_or(&[])
+}
+
+/// Calculate number of frames needed for a chunk of given encrypted size.
+pub fn frames_for_chunk(encrypted_size: usize) -> u16 {
+    frames_for_chunk_with(encrypted_size, FRAME_PAYLOAD)
+}
+
+/// Most data frames a chunk of `encrypted_size` bytes can arrive in. No
+/// sender strides below `MIN_FRAME_PAYLOAD`, so a header claiming more is
+/// forged or corrupt. `MAX_FRAMES_PER_CHUNK` at the default chunk size.
+pub fn max_frames_per_chunk(encrypted_size: usize) -> u16 {
+    frames_for_chunk_with(encrypted_size, MIN_FRAME_PAYLOAD)
+}
+
+/// Like `frames_for_chunk`, for a transfer using `frame_payload` bytes per frame.
+pub fn frames_for_chunk_with(encrypted_size: usize, frame_payload: usize) -> u16 {
+    encrypted_size.div_ceil(frame_payload) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Small deterministic PRNG so fuzz runs are reproducible without extra deps.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn fill(&mut self, buf: &mut [u8]) {
+            for b in buf {
+                *b = self.next() as u8;
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let tid = [0xAB; 16];
+        let payload: Vec<u8> = (0..FRAME_PAYLOAD).map(|i| i as u8).collect();
+        let mut buf = [0u8; FRAME_MAX];
+
+        let n = encode_frame(&mut buf, &tid, 0xDEAD_BEEF, 2996, 2997, &payload);
+        assert_eq!(n, FRAME_MAX);
+
+        let (header, body) = decode_frame(&buf[..n]).unwrap();
+        assert_eq!(
+            header,
+            FrameHeader { transfer_id: tid, chunk_index: 0xDEAD_BEEF, frame_index: 2996, frame_count: 2997 }
+        );
+        assert_eq!(body, &payload[..]);
+        assert_eq!(frame_payload(&buf[..n]), &payload[..]);
+    }
+
+    #[test]
+    fn test_big_endian_layout() {
+        let mut buf = [0u8; FRAME_HEADER];
+        try_encode_frame(&mut buf, &[0; 16], 0x0102_0304, 0x0506, 0x0708, &[]).unwrap();
+        assert_eq!(&buf[16..24], &[1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_header_decodes_from_fixed_bytes() {
+        // Bytes as another host would have sent them; the result must not
+        // depend on this host's byte order.
+        let mut wire = [0u8; FRAME_HEADER];
+        wire[..16].copy_from_slice(&[0x42; 16]);
+        wire[16..].copy_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x80, 0x02, 0x00, 0x03]);
+        let header = decode_frame_header(&wire).unwrap();
+        assert_eq!(
+            header,
+            FrameHeader { transfer_id: [0x42; 16], chunk_index: 256, frame_index: PARITY_FRAME_FLAG | 2, frame_count: 3 }
+        );
+        assert!(header.is_parity());
+
+        let mut out = [0u8; FRAME_HEADER];
+        header.write_to(&mut out).unwrap();
+        assert_eq!(out, wire);
+    }
+
+    #[test]
+    fn test_round_trip_edge_values() {
+        let mut buf = [0u8; FRAME_HEADER];
+        for chunk_index in [0, 1, 0xFF, 0x100, 0x7FFF_FFFF, PROBE_CHUNK_INDEX] {
+            for frame_index in [0, 1, 0xFF, 0x100, PARITY_FRAME_FLAG - 1, PARITY_FRAME_FLAG, u16::MAX] {
+                for frame_count in [0, 1, 0xFF00, u16::MAX] {
+                    let transfer_id = [chunk_index as u8; 16];
+                    let header = FrameHeader { transfer_id, chunk_index, frame_index, frame_count };
+                    header.write_to(&mut buf).unwrap();
+                    assert_eq!(FrameHeader::read_from(&buf), Some(header));
+                    assert_eq!(header.is_parity(), frame_index >= PARITY_FRAME_FLAG);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        let mut small = [0u8; FRAME_HEADER + 3];
+        assert_eq!(
+            try_encode_frame(&mut small, &[0; 16], 0, 0, 1, &[0; 4]),
+            Err(WireError::BufferTooSmall { needed: FRAME_HEADER + 4, got: FRAME_HEADER + 3 })
+        );
+        let mut big = [0u8; MAX_FRAME + 10];
+        assert_eq!(
+            try_encode_frame(&mut big, &[0; 16], 0, 0, 1, &[0; MAX_FRAME_PAYLOAD + 1]),
+            Err(WireError::PayloadTooLarge { len: MAX_FRAME_PAYLOAD + 1 })
+        );
+        assert!(try_encode_frame(&mut big, &[0; 16], 0, 0, 1, &[0; MAX_FRAME_PAYLOAD]).is_ok());
+    }
+
+    #[test]
+    fn test_short_input() {
+        for len in 0..FRAME_HEADER {
+            let data = vec![0u8; len];
+            assert!(decode_frame_header(&data).is_none());
+            assert!(decode_frame(&data).is_none());
+            assert!(frame_payload(&data).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_frames_for_chunk() {
+        assert_eq!(frames_for_chunk(0), 0);
+        assert_eq!(frames_for_chunk(1), 1);
+        assert_eq!(frames_for_chunk(FRAME_PAYLOAD), 1);
+        assert_eq!(frames_for_chunk(FRAME_PAYLOAD + 1), 2);
+        assert_eq!(frames_for_chunk_with(ENCRYPTED_CHUNK_SIZE, MAX_FRAME_PAYLOAD), 469);
+        assert_eq!(frames_for_chunk(ENCRYPTED_CHUNK_SIZE), 2996);
+        assert_eq!(max_frames_per_chunk(ENCRYPTED_CHUNK_SIZE) as usize, MAX_FRAMES_PER_CHUNK);
+        // The largest negotiable chunk must not wrap the u16 frame count.
+        let max_frames = encrypted_chunk_size(MAX_CHUNK_SIZE).div_ceil(MIN_FRAME_PAYLOAD);
+        assert!(max_frames < u16::MAX as usize);
+        // Nor reach the parity flag.
+        assert!(max_frames < PARITY_FRAME_FLAG as usize);
+    }
+
+    #[test]
+    fn fuzz_decode_arbitrary_bytes() {
+        let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+        let mut data = vec![0u8; FRAME_MAX + 64];
+        for _ in 0..20_000 {
+            let len = (rng.next() as usize) % data.len();
+            rng.fill(&mut data[..len]);
+            match decode_frame(&data[..len]) {
+                Some((_, payload)) => assert_eq!(payload.len(), len - FRAME_HEADER),
+                None => assert!(len < FRAME_HEADER),
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_round_trip() {
+        let mut rng = XorShift(0xD1B5_4A32_D192_ED03);
+        let mut payload = vec![0u8; FRAME_PAYLOAD];
+        let mut buf = vec![0u8; FRAME_MAX];
+        for _ in 0..20_000 {
+            let mut tid = [0u8; 16];
+            rng.fill(&mut tid);
+            let chunk_index = rng.next() as u32;
+            let frame_index = rng.next() as u16;
+            let frame_count = rng.next() as u16;
+            let len = (rng.next() as usize) % (FRAME_PAYLOAD + 1);
+            rng.fill(&mut payload[..len]);
+
+            let n = try_encode_frame(&mut buf, &tid, chunk_index, frame_index, frame_count, &payload[..len])
+                .unwrap();
+            let (header, body) = decode_frame(&buf[..n]).unwrap();
+            assert_eq!(header.transfer_id, tid);
+            assert_eq!(header.chunk_index, chunk_index);
+            assert_eq!(header.frame_index, frame_index);
+            assert_eq!(header.frame_count, frame_count);
+            assert_eq!(body, &payload[..len]);
+        }
+    }
+}
@@ -14,12 +14,15 @@ use reqwest::Client;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use tracing::{error, info, warn};
 
+use haven_types::jwt::TokenScope;
+
 // ── App state ──────────────────────────────────────────────────────────
 
 #[derive(Clone)]
 struct AppState {
     file_server_url: String, // e.g. "http://127.0.0.1:3211"
     jwt_secret: String,
+    token_scope: TokenScope,
     client: Client,
 }
 
@@ -44,12 +47,7 @@ async fn require_auth(
         StatusCode::UNAUTHORIZED
     })?;
 
-    jsonwebtoken::decode::<haven_types::api::Claims>(
-        token,
-        &jsonwebtoken::DecodingKey::from_secret(state.jwt_secret.as_bytes()),
-        &jsonwebtoken::Validation::default(),
-    )
-    .map_err(|e| {
+    state.token_scope.decode(token, &state.jwt_secret).map_err(|e| {
         warn!(error = %e, "request rejected: JWT validation failed");
         StatusCode::UNAUTHORIZED
     })?;
@@ -219,12 +217,15 @@ async fn main() -> anyhow::Result<()> {
         eprintln!("FATAL: HAVEN_JWT_SECRET is unset. Set it in .env and restart.");
         std::process::exit(1);
     }
+    let token_scope = TokenScope::from_env();
+    token_scope.log_config();
 
     info!(port, file_server_url = %file_server_url, "haven-file-gateway starting");
 
     let state = AppState {
         file_server_url,
         jwt_secret,
+        token_scope,
         client: Client::builder()
             .no_proxy()
             .build()?,
@@ -3,19 +3,31 @@ use std::time::Duration;
 use tracing::{info, warn};
 
 use crate::db::FileDb;
+use crate::dedup;
+use crate::routes::DownloadSessions;
 use crate::storage::Storage;
+use crate::webhook::{ExpiryEvent, ExpiryWebhook};
 
 /// Background task that prunes expired transfers.
 ///
-/// Runs on an interval, finds transfers past their `expires_at` timestamp,
-/// deletes their files from disk, and marks them as expired in the DB.
-pub async fn run_cleanup_loop(db: Arc<FileDb>, storage: Arc<Storage>, interval_secs: u64) {
+/// Runs on an interval, finds transfers past their `expires_at` timestamp or
+/// out of confirmed downloads (`max_downloads`), deletes their files from
+/// disk, marks them as expired in the DB, and forgets their download
+/// sessions. Transfers that expire unconfirmed are reported to `webhook`, if
+/// set.
+pub async fn run_cleanup_loop(
+    db: Arc<FileDb>,
+    storage: Arc<Storage>,
+    download_sessions: DownloadSessions,
+    webhook: Option<ExpiryWebhook>,
+    interval_secs: u64,
+) {
     let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
 
     loop {
         interval.tick().await;
 
-        match cleanup_expired(&db, &storage).await {
+        match cleanup_expired(&db, &storage, &download_sessions, webhook.as_ref()).await {
             Ok(count) => {
                 if count > 0 {
                     info!("Cleanup: pruned {} expired transfers", count);
@@ -28,25 +40,36 @@ pub async fn run_cleanup_loop(db: Arc<FileDb>, storage: Arc<Storage>, interval_s
     }
 }
 
-async fn cleanup_expired(db: &FileDb, storage: &Storage) -> anyhow::Result<usize> {
-    // Find expired transfers
-    let expired: Vec<String> = db.with_conn(|conn| {
+async fn cleanup_expired(
+    db: &FileDb,
+    storage: &Storage,
+    download_sessions: &DownloadSessions,
+    webhook: Option<&ExpiryWebhook>,
+) -> anyhow::Result<usize> {
+    // Find expired transfers. `confirm_transfer` normally releases a capped
+    // one on its last download; this catches any it didn't finish.
+    let expired: Vec<(String, String, String, bool)> = db.with_conn(|conn| {
         let mut stmt = conn.prepare(
-            "SELECT id FROM transfers
-             WHERE expires_at IS NOT NULL
-               AND expires_at < datetime('now')
-               AND status != 'expired'"
+            "SELECT id, uploader_id, status, downloads_used FROM (
+                 SELECT id, uploader_id, status, expires_at,
+                        max_downloads IS NOT NULL
+                            AND (SELECT COUNT(*) FROM transfer_confirmations c WHERE c.transfer_id = transfers.id)
+                                >= max_downloads AS downloads_used
+                 FROM transfers
+                 WHERE status != 'expired'
+             )
+             WHERE (expires_at IS NOT NULL AND expires_at < datetime('now')) OR downloads_used"
         )?;
-        let ids = stmt
-            .query_map([], |row| row.get::<_, String>(0))?
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
             .collect::<Result<Vec<_>, _>>()?;
-        Ok(ids)
+        Ok(rows)
     })?;
 
     let count = expired.len();
-    for id in &expired {
+    for (id, uploader_id, status, downloads_used) in &expired {
         // Delete file from disk
-        storage.delete_file(id).await.ok();
+        dedup::release(db, storage, id).await.ok();
 
         // Mark as expired in DB
         db.with_conn_mut(|conn| {
@@ -56,7 +79,131 @@ async fn cleanup_expired(db: &FileDb, storage: &Storage) -> anyhow::Result<usize
             )?;
             Ok(())
         })?;
+        download_sessions.lock().unwrap().remove(id);
+
+        // The recipient never confirmed: tell the sender it's gone.
+        if let Some(webhook) = webhook
+            && status != "confirmed"
+            && !downloads_used
+        {
+            let event = ExpiryEvent {
+                transfer_id: id.clone(),
+                uploader_id: uploader_id.clone(),
+                expired_at: chrono::Utc::now().to_rfc3339(),
+            };
+            webhook.send(&event).await;
+        }
     }
 
     Ok(count)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn webhook_fires_once_per_unconfirmed_expiry() {
+        let dir = std::env::temp_dir().join(format!("haven-fs-cleanup-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = FileDb::open(&dir.join("files.db")).unwrap();
+        let storage = Storage::new(dir.join("storage")).await.unwrap();
+        let sessions = DownloadSessions::default();
+
+        // Receiver that records every POST body.
+        let received = Arc::new(Mutex::new(Vec::<serde_json::Value>::new()));
+        let sink = received.clone();
+        let app = axum::Router::new().route(
+            "/expired",
+            axum::routing::post(move |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                sink.lock().unwrap().push(body);
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let webhook = ExpiryWebhook::new(format!("http://{}/expired", addr)).unwrap();
+
+        // Everything expires an hour from now.
+        db.with_conn_mut(|conn| {
+            for (id, status) in [("a", "complete"), ("b", "uploading"), ("c", "confirmed")] {
+                conn.execute(
+                    "INSERT INTO transfers (id, uploader_id, file_size, chunk_count, file_sha256, status, expires_at)
+                     VALUES (?1, ?2, 0, 0, '', ?3, datetime('now', '+1 hour'))",
+                    rusqlite::params![id, format!("user-{}", id), status],
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(cleanup_expired(&db, &storage, &sessions, Some(&webhook)).await.unwrap(), 0);
+        assert!(received.lock().unwrap().is_empty());
+
+        // Advance two hours: all three expire, but only the unconfirmed ones notify.
+        db.with_conn_mut(|conn| {
+            conn.execute("UPDATE transfers SET expires_at = datetime(expires_at, '-2 hours')", [])?;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(cleanup_expired(&db, &storage, &sessions, Some(&webhook)).await.unwrap(), 3);
+        // A later pass finds nothing new to report.
+        assert_eq!(cleanup_expired(&db, &storage, &sessions, Some(&webhook)).await.unwrap(), 0);
+
+        let mut events = received.lock().unwrap().clone();
+        events.sort_by_key(|e| e["transfer_id"].as_str().unwrap().to_owned());
+        assert_eq!(events.len(), 2);
+        for (event, id) in events.iter().zip(["a", "b"]) {
+            assert_eq!(event["transfer_id"], id);
+            assert_eq!(event["uploader_id"], format!("user-{}", id));
+            assert!(chrono::DateTime::parse_from_rfc3339(event["expired_at"].as_str().unwrap()).is_ok());
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn download_capped_transfer_expires_once_used_up() {
+        let dir = std::env::temp_dir().join(format!("haven-fs-cleanup-cap-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = FileDb::open(&dir.join("files.db")).unwrap();
+        let storage = Storage::new(dir.join("storage")).await.unwrap();
+        let sessions = DownloadSessions::default();
+
+        // Both have a day left; "once" allows a single download, "twice" two.
+        db.with_conn_mut(|conn| {
+            for (id, max_downloads) in [("once", 1), ("twice", 2)] {
+                conn.execute(
+                    "INSERT INTO transfers (id, uploader_id, file_size, chunk_count, file_sha256, status, expires_at, max_downloads)
+                     VALUES (?1, 'u', 0, 0, '', 'complete', datetime('now', '+1 day'), ?2)",
+                    rusqlite::params![id, max_downloads],
+                )?;
+                conn.execute(
+                    "INSERT INTO transfer_confirmations (transfer_id, recipient) VALUES (?1, 'alice')",
+                    [id],
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        storage.create_file("once", 16).await.unwrap();
+        for id in ["once", "twice"] {
+            sessions.lock().unwrap().insert(id.into(), [uuid::Uuid::new_v4()].into());
+        }
+
+        assert_eq!(cleanup_expired(&db, &storage, &sessions, None).await.unwrap(), 1);
+        assert!(!storage.file_path("once").exists());
+        assert!(!sessions.lock().unwrap().contains_key("once"));
+        assert!(sessions.lock().unwrap().contains_key("twice"));
+        let status = |id: &str| {
+            db.with_conn(|c| Ok(c.query_row("SELECT status FROM transfers WHERE id = ?1", [id], |r| r.get::<_, String>(0))?))
+                .unwrap()
+        };
+        assert_eq!(status("once"), "expired");
+        assert_eq!(status("twice"), "complete");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -22,12 +22,38 @@ impl FileDb {
         self.pool.with_conn(f)
     }
 
+    /// See [`DbPool::query_row_cached`].
+    pub fn query_row_cached<T, P, F>(&self, sql: &str, params: P, f: F) -> Result<T>
+    where
+        P: rusqlite::Params,
+        F: FnOnce(&rusqlite::Row<'_>) -> rusqlite::Result<T>,
+    {
+        self.pool.query_row_cached(sql, params, f)
+    }
+
+    /// See [`DbPool::query_all_cached`].
+    pub fn query_all_cached<T, P, F>(&self, sql: &str, params: P, f: F) -> Result<Vec<T>>
+    where
+        P: rusqlite::Params,
+        F: FnMut(&rusqlite::Row<'_>) -> rusqlite::Result<T>,
+    {
+        self.pool.query_all_cached(sql, params, f)
+    }
+
     pub fn with_conn_mut<F, T>(&self, f: F) -> Result<T>
     where
         F: FnOnce(&rusqlite::Connection) -> Result<T>,
     {
         self.pool.with_conn_mut(f)
     }
+
+    /// Run `f` in a single writer transaction (rolled back on `Err`).
+    pub fn with_transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&rusqlite::Connection) -> Result<T>,
+    {
+        self.pool.with_transaction(f)
+    }
 }
 
 fn run_migrations(conn: &rusqlite::Connection) -> Result<()> {
@@ -70,5 +96,110 @@ fn run_migrations(conn: &rusqlite::Connection) -> Result<()> {
         )?;
     }
 
+    if version < 2 {
+        info!("File DB: running migration v2 (control MAC)");
+        conn.execute_batch(
+            "
+            ALTER TABLE transfers ADD COLUMN control_mac TEXT;
+
+            INSERT INTO schema_version (version) VALUES (2);
+            "
+        )?;
+    }
+
+    if version < 3 {
+        info!("File DB: running migration v3 (blob dedup)");
+        conn.execute_batch(
+            "
+            CREATE TABLE blobs (
+                sha256 TEXT PRIMARY KEY,
+                file_size INTEGER NOT NULL,
+                chunk_size INTEGER NOT NULL,
+                chunk_hashes TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now'))
+            );
+
+            ALTER TABLE transfers ADD COLUMN blob_sha256 TEXT;
+            CREATE INDEX idx_transfers_blob ON transfers(blob_sha256);
+
+            INSERT INTO schema_version (version) VALUES (3);
+            "
+        )?;
+    }
+
+    if version < 4 {
+        info!("File DB: running migration v4 (compressed chunks)");
+        conn.execute_batch(
+            "
+            ALTER TABLE transfers ADD COLUMN compressed INTEGER NOT NULL DEFAULT 0;
+
+            INSERT INTO schema_version (version) VALUES (4);
+            "
+        )?;
+    }
+
+    if version < 5 {
+        info!("File DB: running migration v5 (download metadata)");
+        conn.execute_batch(
+            "
+            ALTER TABLE transfers ADD COLUMN filename TEXT;
+            ALTER TABLE transfers ADD COLUMN content_type TEXT;
+
+            INSERT INTO schema_version (version) VALUES (5);
+            "
+        )?;
+    }
+
+    if version < 6 {
+        info!("File DB: running migration v6 (multi-recipient confirms)");
+        conn.execute_batch(
+            "
+            ALTER TABLE transfers ADD COLUMN recipients INTEGER NOT NULL DEFAULT 1;
+
+            CREATE TABLE transfer_confirmations (
+                transfer_id TEXT NOT NULL REFERENCES transfers(id) ON DELETE CASCADE,
+                recipient TEXT NOT NULL,
+                confirmed_at TEXT NOT NULL DEFAULT (datetime('now')),
+                PRIMARY KEY (transfer_id, recipient)
+            );
+
+            INSERT INTO schema_version (version) VALUES (6);
+            "
+        )?;
+    }
+
+    if version < 7 {
+        info!("File DB: running migration v7 (download caps)");
+        conn.execute_batch(
+            "
+            ALTER TABLE transfers ADD COLUMN max_downloads INTEGER;
+
+            INSERT INTO schema_version (version) VALUES (7);
+            "
+        )?;
+    }
+
+    if version < 8 {
+        info!("File DB: running migration v8 (AEAD algorithm)");
+        conn.execute_batch(
+            "
+            ALTER TABLE transfers ADD COLUMN aead INTEGER NOT NULL DEFAULT 0;
+
+            INSERT INTO schema_version (version) VALUES (8);
+            "
+        )?;
+    }
+
+    if version < 9 {
+        info!("File DB: running migration v9 (sparse chunks)");
+        conn.execute_batch(
+            "
+            ALTER TABLE transfers ADD COLUMN sparse_chunks TEXT;
+
+            INSERT INTO schema_version (version) VALUES (9);
+            "
+        )?;
+    }
+
     Ok(())
 }
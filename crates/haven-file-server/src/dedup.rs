@@ -0,0 +1,164 @@
+//! Content-addressed deduplication of stored transfers.
+//!
+//! When an upload completes, its file is hashed and, if the bytes match the
+//! declared `file_sha256`, indexed in the `blobs` table and hard-linked at
+//! `blobs/{sha256}`. A later `create_transfer` with the same hash and chunk
+//! layout links that blob instead of asking for the bytes again.
+//!
+//! Transfers reference their blob through `transfers.blob_sha256`. Releasing
+//! a transfer (confirm, delete, expiry) unlinks its file and drops the
+//! reference; the blob row and index link go once nothing references them.
+
+use anyhow::Result;
+use rusqlite::{Connection, OptionalExtension};
+use tracing::{info, warn};
+
+use crate::db::FileDb;
+use crate::storage::{Storage, is_blob_key};
+
+/// Link `transfer_id` to an indexed blob with this exact content and chunk
+/// layout. Returns whether the link was made; the caller must still confirm
+/// with `blob_exists` inside its transaction, since the blob may be released
+/// concurrently.
+pub async fn link_existing(
+    db: &FileDb,
+    storage: &Storage,
+    transfer_id: &str,
+    file_sha256: &str,
+    file_size: u64,
+    chunk_size: u64,
+    chunk_hashes: &[String],
+) -> bool {
+    if !is_blob_key(file_sha256) {
+        return false;
+    }
+    let blob = db.with_conn(|conn| {
+        Ok(conn
+            .query_row(
+                "SELECT file_size, chunk_size, chunk_hashes FROM blobs WHERE sha256 = ?1",
+                [file_sha256],
+                |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64, row.get::<_, String>(2)?)),
+            )
+            .optional()?)
+    });
+    let Ok(Some((blob_size, blob_chunk_size, blob_hashes))) = blob else {
+        return false;
+    };
+    // Matching chunk hashes too means the client knows more than the
+    // whole-file hash, and the chunk rows we create will be correct.
+    let layout_matches = blob_size == file_size
+        && blob_chunk_size == chunk_size
+        && serde_json::from_str::<Vec<String>>(&blob_hashes).is_ok_and(|h| h == chunk_hashes);
+    if !layout_matches {
+        return false;
+    }
+
+    match storage.link_from_blob(file_sha256, transfer_id).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Dedup: failed to link blob {} for {}: {}", file_sha256, transfer_id, e);
+            false
+        }
+    }
+}
+
+/// Whether the blob row is still present. Call inside the transaction that
+/// records the new reference.
+pub fn blob_exists(conn: &Connection, sha256: &str) -> rusqlite::Result<bool> {
+    conn.query_row("SELECT 1 FROM blobs WHERE sha256 = ?1", [sha256], |_| Ok(()))
+        .optional()
+        .map(|r| r.is_some())
+}
+
+/// Index a just-completed transfer as a blob. Best-effort: errors are logged
+/// and the transfer keeps its own file.
+pub async fn index_completed(db: &FileDb, storage: &Storage, transfer_id: &str) {
+    if let Err(e) = try_index(db, storage, transfer_id).await {
+        warn!("Dedup: failed to index {}: {}", transfer_id, e);
+    }
+}
+
+async fn try_index(db: &FileDb, storage: &Storage, transfer_id: &str) -> Result<()> {
+    type Row = (String, u64, u64, String, Option<String>);
+    let (sha256, file_size, chunk_size, status, blob): Row = db.with_conn_mut(|conn| {
+        Ok(conn.query_row(
+            "SELECT file_sha256, file_size, chunk_size, status, blob_sha256 FROM transfers WHERE id = ?1",
+            [transfer_id],
+            |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64, row.get::<_, i64>(2)? as u64, row.get(3)?, row.get(4)?)),
+        )?)
+    })?;
+    if status != "complete" || blob.is_some() || !is_blob_key(&sha256) {
+        return Ok(());
+    }
+
+    // The declared hash is client-supplied; only index bytes that match it,
+    // or one bad upload could poison every later dedup of that hash.
+    let actual = storage.hash_file(transfer_id).await?;
+    if actual != sha256 {
+        warn!("Dedup: {} content hash {} != declared {}, not indexing", transfer_id, actual, sha256);
+        return Ok(());
+    }
+
+    storage.index_blob(transfer_id, &sha256).await?;
+
+    let (still_complete, orphaned) = db.with_transaction(|conn| {
+        let hashes: Vec<String> = {
+            let mut stmt = conn.prepare("SELECT sha256 FROM chunks WHERE transfer_id = ?1 ORDER BY chunk_index")?;
+            stmt.query_map([transfer_id], |row| row.get(0))?.collect::<Result<_, _>>()?
+        };
+        conn.execute(
+            "INSERT OR IGNORE INTO blobs (sha256, file_size, chunk_size, chunk_hashes) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![&sha256, file_size as i64, chunk_size as i64, serde_json::to_string(&hashes)?],
+        )?;
+        // The transfer may have been released while we were hashing.
+        let updated = conn.execute(
+            "UPDATE transfers SET blob_sha256 = ?1 WHERE id = ?2 AND status = 'complete'",
+            rusqlite::params![&sha256, transfer_id],
+        )?;
+        Ok((updated > 0, drop_if_unreferenced(conn, &sha256)?))
+    })?;
+    if !still_complete {
+        // index_blob may have re-created the file after release removed it.
+        storage.delete_file(transfer_id).await?;
+    }
+    if orphaned {
+        storage.delete_blob(&sha256).await?;
+    }
+    if still_complete {
+        info!("Dedup: indexed {} as blob {}", transfer_id, sha256);
+    }
+    Ok(())
+}
+
+/// Remove a transfer's file and its blob reference, deleting the blob if
+/// this was the last one. The transfer row itself is left to the caller.
+pub async fn release(db: &FileDb, storage: &Storage, transfer_id: &str) -> Result<()> {
+    let orphaned_blob = db.with_transaction(|conn| {
+        let blob: Option<String> = conn
+            .query_row("SELECT blob_sha256 FROM transfers WHERE id = ?1", [transfer_id], |row| row.get(0))
+            .optional()?
+            .flatten();
+        let Some(sha256) = blob else {
+            return Ok(None);
+        };
+        conn.execute("UPDATE transfers SET blob_sha256 = NULL WHERE id = ?1", [transfer_id])?;
+        Ok(drop_if_unreferenced(conn, &sha256)?.then_some(sha256))
+    })?;
+
+    storage.delete_file(transfer_id).await?;
+    if let Some(sha256) = orphaned_blob {
+        storage.delete_blob(&sha256).await?;
+    }
+    Ok(())
+}
+
+/// Delete the blob row if no transfer references it. Returns whether it did,
+/// in which case the caller removes the blob file after committing.
+fn drop_if_unreferenced(conn: &Connection, sha256: &str) -> Result<bool> {
+    let refs: i64 = conn.query_row("SELECT COUNT(*) FROM transfers WHERE blob_sha256 = ?1", [sha256], |r| r.get(0))?;
+    if refs > 0 {
+        return Ok(false);
+    }
+    conn.execute("DELETE FROM blobs WHERE sha256 = ?1", [sha256])?;
+    Ok(true)
+}
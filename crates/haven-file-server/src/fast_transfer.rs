@@ -2,25 +2,38 @@
 ///
 /// Handles FastUploadStart / FastDownloadStart commands from clients,
 /// manages UDP receiver/sender pipelines, and sends control messages
-/// (FastNack, FastChunkAck, FastUploadDone, FastDownloadDone) back.
+/// (FastNack or FastNackBatch, FastChunkAckBitmap, FastUploadDone,
+/// FastDownloadDone) back.
+/// A FastCancel mid-upload stops the receiver and discards the partial file.
+/// An upload cut off any other way keeps its partial file and the record of
+/// which chunks it holds; FastResume reports the chunks still missing, and a
+/// FastUploadStart for the same file then only needs those. A FastFallback
+/// stops the UDP side of either direction and leaves the transfer to the
+/// HTTP routes, for clients whose UDP isn't getting through. FastPause holds
+/// a download's blaster (keepalives only) until the downloader resumes it.
 
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use axum::extract::ws::{Message, WebSocket};
 use crossbeam_channel::bounded;
 use futures_util::{SinkExt, StreamExt};
+use rusqlite::OptionalExtension;
 use serde::{Deserialize, Serialize};
-use tracing::{info, warn};
+use tracing::{Instrument, Span, info, info_span, warn};
 
 use haven_fast_transfer::{
-    NackMessage, ChunkAckMessage, RawSenderConfig, ReceiverConfig, ReceiverProgress,
-    SenderProgress, TracingLogger, run_raw_sender, run_receiver,
+    AeadAlgorithm, ChunkProgress, CongestionConfig, NackMessage, ChunkAckMessage, Pacing, RawSenderConfig, ReceiverConfig, ReceiverProgress,
+    MAX_CHUNK_RETRANSMITS, SENDER_CACHE_SIZE, STALL_TIMEOUT_SECS, SenderProgress, PooledSocket, SocketPool, SocketSpec, TracingLogger, check_chunk_layout, net,
+    resume, run_raw_sender, run_receiver,
 };
 
-use haven_types::api::Claims;
+use haven_types::api::{Claims, TransferStatus as TStatus};
 
+use crate::quota::{self, QuotaExceeded};
 use crate::routes::AppState;
+use crate::shutdown::TransferKind;
 
 /// WebSocket control messages for fast transfer (JSON, tagged union).
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,26 +47,105 @@ pub enum FastControlMessage {
         chunk_size: u64,
         chunk_hashes: Vec<String>,
         file_sha256: String,
+        /// Uploader's HMAC over the size/count/hash fields, keyed by the file
+        /// key. Stored opaquely and handed to downloaders, who verify it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        control_mac: Option<String>,
+        /// Chunks are zstd-compressed slots (`chunk_size` is the slot size).
+        #[serde(default)]
+        compression: bool,
+        /// Algorithm byte the chunks are sealed with (`AeadAlgorithm::id`);
+        /// absent means AES-256-GCM.
+        #[serde(default)]
+        aead: u8,
+        /// MIME type sniffed from the plaintext, as on `POST /transfers`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_type: Option<String>,
+        /// All-zero chunks the client won't blast (see
+        /// `haven_fast_transfer::sparse`); the receiver zeroes them itself.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        sparse_chunks: Vec<u32>,
+        /// The client understands FastNackBatch. Without it NACKs go out as
+        /// one FastNack per chunk, which every client handles.
+        #[serde(default)]
+        nack_batch: bool,
     },
     FastDownloadStart {
         transfer_id: String,
         udp_port: u16,
     },
+    /// Swap in a refreshed JWT mid-session. Must belong to the same user.
+    FastReauth {
+        token: String,
+    },
+    /// The uploader gave up: stop receiving, mark the transfer `cancelled`
+    /// and delete the partial file.
+    FastCancel {
+        transfer_id: String,
+    },
+    /// UDP isn't getting through: stop the receiver or blaster and leave the
+    /// transfer as it is (an upload stays `uploading`), since the client
+    /// carries on over the HTTP chunk routes.
+    FastFallback {
+        transfer_id: String,
+    },
+    /// Ask which chunks of an interrupted upload the server still needs,
+    /// before sending its FastUploadStart again. Stops a receiver still
+    /// waiting on this client's earlier connection.
+    FastResume {
+        transfer_id: String,
+    },
+    /// The downloader paused (`paused: true`) or resumed its transfer. The
+    /// blaster holds between chunks, sending only keepalives, while paused.
+    FastPause {
+        transfer_id: String,
+        paused: bool,
+    },
 
     // Server → Client
     FastUploadReady {
         transfer_id: String,
         udp_port: u16,
+        /// FEC parity the server wants blasted with the chunks, as
+        /// `FecRatio`'s `"parity/data"`; absent for none.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fec: Option<String>,
+    },
+    /// FastUploadStart was refused before any receiver was started.
+    FastUploadRejected {
+        transfer_id: String,
+        reason: String,
+    },
+    /// Reply to FastResume: the chunk indices to blast, or `None` if there
+    /// is nothing to resume (unknown, someone else's, or no longer uploading)
+    /// and the upload should start from scratch.
+    FastResumeState {
+        transfer_id: String,
+        missing_chunks: Option<Vec<u32>>,
     },
     FastNack {
         transfer_id: String,
         chunk_idx: u32,
         missing_frames: Vec<u16>,
     },
+    /// Every chunk the receiver NACKed in one scan, sent instead of one
+    /// FastNack per chunk when the other side can take it (`nack_batch` on
+    /// FastUploadStart). Both are accepted from downloaders.
+    FastNackBatch {
+        transfer_id: String,
+        nacks: Vec<ChunkNack>,
+    },
     FastChunkAck {
         transfer_id: String,
         chunk_idx: u32,
     },
+    /// A run of written chunks in one message: bit `i` of `bitmap`
+    /// (LSB-first) acks chunk `base_chunk + i`.
+    FastChunkAckBitmap {
+        transfer_id: String,
+        base_chunk: u32,
+        bitmap: Vec<u8>,
+    },
     FastUploadDone {
         transfer_id: String,
     },
@@ -63,17 +155,52 @@ pub enum FastControlMessage {
     FastDownloadDone {
         transfer_id: String,
     },
+    FastReauthResult {
+        ok: bool,
+    },
+}
+
+/// One chunk's entry in a FastNackBatch.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkNack {
+    pub chunk_idx: u32,
+    pub missing_frames: Vec<u16>,
+}
+
+impl From<NackMessage> for ChunkNack {
+    fn from(nack: NackMessage) -> Self {
+        Self { chunk_idx: nack.chunk_index, missing_frames: nack.missing_frames }
+    }
 }
 
 /// Handle a fast transfer WebSocket connection.
 ///
 /// This endpoint handles both upload and download control signaling.
 /// The JWT is passed as a query parameter: `/fast-transfer?token=...`
+///
+/// Everything the session logs, including its pipeline threads, runs in a
+/// `fast_transfer` span carrying a `session_id` and, once known, the
+/// `transfer_id`.
 pub async fn handle_fast_transfer_ws(
     socket: WebSocket,
     state: AppState,
     claims: Claims,
     peer_addr: SocketAddr,
+) {
+    let span = info_span!(
+        "fast_transfer",
+        session_id = %uuid::Uuid::new_v4(),
+        user = %claims.username,
+        transfer_id = tracing::field::Empty,
+    );
+    run_session(socket, state, claims, peer_addr).instrument(span).await
+}
+
+async fn run_session(
+    socket: WebSocket,
+    state: AppState,
+    mut claims: Claims,
+    peer_addr: SocketAddr,
 ) {
     let (mut ws_tx, mut ws_rx) = socket.split();
 
@@ -102,28 +229,140 @@ pub async fn handle_fast_transfer_ws(
                 chunk_size,
                 chunk_hashes,
                 file_sha256,
+                control_mac,
+                compression,
+                aead,
+                content_type,
+                sparse_chunks,
+                nack_batch,
             } => {
+                Span::current().record("transfer_id", transfer_id.as_str());
                 info!(
-                    "FastUploadStart: transfer={} size={} chunks={}",
-                    transfer_id, file_size, chunk_count
+                    "FastUploadStart: transfer={} size={} chunks={} compressed={} aead={}",
+                    transfer_id, file_size, chunk_count, compression, aead
                 );
 
-                // Create transfer record in DB
+                // Refuse an algorithm or chunk size we'd handle wrongly, or a file the
+                // policy forbids, before touching the DB
+                let layout = AeadAlgorithm::from_id(aead)
+                    .ok_or_else(|| format!("Unknown AEAD algorithm {}", aead))
+                    .and_then(|algorithm| {
+                        check_chunk_layout(file_size, chunk_count, chunk_size, compression, algorithm)?;
+                        if chunk_hashes.len() != chunk_count as usize {
+                            return Err(format!(
+                                "Chunk hash count {} does not match chunk count {}",
+                                chunk_hashes.len(), chunk_count
+                            ));
+                        }
+                        if compression && !sparse_chunks.is_empty() {
+                            return Err("Sparse chunks in a compressed transfer".into());
+                        }
+                        if let Some(&c) = sparse_chunks.iter().find(|&&c| c >= chunk_count) {
+                            return Err(format!("Sparse chunk {} out of range", c));
+                        }
+                        if let Some(ct) = content_type.as_deref()
+                            && !crate::routes::is_valid_content_type(ct)
+                        {
+                            return Err(format!("Invalid content type {:?}", ct));
+                        }
+                        state.file_policy.check_size(file_size).map_err(|e| e.to_string())?;
+                        state.file_policy.check_type(content_type.as_deref()).map_err(|e| e.to_string())?;
+                        Ok(algorithm)
+                    });
+                let algorithm = match layout {
+                    Ok(algorithm) => algorithm,
+                    Err(reason) => {
+                        warn!("FastUploadStart rejected: transfer={} {}", transfer_id, reason);
+                        let rejected = FastControlMessage::FastUploadRejected { transfer_id, reason };
+                        let _ = ws_tx
+                            .send(Message::Text(serde_json::to_string(&rejected).unwrap().into()))
+                            .await;
+                        continue;
+                    }
+                };
+
+                // Register with the shutdown drain; refused once it has begun
+                let progress = Arc::new(ReceiverProgress::new());
+                let progress_cancel = progress.clone();
+                let Some(guard) = state.transfers.begin(&transfer_id, TransferKind::Upload, move || {
+                    progress_cancel.cancelled.store(1, Ordering::Relaxed);
+                }) else {
+                    info!("FastUploadStart rejected: transfer={} server shutting down", transfer_id);
+                    let rejected = FastControlMessage::FastUploadRejected {
+                        transfer_id,
+                        reason: "Server is shutting down".into(),
+                    };
+                    let _ = ws_tx
+                        .send(Message::Text(serde_json::to_string(&rejected).unwrap().into()))
+                        .await;
+                    continue;
+                };
+
+                // Same limits as POST /transfers
+                if state.quota.min_free_bytes.is_some()
+                    && let Ok(available) = state.storage.available_space()
+                    && !quota::has_disk_headroom(&state.quota, available, file_size)
+                {
+                    warn!("FastUploadStart rejected: transfer={} only {} bytes free", transfer_id, available);
+                    let rejected = FastControlMessage::FastUploadRejected {
+                        transfer_id,
+                        reason: "Server storage is full".into(),
+                    };
+                    let _ = ws_tx
+                        .send(Message::Text(serde_json::to_string(&rejected).unwrap().into()))
+                        .await;
+                    continue;
+                }
+
+                // Create transfer record in DB, or resume one a shutdown interrupted
                 let retention_hours = state.retention_hours;
+                let quota_config = state.quota;
                 let tid = transfer_id.clone();
                 let uploader_id = claims.sub.to_string();
                 let hashes = chunk_hashes.clone();
                 let cs = chunk_size;
                 let fs = file_size;
                 let fsha = file_sha256.clone();
+                // Downloaders read these back, and check them against the MAC
+                let sparse_json = (!sparse_chunks.is_empty()).then(|| serde_json::json!(sparse_chunks).to_string());
+
+                let db_result = state.db.with_transaction(move |conn| {
+                    let existing: Option<(String, String, i64, i64, String, bool, u8)> = conn
+                        .query_row(
+                            "SELECT uploader_id, status, file_size, chunk_size, file_sha256, compressed, aead
+                             FROM transfers WHERE id = ?1",
+                            [&tid],
+                            |row| {
+                                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?))
+                            },
+                        )
+                        .optional()?;
+                    if let Some((uploader, status, size, csize, sha, compressed, stored_aead)) = existing {
+                        let resumable = uploader == uploader_id
+                            && status == TStatus::Interrupted.to_string()
+                            && size as u64 == fs
+                            && csize as u64 == cs
+                            && sha == fsha
+                            && compressed == compression
+                            && stored_aead == aead;
+                        if !resumable {
+                            anyhow::bail!("Transfer {} already exists", tid);
+                        }
+                        conn.execute(
+                            "UPDATE transfers SET status = ?1 WHERE id = ?2",
+                            rusqlite::params![TStatus::Uploading.to_string(), &tid],
+                        )?;
+                        return Ok(true);
+                    }
 
-                let db_result = state.db.with_conn_mut(move |conn| {
+                    quota::check_user_quota(conn, &quota_config, &uploader_id, fs)?;
                     conn.execute(
-                        "INSERT INTO transfers (id, uploader_id, file_size, chunk_size, chunk_count, file_sha256, expires_at)
-                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, datetime('now', '+' || ?7 || ' hours'))",
+                        "INSERT INTO transfers (id, uploader_id, file_size, chunk_size, chunk_count, file_sha256, control_mac, compressed, aead, content_type, sparse_chunks, expires_at)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, datetime('now', '+' || ?12 || ' hours'))",
                         rusqlite::params![
                             &tid, &uploader_id, fs as i64, cs as i64,
-                            chunk_count as i64, &fsha, retention_hours as i64,
+                            chunk_count as i64, &fsha, &control_mac, compression, aead, &content_type, &sparse_json,
+                            retention_hours as i64,
                         ],
                     )?;
 
@@ -141,23 +380,41 @@ pub async fn handle_fast_transfer_ws(
                         )?;
                         offset += length;
                     }
-                    Ok(())
+                    Ok(false)
                 });
 
-                if let Err(e) = db_result {
-                    warn!("FastUploadStart DB error: {}", e);
-                    continue;
-                }
+                let resumed = match db_result {
+                    Ok(resumed) => resumed,
+                    Err(e) => {
+                        if let Some(exceeded) = e.downcast_ref::<QuotaExceeded>() {
+                            warn!("FastUploadStart rejected: transfer={} {}", transfer_id, exceeded);
+                            let rejected = FastControlMessage::FastUploadRejected {
+                                transfer_id,
+                                reason: exceeded.to_string(),
+                            };
+                            let _ = ws_tx
+                                .send(Message::Text(serde_json::to_string(&rejected).unwrap().into()))
+                                .await;
+                        } else {
+                            warn!("FastUploadStart DB error: {}", e);
+                        }
+                        continue;
+                    }
+                };
 
-                // Pre-allocate file
-                if let Err(e) = state.storage.create_file(&transfer_id, file_size).await {
+                // Pre-allocate file, unless it holds an interrupted upload's chunks
+                if !resumed && let Err(e) = state.storage.create_file(&transfer_id, file_size).await {
                     warn!("FastUploadStart storage error: {}", e);
                     continue;
                 }
 
                 // Use the shared UDP socket (fixed port, bound at startup)
-                let udp_socket = match state.udp_socket.try_clone() {
-                    Ok(s) => s,
+                let udp_sockets = std::iter::once(&state.udp_socket)
+                    .chain(&state.udp_extra_sockets)
+                    .map(|s| s.try_clone().map(PooledSocket::from))
+                    .collect::<std::io::Result<Vec<_>>>();
+                let mut udp_sockets = match udp_sockets {
+                    Ok(s) => s.into_iter(),
                     Err(e) => {
                         warn!("FastUploadStart socket clone error: {}", e);
                         continue;
@@ -171,6 +428,7 @@ pub async fn handle_fast_transfer_ws(
                 let ready = FastControlMessage::FastUploadReady {
                     transfer_id: transfer_id.clone(),
                     udp_port,
+                    fec: state.fec.is_enabled().then(|| state.fec.to_string()),
                 };
                 let _ = ws_tx
                     .send(Message::Text(serde_json::to_string(&ready).unwrap().into()))
@@ -181,6 +439,9 @@ pub async fn handle_fast_transfer_ws(
                 let transfer_id_bytes = parse_transfer_id_bytes(&transfer_id);
                 let logger = Arc::new(TracingLogger);
 
+                // Channel to collect chunk ACK bitmaps from receiver → WS sender
+                let (ack_tx, ack_rx) = bounded::<(u32, Vec<u8>)>(256);
+
                 let receiver_config = ReceiverConfig {
                     output_path: output_path.to_string_lossy().into_owned(),
                     transfer_id: transfer_id_bytes,
@@ -191,46 +452,80 @@ pub async fn handle_fast_transfer_ws(
                     file_sha256: file_sha256.clone(),
                     bind_addr: format!("0.0.0.0:{}", udp_port).parse().unwrap(),
                     logger: Some(logger),
-                    pre_bound_socket: Some(udp_socket),
+                    pre_bound_socket: udp_sockets.next(),
+                    extra_sockets: udp_sockets.collect(),
+                    // We never hold the file key; downloaders verify the MAC.
+                    control_key: None,
+                    control_mac: None,
+                    ack_callback: Some({
+                        let dropped = AtomicU64::new(0);
+                        Box::new(move |base_chunk, bitmap| {
+                            queue_ack(&ack_tx, (base_chunk, bitmap), &dropped);
+                        })
+                    }),
+                    compressed: compression,
+                    aead: algorithm,
+                    stall_timeout: std::time::Duration::from_secs(STALL_TIMEOUT_SECS),
+                    // The shared socket was sized at startup from HAVEN_UDP_RECV_BUFFER.
+                    recv_buffer: None,
+                    // Keep what arrived if the client drops, for FastResume.
+                    resume: true,
+                    sync: state.upload_sync,
+                    sparse_chunks,
                 };
 
-                let progress = Arc::new(ReceiverProgress::new());
                 let progress_clone = progress.clone();
 
-                // Channel to collect NACKs from receiver → WS sender
-                let (nack_tx, nack_rx) = bounded::<(u32, Vec<u16>)>(256);
+                // Channel to collect NACK batches from receiver → WS sender
+                let (nack_tx, nack_rx) = bounded::<Vec<NackMessage>>(256);
 
-                let nack_callback: haven_fast_transfer::NackCallback =
-                    Box::new(move |chunk_idx, missing| {
-                        let _ = nack_tx.try_send((chunk_idx, missing));
-                    });
+                let nack_callback: haven_fast_transfer::NackCallback = Box::new(move |nacks| {
+                    let _ = nack_tx.try_send(nacks);
+                });
 
                 let tid_complete = transfer_id.clone();
                 let db_complete = state.db.clone();
+                let state_complete = state.clone();
 
                 // Start receiver in a blocking thread
+                let span_receiver = Span::current();
                 let receiver_handle = std::thread::spawn(move || {
+                    let _span = span_receiver.entered();
                     run_receiver(receiver_config, progress_clone, nack_callback)
                 });
 
                 // Event loop: forward NACKs to client, read WS messages, detect completion
                 let tid_ws = transfer_id.clone();
                 let progress_poll = progress.clone();
+                let mut client_cancelled = false;
+                let mut fell_back = false;
+                let mut ws_open = true;
                 loop {
                     // Check for NACKs from receiver (non-blocking batch drain)
                     let mut nacks_sent = 0;
-                    while let Ok((chunk_idx, missing)) = nack_rx.try_recv() {
-                        let nack_msg = FastControlMessage::FastNack {
+                    'nacks: while let Ok(nacks) = nack_rx.try_recv() {
+                        let chunks = nacks.len();
+                        for nack_msg in nack_messages(&tid_ws, nacks, nack_batch) {
+                            if ws_tx.send(Message::Text(serde_json::to_string(&nack_msg).unwrap().into())).await.is_err() {
+                                warn!("WS send failed for NACK of {} chunks", chunks);
+                                break 'nacks;
+                            }
+                        }
+                        nacks_sent += 1;
+                        if nacks_sent >= 50 { break; } // don't starve the loop
+                    }
+
+                    // Forward chunk ACK bitmaps so the client can free its cache
+                    while let Ok((base_chunk, bitmap)) = ack_rx.try_recv() {
+                        let ack_msg = FastControlMessage::FastChunkAckBitmap {
                             transfer_id: tid_ws.clone(),
-                            chunk_idx,
-                            missing_frames: missing,
+                            base_chunk,
+                            bitmap,
                         };
-                        if ws_tx.send(Message::Text(serde_json::to_string(&nack_msg).unwrap().into())).await.is_err() {
-                            warn!("WS send failed for NACK chunk {}", chunk_idx);
+                        if ws_tx.send(Message::Text(serde_json::to_string(&ack_msg).unwrap().into())).await.is_err() {
+                            warn!("WS send failed for chunk ACK bitmap at {}", base_chunk);
                             break;
                         }
-                        nacks_sent += 1;
-                        if nacks_sent >= 50 { break; } // don't starve the loop
                     }
 
                     // Check receiver state
@@ -248,13 +543,55 @@ pub async fn handle_fast_transfer_ws(
                         warn!("Fast upload receiver error state");
                         break;
                     }
+                    if progress_poll.is_cancelled() {
+                        warn!("Fast upload cancelled by server shutdown or a resuming client");
+                        break;
+                    }
 
-                    // Brief yield to not busy-spin
-                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                    // Brief yield to not busy-spin, listening for a cancel meanwhile
+                    let tick = tokio::time::sleep(std::time::Duration::from_millis(20));
+                    if !ws_open {
+                        tick.await;
+                        continue;
+                    }
+                    tokio::select! {
+                        msg = ws_rx.next() => match msg {
+                            Some(Ok(Message::Text(text))) => match serde_json::from_str::<FastControlMessage>(&text) {
+                                Ok(FastControlMessage::FastCancel { transfer_id }) if transfer_id == tid_ws => {
+                                    info!("Fast upload cancelled by client");
+                                    client_cancelled = true;
+                                    progress_poll.cancelled.store(1, Ordering::Relaxed);
+                                    break;
+                                }
+                                Ok(FastControlMessage::FastFallback { transfer_id }) if transfer_id == tid_ws => {
+                                    info!("Fast upload falling back to HTTP");
+                                    fell_back = true;
+                                    progress_poll.cancelled.store(1, Ordering::Relaxed);
+                                    break;
+                                }
+                                Ok(FastControlMessage::FastReauth { token }) => {
+                                    let ok = reauthenticate(&state, &mut claims, &token);
+                                    let result = FastControlMessage::FastReauthResult { ok };
+                                    let _ = ws_tx
+                                        .send(Message::Text(serde_json::to_string(&result).unwrap().into()))
+                                        .await;
+                                }
+                                _ => {}
+                            },
+                            Some(Ok(_)) => {}
+                            // The receiver runs on; it stalls out if the blast stopped too.
+                            Some(Err(_)) | None => ws_open = false,
+                        },
+                        _ = tick => {}
+                    }
                 }
 
-                // Wait for receiver thread to finish and update DB
+                // Wait for receiver thread to finish and update DB. The guard
+                // goes last so the shutdown drain sees the final status.
+                let span_complete = Span::current();
                 tokio::task::spawn_blocking(move || {
+                    let _span = span_complete.entered();
+                    let _guard = guard;
                     match receiver_handle.join() {
                         Ok(Ok(_)) => {
                             let _ = db_complete.with_conn_mut(|conn| {
@@ -265,9 +602,21 @@ pub async fn handle_fast_transfer_ws(
                                 Ok(())
                             });
                             info!("Fast upload complete: {}", tid_complete);
+                            crate::routes::spawn_index(&state_complete, tid_complete);
+                        }
+                        Ok(Err(_)) if client_cancelled => {
+                            tokio::runtime::Handle::current().block_on(discard_cancelled_upload(&state_complete, &tid_complete));
+                        }
+                        // The HTTP routes finish it; it may already be complete.
+                        Ok(Err(_)) if fell_back => {
+                            info!("Fast upload {} continues over HTTP", tid_complete);
                         }
                         Ok(Err(e)) => {
-                            warn!("Fast upload failed: {}: {}", tid_complete, e);
+                            // What arrived stays on disk for a FastResume.
+                            warn!("Fast upload interrupted: {}: {}", tid_complete, e);
+                            if let Err(e) = crate::shutdown::mark_interrupted(&db_complete, std::slice::from_ref(&tid_complete)) {
+                                warn!("Failed to mark interrupted upload {}: {}", tid_complete, e);
+                            }
                         }
                         Err(_) => {
                             warn!("Fast upload receiver panicked: {}", tid_complete);
@@ -283,6 +632,7 @@ pub async fn handle_fast_transfer_ws(
                 transfer_id,
                 udp_port,
             } => {
+                Span::current().record("transfer_id", transfer_id.as_str());
                 info!(
                     "FastDownloadStart: transfer={} receiver_port={}",
                     transfer_id, udp_port
@@ -325,19 +675,19 @@ pub async fn handle_fast_transfer_ws(
                 }
 
                 // Get chunk count
-                let chunk_count = if file_size == 0 {
-                    1u32
-                } else {
-                    ((file_size as f64 / chunk_size as f64).ceil()) as u32
-                };
+                let chunk_count = haven_fast_transfer::chunk_count(file_size, chunk_size);
 
                 // Wait for UDP hole-punch packet from client to learn their NAT-mapped address.
                 // We bind a temporary UDP socket to receive the punch, avoiding conflicts
                 // with the main upload receiver on port 3211.
                 let transfer_id_bytes = parse_transfer_id_bytes(&transfer_id);
                 let target_addr = {
-                    // Bind a temporary socket on an ephemeral port for punch detection
-                    let punch_socket = std::net::UdpSocket::bind("0.0.0.0:0")
+                    // Take an ephemeral socket from the pool for punch detection.
+                    // Same address family as the main socket, so it hears the same clients.
+                    let punch_socket = state
+                        .udp_socket
+                        .local_addr()
+                        .and_then(|main| SocketPool::global().checkout(SocketSpec::receiver(net::unspecified_for(main))))
                         .map_err(|e| format!("Punch socket bind: {}", e));
 
                     match punch_socket {
@@ -411,20 +761,44 @@ pub async fn handle_fast_transfer_ws(
                     file_size,
                     chunk_size,
                     chunk_count,
+                    probe_mtu: true,
+                    fec: state.fec,
+                    stall_timeout: std::time::Duration::from_secs(STALL_TIMEOUT_SECS),
+                    cache_size: SENDER_CACHE_SIZE,
+                    max_rate_bps: 0,
+                    max_chunk_retransmits: MAX_CHUNK_RETRANSMITS,
+                    pacing: Pacing::default(),
+                    congestion: CongestionConfig::default(),
                     logger: Some(logger),
                 };
 
                 let sender_progress = Arc::new(SenderProgress::new());
+                let sender_cancel = sender_progress.clone();
+                let sender_pause = sender_progress.clone();
                 let tid_done = transfer_id.clone();
 
-                // Start sender in blocking thread
+                // Register with the shutdown drain. Downloads leave nothing to
+                // clean up, so one starting mid-drain just isn't waited for.
+                let progress_cancel = sender_progress.clone();
+                let guard = state.transfers.begin(&transfer_id, TransferKind::Download, move || {
+                    progress_cancel.cancelled.store(1, Ordering::Relaxed);
+                });
+                if guard.is_none() {
+                    warn!("FastDownloadStart: {} started during shutdown, not drained", transfer_id);
+                }
+
+                // Start sender in blocking thread; the guard lives as long as it
+                let span_sender = Span::current();
                 let sender_handle = std::thread::spawn(move || {
+                    let _span = span_sender.entered();
+                    let _guard = guard;
                     run_raw_sender(sender_config, sender_progress, nack_rx, ack_rx)
                 });
 
                 // Process incoming WS messages (NACKs from client) while sender runs
                 let nack_tx_clone = nack_tx;
                 let ack_tx_clone = ack_tx;
+                let acks_dropped = AtomicU64::new(0);
 
                 // Read NACKs/ACKs from WS and feed to sender
                 while let Some(Ok(msg)) = ws_rx.next().await {
@@ -446,10 +820,35 @@ pub async fn handle_fast_transfer_ws(
                                     missing_frames,
                                 });
                             }
+                            FastControlMessage::FastNackBatch { nacks, .. } => {
+                                for nack in nacks {
+                                    let _ = nack_tx_clone.try_send(NackMessage {
+                                        chunk_index: nack.chunk_idx,
+                                        missing_frames: nack.missing_frames,
+                                    });
+                                }
+                            }
                             FastControlMessage::FastChunkAck { chunk_idx, .. } => {
-                                let _ = ack_tx_clone.try_send(ChunkAckMessage {
-                                    chunk_index: chunk_idx,
-                                });
+                                queue_ack(&ack_tx_clone, ChunkAckMessage::Chunk { chunk_index: chunk_idx }, &acks_dropped);
+                            }
+                            FastControlMessage::FastChunkAckBitmap { base_chunk, bitmap, .. } => {
+                                queue_ack(&ack_tx_clone, ChunkAckMessage::Bitmap { base_chunk, bitmap }, &acks_dropped);
+                            }
+                            FastControlMessage::FastFallback { transfer_id: tid } if tid == transfer_id => {
+                                info!("Fast download falling back to HTTP");
+                                sender_cancel.cancelled.store(1, Ordering::Relaxed);
+                                break;
+                            }
+                            FastControlMessage::FastPause { transfer_id: tid, paused } if tid == transfer_id => {
+                                info!("Fast download {}", if paused { "paused" } else { "resumed" });
+                                sender_pause.paused.store(paused as u8, Ordering::Relaxed);
+                            }
+                            FastControlMessage::FastReauth { token } => {
+                                let ok = reauthenticate(&state, &mut claims, &token);
+                                let result = FastControlMessage::FastReauthResult { ok };
+                                let _ = ws_tx
+                                    .send(Message::Text(serde_json::to_string(&result).unwrap().into()))
+                                    .await;
                             }
                             _ => {}
                         }
@@ -457,7 +856,9 @@ pub async fn handle_fast_transfer_ws(
                 }
 
                 // WS closed or sender finished — wait for sender
+                let span_done = Span::current();
                 tokio::task::spawn_blocking(move || {
+                    let _span = span_done.entered();
                     match sender_handle.join() {
                         Ok(Ok(_)) => {
                             info!("Fast download blast complete: {}", tid_done);
@@ -483,6 +884,32 @@ pub async fn handle_fast_transfer_ws(
                 break;
             }
 
+            FastControlMessage::FastReauth { token } => {
+                let ok = reauthenticate(&state, &mut claims, &token);
+                let result = FastControlMessage::FastReauthResult { ok };
+                let _ = ws_tx
+                    .send(Message::Text(serde_json::to_string(&result).unwrap().into()))
+                    .await;
+            }
+
+            FastControlMessage::FastResume { transfer_id } => {
+                Span::current().record("transfer_id", transfer_id.as_str());
+                let missing_chunks = match missing_chunks(&state, &claims.sub.to_string(), &transfer_id).await {
+                    Ok(missing) => {
+                        info!("FastResume: transfer={} needs {} chunk(s)", transfer_id, missing.len());
+                        Some(missing)
+                    }
+                    Err(reason) => {
+                        info!("FastResume: transfer={} not resumable: {}", transfer_id, reason);
+                        None
+                    }
+                };
+                let reply = FastControlMessage::FastResumeState { transfer_id, missing_chunks };
+                let _ = ws_tx
+                    .send(Message::Text(serde_json::to_string(&reply).unwrap().into()))
+                    .await;
+            }
+
             _ => {
                 warn!("Unexpected fast transfer message from client");
             }
@@ -492,6 +919,137 @@ pub async fn handle_fast_transfer_ws(
     info!("Fast transfer WS disconnected: user={}", claims.username);
 }
 
+/// How long FastResume waits for a receiver still running from the client's
+/// previous connection to stop.
+const RESUME_CANCEL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Chunks `uploader_id`'s upload `transfer_id` still needs, per the record
+/// its receiver kept beside the partial file. A receiver still running for
+/// it (the client's old connection died but it hasn't stalled out yet) is
+/// stopped first, leaving the transfer `interrupted` so the next
+/// FastUploadStart resumes it.
+async fn missing_chunks(state: &AppState, uploader_id: &str, transfer_id: &str) -> Result<Vec<u32>, String> {
+    let row: Option<(String, String, u64, u64, u32)> = state
+        .db
+        .with_conn(|conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT uploader_id, status, file_size, chunk_size, chunk_count FROM transfers WHERE id = ?1",
+                    [transfer_id],
+                    |row| {
+                        Ok((
+                            row.get(0)?,
+                            row.get(1)?,
+                            row.get::<_, i64>(2)? as u64,
+                            row.get::<_, i64>(3)? as u64,
+                            row.get::<_, i64>(4)? as u32,
+                        ))
+                    },
+                )
+                .optional()?)
+        })
+        .map_err(|e| e.to_string())?;
+    let Some((uploader, status, file_size, chunk_size, chunk_count)) = row else {
+        return Err("unknown transfer".into());
+    };
+    if uploader != uploader_id {
+        return Err("uploaded by someone else".into());
+    }
+    if status == TStatus::Uploading.to_string() {
+        if !state.transfers.cancel(transfer_id, TransferKind::Upload, RESUME_CANCEL_TIMEOUT).await {
+            return Err("previous receiver did not stop".into());
+        }
+        // Also covers a row left `uploading` by a server that died mid-upload.
+        crate::shutdown::mark_interrupted(&state.db, &[transfer_id.to_string()]).map_err(|e| e.to_string())?;
+    } else if status != TStatus::Interrupted.to_string() {
+        return Err(format!("status is {}", status));
+    }
+
+    let record = resume::state_path(&state.storage.file_path(transfer_id));
+    let missing = ChunkProgress::load(&record)
+        .ok()
+        .flatten()
+        .filter(|p| p.fits(&parse_transfer_id_bytes(transfer_id), file_size, chunk_size, chunk_count))
+        .map(|p| p.missing())
+        .unwrap_or_else(|| (0..chunk_count).collect());
+    Ok(missing)
+}
+
+/// Delete a client-cancelled upload's partial file and mark it `cancelled`.
+async fn discard_cancelled_upload(state: &AppState, transfer_id: &str) {
+    if let Err(e) = state.storage.delete_file(transfer_id).await {
+        warn!("Failed to delete cancelled upload {}: {}", transfer_id, e);
+    }
+    let marked = state.db.with_conn_mut(|conn| {
+        conn.execute(
+            "UPDATE transfers SET status = ?1 WHERE id = ?2",
+            rusqlite::params![TStatus::Cancelled.to_string(), transfer_id],
+        )?;
+        Ok(())
+    });
+    if let Err(e) = marked {
+        warn!("Failed to mark cancelled upload {}: {}", transfer_id, e);
+    }
+    info!("Fast upload cancelled: {}", transfer_id);
+}
+
+/// Validate a `FastReauth` token and, if it's for the same user, adopt its claims.
+///
+/// The WS token is only checked at upgrade time; a long download lets the
+/// client prove it still holds a live session without restarting the transfer.
+fn reauthenticate(state: &AppState, claims: &mut Claims, token: &str) -> bool {
+    match crate::routes::decode_token(token, state) {
+        Ok(fresh) if fresh.sub == claims.sub => {
+            info!("Fast transfer re-authenticated: user={}", fresh.username);
+            *claims = fresh;
+            true
+        }
+        Ok(fresh) => {
+            warn!(
+                "Fast transfer re-auth rejected: token for {} on session of {}",
+                fresh.username, claims.username
+            );
+            false
+        }
+        Err(_) => {
+            warn!("Fast transfer re-auth rejected: invalid token for {}", claims.username);
+            false
+        }
+    }
+}
+
+/// Hand a chunk ACK to the other side of the pipeline without blocking.
+/// A full queue drops it (the chunk stays cached or unreported until a
+/// later ACK covers it); drops are counted in `dropped` and logged at the
+/// 1st, 2nd, 4th, 8th... so a stalled consumer shows without flooding.
+fn queue_ack<T>(tx: &crossbeam_channel::Sender<T>, ack: T, dropped: &AtomicU64) {
+    if let Err(crossbeam_channel::TrySendError::Full(_)) = tx.try_send(ack) {
+        let n = dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if n.is_power_of_two() {
+            warn!("Chunk ACK queue full: {} ACKs dropped so far", n);
+        }
+    }
+}
+
+/// One receiver scan's NACKs as control messages: a single FastNackBatch
+/// for clients that asked for it, else a FastNack per chunk.
+fn nack_messages(transfer_id: &str, nacks: Vec<NackMessage>, batch: bool) -> Vec<FastControlMessage> {
+    if batch {
+        return vec![FastControlMessage::FastNackBatch {
+            transfer_id: transfer_id.to_string(),
+            nacks: nacks.into_iter().map(ChunkNack::from).collect(),
+        }];
+    }
+    nacks
+        .into_iter()
+        .map(|nack| FastControlMessage::FastNack {
+            transfer_id: transfer_id.to_string(),
+            chunk_idx: nack.chunk_index,
+            missing_frames: nack.missing_frames,
+        })
+        .collect()
+}
+
 /// Parse a transfer ID string into 16 bytes (UUID without hyphens, or truncated hash).
 fn parse_transfer_id_bytes(transfer_id: &str) -> [u8; 16] {
     let stripped = transfer_id.replace('-', "");
@@ -508,3 +1066,451 @@ fn parse_transfer_id_bytes(transfer_id: &str) -> [u8; 16] {
     arr.copy_from_slice(&hash[..16]);
     arr
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use haven_fast_transfer::FecRatio;
+    use haven_fast_transfer::protocol::{MIN_CHUNK_SIZE, encrypted_chunk_size};
+    use sha2::{Digest, Sha256};
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+    use uuid::Uuid;
+
+    use crate::harness::{app_state, token};
+
+    /// A file server on loopback, returning its state and HTTP port.
+    async fn serve(dir: &std::path::Path) -> (AppState, u16) {
+        serve_state(app_state(dir).await).await
+    }
+
+    async fn serve_state(state: AppState) -> (AppState, u16) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app = crate::build_router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (state, port)
+    }
+
+    fn status(state: &AppState, transfer_id: &str) -> String {
+        state
+            .db
+            .with_conn(|c| Ok(c.query_row("SELECT status FROM transfers WHERE id = ?1", [transfer_id], |r| r.get(0))?))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fast_cancel_discards_partial_upload() {
+        let dir = std::env::temp_dir().join(format!("haven-fs-cancel-{}", std::process::id()));
+        let (state, port) = serve(&dir).await;
+
+        let url = format!("ws://127.0.0.1:{port}/fast-transfer?token={}", token(Uuid::new_v4(), 3600));
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        // Start an upload, then cancel without sending a single datagram.
+        let transfer_id = Uuid::new_v4().to_string();
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE) as u64;
+        let start = FastControlMessage::FastUploadStart {
+            transfer_id: transfer_id.clone(),
+            file_size: 2 * chunk_size,
+            chunk_count: 2,
+            chunk_size,
+            chunk_hashes: vec!["00".repeat(32); 2],
+            file_sha256: "00".repeat(32),
+            control_mac: None,
+            compression: false,
+            aead: 0,
+            content_type: None,
+            sparse_chunks: Vec::new(),
+            nack_batch: false,
+        };
+        ws.send(WsMessage::Text(serde_json::to_string(&start).unwrap().into())).await.unwrap();
+        loop {
+            let Some(Ok(WsMessage::Text(text))) = ws.next().await else { panic!("no FastUploadReady") };
+            if let Ok(FastControlMessage::FastUploadReady { .. }) = serde_json::from_str(&text) {
+                break;
+            }
+        }
+        let path = state.storage.file_path(&transfer_id);
+        assert!(path.exists());
+
+        let cancel = FastControlMessage::FastCancel { transfer_id: transfer_id.clone() };
+        ws.send(WsMessage::Text(serde_json::to_string(&cancel).unwrap().into())).await.unwrap();
+
+        // Well within the stall timeout the receiver has gone and cleaned up.
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while status(&state, &transfer_id) != TStatus::Cancelled.to_string() && std::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(status(&state, &transfer_id), TStatus::Cancelled.to_string());
+        assert!(!path.exists(), "partial file left behind");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_upload_ready_asks_for_configured_fec() {
+        let dir = std::env::temp_dir().join(format!("haven-fs-fec-{}", std::process::id()));
+        let fec = FecRatio::new(4, 32).unwrap();
+        let (_state, port) = serve_state(AppState { fec, ..app_state(&dir).await }).await;
+
+        let url = format!("ws://127.0.0.1:{port}/fast-transfer?token={}", token(Uuid::new_v4(), 3600));
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE) as u64;
+        let start = FastControlMessage::FastUploadStart {
+            transfer_id: Uuid::new_v4().to_string(),
+            file_size: chunk_size,
+            chunk_count: 1,
+            chunk_size,
+            chunk_hashes: vec!["00".repeat(32)],
+            file_sha256: "00".repeat(32),
+            control_mac: None,
+            compression: false,
+            aead: 0,
+            content_type: None,
+            sparse_chunks: Vec::new(),
+            nack_batch: false,
+        };
+        ws.send(WsMessage::Text(serde_json::to_string(&start).unwrap().into())).await.unwrap();
+        let asked = loop {
+            let Some(Ok(WsMessage::Text(text))) = ws.next().await else { panic!("no FastUploadReady") };
+            if let Ok(FastControlMessage::FastUploadReady { fec, .. }) = serde_json::from_str(&text) {
+                break fec;
+            }
+        };
+        assert_eq!(asked.as_deref().map(FecRatio::parse), Some(Ok(fec)));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_fast_fallback_leaves_upload_to_http() {
+        let dir = std::env::temp_dir().join(format!("haven-fs-fallback-{}", std::process::id()));
+        let (state, port) = serve(&dir).await;
+        let token = token(Uuid::new_v4(), 3600);
+
+        let url = format!("ws://127.0.0.1:{port}/fast-transfer?token={token}");
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        let transfer_id = Uuid::new_v4().to_string();
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE) as u64;
+        let chunks = [vec![1u8; chunk_size as usize], vec![2u8; 100]];
+        let start = FastControlMessage::FastUploadStart {
+            transfer_id: transfer_id.clone(),
+            file_size: chunk_size + 100,
+            chunk_count: 2,
+            chunk_size,
+            chunk_hashes: chunks.iter().map(|c| hex::encode(Sha256::digest(c))).collect(),
+            file_sha256: hex::encode(Sha256::digest(chunks.concat())),
+            control_mac: None,
+            compression: false,
+            aead: 0,
+            content_type: None,
+            sparse_chunks: Vec::new(),
+            nack_batch: false,
+        };
+        ws.send(WsMessage::Text(serde_json::to_string(&start).unwrap().into())).await.unwrap();
+        loop {
+            let Some(Ok(WsMessage::Text(text))) = ws.next().await else { panic!("no FastUploadReady") };
+            if let Ok(FastControlMessage::FastUploadReady { .. }) = serde_json::from_str(&text) {
+                break;
+            }
+        }
+
+        // No datagram ever arrives; the client gives up on UDP.
+        let fallback = FastControlMessage::FastFallback { transfer_id: transfer_id.clone() };
+        ws.send(WsMessage::Text(serde_json::to_string(&fallback).unwrap().into())).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(status(&state, &transfer_id), TStatus::Uploading.to_string());
+
+        let client = reqwest::Client::new();
+        for (idx, chunk) in chunks.iter().enumerate() {
+            let resp = client
+                .put(format!("http://127.0.0.1:{port}/transfers/{transfer_id}/chunks/{idx}"))
+                .bearer_auth(&token)
+                .body(chunk.clone())
+                .send()
+                .await
+                .unwrap();
+            assert!(resp.status().is_success(), "chunk {idx}: {}", resp.status());
+        }
+        assert_eq!(status(&state, &transfer_id), TStatus::Complete.to_string());
+        assert_eq!(std::fs::read(state.storage.file_path(&transfer_id)).unwrap(), chunks.concat());
+
+        // The stopped receiver doesn't mark it interrupted afterwards.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(status(&state, &transfer_id), TStatus::Complete.to_string());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_upload_start_records_aead_and_sparse_chunks() {
+        let dir = std::env::temp_dir().join(format!("haven-fs-aead-{}", std::process::id()));
+        let (state, port) = serve(&dir).await;
+        let url = format!("ws://127.0.0.1:{port}/fast-transfer?token={}", token(Uuid::new_v4(), 3600));
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        let chunk_size = AeadAlgorithm::ChaCha20Poly1305.encrypted_chunk_size(MIN_CHUNK_SIZE) as u64;
+        // One chunk, stored as a zero slot.
+        let zero_hash = hex::encode(Sha256::digest(vec![0u8; chunk_size as usize]));
+        let start = |transfer_id: &str, aead: u8| FastControlMessage::FastUploadStart {
+            transfer_id: transfer_id.into(),
+            file_size: chunk_size,
+            chunk_count: 1,
+            chunk_size,
+            chunk_hashes: vec![zero_hash.clone()],
+            file_sha256: zero_hash.clone(),
+            control_mac: None,
+            compression: false,
+            aead,
+            content_type: None,
+            sparse_chunks: vec![0],
+            nack_batch: false,
+        };
+
+        // An algorithm this server doesn't know is refused before any record.
+        let unknown = Uuid::new_v4().to_string();
+        ws.send(WsMessage::Text(serde_json::to_string(&start(&unknown, 9)).unwrap().into())).await.unwrap();
+        match next_message(&mut ws).await {
+            FastControlMessage::FastUploadRejected { reason, .. } => assert!(reason.contains("AEAD"), "{reason}"),
+            other => panic!("expected FastUploadRejected, got {other:?}"),
+        }
+        let count: i64 = state
+            .db
+            .with_conn(|c| Ok(c.query_row("SELECT COUNT(*) FROM transfers WHERE id = ?1", [&unknown], |r| r.get(0))?))
+            .unwrap();
+        assert_eq!(count, 0);
+
+        // ChaCha20-Poly1305 and the sparse chunks are stored for downloaders
+        // to read back.
+        let chacha = Uuid::new_v4().to_string();
+        let id = AeadAlgorithm::ChaCha20Poly1305.id();
+        ws.send(WsMessage::Text(serde_json::to_string(&start(&chacha, id)).unwrap().into())).await.unwrap();
+        while !matches!(next_message(&mut ws).await, FastControlMessage::FastUploadReady { .. }) {}
+        let stored: (u8, String) = state
+            .db
+            .with_conn(|c| {
+                Ok(c.query_row("SELECT aead, sparse_chunks FROM transfers WHERE id = ?1", [&chacha], |r| {
+                    Ok((r.get(0)?, r.get(1)?))
+                })?)
+            })
+            .unwrap();
+        assert_eq!(stored, (id, "[0]".to_string()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Blast one chunk's frames at the server, again every 200ms until `done`.
+    async fn blast_until(udp: &std::net::UdpSocket, port: u16, transfer_id: &[u8; 16], chunk_index: u32, data: &[u8], done: impl Fn() -> bool) {
+        use haven_fast_transfer::{FRAME_PAYLOAD, MAX_FRAME, encode_frame};
+        let frames: Vec<&[u8]> = data.chunks(FRAME_PAYLOAD).collect();
+        let mut buf = vec![0u8; MAX_FRAME];
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(std::time::Instant::now() < deadline, "chunk {chunk_index} never landed");
+            for (i, frame) in frames.iter().enumerate() {
+                let n = encode_frame(&mut buf, transfer_id, chunk_index, i as u16, frames.len() as u16, frame);
+                udp.send_to(&buf[..n], ("127.0.0.1", port)).unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+
+    async fn next_message(ws: &mut (impl StreamExt<Item = Result<WsMessage, tokio_tungstenite::tungstenite::Error>> + Unpin)) -> FastControlMessage {
+        loop {
+            let Some(Ok(WsMessage::Text(text))) = ws.next().await else { panic!("WS closed") };
+            if let Ok(msg) = serde_json::from_str(&text) {
+                return msg;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dropped_upload_resumes_with_missing_chunks() {
+        let dir = std::env::temp_dir().join(format!("haven-fs-fast-resume-{}", std::process::id()));
+        let (state, port) = serve(&dir).await;
+        let url = format!("ws://127.0.0.1:{port}/fast-transfer?token={}", token(Uuid::new_v4(), 3600));
+
+        let transfer_id = Uuid::new_v4().to_string();
+        let tid_bytes = parse_transfer_id_bytes(&transfer_id);
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE);
+        let data: Vec<u8> = (0..3 * chunk_size - 500).map(|i| (i % 253) as u8).collect();
+        let chunks: Vec<&[u8]> = data.chunks(chunk_size).collect();
+        let start = FastControlMessage::FastUploadStart {
+            transfer_id: transfer_id.clone(),
+            file_size: data.len() as u64,
+            chunk_count: 3,
+            chunk_size: chunk_size as u64,
+            chunk_hashes: chunks.iter().map(|c| hex::encode(Sha256::digest(c))).collect(),
+            file_sha256: hex::encode(Sha256::digest(&data)),
+            control_mac: None,
+            compression: false,
+            aead: 0,
+            content_type: None,
+            sparse_chunks: Vec::new(),
+            nack_batch: false,
+        };
+        let start = serde_json::to_string(&start).unwrap();
+        let record = resume::state_path(&state.storage.file_path(&transfer_id));
+        let recorded = |c: u32| {
+            ChunkProgress::load(&record).ok().flatten().is_some_and(|p| p.contains(c))
+        };
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        // Chunks 0 and 2 land, then the connection drops.
+        let (mut ws, _) = tokio_tungstenite::connect_async(url.as_str()).await.unwrap();
+        ws.send(WsMessage::Text(start.clone().into())).await.unwrap();
+        while !matches!(next_message(&mut ws).await, FastControlMessage::FastUploadReady { .. }) {}
+        blast_until(&udp, state.udp_port, &tid_bytes, 0, chunks[0], || recorded(0)).await;
+        blast_until(&udp, state.udp_port, &tid_bytes, 2, chunks[2], || recorded(2)).await;
+        drop(ws);
+
+        // The reconnected client learns only chunk 1 is needed; the old
+        // receiver is stopped rather than left to stall out.
+        let (mut ws, _) = tokio_tungstenite::connect_async(url.as_str()).await.unwrap();
+        let resume_msg = FastControlMessage::FastResume { transfer_id: transfer_id.clone() };
+        ws.send(WsMessage::Text(serde_json::to_string(&resume_msg).unwrap().into())).await.unwrap();
+        match next_message(&mut ws).await {
+            FastControlMessage::FastResumeState { missing_chunks, .. } => assert_eq!(missing_chunks, Some(vec![1])),
+            other => panic!("expected FastResumeState, got {other:?}"),
+        }
+        assert_eq!(status(&state, &transfer_id), TStatus::Interrupted.to_string());
+
+        ws.send(WsMessage::Text(start.into())).await.unwrap();
+        while !matches!(next_message(&mut ws).await, FastControlMessage::FastUploadReady { .. }) {}
+        let complete = || status(&state, &transfer_id) == TStatus::Complete.to_string();
+        blast_until(&udp, state.udp_port, &tid_bytes, 1, chunks[1], complete).await;
+
+        assert_eq!(std::fs::read(state.storage.file_path(&transfer_id)).unwrap(), data);
+        assert!(!record.exists());
+
+        // Someone else can't probe it, and a finished upload has nothing to resume.
+        let (mut other, _) = tokio_tungstenite::connect_async(
+            format!("ws://127.0.0.1:{port}/fast-transfer?token={}", token(Uuid::new_v4(), 3600)),
+        )
+        .await
+        .unwrap();
+        other.send(WsMessage::Text(serde_json::to_string(&resume_msg).unwrap().into())).await.unwrap();
+        match next_message(&mut other).await {
+            FastControlMessage::FastResumeState { missing_chunks, .. } => assert_eq!(missing_chunks, None),
+            other => panic!("expected FastResumeState, got {other:?}"),
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Chunk indices of the frames arriving on `udp` over `window`.
+    async fn chunks_arriving(udp: &tokio::net::UdpSocket, window: Duration) -> std::collections::BTreeSet<u32> {
+        let mut chunks = std::collections::BTreeSet::new();
+        let mut buf = vec![0u8; haven_fast_transfer::MAX_FRAME];
+        let _ = tokio::time::timeout(window, async {
+            while let Ok(n) = udp.recv(&mut buf).await {
+                if let Some(header) = haven_fast_transfer::decode_frame_header(&buf[..n]) {
+                    chunks.insert(header.chunk_index);
+                }
+            }
+        })
+        .await;
+        chunks
+    }
+
+    // Multi-threaded: the server blocks a thread waiting for the punch.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_fast_pause_holds_the_download_blaster() {
+        use haven_fast_transfer::KEEPALIVE_CHUNK_INDEX;
+
+        let dir = std::env::temp_dir().join(format!("haven-fs-pause-{}", std::process::id()));
+        let (state, port) = serve(&dir).await;
+
+        // A complete four-chunk transfer to download.
+        let transfer_id = Uuid::new_v4().to_string();
+        let chunk_size = encrypted_chunk_size(MIN_CHUNK_SIZE) as u64;
+        let chunk_count = 4;
+        state.storage.create_file(&transfer_id, chunk_count * chunk_size).await.unwrap();
+        state
+            .db
+            .with_conn_mut(|conn| {
+                conn.execute(
+                    "INSERT INTO transfers (id, uploader_id, file_size, chunk_size, chunk_count, file_sha256, bytes_received, status)
+                     VALUES (?1, ?2, ?3, ?4, ?5, '', ?3, 'complete')",
+                    rusqlite::params![
+                        transfer_id,
+                        Uuid::new_v4().to_string(),
+                        (chunk_count * chunk_size) as i64,
+                        chunk_size as i64,
+                        chunk_count as i64
+                    ],
+                )?;
+                Ok(())
+            })
+            .unwrap();
+
+        let url = format!("ws://127.0.0.1:{port}/fast-transfer?token={}", token(Uuid::new_v4(), 3600));
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let udp = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let send = |msg: FastControlMessage| WsMessage::Text(serde_json::to_string(&msg).unwrap().into());
+
+        let start = FastControlMessage::FastDownloadStart {
+            transfer_id: transfer_id.clone(),
+            udp_port: udp.local_addr().unwrap().port(),
+        };
+        ws.send(send(start)).await.unwrap();
+        let punch_port = loop {
+            let Some(Ok(WsMessage::Text(text))) = ws.next().await else { panic!("no FastPunchPort") };
+            let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+            if msg["type"] == "FastPunchPort" {
+                break msg["data"]["port"].as_u64().unwrap() as u16;
+            }
+        };
+        // Paused before the blaster starts, so it sends no chunk at all.
+        let pause = |paused| FastControlMessage::FastPause { transfer_id: transfer_id.clone(), paused };
+        ws.send(send(pause(true))).await.unwrap();
+        udp.send_to(&parse_transfer_id_bytes(&transfer_id), ("127.0.0.1", punch_port)).await.unwrap();
+
+        let paused = chunks_arriving(&udp, Duration::from_millis(2500)).await;
+        assert!(paused.contains(&KEEPALIVE_CHUNK_INDEX), "no keepalive while paused: {paused:?}");
+        assert!(paused.iter().all(|&idx| idx >= chunk_count as u32), "chunks sent while paused: {paused:?}");
+
+        ws.send(send(pause(false))).await.unwrap();
+        let resumed = chunks_arriving(&udp, Duration::from_secs(2)).await;
+        assert!((0..chunk_count as u32).all(|idx| resumed.contains(&idx)), "chunks missing after resume: {resumed:?}");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_nacks_batched_only_for_clients_that_ask() {
+        let nacks = || {
+            vec![
+                NackMessage { chunk_index: 3, missing_frames: vec![1, 2] },
+                NackMessage { chunk_index: 7, missing_frames: vec![0] },
+            ]
+        };
+        let types = |batch| {
+            nack_messages("t", nacks(), batch)
+                .iter()
+                .map(|m| serde_json::to_value(m).unwrap()["type"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(types(false), ["FastNack", "FastNack"]);
+        assert_eq!(types(true), ["FastNackBatch"]);
+
+        match &nack_messages("t", nacks(), false)[1] {
+            FastControlMessage::FastNack { chunk_idx, missing_frames, .. } => {
+                assert_eq!((*chunk_idx, missing_frames.as_slice()), (7, &[0][..]))
+            }
+            other => panic!("expected FastNack, got {other:?}"),
+        }
+        let old_client: FastControlMessage = serde_json::from_value(serde_json::json!({
+            "type": "FastUploadStart",
+            "data": {
+                "transfer_id": "t", "file_size": 1, "chunk_count": 1, "chunk_size": 1,
+                "chunk_hashes": [], "file_sha256": "",
+            }
+        }))
+        .unwrap();
+        assert!(matches!(old_client, FastControlMessage::FastUploadStart { nack_batch: false, .. }));
+    }
+}
@@ -0,0 +1,62 @@
+//! Test fixtures shared by the route, fast-transfer and TLS tests: a JWT
+//! signed with the test secret, and an [`AppState`] over a fresh database
+//! and storage directory.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use uuid::Uuid;
+
+use haven_types::api::Claims;
+
+use crate::db::FileDb;
+use crate::routes::AppState;
+use crate::storage::Storage;
+
+/// What [`app_state`] verifies tokens with.
+pub const SECRET: &str = "test-secret";
+
+/// A user token that expires `expires_in_secs` from now (in the past if
+/// negative).
+pub fn token(user_id: Uuid, expires_in_secs: i64) -> String {
+    let claims = Claims {
+        sub: user_id,
+        username: "tester".into(),
+        exp: (chrono::Utc::now().timestamp() + expires_in_secs) as usize,
+        iss: None,
+        aud: None,
+    };
+    jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret(SECRET.as_bytes()),
+    )
+    .unwrap()
+}
+
+/// Default state over an emptied `dir`, taking fast transfers on a loopback
+/// UDP socket of its own.
+pub async fn app_state(dir: &Path) -> AppState {
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+
+    let udp_socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let udp_port = udp_socket.local_addr().unwrap().port();
+    AppState {
+        db: Arc::new(FileDb::open(&dir.join("files.db")).unwrap()),
+        storage: Arc::new(Storage::new(dir.join("storage")).await.unwrap()),
+        jwt_secret: SECRET.into(),
+        token_scope: Default::default(),
+        retention_hours: 1,
+        udp_socket: Arc::new(udp_socket),
+        udp_extra_sockets: Vec::new(),
+        udp_port,
+        download_sessions: Default::default(),
+        transfers: Default::default(),
+        quota: Default::default(),
+        fec: Default::default(),
+        upload_sync: Default::default(),
+        verify_uploads: true,
+        file_policy: Default::default(),
+    }
+}
@@ -1,37 +1,45 @@
 mod cleanup;
 mod db;
+mod dedup;
 mod fast_transfer;
+#[cfg(test)]
+mod harness;
+mod logging;
+mod policy;
+mod quota;
 mod routes;
+mod shutdown;
 mod storage;
+mod tls;
+mod verify;
+mod webhook;
 
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
-use socket2::{Domain, Protocol, Socket, Type};
 
 use axum::{Router, extract::DefaultBodyLimit, routing::{get, post, put, delete}};
 use axum::http::{Method, header::{AUTHORIZATION, CONTENT_TYPE, RANGE}};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::db::FileDb;
+use crate::logging::LogFormat;
 use crate::routes::AppState;
+use crate::shutdown::TransferRegistry;
 use crate::storage::Storage;
 
+use haven_fast_transfer::{FecRatio, SyncCadence, net};
 use haven_types::PLACEHOLDER_SECRETS;
+use haven_types::jwt::TokenScope;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let _ = dotenvy::dotenv();
 
-    tracing_subscriber::fmt()
-        .with_writer(std::io::stderr)
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "haven_file_server=debug,haven_fast_transfer=info,tower_http=debug".into()),
-        )
-        .init();
+    let log_format = logging::init("haven_file_server=debug,haven_fast_transfer=info,tower_http=debug");
+    haven_fast_transfer::logging::set_structured(log_format == LogFormat::Json);
 
     // Config
     let jwt_secret = std::env::var("HAVEN_JWT_SECRET").unwrap_or_default();
@@ -41,6 +49,22 @@ async fn main() -> anyhow::Result<()> {
         eprintln!("       Set it in your .env file and restart.");
         std::process::exit(1);
     }
+    let token_scope = TokenScope::from_env();
+    token_scope.log_config();
+
+    // Optional HTTPS: both files or neither.
+    let tls_paths = match tls::paths_from_env() {
+        Ok(paths) => paths,
+        Err(msg) => {
+            eprintln!("FATAL: {msg}");
+            eprintln!("       Set both HAVEN_FILE_TLS_CERT and HAVEN_FILE_TLS_KEY for HTTPS, or neither for plain HTTP.");
+            std::process::exit(1);
+        }
+    };
+    let tls_config = match &tls_paths {
+        Some((cert, key)) => Some(tls::load_server_config(cert, key)?),
+        None => None,
+    };
 
     let host = std::env::var("HAVEN_FILE_HOST").unwrap_or_else(|_| "0.0.0.0".into());
     let port: u16 = std::env::var("HAVEN_FILE_PORT")
@@ -56,38 +80,177 @@ async fn main() -> anyhow::Result<()> {
         .ok()
         .and_then(|v| v.parse().ok())
         .unwrap_or(168); // 7 days
+    // How long in-flight fast transfers get to finish after a shutdown signal
+    let shutdown_grace = std::env::var("HAVEN_FILE_SHUTDOWN_GRACE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(std::time::Duration::from_secs)
+        .unwrap_or(shutdown::DEFAULT_GRACE);
+
+    let quota = quota::QuotaConfig::from_env();
+    if let Some(limit) = quota.user_quota_bytes {
+        info!("Per-user storage quota: {} bytes", limit);
+    }
+    if let Some(floor) = quota.min_free_bytes {
+        info!("Refusing new transfers below {} bytes free", floor);
+    }
+    // FEC parity for fast transfers as "parity/data" frames, e.g. "4/32"
+    let fec = match std::env::var("HAVEN_FILE_FEC") {
+        Ok(v) => FecRatio::parse(&v).map_err(anyhow::Error::msg)?,
+        Err(_) => FecRatio::DISABLED,
+    };
+    if fec.is_enabled() {
+        info!("Fast transfer FEC: {} parity frames per {} data frames", fec.parity_frames(), fec.data_frames());
+    }
+
+    let file_policy = policy::FilePolicy::from_env();
+    if file_policy.restricts_types() {
+        info!(
+            "File types: allowing {:?}, blocking {:?} (client-reported)",
+            file_policy.allowed_types, file_policy.blocked_types
+        );
+    }
+    if file_policy.min_bytes.is_some() || file_policy.max_bytes.is_some() {
+        info!("File size bounds: {:?}..={:?} bytes", file_policy.min_bytes, file_policy.max_bytes);
+    }
+
+    let upload_sync = SyncCadence::from_env();
+    if upload_sync != SyncCadence::default() {
+        info!(
+            "Fast uploads sync every {} chunks / {} bytes (0 = off)",
+            upload_sync.every_chunks, upload_sync.every_bytes
+        );
+    }
+
+    let verify_uploads = verify::enabled_from_env();
+    if !verify_uploads {
+        info!("Whole-file verification of completed uploads disabled");
+    }
 
     // Init DB and storage
     let db = Arc::new(FileDb::open(&db_path)?);
     let storage = Arc::new(Storage::new(storage_dir).await?);
 
-    // Bind UDP on same port as HTTP (TCP and UDP don't conflict)
-    let udp_bind_addr: SocketAddr = format!("{}:{}", host, port).parse()?;
-    let udp_socket = {
-        let sock = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-        sock.set_recv_buffer_size(32 * 1024 * 1024)?;
+    // Bind UDP on same port as HTTP (TCP and UDP don't conflict). A host of
+    // `::` listens dual-stack where the OS allows.
+    let udp_bind_addr = net::host_port(&host, port)?;
+    let recv_buffer = net::recv_buffer_from_env();
+    let udp_workers = net::recv_workers_from_env();
+    let buffer_logged = std::cell::Cell::new(false);
+    let mut udp_sockets = net::bind_udp_group(udp_bind_addr, udp_workers, |sock| {
+        sock.set_recv_buffer_size(recv_buffer)?;
+        if !buffer_logged.replace(true) {
+            info!("UDP receive buffer: asked for {} bytes, got {}", recv_buffer, sock.recv_buffer_size()?);
+        }
         sock.set_nonblocking(false)?;
-        sock.set_read_timeout(Some(std::time::Duration::from_millis(100)))?;
-        sock.bind(&udp_bind_addr.into())?;
-        let std_sock: std::net::UdpSocket = sock.into();
-        Arc::new(std_sock)
-    };
+        sock.set_read_timeout(Some(std::time::Duration::from_millis(100)))
+    })?
+    .into_iter()
+    .map(Arc::new);
+    let udp_socket = udp_sockets.next().expect("bind_udp_group binds at least one socket");
+    let udp_extra_sockets: Vec<_> = udp_sockets.collect();
     info!("UDP fast transfer socket bound on {}", udp_bind_addr);
+    if udp_workers > 1 {
+        if udp_extra_sockets.is_empty() {
+            warn!("{}={} needs SO_REUSEPORT (Linux); using one socket", net::RECV_WORKERS_ENV, udp_workers);
+        } else {
+            info!("UDP receive workers: {} sockets sharing the port", udp_extra_sockets.len() + 1);
+        }
+    }
+    tokio::spawn(report_udp_drops(std::iter::once(&udp_socket).chain(&udp_extra_sockets).cloned().collect()));
 
     // Background cleanup task (runs every hour)
+    let expiry_webhook = webhook::ExpiryWebhook::from_env()?;
+    if let Some(hook) = &expiry_webhook {
+        info!("Reporting unconfirmed expiries to {}", hook.url());
+    }
+    let download_sessions = routes::DownloadSessions::default();
     let cleanup_db = db.clone();
     let cleanup_storage = storage.clone();
-    tokio::spawn(cleanup::run_cleanup_loop(cleanup_db, cleanup_storage, 3600));
+    tokio::spawn(cleanup::run_cleanup_loop(
+        cleanup_db,
+        cleanup_storage,
+        download_sessions.clone(),
+        expiry_webhook,
+        3600,
+    ));
 
+    let transfers = Arc::new(TransferRegistry::default());
     let state = AppState {
-        db,
+        db: db.clone(),
         storage,
         jwt_secret,
+        token_scope,
         retention_hours,
         udp_socket,
+        udp_extra_sockets,
         udp_port: port,
+        download_sessions,
+        transfers: transfers.clone(),
+        quota,
+        fec,
+        upload_sync,
+        verify_uploads,
+        file_policy: Arc::new(file_policy),
+    };
+
+    let app = build_router(state);
+
+    let addr = net::host_port(&host, port)?;
+    info!("Retention: {} hours ({} days)", retention_hours, retention_hours / 24);
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+
+    // Stop admitting fast transfers as soon as the signal arrives, while
+    // in-flight HTTP requests are still being served.
+    let shutdown = {
+        let transfers = transfers.clone();
+        async move {
+            shutdown_signal().await;
+            transfers.stop_accepting();
+        }
     };
 
+    if let Some(tls_config) = tls_config {
+        info!("Haven file server listening on {} (HTTPS)", addr);
+        tls::serve(listener, app, tls_config, shutdown).await?;
+    } else {
+        info!("Haven file server listening on {}", addr);
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    }
+
+    shutdown::drain_and_record(&transfers, &db, shutdown_grace).await;
+
+    Ok(())
+}
+
+/// Warn whenever the kernel has dropped datagrams on the shared UDP sockets
+/// since the last look: the receivers aren't draining them fast enough (or
+/// the buffer is too small, see `HAVEN_UDP_RECV_BUFFER`).
+async fn report_udp_drops(sockets: Vec<Arc<std::net::UdpSocket>>) {
+    let total_drops = || sockets.iter().map(|s| net::udp_drops(s)).sum::<Option<u64>>();
+    let Some(mut last) = total_drops() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+    loop {
+        interval.tick().await;
+        let Some(drops) = total_drops() else {
+            return;
+        };
+        if drops > last {
+            warn!("UDP socket dropped {} datagrams in the last 10s ({} total)", drops - last, drops);
+        }
+        last = drops;
+    }
+}
+
+fn build_router(state: AppState) -> Router {
     // CORS — permissive for file server (clients connect from various origins)
     let cors = CorsLayer::new()
         .allow_origin(AllowOrigin::any())
@@ -95,38 +258,28 @@ async fn main() -> anyhow::Result<()> {
         .allow_headers([AUTHORIZATION, CONTENT_TYPE, RANGE])
         .allow_credentials(false);
 
-    let app = Router::new()
+    Router::new()
         .route("/transfers", post(routes::create_transfer))
+        .route("/transfers/check", post(routes::check_transfer))
         .route("/transfers/{id}/data", put(routes::upload_data))
         .route("/transfers/{id}/chunks/{index}", put(routes::upload_chunk))
+        .route("/transfers/{id}/chunks:batch", put(routes::upload_chunk_batch))
         .route("/transfers/{id}/data", get(routes::download_data))
         .route("/transfers/{id}", get(routes::get_transfer_status))
         .route("/transfers/{id}/chunks", get(routes::get_chunk_status))
+        .route("/transfers/{id}/manifest", get(routes::get_manifest))
         .route("/transfers/{id}/confirm", post(routes::confirm_transfer))
+        .route("/transfers/{id}/recipients", put(routes::set_recipients))
         .route("/transfers/{id}", delete(routes::delete_transfer))
         .route("/fast-transfer", get(routes::fast_transfer_ws))
         .route("/health", get(routes::health))
-        .route("/transfers/all", get(routes::list_all_transfers))
+        .route("/ready", get(routes::ready))
+        .route("/admin/transfers", get(routes::admin_list_transfers))
         .route("/admin/transfers/{id}", delete(routes::admin_delete_transfer))
         .layer(DefaultBodyLimit::max(4 * 1024 * 1024 * 1024)) // 4 GB max
         .layer(cors)
-        .layer(TraceLayer::new_for_http())
-        .with_state(state);
-
-    let addr: SocketAddr = format!("{}:{}", host, port).parse()?;
-    info!("Haven file server listening on {}", addr);
-    info!("Retention: {} hours ({} days)", retention_hours, retention_hours / 24);
-
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .with_graceful_shutdown(shutdown_signal())
-    .await?;
-
-    Ok(())
+        .layer(TraceLayer::new_for_http().make_span_with(logging::http_request_span))
+        .with_state(state)
 }
 
 use haven_types::shutdown_signal;
@@ -0,0 +1,160 @@
+//! What new transfers may carry, for deployments that need to restrict it.
+//!
+//! All off unless configured:
+//!
+//! - `HAVEN_FILE_ALLOWED_TYPES` / `HAVEN_FILE_BLOCKED_TYPES`: comma-separated
+//!   MIME types, `image/*` standing for a whole family. Checked against the
+//!   `content_type` the client sniffed from the plaintext before encrypting
+//!   it. The server only ever sees ciphertext, so this is advisory: it keeps
+//!   honest clients in line, nothing more. With an allowlist set, a transfer
+//!   that reports no type is refused.
+//! - `HAVEN_FILE_MIN_BYTES` / `HAVEN_FILE_MAX_BYTES`: bounds on the declared
+//!   (encrypted) file size. These hold, since nothing past the declared size
+//!   is ever accepted.
+
+use std::fmt;
+
+use axum::http::StatusCode;
+
+/// Configured rules. Empty lists and `None` bounds don't restrict anything.
+#[derive(Debug, Clone, Default)]
+pub struct FilePolicy {
+    pub allowed_types: Vec<String>,
+    pub blocked_types: Vec<String>,
+    pub min_bytes: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl FilePolicy {
+    /// Read the rules from the environment; unset, unparsable or `0` bounds
+    /// are off.
+    pub fn from_env() -> Self {
+        let types = |name: &str| std::env::var(name).map(|v| parse_types(&v)).unwrap_or_default();
+        let bound = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|&v| v > 0)
+        };
+        Self {
+            allowed_types: types("HAVEN_FILE_ALLOWED_TYPES"),
+            blocked_types: types("HAVEN_FILE_BLOCKED_TYPES"),
+            min_bytes: bound("HAVEN_FILE_MIN_BYTES"),
+            max_bytes: bound("HAVEN_FILE_MAX_BYTES"),
+        }
+    }
+
+    pub fn restricts_types(&self) -> bool {
+        !self.allowed_types.is_empty() || !self.blocked_types.is_empty()
+    }
+
+    /// Check the client-reported content type. Parameters (`; charset=…`)
+    /// and case are ignored.
+    pub fn check_type(&self, content_type: Option<&str>) -> Result<(), PolicyViolation> {
+        let essence = content_type.map(|ct| ct.split(';').next().unwrap_or_default().trim().to_ascii_lowercase());
+        let refused = || PolicyViolation::TypeNotAllowed(essence.clone());
+        match essence.as_deref() {
+            Some(ct) if self.blocked_types.iter().any(|p| type_matches(p, ct)) => Err(refused()),
+            Some(ct) if self.allowed_types.is_empty() || self.allowed_types.iter().any(|p| type_matches(p, ct)) => Ok(()),
+            None if self.allowed_types.is_empty() => Ok(()),
+            _ => Err(refused()),
+        }
+    }
+
+    /// Check the declared file size against the bounds.
+    pub fn check_size(&self, file_size: u64) -> Result<(), PolicyViolation> {
+        if let Some(max) = self.max_bytes
+            && file_size > max
+        {
+            return Err(PolicyViolation::TooLarge { size: file_size, max });
+        }
+        if let Some(min) = self.min_bytes
+            && file_size < min
+        {
+            return Err(PolicyViolation::TooSmall { size: file_size, min });
+        }
+        Ok(())
+    }
+}
+
+/// A new transfer the policy refuses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyViolation {
+    /// The reported type (if any) isn't allowed.
+    TypeNotAllowed(Option<String>),
+    TooLarge { size: u64, max: u64 },
+    TooSmall { size: u64, min: u64 },
+}
+
+impl PolicyViolation {
+    /// `415` for the type, `413` over the size limit, `400` under it.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            PolicyViolation::TypeNotAllowed(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            PolicyViolation::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            PolicyViolation::TooSmall { .. } => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::TypeNotAllowed(Some(ct)) => write!(f, "file type {} is not allowed", ct),
+            PolicyViolation::TypeNotAllowed(None) => write!(f, "file type is required"),
+            PolicyViolation::TooLarge { size, max } => write!(f, "file size {} is over the {} byte limit", size, max),
+            PolicyViolation::TooSmall { size, min } => write!(f, "file size {} is under the {} byte minimum", size, min),
+        }
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+fn parse_types(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|t| t.trim().to_ascii_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// `pattern` is a full type or `family/*`; `content_type` is lowercase.
+fn type_matches(pattern: &str, content_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(family) => content_type.split('/').next() == Some(family),
+        None => pattern == content_type,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_type_lists_and_size_bounds() {
+        let policy = FilePolicy {
+            allowed_types: parse_types("image/*, Application/PDF"),
+            blocked_types: parse_types("image/svg+xml"),
+            min_bytes: Some(10),
+            max_bytes: Some(100),
+        };
+        assert!(policy.check_type(Some("image/png")).is_ok());
+        assert!(policy.check_type(Some("application/pdf; charset=binary")).is_ok());
+        assert_eq!(
+            policy.check_type(Some("image/SVG+xml")),
+            Err(PolicyViolation::TypeNotAllowed(Some("image/svg+xml".into())))
+        );
+        assert!(policy.check_type(Some("application/zip")).is_err());
+        assert_eq!(policy.check_type(None), Err(PolicyViolation::TypeNotAllowed(None)));
+
+        // A blocklist alone lets everything else through, unreported included.
+        let blocking = FilePolicy { blocked_types: parse_types("application/x-msdownload"), ..Default::default() };
+        assert!(blocking.check_type(None).is_ok());
+        assert!(blocking.check_type(Some("text/plain")).is_ok());
+        assert!(blocking.check_type(Some("application/x-msdownload")).is_err());
+
+        assert!(policy.check_size(10).is_ok() && policy.check_size(100).is_ok());
+        assert_eq!(policy.check_size(101).unwrap_err().status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(policy.check_size(9).unwrap_err().status(), StatusCode::BAD_REQUEST);
+        assert!(FilePolicy::default().check_size(u64::MAX).is_ok());
+    }
+}
@@ -0,0 +1,103 @@
+//! Storage limits for new transfers.
+//!
+//! Two independent guards, both off unless configured:
+//!
+//! - `HAVEN_FILE_USER_QUOTA_BYTES`: the most one user may hold. Usage is the
+//!   declared `file_size` of their transfers that still occupy (or will
+//!   occupy) disk — anything not `confirmed` or `expired`. Confirming,
+//!   deleting or expiring a transfer releases its share.
+//! - `HAVEN_FILE_MIN_FREE_BYTES`: refuse every new transfer once the storage
+//!   filesystem would drop below this much free space.
+
+use std::fmt;
+
+use rusqlite::Connection;
+
+use haven_types::api::TransferStatus as TStatus;
+
+/// Configured limits. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuotaConfig {
+    pub user_quota_bytes: Option<u64>,
+    pub min_free_bytes: Option<u64>,
+}
+
+impl QuotaConfig {
+    /// Read both limits from the environment; unset, unparsable or `0` is off.
+    pub fn from_env() -> Self {
+        let limit = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|&v| v > 0)
+        };
+        Self {
+            user_quota_bytes: limit("HAVEN_FILE_USER_QUOTA_BYTES"),
+            min_free_bytes: limit("HAVEN_FILE_MIN_FREE_BYTES"),
+        }
+    }
+}
+
+/// A new transfer would take its uploader past their quota.
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub used: u64,
+    pub requested: u64,
+    pub limit: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "storage quota exceeded: {} used + {} requested > {} allowed",
+            self.used, self.requested, self.limit
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// Bytes charged to `uploader_id`.
+pub fn user_usage(conn: &Connection, uploader_id: &str) -> rusqlite::Result<u64> {
+    conn.query_row(
+        "SELECT COALESCE(SUM(file_size), 0) FROM transfers
+         WHERE uploader_id = ?1 AND status NOT IN (?2, ?3, ?4)",
+        rusqlite::params![
+            uploader_id,
+            TStatus::Confirmed.to_string(),
+            TStatus::Expired.to_string(),
+            TStatus::Cancelled.to_string(),
+        ],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|used| used as u64)
+}
+
+/// Check a new transfer of `requested` bytes against the user quota. Call
+/// inside the transaction that inserts it, so concurrent creates can't both
+/// squeeze under the limit.
+pub fn check_user_quota(
+    conn: &Connection,
+    config: &QuotaConfig,
+    uploader_id: &str,
+    requested: u64,
+) -> anyhow::Result<()> {
+    let Some(limit) = config.user_quota_bytes else {
+        return Ok(());
+    };
+    let used = user_usage(conn, uploader_id)?;
+    if used.saturating_add(requested) > limit {
+        return Err(QuotaExceeded { used, requested, limit }.into());
+    }
+    Ok(())
+}
+
+/// Whether `available` bytes of free space leave room for `requested` more
+/// above the configured floor.
+pub fn has_disk_headroom(config: &QuotaConfig, available: u64, requested: u64) -> bool {
+    match config.min_free_bytes {
+        Some(floor) => available.saturating_sub(requested) >= floor,
+        None => true,
+    }
+}
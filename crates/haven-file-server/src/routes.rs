@@ -1,9 +1,10 @@
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 
 use axum::{
     Json,
     body::Body,
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::{HeaderMap, StatusCode, header},
     response::IntoResponse,
 };
@@ -11,11 +12,24 @@ use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncReadExt;
 use tracing::{info, warn};
+use uuid::Uuid;
 
-use haven_types::api::{Claims, TransferStatus as TStatus};
+use haven_fast_transfer::{FecRatio, SyncCadence};
+use haven_fast_transfer::protocol::{MAX_CHUNK_SIZE, MIN_CHUNK_SIZE, encrypted_chunk_size};
+use haven_types::api::{AdminClaims, Claims, TransferStatus as TStatus};
+use haven_types::jwt::TokenScope;
+use haven_types::ready::Readiness;
 
 use crate::db::FileDb;
+use crate::dedup;
+use crate::policy::FilePolicy;
+use crate::quota::{self, QuotaConfig, QuotaExceeded};
+use crate::shutdown::TransferRegistry;
 use crate::storage::Storage;
+use crate::verify;
+
+/// Users bound to each transfer's HTTP downloads, keyed by transfer ID.
+pub type DownloadSessions = Arc<Mutex<HashMap<String, HashSet<Uuid>>>>;
 
 /// Shared application state for all route handlers.
 #[derive(Clone)]
@@ -23,10 +37,34 @@ pub struct AppState {
     pub db: Arc<FileDb>,
     pub storage: Arc<Storage>,
     pub jwt_secret: String,
+    /// Issuer/audience tokens must carry (`HAVEN_JWT_ISSUER`/`HAVEN_JWT_AUDIENCE`).
+    pub token_scope: TokenScope,
     pub retention_hours: u64,
     /// Pre-bound UDP socket for fast transfers (fixed port, bound at startup).
     pub udp_socket: Arc<std::net::UdpSocket>,
+    /// More sockets bound to `udp_port` with `SO_REUSEPORT`
+    /// (`HAVEN_UDP_RECV_WORKERS`); every fast upload drains each on its own
+    /// thread. Empty with one worker.
+    pub udp_extra_sockets: Vec<Arc<std::net::UdpSocket>>,
     pub udp_port: u16,
+    /// Users bound to each transfer's HTTP downloads (see
+    /// `check_download_session`), pruned with the transfer.
+    pub download_sessions: DownloadSessions,
+    /// In-flight fast transfers, drained on shutdown.
+    pub transfers: Arc<TransferRegistry>,
+    /// Per-user and disk-pressure limits on new transfers.
+    pub quota: QuotaConfig,
+    /// FEC parity blasted with fast downloads and asked of fast uploads in
+    /// FastUploadReady. The receiver uses whatever parity arrives, so an
+    /// uploader that ignores the ask still works.
+    pub fec: FecRatio,
+    /// How often fast uploads sync to disk before completing
+    /// (`HAVEN_FAST_SYNC_CHUNKS` / `HAVEN_FAST_SYNC_BYTES`).
+    pub upload_sync: SyncCadence,
+    /// Re-hash each HTTP upload once complete (see `verify`).
+    pub verify_uploads: bool,
+    /// Allowed file types and sizes for new transfers (see `policy`).
+    pub file_policy: Arc<FilePolicy>,
 }
 
 // ── Request/response types ──────────────────────────────────────────────
@@ -38,12 +76,99 @@ pub struct CreateTransferRequest {
     pub chunk_size: Option<u64>,
     pub file_sha256: String,
     pub chunk_hashes: Vec<String>,
+    /// Original filename, sent back as `Content-Disposition` on download.
+    /// Path components and control characters are stripped.
+    #[serde(default)]
+    pub filename: Option<String>,
+    /// MIME type served on download instead of `application/octet-stream`.
+    /// Clients sniff it from the plaintext; see `policy` for what the server
+    /// may refuse by it.
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// How many recipients will download this transfer (1 if absent). A
+    /// channel-wide offer has several; the file is kept until each one's
+    /// download has been confirmed. Fan-out only learns the count once the
+    /// file is uploaded, so it is usually set later with
+    /// `PUT /transfers/{id}/recipients`.
+    #[serde(default)]
+    pub recipients: Option<u32>,
+    /// Keep the transfer this many minutes instead of the server's full
+    /// retention window, which is also the most that can be asked for.
+    #[serde(default)]
+    pub retention_minutes: Option<u64>,
+    /// Expire the transfer after this many confirmed downloads, whatever
+    /// `recipients` says: 1 makes a one-time link.
+    #[serde(default)]
+    pub max_downloads: Option<u32>,
+}
+
+/// Optional body of `POST /transfers/{id}/confirm`.
+#[derive(Debug, Default, Deserialize)]
+pub struct ConfirmRequest {
+    /// Whose download this confirms. Confirming the same recipient twice
+    /// counts once; without it every confirm counts.
+    #[serde(default)]
+    pub recipient_id: Option<String>,
+}
+
+/// Body of `PUT /transfers/{id}/recipients`.
+#[derive(Debug, Deserialize)]
+pub struct SetRecipientsRequest {
+    pub recipients: u32,
 }
 
 #[derive(Debug, Serialize)]
 pub struct CreateTransferResponse {
     pub id: String,
     pub chunk_count: usize,
+    /// The server already holds these exact bytes; the transfer is complete
+    /// and the client can skip uploading.
+    pub already_present: bool,
+    /// Current transfer status. Only differs from `uploading`/`complete` when
+    /// a retried create finds an older transfer.
+    pub status: String,
+}
+
+/// Chunk size of an HTTP upload that doesn't name one (4 MB).
+pub const DEFAULT_CHUNK_SIZE: u64 = 4_194_304;
+
+#[derive(Debug, Deserialize)]
+pub struct CheckTransferRequest {
+    pub file_size: u64,
+    /// Checked against stored blobs when given.
+    #[serde(default)]
+    pub file_sha256: Option<String>,
+    /// Checked against the file type policy as on create. Left out, the
+    /// type isn't judged; `restricts_types` says whether to name one.
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
+
+/// What `POST /transfers` would make of an upload, without creating it.
+#[derive(Debug, Serialize)]
+pub struct CheckTransferResponse {
+    pub accepted: bool,
+    /// Why not: `quota_exceeded`, `insufficient_storage`, `shutting_down`,
+    /// `size_not_allowed` or `type_not_allowed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// The server already holds these bytes; a create with the matching
+    /// chunk layout completes without an upload.
+    pub already_present: bool,
+    /// The server has a file type policy, so uploads should report their
+    /// `content_type`. Otherwise clients keep it to themselves.
+    pub restricts_types: bool,
+    pub chunk_sizes: ChunkSizes,
+}
+
+/// Encrypted chunk sizes the server takes.
+#[derive(Debug, Serialize)]
+pub struct ChunkSizes {
+    /// Used by HTTP uploads that don't name a chunk size.
+    pub default: u64,
+    /// Range a fast upload may negotiate.
+    pub min: u64,
+    pub max: u64,
 }
 
 #[derive(Debug, Serialize)]
@@ -53,7 +178,85 @@ pub struct TransferStatus {
     pub file_size: u64,
     pub bytes_received: u64,
     pub chunk_count: u64,
+    /// Bytes per full chunk as recorded at upload (encrypted size; fast
+    /// uploads may negotiate a non-default size).
+    pub chunk_size: u64,
+    /// SHA-256 of the full encrypted file, so a retrying uploader can tell
+    /// its own transfer from an id collision.
+    pub file_sha256: String,
+    pub created_at: String,
+    /// Uploader's HMAC over the control fields, if the upload supplied one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_mac: Option<String>,
+    /// Chunks are zstd-compressed slots; downloaders must open them with
+    /// `open_compressed_chunk`.
+    pub compressed: bool,
+    /// Algorithm byte the chunks were sealed with (`AeadAlgorithm::id`);
+    /// 0, AES-256-GCM, for HTTP uploads.
+    pub aead: u8,
+    /// Chunks a sparse fast upload stored as zero slots, as its
+    /// `control_mac` covers them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sparse_chunks: Vec<u32>,
+}
+
+/// Query of `GET /admin/transfers`.
+#[derive(Debug, Default, Deserialize)]
+pub struct AdminTransferPage {
+    pub limit: Option<u32>,
+    pub offset: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminTransferList {
+    pub transfers: Vec<AdminTransfer>,
+    /// Transfers on record, across all pages.
+    pub total: u64,
+    pub limit: u32,
+    pub offset: u64,
+}
+
+/// What `DELETE /admin/transfers/{id}` removed.
+#[derive(Debug, Serialize)]
+pub struct AdminPurge {
+    /// The transfer asked for, then any that shared its deduplicated blob.
+    pub purged: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminTransfer {
+    pub id: String,
+    pub uploader_id: String,
+    pub file_size: u64,
+    pub chunk_size: u64,
+    pub chunk_count: u64,
+    pub file_sha256: String,
+    pub bytes_received: u64,
+    pub status: String,
     pub created_at: String,
+    pub expires_at: Option<String>,
+    pub filename: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ManifestResponse {
+    pub transfer_id: String,
+    pub status: String,
+    pub file_size: u64,
+    pub file_sha256: String,
+    pub chunks: Vec<ManifestChunk>,
+}
+
+/// One chunk's byte range in the stored file.
+#[derive(Debug, Serialize)]
+pub struct ManifestChunk {
+    pub index: u64,
+    pub offset: u64,
+    pub length: u64,
+    pub sha256: String,
+    /// Whether the range is on disk yet; only these can be fetched while
+    /// the upload is still running.
+    pub received: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -67,41 +270,104 @@ pub struct ChunkStatusResponse {
 
 // ── Auth helper ─────────────────────────────────────────────────────────
 
-pub fn extract_claims(headers: &HeaderMap, jwt_secret: &str) -> Result<Claims, StatusCode> {
-    let auth_header = headers
+pub fn extract_claims(headers: &HeaderMap, state: &AppState) -> Result<Claims, StatusCode> {
+    decode_token(bearer_token(headers)?, state)
+}
+
+/// Claims of an admin token (haven-api's `/admin/login`), checked against
+/// the configured issuer/audience like user tokens. A valid user token is
+/// `403`; anything else `401`.
+pub fn extract_admin_claims(headers: &HeaderMap, state: &AppState) -> Result<AdminClaims, StatusCode> {
+    let token = bearer_token(headers)?;
+    match state.token_scope.decode_admin(token, &state.jwt_secret) {
+        Ok(claims) if claims.admin => Ok(claims),
+        Ok(_) => Err(StatusCode::FORBIDDEN),
+        Err(_) if decode_token(token, state).is_ok() => Err(StatusCode::FORBIDDEN),
+        Err(_) => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Result<&str, StatusCode> {
+    headers
         .get(header::AUTHORIZATION)
         .and_then(|v| v.to_str().ok())
         .and_then(|s| s.strip_prefix("Bearer "))
-        .ok_or(StatusCode::UNAUTHORIZED)?;
+        .ok_or(StatusCode::UNAUTHORIZED)
+}
 
-    let token_data = jsonwebtoken::decode::<Claims>(
-        auth_header,
-        &jsonwebtoken::DecodingKey::from_secret(jwt_secret.as_bytes()),
-        &jsonwebtoken::Validation::default(),
-    )
-    .map_err(|_| StatusCode::UNAUTHORIZED)?;
+/// Validate a raw JWT (signature, expiry and any configured issuer/audience)
+/// and return its claims.
+pub fn decode_token(token: &str, state: &AppState) -> Result<Claims, StatusCode> {
+    state
+        .token_scope
+        .decode(token, &state.jwt_secret)
+        .map_err(|_| StatusCode::UNAUTHORIZED)
+}
 
-    Ok(token_data.claims)
+/// Bind a capped transfer's downloads to the users who make them.
+///
+/// Only a transfer created with `max_downloads` has `seats`: the first
+/// request from a user, Range or not, binds them, and once every seat is
+/// taken any other user is `403`. Without a cap every recipient may download.
+/// A bound user's later requests only need a token valid *now*, so a client
+/// whose original JWT expired mid-download resumes with a refreshed one.
+/// Nothing is bound after a server restart, so the first requests then bind
+/// afresh.
+fn check_download_session(
+    state: &AppState,
+    transfer_id: &str,
+    user_id: Uuid,
+    seats: Option<usize>,
+) -> Result<(), StatusCode> {
+    let Some(seats) = seats else {
+        return Ok(());
+    };
+    let mut sessions = state.download_sessions.lock().unwrap();
+    let users = sessions.entry(transfer_id.to_string()).or_default();
+    if !users.contains(&user_id) && users.len() >= seats {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    users.insert(user_id);
+    Ok(())
 }
 
+/// Most recipients one transfer can be created for.
+const MAX_RECIPIENTS: u32 = 1024;
+
+/// Page size of `GET /admin/transfers` when none is asked for, and the most
+/// that can be.
+const ADMIN_PAGE_DEFAULT: u32 = 200;
+const ADMIN_PAGE_MAX: u32 = 1000;
+
 // ── Handlers ────────────────────────────────────────────────────────────
 
 /// POST /transfers — create a new transfer record with file metadata + chunk hashes.
+///
+/// Idempotent: retrying with the same id, size and hash returns `200` with the
+/// existing transfer's state; the same id with other metadata, or from another
+/// user, is `409`.
+///
+/// New transfers are refused with `413` past the uploader's quota and `507`
+/// when the disk is under pressure (see `quota`), and with `415`, `413` or
+/// `400` for a type or size the file policy doesn't allow (see `policy`).
 pub async fn create_transfer(
     State(state): State<AppState>,
     headers: HeaderMap,
     Json(req): Json<CreateTransferRequest>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let claims = extract_claims(&headers, &state.jwt_secret)?;
-    let chunk_size = req.chunk_size.unwrap_or(4_194_304); // 4 MB default
+    let claims = extract_claims(&headers, &state)?;
+    if state.transfers.is_draining() {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    let chunk_size = req.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    if chunk_size == 0 {
+        warn!("Rejecting transfer {}: zero chunk size", req.id);
+        return Err(StatusCode::BAD_REQUEST);
+    }
     let chunk_count = req.chunk_hashes.len();
 
     // Validate chunk count matches file size
-    let expected_chunks = if req.file_size == 0 {
-        1
-    } else {
-        ((req.file_size + chunk_size - 1) / chunk_size) as usize
-    };
+    let expected_chunks = haven_fast_transfer::chunk_count(req.file_size, chunk_size) as usize;
     if chunk_count != expected_chunks {
         warn!(
             "Chunk count mismatch: got {} hashes, expected {} for {} bytes with {} chunk size",
@@ -110,14 +376,111 @@ pub async fn create_transfer(
         return Err(StatusCode::BAD_REQUEST);
     }
 
-    let retention_hours = state.retention_hours;
+    let filename = req.filename.as_deref().and_then(sanitize_filename);
+    let content_type = match req.content_type.as_deref() {
+        Some(ct) if !is_valid_content_type(ct) => {
+            warn!("Rejecting transfer {}: invalid content type {:?}", req.id, ct);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        ct => ct.map(str::to_owned),
+    };
+    let allowed = state
+        .file_policy
+        .check_size(req.file_size)
+        .and_then(|()| state.file_policy.check_type(content_type.as_deref()));
+    if let Err(violation) = allowed {
+        warn!("Rejecting transfer {}: {}", req.id, violation);
+        return Err(violation.status());
+    }
+
+    let recipients = req.recipients.unwrap_or(1);
+    if !(1..=MAX_RECIPIENTS).contains(&recipients) {
+        warn!("Rejecting transfer {}: {} recipients", req.id, recipients);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if req.max_downloads == Some(0) {
+        warn!("Rejecting transfer {}: max_downloads of 0", req.id);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let retention_minutes = retention_minutes(req.retention_minutes, state.retention_hours);
     let transfer_id = req.id.clone();
 
-    // Create DB record
-    state.db.with_conn_mut(|conn| {
+    // A retried POST finds its own transfer. Checked before dedup, which
+    // would otherwise link a blob over the existing transfer's file.
+    let existing = state.db.with_conn(|conn| {
+        use rusqlite::OptionalExtension;
+        Ok(conn
+            .query_row(
+                "SELECT uploader_id, file_size, file_sha256, chunk_count, status FROM transfers WHERE id = ?1",
+                [&transfer_id],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, i64>(1)? as u64,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, i64>(3)? as usize,
+                        row.get::<_, String>(4)?,
+                    ))
+                },
+            )
+            .optional()?)
+    });
+    let existing = existing.map_err(|e| {
+        warn!("Failed to look up transfer {}: {}", transfer_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if let Some((uploader_id, file_size, file_sha256, existing_chunks, status)) = existing {
+        if uploader_id != claims.sub.to_string() || file_size != req.file_size || file_sha256 != req.file_sha256 {
+            warn!("Transfer {} already exists with different metadata", transfer_id);
+            return Err(StatusCode::CONFLICT);
+        }
+        info!("Transfer {} re-created by {}: returning existing ({})", transfer_id, claims.username, status);
+        return Ok((
+            StatusCode::OK,
+            Json(CreateTransferResponse {
+                id: transfer_id,
+                chunk_count: existing_chunks,
+                already_present: status == TStatus::Complete.to_string(),
+                status,
+            }),
+        ));
+    }
+
+    // Disk-pressure guard: refuse everyone before anything is written
+    if state.quota.min_free_bytes.is_some()
+        && let Ok(available) = state.storage.available_space()
+        && !quota::has_disk_headroom(&state.quota, available, req.file_size)
+    {
+        warn!("Transfer {} rejected: only {} bytes free on storage", transfer_id, available);
+        return Err(StatusCode::INSUFFICIENT_STORAGE);
+    }
+
+    // Same bytes already stored? Link them instead of asking for an upload.
+    let linked = dedup::link_existing(
+        &state.db,
+        &state.storage,
+        &transfer_id,
+        &req.file_sha256,
+        req.file_size,
+        chunk_size,
+        &req.chunk_hashes,
+    )
+    .await;
+
+    // Create transfer + chunk records atomically
+    let db_result = state.db.with_transaction(|conn| {
+        quota::check_user_quota(conn, &state.quota, &claims.sub.to_string(), req.file_size)?;
+
+        // The blob may have been released between linking and now.
+        let present = linked && dedup::blob_exists(conn, &req.file_sha256)?;
         conn.execute(
-            "INSERT INTO transfers (id, uploader_id, file_size, chunk_size, chunk_count, file_sha256, expires_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, datetime('now', '+' || ?7 || ' hours'))",
+            "INSERT INTO transfers (id, uploader_id, file_size, chunk_size, chunk_count, file_sha256, expires_at,
+                                    status, bytes_received, blob_sha256, filename, content_type, recipients,
+                                    max_downloads)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, datetime('now', '+' || ?7 || ' minutes'), ?8, ?9, ?10, ?11, ?12, ?13,
+                     ?14)",
             rusqlite::params![
                 &req.id,
                 &claims.sub.to_string(),
@@ -125,7 +488,14 @@ pub async fn create_transfer(
                 chunk_size as i64,
                 chunk_count as i64,
                 &req.file_sha256,
-                retention_hours as i64,
+                retention_minutes as i64,
+                if present { TStatus::Complete } else { TStatus::Uploading }.to_string(),
+                if present { req.file_size as i64 } else { 0 },
+                present.then_some(&req.file_sha256),
+                filename,
+                content_type,
+                recipients,
+                req.max_downloads,
             ],
         )?;
 
@@ -139,38 +509,119 @@ pub async fn create_transfer(
                 chunk_size
             };
             conn.execute(
-                "INSERT INTO chunks (transfer_id, chunk_index, sha256, byte_offset, byte_length)
-                 VALUES (?1, ?2, ?3, ?4, ?5)",
-                rusqlite::params![&req.id, i as i64, hash, offset as i64, length as i64],
+                "INSERT INTO chunks (transfer_id, chunk_index, sha256, byte_offset, byte_length, received)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                rusqlite::params![&req.id, i as i64, hash, offset as i64, length as i64, present],
             )?;
             offset += length;
         }
-        Ok(())
-    }).map_err(|e| {
+        Ok(present)
+    });
+
+    // A link we made but didn't record must go before create_file, which
+    // would otherwise truncate the shared blob.
+    if linked && !matches!(db_result, Ok(true)) {
+        state.storage.delete_file(&transfer_id).await.ok();
+    }
+    let already_present = db_result.map_err(|e| {
+        if let Some(exceeded) = e.downcast_ref::<QuotaExceeded>() {
+            warn!("Transfer {} rejected for {}: {}", transfer_id, claims.username, exceeded);
+            return StatusCode::PAYLOAD_TOO_LARGE;
+        }
         warn!("Failed to create transfer: {}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    // Pre-allocate file on disk
-    state.storage.create_file(&transfer_id, req.file_size).await.map_err(|e| {
-        warn!("Failed to create file: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    if already_present {
+        info!(
+            "Transfer {} created by {}: {} bytes, deduplicated against stored blob",
+            transfer_id, claims.username, req.file_size
+        );
+    } else {
+        // Pre-allocate file on disk
+        state.storage.create_file(&transfer_id, req.file_size).await.map_err(|e| {
+            warn!("Failed to create file: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
 
-    info!(
-        "Transfer {} created by {}: {} bytes, {} chunks",
-        transfer_id, claims.username, req.file_size, chunk_count
-    );
+        info!(
+            "Transfer {} created by {}: {} bytes, {} chunks",
+            transfer_id, claims.username, req.file_size, chunk_count
+        );
+    }
 
     Ok((
         StatusCode::CREATED,
         Json(CreateTransferResponse {
             id: transfer_id,
             chunk_count,
+            already_present,
+            status: if already_present { TStatus::Complete } else { TStatus::Uploading }.to_string(),
         }),
     ))
 }
 
+/// POST /transfers/check — whether a create for `file_size` bytes would be
+/// accepted, and whether the bytes are already stored. Creates nothing.
+pub async fn check_transfer(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<CheckTransferRequest>,
+) -> Result<Json<CheckTransferResponse>, StatusCode> {
+    let claims = extract_claims(&headers, &state)?;
+
+    let checked = state.db.with_conn(|conn| {
+        let quota = quota::check_user_quota(conn, &state.quota, &claims.sub.to_string(), req.file_size);
+        let present = match &req.file_sha256 {
+            Some(sha256) => {
+                use rusqlite::OptionalExtension;
+                conn.query_row("SELECT file_size FROM blobs WHERE sha256 = ?1", [sha256], |row| row.get::<_, i64>(0))
+                    .optional()?
+                    .is_some_and(|size| size as u64 == req.file_size)
+            }
+            None => false,
+        };
+        Ok((quota, present))
+    });
+    let (quota, already_present) = checked.map_err(|e| {
+        warn!("Failed to check transfer for {}: {}", claims.username, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let reason = if state.transfers.is_draining() {
+        Some("shutting_down")
+    } else if state.file_policy.check_size(req.file_size).is_err() {
+        Some("size_not_allowed")
+    } else if req.content_type.is_some() && state.file_policy.check_type(req.content_type.as_deref()).is_err() {
+        Some("type_not_allowed")
+    } else if let Err(e) = quota {
+        if e.downcast_ref::<QuotaExceeded>().is_none() {
+            warn!("Failed to check quota for {}: {}", claims.username, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        Some("quota_exceeded")
+    } else if state.quota.min_free_bytes.is_some()
+        && let Ok(available) = state.storage.available_space()
+        && !quota::has_disk_headroom(&state.quota, available, req.file_size)
+    {
+        Some("insufficient_storage")
+    } else {
+        None
+    };
+
+    Ok(Json(CheckTransferResponse {
+        accepted: reason.is_none(),
+        reason: reason.map(str::to_owned),
+        already_present,
+        restricts_types: state.file_policy.restricts_types(),
+        chunk_sizes: ChunkSizes {
+            default: DEFAULT_CHUNK_SIZE,
+            min: encrypted_chunk_size(MIN_CHUNK_SIZE) as u64,
+            max: encrypted_chunk_size(MAX_CHUNK_SIZE) as u64,
+        },
+    }))
+}
+
 /// PUT /transfers/{id}/data — streaming upload.
 ///
 /// The body is the raw encrypted file data, written sequentially chunk by chunk.
@@ -181,123 +632,101 @@ pub async fn upload_data(
     headers: HeaderMap,
     body: Body,
 ) -> Result<StatusCode, StatusCode> {
-    let claims = extract_claims(&headers, &state.jwt_secret)?;
+    let claims = extract_claims(&headers, &state)?;
 
     // Verify transfer exists and caller is the uploader
-    let (chunk_size, file_size, uploader_id, current_status): (u64, u64, String, String) =
-        state.db.with_conn(|conn| {
-            conn.query_row(
-                "SELECT chunk_size, file_size, uploader_id, status FROM transfers WHERE id = ?1",
-                [&transfer_id],
-                |row| {
-                    Ok((
-                        row.get::<_, i64>(0)? as u64,
-                        row.get::<_, i64>(1)? as u64,
-                        row.get::<_, String>(2)?,
-                        row.get::<_, String>(3)?,
-                    ))
-                },
-            )
-            .map_err(|_| anyhow::anyhow!("Transfer not found"))
-        })
+    let (file_size, uploader_id, current_status): (u64, String, String) = state
+        .db
+        .query_row_cached(
+            "SELECT file_size, uploader_id, status FROM transfers WHERE id = ?1",
+            [&transfer_id],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)? as u64,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            },
+        )
         .map_err(|_| StatusCode::NOT_FOUND)?;
 
     if uploader_id != claims.sub.to_string() {
         return Err(StatusCode::FORBIDDEN);
     }
-    if current_status.as_str() != TStatus::Uploading.to_string() {
-        return Err(StatusCode::CONFLICT);
-    }
+    accept_upload(&state.db, &transfer_id, &current_status)?;
 
     // Load chunk metadata (expected hashes, offsets, lengths)
-    let chunks: Vec<(i64, String, u64, u64, bool)> = state.db.with_conn(|conn| {
-        let mut stmt = conn.prepare(
+    let chunks: Vec<(i64, String, u64, u64, bool)> = state
+        .db
+        .query_all_cached(
             "SELECT chunk_index, sha256, byte_offset, byte_length, received
-             FROM chunks WHERE transfer_id = ?1 ORDER BY chunk_index"
-        )?;
-        let rows = stmt.query_map([&transfer_id], |row| {
-            Ok((
-                row.get::<_, i64>(0)?,
-                row.get::<_, String>(1)?,
-                row.get::<_, i64>(2)? as u64,
-                row.get::<_, i64>(3)? as u64,
-                row.get::<_, bool>(4)?,
-            ))
-        })?;
-        rows.collect::<Result<Vec<_>, _>>().map_err(Into::into)
-    }).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    // Stream the body, splitting into chunk-sized pieces and verifying hashes
-    let mut stream = http_body_util::BodyStream::new(body);
-    use futures_util::StreamExt;
+             FROM chunks WHERE transfer_id = ?1 ORDER BY chunk_index",
+            [&transfer_id],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)? as u64,
+                    row.get::<_, i64>(3)? as u64,
+                    row.get::<_, bool>(4)?,
+                ))
+            },
+        )
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let mut buf = Vec::with_capacity(chunk_size as usize);
+    // Stream the body, splitting into chunk-sized pieces and verifying hashes.
+    // The body is only polled while the current chunk is short, so a slow
+    // disk holds back the client instead of growing a buffer.
+    let mut reader = ChunkReader::new(body.into_data_stream());
     let mut chunk_idx: usize = 0;
     let mut total_received: u64 = 0;
 
-    // Process all complete chunks out of `buf`. Requires a full `len` bytes
-    // for every chunk including the last — avoids hashing partial data.
-    macro_rules! flush_chunks {
-        () => {
-            while chunk_idx < chunks.len() {
-                let (_, ref expected_hash, offset, length, already_received) = chunks[chunk_idx];
-                let len = length as usize;
+    while chunk_idx < chunks.len() {
+        let (_, ref expected_hash, offset, length, already_received) = chunks[chunk_idx];
 
-                if buf.len() < len {
-                    break;
-                }
-
-                let chunk_data: Vec<u8> = buf.drain(..len).collect();
-
-                if !already_received {
-                    state
-                        .storage
-                        .write_chunk(&transfer_id, offset, expected_hash, &chunk_data)
-                        .await
-                        .map_err(|e| {
-                            warn!("Chunk {} hash verification failed: {}", chunk_idx, e);
-                            StatusCode::BAD_REQUEST
-                        })?;
-
-                    total_received += chunk_data.len() as u64;
-                    let tid = transfer_id.clone();
-                    let ci = chunk_idx as i64;
-                    let tr = total_received as i64;
-                    state.db.with_conn_mut(move |conn| {
-                        conn.execute(
-                            "UPDATE chunks SET received = 1 WHERE transfer_id = ?1 AND chunk_index = ?2",
-                            rusqlite::params![&tid, ci],
-                        )?;
-                        conn.execute(
-                            "UPDATE transfers SET bytes_received = ?1 WHERE id = ?2",
-                            rusqlite::params![tr, &tid],
-                        )?;
-                        Ok(())
-                    }).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-                }
-
-                if chunk_idx % 100 == 0 {
-                    info!(
-                        "Transfer {}: chunk {}/{} received ({} bytes total)",
-                        &transfer_id, chunk_idx, chunks.len(), total_received
-                    );
-                }
-
-                chunk_idx += 1;
-            }
+        // Requires a full `length` bytes for every chunk including the last;
+        // a body that ends early leaves the rest for a later upload.
+        let Some(chunk_data) = reader.next_chunk(length as usize).await? else {
+            break;
         };
-    }
 
-    while let Some(frame_result) = stream.next().await {
-        let frame = frame_result.map_err(|_| StatusCode::BAD_REQUEST)?;
-        if let Ok(data) = frame.into_data() {
-            buf.extend_from_slice(&data);
-            flush_chunks!();
+        if !already_received {
+            state
+                .storage
+                .write_chunk(&transfer_id, offset, expected_hash, &chunk_data)
+                .await
+                .map_err(|e| {
+                    warn!("Chunk {} hash verification failed: {}", chunk_idx, e);
+                    StatusCode::BAD_REQUEST
+                })?;
+
+            total_received += chunk_data.len() as u64;
+            let tid = transfer_id.clone();
+            let ci = chunk_idx as i64;
+            let tr = total_received as i64;
+            state.db.with_conn_mut(move |conn| {
+                conn.execute(
+                    "UPDATE chunks SET received = 1 WHERE transfer_id = ?1 AND chunk_index = ?2",
+                    rusqlite::params![&tid, ci],
+                )?;
+                conn.execute(
+                    "UPDATE transfers SET bytes_received = ?1 WHERE id = ?2",
+                    rusqlite::params![tr, &tid],
+                )?;
+                Ok(())
+            }).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+
+        if chunk_idx.is_multiple_of(100) {
+            info!(
+                "Transfer {}: chunk {}/{} received ({} bytes total)",
+                &transfer_id, chunk_idx, chunks.len(), total_received
+            );
         }
-    }
 
-    // Stream exhausted — process the last chunk from whatever remains in buf.
-    flush_chunks!();
+        chunk_idx += 1;
+    }
+    // Anything past the last chunk is left unread rather than buffered.
 
     // If we received all chunks, mark as complete
     if chunk_idx >= chunks.len() {
@@ -311,6 +740,8 @@ pub async fn upload_data(
         }).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
         info!("Transfer {} complete ({} bytes)", transfer_id, file_size);
+        check_assembled(&state, &transfer_id).await?;
+        spawn_index(&state, transfer_id);
     }
 
     Ok(StatusCode::OK)
@@ -327,7 +758,7 @@ pub async fn upload_chunk(
     headers: HeaderMap,
     body: Bytes,
 ) -> Result<StatusCode, StatusCode> {
-    let claims = extract_claims(&headers, &state.jwt_secret)?;
+    let claims = extract_claims(&headers, &state)?;
 
     // Single JOIN query: transfer auth/status + chunk metadata in one round-trip.
     // Uses writer connection to guarantee visibility of just-created transfers
@@ -335,11 +766,13 @@ pub async fn upload_chunk(
     let (uploader_id, current_status, offset, byte_length, already_received): (String, String, u64, u64, bool) = state
         .db
         .with_conn_mut(|conn| {
-            conn.query_row(
+            conn.prepare_cached(
                 "SELECT t.uploader_id, t.status, c.byte_offset, c.byte_length, c.received
                  FROM transfers t
                  JOIN chunks c ON c.transfer_id = t.id
                  WHERE t.id = ?1 AND c.chunk_index = ?2",
+            )?
+            .query_row(
                 rusqlite::params![&transfer_id, chunk_index],
                 |row| {
                     Ok((
@@ -358,9 +791,7 @@ pub async fn upload_chunk(
     if uploader_id != claims.sub.to_string() {
         return Err(StatusCode::FORBIDDEN);
     }
-    if current_status.as_str() != TStatus::Uploading.to_string() {
-        return Err(StatusCode::CONFLICT);
-    }
+    accept_upload(&state.db, &transfer_id, &current_status)?;
 
     // Idempotent: already received -> 200
     if already_received {
@@ -404,79 +835,226 @@ pub async fn upload_chunk(
         // No flush — OS page cache coalesces writes for pre-allocated file
     }
 
-    // Mark chunk received + check completion (2 ops, no bytes_received update)
+    // Mark chunk received + check completion (no bytes_received update)
+    let completed = mark_chunks_received(&state.db, &transfer_id, &[chunk_index]).map_err(|e| {
+        warn!("DB update failed for chunk {}: {}", chunk_index, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if completed {
+        check_assembled(&state, &transfer_id).await?;
+        spawn_index(&state, transfer_id);
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Most chunks one batch upload may carry.
+pub const MAX_CHUNK_BATCH: usize = 64;
+
+/// Split a batch body into `(chunk_index, data)` pairs. The body is each
+/// chunk in turn as `[u32 LE index][u32 LE length][data]`. `None` if it is
+/// truncated, empty, too long or names a chunk twice.
+fn parse_chunk_batch(body: &Bytes) -> Option<Vec<(i64, Bytes)>> {
+    let mut chunks = Vec::new();
+    let mut seen = HashSet::new();
+    let mut pos = 0;
+    while pos < body.len() {
+        let header = body.get(pos..pos + 8)?;
+        let index = u32::from_le_bytes(header[..4].try_into().unwrap()) as i64;
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let start = pos + 8;
+        if body.len() - start < len || !seen.insert(index) || chunks.len() == MAX_CHUNK_BATCH {
+            return None;
+        }
+        chunks.push((index, body.slice(start..start + len)));
+        pos = start + len;
+    }
+    (!chunks.is_empty()).then_some(chunks)
+}
+
+/// PUT /transfers/{id}/chunks:batch — upload several chunks in one request.
+///
+/// Saves a round-trip per chunk on high-latency links. Every chunk is checked
+/// against its metadata before any is written; they are then written through
+/// one file handle and marked received in one transaction. Chunks already
+/// received are skipped, so a retried batch is idempotent.
+pub async fn upload_chunk_batch(
+    State(state): State<AppState>,
+    Path(transfer_id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, StatusCode> {
+    let claims = extract_claims(&headers, &state)?;
+    let chunks = parse_chunk_batch(&body).ok_or(StatusCode::BAD_REQUEST)?;
+
+    // Writer connection for the same reason as `upload_chunk`.
+    let indices: Vec<i64> = chunks.iter().map(|(i, _)| *i).collect();
     let tid = transfer_id.clone();
-    state
+    let (uploader_id, current_status, meta) = state
         .db
         .with_conn_mut(move |conn| {
-            conn.execute(
-                "UPDATE chunks SET received = 1 WHERE transfer_id = ?1 AND chunk_index = ?2",
-                rusqlite::params![&tid, chunk_index],
+            let (uploader, status): (String, String) = conn
+                .prepare_cached("SELECT uploader_id, status FROM transfers WHERE id = ?1")?
+                .query_row([&tid], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            let mut stmt = conn.prepare_cached(
+                "SELECT byte_offset, byte_length, received FROM chunks
+                 WHERE transfer_id = ?1 AND chunk_index = ?2",
             )?;
+            let meta = indices
+                .iter()
+                .map(|&index| {
+                    stmt.query_row(rusqlite::params![&tid, index], |row| {
+                        Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64, row.get::<_, bool>(2)?))
+                    })
+                })
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok((uploader, status, meta))
+        })
+        .map_err(|_| StatusCode::NOT_FOUND)?;
 
-            let unreceived: i64 = conn.query_row(
-                "SELECT COUNT(*) FROM chunks WHERE transfer_id = ?1 AND received = 0",
-                [&tid],
-                |r| r.get(0),
-            )?;
-            if unreceived == 0 {
-                conn.execute(
-                    "UPDATE transfers SET status = 'complete', bytes_received = file_size WHERE id = ?1",
-                    [&tid],
-                )?;
-            }
+    if uploader_id != claims.sub.to_string() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    accept_upload(&state.db, &transfer_id, &current_status)?;
 
-            Ok(())
-        })
-        .map_err(|e| {
-            warn!("DB update failed for chunk {}: {}", chunk_index, e);
+    for ((index, data), (_, byte_length, _)) in chunks.iter().zip(&meta) {
+        if data.len() as u64 != *byte_length {
+            warn!("Batch chunk {} body length {} != expected {}", index, data.len(), byte_length);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    let pending: Vec<_> = chunks
+        .iter()
+        .zip(&meta)
+        .filter(|(_, (_, _, received))| !received)
+        .map(|((index, data), (offset, _, _))| (*index, *offset, data))
+        .collect();
+    if pending.is_empty() {
+        return Ok(StatusCode::OK);
+    }
+
+    {
+        use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+        let path = state.storage.file_path(&transfer_id);
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(&path).await.map_err(|e| {
+            warn!("Failed to open file for chunk batch on {}: {}", transfer_id, e);
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
+        for (index, offset, data) in &pending {
+            file.seek(std::io::SeekFrom::Start(*offset)).await.map_err(|e| {
+                warn!("Failed to seek for chunk {}: {}", index, e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+            file.write_all(data).await.map_err(|e| {
+                warn!("Failed to write chunk {}: {}", index, e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        }
+    }
+
+    let written: Vec<i64> = pending.iter().map(|(index, _, _)| *index).collect();
+    let completed = mark_chunks_received(&state.db, &transfer_id, &written).map_err(|e| {
+        warn!("DB update failed for chunk batch on {}: {}", transfer_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if completed {
+        check_assembled(&state, &transfer_id).await?;
+        spawn_index(&state, transfer_id);
+    }
 
     Ok(StatusCode::OK)
 }
 
+/// Mark `indices` received and, if that was the last of them, the transfer
+/// complete, all in one transaction. Returns whether the transfer completed.
+fn mark_chunks_received(db: &FileDb, transfer_id: &str, indices: &[i64]) -> anyhow::Result<bool> {
+    db.with_transaction(|conn| {
+        let mut stmt =
+            conn.prepare_cached("UPDATE chunks SET received = 1 WHERE transfer_id = ?1 AND chunk_index = ?2")?;
+        for index in indices {
+            stmt.execute(rusqlite::params![transfer_id, index])?;
+        }
+
+        let unreceived: i64 = conn
+            .prepare_cached("SELECT COUNT(*) FROM chunks WHERE transfer_id = ?1 AND received = 0")?
+            .query_row([transfer_id], |r| r.get(0))?;
+        if unreceived == 0 {
+            conn.execute(
+                "UPDATE transfers SET status = 'complete', bytes_received = file_size WHERE id = ?1",
+                [transfer_id],
+            )?;
+        }
+
+        Ok(unreceived == 0)
+    })
+}
+
 /// GET /transfers/{id}/data — streaming download.
 ///
-/// Supports HTTP Range header for resume. Serves bytes up to `bytes_received`,
+/// Supports HTTP Range header for resume (`bytes=START-`) and for re-fetching a
+/// single chunk (`bytes=START-END`). Serves bytes up to `bytes_received`,
 /// allowing the receiver to start downloading before the upload completes.
 pub async fn download_data(
     State(state): State<AppState>,
     Path(transfer_id): Path<String>,
     headers: HeaderMap,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let _claims = extract_claims(&headers, &state.jwt_secret)?;
+    let claims = extract_claims(&headers, &state)?;
 
     // Get transfer info
-    let (file_size, bytes_received, status): (u64, u64, String) = state.db.with_conn(|conn| {
-        conn.query_row(
-            "SELECT file_size, bytes_received, status FROM transfers WHERE id = ?1",
+    let (file_size, bytes_received, status, filename, content_type, seats): (
+        u64,
+        u64,
+        String,
+        Option<String>,
+        Option<String>,
+        Option<usize>,
+    ) = state
+        .db
+        .query_row_cached(
+            "SELECT file_size, bytes_received, status, filename, content_type, max_downloads
+             FROM transfers WHERE id = ?1",
             [&transfer_id],
             |row| {
                 Ok((
                     row.get::<_, i64>(0)? as u64,
                     row.get::<_, i64>(1)? as u64,
                     row.get::<_, String>(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get::<_, Option<i64>>(5)?.map(|n| n.max(1) as usize),
                 ))
             },
         )
-        .map_err(|_| anyhow::anyhow!("Transfer not found"))
-    }).map_err(|_| StatusCode::NOT_FOUND)?;
+        .map_err(|_| StatusCode::NOT_FOUND)?;
 
-    if status.as_str() == TStatus::Expired.to_string() {
+    if status.as_str() == TStatus::Expired.to_string() || status.as_str() == TStatus::Cancelled.to_string() {
         return Err(StatusCode::GONE);
     }
+    if status.as_str() == TStatus::Corrupt.to_string() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
 
-    // Parse Range header for resume support
-    let start_offset = parse_range_start(&headers).unwrap_or(0);
+    // Parse Range header for resume / single-chunk support
+    let range = parse_range(&headers);
+    let (start_offset, range_end) = range.unwrap_or((0, None));
+    check_download_session(&state, &transfer_id, claims.sub, seats)?;
 
     // Determine how many bytes are available to serve
-    let available = if status.as_str() == TStatus::Complete.to_string() { file_size } else { bytes_received };
-    if start_offset >= available {
+    let complete = status.as_str() == TStatus::Complete.to_string();
+    let available = if complete { file_size } else { bytes_received };
+    // A complete empty file is still served whole, as an empty body.
+    let empty = complete && available == 0 && range.is_none();
+    if start_offset >= available && !empty {
         return Err(StatusCode::RANGE_NOT_SATISFIABLE);
     }
 
-    let content_length = available - start_offset;
+    // An end past what's available is clamped, per RFC 9110.
+    let end_offset = range_end.map_or(available.saturating_sub(1), |end| end.min(available - 1));
+    let content_length = if empty { 0 } else { end_offset - start_offset + 1 };
     let transfer_id_owned = transfer_id.clone();
     let storage = state.storage.clone();
 
@@ -520,14 +1098,21 @@ pub async fn download_data(
     let body = Body::from_stream(stream);
 
     let mut response_headers = HeaderMap::new();
-    response_headers.insert(header::CONTENT_TYPE, "application/octet-stream".parse().unwrap());
+    // Both were validated at create time; a bad stored value falls back.
+    let content_type = content_type
+        .and_then(|ct| ct.parse().ok())
+        .unwrap_or_else(|| header::HeaderValue::from_static("application/octet-stream"));
+    response_headers.insert(header::CONTENT_TYPE, content_type);
+    if let Some(disposition) = filename.as_deref().and_then(|name| content_disposition(name).parse().ok()) {
+        response_headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
     response_headers.insert(header::CONTENT_LENGTH, content_length.to_string().parse().unwrap());
     response_headers.insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
 
-    if start_offset > 0 {
+    if range.is_some_and(|(start, end)| start > 0 || end.is_some()) {
         response_headers.insert(
             header::CONTENT_RANGE,
-            format!("bytes {}-{}/{}", start_offset, available - 1, file_size)
+            format!("bytes {}-{}/{}", start_offset, end_offset, file_size)
                 .parse()
                 .unwrap(),
         );
@@ -543,11 +1128,13 @@ pub async fn get_transfer_status(
     Path(transfer_id): Path<String>,
     headers: HeaderMap,
 ) -> Result<Json<TransferStatus>, StatusCode> {
-    let _claims = extract_claims(&headers, &state.jwt_secret)?;
+    let _claims = extract_claims(&headers, &state)?;
 
-    let status = state.db.with_conn(|conn| {
-        conn.query_row(
-            "SELECT id, status, file_size, bytes_received, chunk_count, created_at
+    let status = state
+        .db
+        .query_row_cached(
+            "SELECT id, status, file_size, bytes_received, chunk_count, created_at, control_mac, chunk_size,
+                    file_sha256, compressed, aead, sparse_chunks
              FROM transfers WHERE id = ?1",
             [&transfer_id],
             |row| {
@@ -557,12 +1144,20 @@ pub async fn get_transfer_status(
                     file_size: row.get::<_, i64>(2)? as u64,
                     bytes_received: row.get::<_, i64>(3)? as u64,
                     chunk_count: row.get::<_, i64>(4)? as u64,
+                    chunk_size: row.get::<_, i64>(7)? as u64,
+                    file_sha256: row.get(8)?,
                     created_at: row.get(5)?,
+                    control_mac: row.get(6)?,
+                    compressed: row.get(9)?,
+                    aead: row.get(10)?,
+                    sparse_chunks: row
+                        .get::<_, Option<String>>(11)?
+                        .and_then(|json| serde_json::from_str(&json).ok())
+                        .unwrap_or_default(),
                 })
             },
         )
-        .map_err(|_| anyhow::anyhow!("Transfer not found"))
-    }).map_err(|_| StatusCode::NOT_FOUND)?;
+        .map_err(|_| StatusCode::NOT_FOUND)?;
 
     Ok(Json(status))
 }
@@ -575,24 +1170,21 @@ pub async fn get_chunk_status(
     Path(transfer_id): Path<String>,
     headers: HeaderMap,
 ) -> Result<Json<ChunkStatusResponse>, StatusCode> {
-    let _claims = extract_claims(&headers, &state.jwt_secret)?;
+    let _claims = extract_claims(&headers, &state)?;
 
     // Get transfer info using writer connection for WAL visibility
     let (status, chunk_count, bytes_received): (String, u64, u64) = state
         .db
         .with_conn_mut(|conn| {
-            conn.query_row(
-                "SELECT status, chunk_count, bytes_received FROM transfers WHERE id = ?1",
-                [&transfer_id],
-                |row| {
+            conn.prepare_cached("SELECT status, chunk_count, bytes_received FROM transfers WHERE id = ?1")?
+                .query_row([&transfer_id], |row| {
                     Ok((
                         row.get::<_, String>(0)?,
                         row.get::<_, i64>(1)? as u64,
                         row.get::<_, i64>(2)? as u64,
                     ))
-                },
-            )
-            .map_err(|_| anyhow::anyhow!("Transfer not found"))
+                })
+                .map_err(|_| anyhow::anyhow!("Transfer not found"))
         })
         .map_err(|_| StatusCode::NOT_FOUND)?;
 
@@ -600,7 +1192,7 @@ pub async fn get_chunk_status(
     let received_chunks: Vec<u64> = state
         .db
         .with_conn_mut(|conn| {
-            let mut stmt = conn.prepare(
+            let mut stmt = conn.prepare_cached(
                 "SELECT chunk_index FROM chunks WHERE transfer_id = ?1 AND received = 1 ORDER BY chunk_index",
             )?;
             let rows = stmt
@@ -620,21 +1212,81 @@ pub async fn get_chunk_status(
     }))
 }
 
+/// GET /transfers/{id}/manifest — every chunk's byte range and hash.
+///
+/// Lets a client fetch chunks independently, in parallel if it likes, with
+/// ranged `GET /transfers/{id}/data` requests and verify each as it lands,
+/// so a failure costs one chunk rather than the rest of the stream. A
+/// projection of the `chunks` table.
+pub async fn get_manifest(
+    State(state): State<AppState>,
+    Path(transfer_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<ManifestResponse>, StatusCode> {
+    let _claims = extract_claims(&headers, &state)?;
+
+    let (status, file_size, file_sha256): (String, u64, String) = state
+        .db
+        .query_row_cached(
+            "SELECT status, file_size, file_sha256 FROM transfers WHERE id = ?1",
+            [&transfer_id],
+            |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64, row.get(2)?)),
+        )
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    if status == TStatus::Expired.to_string() || status == TStatus::Cancelled.to_string() {
+        return Err(StatusCode::GONE);
+    }
+    if status == TStatus::Corrupt.to_string() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let chunks = state
+        .db
+        .query_all_cached(
+            "SELECT chunk_index, byte_offset, byte_length, sha256, received FROM chunks
+             WHERE transfer_id = ?1 ORDER BY chunk_index",
+            [&transfer_id],
+            |row| {
+                Ok(ManifestChunk {
+                    index: row.get::<_, i64>(0)? as u64,
+                    offset: row.get::<_, i64>(1)? as u64,
+                    length: row.get::<_, i64>(2)? as u64,
+                    sha256: row.get(3)?,
+                    received: row.get::<_, i64>(4)? != 0,
+                })
+            },
+        )
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(ManifestResponse { transfer_id, status, file_size, file_sha256, chunks }))
+}
+
 /// POST /transfers/{id}/confirm — receiver confirms successful download.
 /// Server deletes the file from disk. Only the uploader can confirm.
+///
+/// A transfer created for several recipients counts confirmations (one per
+/// `recipient_id` in the optional JSON body) and answers `202` while some
+/// are still outstanding; the file goes with the last one. With
+/// `max_downloads` set, that many confirms release it and expire the transfer.
 pub async fn confirm_transfer(
     State(state): State<AppState>,
     Path(transfer_id): Path<String>,
     headers: HeaderMap,
+    body: Bytes,
 ) -> Result<StatusCode, StatusCode> {
-    let claims = extract_claims(&headers, &state.jwt_secret)?;
+    let claims = extract_claims(&headers, &state)?;
+    let req: ConfirmRequest = if body.is_empty() {
+        ConfirmRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?
+    };
 
     // Verify the caller is the uploader
-    let uploader_id: String = state.db.with_conn(|conn| {
+    let (uploader_id, recipients, max_downloads): (String, i64, Option<i64>) = state.db.with_conn(|conn| {
         conn.query_row(
-            "SELECT uploader_id FROM transfers WHERE id = ?1",
+            "SELECT uploader_id, recipients, max_downloads FROM transfers WHERE id = ?1",
             [&transfer_id],
-            |row| row.get(0),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
         )
         .map_err(|_| anyhow::anyhow!("Transfer not found"))
     }).map_err(|_| StatusCode::NOT_FOUND)?;
@@ -643,32 +1295,100 @@ pub async fn confirm_transfer(
         return Err(StatusCode::FORBIDDEN);
     }
 
+    // A download cap replaces the recipient count as the number of confirms
+    // that releases the file.
+    let needed = max_downloads.unwrap_or(recipients);
+    if needed > 1 {
+        let recipient = req.recipient_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+        let confirmed: i64 = state.db.with_conn_mut(|conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO transfer_confirmations (transfer_id, recipient) VALUES (?1, ?2)",
+                [&transfer_id, &recipient],
+            )?;
+            Ok(conn.query_row(
+                "SELECT COUNT(*) FROM transfer_confirmations WHERE transfer_id = ?1",
+                [&transfer_id],
+                |row| row.get(0),
+            )?)
+        }).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if confirmed < needed {
+            info!("Transfer {} confirmed {} of {} times", transfer_id, confirmed, needed);
+            return Ok(StatusCode::ACCEPTED);
+        }
+    }
+
     // Delete file from disk
-    state.storage.delete_file(&transfer_id).await.map_err(|e| {
+    dedup::release(&state.db, &state.storage, &transfer_id).await.map_err(|e| {
         warn!("Failed to delete file for {}: {}", transfer_id, e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    // Mark as confirmed in DB
+    // Mark as confirmed in DB. A used-up download cap expires the transfer,
+    // so later downloads of the link get `410`.
+    let status = if max_downloads.is_some() { TStatus::Expired } else { TStatus::Confirmed };
     state.db.with_conn_mut(|conn| {
         conn.execute(
-            "UPDATE transfers SET status = 'confirmed' WHERE id = ?1",
-            [&transfer_id],
+            "UPDATE transfers SET status = ?1 WHERE id = ?2",
+            rusqlite::params![status.to_string(), &transfer_id],
         )?;
         Ok(())
     }).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    state.download_sessions.lock().unwrap().remove(&transfer_id);
+
     info!("Transfer {} confirmed and file deleted", transfer_id);
     Ok(StatusCode::OK)
 }
 
+/// PUT /transfers/{id}/recipients — set how many recipients' confirms
+/// release the file (uploader only), once a fan-out offer has gone out and
+/// the count is known. Works the same for transfers uploaded over fast
+/// transfer, which are created without a count.
+pub async fn set_recipients(
+    State(state): State<AppState>,
+    Path(transfer_id): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<SetRecipientsRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = extract_claims(&headers, &state)?;
+
+    if !(1..=MAX_RECIPIENTS).contains(&req.recipients) {
+        warn!("Rejecting {} recipients for transfer {}", req.recipients, transfer_id);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let uploader_id: String = state.db.with_conn(|conn| {
+        conn.query_row(
+            "SELECT uploader_id FROM transfers WHERE id = ?1",
+            [&transfer_id],
+            |row| row.get(0),
+        )
+        .map_err(|_| anyhow::anyhow!("Transfer not found"))
+    }).map_err(|_| StatusCode::NOT_FOUND)?;
+
+    if uploader_id != claims.sub.to_string() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    state.db.with_conn_mut(|conn| {
+        conn.execute(
+            "UPDATE transfers SET recipients = ?1 WHERE id = ?2",
+            rusqlite::params![req.recipients, &transfer_id],
+        )?;
+        Ok(())
+    }).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    info!("Transfer {} set to {} recipients", transfer_id, req.recipients);
+    Ok(StatusCode::NO_CONTENT)
+}
+
 /// DELETE /transfers/{id} — delete a transfer (uploader only).
 pub async fn delete_transfer(
     State(state): State<AppState>,
     Path(transfer_id): Path<String>,
     headers: HeaderMap,
 ) -> Result<StatusCode, StatusCode> {
-    let claims = extract_claims(&headers, &state.jwt_secret)?;
+    let claims = extract_claims(&headers, &state)?;
 
     // Verify ownership
     let uploader_id: String = state.db.with_conn(|conn| {
@@ -685,7 +1405,7 @@ pub async fn delete_transfer(
     }
 
     // Delete file from disk
-    state.storage.delete_file(&transfer_id).await.ok();
+    dedup::release(&state.db, &state.storage, &transfer_id).await.ok();
 
     // Delete from DB (CASCADE deletes chunks too)
     state.db.with_conn_mut(|conn| {
@@ -693,6 +1413,8 @@ pub async fn delete_transfer(
         Ok(())
     }).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    state.download_sessions.lock().unwrap().remove(&transfer_id);
+
     info!("Transfer {} deleted by {}", transfer_id, claims.username);
     Ok(StatusCode::OK)
 }
@@ -702,76 +1424,237 @@ pub async fn health() -> &'static str {
     "ok"
 }
 
-/// GET /transfers/all — list all transfers (admin, internal only).
-/// Rejects non-loopback callers for defense in depth.
-pub async fn list_all_transfers(
+/// GET /ready — readiness check (no auth): DB, storage and UDP socket usable,
+/// and not shutting down. `200` if all pass, else `503`; the body lists each.
+pub async fn ready(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let mut report = Readiness::default();
+    report.check(
+        "database",
+        state.db.with_conn(|conn| Ok(conn.query_row("SELECT 1", [], |_| Ok(()))?)),
+    );
+    report.check("storage", state.storage.check_writable().await);
+    report.check("udp", state.udp_socket.local_addr().map(|_| ()));
+    report.check(
+        "accepting",
+        if state.transfers.is_draining() { Err("shutting down") } else { Ok(()) },
+    );
+
+    let status = if report.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(report))
+}
+
+/// GET /admin/transfers?limit=&offset= — every transfer, newest first, a
+/// page at a time (admin token, loopback callers only).
+pub async fn admin_list_transfers(
     axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<std::net::SocketAddr>,
     State(state): State<AppState>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
-    if !peer.ip().is_loopback() {
-        return Err(StatusCode::FORBIDDEN);
-    }
+    headers: HeaderMap,
+    Query(page): Query<AdminTransferPage>,
+) -> Result<Json<AdminTransferList>, StatusCode> {
+    check_admin(peer, &headers, &state)?;
+    let limit = page.limit.unwrap_or(ADMIN_PAGE_DEFAULT).clamp(1, ADMIN_PAGE_MAX);
+    let offset = page.offset.unwrap_or(0);
+
     let db = state.db.clone();
-    let transfers = tokio::task::spawn_blocking(move || {
+    let list = tokio::task::spawn_blocking(move || {
         db.with_conn(|conn| {
+            let total: i64 = conn.query_row("SELECT COUNT(*) FROM transfers", [], |row| row.get(0))?;
             let mut stmt = conn.prepare(
-                "SELECT id, uploader_id, file_size, chunk_size, chunk_count,
-                        file_sha256, bytes_received, status, created_at
-                 FROM transfers ORDER BY created_at DESC LIMIT 200",
+                "SELECT id, uploader_id, file_size, chunk_size, chunk_count, file_sha256, bytes_received,
+                        status, created_at, expires_at, filename
+                 FROM transfers ORDER BY created_at DESC, id LIMIT ?1 OFFSET ?2",
             )?;
-            let rows: Vec<serde_json::Value> = stmt
-                .query_map([], |row| {
-                    Ok(serde_json::json!({
-                        "id": row.get::<_, String>(0)?,
-                        "uploader_id": row.get::<_, String>(1)?,
-                        "file_size": row.get::<_, i64>(2)?,
-                        "chunk_size": row.get::<_, i64>(3)?,
-                        "chunk_count": row.get::<_, i64>(4)?,
-                        "file_sha256": row.get::<_, String>(5)?,
-                        "bytes_received": row.get::<_, i64>(6)?,
-                        "status": row.get::<_, String>(7)?,
-                        "created_at": row.get::<_, String>(8)?,
-                    }))
+            let transfers = stmt
+                .query_map(rusqlite::params![limit, offset], |row| {
+                    Ok(AdminTransfer {
+                        id: row.get(0)?,
+                        uploader_id: row.get(1)?,
+                        file_size: row.get::<_, i64>(2)? as u64,
+                        chunk_size: row.get::<_, i64>(3)? as u64,
+                        chunk_count: row.get::<_, i64>(4)? as u64,
+                        file_sha256: row.get(5)?,
+                        bytes_received: row.get::<_, i64>(6)? as u64,
+                        status: row.get(7)?,
+                        created_at: row.get(8)?,
+                        expires_at: row.get(9)?,
+                        filename: row.get(10)?,
+                    })
                 })?
                 .collect::<std::result::Result<Vec<_>, _>>()?;
-            Ok::<_, anyhow::Error>(serde_json::json!({ "transfers": rows }))
+            Ok(AdminTransferList { transfers, total: total as u64, limit, offset })
         })
     })
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(|e| {
+        warn!("Failed to list transfers: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
 
-    Ok(Json(transfers))
+    Ok(Json(list))
 }
 
-/// DELETE /admin/transfers/{id} — admin delete transfer (internal only).
-/// Rejects non-loopback callers for defense in depth.
+/// DELETE /admin/transfers/{id} — purge a transfer now, whatever its state
+/// (admin token, loopback callers only): its file and its transfer, chunk
+/// and confirmation rows. Content deduplicated onto other transfers would
+/// otherwise stay downloadable through them, so every transfer sharing its
+/// blob is purged too, and the blob with them. Returns the ids purged.
 pub async fn admin_delete_transfer(
     axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<std::net::SocketAddr>,
     State(state): State<AppState>,
     Path(transfer_id): Path<String>,
-) -> Result<StatusCode, StatusCode> {
-    if !peer.ip().is_loopback() {
-        return Err(StatusCode::FORBIDDEN);
+    headers: HeaderMap,
+) -> Result<Json<AdminPurge>, StatusCode> {
+    check_admin(peer, &headers, &state)?;
+    let purged = state
+        .db
+        .with_conn(|conn| {
+            use rusqlite::OptionalExtension;
+            let Some(blob) = conn
+                .query_row("SELECT blob_sha256 FROM transfers WHERE id = ?1", [&transfer_id], |row| {
+                    row.get::<_, Option<String>>(0)
+                })
+                .optional()?
+            else {
+                return Ok(Vec::new());
+            };
+            let mut ids = vec![transfer_id.clone()];
+            if let Some(blob) = blob {
+                let mut stmt = conn.prepare("SELECT id FROM transfers WHERE blob_sha256 = ?1 AND id != ?2 ORDER BY id")?;
+                let sharing = stmt.query_map([&blob, &transfer_id], |row| row.get::<_, String>(0))?;
+                ids.extend(sharing.collect::<std::result::Result<Vec<_>, _>>()?);
+            }
+            Ok(ids)
+        })
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if purged.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    for id in &purged {
+        // Delete file from disk (ignore if already gone); the last one out
+        // takes the blob
+        dedup::release(&state.db, &state.storage, id).await.ok();
     }
-    // Delete file from disk (ignore if already gone)
-    state.storage.delete_file(&transfer_id).await.ok();
 
-    // Delete from DB
+    // Delete from DB (CASCADE deletes chunks and confirmations too)
     state
         .db
-        .with_conn_mut(|conn| {
-            conn.execute("DELETE FROM transfers WHERE id = ?1", [&transfer_id])?;
+        .with_transaction(|conn| {
+            for id in &purged {
+                conn.execute("DELETE FROM transfers WHERE id = ?1", [id])?;
+            }
             Ok(())
         })
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    info!("Admin deleted transfer {}", transfer_id);
-    Ok(StatusCode::NO_CONTENT)
+    let mut sessions = state.download_sessions.lock().unwrap();
+    for id in &purged {
+        sessions.remove(id);
+    }
+    drop(sessions);
+
+    info!("Admin purged transfer {} ({} sharing its content)", transfer_id, purged.len() - 1);
+    Ok(Json(AdminPurge { purged }))
+}
+
+/// Admin routes need an admin token and, for defense in depth, a loopback
+/// caller: the messaging server's admin API proxies to them from the same
+/// host.
+fn check_admin(peer: std::net::SocketAddr, headers: &HeaderMap, state: &AppState) -> Result<AdminClaims, StatusCode> {
+    if !peer.ip().is_loopback() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    extract_admin_claims(headers, state)
 }
 
 // ── Helpers ─────────────────────────────────────────────────────────────
 
+/// Hash and index a completed upload for dedup in the background.
+pub fn spawn_index(state: &AppState, transfer_id: String) {
+    let db = state.db.clone();
+    let storage = state.storage.clone();
+    tokio::spawn(async move {
+        dedup::index_completed(&db, &storage, &transfer_id).await;
+    });
+}
+
+/// Whole-file check after the last chunk, if enabled. A mismatch has left
+/// the transfer `corrupt` and is reported to the uploader as `422`.
+async fn check_assembled(state: &AppState, transfer_id: &str) -> Result<(), StatusCode> {
+    if !state.verify_uploads {
+        return Ok(());
+    }
+    match verify::verify_upload(&state.db, &state.storage, transfer_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(StatusCode::UNPROCESSABLE_ENTITY),
+        Err(e) => {
+            warn!("Failed to verify transfer {}: {}", transfer_id, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Cuts an upload body into chunks while holding at most one chunk plus the
+/// frame it is being filled from.
+struct ChunkReader<S> {
+    stream: S,
+    /// Unconsumed tail of the last frame.
+    pending: Bytes,
+    /// Most bytes held at once, for tests.
+    peak: usize,
+}
+
+impl<S, E> ChunkReader<S>
+where
+    S: futures_util::Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    fn new(stream: S) -> Self {
+        Self { stream, pending: Bytes::new(), peak: 0 }
+    }
+
+    /// The next `len` bytes, or `None` if the body ends first.
+    async fn next_chunk(&mut self, len: usize) -> Result<Option<Vec<u8>>, StatusCode> {
+        use futures_util::StreamExt;
+
+        let mut chunk = Vec::with_capacity(len);
+        while chunk.len() < len {
+            if self.pending.is_empty() {
+                match self.stream.next().await {
+                    Some(Ok(data)) => self.pending = data,
+                    Some(Err(_)) => return Err(StatusCode::BAD_REQUEST),
+                    None => return Ok(None),
+                }
+                self.peak = self.peak.max(chunk.len() + self.pending.len());
+            }
+            let take = (len - chunk.len()).min(self.pending.len());
+            chunk.extend_from_slice(&self.pending.split_to(take));
+        }
+        Ok(Some(chunk))
+    }
+}
+
+/// Check that a transfer takes upload data. One interrupted by a shutdown is
+/// flipped back to `uploading`, so clients resume by simply sending again.
+fn accept_upload(db: &FileDb, transfer_id: &str, status: &str) -> Result<(), StatusCode> {
+    if status == TStatus::Uploading.to_string() {
+        return Ok(());
+    }
+    if status != TStatus::Interrupted.to_string() {
+        return Err(StatusCode::CONFLICT);
+    }
+    db.with_conn_mut(|conn| {
+        conn.execute(
+            "UPDATE transfers SET status = ?1 WHERE id = ?2 AND status = ?3",
+            rusqlite::params![TStatus::Uploading.to_string(), transfer_id, TStatus::Interrupted.to_string()],
+        )?;
+        Ok(())
+    })
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    info!("Resuming interrupted upload {}", transfer_id);
+    Ok(())
+}
+
 /// GET /fast-transfer — WebSocket upgrade for UDP blast transfer control.
 ///
 /// Clients connect here to initiate fast uploads/downloads.
@@ -783,15 +1666,7 @@ pub async fn fast_transfer_ws(
     axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let token = params.get("token").ok_or(StatusCode::UNAUTHORIZED)?;
-
-    let token_data = jsonwebtoken::decode::<Claims>(
-        token,
-        &jsonwebtoken::DecodingKey::from_secret(state.jwt_secret.as_bytes()),
-        &jsonwebtoken::Validation::default(),
-    )
-    .map_err(|_| StatusCode::UNAUTHORIZED)?;
-
-    let claims = token_data.claims;
+    let claims = decode_token(token, &state)?;
     info!("Fast transfer WS upgrade: user={} peer={}", claims.username, peer_addr);
 
     Ok(ws.on_upgrade(move |socket| {
@@ -799,10 +1674,1097 @@ pub async fn fast_transfer_ws(
     }))
 }
 
-fn parse_range_start(headers: &HeaderMap) -> Option<u64> {
+/// Parse `bytes=START-` or `bytes=START-END` into `(start, inclusive end)`.
+///
+/// Anything else — suffix ranges, multiple ranges, END before START — is
+/// `None`, and the header is ignored as RFC 9110 allows.
+fn parse_range(headers: &HeaderMap) -> Option<(u64, Option<u64>)> {
     let range = headers.get(header::RANGE)?.to_str().ok()?;
-    // Parse "bytes=START-" or "bytes=START-END"
-    let range = range.strip_prefix("bytes=")?;
-    let start_str = range.split('-').next()?;
-    start_str.parse().ok()
+    let range = range.trim().strip_prefix("bytes=")?;
+    let (start_str, end_str) = range.split_once('-')?;
+    let start = start_str.trim().parse().ok()?;
+    let end_str = end_str.trim();
+    if end_str.is_empty() {
+        return Some((start, None));
+    }
+    let end: u64 = end_str.parse().ok()?;
+    (end >= start).then_some((start, Some(end)))
+}
+
+/// Longest filename kept, in bytes.
+const MAX_FILENAME_LEN: usize = 255;
+
+/// Reduce a client-supplied filename to something safe to echo in a header:
+/// the last path component, without control characters, at most
+/// `MAX_FILENAME_LEN` bytes. `None` if nothing usable is left.
+fn sanitize_filename(name: &str) -> Option<String> {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let mut clean = String::new();
+    for c in base.chars().filter(|c| !c.is_control()) {
+        if clean.len() + c.len_utf8() > MAX_FILENAME_LEN {
+            break;
+        }
+        clean.push(c);
+    }
+    let clean = clean.trim();
+    (!clean.is_empty() && clean != "." && clean != "..").then(|| clean.to_string())
+}
+
+/// Minutes a new transfer is kept: what it asked for, clamped to between one
+/// minute and the server's `max_hours`.
+fn retention_minutes(requested: Option<u64>, max_hours: u64) -> u64 {
+    let max = max_hours.saturating_mul(60);
+    requested.map_or(max, |minutes| minutes.clamp(1, max.max(1)))
+}
+
+/// `type/subtype` with optional `; param=value` pairs, all in visible ASCII.
+pub fn is_valid_content_type(ct: &str) -> bool {
+    let is_token = |s: &str| {
+        !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b))
+    };
+    let mut parts = ct.split(';');
+    let essence = parts.next().unwrap_or_default().trim();
+    let Some((ty, subtype)) = essence.split_once('/') else {
+        return false;
+    };
+    ct.len() <= 255
+        && ct.bytes().all(|b| b == b' ' || b.is_ascii_graphic())
+        && is_token(ty)
+        && is_token(subtype)
+        && parts.all(|param| param.trim().split_once('=').is_some_and(|(k, v)| is_token(k.trim()) && !v.is_empty()))
+}
+
+/// `attachment` with an ASCII `filename` fallback and the exact name as an
+/// RFC 5987 `filename*`.
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| if (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let mut encoded = String::new();
+    for b in filename.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::ConnectInfo;
+
+    use crate::harness::{SECRET, app_state, token};
+
+    fn auth_headers(token: &str, range_start: Option<u64>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        if let Some(start) = range_start {
+            headers.insert(header::RANGE, format!("bytes={}-", start).parse().unwrap());
+        }
+        headers
+    }
+
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("haven-fs-{}-{}", name, std::process::id()))
+    }
+
+    /// App state backed by a fresh DB and storage dir.
+    async fn empty_state(name: &str) -> AppState {
+        app_state(&test_dir(name)).await
+    }
+
+    /// App state with one complete transfer.
+    async fn test_state(name: &str, transfer_id: &str, file_size: u64) -> AppState {
+        let state = empty_state(name).await;
+        state.storage.create_file(transfer_id, file_size).await.unwrap();
+        state.db.with_conn_mut(|conn| {
+            conn.execute(
+                "INSERT INTO transfers (id, uploader_id, file_size, chunk_count, file_sha256, bytes_received, status)
+                 VALUES (?1, ?2, ?3, 1, '', ?3, 'complete')",
+                rusqlite::params![transfer_id, Uuid::new_v4().to_string(), file_size as i64],
+            )?;
+            Ok(())
+        })
+        .unwrap();
+        state
+    }
+
+    async fn download_status(state: &AppState, transfer_id: &str, headers: HeaderMap) -> StatusCode {
+        match download_data(State(state.clone()), Path(transfer_id.to_string()), headers).await {
+            Ok(resp) => resp.into_response().status(),
+            Err(status) => status,
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_with_refreshed_token_after_expiry() {
+        let tid = "resume-refresh";
+        let state = test_state("resume", tid, 4096).await;
+        state.db.with_conn_mut(|conn| {
+            conn.execute("UPDATE transfers SET max_downloads = 1 WHERE id = ?1", [tid])?;
+            Ok(())
+        })
+        .unwrap();
+        let user = Uuid::new_v4();
+
+        // Start the download with the original token.
+        let original = token(user, 3600);
+        assert_eq!(download_status(&state, tid, auth_headers(&original, None)).await, StatusCode::OK);
+
+        // The original token has since expired (past the default 60s leeway).
+        let expired = token(user, -300);
+        assert_eq!(
+            download_status(&state, tid, auth_headers(&expired, Some(1024))).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        // Same user with a refreshed token resumes mid-file.
+        let refreshed = token(user, 3600);
+        assert_eq!(
+            download_status(&state, tid, auth_headers(&refreshed, Some(1024))).await,
+            StatusCode::PARTIAL_CONTENT
+        );
+
+        // A different user can't take over the resume, nor start afresh on
+        // a one-time link already bound to someone else.
+        let other = token(Uuid::new_v4(), 3600);
+        assert_eq!(
+            download_status(&state, tid, auth_headers(&other, Some(1024))).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(download_status(&state, tid, auth_headers(&other, None)).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn every_recipient_downloads_without_a_cap() {
+        let tid = "two-recipients";
+        let state = test_state("two-recipients", tid, 4096).await;
+        state.db.with_conn_mut(|conn| {
+            conn.execute("UPDATE transfers SET recipients = 2 WHERE id = ?1", [tid])?;
+            Ok(())
+        })
+        .unwrap();
+        let (alice, bob) = (token(Uuid::new_v4(), 3600), token(Uuid::new_v4(), 3600));
+
+        assert_eq!(download_status(&state, tid, auth_headers(&alice, None)).await, StatusCode::OK);
+        assert_eq!(download_status(&state, tid, auth_headers(&bob, None)).await, StatusCode::OK);
+        assert_eq!(download_status(&state, tid, auth_headers(&alice, Some(1024))).await, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(download_status(&state, tid, auth_headers(&bob, Some(2048))).await, StatusCode::PARTIAL_CONTENT);
+
+        let _ = std::fs::remove_dir_all(test_dir("two-recipients"));
+    }
+
+    fn range_headers(token: &str, range: &str) -> HeaderMap {
+        let mut headers = auth_headers(token, None);
+        headers.insert(header::RANGE, range.parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn configured_issuer_and_audience_are_required() {
+        let mut state = empty_state("token-scope").await;
+        state.token_scope = TokenScope { issuer: Some("haven".into()), audience: Some("haven-files".into()) };
+        let user = Uuid::new_v4();
+        let scoped = |iss: Option<&str>, aud: Option<&str>| {
+            let claims = Claims {
+                sub: user,
+                username: "tester".into(),
+                exp: (chrono::Utc::now().timestamp() + 3600) as usize,
+                iss: iss.map(Into::into),
+                aud: aud.map(Into::into),
+            };
+            jsonwebtoken::encode(
+                &jsonwebtoken::Header::default(),
+                &claims,
+                &jsonwebtoken::EncodingKey::from_secret(SECRET.as_bytes()),
+            )
+            .unwrap()
+        };
+
+        let claims = extract_claims(&auth_headers(&scoped(Some("haven"), Some("haven-files")), None), &state).unwrap();
+        assert_eq!(claims.sub, user);
+        // Wrong issuer, wrong audience, missing issuer, missing audience.
+        let rejected = [
+            (Some("chat"), Some("haven-files")),
+            (Some("haven"), Some("chat")),
+            (None, Some("haven-files")),
+            (Some("haven"), None),
+        ];
+        for (iss, aud) in rejected {
+            let headers = auth_headers(&scoped(iss, aud), None);
+            assert_eq!(extract_claims(&headers, &state).unwrap_err(), StatusCode::UNAUTHORIZED, "{iss:?} {aud:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn manifest_ranges_reassemble_the_file() {
+        use sha2::{Digest, Sha256};
+
+        let tid = "manifest";
+        let data: Vec<u8> = (0..250u32).map(|i| (i % 251) as u8).collect();
+        let state = test_state("manifest", tid, data.len() as u64).await;
+        std::fs::write(state.storage.file_path(tid), &data).unwrap();
+        let ranges = [(0usize, 100usize), (100, 100), (200, 50)];
+        state
+            .db
+            .with_conn_mut(|conn| {
+                for (idx, (offset, len)) in ranges.iter().enumerate() {
+                    conn.execute(
+                        "INSERT INTO chunks (transfer_id, chunk_index, sha256, byte_offset, byte_length, received)
+                         VALUES (?1, ?2, ?3, ?4, ?5, 1)",
+                        rusqlite::params![
+                            tid,
+                            idx as i64,
+                            hex::encode(Sha256::digest(&data[*offset..offset + len])),
+                            *offset as i64,
+                            *len as i64
+                        ],
+                    )?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        let tok = token(Uuid::new_v4(), 3600);
+
+        let Json(manifest) =
+            get_manifest(State(state.clone()), Path(tid.into()), auth_headers(&tok, None)).await.unwrap();
+        assert_eq!(manifest.file_size, 250);
+        let layout: Vec<_> = manifest.chunks.iter().map(|c| (c.index, c.offset, c.length, c.received)).collect();
+        assert_eq!(layout, vec![(0, 0, 100, true), (1, 100, 100, true), (2, 200, 50, true)]);
+
+        // Every chunk fetched on its own, all at once, in reverse.
+        let fetches = manifest.chunks.iter().rev().map(|chunk| {
+            let range = format!("bytes={}-{}", chunk.offset, chunk.offset + chunk.length - 1);
+            let headers = range_headers(&tok, &range);
+            let state = state.clone();
+            async move {
+                let resp = download_data(State(state), Path(tid.into()), headers).await.unwrap().into_response();
+                axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap()
+            }
+        });
+        let mut bodies = futures_util::future::join_all(fetches).await;
+        bodies.reverse();
+        let mut rebuilt = Vec::new();
+        for (chunk, body) in manifest.chunks.iter().zip(&bodies) {
+            assert_eq!(hex::encode(Sha256::digest(body)), chunk.sha256);
+            rebuilt.extend_from_slice(body);
+        }
+        assert_eq!(rebuilt, data);
+
+        state.db.with_conn_mut(|conn| {
+            conn.execute("UPDATE transfers SET status = 'expired' WHERE id = ?1", [tid])?;
+            Ok(())
+        })
+        .unwrap();
+        let gone = get_manifest(State(state.clone()), Path(tid.into()), auth_headers(&tok, None)).await.unwrap_err();
+        assert_eq!(gone, StatusCode::GONE);
+
+        let _ = std::fs::remove_dir_all(test_dir("manifest"));
+    }
+
+    #[test]
+    fn parse_range_forms() {
+        let parse = |v: &str| parse_range(&range_headers("t", v));
+        assert_eq!(parse("bytes=100-199"), Some((100, Some(199))));
+        assert_eq!(parse("bytes=100-"), Some((100, None)));
+        assert_eq!(parse("bytes=5-5"), Some((5, Some(5))));
+        // END before START, suffix ranges and junk are ignored.
+        assert_eq!(parse("bytes=200-100"), None);
+        assert_eq!(parse("bytes=-500"), None);
+        assert_eq!(parse("bytes=0-1,5-9"), None);
+        assert_eq!(parse("items=0-1"), None);
+    }
+
+    #[tokio::test]
+    async fn download_serves_exact_range_window() {
+        let tid = "range-window";
+        let state = test_state("range", tid, 4096).await;
+        let data: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(state.storage.file_path(tid), &data).unwrap();
+        let tok = token(Uuid::new_v4(), 3600);
+
+        let fetch = |range: &'static str| {
+            let state = state.clone();
+            let headers = range_headers(&tok, range);
+            async move {
+                let resp = download_data(State(state), Path(tid.to_string()), headers).await?.into_response();
+                let content_range = resp.headers().get(header::CONTENT_RANGE).map(|v| v.to_str().unwrap().to_owned());
+                let status = resp.status();
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+                Ok::<_, StatusCode>((status, content_range, body))
+            }
+        };
+
+        // One chunk from the middle: exactly that window.
+        let (status, content_range, body) = fetch("bytes=1024-2047").await.unwrap();
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(content_range.as_deref(), Some("bytes 1024-2047/4096"));
+        assert_eq!(&body[..], &data[1024..2048]);
+
+        // Open-ended resume runs to the end of the file.
+        let (status, content_range, body) = fetch("bytes=3000-").await.unwrap();
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(content_range.as_deref(), Some("bytes 3000-4095/4096"));
+        assert_eq!(&body[..], &data[3000..]);
+
+        // An end past the file is clamped; a start past it is unsatisfiable.
+        let (_, content_range, body) = fetch("bytes=4000-9999").await.unwrap();
+        assert_eq!(content_range.as_deref(), Some("bytes 4000-4095/4096"));
+        assert_eq!(body.len(), 96);
+        assert_eq!(fetch("bytes=4096-5000").await.unwrap_err(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        let _ = std::fs::remove_dir_all(test_dir("range"));
+    }
+
+    /// POST /transfers for `data` as a single chunk; the body is `Null` on error.
+    async fn create_response(state: &AppState, token: &str, id: &str, data: &[u8]) -> (StatusCode, serde_json::Value) {
+        use sha2::{Digest, Sha256};
+        let hash = hex::encode(Sha256::digest(data));
+        let req = CreateTransferRequest {
+            id: id.into(),
+            file_size: data.len() as u64,
+            chunk_size: None,
+            file_sha256: hash.clone(),
+            chunk_hashes: vec![hash],
+            filename: None,
+            content_type: None,
+            recipients: None,
+            retention_minutes: None,
+            max_downloads: None,
+        };
+        let resp = match create_transfer(State(state.clone()), auth_headers(token, None), Json(req)).await {
+            Ok(resp) => resp.into_response(),
+            Err(status) => return (status, serde_json::Value::Null),
+        };
+        let status = resp.status();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn create(state: &AppState, token: &str, id: &str, data: &[u8]) -> bool {
+        let (status, body) = create_response(state, token, id, data).await;
+        assert_eq!(status, StatusCode::CREATED);
+        body["already_present"].as_bool().unwrap()
+    }
+
+    #[tokio::test]
+    async fn create_transfer_is_idempotent_for_retries() {
+        let state = empty_state("idempotent").await;
+        let mine = token(Uuid::new_v4(), 3600);
+        let data = b"retried after a network blip".to_vec();
+
+        let (status, body) = create_response(&state, &mine, "retry", &data).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["status"], "uploading");
+
+        // Same metadata: the existing transfer comes back.
+        let (status, body) = create_response(&state, &mine, "retry", &data).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["chunk_count"], 1);
+        assert_eq!(body["status"], "uploading");
+        assert_eq!(body["already_present"], false);
+
+        // Different bytes under the same id, or someone else's retry, conflict.
+        let (status, _) = create_response(&state, &mine, "retry", b"something else entirely").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let other = token(Uuid::new_v4(), 3600);
+        let (status, _) = create_response(&state, &other, "retry", &data).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let transfers: i64 = state.db.with_conn(|c| Ok(c.query_row("SELECT COUNT(*) FROM transfers", [], |r| r.get(0))?)).unwrap();
+        assert_eq!(transfers, 1);
+
+        let _ = std::fs::remove_dir_all(test_dir("idempotent"));
+    }
+
+    #[test]
+    fn filenames_are_sanitized() {
+        assert_eq!(sanitize_filename("report.pdf").as_deref(), Some("report.pdf"));
+        assert_eq!(sanitize_filename("../../etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(sanitize_filename("C:\\Users\\me\\notes.txt").as_deref(), Some("notes.txt"));
+        assert_eq!(sanitize_filename("evil\r\nSet-Cookie: x=1.txt").as_deref(), Some("evilSet-Cookie: x=1.txt"));
+        assert_eq!(sanitize_filename("dir/"), None);
+        assert_eq!(sanitize_filename(".."), None);
+        assert_eq!(sanitize_filename(&"é".repeat(200)).unwrap().len(), 254);
+
+        assert!(is_valid_content_type("image/png"));
+        assert!(is_valid_content_type("text/plain; charset=utf-8"));
+        assert!(!is_valid_content_type("text"));
+        assert!(!is_valid_content_type("text/plain\r\nX-Injected: 1"));
+        assert!(!is_valid_content_type("text/plain; charset"));
+    }
+
+    #[tokio::test]
+    async fn download_sends_stored_filename_and_content_type() {
+        use sha2::{Digest, Sha256};
+        let state = empty_state("metadata").await;
+        let tok = token(Uuid::new_v4(), 3600);
+        let data = b"hello, browser".to_vec();
+        let hash = hex::encode(Sha256::digest(&data));
+
+        let create = |id: &str, filename: Option<&str>, content_type: Option<&str>| {
+            let req = CreateTransferRequest {
+                id: id.into(),
+                file_size: data.len() as u64,
+                chunk_size: None,
+                file_sha256: hash.clone(),
+                chunk_hashes: vec![hash.clone()],
+                filename: filename.map(Into::into),
+                content_type: content_type.map(Into::into),
+                recipients: None,
+                retention_minutes: None,
+                max_downloads: None,
+            };
+            create_transfer(State(state.clone()), auth_headers(&tok, None), Json(req))
+        };
+        let headers_for = |id: &'static str| {
+            let state = state.clone();
+            let headers = auth_headers(&tok, None);
+            async move {
+                std::fs::write(state.storage.file_path(id), b"hello, browser").unwrap();
+                state
+                    .db
+                    .with_conn_mut(|c| {
+                        c.execute("UPDATE transfers SET status = 'complete', bytes_received = file_size WHERE id = ?1", [id])?;
+                        Ok(())
+                    })
+                    .unwrap();
+                let resp = download_data(State(state), Path(id.to_string()), headers).await.unwrap().into_response();
+                let get = |name| resp.headers().get(name).map(|v: &header::HeaderValue| v.to_str().unwrap().to_owned());
+                (get(header::CONTENT_TYPE), get(header::CONTENT_DISPOSITION))
+            }
+        };
+
+        assert!(create("named", Some("../Résumé \"final\".pdf"), Some("application/pdf")).await.is_ok());
+        let (content_type, disposition) = headers_for("named").await;
+        assert_eq!(content_type.as_deref(), Some("application/pdf"));
+        assert_eq!(
+            disposition.as_deref(),
+            Some("attachment; filename=\"R_sum_ _final_.pdf\"; filename*=UTF-8''R%C3%A9sum%C3%A9%20%22final%22.pdf")
+        );
+
+        // Without metadata the download stays anonymous bytes.
+        assert!(create("bare", None, None).await.is_ok());
+        let (content_type, disposition) = headers_for("bare").await;
+        assert_eq!(content_type.as_deref(), Some("application/octet-stream"));
+        assert_eq!(disposition, None);
+
+        // A content type that could smuggle a header is refused outright.
+        assert_eq!(
+            create("injected", None, Some("text/html\r\nSet-Cookie: a=b")).await.err(),
+            Some(StatusCode::BAD_REQUEST)
+        );
+
+        let _ = std::fs::remove_dir_all(test_dir("metadata"));
+    }
+
+    #[tokio::test]
+    async fn empty_file_uploads_and_downloads() {
+        let state = empty_state("empty").await;
+        let tok = token(Uuid::new_v4(), 3600);
+        assert!(!create(&state, &tok, "empty", b"").await);
+
+        let uploaded = upload_chunk(State(state.clone()), Path(("empty".into(), 0)), auth_headers(&tok, None), Bytes::new()).await;
+        assert_eq!(uploaded, Ok(StatusCode::OK));
+        let status: String = state
+            .db
+            .with_conn(|c| Ok(c.query_row("SELECT status FROM transfers WHERE id = 'empty'", [], |r| r.get(0))?))
+            .unwrap();
+        assert_eq!(status, "complete");
+
+        let resp = download_data(State(state.clone()), Path("empty".into()), auth_headers(&tok, None))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap().is_empty());
+        // Nothing to resume from in an empty file.
+        assert_eq!(
+            download_status(&state, "empty", auth_headers(&tok, Some(0))).await,
+            StatusCode::RANGE_NOT_SATISFIABLE
+        );
+
+        // A zero chunk size can't lay out any file.
+        let req = CreateTransferRequest {
+            id: "zero-chunks".into(),
+            file_size: 10,
+            chunk_size: Some(0),
+            file_sha256: String::new(),
+            chunk_hashes: vec![String::new()],
+            filename: None,
+            content_type: None,
+            recipients: None,
+            retention_minutes: None,
+            max_downloads: None,
+        };
+        let refused = create_transfer(State(state.clone()), auth_headers(&tok, None), Json(req)).await;
+        assert_eq!(refused.err(), Some(StatusCode::BAD_REQUEST));
+
+        let _ = std::fs::remove_dir_all(test_dir("empty"));
+    }
+
+    #[tokio::test]
+    async fn chunk_batch_completes_transfer_at_right_offsets() {
+        use sha2::{Digest, Sha256};
+        let state = empty_state("batch").await;
+        let tok = token(Uuid::new_v4(), 3600);
+        let data = b"aaaaaaaabbbbbbbbccc";
+        let req = CreateTransferRequest {
+            id: "batched".into(),
+            file_size: data.len() as u64,
+            chunk_size: Some(8),
+            file_sha256: hex::encode(Sha256::digest(data)),
+            chunk_hashes: data.chunks(8).map(|c| hex::encode(Sha256::digest(c))).collect(),
+            filename: None,
+            content_type: None,
+            recipients: None,
+            retention_minutes: None,
+            max_downloads: None,
+        };
+        assert!(create_transfer(State(state.clone()), auth_headers(&tok, None), Json(req)).await.is_ok());
+
+        let batch = |chunks: &[(u32, &[u8])]| {
+            let mut body = Vec::new();
+            for (index, chunk) in chunks {
+                body.extend(index.to_le_bytes());
+                body.extend((chunk.len() as u32).to_le_bytes());
+                body.extend(*chunk);
+            }
+            Bytes::from(body)
+        };
+        let put = |body: Bytes| {
+            let state = state.clone();
+            let headers = auth_headers(&tok, None);
+            async move {
+                match upload_chunk_batch(State(state), Path("batched".into()), headers, body).await {
+                    Ok(status) | Err(status) => status,
+                }
+            }
+        };
+
+        // Truncated, duplicated and wrong-length batches are refused whole.
+        let mut truncated = batch(&[(0, b"aaaaaaaa")]).to_vec();
+        truncated.pop();
+        assert_eq!(put(truncated.into()).await, StatusCode::BAD_REQUEST);
+        assert_eq!(put(batch(&[(0, b"aaaaaaaa"), (0, b"aaaaaaaa")])).await, StatusCode::BAD_REQUEST);
+        assert_eq!(put(batch(&[(0, b"aaaaaaaa"), (2, b"cc")])).await, StatusCode::BAD_REQUEST);
+        assert_eq!(put(batch(&[(9, b"aaaaaaaa")])).await, StatusCode::NOT_FOUND);
+
+        // Out of order, one chunk overlapping an earlier single upload.
+        let single = upload_chunk(State(state.clone()), Path(("batched".into(), 1)), auth_headers(&tok, None), Bytes::from_static(b"bbbbbbbb")).await;
+        assert_eq!(single, Ok(StatusCode::OK));
+        assert_eq!(put(batch(&[(2, b"ccc"), (1, b"bbbbbbbb"), (0, b"aaaaaaaa")])).await, StatusCode::OK);
+
+        let status: String = state
+            .db
+            .with_conn(|c| Ok(c.query_row("SELECT status FROM transfers WHERE id = 'batched'", [], |r| r.get(0))?))
+            .unwrap();
+        assert_eq!(status, "complete");
+        assert_eq!(std::fs::read(state.storage.file_path("batched")).unwrap(), data);
+
+        let _ = std::fs::remove_dir_all(test_dir("batch"));
+    }
+
+    #[tokio::test]
+    async fn mismatched_chunks_leave_transfer_corrupt() {
+        use sha2::{Digest, Sha256};
+        let mut state = empty_state("verify").await;
+        let tok = token(Uuid::new_v4(), 3600);
+
+        // Declares two 8-byte chunks of `declared`, then sends `chunks`. Each
+        // case declares different bytes so dedup can't link them together.
+        let upload = |state: AppState, id: &'static str, declared: &[u8], chunks: [&'static [u8]; 2]| {
+            let tok = tok.clone();
+            let hash = hex::encode(Sha256::digest(declared));
+            async move {
+                let req = CreateTransferRequest {
+                    id: id.into(),
+                    file_size: 16,
+                    chunk_size: Some(8),
+                    file_sha256: hash.clone(),
+                    chunk_hashes: vec![hash.clone(), hash],
+                    filename: None,
+                    content_type: None,
+                    recipients: None,
+                    retention_minutes: None,
+                    max_downloads: None,
+                };
+                assert!(create_transfer(State(state.clone()), auth_headers(&tok, None), Json(req)).await.is_ok());
+                let mut last = StatusCode::OK;
+                for (i, chunk) in chunks.into_iter().enumerate() {
+                    let path = Path((id.to_string(), i as i64));
+                    last = match upload_chunk(State(state.clone()), path, auth_headers(&tok, None), Bytes::from_static(chunk)).await {
+                        Ok(status) | Err(status) => status,
+                    };
+                }
+                let status: String = state
+                    .db
+                    .with_conn(|c| Ok(c.query_row("SELECT status FROM transfers WHERE id = ?1", [id], |r| r.get(0))?))
+                    .unwrap();
+                (last, status)
+            }
+        };
+
+        assert_eq!(upload(state.clone(), "good", b"0123456789abcdef", [b"01234567", b"89abcdef"]).await, (StatusCode::OK, "complete".into()));
+
+        // Chunks land at the right offsets but with the wrong bytes.
+        let (last, status) = upload(state.clone(), "bad", b"ABCDEFGH89abcdef", [b"ABCDEFGH", b"89abcdeX"]).await;
+        assert_eq!((last, status.as_str()), (StatusCode::UNPROCESSABLE_ENTITY, "corrupt"));
+        assert_eq!(download_status(&state, "bad", auth_headers(&tok, None)).await, StatusCode::UNPROCESSABLE_ENTITY);
+
+        // With verification off the same upload is taken at its word.
+        state.verify_uploads = false;
+        let (last, status) = upload(state.clone(), "unchecked", b"abcdefgh01234567", [b"abcdefgh", b"0123456X"]).await;
+        assert_eq!((last, status.as_str()), (StatusCode::OK, "complete"));
+
+        let _ = std::fs::remove_dir_all(test_dir("verify"));
+    }
+
+    #[tokio::test]
+    async fn chunk_reader_holds_at_most_a_chunk_and_a_frame() {
+        // 10 chunks of 4 KiB plus a short tail, in 7-byte frames, then a
+        // burst of trailing junk far larger than a chunk.
+        let chunk = 4096;
+        let data: Vec<u8> = (0..10 * chunk + 100).map(|i| (i % 251) as u8).collect();
+        let mut frames: Vec<Result<Bytes, std::convert::Infallible>> =
+            data.chunks(7).map(|f| Ok(Bytes::copy_from_slice(f))).collect();
+        frames.push(Ok(Bytes::from(vec![0u8; 64 * chunk])));
+        let mut reader = ChunkReader::new(futures_util::stream::iter(frames));
+
+        for i in 0..10 {
+            let got = reader.next_chunk(chunk).await.unwrap().unwrap();
+            assert_eq!(got, data[i * chunk..(i + 1) * chunk]);
+        }
+        assert!(reader.peak <= chunk + 7, "peak {}", reader.peak);
+        let tail = reader.next_chunk(100).await.unwrap().unwrap();
+        assert_eq!(tail, data[10 * chunk..]);
+        assert!(reader.peak <= chunk + 7, "peak {}", reader.peak);
+
+        // A body ending mid-chunk yields nothing rather than a short chunk.
+        let short = futures_util::stream::iter([Ok::<_, std::convert::Infallible>(Bytes::from_static(b"abc"))]);
+        assert_eq!(ChunkReader::new(short).next_chunk(4).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn ready_reports_failed_checks() {
+        let state = empty_state("ready").await;
+        let (status, Json(report)) = ready(State(state.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(report.checks.values().all(|v| v == "ok"), "{:?}", report.checks);
+
+        // Storage gone and shutdown begun: 503, naming exactly those checks.
+        std::fs::remove_dir_all(test_dir("ready").join("storage")).unwrap();
+        state.transfers.stop_accepting();
+        let (status, Json(report)) = ready(State(state)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!report.ready);
+        let failed: Vec<_> = report.checks.iter().filter(|(_, v)| *v != "ok").map(|(k, _)| *k).collect();
+        assert_eq!(failed, ["accepting", "storage"]);
+
+        let _ = std::fs::remove_dir_all(test_dir("ready"));
+    }
+
+    #[tokio::test]
+    async fn user_quota_allows_up_to_limit_and_releases() {
+        let mut state = empty_state("quota").await;
+        state.quota.user_quota_bytes = Some(100);
+        let mine = token(Uuid::new_v4(), 3600);
+
+        // 60 + 40 lands exactly on the limit.
+        create(&state, &mine, "q1", &[1u8; 60]).await;
+        create(&state, &mine, "q2", &[2u8; 40]).await;
+
+        // One more byte is over, for this user only.
+        let (status, _) = create_response(&state, &mine, "q3", &[3u8; 1]).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let transfers: i64 = state.db.with_conn(|c| Ok(c.query_row("SELECT COUNT(*) FROM transfers", [], |r| r.get(0))?)).unwrap();
+        assert_eq!(transfers, 2);
+        let other = token(Uuid::new_v4(), 3600);
+        create(&state, &other, "theirs", &[4u8; 100]).await;
+
+        // Deleting and expiring give the space back.
+        delete_transfer(State(state.clone()), Path("q2".into()), auth_headers(&mine, None)).await.unwrap();
+        create(&state, &mine, "q3", &[3u8; 40]).await;
+        state.db.with_conn_mut(|c| Ok(c.execute("UPDATE transfers SET status = 'expired' WHERE id = 'q1'", [])?)).unwrap();
+        create(&state, &mine, "q4", &[5u8; 60]).await;
+
+        let _ = std::fs::remove_dir_all(test_dir("quota"));
+    }
+
+    async fn check(state: &AppState, token: &str, file_size: u64, sha256: Option<&str>) -> serde_json::Value {
+        let req = CheckTransferRequest { file_size, file_sha256: sha256.map(Into::into), content_type: None };
+        let Json(resp) = check_transfer(State(state.clone()), auth_headers(token, None), Json(req)).await.unwrap();
+        serde_json::to_value(resp).unwrap()
+    }
+
+    #[tokio::test]
+    async fn check_reports_quota_and_dedup_without_creating() {
+        let mut state = empty_state("check").await;
+        state.quota.user_quota_bytes = Some(100);
+        let mine = token(Uuid::new_v4(), 3600);
+        create(&state, &mine, "held", &[1u8; 60]).await;
+
+        // Accepted: fits the quota, nothing stored under that hash.
+        let body = check(&state, &mine, 40, Some("ab")).await;
+        assert_eq!(body["accepted"], true);
+        assert_eq!(body["already_present"], false);
+        assert_eq!(body["restricts_types"], false);
+        assert!(body.get("reason").is_none());
+        assert_eq!(body["chunk_sizes"]["default"], DEFAULT_CHUNK_SIZE);
+        assert_eq!(body["chunk_sizes"]["min"], encrypted_chunk_size(MIN_CHUNK_SIZE) as u64);
+
+        // Rejected: one byte over.
+        let body = check(&state, &mine, 41, None).await;
+        assert_eq!(body["accepted"], false);
+        assert_eq!(body["reason"], "quota_exceeded");
+
+        // Dedup hit: same hash and size as a stored blob.
+        state
+            .db
+            .with_conn_mut(|c| {
+                Ok(c.execute(
+                    "INSERT INTO blobs (sha256, file_size, chunk_size, chunk_hashes) VALUES ('cd', 30, 30, '[\"cd\"]')",
+                    [],
+                )?)
+            })
+            .unwrap();
+        let body = check(&state, &mine, 30, Some("cd")).await;
+        assert_eq!(body["accepted"], true);
+        assert_eq!(body["already_present"], true);
+        assert_eq!(check(&state, &mine, 31, Some("cd")).await["already_present"], false);
+
+        // Nothing was created along the way.
+        let transfers: i64 = state.db.with_conn(|c| Ok(c.query_row("SELECT COUNT(*) FROM transfers", [], |r| r.get(0))?)).unwrap();
+        assert_eq!(transfers, 1);
+
+        let _ = std::fs::remove_dir_all(test_dir("check"));
+    }
+
+    #[tokio::test]
+    async fn create_enforces_file_type_and_size_policy() {
+        let mut state = empty_state("policy").await;
+        state.file_policy = Arc::new(FilePolicy {
+            allowed_types: vec!["image/*".into(), "application/pdf".into()],
+            blocked_types: vec!["image/svg+xml".into()],
+            min_bytes: Some(2),
+            max_bytes: Some(64),
+        });
+        let tok = token(Uuid::new_v4(), 3600);
+        let create = |content_type: Option<&str>, size: u64| {
+            let hash = "ab".repeat(32);
+            let req = CreateTransferRequest {
+                id: Uuid::new_v4().to_string(),
+                file_size: size,
+                chunk_size: None,
+                file_sha256: hash.clone(),
+                chunk_hashes: vec![hash],
+                filename: None,
+                content_type: content_type.map(Into::into),
+                recipients: None,
+                retention_minutes: None,
+                max_downloads: None,
+            };
+            let state = state.clone();
+            let headers = auth_headers(&tok, None);
+            async move { create_transfer(State(state), headers, Json(req)).await.map(|r| r.into_response().status()) }
+        };
+
+        assert_eq!(create(Some("image/png"), 10).await, Ok(StatusCode::CREATED));
+        assert_eq!(create(Some("application/pdf"), 10).await, Ok(StatusCode::CREATED));
+        assert_eq!(create(Some("image/svg+xml"), 10).await, Err(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        assert_eq!(create(Some("application/x-msdownload"), 10).await, Err(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        assert_eq!(create(None, 10).await, Err(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        assert_eq!(create(Some("image/png"), 65).await, Err(StatusCode::PAYLOAD_TOO_LARGE));
+        assert_eq!(create(Some("image/png"), 1).await, Err(StatusCode::BAD_REQUEST));
+
+        // The pre-upload check says the same.
+        let req = CheckTransferRequest { file_size: 10, file_sha256: None, content_type: Some("text/html".into()) };
+        let Json(resp) = check_transfer(State(state.clone()), auth_headers(&tok, None), Json(req)).await.unwrap();
+        assert_eq!(resp.reason.as_deref(), Some("type_not_allowed"));
+        assert!(resp.restricts_types);
+        // Without a type it only says a type is wanted.
+        let req = CheckTransferRequest { file_size: 10, file_sha256: None, content_type: None };
+        let Json(resp) = check_transfer(State(state.clone()), auth_headers(&tok, None), Json(req)).await.unwrap();
+        assert!(resp.accepted && resp.restricts_types);
+
+        let transfers: i64 = state.db.with_conn(|c| Ok(c.query_row("SELECT COUNT(*) FROM transfers", [], |r| r.get(0))?)).unwrap();
+        assert_eq!(transfers, 2);
+        let _ = std::fs::remove_dir_all(test_dir("policy"));
+    }
+
+    #[tokio::test]
+    async fn dedup_links_identical_uploads_and_refcounts_blob() {
+        let state = empty_state("dedup").await;
+        let token = token(Uuid::new_v4(), 3600);
+        let data = b"the same encrypted bytes, sent twice".to_vec();
+        let blob = test_dir("dedup")
+            .join("storage/blobs")
+            .join(hex::encode(<sha2::Sha256 as sha2::Digest>::digest(&data)));
+
+        // First upload goes the normal way and gets indexed on completion.
+        assert!(!create(&state, &token, "first", &data).await);
+        let status = upload_chunk(
+            State(state.clone()),
+            Path(("first".into(), 0)),
+            auth_headers(&token, None),
+            Bytes::from(data.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        dedup::index_completed(&state.db, &state.storage, "first").await;
+        assert!(blob.exists());
+
+        // Second one is complete on creation and shares the bytes.
+        assert!(create(&state, &token, "second", &data).await);
+        assert_eq!(std::fs::read(state.storage.file_path("second")).unwrap(), data);
+
+        // The blob outlives the first transfer, and goes with the last.
+        delete_transfer(State(state.clone()), Path("first".into()), auth_headers(&token, None)).await.unwrap();
+        assert!(blob.exists());
+        assert_eq!(std::fs::read(state.storage.file_path("second")).unwrap(), data);
+
+        delete_transfer(State(state.clone()), Path("second".into()), auth_headers(&token, None)).await.unwrap();
+        assert!(!blob.exists());
+        let blobs: i64 = state.db.with_conn_mut(|c| Ok(c.query_row("SELECT COUNT(*) FROM blobs", [], |r| r.get(0))?)).unwrap();
+        assert_eq!(blobs, 0);
+
+        let _ = std::fs::remove_dir_all(test_dir("dedup"));
+    }
+
+    #[tokio::test]
+    async fn fan_out_transfer_kept_until_every_recipient_confirms() {
+        let tid = "fan-out";
+        let state = test_state("fanout", tid, 64).await;
+        let uploader = Uuid::new_v4();
+        state.db.with_conn_mut(|conn| {
+            conn.execute(
+                "UPDATE transfers SET uploader_id = ?1 WHERE id = ?2",
+                rusqlite::params![uploader.to_string(), tid],
+            )?;
+            Ok(())
+        })
+        .unwrap();
+        let tok = token(uploader, 3600);
+        let set = |tok: &str, recipients| {
+            let req = SetRecipientsRequest { recipients };
+            set_recipients(State(state.clone()), Path(tid.into()), auth_headers(tok, None), Json(req))
+        };
+
+        // Only the uploader sets the count the offer went out to.
+        assert_eq!(set(&token(Uuid::new_v4(), 3600), 2).await.unwrap_err(), StatusCode::FORBIDDEN);
+        assert_eq!(set(&tok, 0).await.unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(set(&tok, 2).await.unwrap(), StatusCode::NO_CONTENT);
+        let confirm = |recipient: &str| {
+            let body = Bytes::from(serde_json::json!({ "recipient_id": recipient }).to_string());
+            confirm_transfer(State(state.clone()), Path(tid.into()), auth_headers(&tok, None), body)
+        };
+
+        // The first recipient's confirm (even repeated) leaves the file for the other.
+        assert_eq!(confirm("alice").await.unwrap(), StatusCode::ACCEPTED);
+        assert_eq!(confirm("alice").await.unwrap(), StatusCode::ACCEPTED);
+        assert!(state.storage.file_path(tid).exists());
+        assert_eq!(download_status(&state, tid, auth_headers(&token(Uuid::new_v4(), 3600), None)).await, StatusCode::OK);
+
+        assert_eq!(confirm("bob").await.unwrap(), StatusCode::OK);
+        assert!(!state.storage.file_path(tid).exists());
+
+        let _ = std::fs::remove_dir_all(test_dir("fanout"));
+    }
+
+    #[tokio::test]
+    async fn retention_is_per_transfer_up_to_server_window() {
+        assert_eq!(retention_minutes(None, 24), 24 * 60);
+        assert_eq!(retention_minutes(Some(0), 24), 1);
+        assert_eq!(retention_minutes(Some(10_000), 24), 24 * 60);
+
+        let state = empty_state("retention").await;
+        let tok = token(Uuid::new_v4(), 3600);
+        let create_for = |id: &'static str, retention_minutes: Option<u64>| {
+            use sha2::{Digest, Sha256};
+            let hash = hex::encode(Sha256::digest(id.as_bytes()));
+            let req = CreateTransferRequest {
+                id: id.into(),
+                file_size: id.len() as u64,
+                chunk_size: None,
+                file_sha256: hash.clone(),
+                chunk_hashes: vec![hash],
+                filename: None,
+                content_type: None,
+                recipients: None,
+                retention_minutes,
+                max_downloads: None,
+            };
+            create_transfer(State(state.clone()), auth_headers(&tok, None), Json(req))
+        };
+        assert!(create_for("ephemeral", Some(5)).await.is_ok());
+        assert!(create_for("archive", Some(10_000)).await.is_ok());
+        assert!(create_for("default", None).await.is_ok());
+
+        // Minutes until each expires (the test state keeps transfers an hour).
+        let minutes_left = |id: &str| {
+            state
+                .db
+                .with_conn(|c| {
+                    Ok(c.query_row(
+                        "SELECT CAST(ROUND((julianday(expires_at) - julianday('now')) * 1440) AS INTEGER)
+                         FROM transfers WHERE id = ?1",
+                        [id],
+                        |r| r.get::<_, i64>(0),
+                    )?)
+                })
+                .unwrap()
+        };
+        assert_eq!(minutes_left("ephemeral"), 5);
+        assert_eq!(minutes_left("archive"), 60);
+        assert_eq!(minutes_left("default"), 60);
+
+        let _ = std::fs::remove_dir_all(test_dir("retention"));
+    }
+
+    #[tokio::test]
+    async fn download_cap_releases_before_every_recipient_confirms() {
+        let tid = "one-time";
+        let state = test_state("cap", tid, 64).await;
+        let uploader = Uuid::new_v4();
+        state.db.with_conn_mut(|conn| {
+            conn.execute(
+                "UPDATE transfers SET uploader_id = ?1, recipients = 5, max_downloads = 2 WHERE id = ?2",
+                rusqlite::params![uploader.to_string(), tid],
+            )?;
+            Ok(())
+        })
+        .unwrap();
+        let tok = token(uploader, 3600);
+        let confirm = |recipient: &str| {
+            let body = Bytes::from(serde_json::json!({ "recipient_id": recipient }).to_string());
+            confirm_transfer(State(state.clone()), Path(tid.into()), auth_headers(&tok, None), body)
+        };
+
+        assert_eq!(confirm("alice").await.unwrap(), StatusCode::ACCEPTED);
+        assert!(state.storage.file_path(tid).exists());
+        // Two downloads used up the cap, though five recipients were offered it.
+        assert_eq!(confirm("bob").await.unwrap(), StatusCode::OK);
+        assert!(!state.storage.file_path(tid).exists());
+        assert_eq!(download_status(&state, tid, auth_headers(&token(Uuid::new_v4(), 3600), None)).await, StatusCode::GONE);
+
+        let _ = std::fs::remove_dir_all(test_dir("cap"));
+    }
+
+    fn admin_token(admin: bool) -> String {
+        let claims = AdminClaims {
+            admin,
+            exp: (chrono::Utc::now() + chrono::Duration::hours(1)).timestamp() as usize,
+            iss: None,
+            aud: None,
+        };
+        jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &jsonwebtoken::EncodingKey::from_secret(SECRET.as_bytes()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_admin_token_and_purge_rows_and_file() {
+        let tid = "abusive";
+        let state = test_state("admin", tid, 64).await;
+        state.db.with_conn_mut(|conn| {
+            conn.execute(
+                "INSERT INTO chunks (transfer_id, chunk_index, sha256, byte_offset, byte_length, received)
+                 VALUES (?1, 0, '', 0, 64, 1)",
+                [tid],
+            )?;
+            conn.execute("INSERT INTO transfer_confirmations (transfer_id, recipient) VALUES (?1, 'alice')", [tid])?;
+            for id in ["older-1", "older-2"] {
+                conn.execute(
+                    "INSERT INTO transfers (id, uploader_id, file_size, chunk_count, file_sha256, created_at)
+                     VALUES (?1, 'u', 0, 0, '', datetime('now', '-1 hour'))",
+                    [id],
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let local = ConnectInfo(std::net::SocketAddr::from(([127, 0, 0, 1], 40000)));
+        let list = |headers: HeaderMap, limit: Option<u32>, offset: Option<u64>| {
+            admin_list_transfers(local, State(state.clone()), headers, Query(AdminTransferPage { limit, offset }))
+        };
+        let purge = |headers: HeaderMap| admin_delete_transfer(local, State(state.clone()), Path(tid.into()), headers);
+        let user = auth_headers(&token(Uuid::new_v4(), 3600), None);
+        let admin = auth_headers(&admin_token(true), None);
+
+        assert_eq!(list(HeaderMap::new(), None, None).await.unwrap_err(), StatusCode::UNAUTHORIZED);
+        assert_eq!(list(user.clone(), None, None).await.unwrap_err(), StatusCode::FORBIDDEN);
+        assert_eq!(list(auth_headers(&admin_token(false), None), None, None).await.unwrap_err(), StatusCode::FORBIDDEN);
+        assert_eq!(purge(user).await.unwrap_err(), StatusCode::FORBIDDEN);
+        // Even an admin token only works from this host.
+        let remote = ConnectInfo(std::net::SocketAddr::from(([192, 0, 2, 7], 40000)));
+        let page = Query(AdminTransferPage { limit: None, offset: None });
+        let refused = admin_list_transfers(remote, State(state.clone()), admin.clone(), page).await;
+        assert_eq!(refused.unwrap_err(), StatusCode::FORBIDDEN);
+        assert!(state.storage.file_path(tid).exists());
+
+        // Newest first, a page at a time.
+        let Json(page) = list(admin.clone(), Some(2), None).await.unwrap();
+        assert_eq!(page.total, 3);
+        assert_eq!(page.transfers.iter().map(|t| t.id.as_str()).collect::<Vec<_>>(), [tid, "older-1"]);
+        let Json(page) = list(admin.clone(), Some(2), Some(2)).await.unwrap();
+        assert_eq!(page.transfers.iter().map(|t| t.id.as_str()).collect::<Vec<_>>(), ["older-2"]);
+
+        let Json(purged) = purge(admin.clone()).await.unwrap();
+        assert_eq!(purged.purged, [tid]);
+        assert!(!state.storage.file_path(tid).exists());
+        let count = |table: &str| {
+            let sql = format!("SELECT COUNT(*) FROM {} WHERE {} = ?1", table, if table == "transfers" { "id" } else { "transfer_id" });
+            state.db.with_conn(|c| Ok(c.query_row(&sql, [tid], |r| r.get::<_, i64>(0))?)).unwrap()
+        };
+        assert_eq!((count("transfers"), count("chunks"), count("transfer_confirmations")), (0, 0, 0));
+        assert_eq!(purge(admin).await.unwrap_err(), StatusCode::NOT_FOUND);
+
+        let _ = std::fs::remove_dir_all(test_dir("admin"));
+    }
+
+    #[tokio::test]
+    async fn admin_purge_takes_deduplicated_copies_and_the_blob() {
+        let (tid, copy, sha) = ("abusive", "reupload", "ab".repeat(32));
+        let state = test_state("admin-dedup", tid, 64).await;
+        state.storage.index_blob(tid, &sha).await.unwrap();
+        state.storage.link_from_blob(&sha, copy).await.unwrap();
+        state.db.with_conn_mut(|conn| {
+            conn.execute(
+                "INSERT INTO blobs (sha256, file_size, chunk_size, chunk_hashes) VALUES (?1, 64, 64, '[]')",
+                [&sha],
+            )?;
+            conn.execute(
+                "INSERT INTO transfers (id, uploader_id, file_size, chunk_count, file_sha256, status)
+                 VALUES (?1, 'u', 64, 1, ?2, 'complete')",
+                [copy, sha.as_str()],
+            )?;
+            conn.execute("UPDATE transfers SET blob_sha256 = ?1, file_sha256 = ?1", [&sha])?;
+            Ok(())
+        })
+        .unwrap();
+
+        let local = ConnectInfo(std::net::SocketAddr::from(([127, 0, 0, 1], 40000)));
+        let admin = auth_headers(&admin_token(true), None);
+        let Json(purged) = admin_delete_transfer(local, State(state.clone()), Path(tid.into()), admin).await.unwrap();
+        assert_eq!(purged.purged, [tid, copy]);
+
+        assert!(!state.storage.file_path(tid).exists());
+        assert!(!state.storage.file_path(copy).exists());
+        let left = state
+            .db
+            .with_conn(|c| {
+                let transfers: i64 = c.query_row("SELECT COUNT(*) FROM transfers", [], |r| r.get(0))?;
+                let blobs: i64 = c.query_row("SELECT COUNT(*) FROM blobs", [], |r| r.get(0))?;
+                Ok((transfers, blobs))
+            })
+            .unwrap();
+        assert_eq!(left, (0, 0));
+
+        let _ = std::fs::remove_dir_all(test_dir("admin-dedup"));
+    }
 }
@@ -0,0 +1,250 @@
+//! Graceful shutdown for in-flight fast transfers.
+//!
+//! Every UDP receiver/sender pipeline registers here for as long as its
+//! thread runs. On shutdown the registry stops admitting new transfers,
+//! waits up to a grace period for the registered ones to finish, then
+//! cancels the rest. Uploads cut off that way are marked `interrupted` so
+//! their clients can resume instead of finding them stuck in `uploading`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use haven_types::api::TransferStatus as TStatus;
+
+use crate::db::FileDb;
+
+/// Default time in-flight transfers get to finish after a shutdown signal.
+pub const DEFAULT_GRACE: Duration = Duration::from_secs(30);
+
+/// Direction of a registered transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferKind {
+    Upload,
+    Download,
+}
+
+struct Active {
+    transfer_id: String,
+    kind: TransferKind,
+    cancel: Box<dyn Fn() + Send + Sync>,
+}
+
+/// Outcome of [`TransferRegistry::drain`].
+#[derive(Debug, Default)]
+pub struct DrainReport {
+    /// Transfers that finished within the grace period.
+    pub drained: usize,
+    /// Uploads cancelled at the deadline; these need marking `interrupted`.
+    pub aborted_uploads: Vec<String>,
+    /// Downloads cancelled at the deadline. Nothing to record: the stored
+    /// file is intact and the client can simply download again.
+    pub aborted_downloads: usize,
+}
+
+/// In-flight fast transfers, keyed by registration.
+#[derive(Default)]
+pub struct TransferRegistry {
+    draining: AtomicBool,
+    next_id: AtomicU64,
+    active: Mutex<HashMap<u64, Active>>,
+    changed: Notify,
+}
+
+/// Keeps a transfer registered until dropped. Move it into whatever outlives
+/// the pipeline thread (the join task), not the WebSocket handler.
+pub struct TransferGuard {
+    registry: Arc<TransferRegistry>,
+    id: u64,
+}
+
+impl TransferRegistry {
+    /// Register a transfer. `cancel` must make its pipeline thread exit
+    /// promptly. Returns `None` once shutdown has begun.
+    pub fn begin(
+        self: &Arc<Self>,
+        transfer_id: &str,
+        kind: TransferKind,
+        cancel: impl Fn() + Send + Sync + 'static,
+    ) -> Option<TransferGuard> {
+        let mut active = self.active.lock().unwrap();
+        // Checked under the lock so drain can't miss a late registration.
+        if self.draining.load(Ordering::Relaxed) {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        active.insert(id, Active { transfer_id: transfer_id.to_string(), kind, cancel: Box::new(cancel) });
+        Some(TransferGuard { registry: self.clone(), id })
+    }
+
+    /// Stop admitting new transfers. Idempotent.
+    pub fn stop_accepting(&self) {
+        let _active = self.active.lock().unwrap();
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    fn active_count(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+
+    /// Whether a pipeline for `transfer_id` is registered.
+    pub fn is_active(&self, transfer_id: &str) -> bool {
+        self.active.lock().unwrap().values().any(|t| t.transfer_id == transfer_id)
+    }
+
+    /// Cancel the registered `kind` pipelines for `transfer_id`, e.g. a
+    /// receiver still waiting on a client that has since reconnected, and
+    /// wait up to `timeout` for them to unwind. Returns whether they did.
+    pub async fn cancel(&self, transfer_id: &str, kind: TransferKind, timeout: Duration) -> bool {
+        for transfer in self.active.lock().unwrap().values() {
+            if transfer.transfer_id == transfer_id && transfer.kind == kind {
+                (transfer.cancel)();
+            }
+        }
+        let finished = async {
+            loop {
+                let changed = self.changed.notified();
+                if !self.is_active(transfer_id) {
+                    return;
+                }
+                changed.await;
+            }
+        };
+        tokio::time::timeout(timeout, finished).await.is_ok()
+    }
+
+    /// Stop admitting transfers, wait up to `grace` for the active ones, then
+    /// cancel whatever is left and give it a moment to unwind.
+    pub async fn drain(&self, grace: Duration) -> DrainReport {
+        self.stop_accepting();
+        let initial = self.active_count();
+        if initial > 0 {
+            info!("Shutdown: waiting up to {:?} for {} in-flight transfer(s)", grace, initial);
+        }
+        let _ = tokio::time::timeout(grace, self.wait_idle()).await;
+
+        let mut report = DrainReport::default();
+        {
+            let active = self.active.lock().unwrap();
+            report.drained = initial.saturating_sub(active.len());
+            for transfer in active.values() {
+                (transfer.cancel)();
+                match transfer.kind {
+                    TransferKind::Upload => report.aborted_uploads.push(transfer.transfer_id.clone()),
+                    TransferKind::Download => report.aborted_downloads += 1,
+                }
+            }
+        }
+        if !report.aborted_uploads.is_empty() || report.aborted_downloads > 0 {
+            // Pipelines poll their cancel flag every ~100ms.
+            let _ = tokio::time::timeout(Duration::from_secs(2), self.wait_idle()).await;
+        }
+        report
+    }
+
+    async fn wait_idle(&self) {
+        loop {
+            let changed = self.changed.notified();
+            if self.active_count() == 0 {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        self.registry.active.lock().unwrap().remove(&self.id);
+        self.registry.changed.notify_waiters();
+    }
+}
+
+/// Mark uploads cut off by shutdown (or by their client going away) as
+/// `interrupted`. Only rows still `uploading` change, so one that completed while being cancelled keeps
+/// its status.
+pub fn mark_interrupted(db: &FileDb, transfer_ids: &[String]) -> anyhow::Result<usize> {
+    db.with_transaction(|conn| {
+        let mut marked = 0;
+        for id in transfer_ids {
+            marked += conn.execute(
+                "UPDATE transfers SET status = ?1 WHERE id = ?2 AND status = ?3",
+                rusqlite::params![TStatus::Interrupted.to_string(), id, TStatus::Uploading.to_string()],
+            )?;
+        }
+        Ok(marked)
+    })
+}
+
+/// Drain the registry and record the outcome.
+pub async fn drain_and_record(registry: &TransferRegistry, db: &FileDb, grace: Duration) {
+    let report = registry.drain(grace).await;
+    let marked = match mark_interrupted(db, &report.aborted_uploads) {
+        Ok(n) => n,
+        Err(e) => {
+            warn!("Shutdown: failed to mark interrupted uploads: {}", e);
+            0
+        }
+    };
+    let aborted = report.aborted_uploads.len() + report.aborted_downloads;
+    if aborted > 0 {
+        warn!(
+            "Shutdown: {} transfer(s) drained, {} aborted ({} upload(s) marked interrupted)",
+            report.drained, aborted, marked
+        );
+    } else {
+        info!("Shutdown: {} transfer(s) drained, 0 aborted", report.drained);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU8;
+
+    #[tokio::test]
+    async fn drain_waits_then_aborts_stragglers() {
+        let registry = Arc::new(TransferRegistry::default());
+
+        // One transfer finishes during the grace period.
+        let quick = registry.begin("quick", TransferKind::Upload, || {}).unwrap();
+        let finisher = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(quick);
+        });
+
+        // The other only stops when cancelled, like a stalled receiver.
+        let cancelled = Arc::new(AtomicU8::new(0));
+        let flag = cancelled.clone();
+        let slow = registry
+            .begin("slow", TransferKind::Upload, move || flag.store(1, Ordering::Relaxed))
+            .unwrap();
+        let watcher = {
+            let cancelled = cancelled.clone();
+            tokio::spawn(async move {
+                while cancelled.load(Ordering::Relaxed) == 0 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                drop(slow);
+            })
+        };
+
+        let report = registry.drain(Duration::from_millis(300)).await;
+        finisher.await.unwrap();
+        watcher.await.unwrap();
+        assert_eq!(report.drained, 1);
+        assert_eq!(report.aborted_uploads, vec!["slow".to_string()]);
+        assert_eq!(registry.active_count(), 0);
+
+        // Nothing new gets in once draining.
+        assert!(registry.begin("late", TransferKind::Download, || {}).is_none());
+    }
+}
@@ -2,20 +2,23 @@ use anyhow::{Result, bail};
 use sha2::{Sha256, Digest};
 use std::path::PathBuf;
 use tokio::fs;
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tracing::{info, warn};
 
 /// Manages on-disk file storage for transfers.
 ///
 /// Each transfer is stored as a single flat file at `{storage_dir}/{transfer_id}`.
 /// Sequential writes maximize throughput on HDDs.
+///
+/// Deduplicated content lives at `{storage_dir}/blobs/{sha256}`; transfers
+/// that share it are hard links to the same inode (see `dedup`).
 pub struct Storage {
     dir: PathBuf,
 }
 
 impl Storage {
     pub async fn new(dir: PathBuf) -> Result<Self> {
-        fs::create_dir_all(&dir).await?;
+        fs::create_dir_all(dir.join("blobs")).await?;
         info!("File storage directory: {}", dir.display());
         Ok(Self { dir })
     }
@@ -35,6 +38,16 @@ impl Storage {
         self.dir.join(transfer_id)
     }
 
+    /// Free bytes on the filesystem holding the storage directory.
+    pub fn available_space(&self) -> Result<u64> {
+        Ok(haven_fast_transfer::disk::available_space(&self.dir)?)
+    }
+
+    /// Fail unless files can be created in the storage directory.
+    pub async fn check_writable(&self) -> Result<()> {
+        Ok(haven_types::ready::probe_writable(&self.dir).await?)
+    }
+
     /// Pre-allocate a file of the given size (sparse file on supported FSes).
     pub async fn create_file(&self, transfer_id: &str, size: u64) -> Result<()> {
         let path = self.file_path(transfer_id);
@@ -76,9 +89,11 @@ impl Storage {
         Ok(data.len())
     }
 
-    /// Delete a transfer's file from disk.
+    /// Delete a transfer's file from disk, with any record of which chunks
+    /// an interrupted upload left in it.
     pub async fn delete_file(&self, transfer_id: &str) -> Result<()> {
         let path = self.file_path(transfer_id);
+        let _ = fs::remove_file(haven_fast_transfer::resume::state_path(&path)).await;
         match fs::remove_file(&path).await {
             Ok(()) => {
                 info!("Deleted file for transfer {}", transfer_id);
@@ -92,4 +107,71 @@ impl Storage {
         }
     }
 
+    /// Path to the deduplicated blob with the given content hash.
+    fn blob_path(&self, sha256: &str) -> PathBuf {
+        assert!(is_blob_key(sha256), "invalid blob key: {}", sha256);
+        self.dir.join("blobs").join(sha256)
+    }
+
+    /// SHA-256 of a transfer's stored file, as lowercase hex.
+    pub async fn hash_file(&self, transfer_id: &str) -> Result<String> {
+        let mut file = fs::File::open(self.file_path(transfer_id)).await?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 1024 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+
+    /// Create a transfer's file as a hard link to an existing blob. Fails if
+    /// the blob is gone, the transfer file already exists, or the filesystem
+    /// has no hard links.
+    pub async fn link_from_blob(&self, sha256: &str, transfer_id: &str) -> Result<()> {
+        fs::hard_link(self.blob_path(sha256), self.file_path(transfer_id)).await?;
+        Ok(())
+    }
+
+    /// Register a complete transfer's file as the blob for `sha256`. If that
+    /// blob already exists, the transfer's copy is replaced by a link to it
+    /// so the bytes are only stored once.
+    pub async fn index_blob(&self, transfer_id: &str, sha256: &str) -> Result<()> {
+        let blob = self.blob_path(sha256);
+        let file = self.file_path(transfer_id);
+        match fs::hard_link(&file, &blob).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                // Link beside the target, then rename over it: readers with
+                // the old file open keep their inode.
+                let tmp = self.dir.join(format!("{}.dedup", transfer_id));
+                let _ = fs::remove_file(&tmp).await;
+                fs::hard_link(&blob, &tmp).await?;
+                fs::rename(&tmp, &file).await?;
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Remove a blob's index link. Transfers still linked to it keep their data.
+    pub async fn delete_blob(&self, sha256: &str) -> Result<()> {
+        match fs::remove_file(self.blob_path(sha256)).await {
+            Ok(()) => {
+                info!("Deleted blob {}", sha256);
+                Ok(())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Whether `s` can name a blob: a lowercase hex SHA-256. Client-supplied
+/// hashes go through this before touching the filesystem.
+pub fn is_blob_key(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
 }
@@ -0,0 +1,278 @@
+//! Optional HTTPS for the HTTP control plane.
+//!
+//! Enabled when both `HAVEN_FILE_TLS_CERT` (PEM certificate chain, leaf
+//! first) and `HAVEN_FILE_TLS_KEY` (PEM private key: PKCS#8, PKCS#1 or SEC1)
+//! are set. The UDP fast-transfer socket is unaffected; its payload is
+//! already end-to-end encrypted.
+//!
+//! Cipher defaults are rustls' safe defaults with the ring provider:
+//! TLS 1.3 and 1.2 only, AEAD suites only (AES-256-GCM, AES-128-GCM,
+//! ChaCha20-Poly1305), ECDHE key exchange (X25519, P-256, P-384), no
+//! renegotiation, and no CBC or RSA key exchange. ALPN advertises
+//! `http/1.1` so WebSocket upgrades keep working.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use axum::Router;
+use rustls::ServerConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+/// Clients that haven't finished the handshake by then are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long shutdown waits for open connections to finish their in-flight
+/// requests before cutting them off.
+const CONNECTION_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read the cert/key paths from the environment.
+///
+/// `Ok(None)` when neither is set (plain HTTP); `Err` when only one is, since
+/// silently falling back to plain HTTP would defeat the point.
+pub fn paths_from_env() -> Result<Option<(PathBuf, PathBuf)>, &'static str> {
+    let cert = std::env::var("HAVEN_FILE_TLS_CERT").ok().filter(|v| !v.is_empty());
+    let key = std::env::var("HAVEN_FILE_TLS_KEY").ok().filter(|v| !v.is_empty());
+    match (cert, key) {
+        (Some(cert), Some(key)) => Ok(Some((cert.into(), key.into()))),
+        (None, None) => Ok(None),
+        (Some(_), None) => Err("HAVEN_FILE_TLS_CERT is set but HAVEN_FILE_TLS_KEY is not."),
+        (None, Some(_)) => Err("HAVEN_FILE_TLS_KEY is set but HAVEN_FILE_TLS_CERT is not."),
+    }
+}
+
+/// Build a rustls server config from PEM files.
+pub fn load_server_config(cert_path: &Path, key_path: &Path) -> anyhow::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .with_context(|| format!("reading TLS certificate {}", cert_path.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("parsing TLS certificate {}", cert_path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", cert_path.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("reading TLS private key {}", key_path.display()))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("TLS certificate and key don't match")?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Serve `app` over TLS until `shutdown` resolves. Handshakes run on the
+/// per-connection task so a slow client can't stall the accept loop.
+///
+/// On shutdown the listener closes, open connections are asked to finish
+/// their in-flight requests and close, and whatever is still open after
+/// [`CONNECTION_DRAIN_TIMEOUT`] is aborted.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    config: Arc<ServerConfig>,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    use hyper_util::rt::TokioIo;
+    use tower::Service;
+
+    let acceptor = TlsAcceptor::from(config);
+    let mut make_svc = app.into_make_service_with_connect_info::<SocketAddr>();
+
+    let (closing_tx, closing_rx) = tokio::sync::watch::channel(());
+    let mut connections = tokio::task::JoinSet::new();
+
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            result = listener.accept() => {
+                let (stream, peer_addr) = match result {
+                    Ok(r) => r,
+                    Err(e) => {
+                        error!("TCP accept error: {}", e);
+                        continue;
+                    }
+                };
+                let _ = stream.set_nodelay(true);
+
+                let svc = make_svc.call(peer_addr).await.unwrap();
+                let hyper_svc = hyper_util::service::TowerToHyperService::new(svc);
+                let acceptor = acceptor.clone();
+                let mut closing = closing_rx.clone();
+                connections.spawn(async move {
+                    let tls_stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(s)) => s,
+                        Ok(Err(e)) => {
+                            debug!("TLS handshake with {} failed: {}", peer_addr, e);
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("TLS handshake with {} timed out", peer_addr);
+                            return;
+                        }
+                    };
+                    let io = TokioIo::new(tls_stream);
+                    let builder = hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+                    let conn = builder.serve_connection_with_upgrades(io, hyper_svc);
+                    tokio::pin!(conn);
+                    tokio::select! {
+                        _ = conn.as_mut() => {}
+                        _ = closing.changed() => {
+                            conn.as_mut().graceful_shutdown();
+                            let _ = conn.await;
+                        }
+                    }
+                });
+                // Reap finished connections so the set only holds open ones.
+                while connections.try_join_next().is_some() {}
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    drop(listener);
+    let _ = closing_tx.send(());
+    if !connections.is_empty() {
+        info!("Waiting for {} TLS connection(s) to close", connections.len());
+    }
+    let drained = tokio::time::timeout(CONNECTION_DRAIN_TIMEOUT, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "{} TLS connection(s) still open after {:?}, closing them",
+            connections.len(),
+            CONNECTION_DRAIN_TIMEOUT
+        );
+        connections.shutdown().await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+    use uuid::Uuid;
+
+    use crate::harness::{app_state, token};
+
+    /// A self-signed `localhost` certificate written into `dir`, loaded as a
+    /// server config, and a client for `port` that trusts it.
+    fn self_signed(dir: &Path, port: u16) -> (Arc<ServerConfig>, reqwest::Client) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        let config = load_server_config(&cert_path, &key_path).unwrap();
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(cert.cert.pem().as_bytes()).unwrap())
+            .resolve("localhost", ([127, 0, 0, 1], port).into())
+            .build()
+            .unwrap();
+        (config, client)
+    }
+
+    #[tokio::test]
+    async fn test_handshake_and_upload_over_tls() {
+        let dir = std::env::temp_dir().join(format!("haven-fs-tls-{}", std::process::id()));
+        let state = app_state(&dir).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (config, client) = self_signed(&dir, port);
+        tokio::spawn(serve(listener, crate::build_router(state), config, std::future::pending()));
+
+        let base = format!("https://localhost:{port}");
+        let auth = format!("Bearer {}", token(Uuid::new_v4(), 3600));
+
+        let health = client.get(format!("{base}/health")).send().await.unwrap();
+        assert!(health.status().is_success());
+
+        let data = b"hello over tls".to_vec();
+        let hash = hex::encode(Sha256::digest(&data));
+        let created = client
+            .post(format!("{base}/transfers"))
+            .header("authorization", &auth)
+            .json(&serde_json::json!({
+                "id": "tls-upload",
+                "file_size": data.len(),
+                "file_sha256": hash,
+                "chunk_hashes": [hash],
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert!(created.status().is_success(), "create: {}", created.status());
+
+        let uploaded = client
+            .put(format!("{base}/transfers/tls-upload/chunks/0"))
+            .header("authorization", &auth)
+            .body(data)
+            .send()
+            .await
+            .unwrap();
+        assert!(uploaded.status().is_success(), "upload: {}", uploaded.status());
+
+        let status: serde_json::Value = client
+            .get(format!("{base}/transfers/tls-upload"))
+            .header("authorization", &auth)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(status["status"], "complete");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_in_flight_request() {
+        let dir = std::env::temp_dir().join(format!("haven-fs-tls-drain-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (config, client) = self_signed(&dir, port);
+        let app = Router::new().route(
+            "/slow",
+            axum::routing::get(|| async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                "done"
+            }),
+        );
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, app, config, async {
+            let _ = shutdown_rx.await;
+        }));
+
+        let request = tokio::spawn(async move { client.get(format!("https://localhost:{port}/slow")).send().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown_tx.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!server.is_finished(), "serve waits while a request is in flight");
+
+        let response = request.await.unwrap().unwrap();
+        assert_eq!(response.text().await.unwrap(), "done");
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("serve returns once the connection closes")
+            .unwrap()
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
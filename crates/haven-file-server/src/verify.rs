@@ -0,0 +1,67 @@
+//! Whole-file integrity check for HTTP uploads.
+//!
+//! Chunked uploads only check each chunk as it lands (and `upload_chunk` not
+//! even that), so a client bug in chunk offsets can still assemble a file
+//! that is "complete" but wrong. Once the last chunk arrives the stored file
+//! is re-hashed and compared with the declared `file_sha256`; a mismatch
+//! marks the transfer `corrupt`.
+//!
+//! On by default. `HAVEN_FILE_VERIFY_UPLOADS=0` skips the extra read of every
+//! file for deployments that can't afford it.
+
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use haven_types::api::TransferStatus as TStatus;
+
+use crate::db::FileDb;
+use crate::storage::Storage;
+
+/// `HAVEN_FILE_VERIFY_UPLOADS`; anything but `0`/`false` (or unset) is on.
+pub fn enabled_from_env() -> bool {
+    std::env::var("HAVEN_FILE_VERIFY_UPLOADS")
+        .map(|v| !matches!(v.trim(), "0" | "false"))
+        .unwrap_or(true)
+}
+
+/// Re-hash a just-completed transfer's file against its declared hash.
+/// Returns `false` after marking it `corrupt` if they differ.
+pub async fn verify_upload(db: &FileDb, storage: &Storage, transfer_id: &str) -> Result<bool> {
+    let declared: String = db.with_conn_mut(|conn| {
+        Ok(conn.query_row("SELECT file_sha256 FROM transfers WHERE id = ?1", [transfer_id], |row| row.get(0))?)
+    })?;
+
+    let path = storage.file_path(transfer_id);
+    let actual = tokio::task::spawn_blocking(move || hash_path(&path)).await??;
+    if actual.eq_ignore_ascii_case(&declared) {
+        return Ok(true);
+    }
+
+    warn!("Transfer {} assembled to {} but declared {}, marking corrupt", transfer_id, actual, declared);
+    db.with_conn_mut(|conn| {
+        conn.execute(
+            "UPDATE transfers SET status = ?1 WHERE id = ?2 AND status = ?3",
+            rusqlite::params![TStatus::Corrupt.to_string(), transfer_id, TStatus::Complete.to_string()],
+        )?;
+        Ok(())
+    })?;
+    Ok(false)
+}
+
+fn hash_path(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
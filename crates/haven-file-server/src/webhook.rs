@@ -0,0 +1,74 @@
+//! Outbound notification when a transfer expires unclaimed.
+//!
+//! Set `HAVEN_FILE_EXPIRY_WEBHOOK` to a URL and the cleanup loop POSTs an
+//! [`ExpiryEvent`] there for every transfer it expires that was never
+//! confirmed, so the sender learns the recipient never got the file. Off when
+//! unset.
+
+use std::time::Duration;
+
+use serde::Serialize;
+use tracing::warn;
+
+/// Attempts per event before giving up.
+const MAX_ATTEMPTS: u32 = 3;
+/// Delay before the first retry; doubles after each failure.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// JSON body of one notification.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExpiryEvent {
+    pub transfer_id: String,
+    pub uploader_id: String,
+    /// RFC 3339, UTC.
+    pub expired_at: String,
+}
+
+pub struct ExpiryWebhook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl ExpiryWebhook {
+    pub fn new(url: String) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        Ok(Self { client, url })
+    }
+
+    /// `HAVEN_FILE_EXPIRY_WEBHOOK`, if set and non-empty.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        match std::env::var("HAVEN_FILE_EXPIRY_WEBHOOK") {
+            Ok(url) if !url.trim().is_empty() => Self::new(url.trim().to_string()).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// POST `event`, retrying connection errors and non-2xx responses with
+    /// backoff. Returns whether it was delivered; failures are logged, never
+    /// fatal to cleanup.
+    pub async fn send(&self, event: &ExpiryEvent) -> bool {
+        let mut backoff = RETRY_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let error = match self.client.post(&self.url).json(event).send().await {
+                Ok(resp) if resp.status().is_success() => return true,
+                Ok(resp) => format!("HTTP {}", resp.status()),
+                Err(e) => e.to_string(),
+            };
+            if attempt == MAX_ATTEMPTS {
+                warn!(
+                    "Expiry webhook for {} failed after {} attempts: {}",
+                    event.transfer_id, MAX_ATTEMPTS, error
+                );
+            } else {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,141 @@
+//! Caps on concurrent gateway connections.
+//!
+//! Each connection costs two tasks and a 2048-deep queue, so an unbounded
+//! flood of upgrades can exhaust memory. [`ConnectionLimits`] gates upgrades
+//! on a server-wide total and a per-IP count; a granted [`ConnectionSlot`]
+//! is held for the life of the connection and frees itself on drop.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Default server-wide connection cap.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 10_000;
+
+/// Default cap per client IP. Behind a reverse proxy every client shares the
+/// proxy's address, so set it to 0 (no cap) there.
+pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 64;
+
+/// Why an upgrade was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    /// The server is at its total connection limit.
+    Total,
+    /// This IP already holds its share of connections.
+    PerIp,
+}
+
+struct Inner {
+    max_total: usize,
+    max_per_ip: usize,
+    total: AtomicUsize,
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Shared connection counters. A limit of 0 means unlimited.
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    inner: Arc<Inner>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_CONNECTIONS_PER_IP)
+    }
+}
+
+impl ConnectionLimits {
+    pub fn new(max_total: usize, max_per_ip: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                max_total,
+                max_per_ip,
+                total: AtomicUsize::new(0),
+                per_ip: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Claim a slot for a connection from `ip`, or say which limit it hit.
+    pub fn try_acquire(&self, ip: IpAddr) -> Result<ConnectionSlot, Rejected> {
+        let ip = ip.to_canonical();
+        let mut per_ip = self.inner.per_ip.lock().unwrap();
+        let held = per_ip.get(&ip).copied().unwrap_or(0);
+        if self.inner.max_per_ip != 0 && held >= self.inner.max_per_ip {
+            return Err(Rejected::PerIp);
+        }
+        let max_total = self.inner.max_total;
+        self.inner
+            .total
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (max_total == 0 || n < max_total).then_some(n + 1))
+            .map_err(|_| Rejected::Total)?;
+        per_ip.insert(ip, held + 1);
+        Ok(ConnectionSlot { inner: self.inner.clone(), ip })
+    }
+
+    /// Connections currently holding a slot.
+    pub fn open(&self) -> usize {
+        self.inner.total.load(Ordering::Acquire)
+    }
+
+    /// Connections currently held by `ip`.
+    pub fn open_from(&self, ip: IpAddr) -> usize {
+        self.inner.per_ip.lock().unwrap().get(&ip.to_canonical()).copied().unwrap_or(0)
+    }
+}
+
+/// One connection's claim on [`ConnectionLimits`], released on drop.
+pub struct ConnectionSlot {
+    inner: Arc<Inner>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut per_ip = self.inner.per_ip.lock().unwrap();
+        if let Some(held) = per_ip.get_mut(&self.ip) {
+            *held -= 1;
+            if *held == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+        self.inner.total.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn test_upgrade_past_total_limit_is_refused() {
+        let limits = ConnectionLimits::new(3, 0);
+        let slots: Vec<_> = (1..=3).map(|i| limits.try_acquire(ip(i)).unwrap()).collect();
+        assert_eq!(limits.open(), 3);
+        assert_eq!(limits.try_acquire(ip(4)).err(), Some(Rejected::Total));
+
+        drop(slots);
+        assert_eq!(limits.open(), 0);
+        assert!(limits.try_acquire(ip(4)).is_ok());
+    }
+
+    #[test]
+    fn test_per_ip_cap_leaves_other_ips_alone() {
+        let limits = ConnectionLimits::new(0, 2);
+        let first = limits.try_acquire(ip(1)).unwrap();
+        let _second = limits.try_acquire(ip(1)).unwrap();
+        // The IPv4-mapped form of the same address counts against it too.
+        let mapped: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        assert_eq!(limits.try_acquire(mapped).err(), Some(Rejected::PerIp));
+        let _other = limits.try_acquire(ip(2)).unwrap();
+
+        drop(first);
+        assert_eq!(limits.open_from(ip(1)), 1);
+        assert!(limits.try_acquire(ip(1)).is_ok());
+    }
+}
@@ -3,16 +3,21 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
 use bytes::Bytes;
 use futures_util::{SinkExt, StreamExt};
 use tracing::{info, trace, warn};
 use uuid::Uuid;
 
 use haven_types::api::OfferStatus;
-use haven_types::events::{FolderFileEntry, GatewayCommand, GatewayEvent, TurnServer};
+use haven_types::events::{
+    FolderFileEntry, GatewayCommand, GatewayEvent, TurnServer, GATEWAY_PROTOCOL_VERSION, MIN_GATEWAY_PROTOCOL_VERSION,
+};
 
-use crate::dispatcher::{Dispatcher, UserMessage};
+use crate::dispatcher::{Dispatcher, UserMessage, VoiceFrameHeader};
+use crate::rate_limit::CommandCategory;
+use crate::reconnect::{ReconnectIdentity, Session};
+use crate::relay_auth::Offered;
 
 /// Optional database handle for persisting/replaying pending offers.
 /// When Some, file/folder offers are stored and replayed on reconnect.
@@ -25,24 +30,40 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
 /// #6: Handle a pre-authenticated WebSocket connection.
 /// The JWT was already validated at the HTTP upgrade layer (main.rs), so we
 /// skip the Identify handshake and go straight to Ready + event loop.
+/// `protocol_version` is what the client asked for (see `version`); a client
+/// the server can't serve is closed before Ready.
 pub async fn handle_connection_authenticated(
     socket: WebSocket,
     dispatcher: Dispatcher,
-    user_id: Uuid,
-    username: String,
+    session: Session,
+    protocol_version: Option<u32>,
     file_server_url: Option<String>,
     turn_servers: Option<Vec<TurnServer>>,
     db: DbHandle,
 ) {
     let (mut sender, receiver) = socket.split();
+    let (user_id, username) = (session.user_id, session.username.clone());
 
-    info!("{} ({}) connected to gateway (pre-authenticated)", username, user_id);
+    let protocol_version = match crate::version::negotiate(protocol_version) {
+        Ok(version) => version,
+        Err(reason) => {
+            info!("{} ({}) refused: {}", username, user_id, reason);
+            let close = CloseFrame { code: crate::version::CLOSE_UNSUPPORTED_PROTOCOL, reason: reason.into() };
+            let _ = sender.send(Message::Close(Some(close))).await;
+            return;
+        }
+    };
+
+    info!("{} ({}) connected to gateway (pre-authenticated, protocol v{})", username, user_id, protocol_version);
 
     // Send Ready event
     let ready = GatewayEvent::Ready {
         user_id,
         username: username.clone(),
         turn_servers,
+        min_protocol_version: MIN_GATEWAY_PROTOCOL_VERSION,
+        max_protocol_version: GATEWAY_PROTOCOL_VERSION,
+        protocol_version,
     };
     if sender
         .send(Message::Text(serde_json::to_string(&ready).expect("GatewayEvent serialization").into()))
@@ -58,7 +79,7 @@ pub async fn handle_connection_authenticated(
     }
 
     // Shared connection loop
-    run_connection_loop(sender, receiver, dispatcher, user_id, username, file_server_url, db).await;
+    run_connection_loop(sender, receiver, dispatcher, session, protocol_version, file_server_url, db).await;
 }
 
 /// Connection event loop — handles broadcasts, targeted messages, and heartbeats.
@@ -66,22 +87,34 @@ async fn run_connection_loop(
     mut sender: futures_util::stream::SplitSink<WebSocket, Message>,
     mut receiver: futures_util::stream::SplitStream<WebSocket>,
     dispatcher: Dispatcher,
-    user_id: Uuid,
-    username: String,
+    session: Session,
+    protocol_version: u32,
     file_server_url: Option<String>,
     db: DbHandle,
 ) {
+    let (user_id, username) = (session.user_id, session.username.clone());
+
     // Register per-user channel and send existing online users, then go online
     let (conn_id, mut user_rx) = dispatcher.register_user_channel(user_id).await;
 
+    // Let the client come back on its next socket without a fresh JWT, for
+    // as long as the session's own JWT would have been good
+    if let Some((token, expires_at)) = dispatcher.reconnect_tokens().issue(ReconnectIdentity { session, conn_id }) {
+        let event = GatewayEvent::ReconnectToken { token, expires_at };
+        if sender
+            .send(Message::Text(serde_json::to_string(&event).expect("GatewayEvent serialization").into()))
+            .await
+            .is_err()
+        {
+            dispatcher.unregister_user_channel(user_id, conn_id).await;
+            return;
+        }
+    }
+
     // Send existing online users to this client so they see who's already here
     let existing_users = dispatcher.online_users().await;
-    for (uid, uname) in &existing_users {
-        let event = GatewayEvent::PresenceUpdate {
-            user_id: *uid,
-            username: uname.clone(),
-            online: true,
-        };
+    for (uid, presence) in &existing_users {
+        let event = presence.event(*uid);
         if sender
             .send(Message::Text(serde_json::to_string(&event).expect("GatewayEvent serialization").into()))
             .await
@@ -97,6 +130,7 @@ async fn run_connection_loop(
     // Subscribe to broadcasts and relay to this client
     let mut broadcast_rx = dispatcher.subscribe();
     let dispatcher_clone = dispatcher.clone();
+    let send_dispatcher = dispatcher.clone();
 
     // Per-connection channel subscriptions (shared between send and recv tasks).
     let subscribed_channels: Arc<tokio::sync::RwLock<HashSet<Uuid>>> =
@@ -120,6 +154,7 @@ async fn run_connection_loop(
                     let msg = match result {
                         Ok(msg) => msg,
                         Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                            send_dispatcher.metrics().broadcast_lagged(n);
                             warn!("Broadcast receiver lagged by {} messages", n);
                             continue;
                         }
@@ -184,8 +219,51 @@ async fn run_connection_loop(
         while let Some(Ok(msg)) = receiver.next().await {
             match msg {
                 Message::Text(text) => {
-                    match serde_json::from_str::<GatewayCommand>(&text) {
-                        Ok(cmd) => {
+                    let cmd = match serde_json::from_str::<GatewayCommand>(&text) {
+                        Ok(cmd) => cmd,
+                        Err(e) => {
+                            warn!(
+                                "{} ({}, protocol v{}) bad command: {} -- raw: {}",
+                                username_recv,
+                                user_id,
+                                protocol_version,
+                                e,
+                                &text[..text.len().min(200)]
+                            );
+                            continue;
+                        }
+                    };
+                    if !dispatcher_clone.admit_command(user_id, CommandCategory::of(&cmd)).await {
+                        continue;
+                    }
+                    // A Ping comes from the client on its own, not from the user
+                    if !matches!(cmd, GatewayCommand::Ping { .. }) {
+                        dispatcher_clone.presence_activity(user_id).await;
+                    }
+                    match cmd {
+                        GatewayCommand::Resume { last_event_seq } => {
+                            if let Some(db) = &db_recv {
+                                let subs = recv_subscriptions.read().await.clone();
+                                crate::resume::replay_missed_messages(
+                                    &dispatcher_clone,
+                                    db,
+                                    user_id,
+                                    conn_id,
+                                    last_event_seq,
+                                    &subs,
+                                )
+                                .await;
+                            }
+                        }
+                        GatewayCommand::Ping { nonce, client_ts } => {
+                            // Only the asking connection wants the answer.
+                            if let Some(tx) = dispatcher_clone.connection_sender(user_id, conn_id).await {
+                                let server_ts = chrono::Utc::now().timestamp_millis();
+                                let pong = GatewayEvent::Pong { nonce, client_ts, server_ts };
+                                let _ = tx.send(UserMessage::Event(pong)).await;
+                            }
+                        }
+                        cmd => {
                             handle_command(
                                 &dispatcher_clone,
                                 user_id,
@@ -197,18 +275,13 @@ async fn run_connection_loop(
                             )
                             .await;
                         }
-                        Err(e) => {
-                            warn!(
-                                "{} ({}) bad command: {} -- raw: {}",
-                                username_recv,
-                                user_id,
-                                e,
-                                &text[..text.len().min(200)]
-                            );
-                        }
                     }
                 }
                 Message::Binary(data) => {
+                    let category = CommandCategory::of_binary(data.first().copied().unwrap_or_default());
+                    if !dispatcher_clone.admit_command(user_id, category).await {
+                        continue;
+                    }
                     handle_binary_message(
                         &dispatcher_clone,
                         user_id,
@@ -245,6 +318,8 @@ async fn handle_command(
 ) {
     match cmd {
         GatewayCommand::Identify { .. } => {} // Already handled
+        GatewayCommand::Resume { .. } => {} // Handled in the receive loop (needs conn_id)
+        GatewayCommand::Ping { .. } => {} // Same
 
         GatewayCommand::Subscribe { channel_ids } => {
             info!(
@@ -262,12 +337,17 @@ async fn handle_command(
                 .await;
         }
 
+        GatewayCommand::SetPresence { status, custom, idle_timeout_secs } => {
+            let idle_timeout = idle_timeout_secs.map(|secs| Duration::from_secs(secs.into()));
+            dispatcher.set_presence(user_id, status, custom, idle_timeout).await;
+        }
+
         GatewayCommand::StartTyping { channel_id } => {
-            dispatcher.broadcast(GatewayEvent::TypingStart {
-                channel_id,
-                user_id,
-                username: username.to_string(),
-            });
+            dispatcher.start_typing(user_id, channel_id, username.to_string());
+        }
+
+        GatewayCommand::StopTyping { channel_id } => {
+            dispatcher.stop_typing(user_id, channel_id);
         }
 
         GatewayCommand::VoiceJoin { channel_id } => {
@@ -376,6 +456,14 @@ async fn handle_command(
             dispatcher.relay_voice_data(user_id, data).await;
         }
 
+        GatewayCommand::VoiceStatsRequest => {
+            if let Some((channel_id, peers)) = dispatcher.voice_stats(user_id).await {
+                dispatcher
+                    .send_to_user(user_id, GatewayEvent::VoiceStats { channel_id, peers })
+                    .await;
+            }
+        }
+
         GatewayCommand::FileOfferSend {
             target_user_id,
             transfer_id,
@@ -390,6 +478,10 @@ async fn handle_command(
                 username, user_id, target_user_id, filename,
                 if folder_id.is_some() { " [folder]" } else { "" }
             );
+            if dispatcher.transfer_agreements().offer(&transfer_id, user_id, [target_user_id]) == Offered::TooMany {
+                refuse_offer(dispatcher, user_id, username, &transfer_id).await;
+                return;
+            }
             // Persist offer for replay on reconnect
             if let Some(db) = &db {
                 let ch_json = chunk_hashes.as_ref().map(|h| serde_json::to_string(h).unwrap_or_default());
@@ -425,6 +517,82 @@ async fn handle_command(
                 .await;
         }
 
+        GatewayCommand::FileOfferBroadcast {
+            channel_id,
+            transfer_id,
+            filename,
+            size,
+            file_sha256,
+            chunk_hashes,
+        } => {
+            if !subscriptions.read().await.contains(&channel_id) {
+                warn!(
+                    "{} ({}) file offer to channel {} they are not subscribed to",
+                    username, user_id, channel_id
+                );
+                return;
+            }
+            let recipient_ids: Vec<Uuid> = dispatcher
+                .channel_subscribers(channel_id)
+                .await
+                .into_iter()
+                .filter(|id| *id != user_id)
+                .collect();
+            info!(
+                "{} ({}) -> file offer to {} members of {} ({})",
+                username, user_id, recipient_ids.len(), channel_id, filename
+            );
+            if dispatcher.transfer_agreements().offer(&transfer_id, user_id, recipient_ids.iter().copied())
+                == Offered::TooMany
+            {
+                refuse_offer(dispatcher, user_id, username, &transfer_id).await;
+                return;
+            }
+            let ch_json = chunk_hashes.as_ref().map(|h| serde_json::to_string(h).unwrap_or_default());
+            for &recipient in &recipient_ids {
+                if let Some(db) = &db
+                    && let Err(e) = db.insert_pending_offer(
+                        &transfer_id,
+                        &user_id.to_string(),
+                        &recipient.to_string(),
+                        &filename,
+                        size as i64,
+                        file_sha256.as_deref(),
+                        ch_json.as_deref(),
+                        file_server_url,
+                        None,
+                    )
+                {
+                    warn!("Failed to persist pending offer: {}", e);
+                }
+                dispatcher
+                    .send_to_user(
+                        recipient,
+                        GatewayEvent::FileOffer {
+                            from_user_id: user_id,
+                            transfer_id: transfer_id.clone(),
+                            filename: filename.clone(),
+                            size,
+                            file_sha256: file_sha256.clone(),
+                            chunk_hashes: chunk_hashes.clone(),
+                            file_server_url: file_server_url.map(|s| s.to_string()),
+                            folder_id: None,
+                        },
+                    )
+                    .await;
+            }
+            dispatcher
+                .send_to_user(
+                    user_id,
+                    GatewayEvent::FileOfferBroadcastSent {
+                        channel_id,
+                        transfer_id,
+                        recipient_ids,
+                    },
+                )
+                .await;
+        }
+
         GatewayCommand::FileAcceptSend {
             target_user_id,
             transfer_id,
@@ -433,8 +601,11 @@ async fn handle_command(
                 "{} ({}) -> file accept to {}",
                 username, user_id, target_user_id
             );
+            if !dispatcher.transfer_agreements().accept(&transfer_id, user_id, target_user_id) {
+                warn!("{} ({}) accepted transfer {} that {} never offered them", username, user_id, transfer_id, target_user_id);
+            }
             if let Some(db) = &db {
-                let _ = db.update_pending_offer_status(&transfer_id, &OfferStatus::Accepted.to_string());
+                let _ = db.update_pending_offer_status_for(&transfer_id, &user_id.to_string(), &OfferStatus::Accepted.to_string());
             }
             dispatcher
                 .send_to_user(
@@ -455,8 +626,9 @@ async fn handle_command(
                 "{} ({}) -> file reject to {}",
                 username, user_id, target_user_id
             );
+            dispatcher.transfer_agreements().reject(&transfer_id, user_id, target_user_id);
             if let Some(db) = &db {
-                let _ = db.update_pending_offer_status(&transfer_id, &OfferStatus::Rejected.to_string());
+                let _ = db.update_pending_offer_status_for(&transfer_id, &user_id.to_string(), &OfferStatus::Rejected.to_string());
             }
             dispatcher
                 .send_to_user(
@@ -506,6 +678,9 @@ async fn handle_command(
                 "{} ({}) -> file chunk {} to {}",
                 username, user_id, chunk_index, target_user_id
             );
+            if !relay_allowed(dispatcher, "FileChunkSend", Uuid::parse_str(&transfer_id).ok(), user_id, target_user_id) {
+                return;
+            }
             dispatcher
                 .send_to_user(
                     target_user_id,
@@ -527,6 +702,9 @@ async fn handle_command(
                 "{} ({}) -> file done to {}",
                 username, user_id, target_user_id
             );
+            if !relay_allowed(dispatcher, "FileDoneSend", Uuid::parse_str(&transfer_id).ok(), user_id, target_user_id) {
+                return;
+            }
             dispatcher
                 .send_to_user(
                     target_user_id,
@@ -547,6 +725,9 @@ async fn handle_command(
                 "{} ({}) -> file ack {} to {}",
                 username, user_id, ack_chunk_index, target_user_id
             );
+            if !relay_allowed(dispatcher, "FileAckSend", Uuid::parse_str(&transfer_id).ok(), user_id, target_user_id) {
+                return;
+            }
             dispatcher
                 .send_to_user(
                     target_user_id,
@@ -738,15 +919,19 @@ async fn handle_command(
 ///   0x01 FileChunkSend:  [type(1)] [target_uid(16)] [transfer_id(16)] [chunk_idx(4)] [payload...]
 ///   0x02 FileAckSend:    [type(1)] [target_uid(16)] [transfer_id(16)] [ack_chunk_idx(4)]
 ///   0x03 FileDoneSend:   [type(1)] [target_uid(16)] [transfer_id(16)]
-///   0x04 VoiceAudio:     [type(1)] [encrypted_payload...]
+///   0x04 VoiceAudio:     [type(1)] [seq(4)] [timestamp_ms(4)] [encrypted_payload...]
 ///   0x05 ScreenAudio:    [type(1)] [encrypted_payload...]
 ///
 /// For 0x01-0x03: The server swaps `target_user_id` for `sender_user_id`
 /// and forwards the frame to the target — zero-copy relay for the encrypted payload.
+/// Only frames of a transfer the recipient accepted are forwarded (see
+/// [`relay_allowed`]).
 ///
 /// For 0x04-0x05: The server prepends the sender's UUID and relays to all other
 /// voice channel participants as binary frames. 0x05 is screen share system audio
 /// (48kHz stereo) routed to a separate playback pipeline on receivers.
+/// The 0x04 [`VoiceFrameHeader`] sits outside the encryption; the server only
+/// reads it for `VoiceStats` loss and jitter counters and relays it untouched.
 async fn handle_binary_message(
     dispatcher: &Dispatcher,
     sender_user_id: Uuid,
@@ -771,6 +956,10 @@ async fn handle_binary_message(
                 return;
             }
             let target_user_id = Uuid::from_bytes(data[1..17].try_into().unwrap());
+            let transfer_id = Uuid::from_bytes(data[17..33].try_into().unwrap());
+            if !relay_allowed(dispatcher, label, Some(transfer_id), sender_user_id, target_user_id) {
+                return;
+            }
 
             if msg_type == 0x01 {
                 let chunk_index = u32::from_be_bytes(data[33..37].try_into().unwrap());
@@ -788,11 +977,19 @@ async fn handle_binary_message(
 
         // 0x04/0x05: Voice/ScreenAudio binary relay to all voice participants.
         0x04 | 0x05 => {
-            if data.len() < 2 {
-                return;
-            }
+            let header = if msg_type == 0x04 {
+                match VoiceFrameHeader::parse(&data[1..]) {
+                    Some(header) if data.len() > 1 + VoiceFrameHeader::LEN => Some(header),
+                    _ => return,
+                }
+            } else {
+                if data.len() < 2 {
+                    return;
+                }
+                None
+            };
             let outgoing = relay_binary_frame(msg_type, sender_user_id, &data[1..]);
-            dispatcher.relay_voice_data_binary(sender_user_id, outgoing).await;
+            dispatcher.relay_voice_data_binary(sender_user_id, outgoing, header).await;
         }
 
         _ => {
@@ -801,6 +998,27 @@ async fn handle_binary_message(
     }
 }
 
+/// Whether a file relay frame (`label` names the command) of `transfer_id`
+/// may go from `from` to `to`. Chunks and done travel offerer -> accepted
+/// recipient, acks the other way. Refusals are logged.
+fn relay_allowed(dispatcher: &Dispatcher, label: &str, transfer_id: Option<Uuid>, from: Uuid, to: Uuid) -> bool {
+    let agreements = dispatcher.transfer_agreements();
+    let allowed = transfer_id.is_some_and(|id| match label {
+        "FileAckSend" => agreements.allows(id, to, from),
+        _ => agreements.allows(id, from, to),
+    });
+    if !allowed {
+        warn!(
+            "Dropping {} from {} to {}: no accepted transfer {}",
+            label,
+            from,
+            to,
+            transfer_id.map_or_else(|| "(invalid id)".to_string(), |id| id.to_string())
+        );
+    }
+    allowed
+}
+
 /// Build an outgoing binary frame: [msg_type][sender_uid(16)][tail_data].
 /// Used by all binary relay handlers to swap target_uid for sender_uid.
 fn relay_binary_frame(msg_type: u8, sender_user_id: Uuid, tail_data: &[u8]) -> Bytes {
@@ -812,6 +1030,15 @@ fn relay_binary_frame(msg_type: u8, sender_user_id: Uuid, tail_data: &[u8]) -> B
 }
 
 /// Replay pending file/folder offers to a reconnecting client.
+/// Drop an offer from a sender with too many unanswered ones on record,
+/// telling them the way other dropped file commands are.
+async fn refuse_offer(dispatcher: &Dispatcher, user_id: Uuid, username: &str, transfer_id: &str) {
+    warn!("{} ({}) has too many unanswered file offers; dropped {}", username, user_id, transfer_id);
+    dispatcher
+        .send_to_user(user_id, GatewayEvent::RateLimited { category: "file".into() })
+        .await;
+}
+
 async fn replay_pending_offers(
     sender: &mut futures_util::stream::SplitSink<WebSocket, Message>,
     db: &haven_db::Database,
@@ -880,3 +1107,370 @@ async fn replay_pending_offers(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use axum::extract::{Query, State, WebSocketUpgrade};
+    use axum::response::IntoResponse;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    use haven_types::events::PresenceStatus;
+
+    use crate::rate_limit::{Limit, RateLimitConfig};
+
+    type Client = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+    async fn ws_handler(
+        ws: WebSocketUpgrade,
+        State(dispatcher): State<Dispatcher>,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> impl IntoResponse {
+        let session = Session {
+            user_id: query["user"].parse().unwrap(),
+            username: "tester".into(),
+            expires_at: chrono::DateTime::<chrono::Utc>::MAX_UTC,
+            scope: Default::default(),
+            turn_session: None,
+        };
+        let protocol_version = query.get("protocol_version").map(|v| v.parse().unwrap());
+        ws.on_upgrade(move |socket| handle_connection_authenticated(socket, dispatcher, session, protocol_version, None, None, None))
+    }
+
+    /// Serve `ws_handler` on a loopback port and return the port.
+    async fn spawn_gateway(dispatcher: Dispatcher) -> u16 {
+        let app = axum::Router::new()
+            .route("/ws", axum::routing::get(ws_handler))
+            .with_state(dispatcher);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        port
+    }
+
+    async fn connect(port: u16, user_id: Uuid) -> Client {
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{port}/ws?user={user_id}"))
+            .await
+            .unwrap();
+        ws
+    }
+
+    async fn send(ws: &mut Client, cmd: &GatewayCommand) {
+        ws.send(WsMessage::Text(serde_json::to_string(cmd).unwrap().into())).await.unwrap();
+    }
+
+    /// Read events until the connection has been quiet for 300ms.
+    async fn drain(ws: &mut Client) -> Vec<serde_json::Value> {
+        let mut events = Vec::new();
+        while let Ok(Some(Ok(msg))) = tokio::time::timeout(Duration::from_millis(300), ws.next()).await {
+            if let WsMessage::Text(text) = msg {
+                events.push(serde_json::from_str(&text).unwrap());
+            }
+        }
+        events
+    }
+
+    fn typing_from(events: &[serde_json::Value], user_id: Uuid) -> usize {
+        events
+            .iter()
+            .filter(|e| e["type"] == "TypingStart" && e["data"]["user_id"] == user_id.to_string())
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_typing_flood_is_rate_limited_per_user() {
+        let dispatcher = Dispatcher::with_rate_limits(RateLimitConfig {
+            control: Limit::new(5.0, 5.0),
+            ..RateLimitConfig::default()
+        });
+        let port = spawn_gateway(dispatcher).await;
+
+        let (flooder, polite) = (Uuid::new_v4(), Uuid::new_v4());
+        let channel_id = Uuid::new_v4();
+        let mut flooder_ws = connect(port, flooder).await;
+        let mut polite_ws = connect(port, polite).await;
+        for ws in [&mut flooder_ws, &mut polite_ws] {
+            send(ws, &GatewayCommand::Subscribe { channel_ids: vec![channel_id] }).await;
+        }
+        drain(&mut flooder_ws).await;
+        drain(&mut polite_ws).await;
+
+        for _ in 0..50 {
+            send(&mut flooder_ws, &GatewayCommand::StartTyping { channel_id }).await;
+        }
+        send(&mut polite_ws, &GatewayCommand::StartTyping { channel_id }).await;
+
+        let flooder_events = drain(&mut flooder_ws).await;
+        let polite_events = drain(&mut polite_ws).await;
+
+        // Subscribe took one of the 5 tokens; allow a little refill.
+        let relayed = typing_from(&polite_events, flooder);
+        assert!((1..=6).contains(&relayed), "relayed {relayed} of 50 typing events");
+        let notices: Vec<_> = flooder_events.iter().filter(|e| e["type"] == "RateLimited").collect();
+        assert_eq!(notices.len(), 1, "one notice per burst");
+        assert_eq!(notices[0]["data"]["category"], "control");
+
+        // The well-behaved peer is unaffected.
+        assert_eq!(typing_from(&flooder_events, polite), 1);
+        assert!(!polite_events.iter().any(|e| e["type"] == "RateLimited"));
+    }
+
+    #[tokio::test]
+    async fn test_compatible_versions_get_ready_with_the_range() {
+        let port = spawn_gateway(Dispatcher::new()).await;
+
+        // A client from before the handshake is served the oldest version.
+        let legacy = format!("ws://127.0.0.1:{port}/ws?user={}", Uuid::new_v4());
+        let current = format!("{legacy}&protocol_version={GATEWAY_PROTOCOL_VERSION}");
+        for (url, expected) in [(legacy, MIN_GATEWAY_PROTOCOL_VERSION), (current, GATEWAY_PROTOCOL_VERSION)] {
+            let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+            let ready = drain(&mut ws).await.into_iter().find(|e| e["type"] == "Ready").unwrap();
+            let ready: GatewayEvent = serde_json::from_value(ready).unwrap();
+            let GatewayEvent::Ready { min_protocol_version, max_protocol_version, protocol_version, .. } = ready else {
+                unreachable!()
+            };
+            assert_eq!(
+                (min_protocol_version, max_protocol_version, protocol_version),
+                (MIN_GATEWAY_PROTOCOL_VERSION, GATEWAY_PROTOCOL_VERSION, expected)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_version_is_closed_with_a_reason() {
+        let dispatcher = Dispatcher::new();
+        let port = spawn_gateway(dispatcher.clone()).await;
+
+        for version in [MIN_GATEWAY_PROTOCOL_VERSION - 1, GATEWAY_PROTOCOL_VERSION + 1] {
+            let url = format!("ws://127.0.0.1:{port}/ws?user={}&protocol_version={version}", Uuid::new_v4());
+            let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+            let Some(Ok(WsMessage::Close(Some(frame)))) = ws.next().await else {
+                panic!("expected a close frame before anything else");
+            };
+            assert_eq!(u16::from(frame.code), crate::version::CLOSE_UNSUPPORTED_PROTOCOL);
+            assert!(frame.reason.contains(&format!("protocol version {version}")), "{}", frame.reason);
+        }
+        // Never registered, so never online.
+        assert!(dispatcher.list_connections().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ping_echoes_to_the_asking_connection() {
+        let port = spawn_gateway(Dispatcher::new()).await;
+
+        let user = Uuid::new_v4();
+        let mut asking = connect(port, user).await;
+        let mut other_device = connect(port, user).await;
+        drain(&mut asking).await;
+        drain(&mut other_device).await;
+
+        let before = chrono::Utc::now().timestamp_millis();
+        let nonce = (u64::MAX - 7).to_string();
+        send(&mut asking, &GatewayCommand::Ping { nonce: nonce.clone(), client_ts: -12345 }).await;
+        let events = drain(&mut asking).await;
+        let after = chrono::Utc::now().timestamp_millis();
+
+        let pongs: Vec<_> = events.iter().filter(|e| e["type"] == "Pong").collect();
+        assert_eq!(pongs.len(), 1, "{events:?}");
+        assert_eq!(pongs[0]["data"]["nonce"], nonce, "nonce goes over the wire as a string");
+        let pong: GatewayEvent = serde_json::from_value(pongs[0].clone()).unwrap();
+        let GatewayEvent::Pong { nonce: echoed, client_ts, server_ts } = pong else { unreachable!() };
+        assert_eq!((echoed, client_ts), (nonce, -12345));
+        assert!((before..=after).contains(&server_ts));
+        assert!(!drain(&mut other_device).await.iter().any(|e| e["type"] == "Pong"));
+    }
+
+    #[tokio::test]
+    async fn test_new_connection_sees_current_statuses() {
+        let port = spawn_gateway(Dispatcher::new()).await;
+
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut alice_ws = connect(port, alice).await;
+        send(
+            &mut alice_ws,
+            &GatewayCommand::SetPresence {
+                status: PresenceStatus::Busy,
+                custom: Some("focusing".into()),
+                idle_timeout_secs: None,
+            },
+        )
+        .await;
+        drain(&mut alice_ws).await;
+
+        let mut bob_ws = connect(port, bob).await;
+        let presence_of = |events: &[serde_json::Value], user_id: Uuid| -> Vec<GatewayEvent> {
+            events
+                .iter()
+                .filter(|e| e["type"] == "PresenceUpdate" && e["data"]["user_id"] == user_id.to_string())
+                .map(|e| serde_json::from_value(e.clone()).unwrap())
+                .collect()
+        };
+        let seen = presence_of(&drain(&mut bob_ws).await, alice);
+        assert_eq!(seen.len(), 1, "{seen:?}");
+        let GatewayEvent::PresenceUpdate { online, status, custom, .. } = &seen[0] else { unreachable!() };
+        assert_eq!((*online, *status, custom.as_deref()), (true, PresenceStatus::Busy, Some("focusing")));
+
+        // Alice hears about Bob, as he is: online with no custom status.
+        let seen = presence_of(&drain(&mut alice_ws).await, bob);
+        let GatewayEvent::PresenceUpdate { online, status, custom, .. } = &seen[0] else { unreachable!() };
+        assert_eq!((*online, *status, custom.as_deref()), (true, PresenceStatus::Online, None));
+    }
+
+    #[tokio::test]
+    async fn test_voice_frames_relay_header_and_count_gaps() {
+        let dispatcher = Dispatcher::new();
+        let channel = Uuid::new_v4();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let (_bob_conn, mut bob_rx) = dispatcher.register_user_channel(bob).await;
+        dispatcher.voice_join(channel, alice, "alice".into(), "a".into()).await;
+        dispatcher.voice_join(channel, bob, "bob".into(), "b".into()).await;
+
+        // 10..=12 are missing when 13 arrives; 11 then turns up late.
+        let frame = |seq: u32| {
+            let mut frame = vec![0x04];
+            frame.extend_from_slice(&VoiceFrameHeader { seq, timestamp_ms: seq * 20 }.encode());
+            frame.extend_from_slice(b"encrypted-opus");
+            frame
+        };
+        for seq in [7, 8, 9, 13, 11] {
+            handle_binary_message(&dispatcher, alice, &frame(seq)).await;
+        }
+        // A frame with no room for a header is refused outright.
+        handle_binary_message(&dispatcher, alice, &frame(14)[..1 + VoiceFrameHeader::LEN]).await;
+
+        let mut relayed = Vec::new();
+        while let Ok(UserMessage::Binary(data)) = bob_rx.try_recv() {
+            relayed.push(data);
+        }
+        assert_eq!(relayed.len(), 5);
+        // Sender UUID goes in after the type byte; header and payload are untouched.
+        assert_eq!(&relayed[3][..17], [&[0x04][..], alice.as_bytes()].concat());
+        assert_eq!(&relayed[3][17..], &frame(13)[1..]);
+
+        let (_, peers) = dispatcher.voice_stats(bob).await.unwrap();
+        let alice_stats = peers.iter().find(|p| p.user_id == alice).unwrap();
+        assert_eq!(alice_stats.frames_relayed, 5);
+        assert_eq!(alice_stats.frames_lost, 3);
+        assert_eq!(alice_stats.frames_late, 1);
+    }
+
+    #[tokio::test]
+    async fn test_third_party_cannot_inject_relay_chunks() {
+        let port = spawn_gateway(Dispatcher::new()).await;
+
+        let (sender, recipient, intruder) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut sender_ws = connect(port, sender).await;
+        let mut recipient_ws = connect(port, recipient).await;
+        let mut intruder_ws = connect(port, intruder).await;
+
+        let transfer = Uuid::new_v4();
+        let chunk = |target: Uuid, payload: &[u8]| {
+            let mut frame = vec![0x01];
+            frame.extend_from_slice(target.as_bytes());
+            frame.extend_from_slice(transfer.as_bytes());
+            frame.extend_from_slice(&0u32.to_be_bytes());
+            frame.extend_from_slice(payload);
+            WsMessage::Binary(frame.into())
+        };
+        // Binary frames that reach `ws` within 300ms.
+        async fn binary_frames(ws: &mut Client) -> Vec<Vec<u8>> {
+            let mut frames = Vec::new();
+            while let Ok(Some(Ok(msg))) = tokio::time::timeout(Duration::from_millis(300), ws.next()).await {
+                if let WsMessage::Binary(data) = msg {
+                    frames.push(data.to_vec());
+                }
+            }
+            frames
+        }
+
+        // Nothing is relayed before the recipient accepts.
+        send(&mut sender_ws, &GatewayCommand::FileOfferSend {
+            target_user_id: recipient,
+            transfer_id: transfer.to_string(),
+            filename: "a.bin".into(),
+            size: 5,
+            file_sha256: None,
+            chunk_hashes: None,
+            folder_id: None,
+        })
+        .await;
+        sender_ws.send(chunk(recipient, b"early")).await.unwrap();
+        assert!(binary_frames(&mut recipient_ws).await.is_empty());
+
+        send(&mut recipient_ws, &GatewayCommand::FileAcceptSend { target_user_id: sender, transfer_id: transfer.to_string() }).await;
+        drain(&mut sender_ws).await;
+
+        intruder_ws.send(chunk(recipient, b"evil!")).await.unwrap();
+        sender_ws.send(chunk(recipient, b"hello")).await.unwrap();
+        let frames = binary_frames(&mut recipient_ws).await;
+        assert_eq!(frames.len(), 1, "only the offerer's chunk is relayed");
+        assert_eq!(&frames[0][1..17], sender.as_bytes());
+        assert!(frames[0].ends_with(b"hello"));
+    }
+
+    #[tokio::test]
+    async fn test_file_offer_broadcast_reaches_channel_subscribers() {
+        let port = spawn_gateway(Dispatcher::new()).await;
+
+        let channel_id = Uuid::new_v4();
+        let users = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        let mut clients = Vec::new();
+        for (i, &user) in users.iter().enumerate() {
+            let mut ws = connect(port, user).await;
+            // The last user is in another channel and must not get the offer.
+            let channel_ids = if i == 3 { vec![Uuid::new_v4()] } else { vec![channel_id] };
+            send(&mut ws, &GatewayCommand::Subscribe { channel_ids }).await;
+            clients.push(ws);
+        }
+        for ws in &mut clients {
+            drain(ws).await;
+        }
+
+        let offer = GatewayCommand::FileOfferBroadcast {
+            channel_id,
+            transfer_id: "shared".into(),
+            filename: "notes.pdf".into(),
+            size: 1234,
+            file_sha256: None,
+            chunk_hashes: None,
+        };
+        send(&mut clients[0], &offer).await;
+
+        let offers_in = |events: &[serde_json::Value]| {
+            events
+                .iter()
+                .filter(|e| e["type"] == "FileOffer" && e["data"]["transfer_id"] == "shared")
+                .count()
+        };
+        let sender_events = drain(&mut clients[0]).await;
+        let sent = sender_events.iter().find(|e| e["type"] == "FileOfferBroadcastSent").unwrap();
+        let mut recipients: Vec<String> = serde_json::from_value(sent["data"]["recipient_ids"].clone()).unwrap();
+        recipients.sort();
+        let mut expected = vec![users[1].to_string(), users[2].to_string()];
+        expected.sort();
+        assert_eq!(recipients, expected);
+        assert_eq!(offers_in(&sender_events), 0);
+
+        for ws in &mut clients[1..3] {
+            let events = drain(ws).await;
+            assert_eq!(offers_in(&events), 1);
+            let offer = events.iter().find(|e| e["type"] == "FileOffer").unwrap();
+            assert_eq!(offer["data"]["from_user_id"], users[0].to_string());
+        }
+        assert_eq!(offers_in(&drain(&mut clients[3]).await), 0);
+
+        // Offering to a channel you are not in goes nowhere.
+        let stranger = GatewayCommand::FileOfferBroadcast {
+            channel_id,
+            transfer_id: "sneaky".into(),
+            filename: "x".into(),
+            size: 1,
+            file_sha256: None,
+            chunk_hashes: None,
+        };
+        send(&mut clients[3], &stranger).await;
+        assert!(drain(&mut clients[1]).await.iter().all(|e| e["type"] != "FileOffer"));
+    }
+}
@@ -1,12 +1,21 @@
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use tokio::sync::{RwLock, broadcast, mpsc};
+use tokio::task::JoinHandle;
 use tracing::warn;
 use uuid::Uuid;
 
-use haven_types::events::GatewayEvent;
+use haven_types::events::{GatewayEvent, PresenceStatus, VoicePeerStats};
+
+use crate::metrics::Metrics;
+use crate::rate_limit::{CommandCategory, RateLimitConfig, RateLimiter, RateVerdict};
+use crate::reconnect::ReconnectTokens;
+use crate::relay_auth::TransferAgreements;
 
 /// Pre-serialized broadcast message. The JSON is serialized once in `broadcast()`
 /// so N connections don't each pay the serialization cost. The `channel_id` is
@@ -26,6 +35,155 @@ pub struct VoiceParticipant {
     pub session_id: String,
     pub self_mute: bool,
     pub self_deaf: bool,
+    /// Their last connection dropped; they keep their place for
+    /// `VOICE_RECONNECT_GRACE` in case it comes back.
+    pub reconnecting: bool,
+    /// Relay counters, shared so the relay path can update them under the
+    /// voice state read lock.
+    pub stats: Arc<VoiceStats>,
+}
+
+/// Sequence gaps larger than this are treated as the sender restarting its
+/// counter rather than as loss (about a minute of 20ms frames).
+const MAX_SEQ_GAP: u32 = 3000;
+
+/// Marks `VoiceStats::last_seq` as not yet seen.
+const SEQ_UNSET: u64 = u64::MAX;
+
+/// Unencrypted prefix of a binary 0x04 voice frame, ahead of the encrypted
+/// Opus payload. The gateway reads it for loss and jitter stats and relays
+/// it untouched, so receivers can reorder and pace playback with it too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoiceFrameHeader {
+    /// Increments by one per frame.
+    pub seq: u32,
+    /// Sender's capture clock in milliseconds; only differences matter.
+    pub timestamp_ms: u32,
+}
+
+impl VoiceFrameHeader {
+    /// Encoded size: `[seq(4)] [timestamp_ms(4)]`, big-endian.
+    pub const LEN: usize = 8;
+
+    /// Read the header from the start of `data`. `None` if it's too short.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let header = data.get(..Self::LEN)?;
+        Some(Self {
+            seq: u32::from_be_bytes(header[0..4].try_into().unwrap()),
+            timestamp_ms: u32::from_be_bytes(header[4..8].try_into().unwrap()),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut out = [0u8; Self::LEN];
+        out[0..4].copy_from_slice(&self.seq.to_be_bytes());
+        out[4..8].copy_from_slice(&self.timestamp_ms.to_be_bytes());
+        out
+    }
+}
+
+/// Per-participant voice relay counters. Updated with atomics only so the
+/// relay path stays lock- and allocation-free.
+#[derive(Debug)]
+pub struct VoiceStats {
+    frames_relayed: AtomicU64,
+    bytes_relayed: AtomicU64,
+    frames_lost: AtomicU64,
+    frames_late: AtomicU64,
+    frames_dropped: AtomicU64,
+    /// Highest sequence number seen from this participant, or `SEQ_UNSET`.
+    last_seq: AtomicU64,
+    /// Arrival time minus sender timestamp of the last in-order frame, in ms
+    /// (wrapping), or `SEQ_UNSET`.
+    last_transit: AtomicU64,
+    /// RFC 3550 interarrival jitter estimate in 1/16 ms.
+    jitter_x16: AtomicU64,
+    /// Origin of the arrival clock `last_transit` is measured on.
+    epoch: Instant,
+}
+
+impl Default for VoiceStats {
+    fn default() -> Self {
+        Self {
+            frames_relayed: AtomicU64::new(0),
+            bytes_relayed: AtomicU64::new(0),
+            frames_lost: AtomicU64::new(0),
+            frames_late: AtomicU64::new(0),
+            frames_dropped: AtomicU64::new(0),
+            last_seq: AtomicU64::new(SEQ_UNSET),
+            last_transit: AtomicU64::new(SEQ_UNSET),
+            jitter_x16: AtomicU64::new(0),
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl VoiceStats {
+    /// Count a frame received from this participant. `header` is set when
+    /// the transport carries one (binary 0x04 frames).
+    fn record_received(&self, bytes: usize, header: Option<VoiceFrameHeader>) {
+        let arrival_ms = self.epoch.elapsed().as_millis() as u32;
+        self.record_received_at(bytes, header, arrival_ms);
+    }
+
+    fn record_received_at(&self, bytes: usize, header: Option<VoiceFrameHeader>, arrival_ms: u32) {
+        self.frames_relayed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_relayed.fetch_add(bytes as u64, Ordering::Relaxed);
+        let Some(VoiceFrameHeader { seq, timestamp_ms }) = header else { return };
+
+        // Only ever advance last_seq; a frame behind it arrived late.
+        let advanced = self.last_seq.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |prev| {
+            if prev == SEQ_UNSET {
+                return Some(seq as u64);
+            }
+            let ahead = seq.wrapping_sub(prev as u32);
+            (ahead != 0 && ahead < u32::MAX / 2).then_some(seq as u64)
+        });
+        match advanced {
+            Ok(prev) => {
+                if prev != SEQ_UNSET {
+                    let gap = seq.wrapping_sub(prev as u32) - 1;
+                    if gap <= MAX_SEQ_GAP {
+                        self.frames_lost.fetch_add(gap as u64, Ordering::Relaxed);
+                    }
+                }
+                self.record_transit(arrival_ms.wrapping_sub(timestamp_ms));
+            }
+            Err(_) => {
+                self.frames_late.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Fold one in-order frame's transit time into the jitter estimate:
+    /// J += (|D| - J) / 16, D being the change in transit since the last one.
+    fn record_transit(&self, transit: u32) {
+        let prev = self.last_transit.swap(transit as u64, Ordering::Relaxed);
+        if prev == SEQ_UNSET {
+            return;
+        }
+        let delta = (transit.wrapping_sub(prev as u32) as i32).unsigned_abs() as u64;
+        let _ = self.jitter_x16.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |j| {
+            Some(j + delta - ((j + 8) >> 4))
+        });
+    }
+
+    /// A frame addressed to this participant was dropped (queue full).
+    fn record_dropped(&self) {
+        self.frames_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, user_id: Uuid) -> VoicePeerStats {
+        VoicePeerStats {
+            user_id,
+            frames_relayed: self.frames_relayed.load(Ordering::Relaxed),
+            bytes_relayed: self.bytes_relayed.load(Ordering::Relaxed),
+            frames_lost: self.frames_lost.load(Ordering::Relaxed),
+            frames_late: self.frames_late.load(Ordering::Relaxed),
+            frames_dropped: self.frames_dropped.load(Ordering::Relaxed),
+            jitter_ms: (self.jitter_x16.load(Ordering::Relaxed) >> 4) as u32,
+        }
+    }
 }
 
 /// Messages that can be sent to a specific user's connection.
@@ -38,10 +196,99 @@ pub enum UserMessage {
     Binary(Bytes),
 }
 
+/// One connection's targeted send channel. Dropping `tx` ends the
+/// connection's send loop, which closes the socket.
+struct UserConn {
+    conn_id: Uuid,
+    connected_at: DateTime<Utc>,
+    tx: mpsc::Sender<UserMessage>,
+}
+
 /// Per-user targeted channel buffer depth. If a client can't keep up with 512
 /// queued events, it is too slow and messages will be dropped with a warning.
 const USER_CHANNEL_CAPACITY: usize = 2048;
 
+/// How long a `TypingStart` stands before the server sends `TypingStop`
+/// for it. Clients re-send `StartTyping` every few seconds while typing.
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// Pending auto-stop for one user typing in one channel.
+struct TypingTimer {
+    /// Tells a timer that fired from one that was replaced meanwhile.
+    id: Uuid,
+    task: JoinHandle<()>,
+}
+
+/// Longest custom status text kept, in characters; longer text is cut.
+pub const MAX_CUSTOM_STATUS_CHARS: usize = 128;
+
+/// An online user's presence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserPresence {
+    pub username: String,
+    /// What they last set with `SetPresence`.
+    pub status: PresenceStatus,
+    pub custom: Option<String>,
+    /// Past their idle timeout with no command since.
+    pub idle: bool,
+}
+
+impl UserPresence {
+    fn new(username: String) -> Self {
+        Self { username, status: PresenceStatus::Online, custom: None, idle: false }
+    }
+
+    /// The status others see: idle turns `Online` into `Away`; a status the
+    /// user picked themselves stands.
+    pub fn effective_status(&self) -> PresenceStatus {
+        match self.status {
+            PresenceStatus::Online if self.idle => PresenceStatus::Away,
+            status => status,
+        }
+    }
+
+    pub fn event(&self, user_id: Uuid) -> GatewayEvent {
+        let status = self.effective_status();
+        GatewayEvent::PresenceUpdate {
+            user_id,
+            username: self.username.clone(),
+            online: status != PresenceStatus::Offline,
+            status,
+            custom: self.custom.clone(),
+        }
+    }
+}
+
+/// Auto-away timer for a user who reported an idle timeout.
+struct IdleWatch {
+    /// Tells a timer that fired from one that was replaced meanwhile.
+    id: Uuid,
+    timeout: Duration,
+    last_activity: tokio::time::Instant,
+    /// `None` once the timer has fired; the next activity re-arms it.
+    task: Option<JoinHandle<()>>,
+}
+
+impl IdleWatch {
+    fn abort(&self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+/// How long a voice participant whose last connection dropped stays in the
+/// channel before the server broadcasts their leave. Coming back within it
+/// cancels the leave, so a network blip doesn't flicker the participant list.
+pub const VOICE_RECONNECT_GRACE: Duration = Duration::from_secs(5);
+
+/// Deferred voice leave for a user whose connections all dropped.
+struct PendingLeave {
+    /// Tells a timer that fired from one that was replaced meanwhile.
+    id: Uuid,
+    task: JoinHandle<()>,
+}
+
 /// Manages all connected clients and broadcasts events.
 #[derive(Clone)]
 pub struct Dispatcher {
@@ -54,13 +301,16 @@ struct DispatcherInner {
     /// the connection level, not here.
     broadcast_tx: broadcast::Sender<BroadcastMessage>,
 
-    /// Track online users: user_id -> username
-    online_users: RwLock<HashMap<Uuid, String>>,
+    /// Track online users: user_id -> presence
+    online_users: RwLock<HashMap<Uuid, UserPresence>>,
+
+    /// Auto-away timers: user_id -> watch.
+    idle_watches: std::sync::Mutex<HashMap<Uuid, IdleWatch>>,
 
-    /// Per-user targeted send channels: user_id -> [(conn_id, sender)]
+    /// Per-user targeted send channels: user_id -> [connection]
     /// Multiple connections per user are supported (multi-device).
     /// Bounded to USER_CHANNEL_CAPACITY; overflows are dropped with a warning.
-    user_channels: RwLock<HashMap<Uuid, Vec<(Uuid, mpsc::Sender<UserMessage>)>>>,
+    user_channels: RwLock<HashMap<Uuid, Vec<UserConn>>>,
 
     /// Voice state: channel_id -> (user_id -> participant)
     voice_states: RwLock<HashMap<Uuid, HashMap<Uuid, VoiceParticipant>>>,
@@ -68,22 +318,75 @@ struct DispatcherInner {
     /// Per-user channel subscriptions: user_id -> set of channel_ids.
     /// Only events for subscribed channels are forwarded to each client.
     channel_subscriptions: RwLock<HashMap<Uuid, HashSet<Uuid>>>,
+
+    /// Lock-free counters for the `/metrics` endpoint.
+    metrics: Metrics,
+
+    /// Per-user command rate limits.
+    rate_limiter: RateLimiter,
+
+    /// Reconnect tokens issued to live connections.
+    reconnect_tokens: ReconnectTokens,
+
+    /// Accepted file offers, which gate the binary file relay.
+    transfer_agreements: TransferAgreements,
+
+    /// Who is typing where: (user_id, channel_id) -> auto-stop timer.
+    typing: std::sync::Mutex<HashMap<(Uuid, Uuid), TypingTimer>>,
+
+    /// See [`TYPING_TIMEOUT`].
+    typing_timeout: Duration,
+
+    /// Voice participants awaiting reconnect: user_id -> deferred leave.
+    pending_leaves: std::sync::Mutex<HashMap<Uuid, PendingLeave>>,
+
+    /// See [`VOICE_RECONNECT_GRACE`].
+    voice_grace: Duration,
 }
 
 impl Dispatcher {
     pub fn new() -> Self {
+        Self::with_rate_limits(RateLimitConfig::default())
+    }
+
+    pub fn with_rate_limits(rate_limits: RateLimitConfig) -> Self {
         let (broadcast_tx, _) = broadcast::channel(8192);
         Self {
             inner: Arc::new(DispatcherInner {
                 broadcast_tx,
                 online_users: RwLock::new(HashMap::new()),
+                idle_watches: std::sync::Mutex::new(HashMap::new()),
                 user_channels: RwLock::new(HashMap::new()),
                 voice_states: RwLock::new(HashMap::new()),
                 channel_subscriptions: RwLock::new(HashMap::new()),
+                metrics: Metrics::new(),
+                rate_limiter: RateLimiter::new(rate_limits),
+                reconnect_tokens: ReconnectTokens::default(),
+                transfer_agreements: TransferAgreements::default(),
+                typing: std::sync::Mutex::new(HashMap::new()),
+                typing_timeout: TYPING_TIMEOUT,
+                pending_leaves: std::sync::Mutex::new(HashMap::new()),
+                voice_grace: VOICE_RECONNECT_GRACE,
             }),
         }
     }
 
+    /// Override [`TYPING_TIMEOUT`]. Call before the dispatcher is cloned.
+    pub fn with_typing_timeout(mut self, timeout: Duration) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("typing timeout set after the dispatcher was shared")
+            .typing_timeout = timeout;
+        self
+    }
+
+    /// Override [`VOICE_RECONNECT_GRACE`]. Call before the dispatcher is cloned.
+    pub fn with_voice_reconnect_grace(mut self, grace: Duration) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("voice reconnect grace set after the dispatcher was shared")
+            .voice_grace = grace;
+        self
+    }
+
     /// Subscribe to gateway events. Returns a broadcast receiver of pre-serialized messages.
     pub fn subscribe(&self) -> broadcast::Receiver<BroadcastMessage> {
         self.inner.broadcast_tx.subscribe()
@@ -98,6 +401,53 @@ impl Dispatcher {
             .into();
         let msg = BroadcastMessage { channel_id, json };
         let _ = self.inner.broadcast_tx.send(msg);
+        self.inner.metrics.broadcast_sent();
+    }
+
+    /// Gateway counters, for recording events outside the dispatcher.
+    pub fn metrics(&self) -> &Metrics {
+        &self.inner.metrics
+    }
+
+    /// Store of reconnect tokens; the upgrade handler redeems them.
+    pub fn reconnect_tokens(&self) -> &ReconnectTokens {
+        &self.inner.reconnect_tokens
+    }
+
+    /// Offer/accept records; the file relay only forwards agreed transfers.
+    pub fn transfer_agreements(&self) -> &TransferAgreements {
+        &self.inner.transfer_agreements
+    }
+
+    /// Render metrics in Prometheus text format, including gauges read from
+    /// current dispatcher state.
+    pub async fn render_metrics(&self) -> String {
+        let online_users = self.inner.online_users.read().await.len();
+        let open_connections = self.inner.user_channels.read().await.values().map(Vec::len).sum();
+        self.inner.metrics.render(online_users, open_connections)
+    }
+
+    /// Charge one incoming command to the user's rate limit. Returns whether to
+    /// process it; on the first rejection of a burst the user is sent a
+    /// `RateLimited` event so the client can back off.
+    pub async fn admit_command(&self, user_id: Uuid, category: CommandCategory) -> bool {
+        match self.inner.rate_limiter.check(user_id, category) {
+            RateVerdict::Allowed => true,
+            RateVerdict::Limited { notify } => {
+                self.inner.metrics.command_rate_limited();
+                if notify {
+                    warn!("Rate limiting {} commands from user {}", category.as_str(), user_id);
+                    self.send_to_user(
+                        user_id,
+                        GatewayEvent::RateLimited {
+                            category: category.as_str().to_string(),
+                        },
+                    )
+                    .await;
+                }
+                false
+            }
+        }
     }
 
     /// Register a per-user targeted channel. Returns (conn_id, receiver).
@@ -109,7 +459,8 @@ impl Dispatcher {
         self.inner.user_channels.write().await
             .entry(user_id)
             .or_default()
-            .push((conn_id, tx));
+            .push(UserConn { conn_id, connected_at: Utc::now(), tx });
+        self.inner.metrics.connection_opened();
         (conn_id, rx)
     }
 
@@ -117,23 +468,61 @@ impl Dispatcher {
     pub async fn unregister_user_channel(&self, user_id: Uuid, conn_id: Uuid) {
         let mut channels = self.inner.user_channels.write().await;
         if let Some(conns) = channels.get_mut(&user_id) {
-            conns.retain(|(cid, _)| *cid != conn_id);
+            conns.retain(|c| c.conn_id != conn_id);
             if conns.is_empty() {
                 channels.remove(&user_id);
             }
         }
     }
 
+    /// Every open connection as `(user_id, conn_id, connected_at)`, for the
+    /// admin dashboard.
+    pub async fn list_connections(&self) -> Vec<(Uuid, Uuid, DateTime<Utc>)> {
+        let channels = self.inner.user_channels.read().await;
+        channels
+            .iter()
+            .flat_map(|(&user_id, conns)| conns.iter().map(move |c| (user_id, c.conn_id, c.connected_at)))
+            .collect()
+    }
+
+    /// Close one connection by dropping its send channel, leaving the user's
+    /// other devices connected. The connection loop then runs the usual
+    /// `user_offline` cleanup. Returns whether the connection was found.
+    pub async fn kick_connection(&self, conn_id: Uuid) -> bool {
+        let mut channels = self.inner.user_channels.write().await;
+        let Some((&user_id, conns)) = channels.iter_mut().find(|(_, conns)| conns.iter().any(|c| c.conn_id == conn_id))
+        else {
+            return false;
+        };
+        conns.retain(|c| c.conn_id != conn_id);
+        if conns.is_empty() {
+            channels.remove(&user_id);
+        }
+        true
+    }
+
+    /// The targeted-message sender for one connection, for callers that want
+    /// to wait for queue space instead of dropping (e.g. resume replay).
+    pub async fn connection_sender(&self, user_id: Uuid, conn_id: Uuid) -> Option<mpsc::Sender<UserMessage>> {
+        let channels = self.inner.user_channels.read().await;
+        channels
+            .get(&user_id)?
+            .iter()
+            .find(|c| c.conn_id == conn_id)
+            .map(|c| c.tx.clone())
+    }
+
     /// Send a targeted event to a specific user (all their devices).
     /// Uses `try_send` -- if a connection's buffer is full, the message is
     /// dropped with a warning (the client is too slow to keep up).
     pub async fn send_to_user(&self, user_id: Uuid, event: GatewayEvent) {
         let channels = self.inner.user_channels.read().await;
         if let Some(conns) = channels.get(&user_id) {
-            for (conn_id, tx) in conns {
+            for UserConn { conn_id, tx, .. } in conns {
                 match tx.try_send(UserMessage::Event(event.clone())) {
                     Ok(()) => {}
                     Err(mpsc::error::TrySendError::Full(_)) => {
+                        self.inner.metrics.message_dropped();
                         warn!(
                             "Dropping targeted event for user {} conn {}: channel full ({} capacity). Client too slow.",
                             user_id, conn_id, USER_CHANNEL_CAPACITY
@@ -154,10 +543,11 @@ impl Dispatcher {
     pub async fn send_binary_to_user(&self, user_id: Uuid, data: Bytes) {
         let channels = self.inner.user_channels.read().await;
         if let Some(conns) = channels.get(&user_id) {
-            for (conn_id, tx) in conns {
+            for UserConn { conn_id, tx, .. } in conns {
                 match tx.try_send(UserMessage::Binary(data.clone())) {
                     Ok(()) => {}
                     Err(mpsc::error::TrySendError::Full(_)) => {
+                        self.inner.metrics.message_dropped();
                         warn!(
                             "Dropping binary data for user {} conn {}: channel full ({} capacity)",
                             user_id, conn_id, USER_CHANNEL_CAPACITY
@@ -175,25 +565,225 @@ impl Dispatcher {
         subs.insert(user_id, channel_ids.into_iter().collect());
     }
 
+    /// Users currently subscribed to `channel_id`.
+    pub async fn channel_subscribers(&self, channel_id: Uuid) -> Vec<Uuid> {
+        let subs = self.inner.channel_subscriptions.read().await;
+        subs.iter()
+            .filter(|(_, channels)| channels.contains(&channel_id))
+            .map(|(user_id, _)| *user_id)
+            .collect()
+    }
+
     /// Remove all subscriptions for a user (called on disconnect).
     async fn clear_subscriptions(&self, user_id: Uuid) {
         let mut subs = self.inner.channel_subscriptions.write().await;
         subs.remove(&user_id);
     }
 
-    /// Register a user as online.
+    /// Broadcast `TypingStart` and (re)arm the timer that broadcasts
+    /// `TypingStop` if the user goes quiet.
+    pub fn start_typing(&self, user_id: Uuid, channel_id: Uuid, username: String) {
+        self.broadcast(GatewayEvent::TypingStart { channel_id, user_id, username });
+
+        let id = Uuid::new_v4();
+        let dispatcher = self.clone();
+        let timeout = self.inner.typing_timeout;
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            let mut typing = dispatcher.inner.typing.lock().unwrap();
+            if typing.get(&(user_id, channel_id)).is_some_and(|t| t.id == id) {
+                typing.remove(&(user_id, channel_id));
+                drop(typing);
+                dispatcher.broadcast(GatewayEvent::TypingStop { channel_id, user_id });
+            }
+        });
+        let previous = self.inner.typing.lock().unwrap().insert((user_id, channel_id), TypingTimer { id, task });
+        if let Some(previous) = previous {
+            previous.task.abort();
+        }
+    }
+
+    /// Broadcast `TypingStop` if the user is typing in `channel_id`.
+    pub fn stop_typing(&self, user_id: Uuid, channel_id: Uuid) {
+        let timer = self.inner.typing.lock().unwrap().remove(&(user_id, channel_id));
+        if let Some(timer) = timer {
+            timer.task.abort();
+            self.broadcast(GatewayEvent::TypingStop { channel_id, user_id });
+        }
+    }
+
+    /// Stop every typing indicator the user has up.
+    fn stop_all_typing(&self, user_id: Uuid) {
+        let stopped: Vec<_> = {
+            let mut typing = self.inner.typing.lock().unwrap();
+            let keys: Vec<_> = typing.keys().filter(|(u, _)| *u == user_id).copied().collect();
+            keys.into_iter().filter_map(|key| typing.remove(&key).map(|t| (key.1, t))).collect()
+        };
+        for (channel_id, timer) in stopped {
+            timer.task.abort();
+            self.broadcast(GatewayEvent::TypingStop { channel_id, user_id });
+        }
+    }
+
+    /// Register a user as online. A voice leave still pending from their
+    /// last disconnect is cancelled. Another device coming online keeps the
+    /// status they already have.
     pub async fn user_online(&self, user_id: Uuid, username: String) {
-        self.inner
+        self.cancel_voice_leave(user_id).await;
+        let event = self
+            .inner
             .online_users
             .write()
             .await
-            .insert(user_id, username.clone());
+            .entry(user_id)
+            .or_insert_with(|| UserPresence::new(username))
+            .event(user_id);
 
-        self.broadcast(GatewayEvent::PresenceUpdate {
-            user_id,
-            username,
-            online: true,
-        });
+        self.broadcast(event);
+    }
+
+    /// Set an online user's status and custom text, broadcasting the change.
+    /// `idle_timeout` (re)arms auto-away; zero turns it off, `None` leaves
+    /// it as it was.
+    pub async fn set_presence(
+        &self,
+        user_id: Uuid,
+        status: PresenceStatus,
+        custom: Option<String>,
+        idle_timeout: Option<Duration>,
+    ) {
+        let custom = custom
+            .map(|c| c.trim().chars().take(MAX_CUSTOM_STATUS_CHARS).collect::<String>())
+            .filter(|c| !c.is_empty());
+        let event = {
+            let mut online = self.inner.online_users.write().await;
+            let Some(presence) = online.get_mut(&user_id) else {
+                return;
+            };
+            let before = (presence.effective_status(), presence.custom.clone());
+            presence.status = status;
+            presence.custom = custom;
+            presence.idle = false;
+            let changed = (presence.effective_status(), presence.custom.clone()) != before;
+            changed.then(|| presence.event(user_id))
+        };
+        if let Some(event) = event {
+            self.broadcast(event);
+        }
+
+        match idle_timeout {
+            Some(timeout) if timeout.is_zero() => self.stop_idle_watch(user_id),
+            Some(timeout) => {
+                // Hold the lock so the timer can't look before the watch is in.
+                let mut watches = self.inner.idle_watches.lock().unwrap();
+                let id = Uuid::new_v4();
+                let watch = IdleWatch {
+                    id,
+                    timeout,
+                    last_activity: tokio::time::Instant::now(),
+                    task: Some(self.spawn_idle_timer(user_id, id)),
+                };
+                if let Some(previous) = watches.insert(user_id, watch) {
+                    previous.abort();
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Note a command from the user: restarts their idle timeout and brings
+    /// them back from auto-away.
+    pub async fn presence_activity(&self, user_id: Uuid) {
+        let rearm = {
+            let mut watches = self.inner.idle_watches.lock().unwrap();
+            let Some(watch) = watches.get_mut(&user_id) else {
+                return;
+            };
+            watch.last_activity = tokio::time::Instant::now();
+            if watch.task.is_some() {
+                return;
+            }
+            watch.task = Some(self.spawn_idle_timer(user_id, watch.id));
+            true
+        };
+        if rearm {
+            self.set_idle(user_id, false).await;
+        }
+    }
+
+    /// Wait out the user's idle timeout, restarting it on activity, then
+    /// mark them idle.
+    fn spawn_idle_timer(&self, user_id: Uuid, id: Uuid) -> JoinHandle<()> {
+        let dispatcher = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let deadline = {
+                    let watches = dispatcher.inner.idle_watches.lock().unwrap();
+                    match watches.get(&user_id) {
+                        Some(watch) if watch.id == id => watch.last_activity + watch.timeout,
+                        _ => return,
+                    }
+                };
+                tokio::time::sleep_until(deadline).await;
+                {
+                    let mut watches = dispatcher.inner.idle_watches.lock().unwrap();
+                    match watches.get_mut(&user_id) {
+                        Some(watch) if watch.id == id => {
+                            if watch.last_activity + watch.timeout > tokio::time::Instant::now() {
+                                continue;
+                            }
+                            watch.task = None;
+                        }
+                        _ => return,
+                    }
+                }
+                dispatcher.set_idle(user_id, true).await;
+                return;
+            }
+        })
+    }
+
+    /// Mark the user idle or back, broadcasting if others see a change.
+    async fn set_idle(&self, user_id: Uuid, idle: bool) {
+        let event = {
+            let mut online = self.inner.online_users.write().await;
+            let Some(presence) = online.get_mut(&user_id) else {
+                return;
+            };
+            // Activity may have re-armed the timer since it fired.
+            let armed = self.inner.idle_watches.lock().unwrap().get(&user_id).is_some_and(|w| w.task.is_some());
+            if presence.idle == idle || (idle && armed) {
+                return;
+            }
+            let before = presence.effective_status();
+            presence.idle = idle;
+            (presence.effective_status() != before).then(|| presence.event(user_id))
+        };
+        if let Some(event) = event {
+            self.broadcast(event);
+        }
+    }
+
+    fn stop_idle_watch(&self, user_id: Uuid) {
+        if let Some(watch) = self.inner.idle_watches.lock().unwrap().remove(&user_id) {
+            watch.abort();
+        }
+    }
+
+    /// Take the user out of `online_users` and broadcast them offline, if
+    /// they were in it.
+    async fn remove_presence(&self, user_id: Uuid) {
+        self.stop_idle_watch(user_id);
+        let presence = self.inner.online_users.write().await.remove(&user_id);
+        if let Some(presence) = presence {
+            self.broadcast(GatewayEvent::PresenceUpdate {
+                user_id,
+                username: presence.username,
+                online: false,
+                status: PresenceStatus::Offline,
+                custom: None,
+            });
+        }
     }
 
     /// Register a user as offline. Removes this connection and only does
@@ -203,7 +793,7 @@ impl Dispatcher {
         let remaining = {
             let mut channels = self.inner.user_channels.write().await;
             if let Some(conns) = channels.get_mut(&user_id) {
-                conns.retain(|(cid, _)| *cid != conn_id);
+                conns.retain(|c| c.conn_id != conn_id);
                 if conns.is_empty() {
                     channels.remove(&user_id);
                     0
@@ -220,34 +810,75 @@ impl Dispatcher {
             return;
         }
 
-        // All connections gone -- full cleanup
-        let username = self
-            .inner
-            .online_users
-            .write()
-            .await
-            .remove(&user_id)
-            .unwrap_or_default();
+        // All connections gone -- full cleanup. Leave voice on disconnect,
+        // unless they're back within the grace
+        self.defer_voice_leave(user_id).await;
+
+        self.stop_all_typing(user_id);
+        self.clear_subscriptions(user_id).await;
+        self.inner.rate_limiter.forget(user_id);
+
+        self.remove_presence(user_id).await;
+    }
+
+    /// Mark the user's voice participant `reconnecting` and arm the timer
+    /// that takes them out of voice once the grace runs out. Does nothing if
+    /// they aren't in voice.
+    async fn defer_voice_leave(&self, user_id: Uuid) {
+        {
+            let mut voice_states = self.inner.voice_states.write().await;
+            let Some(participant) = voice_states.values_mut().find_map(|p| p.get_mut(&user_id)) else {
+                return;
+            };
+            participant.reconnecting = true;
+        }
+
+        let id = Uuid::new_v4();
+        let dispatcher = self.clone();
+        // From the disconnect, not from whenever the task first runs.
+        let deadline = tokio::time::Instant::now() + self.inner.voice_grace;
+        let task = tokio::spawn(async move {
+            tokio::time::sleep_until(deadline).await;
+            {
+                let mut pending = dispatcher.inner.pending_leaves.lock().unwrap();
+                if pending.get(&user_id).is_none_or(|p| p.id != id) {
+                    return;
+                }
+                pending.remove(&user_id);
+            }
+            dispatcher.leave_voice_and_broadcast(user_id).await;
+        });
+        let previous = self.inner.pending_leaves.lock().unwrap().insert(user_id, PendingLeave { id, task });
+        if let Some(previous) = previous {
+            previous.task.abort();
+        }
+    }
 
-        // Auto-leave voice on disconnect
-        if let Some(channel_id) = self.voice_leave(user_id).await {
+    /// Cancel the user's pending voice leave, if any, and clear their
+    /// `reconnecting` mark.
+    async fn cancel_voice_leave(&self, user_id: Uuid) {
+        let Some(pending) = self.inner.pending_leaves.lock().unwrap().remove(&user_id) else {
+            return;
+        };
+        pending.task.abort();
+        let mut voice_states = self.inner.voice_states.write().await;
+        if let Some(participant) = voice_states.values_mut().find_map(|p| p.get_mut(&user_id)) {
+            participant.reconnecting = false;
+        }
+    }
+
+    /// Take the user out of voice and broadcast the leave, if they were in it.
+    async fn leave_voice_and_broadcast(&self, user_id: Uuid) {
+        if let Some((channel_id, participant)) = self.remove_voice_participant(user_id).await {
             self.broadcast(GatewayEvent::VoiceStateUpdate {
                 channel_id,
                 user_id,
-                username: username.clone(),
+                username: participant.username,
                 session_id: None,
                 self_mute: false,
                 self_deaf: false,
             });
         }
-
-        self.clear_subscriptions(user_id).await;
-
-        self.broadcast(GatewayEvent::PresenceUpdate {
-            user_id,
-            username,
-            online: false,
-        });
     }
 
     /// Force-disconnect a user: close all send channels and perform full
@@ -257,36 +888,17 @@ impl Dispatcher {
         // Drop all send channels (closes the connections)
         self.inner.user_channels.write().await.remove(&user_id);
 
-        // Remove from online users
-        let username = self
-            .inner
-            .online_users
-            .write()
-            .await
-            .remove(&user_id)
-            .unwrap_or_default();
-
-        // Auto-leave voice
-        if let Some(channel_id) = self.voice_leave(user_id).await {
-            self.broadcast(GatewayEvent::VoiceStateUpdate {
-                channel_id,
-                user_id,
-                username: username.clone(),
-                session_id: None,
-                self_mute: false,
-                self_deaf: false,
-            });
+        // Auto-leave voice, without waiting out a reconnect grace
+        if let Some(pending) = self.inner.pending_leaves.lock().unwrap().remove(&user_id) {
+            pending.task.abort();
         }
+        self.leave_voice_and_broadcast(user_id).await;
 
+        self.stop_all_typing(user_id);
         self.clear_subscriptions(user_id).await;
+        self.inner.rate_limiter.forget(user_id);
 
-        if !username.is_empty() {
-            self.broadcast(GatewayEvent::PresenceUpdate {
-                user_id,
-                username,
-                online: false,
-            });
-        }
+        self.remove_presence(user_id).await;
     }
 
     /// Get a snapshot of all voice states (for admin dashboard).
@@ -299,13 +911,13 @@ impl Dispatcher {
     }
 
     /// Get list of online users.
-    pub async fn online_users(&self) -> Vec<(Uuid, String)> {
+    pub async fn online_users(&self) -> Vec<(Uuid, UserPresence)> {
         self.inner
             .online_users
             .read()
             .await
             .iter()
-            .map(|(id, name)| (*id, name.clone()))
+            .map(|(id, presence)| (*id, presence.clone()))
             .collect()
     }
 
@@ -341,6 +953,8 @@ impl Dispatcher {
                 session_id,
                 self_mute: false,
                 self_deaf: false,
+                reconnecting: false,
+                stats: Arc::default(),
             },
         );
 
@@ -350,24 +964,29 @@ impl Dispatcher {
     /// Leave voice. Returns the channel_id they were in, if any.
     /// L3: Also removes the channel entry if no participants remain.
     pub async fn voice_leave(&self, user_id: Uuid) -> Option<Uuid> {
+        self.remove_voice_participant(user_id).await.map(|(channel_id, _)| channel_id)
+    }
+
+    /// `voice_leave`, also returning the participant removed.
+    async fn remove_voice_participant(&self, user_id: Uuid) -> Option<(Uuid, VoiceParticipant)> {
         let mut voice_states = self.inner.voice_states.write().await;
 
-        let mut left_channel = None;
+        let mut left = None;
         for (&channel_id, participants) in voice_states.iter_mut() {
-            if participants.remove(&user_id).is_some() {
-                left_channel = Some(channel_id);
+            if let Some(participant) = participants.remove(&user_id) {
+                left = Some((channel_id, participant));
                 break;
             }
         }
 
         // Clean up empty channel entry
-        if let Some(channel_id) = left_channel {
+        if let Some((channel_id, _)) = left {
             if voice_states.get(&channel_id).map_or(false, |p| p.is_empty()) {
                 voice_states.remove(&channel_id);
             }
         }
 
-        left_channel
+        left
     }
 
     /// Relay voice audio data (JSON) from sender to all other participants in the same channel.
@@ -376,29 +995,50 @@ impl Dispatcher {
             from_user_id: sender_id,
             data,
         });
-        self.relay_to_voice_peers(sender_id, msg).await;
+        self.relay_to_voice_peers(sender_id, msg, None).await;
     }
 
     /// Relay binary voice/screen audio data to all other participants in the same channel.
     /// The frame is already built -- just forward as a binary WebSocket frame.
-    pub async fn relay_voice_data_binary(&self, sender_id: Uuid, data: Bytes) {
-        self.relay_to_voice_peers(sender_id, UserMessage::Binary(data)).await;
+    /// `header` is the sender's frame header, if the frame type carries one.
+    pub async fn relay_voice_data_binary(&self, sender_id: Uuid, data: Bytes, header: Option<VoiceFrameHeader>) {
+        self.relay_to_voice_peers(sender_id, UserMessage::Binary(data), header).await;
+    }
+
+    /// Relay counters for every participant in `user_id`'s voice channel.
+    /// Returns `None` if the user isn't in voice.
+    pub async fn voice_stats(&self, user_id: Uuid) -> Option<(Uuid, Vec<VoicePeerStats>)> {
+        let voice_states = self.inner.voice_states.read().await;
+        voice_states.iter().find_map(|(&channel_id, participants)| {
+            participants.contains_key(&user_id).then(|| {
+                let peers = participants.values().map(|p| p.stats.snapshot(p.user_id)).collect();
+                (channel_id, peers)
+            })
+        })
     }
 
     /// Send a message to all voice channel peers of `sender_id` (excluding sender).
-    async fn relay_to_voice_peers(&self, sender_id: Uuid, msg: UserMessage) {
+    async fn relay_to_voice_peers(&self, sender_id: Uuid, msg: UserMessage, header: Option<VoiceFrameHeader>) {
+        let payload_len = match &msg {
+            UserMessage::Event(GatewayEvent::VoiceAudioData { data, .. }) => data.len(),
+            UserMessage::Event(_) => 0,
+            UserMessage::Binary(data) => data.len(),
+        };
         let voice_states = self.inner.voice_states.read().await;
         let channels = self.inner.user_channels.read().await;
 
         for (_channel_id, participants) in voice_states.iter() {
-            if participants.contains_key(&sender_id) {
-                for (&uid, _) in participants.iter() {
+            if let Some(sender) = participants.get(&sender_id) {
+                sender.stats.record_received(payload_len, header);
+                for (&uid, peer) in participants.iter() {
                     if uid != sender_id {
                         if let Some(conns) = channels.get(&uid) {
-                            for (conn_id, tx) in conns {
+                            for UserConn { conn_id, tx, .. } in conns {
                                 match tx.try_send(msg.clone()) {
-                                    Ok(()) => {}
+                                    Ok(()) => self.inner.metrics.voice_relayed(payload_len),
                                     Err(mpsc::error::TrySendError::Full(_)) => {
+                                        self.inner.metrics.message_dropped();
+                                        peer.stats.record_dropped();
                                         warn!(
                                             "Dropping voice data for user {} conn {}: channel full",
                                             uid, conn_id
@@ -435,3 +1075,237 @@ impl Dispatcher {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_voice_stats_count_gaps_and_late_frames() {
+        let dispatcher = Dispatcher::new();
+        let channel = Uuid::new_v4();
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        let (_bob_conn, _bob_rx) = dispatcher.register_user_channel(bob).await;
+        dispatcher.voice_join(channel, alice, "alice".into(), "a".into()).await;
+        dispatcher.voice_join(channel, bob, "bob".into(), "b".into()).await;
+
+        // 3 and 4 never arrive, 2 arrives after 5.
+        for seq in [0, 1, 5, 2, 6] {
+            let header = VoiceFrameHeader { seq, timestamp_ms: seq * 20 };
+            dispatcher
+                .relay_voice_data_binary(alice, Bytes::from_static(&[0u8; 10]), Some(header))
+                .await;
+        }
+
+        let (stats_channel, peers) = dispatcher.voice_stats(bob).await.unwrap();
+        assert_eq!(stats_channel, channel);
+        let alice_stats = peers.iter().find(|p| p.user_id == alice).unwrap();
+        assert_eq!(alice_stats.frames_relayed, 5);
+        assert_eq!(alice_stats.bytes_relayed, 50);
+        // 2..=4 were missing when 5 arrived; 2 then shows up late.
+        assert_eq!(alice_stats.frames_lost, 3);
+        assert_eq!(alice_stats.frames_late, 1);
+        assert_eq!(alice_stats.frames_dropped, 0);
+        assert!(dispatcher.voice_stats(Uuid::new_v4()).await.is_none());
+    }
+
+    #[test]
+    fn test_jitter_tracks_transit_variation() {
+        let header = |seq: u32| Some(VoiceFrameHeader { seq, timestamp_ms: 1000 + seq * 20 });
+
+        // Frames arriving exactly 20ms apart have no jitter, whatever the offset.
+        let steady = VoiceStats::default();
+        for seq in 0..50 {
+            steady.record_received_at(10, header(seq), 5 + seq * 20);
+        }
+        assert_eq!(steady.snapshot(Uuid::nil()).jitter_ms, 0);
+
+        // Alternating 0/40ms of extra delay converges toward 40ms. Late frames
+        // don't feed the estimate.
+        let bursty = VoiceStats::default();
+        for seq in 0..200 {
+            bursty.record_received_at(10, header(seq), seq * 20 + (seq % 2) * 40);
+        }
+        bursty.record_received_at(10, header(3), 0);
+        let stats = bursty.snapshot(Uuid::nil());
+        assert!((36..=40).contains(&stats.jitter_ms), "jitter {}", stats.jitter_ms);
+        assert_eq!(stats.frames_late, 1);
+    }
+
+    #[test]
+    fn test_voice_frame_header_round_trip() {
+        let header = VoiceFrameHeader { seq: 0x0102_0304, timestamp_ms: u32::MAX };
+        let mut frame = header.encode().to_vec();
+        assert_eq!(&frame[..4], &[1, 2, 3, 4]);
+        frame.extend_from_slice(b"opus");
+        assert_eq!(VoiceFrameHeader::parse(&frame), Some(header));
+        assert_eq!(VoiceFrameHeader::parse(&frame[..7]), None);
+    }
+
+    #[tokio::test]
+    async fn test_kick_connection_leaves_other_devices() {
+        let dispatcher = Dispatcher::new();
+        let alice = Uuid::new_v4();
+        let (phone, mut phone_rx) = dispatcher.register_user_channel(alice).await;
+        let (laptop, mut laptop_rx) = dispatcher.register_user_channel(alice).await;
+
+        let mut listed: Vec<_> = dispatcher.list_connections().await.into_iter().map(|(u, c, _)| (u, c)).collect();
+        listed.sort();
+        let mut expected = vec![(alice, phone), (alice, laptop)];
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        assert!(dispatcher.kick_connection(phone).await);
+        assert!(!dispatcher.kick_connection(phone).await);
+
+        // The kicked connection's channel is closed; the other still delivers.
+        assert!(phone_rx.recv().await.is_none());
+        dispatcher
+            .send_to_user(alice, GatewayEvent::RateLimited { category: "test".into() })
+            .await;
+        assert!(matches!(laptop_rx.try_recv(), Ok(UserMessage::Event(_))));
+        let listed: Vec<_> = dispatcher.list_connections().await.into_iter().map(|(_, c, _)| c).collect();
+        assert_eq!(listed, vec![laptop]);
+    }
+
+    #[tokio::test]
+    async fn test_typing_times_out_with_a_stop() {
+        let dispatcher = Dispatcher::new().with_typing_timeout(Duration::from_millis(100));
+        let mut events = dispatcher.subscribe();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let channel = Uuid::new_v4();
+
+        dispatcher.start_typing(alice, channel, "alice".into());
+        dispatcher.start_typing(bob, channel, "bob".into());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        // Still typing: the timer restarts.
+        dispatcher.start_typing(alice, channel, "alice".into());
+        dispatcher.stop_typing(bob, channel);
+        dispatcher.stop_typing(bob, channel);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        let mut seen = Vec::new();
+        while let Ok(msg) = events.try_recv() {
+            let event: serde_json::Value = serde_json::from_str(&msg.json).unwrap();
+            let who = if event["data"]["user_id"] == alice.to_string() { "alice" } else { "bob" };
+            seen.push(format!("{} {}", event["type"].as_str().unwrap(), who));
+        }
+        assert_eq!(
+            seen,
+            ["TypingStart alice", "TypingStart bob", "TypingStart alice", "TypingStop bob", "TypingStop alice"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_presence_status_transitions() {
+        let dispatcher = Dispatcher::new();
+        let alice = Uuid::new_v4();
+        let (conn, _rx) = dispatcher.register_user_channel(alice).await;
+        dispatcher.user_online(alice, "alice".into()).await;
+        let mut events = dispatcher.subscribe();
+        let idle = Some(Duration::from_millis(100));
+        let advance = |ms| tokio::time::advance(Duration::from_millis(ms));
+
+        // Busy stands through idleness; Online turns Away and back.
+        let long = "x".repeat(MAX_CUSTOM_STATUS_CHARS + 10);
+        dispatcher.set_presence(alice, PresenceStatus::Busy, Some(long), idle).await;
+        advance(250).await;
+        dispatcher.set_presence(alice, PresenceStatus::Online, Some("  ".into()), idle).await;
+        advance(60).await;
+        dispatcher.presence_activity(alice).await;
+        advance(60).await;
+        dispatcher.presence_activity(alice).await;
+        advance(250).await;
+
+        // The idle timer runs on its own task, so wait for its Away before
+        // the next activity brings Alice back.
+        let mut seen = Vec::new();
+        while seen.len() < 3 {
+            seen.push(events.recv().await.unwrap());
+        }
+        dispatcher.presence_activity(alice).await;
+
+        // Setting the same thing again says nothing; a zero timeout disarms.
+        dispatcher.set_presence(alice, PresenceStatus::Online, None, Some(Duration::ZERO)).await;
+        advance(250).await;
+
+        // A second device keeps the status; the last one leaving ends it.
+        dispatcher.set_presence(alice, PresenceStatus::Away, Some("lunch".into()), None).await;
+        let (laptop, _laptop_rx) = dispatcher.register_user_channel(alice).await;
+        dispatcher.user_online(alice, "alice".into()).await;
+        dispatcher.user_offline(alice, conn).await;
+        dispatcher.user_offline(alice, laptop).await;
+
+        while let Ok(msg) = events.try_recv() {
+            seen.push(msg);
+        }
+        let seen: Vec<_> = seen
+            .iter()
+            .map(|msg| {
+                let event: serde_json::Value = serde_json::from_str(&msg.json).unwrap();
+                let data = &event["data"];
+                let custom = data["custom"].as_str().map_or(0, |c| c.chars().count());
+                format!("{} {} {}", data["online"], data["status"].as_str().unwrap(), custom)
+            })
+            .collect();
+        assert_eq!(
+            seen,
+            [
+                "true busy 128",
+                "true online 0",
+                "true away 0",
+                "true online 0",
+                "true away 5",
+                "true away 5",
+                "false offline 0",
+            ]
+        );
+        assert!(dispatcher.online_users().await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_voice_participant_survives_a_quick_reconnect() {
+        let dispatcher = Dispatcher::new().with_voice_reconnect_grace(Duration::from_millis(150));
+        let (alice, channel) = (Uuid::new_v4(), Uuid::new_v4());
+        let (conn, _rx) = dispatcher.register_user_channel(alice).await;
+        dispatcher.user_online(alice, "alice".into()).await;
+        dispatcher.voice_join(channel, alice, "alice".into(), "a".into()).await;
+        let mut events = dispatcher.subscribe();
+        let alice_in_voice = || async {
+            let states = dispatcher.voice_states().await;
+            states.get(&channel).and_then(|p| p.iter().find(|p| p.user_id == alice).cloned())
+        };
+
+        // A blip: held as reconnecting, then back before the grace runs out.
+        dispatcher.user_offline(alice, conn).await;
+        assert!(alice_in_voice().await.unwrap().reconnecting);
+        tokio::time::advance(Duration::from_millis(50)).await;
+        let (conn, _rx) = dispatcher.register_user_channel(alice).await;
+        dispatcher.user_online(alice, "alice".into()).await;
+        tokio::time::advance(Duration::from_millis(250)).await;
+        let participant = alice_in_voice().await.unwrap();
+        assert_eq!(participant.session_id, "a");
+        assert!(!participant.reconnecting);
+
+        // Gone for good: the leave goes out once the grace has passed.
+        dispatcher.user_offline(alice, conn).await;
+        tokio::time::advance(Duration::from_millis(149)).await;
+        assert!(alice_in_voice().await.unwrap().reconnecting);
+        tokio::time::advance(Duration::from_millis(1)).await;
+
+        let mut seen = Vec::new();
+        while seen.last().is_none_or(|e: &String| !e.starts_with("voice")) {
+            let msg = events.recv().await.unwrap();
+            let event: serde_json::Value = serde_json::from_str(&msg.json).unwrap();
+            let data = &event["data"];
+            match event["type"].as_str().unwrap() {
+                "PresenceUpdate" => seen.push(format!("presence {}", data["online"])),
+                "VoiceStateUpdate" => seen.push(format!("voice {}", data["session_id"])),
+                other => panic!("unexpected {other}"),
+            }
+        }
+        assert_eq!(seen, ["presence false", "presence true", "presence false", "voice null"]);
+        assert!(alice_in_voice().await.is_none());
+    }
+}
@@ -1,3 +1,11 @@
+pub mod conn_limit;
 pub mod connection;
 pub mod dispatcher;
+pub mod metrics;
+pub mod rate_limit;
+pub mod reconnect;
+pub mod relay_auth;
+pub mod resume;
+pub mod ttl;
 pub mod turn;
+pub mod version;
@@ -0,0 +1,158 @@
+//! Prometheus metrics for the gateway.
+//!
+//! Counters are plain atomics bumped from the relay paths, so recording never
+//! takes a lock. Gauges that mirror dispatcher state (online users, open
+//! connections) are read at scrape time instead of being tracked separately.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::{
+    extract::State,
+    http::header,
+    response::IntoResponse,
+};
+
+use crate::dispatcher::Dispatcher;
+
+/// Content type for the Prometheus text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Gateway counters. All increments are `Relaxed`: each value is independent
+/// and scrapes only need eventual totals.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections_total: AtomicU64,
+    broadcast_messages_total: AtomicU64,
+    broadcast_lagged_total: AtomicU64,
+    dropped_messages_total: AtomicU64,
+    voice_bytes_relayed_total: AtomicU64,
+    rate_limited_commands_total: AtomicU64,
+    rejected_connections_total: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A WebSocket connection registered with the dispatcher.
+    pub fn connection_opened(&self) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An event was published on the broadcast channel.
+    pub fn broadcast_sent(&self) {
+        self.broadcast_messages_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A connection's broadcast receiver fell behind and skipped `n` messages.
+    pub fn broadcast_lagged(&self, n: u64) {
+        self.broadcast_lagged_total.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// A targeted message was dropped because the connection's queue was full.
+    pub fn message_dropped(&self) {
+        self.dropped_messages_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Voice payload bytes handed to a peer connection.
+    pub fn voice_relayed(&self, bytes: usize) {
+        self.voice_bytes_relayed_total.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// A client command was rejected by the per-user rate limiter.
+    pub fn command_rate_limited(&self) {
+        self.rate_limited_commands_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A WebSocket upgrade was refused by the connection limits.
+    pub fn connection_rejected(&self) {
+        self.rejected_connections_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render all metrics in Prometheus text format. Gauges are passed in by
+    /// the caller since they come from dispatcher state.
+    pub fn render(&self, online_users: usize, open_connections: usize) -> String {
+        let mut out = String::with_capacity(1024);
+        gauge(&mut out, "haven_online_users", "Users with at least one open connection.", online_users as u64);
+        gauge(&mut out, "haven_open_connections", "Open gateway WebSocket connections.", open_connections as u64);
+        counter(&mut out, "haven_connections_total", "Gateway connections accepted since startup.", &self.connections_total);
+        // Per-second broadcast rate is rate(haven_broadcast_messages_total[1m]).
+        counter(&mut out, "haven_broadcast_messages_total", "Events published to all connections.", &self.broadcast_messages_total);
+        counter(&mut out, "haven_broadcast_lagged_messages_total", "Broadcast events skipped by connections that fell behind.", &self.broadcast_lagged_total);
+        counter(&mut out, "haven_dropped_messages_total", "Targeted messages dropped because a connection queue was full.", &self.dropped_messages_total);
+        counter(&mut out, "haven_voice_bytes_relayed_total", "Voice payload bytes relayed to peers.", &self.voice_bytes_relayed_total);
+        counter(&mut out, "haven_rate_limited_commands_total", "Client commands dropped by the per-user rate limiter.", &self.rate_limited_commands_total);
+        counter(&mut out, "haven_rejected_connections_total", "Gateway upgrades refused by the connection limits.", &self.rejected_connections_total);
+        out
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n");
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    let value = value.load(Ordering::Relaxed);
+    let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n");
+}
+
+/// GET /metrics — Prometheus scrape endpoint. Unauthenticated; serve it on an
+/// internal address only.
+pub async fn serve_metrics(State(dispatcher): State<Dispatcher>) -> impl IntoResponse {
+    let body = dispatcher.render_metrics().await;
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use bytes::Bytes;
+    use haven_types::events::GatewayEvent;
+    use uuid::Uuid;
+
+    async fn scrape(dispatcher: &Dispatcher) -> String {
+        let response = serve_metrics(State(dispatcher.clone())).await.into_response();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], CONTENT_TYPE);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    fn sample(text: &str, name: &str) -> u64 {
+        text.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_else(|| panic!("{name} missing from scrape"))
+    }
+
+    #[tokio::test]
+    async fn test_scrape_reflects_activity() {
+        let dispatcher = Dispatcher::new();
+        let before = scrape(&dispatcher).await;
+        assert_eq!(sample(&before, "haven_broadcast_messages_total"), 0);
+        assert_eq!(sample(&before, "haven_online_users"), 0);
+
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        let channel = Uuid::new_v4();
+        let (_alice_conn, _alice_rx) = dispatcher.register_user_channel(alice).await;
+        let (_bob_conn, _bob_rx) = dispatcher.register_user_channel(bob).await;
+        dispatcher.user_online(alice, "alice".into()).await;
+        dispatcher.broadcast(GatewayEvent::TypingStart { channel_id: channel, user_id: alice, username: "alice".into() });
+
+        dispatcher.voice_join(channel, alice, "alice".into(), "a".into()).await;
+        dispatcher.voice_join(channel, bob, "bob".into(), "b".into()).await;
+        dispatcher.relay_voice_data_binary(alice, Bytes::from_static(&[0u8; 100]), None).await;
+
+        let after = scrape(&dispatcher).await;
+        // user_online broadcasts a presence update too.
+        assert_eq!(sample(&after, "haven_broadcast_messages_total"), 2);
+        assert_eq!(sample(&after, "haven_online_users"), 1);
+        assert_eq!(sample(&after, "haven_open_connections"), 2);
+        assert_eq!(sample(&after, "haven_connections_total"), 2);
+        assert_eq!(sample(&after, "haven_voice_bytes_relayed_total"), 100);
+        assert_eq!(sample(&after, "haven_dropped_messages_total"), 0);
+    }
+}
@@ -0,0 +1,177 @@
+//! Per-user token-bucket rate limiting for gateway commands.
+//!
+//! Every text command and binary frame is charged to one of three buckets.
+//! Control commands (typing, subscribe, signaling, offers) fan out to many
+//! clients and get the tightest limit; voice and file relay frames arrive
+//! continuously during a call or transfer and get much higher ceilings.
+//! Buckets are per user, so multiple devices share one allowance.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use uuid::Uuid;
+
+use haven_types::events::GatewayCommand;
+
+/// Which bucket a command is charged to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandCategory {
+    Control,
+    Voice,
+    File,
+}
+
+impl CommandCategory {
+    pub fn of(cmd: &GatewayCommand) -> Self {
+        match cmd {
+            GatewayCommand::VoiceData { .. } => Self::Voice,
+            GatewayCommand::FileChunkSend { .. }
+            | GatewayCommand::FileAckSend { .. }
+            | GatewayCommand::FileDoneSend { .. }
+            | GatewayCommand::FastNackSend { .. }
+            | GatewayCommand::FastProgressSend { .. } => Self::File,
+            _ => Self::Control,
+        }
+    }
+
+    /// Category of a binary frame, by its type byte.
+    pub fn of_binary(msg_type: u8) -> Self {
+        match msg_type {
+            0x01..=0x03 => Self::File,
+            0x04 | 0x05 => Self::Voice,
+            _ => Self::Control,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Control => "control",
+            Self::Voice => "voice",
+            Self::File => "file",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Sustained rate and burst size for one category.
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+impl Limit {
+    pub const fn new(per_sec: f64, burst: f64) -> Self {
+        Self { per_sec, burst }
+    }
+
+    /// Parse `"<per_sec>"` or `"<per_sec>:<burst>"`. Burst defaults to two
+    /// seconds' worth.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (rate, burst) = match value.split_once(':') {
+            Some((rate, burst)) => (rate.trim().parse().ok()?, Some(burst.trim().parse().ok()?)),
+            None => (value.trim().parse().ok()?, None),
+        };
+        let limit = Self::new(rate, burst.unwrap_or(rate * 2.0));
+        (limit.per_sec > 0.0 && limit.burst >= 1.0).then_some(limit)
+    }
+}
+
+/// Limits for each category.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    pub control: Limit,
+    pub voice: Limit,
+    pub file: Limit,
+}
+
+impl Default for RateLimitConfig {
+    /// Control allows bursts of ICE candidates; voice covers 20ms mic frames
+    /// plus screen audio; file covers relayed chunks and their acks.
+    fn default() -> Self {
+        Self {
+            control: Limit::new(20.0, 50.0),
+            voice: Limit::new(200.0, 400.0),
+            file: Limit::new(2000.0, 4000.0),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    fn limit(&self, category: CommandCategory) -> Limit {
+        match category {
+            CommandCategory::Control => self.control,
+            CommandCategory::Voice => self.voice,
+            CommandCategory::File => self.file,
+        }
+    }
+}
+
+/// Outcome of charging one command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateVerdict {
+    Allowed,
+    /// Over the limit. `notify` is set on the first rejection after the
+    /// bucket last admitted something, so the client hears once per episode.
+    Limited { notify: bool },
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    notified: bool,
+}
+
+/// Token buckets keyed by user.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<Uuid, [Bucket; 3]>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Charge one command in `category` to `user_id`.
+    pub fn check(&self, user_id: Uuid, category: CommandCategory) -> RateVerdict {
+        let now = Instant::now();
+        let limit = self.config.limit(category);
+        let mut buckets = self.buckets.lock().unwrap();
+        let user = buckets.entry(user_id).or_insert_with(|| {
+            let full = |c: CommandCategory| Bucket {
+                tokens: self.config.limit(c).burst,
+                last_refill: now,
+                notified: false,
+            };
+            [full(CommandCategory::Control), full(CommandCategory::Voice), full(CommandCategory::File)]
+        });
+
+        let bucket = &mut user[category.index()];
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_sec).min(limit.burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.notified = false;
+            RateVerdict::Allowed
+        } else {
+            let notify = !bucket.notified;
+            bucket.notified = true;
+            RateVerdict::Limited { notify }
+        }
+    }
+
+    /// Drop a user's buckets once their last connection closes.
+    pub fn forget(&self, user_id: Uuid) {
+        self.buckets.lock().unwrap().remove(&user_id);
+    }
+}
@@ -0,0 +1,215 @@
+//! Short-lived, single-use reconnect tokens.
+//!
+//! After `Ready` each connection is sent a `ReconnectToken`. A client that
+//! drops (wifi to cellular, say) can open the next gateway socket with it
+//! instead of going through the JWT flow again. A token is good for one
+//! upgrade within [`RECONNECT_TOKEN_TTL`]; redeeming it removes it.
+//!
+//! The connection a token opens is issued a token of its own, so tokens
+//! chain. Each one carries the [`Session`] the chain started from and is
+//! capped at that JWT's `exp`: reconnecting never extends a login, and a
+//! token minted under another issuer/audience configuration is refused.
+//!
+//! Clients present the token as `Authorization: Reconnect <token>` on the
+//! upgrade request, never in the URL, so it stays out of access logs.
+
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use haven_types::api::Claims;
+use haven_types::jwt::TokenScope;
+
+use crate::ttl::TtlMap;
+
+/// How long an issued token stays redeemable.
+pub const RECONNECT_TOKEN_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// `Authorization` scheme for presenting a reconnect token.
+pub const AUTH_SCHEME: &str = "Reconnect ";
+
+/// What a gateway connection was authenticated as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub user_id: Uuid,
+    pub username: String,
+    /// `exp` of the JWT the session started from.
+    pub expires_at: DateTime<Utc>,
+    /// The issuer/audience that JWT was checked against.
+    pub scope: TokenScope,
+    /// The TURN session (see `turn::TurnSessions`) whose credential this
+    /// connection was given. Reconnecting resumes it, so the client's relays
+    /// survive the hop.
+    pub turn_session: Option<u64>,
+}
+
+impl Session {
+    /// A session for a JWT that passed `scope`.
+    pub fn from_claims(claims: &Claims, scope: &TokenScope) -> Self {
+        Self {
+            user_id: claims.sub,
+            username: claims.username.clone(),
+            expires_at: i64::try_from(claims.exp)
+                .ok()
+                .and_then(|exp| DateTime::from_timestamp(exp, 0))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+            scope: scope.clone(),
+            turn_session: None,
+        }
+    }
+}
+
+/// Who a token was issued to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectIdentity {
+    pub session: Session,
+    /// The connection the token was issued on.
+    pub conn_id: Uuid,
+}
+
+/// In-memory token store, shared by every connection.
+#[derive(Clone)]
+pub struct ReconnectTokens {
+    tokens: TtlMap<String, ReconnectIdentity>,
+    ttl: Duration,
+}
+
+impl Default for ReconnectTokens {
+    fn default() -> Self {
+        Self::with_ttl(RECONNECT_TOKEN_TTL)
+    }
+}
+
+impl ReconnectTokens {
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Self { tokens: TtlMap::new(), ttl }
+    }
+
+    /// Issue a token for `identity`, replacing any earlier one for the same
+    /// connection. Returns the token and when it expires: the TTL from now,
+    /// or the session's own expiry if that is sooner. `None` once the
+    /// session has expired.
+    pub fn issue(&self, identity: ReconnectIdentity) -> Option<(String, DateTime<Utc>)> {
+        let issued_at = Utc::now();
+        let session_left = (identity.session.expires_at - issued_at).to_std().ok()?;
+        let lifetime = self.ttl.min(session_left);
+        if lifetime.is_zero() {
+            return None;
+        }
+        let token = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+        self.tokens.with(|tokens| {
+            tokens.retain(|_, issued| issued.conn_id != identity.conn_id);
+            tokens.insert(token.clone(), identity, lifetime);
+        });
+        let expires_at = issued_at + chrono::Duration::from_std(lifetime).unwrap_or_default();
+        Some((token, expires_at))
+    }
+
+    /// Consume `token`. `None` if it is unknown, already used, expired, or
+    /// was issued under a different `scope` than the server now checks.
+    pub fn redeem(&self, token: &str, scope: &TokenScope) -> Option<ReconnectIdentity> {
+        let identity = self.tokens.with(|tokens| tokens.remove(token))?;
+        (identity.session.scope == *scope).then_some(identity)
+    }
+
+    /// Drop expired tokens. Returns how many went.
+    pub fn purge_expired(&self) -> usize {
+        self.tokens.purge_expired()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCOPE: TokenScope = TokenScope { issuer: None, audience: None };
+
+    fn session(expires_in: chrono::Duration) -> Session {
+        Session {
+            user_id: Uuid::new_v4(),
+            username: "tester".into(),
+            expires_at: Utc::now() + expires_in,
+            scope: SCOPE,
+            turn_session: None,
+        }
+    }
+
+    fn identity(conn_id: Uuid) -> ReconnectIdentity {
+        ReconnectIdentity { session: session(chrono::Duration::hours(1)), conn_id }
+    }
+
+    #[test]
+    fn test_token_is_single_use() {
+        let tokens = ReconnectTokens::default();
+        let who = identity(Uuid::new_v4());
+        let (token, expires_at) = tokens.issue(who.clone()).unwrap();
+        assert!(expires_at > Utc::now());
+
+        assert_eq!(tokens.redeem(&token, &SCOPE), Some(who));
+        assert_eq!(tokens.redeem(&token, &SCOPE), None, "reused token");
+        assert_eq!(tokens.redeem("not-a-token", &SCOPE), None);
+    }
+
+    #[test]
+    fn test_expired_token_is_refused_and_purged() {
+        let tokens = ReconnectTokens::with_ttl(Duration::from_millis(20));
+        let (stale, _) = tokens.issue(identity(Uuid::new_v4())).unwrap();
+        let (also_stale, _) = tokens.issue(identity(Uuid::new_v4())).unwrap();
+        std::thread::sleep(Duration::from_millis(40));
+
+        assert_eq!(tokens.redeem(&stale, &SCOPE), None);
+        assert_eq!(tokens.purge_expired(), 1);
+        assert_eq!(tokens.redeem(&also_stale, &SCOPE), None);
+        assert!(tokens.is_empty());
+    }
+
+    #[test]
+    fn test_reissue_replaces_token_for_connection() {
+        let tokens = ReconnectTokens::default();
+        let conn_id = Uuid::new_v4();
+        let (first, _) = tokens.issue(identity(conn_id)).unwrap();
+        let (second, _) = tokens.issue(identity(conn_id)).unwrap();
+        tokens.issue(identity(Uuid::new_v4()));
+
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens.redeem(&first, &SCOPE), None);
+        assert!(tokens.redeem(&second, &SCOPE).is_some());
+    }
+
+    #[test]
+    fn test_chained_tokens_stop_at_the_jwt_expiry() {
+        let tokens = ReconnectTokens::default();
+        let mut who = ReconnectIdentity { session: session(chrono::Duration::milliseconds(150)), conn_id: Uuid::new_v4() };
+        let (token, expires_at) = tokens.issue(who.clone()).unwrap();
+        assert!(expires_at <= who.session.expires_at, "capped at the JWT's exp, not the TTL");
+
+        // Reconnect and get the next token in the chain: it inherits the cap.
+        who = tokens.redeem(&token, &SCOPE).unwrap();
+        who.conn_id = Uuid::new_v4();
+        let (chained, chained_expires_at) = tokens.issue(who.clone()).unwrap();
+        assert!(chained_expires_at <= who.session.expires_at);
+
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(tokens.redeem(&chained, &SCOPE), None);
+        assert_eq!(tokens.issue(who), None, "nothing left to chain from");
+    }
+
+    #[test]
+    fn test_token_from_another_scope_is_refused() {
+        let tokens = ReconnectTokens::default();
+        let (token, _) = tokens.issue(identity(Uuid::new_v4())).unwrap();
+        let scoped = TokenScope { issuer: Some("haven".into()), audience: Some("haven-chat".into()) };
+        assert_eq!(tokens.redeem(&token, &scoped), None);
+    }
+}
@@ -0,0 +1,274 @@
+//! Which file transfers the binary relay may carry, and between whom.
+//!
+//! The 0x01-0x03 binary frames name their own `target_uid`, so without a
+//! check any authenticated user could push chunks at anyone. A transfer is
+//! relayable once the sender has offered it (`FileOfferSend` or
+//! `FileOfferBroadcast`) and the recipient has accepted (`FileAcceptSend`).
+//! Chunks and done frames then flow sender -> recipient, acks the other way.
+//!
+//! Agreements live in memory, rebuilt at startup from the offers persisted
+//! in `pending_offers` (see [`TransferAgreements::restore`]) so offers
+//! replayed after a restart stay relayable. Each sender may have at most
+//! [`MAX_OUTSTANDING_OFFERS`] offers nobody has accepted yet.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use haven_db::models::OpenOfferRow;
+use haven_types::api::OfferStatus;
+use uuid::Uuid;
+
+use crate::ttl::TtlMap;
+
+/// How long an offer and its acceptances stay on record.
+pub const AGREEMENT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Most unaccepted offers one sender may have on record. Folder sends offer
+/// each file, so this is generous; it only stops a client flooding the map.
+pub const MAX_OUTSTANDING_OFFERS: usize = 4096;
+
+/// What [`TransferAgreements::offer`] made of an offer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offered {
+    Recorded,
+    /// Not relayable: the id isn't a UUID, so can't appear in a binary
+    /// frame, or another user already offered it.
+    Ignored,
+    /// The sender already has [`MAX_OUTSTANDING_OFFERS`] unaccepted offers.
+    TooMany,
+}
+
+struct Agreement {
+    sender: Uuid,
+    /// Offered recipients, and whether each has accepted.
+    recipients: HashMap<Uuid, bool>,
+}
+
+impl Agreement {
+    fn is_outstanding(&self) -> bool {
+        !self.recipients.values().any(|&accepted| accepted)
+    }
+}
+
+/// Offer/accept state for every relayable transfer, shared by all connections.
+#[derive(Clone)]
+pub struct TransferAgreements {
+    agreements: TtlMap<Uuid, Agreement>,
+    ttl: Duration,
+    max_outstanding: usize,
+}
+
+impl Default for TransferAgreements {
+    fn default() -> Self {
+        Self::with_limits(AGREEMENT_TTL, MAX_OUTSTANDING_OFFERS)
+    }
+}
+
+impl TransferAgreements {
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Self::with_limits(ttl, MAX_OUTSTANDING_OFFERS)
+    }
+
+    pub fn with_limits(ttl: Duration, max_outstanding: usize) -> Self {
+        Self { agreements: TtlMap::new(), ttl, max_outstanding }
+    }
+
+    /// Record that `sender` offered `transfer_id` to `recipients`.
+    pub fn offer(&self, transfer_id: &str, sender: Uuid, recipients: impl IntoIterator<Item = Uuid>) -> Offered {
+        let Ok(transfer_id) = Uuid::parse_str(transfer_id) else {
+            return Offered::Ignored;
+        };
+        self.agreements.with(|agreements| {
+            match agreements.get(&transfer_id) {
+                Some(entry) if entry.sender != sender => return Offered::Ignored,
+                Some(_) => {}
+                None => {
+                    let outstanding = agreements.values().filter(|e| e.sender == sender && e.is_outstanding()).count();
+                    if outstanding >= self.max_outstanding {
+                        return Offered::TooMany;
+                    }
+                    agreements.insert(transfer_id, Agreement { sender, recipients: HashMap::new() }, self.ttl);
+                }
+            }
+            let entry = agreements.get_mut(&transfer_id).unwrap();
+            for recipient in recipients {
+                entry.recipients.entry(recipient).or_insert(false);
+            }
+            Offered::Recorded
+        })
+    }
+
+    /// Rebuild agreements from offers persisted before a restart (see
+    /// `Database::list_open_offers`), each expiring when it would have had
+    /// the server stayed up. Returns how many recipients' offers were
+    /// restored; rows that don't parse are skipped.
+    pub fn restore(&self, offers: &[OpenOfferRow]) -> usize {
+        self.agreements.with(|agreements| {
+            let mut restored = 0;
+            for offer in offers {
+                let (Ok(transfer_id), Ok(sender), Ok(recipient), Ok(status)) = (
+                    Uuid::parse_str(&offer.transfer_id),
+                    Uuid::parse_str(&offer.from_user_id),
+                    Uuid::parse_str(&offer.to_user_id),
+                    offer.status.parse::<OfferStatus>(),
+                ) else {
+                    continue;
+                };
+                let Some(left) = self.ttl.checked_sub(Duration::from_secs(offer.age_secs.max(0) as u64)) else {
+                    continue;
+                };
+                if agreements.get(&transfer_id).is_none() {
+                    agreements.insert(transfer_id, Agreement { sender, recipients: HashMap::new() }, left);
+                }
+                let entry = agreements.get_mut(&transfer_id).unwrap();
+                if entry.sender == sender {
+                    entry.recipients.insert(recipient, status == OfferStatus::Accepted);
+                    restored += 1;
+                }
+            }
+            restored
+        })
+    }
+
+    /// `recipient` accepted `sender`'s offer of `transfer_id`. False unless
+    /// that offer was made to them.
+    pub fn accept(&self, transfer_id: &str, recipient: Uuid, sender: Uuid) -> bool {
+        self.set_accepted(transfer_id, recipient, sender, true)
+    }
+
+    /// `recipient` turned the offer down; frames for it are refused again.
+    pub fn reject(&self, transfer_id: &str, recipient: Uuid, sender: Uuid) -> bool {
+        self.set_accepted(transfer_id, recipient, sender, false)
+    }
+
+    fn set_accepted(&self, transfer_id: &str, recipient: Uuid, sender: Uuid, accepted: bool) -> bool {
+        let Ok(transfer_id) = Uuid::parse_str(transfer_id) else {
+            return false;
+        };
+        self.agreements.with(|agreements| match agreements.get_mut(&transfer_id) {
+            Some(entry) if entry.sender == sender => match entry.recipients.get_mut(&recipient) {
+                Some(state) => {
+                    *state = accepted;
+                    true
+                }
+                None => false,
+            },
+            _ => false,
+        })
+    }
+
+    /// Whether `sender` may relay `transfer_id` frames to `recipient`, i.e.
+    /// the recipient accepted the sender's offer.
+    pub fn allows(&self, transfer_id: Uuid, sender: Uuid, recipient: Uuid) -> bool {
+        self.agreements.with(|agreements| {
+            agreements
+                .get(&transfer_id)
+                .is_some_and(|entry| entry.sender == sender && entry.recipients.get(&recipient) == Some(&true))
+        })
+    }
+
+    /// Drop expired agreements. Returns how many went.
+    pub fn purge_expired(&self) -> usize {
+        self.agreements.purge_expired()
+    }
+
+    pub fn len(&self) -> usize {
+        self.agreements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.agreements.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_accepted_offers_are_relayable() {
+        let agreements = TransferAgreements::default();
+        let (sender, recipient, bystander) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let transfer = Uuid::new_v4();
+        let id = transfer.to_string();
+
+        assert_eq!(agreements.offer(&id, sender, [recipient]), Offered::Recorded);
+        assert!(!agreements.allows(transfer, sender, recipient), "offered but not accepted");
+
+        assert!(!agreements.accept(&id, bystander, sender), "never offered to them");
+        assert!(!agreements.accept(&id, recipient, bystander), "wrong sender");
+        assert!(agreements.accept(&id, recipient, sender));
+        assert!(agreements.allows(transfer, sender, recipient));
+        assert!(!agreements.allows(transfer, bystander, recipient));
+        assert!(!agreements.allows(transfer, recipient, sender), "direction matters");
+
+        // Someone else can't take over the id, and a rejection revokes it.
+        assert_eq!(agreements.offer(&id, bystander, [recipient]), Offered::Ignored);
+        assert!(agreements.reject(&id, recipient, sender));
+        assert!(!agreements.allows(transfer, sender, recipient));
+
+        assert_eq!(agreements.offer("not-a-uuid", sender, [recipient]), Offered::Ignored);
+    }
+
+    #[test]
+    fn test_expired_agreement_is_refused_and_purged() {
+        let agreements = TransferAgreements::with_ttl(Duration::from_millis(20));
+        let (sender, recipient) = (Uuid::new_v4(), Uuid::new_v4());
+        let transfer = Uuid::new_v4();
+        agreements.offer(&transfer.to_string(), sender, [recipient]);
+        agreements.accept(&transfer.to_string(), recipient, sender);
+        std::thread::sleep(Duration::from_millis(40));
+
+        assert!(!agreements.allows(transfer, sender, recipient));
+        assert_eq!(agreements.purge_expired(), 1);
+        assert!(agreements.is_empty());
+    }
+
+    #[test]
+    fn test_unaccepted_offers_are_capped_per_sender() {
+        let agreements = TransferAgreements::with_limits(AGREEMENT_TTL, 2);
+        let (sender, other, recipient) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let ids: Vec<String> = (0..3).map(|_| Uuid::new_v4().to_string()).collect();
+
+        assert_eq!(agreements.offer(&ids[0], sender, [recipient]), Offered::Recorded);
+        assert_eq!(agreements.offer(&ids[1], sender, [recipient]), Offered::Recorded);
+        assert_eq!(agreements.offer(&ids[2], sender, [recipient]), Offered::TooMany);
+        // Re-offering one already on record, or another sender, is fine.
+        assert_eq!(agreements.offer(&ids[1], sender, [recipient]), Offered::Recorded);
+        assert_eq!(agreements.offer(&ids[2], other, [recipient]), Offered::Recorded);
+
+        // An accepted offer no longer counts.
+        agreements.accept(&ids[0], recipient, sender);
+        let next = Uuid::new_v4().to_string();
+        assert_eq!(agreements.offer(&next, sender, [recipient]), Offered::Recorded);
+    }
+
+    #[test]
+    fn test_restored_offers_are_relayable_until_their_original_expiry() {
+        let agreements = TransferAgreements::with_ttl(Duration::from_secs(60));
+        let (sender, recipient, other) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let (accepted, pending, stale) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let row = |transfer: Uuid, to: Uuid, status: OfferStatus, age_secs| OpenOfferRow {
+            transfer_id: transfer.to_string(),
+            from_user_id: sender.to_string(),
+            to_user_id: to.to_string(),
+            status: status.to_string(),
+            age_secs,
+        };
+        let restored = agreements.restore(&[
+            row(accepted, recipient, OfferStatus::Accepted, 10),
+            row(accepted, other, OfferStatus::Pending, 10),
+            row(pending, recipient, OfferStatus::Pending, 10),
+            row(stale, recipient, OfferStatus::Accepted, 61),
+        ]);
+        assert_eq!(restored, 3);
+
+        assert!(agreements.allows(accepted, sender, recipient));
+        assert!(!agreements.allows(accepted, sender, other));
+        assert!(!agreements.allows(stale, sender, recipient), "already past its TTL");
+        // A replayed offer can still be accepted.
+        assert!(!agreements.allows(pending, sender, recipient));
+        assert!(agreements.accept(&pending.to_string(), recipient, sender));
+        assert!(agreements.allows(pending, sender, recipient));
+    }
+}
@@ -0,0 +1,143 @@
+//! Message replay for reconnecting clients.
+//!
+//! Every message carries a per-channel `seq`. After reconnecting and
+//! subscribing, a client sends `Resume` with the last `seq` it saw in each
+//! channel; messages after it are replayed to that connection as
+//! `MessageCreate` events. Channels more than `RESUME_BACKFILL_LIMIT` messages
+//! behind get a `ResumeGap` instead, and the client refetches over REST.
+//!
+//! Replay shares the connection's targeted queue with live events, so a live
+//! `MessageCreate` can arrive before older replayed ones. Clients order by
+//! `seq` and dedupe by message id.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use haven_db::models::MessageRow;
+use haven_types::events::GatewayEvent;
+
+use crate::dispatcher::{Dispatcher, UserMessage};
+
+/// Most messages replayed per channel before falling back to `ResumeGap`.
+pub const RESUME_BACKFILL_LIMIT: u32 = 500;
+
+/// Replay messages newer than `last_event_seq` in each subscribed channel to
+/// connection `conn_id`.
+pub async fn replay_missed_messages(
+    dispatcher: &Dispatcher,
+    db: &Arc<haven_db::Database>,
+    user_id: Uuid,
+    conn_id: Uuid,
+    last_event_seq: HashMap<Uuid, u64>,
+    subscriptions: &HashSet<Uuid>,
+) {
+    let Some(tx) = dispatcher.connection_sender(user_id, conn_id).await else {
+        return;
+    };
+
+    for (channel_id, after_seq) in last_event_seq {
+        if !subscriptions.contains(&channel_id) {
+            continue;
+        }
+
+        // No channel gets that far; the client's view is from elsewhere.
+        let Ok(after_seq) = i64::try_from(after_seq) else {
+            if tx.send(UserMessage::Event(GatewayEvent::ResumeGap { channel_id })).await.is_err() {
+                return;
+            }
+            continue;
+        };
+        let db = db.clone();
+        let cid = channel_id.to_string();
+        let rows = tokio::task::spawn_blocking(move || {
+            db.get_messages_since(&cid, after_seq, RESUME_BACKFILL_LIMIT)
+        })
+        .await;
+        let events: Vec<GatewayEvent> = match rows {
+            Ok(Ok(Some(rows))) => {
+                if !rows.is_empty() {
+                    info!("Resume: replaying {} messages in {} to {}", rows.len(), channel_id, user_id);
+                }
+                rows.into_iter().filter_map(message_event).collect()
+            }
+            Ok(Ok(None)) => vec![GatewayEvent::ResumeGap { channel_id }],
+            Ok(Err(e)) => {
+                warn!("Resume: failed to load messages for channel {}: {}", channel_id, e);
+                vec![GatewayEvent::ResumeGap { channel_id }]
+            }
+            Err(e) => {
+                warn!("Resume: spawn_blocking join error: {}", e);
+                return;
+            }
+        };
+
+        for event in events {
+            // Wait for queue space: this connection asked for the backlog.
+            if tx.send(UserMessage::Event(event)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Convert a stored message into a `MessageCreate` event. Rows with corrupt
+/// ids are skipped.
+fn message_event(row: MessageRow) -> Option<GatewayEvent> {
+    let parse = |field: &str, value: &str| {
+        value
+            .parse::<Uuid>()
+            .map_err(|e| warn!("Resume: corrupt {} '{}' on message '{}': {}", field, value, row.id, e))
+            .ok()
+    };
+    Some(GatewayEvent::MessageCreate {
+        id: parse("id", &row.id)?,
+        channel_id: parse("channel_id", &row.channel_id)?,
+        author_id: parse("author_id", &row.author_id)?,
+        author_username: row.author_username.clone(),
+        ciphertext: B64.encode(&row.ciphertext),
+        nonce: B64.encode(&row.nonce),
+        timestamp: parse_created_at(&row.created_at),
+        seq: row.seq as u64,
+    })
+}
+
+/// SQLite's `datetime('now')` has no timezone; treat it as UTC.
+fn parse_created_at(value: &str) -> chrono::DateTime<chrono::Utc> {
+    value
+        .parse::<chrono::DateTime<chrono::Utc>>()
+        .or_else(|_| {
+            chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").map(|ndt| ndt.and_utc())
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_seq_past_i64_gets_a_gap() {
+        let dir = std::env::temp_dir().join(format!("haven-gw-resume-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Arc::new(haven_db::Database::open(&dir.join("test.db")).unwrap());
+
+        let dispatcher = Dispatcher::new();
+        let (user_id, channel_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let (conn_id, mut rx) = dispatcher.register_user_channel(user_id).await;
+        let last_event_seq = HashMap::from([(channel_id, u64::MAX - 7)]);
+        replay_missed_messages(&dispatcher, &db, user_id, conn_id, last_event_seq, &HashSet::from([channel_id])).await;
+
+        match rx.try_recv() {
+            Ok(UserMessage::Event(GatewayEvent::ResumeGap { channel_id: gap })) => assert_eq!(gap, channel_id),
+            other => panic!("expected ResumeGap, got {:?}", other.map(|_| ())),
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
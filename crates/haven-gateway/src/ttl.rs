@@ -0,0 +1,135 @@
+//! A shared map whose entries lapse on their own deadlines.
+//!
+//! Reconnect tokens, relay agreements and sent-message nonces are all kept
+//! in memory for a while and then forgotten. [`TtlMap`] holds each value
+//! with its expiry: expired entries are invisible to lookups straight away
+//! and freed by a periodic [`TtlMap::purge_expired`]. How long each entry
+//! lives is up to the caller, per insert.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Expiring<V> {
+    value: V,
+    expires: Instant,
+}
+
+/// Clones share the same entries.
+pub struct TtlMap<K, V> {
+    inner: Arc<Mutex<HashMap<K, Expiring<V>>>>,
+}
+
+impl<K, V> Clone for TtlMap<K, V> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl<K: Eq + Hash, V> Default for TtlMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash, V> TtlMap<K, V> {
+    pub fn new() -> Self {
+        Self { inner: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Run `f` with the map locked, seeing only entries that haven't expired.
+    pub fn with<R>(&self, f: impl FnOnce(&mut Live<'_, K, V>) -> R) -> R {
+        let mut map = self.inner.lock().unwrap();
+        f(&mut Live { map: &mut map, now: Instant::now() })
+    }
+
+    /// Drop expired entries. Returns how many went.
+    pub fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut map = self.inner.lock().unwrap();
+        let before = map.len();
+        map.retain(|_, e| e.expires > now);
+        before - map.len()
+    }
+
+    /// Entries held, including expired ones not yet purged.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A locked [`TtlMap`] as of one instant.
+pub struct Live<'a, K, V> {
+    map: &'a mut HashMap<K, Expiring<V>>,
+    now: Instant,
+}
+
+impl<K: Eq + Hash, V> Live<'_, K, V> {
+    pub fn get<Q: Eq + Hash + ?Sized>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        self.map.get(key).filter(|e| e.expires > self.now).map(|e| &e.value)
+    }
+
+    pub fn get_mut<Q: Eq + Hash + ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+    {
+        let now = self.now;
+        self.map.get_mut(key).filter(|e| e.expires > now).map(|e| &mut e.value)
+    }
+
+    /// Store `value` for `ttl` from now, replacing whatever `key` held.
+    pub fn insert(&mut self, key: K, value: V, ttl: Duration) {
+        self.map.insert(key, Expiring { value, expires: self.now + ttl });
+    }
+
+    /// Take `key`'s value out, if it hasn't expired.
+    pub fn remove<Q: Eq + Hash + ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.map.remove(key).filter(|e| e.expires > self.now).map(|e| e.value)
+    }
+
+    /// Keep the live entries `keep` says to; expired ones go regardless.
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &mut V) -> bool) {
+        let now = self.now;
+        self.map.retain(|k, e| e.expires > now && keep(k, &mut e.value));
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.map.values().filter(|e| e.expires > self.now).map(|e| &e.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_lapse_on_their_own_deadlines() {
+        let map = TtlMap::new();
+        map.with(|m| {
+            m.insert("short", 1, Duration::from_millis(20));
+            m.insert("long", 2, Duration::from_secs(60));
+        });
+        std::thread::sleep(Duration::from_millis(40));
+
+        map.with(|m| {
+            assert_eq!(m.get("short"), None);
+            assert_eq!(m.remove("short"), None);
+            assert_eq!(m.values().copied().collect::<Vec<_>>(), [2]);
+        });
+        assert_eq!(map.purge_expired(), 0, "remove took the expired entry");
+        assert_eq!(map.clone().with(|m| m.remove("long")), Some(2));
+        assert!(map.is_empty());
+    }
+}
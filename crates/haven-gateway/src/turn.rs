@@ -1,9 +1,14 @@
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64;
 
 use tokio::net::UdpSocket;
+use tokio::sync::watch;
 use tracing::{debug, error, info};
 
 // ── STUN/TURN constants ─────────────────────────────────────────────
@@ -41,10 +46,21 @@ const ATTR_REALM: u16 = 0x0014;
 const ATTR_NONCE: u16 = 0x0015;
 const ATTR_SOFTWARE: u16 = 0x8022;
 
-const DEFAULT_LIFETIME: u32 = 600; // 10 minutes
-const MAX_LIFETIME: u32 = 3600; // 1 hour
+/// Default `TurnConfig::default_lifetime_secs`: 10 minutes.
+pub const DEFAULT_LIFETIME_SECS: u32 = 600;
+/// Default `TurnConfig::max_lifetime_secs`: 1 hour.
+pub const MAX_LIFETIME_SECS: u32 = 3600;
+/// Default `TurnConfig::session_grace_secs`: 1 minute.
+pub const DEFAULT_SESSION_GRACE_SECS: u32 = 60;
 const CHANNEL_DATA_HEADER_SIZE: usize = 4;
+/// Default `TurnConfig::max_rate_bytes_per_sec`: 20 Mbit/s per allocation, room for a
+/// call's video plus a screen share.
+pub const DEFAULT_ALLOCATION_BYTES_PER_SEC: u64 = 2_500_000;
 const SOFTWARE_NAME: &[u8] = b"Haven TURN";
+/// How long a gateway session's TURN credential is valid. The session
+/// ending revokes it sooner; a client connected for longer reconnects for a
+/// fresh one.
+pub const CREDENTIAL_TTL_SECS: u64 = 24 * 3600;
 
 // ── Configuration ────────────────────────────────────────────────────
 
@@ -54,7 +70,173 @@ pub struct TurnConfig {
     pub public_ip: IpAddr,
     pub realm: String,
     pub username: String,
+    /// Shared password, and the secret session credentials are derived from.
     pub password: String,
+    /// Only accept the per-session credentials [`TurnSessions`] hands out,
+    /// not the shared `username`/`password`: those are the same for every
+    /// client, so anyone who has seen them could relay.
+    pub require_session: bool,
+    /// Bytes/s each allocation may relay, both directions together; packets
+    /// over it are dropped so one allocation can't take the relay's whole
+    /// uplink. Bursts of up to a second's worth pass. 0 for no cap.
+    pub max_rate_bytes_per_sec: u64,
+    /// Allocation lifetime when a request names none.
+    pub default_lifetime_secs: u32,
+    /// Longest lifetime granted, whatever a request asks for.
+    pub max_lifetime_secs: u32,
+    /// How long a closed gateway session's credential and allocations
+    /// outlive it, so a client that reconnects in time (with its reconnect
+    /// token) keeps its relays across a network hop.
+    pub session_grace_secs: u32,
+}
+
+// ── Gateway sessions ─────────────────────────────────────────────────
+
+/// Authenticated gateway connections, each with its own TURN credential
+/// (the time-limited scheme of draft-uberti-behave-turn-rest): the username
+/// is `<expiry unix secs>:<session id>` and the password the base64
+/// HMAC-SHA1 of it under the shared password. TURN takes a session's
+/// credential only while the session is open, and tears down the
+/// allocations made with it once the session has been closed for
+/// `TurnConfig::session_grace_secs` without being resumed.
+#[derive(Clone)]
+pub struct TurnSessions {
+    live: Arc<Mutex<HashMap<u64, SessionEntry>>>,
+    allocations: Allocations,
+    secret: Arc<str>,
+    grace: Duration,
+}
+
+struct SessionEntry {
+    username: String,
+    /// Open guards: the connection, and its successor if one resumed it.
+    guards: usize,
+    /// Bumped each time the last guard drops, so only the latest close's
+    /// teardown runs.
+    closes: u64,
+}
+
+impl TurnSessions {
+    fn new(allocations: Allocations, secret: &str, grace: Duration) -> Self {
+        Self { live: Arc::default(), allocations, secret: secret.into(), grace }
+    }
+
+    /// Register a gateway connection until the guard drops.
+    pub fn register(&self) -> TurnSession {
+        let expiry = unix_now() + CREDENTIAL_TTL_SECS;
+        let mut live = self.live.lock().unwrap();
+        let id = loop {
+            let id = rand::random::<u64>();
+            if !live.contains_key(&id) {
+                break id;
+            }
+        };
+        let username = format!("{}:{:016x}", expiry, id);
+        live.insert(id, SessionEntry { username: username.clone(), guards: 1, closes: 0 });
+        TurnSession { sessions: self.clone(), id, username }
+    }
+
+    /// Take over session `id` for a connection reconnecting in its place,
+    /// calling off the teardown its close started. `None` once the grace
+    /// period has run out.
+    pub fn resume(&self, id: u64) -> Option<TurnSession> {
+        let mut live = self.live.lock().unwrap();
+        let entry = live.get_mut(&id)?;
+        entry.guards += 1;
+        Some(TurnSession { sessions: self.clone(), id, username: entry.username.clone() })
+    }
+
+    /// The session, open or in its grace period, that an unexpired session
+    /// username names.
+    fn session_of(&self, username: &str) -> Option<u64> {
+        let (expiry, id) = username.split_once(':')?;
+        if expiry.parse::<u64>().ok()? < unix_now() {
+            return None;
+        }
+        let id = u64::from_str_radix(id, 16).ok()?;
+        self.live.lock().unwrap().contains_key(&id).then_some(id)
+    }
+
+    fn password(&self, username: &str) -> String {
+        B64.encode(compute_hmac_sha1(self.secret.as_bytes(), username.as_bytes()))
+    }
+
+    /// Drop a guard on session `id`; the last one starts the grace period.
+    fn close(&self, id: u64) {
+        let closes = {
+            let mut live = self.live.lock().unwrap();
+            let Some(entry) = live.get_mut(&id) else { return };
+            entry.guards -= 1;
+            if entry.guards > 0 {
+                return;
+            }
+            entry.closes += 1;
+            entry.closes
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) if !self.grace.is_zero() => {
+                let sessions = self.clone();
+                runtime.spawn(async move {
+                    tokio::time::sleep(sessions.grace).await;
+                    sessions.tear_down(id, closes);
+                });
+            }
+            _ => self.tear_down(id, closes),
+        }
+    }
+
+    /// End session `id` and drop its allocations, unless it was resumed (or
+    /// closed again) since close number `closes`.
+    fn tear_down(&self, id: u64, closes: u64) {
+        {
+            let mut live = self.live.lock().unwrap();
+            match live.get(&id) {
+                Some(entry) if entry.guards == 0 && entry.closes == closes => live.remove(&id),
+                _ => return,
+            };
+        }
+        let mut allocs = self.allocations.write().unwrap();
+        let before = allocs.len();
+        allocs.retain(|_, alloc| alloc.credential != Credential::Session(id));
+        if allocs.len() < before {
+            info!("TURN: gateway session {:016x} ended, dropping {} allocation(s)", id, before - allocs.len());
+        }
+    }
+}
+
+/// A registered gateway connection; dropping the last guard on a session
+/// starts its grace period.
+pub struct TurnSession {
+    sessions: TurnSessions,
+    id: u64,
+    username: String,
+}
+
+impl TurnSession {
+    /// Identifies the session to [`TurnSessions::resume`].
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The `(username, credential)` this session's client allocates with.
+    pub fn credential(&self) -> (String, String) {
+        (self.username.clone(), self.sessions.password(&self.username))
+    }
+}
+
+impl Drop for TurnSession {
+    fn drop(&mut self) {
+        self.sessions.close(self.id);
+    }
+}
+
+/// What a request authenticated with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Credential {
+    /// The configured shared username (only with `require_session` off).
+    Shared,
+    /// A gateway session's own credential.
+    Session(u64),
 }
 
 // ── Allocation state ─────────────────────────────────────────────────
@@ -67,58 +249,185 @@ struct Allocation {
     channels: HashMap<u16, SocketAddr>,    // channel_number -> peer addr
     channel_rev: HashMap<SocketAddr, u16>, // peer addr -> channel_number
     expires: Instant,
+    /// Goes with the gateway session, if it was made under one's credential.
+    credential: Credential,
+    meter: RelayMeter,
+    /// Never sent on; dropping it with the allocation stops its relay task.
+    closed: watch::Sender<()>,
+}
+
+type Allocations = Arc<RwLock<HashMap<SocketAddr, Allocation>>>;
+
+/// Drop allocations past their lifetime. Returns how many went.
+fn reap_expired(allocs: &RwLock<HashMap<SocketAddr, Allocation>>) -> usize {
+    let now = Instant::now();
+    let mut map = allocs.write().unwrap();
+    let before = map.len();
+    map.retain(|addr, alloc| {
+        let live = now < alloc.expires;
+        if !live {
+            info!("TURN: reaping expired allocation for {}", addr);
+        }
+        live
+    });
+    before - map.len()
+}
+
+/// Traffic an allocation has relayed, in both directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationStats {
+    pub client: SocketAddr,
+    pub bytes: u64,
+    pub packets: u64,
+    /// Packets dropped for going over `TurnConfig::max_rate_bytes_per_sec`.
+    pub dropped: u64,
+}
+
+/// Per-allocation counters and token bucket.
+struct RelayMeter {
+    max_rate_bytes_per_sec: u64,
+    bytes: AtomicU64,
+    packets: AtomicU64,
+    dropped: AtomicU64,
+    budget: Mutex<(f64, Instant)>,
+}
+
+impl RelayMeter {
+    fn new(max_rate_bytes_per_sec: u64) -> Self {
+        Self {
+            max_rate_bytes_per_sec,
+            bytes: AtomicU64::new(0),
+            packets: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            budget: Mutex::new((max_rate_bytes_per_sec as f64, Instant::now())),
+        }
+    }
+
+    /// Charge a `len`-byte packet; false if it's over the rate and must be
+    /// dropped.
+    fn admit(&self, len: usize) -> bool {
+        if self.max_rate_bytes_per_sec > 0 {
+            let mut budget = self.budget.lock().unwrap();
+            let (tokens, last_refill) = &mut *budget;
+            let now = Instant::now();
+            let rate = self.max_rate_bytes_per_sec as f64;
+            *tokens = (*tokens + now.duration_since(*last_refill).as_secs_f64() * rate).min(rate);
+            *last_refill = now;
+            if *tokens < len as f64 {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            *tokens -= len as f64;
+        }
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    fn stats(&self, client: SocketAddr) -> AllocationStats {
+        AllocationStats {
+            client,
+            bytes: self.bytes.load(Ordering::Relaxed),
+            packets: self.packets.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
 }
 
 // ── TURN Server ──────────────────────────────────────────────────────
 
 pub struct TurnServer {
     config: TurnConfig,
-    allocations: Arc<RwLock<HashMap<SocketAddr, Allocation>>>,
+    allocations: Allocations,
     hmac_key: Vec<u8>,
+    sessions: TurnSessions,
+    /// Set by `shutdown`; the UDP listener, its reaper and its relay tasks
+    /// all stop on it.
+    stopping: watch::Sender<bool>,
 }
 
 impl TurnServer {
     pub fn new(config: TurnConfig) -> Self {
         let hmac_key = compute_long_term_key(&config.username, &config.realm, &config.password);
+        let allocations = Allocations::default();
+        let grace = Duration::from_secs(config.session_grace_secs as u64);
+        let sessions = TurnSessions::new(allocations.clone(), &config.password, grace);
         Self {
             config,
-            allocations: Arc::new(RwLock::new(HashMap::new())),
+            allocations,
             hmac_key,
+            sessions,
+            stopping: watch::Sender::new(false),
         }
     }
 
-    /// Run the UDP TURN listener.
+    /// Gateway connections, each with its own credential (see
+    /// `TurnConfig::require_session`).
+    pub fn sessions(&self) -> &TurnSessions {
+        &self.sessions
+    }
+
+    /// Traffic relayed so far by each live allocation.
+    pub fn allocation_stats(&self) -> Vec<AllocationStats> {
+        let allocs = self.allocations.read().unwrap();
+        allocs.iter().map(|(client, alloc)| alloc.meter.stats(*client)).collect()
+    }
+
+    /// Lifetime to grant a request: its LIFETIME, or the default, capped.
+    fn lifetime(&self, attrs: &HashMap<u16, Vec<u8>>) -> u32 {
+        extract_lifetime(attrs).unwrap_or(self.config.default_lifetime_secs).min(self.config.max_lifetime_secs)
+    }
+
+    /// Stop the UDP listener started by `run_udp`, along with its reaper and
+    /// per-allocation relay tasks. `run_udp` then drops every allocation and
+    /// returns. TURN has no message telling a client its allocation is gone;
+    /// clients find out when their refresh fails and ICE restarts.
+    pub fn shutdown(&self) {
+        self.stopping.send_replace(true);
+    }
+
+    /// Resolves once `shutdown` has been called (immediately if it already has).
+    fn stopped(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let mut rx = self.stopping.subscribe();
+        async move {
+            let _ = rx.wait_for(|&stopping| stopping).await;
+        }
+    }
+
+    /// Run the UDP TURN listener until `shutdown` is called.
     pub async fn run_udp(&self, port: u16) -> anyhow::Result<()> {
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
-        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        self.serve_udp(UdpSocket::bind(addr).await?).await
+    }
+
+    /// Serve TURN on an already-bound socket until `shutdown` is called.
+    async fn serve_udp(&self, socket: UdpSocket) -> anyhow::Result<()> {
+        let addr = socket.local_addr()?;
+        let socket = Arc::new(socket);
         info!("TURN relay listening on UDP {}", addr);
 
         // Spawn reaper for expired allocations
         let allocs = self.allocations.clone();
+        let reaper_stopped = self.stopped();
         tokio::spawn(async move {
+            tokio::pin!(reaper_stopped);
             loop {
-                tokio::time::sleep(Duration::from_secs(30)).await;
-                let expired: Vec<SocketAddr> = {
-                    let map = allocs.read().unwrap();
-                    let now = Instant::now();
-                    map.iter()
-                        .filter(|(_, a)| now >= a.expires)
-                        .map(|(k, _)| *k)
-                        .collect()
-                };
-                if !expired.is_empty() {
-                    let mut map = allocs.write().unwrap();
-                    for addr in &expired {
-                        info!("TURN: reaping expired allocation for {}", addr);
-                        map.remove(addr);
-                    }
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(30)) => {}
+                    _ = &mut reaper_stopped => break,
                 }
+                reap_expired(&allocs);
             }
         });
 
+        let stopped = self.stopped();
+        tokio::pin!(stopped);
         let mut buf = vec![0u8; 65536];
         loop {
-            let (n, src) = socket.recv_from(&mut buf).await?;
+            let (n, src) = tokio::select! {
+                received = socket.recv_from(&mut buf) => received?,
+                _ = &mut stopped => break,
+            };
             if n < 1 {
                 continue;
             }
@@ -131,6 +440,15 @@ impl TurnServer {
                 self.handle_stun_message(&socket, src, &buf[..n]).await;
             }
         }
+
+        let active = {
+            let mut allocs = self.allocations.write().unwrap();
+            let active = allocs.len();
+            allocs.clear();
+            active
+        };
+        info!("TURN relay on UDP {} stopped, dropping {} active allocations", addr, active);
+        Ok(())
     }
 
     /// Handle a STUN/TURN message.
@@ -220,11 +538,11 @@ impl TurnServer {
         }
 
         // Verify credentials
-        if !self.verify_message_integrity(raw, attrs) {
+        let Some(credential) = self.verify_message_integrity(raw, attrs) else {
             let resp = build_error_response(ALLOCATE_ERROR, txn_id, 401, "Unauthorized");
             let _ = socket.send_to(&resp, src).await;
             return;
-        }
+        };
 
         // Check REQUESTED-TRANSPORT (must be UDP = 17)
         if let Some(transport) = attrs.get(&ATTR_REQUESTED_TRANSPORT) {
@@ -249,7 +567,7 @@ impl TurnServer {
         let relay_local = relay_socket.local_addr().unwrap();
         let relay_addr = SocketAddr::new(self.config.public_ip, relay_local.port());
 
-        let lifetime = extract_lifetime(attrs).unwrap_or(DEFAULT_LIFETIME).min(MAX_LIFETIME);
+        let lifetime = self.lifetime(attrs);
 
         info!(
             "TURN: allocation created for {} -> relay {} (lifetime {}s)",
@@ -262,12 +580,19 @@ impl TurnServer {
         let main_socket = socket.clone();
         let client_addr = src;
         let allocs_clone = self.allocations.clone();
+        let stopped = self.stopped();
+        let (closed, mut on_closed) = watch::channel(());
         tokio::spawn(async move {
+            tokio::pin!(stopped);
             let mut buf = vec![0u8; 65536];
             loop {
-                let (n, peer_addr) = match relay_rx.recv_from(&mut buf).await {
-                    Ok(r) => r,
-                    Err(_) => break,
+                let (n, peer_addr) = tokio::select! {
+                    received = relay_rx.recv_from(&mut buf) => match received {
+                        Ok(r) => r,
+                        Err(_) => break,
+                    },
+                    _ = &mut stopped => break,
+                    _ = on_closed.changed() => break,
                 };
 
                 // Look up permission + channel binding, then DROP lock before I/O
@@ -277,7 +602,7 @@ impl TurnServer {
                         Some(a) => a,
                         None => break,
                     };
-                    if !alloc.permissions.contains_key(&peer_addr.ip()) {
+                    if !alloc.permissions.contains_key(&peer_addr.ip()) || !alloc.meter.admit(n) {
                         continue;
                     }
                     alloc.channel_rev.get(&peer_addr).copied()
@@ -313,6 +638,9 @@ impl TurnServer {
                 channels: HashMap::new(),
                 channel_rev: HashMap::new(),
                 expires: Instant::now() + Duration::from_secs(lifetime as u64),
+                credential,
+                meter: RelayMeter::new(self.config.max_rate_bytes_per_sec),
+                closed,
             });
         }
 
@@ -336,13 +664,13 @@ impl TurnServer {
         attrs: &HashMap<u16, Vec<u8>>,
         raw: &[u8],
     ) {
-        if !self.verify_message_integrity(raw, attrs) {
+        if self.verify_message_integrity(raw, attrs).is_none() {
             let resp = build_error_response(REFRESH_RESPONSE | 0x0010, txn_id, 401, "Unauthorized");
             let _ = socket.send_to(&resp, src).await;
             return;
         }
 
-        let lifetime = extract_lifetime(attrs).unwrap_or(DEFAULT_LIFETIME).min(MAX_LIFETIME);
+        let lifetime = self.lifetime(attrs);
 
         {
             let mut allocs = self.allocations.write().unwrap();
@@ -373,7 +701,7 @@ impl TurnServer {
         attrs: &HashMap<u16, Vec<u8>>,
         raw: &[u8],
     ) {
-        if !self.verify_message_integrity(raw, attrs) {
+        if self.verify_message_integrity(raw, attrs).is_none() {
             return;
         }
 
@@ -405,7 +733,7 @@ impl TurnServer {
         attrs: &HashMap<u16, Vec<u8>>,
         raw: &[u8],
     ) {
-        if !self.verify_message_integrity(raw, attrs) {
+        if self.verify_message_integrity(raw, attrs).is_none() {
             return;
         }
 
@@ -467,7 +795,7 @@ impl TurnServer {
         let relay = {
             let allocs = self.allocations.read().unwrap();
             allocs.get(&src).and_then(|alloc| {
-                if alloc.permissions.contains_key(&peer_addr.ip()) {
+                if alloc.permissions.contains_key(&peer_addr.ip()) && alloc.meter.admit(payload.len()) {
                     Some(alloc.relay_socket.clone())
                 } else {
                     None
@@ -500,7 +828,8 @@ impl TurnServer {
         let target = {
             let allocs = self.allocations.read().unwrap();
             allocs.get(&src).and_then(|alloc| {
-                alloc.channels.get(&channel).map(|&peer| (alloc.relay_socket.clone(), peer))
+                let peer = *alloc.channels.get(&channel)?;
+                alloc.meter.admit(payload.len()).then(|| (alloc.relay_socket.clone(), peer))
             })
         }; // lock dropped
 
@@ -510,20 +839,21 @@ impl TurnServer {
     }
 
     /// Verify MESSAGE-INTEGRITY on a STUN message.
-    fn verify_message_integrity(&self, raw: &[u8], attrs: &HashMap<u16, Vec<u8>>) -> bool {
-        // Check username matches
-        if let Some(username_data) = attrs.get(&ATTR_USERNAME) {
-            let username = String::from_utf8_lossy(username_data);
-            if username != self.config.username {
-                return false;
-            }
+    fn verify_message_integrity(&self, raw: &[u8], attrs: &HashMap<u16, Vec<u8>>) -> Option<Credential> {
+        // A live session's credential, or the shared one if allowed
+        let username = String::from_utf8_lossy(attrs.get(&ATTR_USERNAME)?);
+        let (credential, key) = if let Some(id) = self.sessions.session_of(&username) {
+            let password = self.sessions.password(&username);
+            (Credential::Session(id), compute_long_term_key(&username, &self.config.realm, &password))
+        } else if !self.config.require_session && username == self.config.username {
+            (Credential::Shared, self.hmac_key.clone())
         } else {
-            return false;
-        }
+            return None;
+        };
 
         let integrity = match attrs.get(&ATTR_MESSAGE_INTEGRITY) {
             Some(data) if data.len() == 20 => data,
-            _ => return false,
+            _ => return None,
         };
 
         // Find position of MESSAGE-INTEGRITY attribute in raw data
@@ -531,7 +861,7 @@ impl TurnServer {
         // with the length field adjusted to include MESSAGE-INTEGRITY (24 bytes: 4 header + 20 value)
         let mi_pos = find_attr_position(raw, ATTR_MESSAGE_INTEGRITY);
         if mi_pos == 0 {
-            return false;
+            return None;
         }
 
         // Build the data to HMAC: header (with adjusted length) + attributes before MI
@@ -541,8 +871,8 @@ impl TurnServer {
         hmac_input[2] = (adjusted_len >> 8) as u8;
         hmac_input[3] = (adjusted_len & 0xFF) as u8;
 
-        let expected = compute_hmac_sha1(&self.hmac_key, &hmac_input);
-        expected == integrity.as_slice()
+        let expected = compute_hmac_sha1(&key, &hmac_input);
+        (expected == integrity.as_slice()).then_some(credential)
     }
 
     /// Run TURN-over-TCP for a single connection.
@@ -617,7 +947,8 @@ impl TurnServer {
                 let target = {
                     let allocs = self.allocations.read().unwrap();
                     allocs.get(&addr).and_then(|alloc| {
-                        alloc.channels.get(&channel).map(|&peer| (alloc.relay_socket.clone(), peer))
+                        let peer = *alloc.channels.get(&channel)?;
+                        alloc.meter.admit(data_len).then(|| (alloc.relay_socket.clone(), peer))
                     })
                 };
                 if let Some((relay_socket, peer_addr)) = target {
@@ -668,20 +999,23 @@ impl TurnServer {
                     let resp = self.handle_allocate_tcp(addr, &txn_id, &attrs, &full_msg).await;
                     // After successful allocation, spawn relay-back task
                     {
-                        let relay_socket = {
+                        let relay = {
                             let allocs = self.allocations.read().unwrap();
-                            allocs.get(&addr).map(|a| a.relay_socket.clone())
+                            allocs.get(&addr).map(|a| (a.relay_socket.clone(), a.closed.subscribe()))
                         };
-                        if let Some(relay_socket) = relay_socket {
+                        if let Some((relay_socket, mut on_closed)) = relay {
                             let tx = relay_tx.clone();
                             let allocs_ref = self.allocations.clone();
                             let client_addr = addr;
                             tokio::spawn(async move {
                                 let mut rbuf = vec![0u8; 65536];
                                 loop {
-                                    let (n, peer_addr) = match relay_socket.recv_from(&mut rbuf).await {
-                                        Ok(r) => r,
-                                        Err(_) => break,
+                                    let (n, peer_addr) = tokio::select! {
+                                        received = relay_socket.recv_from(&mut rbuf) => match received {
+                                            Ok(r) => r,
+                                            Err(_) => break,
+                                        },
+                                        _ = on_closed.changed() => break,
                                     };
 
                                     // Look up permission + channel, drop lock, then build frame
@@ -691,7 +1025,7 @@ impl TurnServer {
                                             Some(a) => a,
                                             None => break,
                                         };
-                                        if !alloc.permissions.contains_key(&peer_addr.ip()) {
+                                        if !alloc.permissions.contains_key(&peer_addr.ip()) || !alloc.meter.admit(n) {
                                             continue;
                                         }
                                         alloc.channel_rev.get(&peer_addr).copied()
@@ -722,10 +1056,10 @@ impl TurnServer {
                     Some(resp)
                 }
                 REFRESH_REQUEST => {
-                    if !self.verify_message_integrity(&full_msg, &attrs) {
+                    if self.verify_message_integrity(&full_msg, &attrs).is_none() {
                         continue;
                     }
-                    let lifetime = extract_lifetime(&attrs).unwrap_or(DEFAULT_LIFETIME).min(MAX_LIFETIME);
+                    let lifetime = self.lifetime(&attrs);
                     {
                         let mut allocs = self.allocations.write().unwrap();
                         if let Some(alloc) = allocs.get_mut(&addr) {
@@ -741,7 +1075,7 @@ impl TurnServer {
                     Some(build_stun_message_with_integrity(REFRESH_RESPONSE, &txn_id, &resp_attrs, &self.hmac_key))
                 }
                 CREATE_PERMISSION_REQUEST => {
-                    if !self.verify_message_integrity(&full_msg, &attrs) {
+                    if self.verify_message_integrity(&full_msg, &attrs).is_none() {
                         continue;
                     }
                     {
@@ -758,7 +1092,7 @@ impl TurnServer {
                     Some(build_stun_message_with_integrity(CREATE_PERMISSION_RESPONSE, &txn_id, &[], &self.hmac_key))
                 }
                 CHANNEL_BIND_REQUEST => {
-                    if !self.verify_message_integrity(&full_msg, &attrs) {
+                    if self.verify_message_integrity(&full_msg, &attrs).is_none() {
                         continue;
                     }
                     let channel_number = match attrs.get(&ATTR_CHANNEL_NUMBER) {
@@ -795,7 +1129,7 @@ impl TurnServer {
                         let relay = {
                             let allocs = self.allocations.read().unwrap();
                             allocs.get(&addr).and_then(|alloc| {
-                                if alloc.permissions.contains_key(&peer.ip()) {
+                                if alloc.permissions.contains_key(&peer.ip()) && alloc.meter.admit(payload.len()) {
                                     Some(alloc.relay_socket.clone())
                                 } else {
                                     None
@@ -848,9 +1182,9 @@ impl TurnServer {
             return build_stun_message(ALLOCATE_ERROR, txn_id, &resp_attrs);
         }
 
-        if !self.verify_message_integrity(raw, attrs) {
+        let Some(credential) = self.verify_message_integrity(raw, attrs) else {
             return build_error_response(ALLOCATE_ERROR, txn_id, 401, "Unauthorized");
-        }
+        };
 
         let relay_socket = match UdpSocket::bind("0.0.0.0:0").await {
             Ok(s) => Arc::new(s),
@@ -862,7 +1196,7 @@ impl TurnServer {
 
         let relay_local = relay_socket.local_addr().unwrap();
         let relay_addr = SocketAddr::new(self.config.public_ip, relay_local.port());
-        let lifetime = extract_lifetime(attrs).unwrap_or(DEFAULT_LIFETIME).min(MAX_LIFETIME);
+        let lifetime = self.lifetime(attrs);
 
         info!("TURN TCP: allocation created for {} -> relay {} (lifetime {}s)", src, relay_addr, lifetime);
 
@@ -872,7 +1206,8 @@ impl TurnServer {
         // For simplicity, we store the relay socket and the main handle_tcp_connection
         // loop doesn't read from it. Instead we spawn a task that does.
         // TCP relay-back is handled by the caller checking for incoming relay data.
-
+        // That task subscribes to `closed` to stop with the allocation.
+        let (closed, _) = watch::channel(());
         {
             let mut allocs = self.allocations.write().unwrap();
             allocs.insert(src, Allocation {
@@ -882,6 +1217,9 @@ impl TurnServer {
                 channels: HashMap::new(),
                 channel_rev: HashMap::new(),
                 expires: Instant::now() + Duration::from_secs(lifetime as u64),
+                credential,
+                meter: RelayMeter::new(self.config.max_rate_bytes_per_sec),
+                closed,
             });
         }
 
@@ -1157,11 +1495,270 @@ fn compute_hmac_sha1(key: &[u8], data: &[u8]) -> Vec<u8> {
 }
 
 /// Generate a random nonce string.
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
 fn generate_nonce() -> String {
-    use std::time::SystemTime;
     let ts = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_nanos();
     format!("{:x}", ts)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> TurnConfig {
+        TurnConfig {
+            udp_port: 0,
+            public_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            realm: "haven".into(),
+            username: "user".into(),
+            password: "pass".into(),
+            require_session: true,
+            max_rate_bytes_per_sec: DEFAULT_ALLOCATION_BYTES_PER_SEC,
+            default_lifetime_secs: DEFAULT_LIFETIME_SECS,
+            max_lifetime_secs: MAX_LIFETIME_SECS,
+            session_grace_secs: 0,
+        }
+    }
+
+    /// A relay serving UDP on a loopback port.
+    async fn spawn_turn(config: TurnConfig) -> (Arc<TurnServer>, SocketAddr) {
+        let turn = Arc::new(TurnServer::new(config));
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let running = turn.clone();
+        tokio::spawn(async move { running.serve_udp(socket).await });
+        (turn, addr)
+    }
+
+    /// A session's username and the long-term key its client signs with.
+    fn session_key(session: &TurnSession) -> (String, Vec<u8>) {
+        let (username, password) = session.credential();
+        let key = compute_long_term_key(&username, "haven", &password);
+        (username, key)
+    }
+
+    /// Send an authenticated Allocate from `client` and return the error
+    /// code, or `None` on success.
+    async fn allocate(client: &UdpSocket, server: SocketAddr, (username, key): &(String, Vec<u8>)) -> Option<u16> {
+        let txn_id = [7u8; 12];
+        let mut attrs = Vec::new();
+        append_string_attr(&mut attrs, ATTR_USERNAME, username);
+        attrs.extend_from_slice(&ATTR_REQUESTED_TRANSPORT.to_be_bytes());
+        attrs.extend_from_slice(&4u16.to_be_bytes());
+        attrs.extend_from_slice(&[17, 0, 0, 0]);
+        let request = build_stun_message_with_integrity(ALLOCATE_REQUEST, &txn_id, &attrs, key);
+        client.send_to(&request, server).await.unwrap();
+
+        let mut buf = [0u8; 1500];
+        let (n, _) = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+            .await
+            .expect("no reply to Allocate")
+            .unwrap();
+        match u16::from_be_bytes([buf[0], buf[1]]) {
+            ALLOCATE_RESPONSE => None,
+            ALLOCATE_ERROR => {
+                let code = &parse_attributes(&buf[STUN_HEADER_SIZE..n])[&ATTR_ERROR_CODE];
+                Some(code[2] as u16 * 100 + code[3] as u16)
+            }
+            other => panic!("unexpected reply 0x{:04x}", other),
+        }
+    }
+
+    /// Permit `peer` on `client`'s allocation.
+    async fn create_permission(
+        client: &UdpSocket,
+        server: SocketAddr,
+        (username, key): &(String, Vec<u8>),
+        peer: SocketAddr,
+    ) {
+        let txn_id = [9u8; 12];
+        let mut attrs = Vec::new();
+        append_string_attr(&mut attrs, ATTR_USERNAME, username);
+        append_xor_peer_address(&mut attrs, peer, &txn_id);
+        let request = build_stun_message_with_integrity(CREATE_PERMISSION_REQUEST, &txn_id, &attrs, key);
+        client.send_to(&request, server).await.unwrap();
+        let mut buf = [0u8; 1500];
+        tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+            .await
+            .expect("no reply to CreatePermission")
+            .unwrap();
+        assert_eq!(u16::from_be_bytes([buf[0], buf[1]]), CREATE_PERMISSION_RESPONSE);
+    }
+
+    /// Relay `payload` to `peer` with a Send indication.
+    async fn send_indication(client: &UdpSocket, server: SocketAddr, peer: SocketAddr, payload: &[u8]) {
+        let txn_id = [0u8; 12];
+        let mut attrs = Vec::new();
+        append_xor_peer_address(&mut attrs, peer, &txn_id);
+        append_data_attr(&mut attrs, payload);
+        client.send_to(&build_stun_message(SEND_INDICATION, &txn_id, &attrs), server).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_allocation_needs_a_live_session_credential() {
+        let (turn, server) = spawn_turn(config()).await;
+        let shared = ("user".to_string(), turn.hmac_key.clone());
+        let client = || async { UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap() };
+
+        // The shared credentials no longer allocate, even from the IP of a
+        // client with a gateway session.
+        let session = turn.sessions().register();
+        assert_eq!(allocate(&client().await, server, &shared).await, Some(401));
+
+        let own = session_key(&session);
+        assert_eq!(allocate(&client().await, server, &own).await, None);
+        assert_eq!(turn.allocations.read().unwrap().len(), 1);
+
+        // Another session's username with the wrong password, or an expired
+        // username signed properly, is refused.
+        let other = turn.sessions().register();
+        let forged = (other.credential().0, own.1.clone());
+        assert_eq!(allocate(&client().await, server, &forged).await, Some(401));
+        let (_, id) = own.0.split_once(':').unwrap();
+        let expired = format!("{}:{}", unix_now() - 1, id);
+        let password = turn.sessions().password(&expired);
+        let expired_key = compute_long_term_key(&expired, "haven", &password);
+        assert_eq!(allocate(&client().await, server, &(expired, expired_key)).await, Some(401));
+
+        // Once the session closes its credential is dead.
+        drop(session);
+        assert_eq!(allocate(&client().await, server, &own).await, Some(401));
+        turn.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_shared_credentials_allocate_without_required_sessions() {
+        let (turn, server) = spawn_turn(TurnConfig { require_session: false, ..config() }).await;
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        assert_eq!(allocate(&client, server, &("user".to_string(), turn.hmac_key.clone())).await, None);
+        turn.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_udp_relay_stops_on_shutdown() {
+        let turn = Arc::new(TurnServer::new(config()));
+        let running = turn.clone();
+        let task = tokio::spawn(async move { running.run_udp(0).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!task.is_finished());
+
+        turn.shutdown();
+        let result = tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("relay did not stop within a second")
+            .unwrap();
+        assert!(result.is_ok());
+
+        // Starting after shutdown returns straight away.
+        let again = tokio::time::timeout(Duration::from_secs(1), turn.run_udp(0)).await;
+        assert!(matches!(again, Ok(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn test_each_allocation_is_metered_and_capped_separately() {
+        let (turn, server) = spawn_turn(TurnConfig { max_rate_bytes_per_sec: 4_000, ..config() }).await;
+        let session = turn.sessions().register();
+        let key = session_key(&session);
+        let peer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let greedy = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let modest = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        for client in [&greedy, &modest] {
+            assert_eq!(allocate(client, server, &key).await, None);
+            create_permission(client, server, &key, peer_addr).await;
+        }
+
+        // The greedy client floods 20 KB into a 4 KB/s allocation; the
+        // other's 3 KB still gets through in full.
+        for _ in 0..20 {
+            send_indication(&greedy, server, peer_addr, &[0xA; 1000]).await;
+        }
+        for _ in 0..3 {
+            send_indication(&modest, server, peer_addr, &[0xB; 1000]).await;
+        }
+        let mut received = [0u64; 2];
+        let mut buf = [0u8; 1500];
+        while let Ok(Ok((_, _))) = tokio::time::timeout(Duration::from_millis(200), peer.recv_from(&mut buf)).await {
+            received[(buf[0] == 0xB) as usize] += 1;
+        }
+        assert_eq!(received[1], 3);
+        assert!((4..=5).contains(&received[0]), "{:?}", received);
+
+        let stats = turn.allocation_stats();
+        let of = |client: &UdpSocket| {
+            let addr = client.local_addr().unwrap();
+            *stats.iter().find(|s| s.client == addr).unwrap()
+        };
+        let (greedy, modest) = (of(&greedy), of(&modest));
+        assert_eq!((greedy.packets, greedy.bytes, greedy.dropped), (received[0], received[0] * 1000, 20 - received[0]));
+        assert_eq!((modest.packets, modest.bytes, modest.dropped), (3, 3000, 0));
+        turn.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_allocations_go_on_expiry_and_when_the_session_ends() {
+        let (turn, server) = spawn_turn(TurnConfig { default_lifetime_secs: 1, ..config() }).await;
+        let session = turn.sessions().register();
+        let key = session_key(&session);
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            clients.push(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap());
+        }
+        for client in &clients {
+            assert_eq!(allocate(client, server, &key).await, None);
+        }
+        assert_eq!(turn.allocations.read().unwrap().len(), 3);
+
+        // Nobody refreshed within the configured second.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(reap_expired(&turn.allocations), 3);
+        assert!(turn.allocations.read().unwrap().is_empty());
+
+        // Two sessions from the same IP: closing one only tears down the
+        // allocation made with its credential.
+        let second = turn.sessions().register();
+        assert_eq!(allocate(&clients[0], server, &key).await, None);
+        assert_eq!(allocate(&clients[1], server, &session_key(&second)).await, None);
+        drop(session);
+        let left: Vec<SocketAddr> = turn.allocations.read().unwrap().keys().copied().collect();
+        assert_eq!(left, [clients[1].local_addr().unwrap()]);
+        drop(second);
+        assert!(turn.allocations.read().unwrap().is_empty());
+        turn.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_allocations_outlive_a_closed_session_until_its_grace_runs_out() {
+        let (turn, server) = spawn_turn(TurnConfig { session_grace_secs: 1, ..config() }).await;
+        let session = turn.sessions().register();
+        let (id, key) = (session.id(), session_key(&session));
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        assert_eq!(allocate(&client, server, &key).await, None);
+
+        // The client drops off and reconnects within the grace period: the
+        // new connection resumes the session, credential and all.
+        drop(session);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let resumed = turn.sessions().resume(id).expect("session still in its grace period");
+        assert_eq!(session_key(&resumed), key);
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        assert_eq!(turn.allocations.read().unwrap().len(), 1);
+
+        // Closed for good, it lasts the grace period and no longer.
+        drop(resumed);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(turn.allocations.read().unwrap().len(), 1);
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        assert!(turn.allocations.read().unwrap().is_empty());
+        assert!(turn.sessions().resume(id).is_none());
+        let other = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        assert_eq!(allocate(&other, server, &key).await, Some(401));
+        turn.shutdown();
+    }
+}
@@ -0,0 +1,48 @@
+//! Gateway protocol version handshake.
+//!
+//! A client names the command/event schema it speaks with
+//! `/gateway?protocol_version=N`. The server accepts anything in
+//! `MIN_GATEWAY_PROTOCOL_VERSION..=GATEWAY_PROTOCOL_VERSION`, echoes the
+//! range in `Ready`, and closes other connections with
+//! [`CLOSE_UNSUPPORTED_PROTOCOL`] and a reason the client can show, so an
+//! out-of-date app can ask for an update instead of failing command by
+//! command.
+
+use haven_types::events::{GATEWAY_PROTOCOL_VERSION, MIN_GATEWAY_PROTOCOL_VERSION};
+
+/// WebSocket close code for a client whose protocol version the server
+/// doesn't speak (4000-4999 are for applications).
+pub const CLOSE_UNSUPPORTED_PROTOCOL: u16 = 4001;
+
+/// The version to serve a client asking for `requested`, or the close
+/// reason if it's out of range. Clients that send none predate the
+/// handshake and speak `MIN_GATEWAY_PROTOCOL_VERSION`.
+pub fn negotiate(requested: Option<u32>) -> Result<u32, String> {
+    let version = requested.unwrap_or(MIN_GATEWAY_PROTOCOL_VERSION);
+    if (MIN_GATEWAY_PROTOCOL_VERSION..=GATEWAY_PROTOCOL_VERSION).contains(&version) {
+        return Ok(version);
+    }
+    let advice = if version > GATEWAY_PROTOCOL_VERSION { "the server needs updating" } else { "please update the app" };
+    Err(format!(
+        "Unsupported gateway protocol version {}: server speaks {} to {}, {}",
+        version, MIN_GATEWAY_PROTOCOL_VERSION, GATEWAY_PROTOCOL_VERSION, advice
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(None), Ok(MIN_GATEWAY_PROTOCOL_VERSION));
+        assert_eq!(negotiate(Some(GATEWAY_PROTOCOL_VERSION)), Ok(GATEWAY_PROTOCOL_VERSION));
+
+        let too_new = negotiate(Some(GATEWAY_PROTOCOL_VERSION + 1)).unwrap_err();
+        assert!(too_new.contains("server needs updating"), "{too_new}");
+        let too_old = negotiate(Some(MIN_GATEWAY_PROTOCOL_VERSION - 1)).unwrap_err();
+        assert!(too_old.contains("update the app"), "{too_old}");
+        // A close frame's reason must fit in its 123 bytes.
+        assert!(negotiate(Some(u32::MAX)).unwrap_err().len() <= 123);
+    }
+}
@@ -0,0 +1,43 @@
+//! Log output.
+//!
+//! Text on stderr by default. `HAVEN_LOG_FORMAT=json` switches to one JSON
+//! object per line for shipping to ELK/Loki: event fields sit at the top
+//! level beside `timestamp`, `level`, `target` and `message`, and the
+//! enclosing `request` span is listed under `spans`.
+
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+/// Install the global subscriber, writing to stderr (unbuffered, so logs
+/// appear immediately even when redirected to a file). `RUST_LOG` overrides
+/// `default_filter`; `HAVEN_LOG_FORMAT=json` (any case) selects JSON.
+pub fn init(default_filter: &str) {
+    let builder = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| default_filter.into()));
+    match std::env::var("HAVEN_LOG_FORMAT") {
+        Ok(v) if v.eq_ignore_ascii_case("json") => builder.json().flatten_event(true).with_current_span(false).init(),
+        _ => builder.init(),
+    }
+}
+
+/// Span for one HTTP request, for `TraceLayer::make_span_with`. Keeps an
+/// inbound `x-request-id` from a proxy so its logs line up with ours, or
+/// mints one.
+pub fn http_request_span<B>(request: &axum::http::Request<B>) -> Span {
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    // tower-http's own span target, so existing `tower_http=` filters still
+    // govern it.
+    tracing::debug_span!(
+        target: "tower_http::trace::make_span",
+        "request",
+        request_id = %request_id,
+        method = %request.method(),
+        uri = %request.uri(),
+    )
+}
@@ -1,3 +1,5 @@
+mod logging;
+
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -13,12 +15,11 @@ use axum::{
 };
 use futures_util::{TryStreamExt, SinkExt, StreamExt};
 use reqwest::Client;
-use jsonwebtoken::{DecodingKey, Validation, decode};
 use serde::Deserialize;
 use socket2::{Domain, Protocol, Socket, Type};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{info, warn};
 
 use haven_api::admin;
 use haven_api::auth::{self, AppState, AppStateInner, AuthRateLimiter};
@@ -26,31 +27,49 @@ use haven_api::files;
 use haven_api::messages;
 use haven_api::middleware::{require_auth, JwtSecret, Claims};
 use haven_api::reactions;
+use haven_api::sent::SentMessages;
+use haven_gateway::conn_limit::{self, ConnectionLimits, ConnectionSlot, Rejected};
 use haven_gateway::connection;
 use haven_gateway::dispatcher::Dispatcher;
-use haven_gateway::turn::{TurnConfig, TurnServer as TurnRelay};
+use haven_gateway::rate_limit::{Limit, RateLimitConfig};
+use haven_gateway::reconnect::{self, ReconnectTokens, Session};
+use haven_gateway::relay_auth;
+use haven_gateway::metrics;
+use haven_gateway::turn::{self, TurnConfig, TurnServer as TurnRelay, TurnSessions};
 
 use haven_types::PLACEHOLDER_SECRETS;
+use haven_types::jwt::TokenScope;
+use haven_types::ready::{Readiness, probe_writable};
 
 /// RFC 5764: STUN/TURN messages have first byte in 0x00..=0x3F (first 2 bits = 00).
 /// HTTP requests start with ASCII letters (0x41+). Used for TCP multiplexing.
 const STUN_FIRST_BYTE_MAX: u8 = 0x3F;
 
+/// How long shutdown waits for the TURN UDP listener to wind down.
+const TURN_STOP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
 #[derive(Clone)]
 struct ServerState {
     app: AppState,
     dispatcher: Dispatcher,
     jwt_secret: String,
+    token_scope: TokenScope,
     file_server_url: Option<String>,
     file_server_internal_url: Option<String>,
     http_client: Client,
     turn_servers: Option<Vec<haven_types::events::TurnServer>>,
+    turn_sessions: Option<TurnSessions>,
+    reconnect_tokens: ReconnectTokens,
+    connection_limits: ConnectionLimits,
 }
 
 /// Query parameters for the WebSocket upgrade endpoint.
 #[derive(Debug, Deserialize)]
 struct GatewayQuery {
     token: Option<String>,
+    /// Gateway protocol version the client speaks (see `version`); absent
+    /// from clients that predate the handshake.
+    protocol_version: Option<u32>,
 }
 
 #[tokio::main]
@@ -58,14 +77,8 @@ async fn main() -> anyhow::Result<()> {
     // Load .env if present
     let _ = dotenvy::dotenv();
 
-    // Init logging (stderr is unbuffered — logs appear immediately even when redirected to file)
-    tracing_subscriber::fmt()
-        .with_writer(std::io::stderr)
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "haven=debug,tower_http=debug".into()),
-        )
-        .init();
+    // Init logging (text, or JSON with HAVEN_LOG_FORMAT=json)
+    logging::init("haven=debug,tower_http=debug");
 
     // Config -- JWT secret is MANDATORY
     let jwt_secret = std::env::var("HAVEN_JWT_SECRET").unwrap_or_default();
@@ -76,6 +89,8 @@ async fn main() -> anyhow::Result<()> {
         eprintln!("       Set it in your .env file and restart.");
         std::process::exit(1);
     }
+    let token_scope = TokenScope::from_env();
+    token_scope.log_config();
 
     let db_path = std::env::var("HAVEN_DB_PATH").unwrap_or_else(|_| "haven.db".into());
     let host = std::env::var("HAVEN_HOST").unwrap_or_else(|_| "0.0.0.0".into());
@@ -98,17 +113,85 @@ async fn main() -> anyhow::Result<()> {
         .and_then(|v| v.parse().ok())
         .unwrap_or(2 * 1024 * 1024 * 1024);
 
+    // Reader pool size: default to one per core, clamped so a tiny VPS still
+    // gets some read concurrency and a big box doesn't hoard file handles.
+    // An explicit setting is held to the same range.
+    let db_readers: usize = {
+        const RANGE: std::ops::RangeInclusive<usize> = 2..=16;
+        let default = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(haven_db::DEFAULT_READER_POOL_SIZE)
+            .clamp(*RANGE.start(), *RANGE.end());
+        match std::env::var("HAVEN_DB_READERS") {
+            Ok(value) => match value.trim().parse::<usize>() {
+                Ok(n) if RANGE.contains(&n) => n,
+                Ok(n) => {
+                    let clamped = n.clamp(*RANGE.start(), *RANGE.end());
+                    warn!("HAVEN_DB_READERS={} is outside {:?}, using {}", n, RANGE, clamped);
+                    clamped
+                }
+                Err(_) => {
+                    warn!("Ignoring invalid HAVEN_DB_READERS={:?}, using {}", value, default);
+                    default
+                }
+            },
+            Err(_) => default,
+        }
+    };
+
     // Init database (Arc-wrapped for sharing between API + gateway connection handlers)
-    let db = Arc::new(haven_db::Database::open(&PathBuf::from(&db_path))?);
+    let db = Arc::new(haven_db::Database::open_with_readers(&PathBuf::from(&db_path), db_readers)?);
+
+    // Per-user gateway command limits: "<per_sec>" or "<per_sec>:<burst>"
+    let mut rate_limits = RateLimitConfig::default();
+    for (var, limit) in [
+        ("HAVEN_GATEWAY_RATE_CONTROL", &mut rate_limits.control),
+        ("HAVEN_GATEWAY_RATE_VOICE", &mut rate_limits.voice),
+        ("HAVEN_GATEWAY_RATE_FILE", &mut rate_limits.file),
+    ] {
+        if let Ok(value) = std::env::var(var) {
+            match Limit::parse(&value) {
+                Some(parsed) => *limit = parsed,
+                None => warn!("Ignoring invalid {}={:?}, using {}/s", var, value, limit.per_sec),
+            }
+        }
+    }
+
+    // Concurrent gateway connections, in total and per client IP (0 = no cap)
+    let connection_limits = {
+        let [max_total, max_per_ip] = [
+            ("HAVEN_GATEWAY_MAX_CONNECTIONS", conn_limit::DEFAULT_MAX_CONNECTIONS),
+            ("HAVEN_GATEWAY_MAX_CONNECTIONS_PER_IP", conn_limit::DEFAULT_MAX_CONNECTIONS_PER_IP),
+        ]
+        .map(|(var, default)| match std::env::var(var) {
+            Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+                warn!("Ignoring invalid {}={:?}, using {}", var, value, default);
+                default
+            }),
+            Err(_) => default,
+        });
+        info!("Gateway connection limits: {} total, {} per IP (0 = unlimited)", max_total, max_per_ip);
+        ConnectionLimits::new(max_total, max_per_ip)
+    };
 
     // Shared state
-    let dispatcher = Dispatcher::new();
+    let dispatcher = Dispatcher::with_rate_limits(rate_limits);
+    // Offers replayed to reconnecting clients must still be relayable.
+    match db.list_open_offers(relay_auth::AGREEMENT_TTL.as_secs() as i64) {
+        Ok(offers) => {
+            let restored = dispatcher.transfer_agreements().restore(&offers);
+            info!("Restored {} file offer(s) for relaying", restored);
+        }
+        Err(e) => warn!("Failed to restore file offers for relaying: {}", e),
+    }
     let app_state: AppState = Arc::new(AppStateInner {
         db: db.clone(),
         jwt_secret: jwt_secret.clone(),
+        token_scope: token_scope.clone(),
         dispatcher: dispatcher.clone(),
         auth_rate_limiter: AuthRateLimiter::new(),
         uploads_dir: uploads_dir.clone(),
+        sent_messages: SentMessages::default(),
     });
 
     let jwt_extension = JwtSecret(Arc::from(jwt_secret.as_str()));
@@ -125,20 +208,51 @@ async fn main() -> anyhow::Result<()> {
     }
 
     // ── TURN relay ────────────────────────────────────────────────────
-    let (turn_servers_for_state, turn_relay_arc) = if let (Ok(turn_user), Ok(turn_pass), Ok(public_ip)) = (
+    let (turn_servers_for_state, turn_relay_arc, turn_udp_task) = if let (Ok(turn_user), Ok(turn_pass), Ok(public_ip)) = (
         std::env::var("HAVEN_TURN_USER"),
         std::env::var("HAVEN_TURN_PASS"),
         std::env::var("HAVEN_PUBLIC_IP"),
     ) {
         let public_ip: std::net::IpAddr = public_ip.parse()?;
 
+        // Bytes/s per allocation, both directions (0 = no cap), the
+        // allocation lifetime granted by default and at most, and how long
+        // a closed connection's allocations wait for it to reconnect, in
+        // seconds
+        let [max_rate_bytes_per_sec, default_lifetime_secs, max_lifetime_secs, session_grace_secs] = [
+            ("HAVEN_TURN_MAX_RATE", turn::DEFAULT_ALLOCATION_BYTES_PER_SEC),
+            ("HAVEN_TURN_DEFAULT_LIFETIME", turn::DEFAULT_LIFETIME_SECS as u64),
+            ("HAVEN_TURN_MAX_LIFETIME", turn::MAX_LIFETIME_SECS as u64),
+            ("HAVEN_TURN_SESSION_GRACE", turn::DEFAULT_SESSION_GRACE_SECS as u64),
+        ]
+        .map(|(var, default)| match std::env::var(var) {
+            Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+                warn!("Ignoring invalid {}={:?}, using {}", var, value, default);
+                default
+            }),
+            Err(_) => default,
+        });
+        let [default_lifetime_secs, max_lifetime_secs, session_grace_secs] =
+            [default_lifetime_secs, max_lifetime_secs, session_grace_secs]
+                .map(|secs| u32::try_from(secs).unwrap_or(u32::MAX));
+
         let turn_config = TurnConfig {
             udp_port: port,  // advertise gateway port for UDP too (same port, UDP vs TCP don't conflict)
             public_ip,
             realm: "haven".to_string(),
             username: turn_user.clone(),
             password: turn_pass.clone(),
+            // Only a gateway session's own credential allocates; "0" also
+            // lets anyone holding the shared credentials relay.
+            require_session: !std::env::var("HAVEN_TURN_REQUIRE_SESSION").is_ok_and(|v| v.trim() == "0"),
+            max_rate_bytes_per_sec,
+            default_lifetime_secs,
+            max_lifetime_secs,
+            session_grace_secs,
         };
+        if !turn_config.require_session {
+            warn!("TURN accepts the shared credentials outside gateway sessions (HAVEN_TURN_REQUIRE_SESSION=0)");
+        }
 
         let turn_relay = std::sync::Arc::new(TurnRelay::new(turn_config));
 
@@ -146,7 +260,7 @@ async fn main() -> anyhow::Result<()> {
         // UDP and TCP don't conflict on the same port number
         let turn_udp = turn_relay.clone();
         let udp_port = port;
-        tokio::spawn(async move {
+        let turn_udp_task = tokio::spawn(async move {
             if let Err(e) = turn_udp.run_udp(udp_port).await {
                 tracing::error!("TURN UDP listener failed: {}", e);
             }
@@ -162,10 +276,10 @@ async fn main() -> anyhow::Result<()> {
             credential: turn_pass,
         }];
 
-        (Some(servers), Some(turn_relay))
+        (Some(servers), Some(turn_relay), Some(turn_udp_task))
     } else {
         info!("TURN relay not configured (set HAVEN_TURN_USER, HAVEN_TURN_PASS, HAVEN_PUBLIC_IP to enable)");
-        (None, None)
+        (None, None, None)
     };
 
     let http_client = Client::builder().no_proxy().build()?;
@@ -174,12 +288,31 @@ async fn main() -> anyhow::Result<()> {
         app: app_state.clone(),
         dispatcher: dispatcher.clone(),
         jwt_secret: jwt_secret.clone(),
+        token_scope: token_scope.clone(),
         file_server_url,
         file_server_internal_url,
         http_client: http_client.clone(),
         turn_servers: turn_servers_for_state,
+        turn_sessions: turn_relay_arc.as_ref().map(|turn| turn.sessions().clone()),
+        reconnect_tokens: dispatcher.reconnect_tokens().clone(),
+        connection_limits,
     };
 
+    // Expired reconnect tokens and transfer agreements are refused on use,
+    // and expired message nonces ignored; this just frees them.
+    let reconnect_tokens = state.reconnect_tokens.clone();
+    let transfer_agreements = dispatcher.transfer_agreements().clone();
+    let sent_messages = app_state.sent_messages.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            reconnect_tokens.purge_expired();
+            transfer_agreements.purge_expired();
+            sent_messages.purge_expired();
+        }
+    });
+
     // CORS -- restrict to known origins; extend via HAVEN_CORS_ORIGINS env var
     // #23: CSRF protection is not needed because this is a Tauri desktop app that
     // uses Bearer token authentication (not cookies). Browsers automatically attach
@@ -192,6 +325,8 @@ async fn main() -> anyhow::Result<()> {
     let public_routes = Router::new()
         .route("/auth/register", post(auth::register))
         .route("/auth/login", post(auth::login))
+        .route("/health", get(health))
+        .route("/ready", get(ready))
         .with_state(app_state.clone());
 
     // Create uploads directory for file storage
@@ -238,6 +373,7 @@ async fn main() -> anyhow::Result<()> {
         let admin_state = admin::AdminState {
             admin_secret,
             jwt_secret: jwt_secret.clone(),
+            token_scope: token_scope.clone(),
             db: db.clone(),
             dispatcher: dispatcher.clone(),
             http_client: http_client.clone(),
@@ -256,6 +392,8 @@ async fn main() -> anyhow::Result<()> {
             .route("/admin/users", get(admin::list_users))
             .route("/admin/users/{id}", delete(admin::delete_user))
             .route("/admin/kick/{user_id}", post(admin::kick_user))
+            .route("/admin/connections", get(admin::list_connections))
+            .route("/admin/connections/{conn_id}", delete(admin::kick_connection))
             .route("/admin/voice", get(admin::get_voice_state))
             .route("/admin/messages", get(admin::list_messages))
             .route("/admin/offers", get(admin::list_offers))
@@ -285,6 +423,24 @@ async fn main() -> anyhow::Result<()> {
         None
     };
 
+    // ── Metrics ─────────────────────────────────────────────────────────
+    // Unauthenticated, so it only ever listens on its own (internal) address.
+    if let Ok(metrics_addr) = std::env::var("HAVEN_METRICS_ADDR") {
+        let metrics_addr: SocketAddr = metrics_addr.parse()?;
+        let metrics_app = Router::new()
+            .route("/metrics", get(metrics::serve_metrics))
+            .with_state(dispatcher.clone());
+        let metrics_listener = tokio::net::TcpListener::bind(metrics_addr).await?;
+        info!("Metrics endpoint listening on http://{}/metrics", metrics_addr);
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(metrics_listener, metrics_app).await {
+                tracing::error!("Metrics listener failed: {}", e);
+            }
+        });
+    } else {
+        info!("HAVEN_METRICS_ADDR not set -- metrics endpoint disabled");
+    }
+
     let mut app = Router::new()
         .merge(public_routes)
         .merge(protected_routes)
@@ -298,9 +454,10 @@ async fn main() -> anyhow::Result<()> {
 
     let app = app
         .layer(axum::Extension(jwt_extension))
+        .layer(axum::Extension(token_scope))
         .layer(DefaultBodyLimit::max(max_body_size))
         .layer(cors)
-        .layer(TraceLayer::new_for_http());
+        .layer(TraceLayer::new_for_http().make_span_with(logging::http_request_span));
 
     let addr: SocketAddr = format!("{}:{}", host, port).parse()?;
     info!("Haven server listening on {}", addr);
@@ -371,6 +528,13 @@ async fn main() -> anyhow::Result<()> {
                 }
             }
         }
+
+        turn_relay.shutdown();
+        if let Some(task) = turn_udp_task
+            && tokio::time::timeout(TURN_STOP_TIMEOUT, task).await.is_err()
+        {
+            tracing::warn!("TURN UDP listener did not stop within {:?}", TURN_STOP_TIMEOUT);
+        }
     } else {
         // No TURN — standard axum serve
         axum::serve(
@@ -420,48 +584,119 @@ fn build_cors_layer() -> CorsLayer {
 }
 
 /// #6: WebSocket upgrade with JWT authentication BEFORE upgrading.
-/// The token is extracted from `?token=` query param or Authorization header.
-/// If invalid, a 401 is returned without upgrading the connection.
+/// The token is extracted from `?token=` query param or Authorization header;
+/// `Authorization: Reconnect <token>` resumes with a reconnect token instead.
+/// If invalid, a 401 is returned without upgrading the connection. A client
+/// past the connection limits gets 503 before its token is even checked.
 async fn ws_upgrade(
     State(state): State<ServerState>,
-    ConnectInfo(_addr): ConnectInfo<SocketAddr>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Query(query): Query<GatewayQuery>,
     headers: axum::http::HeaderMap,
     ws: WebSocketUpgrade,
 ) -> Result<impl IntoResponse, axum::http::StatusCode> {
+    let slot = state.connection_limits.try_acquire(addr.ip()).map_err(|rejected| {
+        state.dispatcher.metrics().connection_rejected();
+        match rejected {
+            Rejected::Total => warn!(
+                "Refusing gateway upgrade from {}: {} connections open",
+                addr,
+                state.connection_limits.open()
+            ),
+            Rejected::PerIp => warn!("Refusing gateway upgrade from {}: per-IP connection cap reached", addr),
+        }
+        axum::http::StatusCode::SERVICE_UNAVAILABLE
+    })?;
+
+    let authorization = headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok());
+    if let Some(reconnect_token) = authorization.and_then(|s| s.strip_prefix(reconnect::AUTH_SCHEME)) {
+        let identity = state
+            .reconnect_tokens
+            .redeem(reconnect_token, &state.token_scope)
+            .ok_or(axum::http::StatusCode::UNAUTHORIZED)?;
+        info!(
+            "{} ({}) resuming with reconnect token from connection {}",
+            identity.session.username, identity.session.user_id, identity.conn_id
+        );
+        return Ok(upgrade_gateway(state, ws, slot, identity.session, query.protocol_version));
+    }
+
     // Extract token from query param or Authorization header
-    let token = query.token.or_else(|| {
-        headers
-            .get(AUTHORIZATION)
-            .and_then(|v| v.to_str().ok())
-            .and_then(|s| s.strip_prefix("Bearer "))
-            .map(|s| s.to_string())
-    });
+    let token = query
+        .token
+        .or_else(|| authorization.and_then(|s| s.strip_prefix("Bearer ")).map(|s| s.to_string()));
 
     let token = token.ok_or(axum::http::StatusCode::UNAUTHORIZED)?;
 
     // Validate JWT before upgrading
-    let token_data = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
-        &Validation::default(),
-    )
-    .map_err(|_| axum::http::StatusCode::UNAUTHORIZED)?;
+    let claims = state
+        .token_scope
+        .decode(&token, &state.jwt_secret)
+        .map_err(|_| axum::http::StatusCode::UNAUTHORIZED)?;
 
-    let user_id = token_data.claims.sub;
-    let username = token_data.claims.username;
+    info!("{} ({}) pre-authenticated for WebSocket upgrade", claims.username, claims.sub);
 
-    info!("{} ({}) pre-authenticated for WebSocket upgrade", username, user_id);
+    let session = Session::from_claims(&claims, &state.token_scope);
+    Ok(upgrade_gateway(state, ws, slot, session, query.protocol_version))
+}
 
+/// Upgrade to the gateway protocol. `slot`, and the TURN session whose
+/// credential the client gets in Ready, are held until the connection ends.
+fn upgrade_gateway(
+    state: ServerState,
+    ws: WebSocketUpgrade,
+    slot: ConnectionSlot,
+    mut session: Session,
+    protocol_version: Option<u32>,
+) -> Response {
     let file_server_url = state.file_server_url.clone();
-    let turn_servers = state.turn_servers.clone();
     let db = state.app.db.clone();
-    Ok(ws
+    // A reconnect picks up the TURN session of the connection it replaces,
+    // if that is still in its grace period
+    let turn_session = state.turn_sessions.as_ref().map(|sessions| {
+        session.turn_session.and_then(|id| sessions.resume(id)).unwrap_or_else(|| sessions.register())
+    });
+    session.turn_session = turn_session.as_ref().map(|turn| turn.id());
+    let turn_servers = state.turn_servers.clone().map(|mut servers| {
+        if let Some(session) = &turn_session {
+            let (username, credential) = session.credential();
+            for server in &mut servers {
+                server.username.clone_from(&username);
+                server.credential.clone_from(&credential);
+            }
+        }
+        servers
+    });
+    ws
         .max_frame_size(4 * 1024 * 1024)    // 4 MB max frame (supports larger chunk sizes)
         .max_message_size(8 * 1024 * 1024) // 8 MB max message
-        .on_upgrade(move |socket| {
-            connection::handle_connection_authenticated(socket, state.dispatcher, user_id, username, file_server_url, turn_servers, Some(db))
-        }))
+        .on_upgrade(move |socket| async move {
+            let _slot = slot;
+            let _turn_session = turn_session;
+            connection::handle_connection_authenticated(socket, state.dispatcher, session, protocol_version, file_server_url, turn_servers, Some(db)).await
+        })
+        .into_response()
+}
+
+// ── Health endpoints ─────────────────────────────────────────────────
+
+/// GET /health — liveness check (no auth).
+async fn health() -> &'static str {
+    "ok"
+}
+
+/// GET /ready — readiness check (no auth): DB reachable and uploads dir
+/// writable. `200` if both pass, else `503`; the body lists each check.
+async fn ready(State(state): State<AppState>) -> (StatusCode, axum::Json<Readiness>) {
+    let mut report = Readiness::default();
+    report.check(
+        "database",
+        state.db.with_conn(|conn| Ok(conn.query_row("SELECT 1", [], |_| Ok(()))?)),
+    );
+    report.check("uploads", probe_writable(&state.uploads_dir).await);
+
+    let status = if report.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, axum::Json(report))
 }
 
 // ── Pending offers endpoint ──────────────────────────────────────────
@@ -673,3 +908,102 @@ async fn ft_fast_transfer_ws(
         }
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use haven_db::Database;
+    use tokio_tungstenite::tungstenite::Error as WsError;
+
+    const SECRET: &str = "test-secret";
+
+    fn token() -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let claims = Claims {
+            sub: uuid::Uuid::new_v4(),
+            username: "tester".into(),
+            exp: (now + 3600) as usize,
+            iss: None,
+            aud: None,
+        };
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    /// The gateway route alone, over a fresh DB, admitting `limits`.
+    async fn spawn_gateway(dir: &std::path::Path, limits: ConnectionLimits) -> SocketAddr {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let dispatcher = Dispatcher::new();
+        let token_scope = TokenScope::default();
+        let app = Arc::new(AppStateInner {
+            db: Arc::new(Database::open(&dir.join("test.db")).unwrap()),
+            jwt_secret: SECRET.into(),
+            token_scope: token_scope.clone(),
+            dispatcher: dispatcher.clone(),
+            auth_rate_limiter: AuthRateLimiter::new(),
+            uploads_dir: dir.to_path_buf(),
+            sent_messages: SentMessages::default(),
+        });
+        let state = ServerState {
+            app,
+            dispatcher: dispatcher.clone(),
+            jwt_secret: SECRET.into(),
+            token_scope,
+            file_server_url: None,
+            file_server_internal_url: None,
+            http_client: Client::new(),
+            turn_servers: None,
+            turn_sessions: None,
+            reconnect_tokens: dispatcher.reconnect_tokens().clone(),
+            connection_limits: limits,
+        };
+        let router = Router::new().route("/gateway", get(ws_upgrade)).with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = router.into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+        addr
+    }
+
+    async fn upgrade(
+        addr: SocketAddr,
+    ) -> Result<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>, WsError> {
+        let url = format!("ws://{addr}/gateway?token={}", token());
+        tokio_tungstenite::connect_async(url).await.map(|(ws, _)| ws)
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_past_connection_limit_gets_503() {
+        let dir = std::env::temp_dir().join(format!("haven-server-conn-limit-{}", std::process::id()));
+        let limits = ConnectionLimits::new(2, 0);
+        let addr = spawn_gateway(&dir, limits.clone()).await;
+
+        let mut first = upgrade(addr).await.unwrap();
+        let _second = upgrade(addr).await.unwrap();
+        assert_eq!(limits.open(), 2);
+        match upgrade(addr).await {
+            Err(WsError::Http(response)) => assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE),
+            other => panic!("expected 503, got {:?}", other.map(|_| ())),
+        }
+
+        // Closing one frees its slot for the next client.
+        first.close(None).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while limits.open() > 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        upgrade(addr).await.unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -14,6 +14,27 @@ pub struct Claims {
     pub sub: Uuid,
     pub username: String,
     pub exp: usize,
+    /// Issuing service, when `HAVEN_JWT_ISSUER` is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    /// Intended audience, when `HAVEN_JWT_AUDIENCE` is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+}
+
+/// JWT claims for admin sessions, minted by haven-api's `/admin/login` and
+/// accepted by the file server's admin routes. Distinct from user Claims --
+/// admin tokens carry `admin: true` and no user identity.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminClaims {
+    pub admin: bool,
+    pub exp: usize,
+    /// Issuing service, when `HAVEN_JWT_ISSUER` is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    /// Intended audience, when `HAVEN_JWT_AUDIENCE` is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
 }
 
 // -- Auth --
@@ -52,9 +73,13 @@ pub struct LoginResponse {
 pub struct SendMessageRequest {
     pub ciphertext: String,
     pub nonce: String,
+    /// Client-chosen ID for this send, echoed in `MessageAck`. Resending
+    /// with the same one returns the first message rather than posting again.
+    #[serde(default)]
+    pub client_nonce: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct MessageResponse {
     pub id: Uuid,
     pub channel_id: Uuid,
@@ -64,6 +89,8 @@ pub struct MessageResponse {
     pub nonce: String,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub reactions: Vec<ReactionGroup>,
+    /// Per-channel sequence number (see `GatewayCommand::Resume`)
+    pub seq: u64,
 }
 
 // -- Reactions --
@@ -88,18 +115,27 @@ pub struct ReactionGroup {
 #[serde(rename_all = "lowercase")]
 pub enum TransferStatus {
     Uploading,
+    /// Cut off by a server shutdown; the uploader may resume it.
+    Interrupted,
     Complete,
+    /// Every chunk arrived but the assembled file doesn't match `file_sha256`.
+    Corrupt,
     Confirmed,
     Expired,
+    /// The uploader called off a fast upload; its partial file is deleted.
+    Cancelled,
 }
 
 impl fmt::Display for TransferStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Uploading => write!(f, "uploading"),
+            Self::Interrupted => write!(f, "interrupted"),
             Self::Complete => write!(f, "complete"),
+            Self::Corrupt => write!(f, "corrupt"),
             Self::Confirmed => write!(f, "confirmed"),
             Self::Expired => write!(f, "expired"),
+            Self::Cancelled => write!(f, "cancelled"),
         }
     }
 }
@@ -109,9 +145,12 @@ impl FromStr for TransferStatus {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "uploading" => Ok(Self::Uploading),
+            "interrupted" => Ok(Self::Interrupted),
             "complete" => Ok(Self::Complete),
+            "corrupt" => Ok(Self::Corrupt),
             "confirmed" => Ok(Self::Confirmed),
             "expired" => Ok(Self::Expired),
+            "cancelled" => Ok(Self::Cancelled),
             other => Err(format!("unknown transfer status: {}", other)),
         }
     }
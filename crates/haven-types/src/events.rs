@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -16,6 +18,45 @@ pub struct TurnServer {
     pub credential: String,
 }
 
+/// A user's status as shown to others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceStatus {
+    #[default]
+    Online,
+    Away,
+    Busy,
+    /// Disconnected, or connected but appearing offline
+    Offline,
+}
+
+/// Relay counters for one voice channel participant, as seen by the gateway.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoicePeerStats {
+    pub user_id: Uuid,
+    /// Audio frames received from this peer and relayed to the channel
+    pub frames_relayed: u64,
+    pub bytes_relayed: u64,
+    /// Frames skipped in this peer's sequence on the way to the server
+    pub frames_lost: u64,
+    /// Frames that arrived after a later sequence number; these were already
+    /// counted in `frames_lost` when the gap opened
+    pub frames_late: u64,
+    /// Frames addressed to this peer that were dropped because their queue was full
+    pub frames_dropped: u64,
+    /// Interarrival jitter of this peer's frames at the server, in milliseconds
+    #[serde(default)]
+    pub jitter_ms: u32,
+}
+
+/// Version of the gateway command/event schema this build speaks. Bump it
+/// when a change would break a peer that ignores unknown fields and events.
+pub const GATEWAY_PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest client protocol version the server still serves. Clients that
+/// predate the version handshake send none and are taken to speak this.
+pub const MIN_GATEWAY_PROTOCOL_VERSION: u32 = 1;
+
 /// Events sent over the WebSocket gateway.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data")]
@@ -26,6 +67,23 @@ pub enum GatewayEvent {
         username: String,
         #[serde(default, skip_serializing_if = "Option::is_none")]
         turn_servers: Option<Vec<TurnServer>>,
+        /// Protocol versions the server accepts; 0 from servers that
+        /// predate the handshake
+        #[serde(default)]
+        min_protocol_version: u32,
+        #[serde(default)]
+        max_protocol_version: u32,
+        /// The version this connection was accepted at
+        #[serde(default)]
+        protocol_version: u32,
+    },
+
+    /// Sent after `Ready`: a single-use token that opens the next gateway
+    /// connection as this user (`Authorization: Reconnect <token>` on the
+    /// upgrade) without a JWT. Never expires after the session's JWT would.
+    ReconnectToken {
+        token: String,
+        expires_at: chrono::DateTime<chrono::Utc>,
     },
 
     /// A new encrypted message was posted
@@ -37,6 +95,17 @@ pub enum GatewayEvent {
         ciphertext: String,
         nonce: String,
         timestamp: chrono::DateTime<chrono::Utc>,
+        /// Per-channel sequence number; pass the last one seen to `Resume`
+        seq: u64,
+    },
+
+    /// Sent to the author once a message tagged with `client_nonce` is
+    /// stored (again on a duplicate resend), so the client can mark it sent
+    MessageAck {
+        client_nonce: String,
+        message_id: Uuid,
+        channel_id: Uuid,
+        seq: u64,
     },
 
     /// A user started typing
@@ -46,11 +115,24 @@ pub enum GatewayEvent {
         username: String,
     },
 
-    /// A user came online or went offline
+    /// A user stopped typing: they said so, went quiet past the server's
+    /// typing timeout, or disconnected
+    TypingStop {
+        channel_id: Uuid,
+        user_id: Uuid,
+    },
+
+    /// A user came online, went offline or changed their status
     PresenceUpdate {
         user_id: Uuid,
         username: String,
+        /// False once they disconnect or while they appear `Offline`
         online: bool,
+        #[serde(default)]
+        status: PresenceStatus,
+        /// Free-form status text, e.g. "in a meeting"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        custom: Option<String>,
     },
 
     /// A reaction was added to a message
@@ -90,6 +172,34 @@ pub enum GatewayEvent {
         data: String,
     },
 
+    /// The client is sending commands of this category ("control", "voice",
+    /// "file") faster than allowed; excess commands are being dropped
+    RateLimited {
+        category: String,
+    },
+
+    /// Too many messages were missed in this channel to replay on `Resume`;
+    /// the client should refetch its history over REST
+    ResumeGap {
+        channel_id: Uuid,
+    },
+
+    /// Relay counters for every participant in the requester's voice channel
+    VoiceStats {
+        channel_id: Uuid,
+        peers: Vec<VoicePeerStats>,
+    },
+
+    /// Answer to a `Ping`, on the connection that sent it: `nonce` and
+    /// `client_ts` echoed, plus the server's clock (Unix milliseconds) when it
+    /// answered. RTT is now minus `client_ts`; the server clock is ahead by
+    /// roughly `server_ts - (client_ts + rtt / 2)`.
+    Pong {
+        nonce: String,
+        client_ts: i64,
+        server_ts: i64,
+    },
+
     /// A peer is offering to send a file
     FileOffer {
         from_user_id: Uuid,
@@ -110,6 +220,14 @@ pub enum GatewayEvent {
         folder_id: Option<String>,
     },
 
+    /// A channel-wide file offer went out; tells the sender who got it, so it
+    /// knows how many downloads to expect.
+    FileOfferBroadcastSent {
+        channel_id: Uuid,
+        transfer_id: String,
+        recipient_ids: Vec<Uuid>,
+    },
+
     /// A peer accepted a file transfer
     FileAccept {
         from_user_id: Uuid,
@@ -209,6 +327,14 @@ pub enum GatewayEvent {
         chunk_idx: u32,
     },
 
+    /// File server confirms a run of chunks at once: bit `i` of `bitmap`
+    /// (LSB-first) covers chunk `base_chunk + i`
+    FastChunkAckBitmap {
+        transfer_id: String,
+        base_chunk: u32,
+        bitmap: Vec<u8>,
+    },
+
     /// File server confirms all chunks received — upload complete
     FastUploadDone {
         transfer_id: String,
@@ -240,6 +366,7 @@ impl GatewayEvent {
         match self {
             Self::MessageCreate { channel_id, .. } => Some(*channel_id),
             Self::TypingStart { channel_id, .. } => Some(*channel_id),
+            Self::TypingStop { channel_id, .. } => Some(*channel_id),
             Self::VoiceStateUpdate { channel_id, .. } => Some(*channel_id),
             // Ready, PresenceUpdate, ReactionAdd/Remove, VoiceSignal, VoiceAudioData are global
             _ => None,
@@ -257,6 +384,9 @@ pub enum GatewayCommand {
     /// Indicate typing in a channel
     StartTyping { channel_id: Uuid },
 
+    /// Stop the typing indicator early (message sent or input cleared)
+    StopTyping { channel_id: Uuid },
+
     /// Join a voice channel
     VoiceJoin { channel_id: Uuid },
 
@@ -275,6 +405,31 @@ pub enum GatewayCommand {
     /// Send voice audio data to be relayed to other participants
     VoiceData { data: String },
 
+    /// After reconnecting, replay messages missed in subscribed channels.
+    /// Maps channel_id to the last `MessageCreate.seq` seen; send after Subscribe.
+    Resume { last_event_seq: HashMap<Uuid, u64> },
+
+    /// Request relay counters for the current voice channel (answered with VoiceStats)
+    VoiceStatsRequest,
+
+    /// Measure round-trip time and clock offset (answered with Pong). Unlike
+    /// the WebSocket-level heartbeat, the client sees the answer. `nonce` is
+    /// an opaque string (JSON numbers lose precision past 2^53 in Dart and
+    /// JS) and `client_ts` is echoed as is; Unix milliseconds lets the client
+    /// estimate the offset.
+    Ping { nonce: String, client_ts: i64 },
+
+    /// Set your status and custom status text for everyone to see. With
+    /// `idle_timeout_secs` the server sets you `Away` after that long without
+    /// a command from you, and back when one arrives; 0 turns that off.
+    SetPresence {
+        status: PresenceStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        custom: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        idle_timeout_secs: Option<u32>,
+    },
+
     /// Subscribe to events for specific channels.
     /// The server will only forward channel-scoped events (messages, typing, voice)
     /// for channels the client has subscribed to.
@@ -297,6 +452,22 @@ pub enum GatewayCommand {
         folder_id: Option<String>,
     },
 
+    /// Offer one uploaded file to every other subscriber of a channel. Each
+    /// recipient gets a `FileOffer` for the same transfer and downloads it on
+    /// its own.
+    FileOfferBroadcast {
+        channel_id: Uuid,
+        transfer_id: String,
+        filename: String,
+        size: u64,
+        /// SHA-256 hash of the full encrypted file
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        file_sha256: Option<String>,
+        /// Per-chunk SHA-256 hashes (in order)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chunk_hashes: Option<Vec<String>>,
+    },
+
     /// Accept a file transfer from a peer
     FileAcceptSend {
         target_user_id: Uuid,
@@ -392,6 +563,9 @@ pub enum GatewayCommand {
         chunk_size: u64,
         chunk_hashes: Vec<String>,
         file_sha256: String,
+        /// Chunks are zstd-compressed before encryption.
+        #[serde(default)]
+        compression: bool,
     },
 
     /// Sender retransmits specific frames (response to FastNack, handled natively)
@@ -0,0 +1,154 @@
+//! Issuer and audience checks for auth tokens.
+//!
+//! The messaging server, file server and file gateway share one signing
+//! secret, so the signature alone can't tell a token minted for one service
+//! from one minted for another. Setting `HAVEN_JWT_ISSUER` and/or
+//! `HAVEN_JWT_AUDIENCE` (to the same values on every service) stamps those
+//! claims into new tokens and makes every verifier require them. Unset,
+//! tokens carry neither and are checked as before.
+
+use jsonwebtoken::{DecodingKey, Validation};
+use serde::de::DeserializeOwned;
+
+use crate::api::{AdminClaims, Claims};
+
+pub const ISSUER_ENV: &str = "HAVEN_JWT_ISSUER";
+pub const AUDIENCE_ENV: &str = "HAVEN_JWT_AUDIENCE";
+
+/// Expected `iss`/`aud` for tokens; `None` means that claim isn't checked.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenScope {
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
+impl TokenScope {
+    /// Read [`ISSUER_ENV`] and [`AUDIENCE_ENV`]. Empty values count as unset.
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok().map(|v: String| v.trim().to_string()).filter(|v| !v.is_empty());
+        Self {
+            issuer: var(ISSUER_ENV),
+            audience: var(AUDIENCE_ENV),
+        }
+    }
+
+    /// Default validation (signature, `exp`) plus the configured claims,
+    /// which must then be present and match.
+    pub fn validation(&self) -> Validation {
+        let mut validation = Validation::default();
+        let mut required = vec!["exp"];
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            required.push("iss");
+        }
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+            required.push("aud");
+        }
+        validation.set_required_spec_claims(&required);
+        validation
+    }
+
+    /// Verify `token` against `secret` and this scope.
+    pub fn decode(&self, token: &str, secret: &str) -> jsonwebtoken::errors::Result<Claims> {
+        self.decode_as(token, secret)
+    }
+
+    /// [`decode`](Self::decode) for an admin token.
+    pub fn decode_admin(&self, token: &str, secret: &str) -> jsonwebtoken::errors::Result<AdminClaims> {
+        self.decode_as(token, secret)
+    }
+
+    fn decode_as<T: DeserializeOwned>(&self, token: &str, secret: &str) -> jsonwebtoken::errors::Result<T> {
+        jsonwebtoken::decode::<T>(token, &DecodingKey::from_secret(secret.as_bytes()), &self.validation())
+            .map(|data| data.claims)
+    }
+
+    /// Log at startup which claims are checked beyond the signature.
+    pub fn log_config(&self) {
+        match (&self.issuer, &self.audience) {
+            (None, None) => tracing::warn!(
+                "{}/{} unset: any token signed with the shared secret is accepted, whichever service minted it",
+                ISSUER_ENV,
+                AUDIENCE_ENV
+            ),
+            (issuer, audience) => tracing::info!("JWT issuer: {:?}, audience: {:?}", issuer, audience),
+        }
+    }
+
+    /// Stamp the configured claims into a token about to be minted.
+    pub fn apply(&self, claims: &mut Claims) {
+        claims.iss = self.issuer.clone();
+        claims.aud = self.audience.clone();
+    }
+
+    /// [`apply`](Self::apply) for an admin token.
+    pub fn apply_admin(&self, claims: &mut AdminClaims) {
+        claims.iss = self.issuer.clone();
+        claims.aud = self.audience.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use uuid::Uuid;
+
+    const SECRET: &str = "test-secret";
+
+    fn token(iss: Option<&str>, aud: Option<&str>) -> String {
+        let claims = Claims {
+            sub: Uuid::new_v4(),
+            username: "alice".into(),
+            exp: (chrono::Utc::now() + chrono::Duration::hours(1)).timestamp() as usize,
+            iss: iss.map(Into::into),
+            aud: aud.map(Into::into),
+        };
+        jsonwebtoken::encode(&Header::default(), &claims, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_issuer_and_audience_must_match() {
+        let scope = TokenScope { issuer: Some("haven".into()), audience: Some("haven-files".into()) };
+        assert!(scope.decode(&token(Some("haven"), Some("haven-files")), SECRET).is_ok());
+
+        assert!(scope.decode(&token(Some("other"), Some("haven-files")), SECRET).is_err(), "wrong issuer");
+        assert!(scope.decode(&token(Some("haven"), Some("haven-chat")), SECRET).is_err(), "wrong audience");
+        assert!(scope.decode(&token(None, Some("haven-files")), SECRET).is_err(), "missing issuer");
+        assert!(scope.decode(&token(Some("haven"), None), SECRET).is_err(), "missing audience");
+
+        let mut claims = scope.decode(&token(Some("haven"), Some("haven-files")), SECRET).unwrap();
+        claims.iss = None;
+        scope.apply(&mut claims);
+        assert_eq!(claims.iss.as_deref(), Some("haven"));
+    }
+
+    #[test]
+    fn test_admin_tokens_are_scoped_too() {
+        let scope = TokenScope { issuer: Some("haven".into()), audience: Some("haven-files".into()) };
+        let admin_token = |scope: &TokenScope| {
+            let mut claims = AdminClaims {
+                admin: true,
+                exp: (chrono::Utc::now() + chrono::Duration::hours(1)).timestamp() as usize,
+                iss: None,
+                aud: None,
+            };
+            scope.apply_admin(&mut claims);
+            jsonwebtoken::encode(&Header::default(), &claims, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap()
+        };
+        assert!(scope.decode_admin(&admin_token(&scope), SECRET).unwrap().admin);
+
+        let wrong_audience = TokenScope { audience: Some("haven-chat".into()), ..scope.clone() };
+        assert!(scope.decode_admin(&admin_token(&wrong_audience), SECRET).is_err(), "wrong audience");
+        assert!(scope.decode_admin(&admin_token(&TokenScope::default()), SECRET).is_err(), "unscoped");
+    }
+
+    #[test]
+    fn test_unconfigured_scope_accepts_plain_tokens() {
+        let scope = TokenScope::default();
+        assert!(scope.decode(&token(None, None), SECRET).is_ok());
+        assert!(scope.decode(&token(Some("anyone"), None), SECRET).is_ok());
+        assert!(scope.decode(&token(None, None), "other-secret").is_err());
+    }
+}
@@ -1,5 +1,7 @@
 pub mod api;
 pub mod events;
+pub mod jwt;
+pub mod ready;
 
 /// Placeholder JWT secrets that MUST NOT be used in production.
 /// Both servers validate against this list at startup and exit if matched.
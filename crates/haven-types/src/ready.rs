@@ -0,0 +1,47 @@
+//! Readiness reporting for `GET /ready` on both servers.
+//!
+//! `/health` only says the process is up; `/ready` runs each dependency
+//! check and reports them by name, so a load balancer can hold traffic back
+//! and an operator can see which dependency is down.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+/// Outcome of a readiness probe: `"ok"` or the error, per check.
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, String>,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self { ready: true, checks: BTreeMap::new() }
+    }
+}
+
+impl Readiness {
+    /// Record one check; any failure makes the whole report not ready.
+    pub fn check<E: Display>(&mut self, name: &'static str, result: Result<(), E>) {
+        let outcome = match result {
+            Ok(()) => "ok".to_string(),
+            Err(e) => {
+                self.ready = false;
+                e.to_string()
+            }
+        };
+        self.checks.insert(name, outcome);
+    }
+}
+
+/// Create and remove a scratch file in `dir` to prove it's writable.
+pub async fn probe_writable(dir: &Path) -> std::io::Result<()> {
+    static PROBE: AtomicU64 = AtomicU64::new(0);
+    let path = dir.join(format!(".ready-probe-{}", PROBE.fetch_add(1, Ordering::Relaxed)));
+    tokio::fs::write(&path, b"ok").await?;
+    tokio::fs::remove_file(&path).await
+}
@@ -18,6 +18,13 @@ class HavenConstants {
     return '$wsScheme://${uri.host}:${uri.port}/gateway';
   }
 
+  /// Gateway protocol version this client speaks (the server's
+  /// `GATEWAY_PROTOCOL_VERSION`), sent as `?protocol_version=`.
+  static const int gatewayProtocolVersion = 1;
+
+  /// WebSocket close code for a protocol version the server doesn't speak.
+  static const int closeUnsupportedProtocol = 4001;
+
   /// Default general channel ID (seeded in server migrations).
   static const String generalChannelId =
       '00000000-0000-0000-0000-000000000001';
@@ -50,8 +57,9 @@ class HavenConstants {
   /// Message fetch limit per page.
   static const int messageFetchLimit = 50;
 
-  /// Typing indicator timeout.
-  static const Duration typingTimeout = Duration(seconds: 5);
+  /// Typing indicator timeout. A fallback: the server sends TypingStop
+  /// after 8s of quiet, so keep this a little longer.
+  static const Duration typingTimeout = Duration(seconds: 10);
 
   /// Typing indicator throttle (don't send more than once per 3s).
   static const Duration typingThrottle = Duration(seconds: 3);
@@ -27,6 +27,9 @@ class Message {
   final String authorUsername;
   final String content;
   final String timestamp;
+
+  /// Per-channel sequence number from the server; 0 if unknown.
+  final int seq;
   final List<ReactionGroup> reactions;
   final String? imageData;
   final String? imageName;
@@ -43,6 +46,7 @@ class Message {
     required this.authorUsername,
     required this.content,
     required this.timestamp,
+    this.seq = 0,
     this.reactions = const [],
     this.imageData,
     this.imageName,
@@ -73,6 +77,7 @@ class Message {
       authorUsername: authorUsername,
       content: content ?? this.content,
       timestamp: timestamp,
+      seq: seq,
       reactions: reactions ?? this.reactions,
       imageData: imageData ?? this.imageData,
       imageName: imageName ?? this.imageName,
@@ -161,6 +161,8 @@ class MessageNotifier extends StateNotifier<MessageState> {
   /// Handle an incoming MessageCreate gateway event.
   Future<void> handleIncomingMessage(Map<String, dynamic> event) async {
     final data = event['data'] as Map<String, dynamic>;
+    // Replay and live broadcast can both deliver a message.
+    if (state.messages.any((m) => m.id == data['id'])) return;
     try {
       final plaintext = await CryptoService.decrypt(
         channelKey,
@@ -175,13 +177,14 @@ class MessageNotifier extends StateNotifier<MessageState> {
         authorUsername: data['author_username'] as String,
         content: parsed['content'] as String,
         timestamp: data['timestamp'] as String,
+        seq: data['seq'] as int? ?? 0,
         imageData: parsed['imageData'] as String?,
         imageName: parsed['imageName'] as String?,
         fileId: parsed['fileId'] as String?,
         fileName: parsed['fileName'] as String?,
         fileSize: parsed['fileSize'] as int?,
       );
-      state = state.copyWith(messages: [...state.messages, message]);
+      _insertInOrder(message);
     } catch (_) {
       final message = Message(
         id: data['id'] as String,
@@ -190,8 +193,9 @@ class MessageNotifier extends StateNotifier<MessageState> {
         authorUsername: data['author_username'] as String,
         content: '[Unable to decrypt]',
         timestamp: data['timestamp'] as String,
+        seq: data['seq'] as int? ?? 0,
       );
-      state = state.copyWith(messages: [...state.messages, message]);
+      _insertInOrder(message);
     }
   }
 
@@ -264,6 +268,20 @@ class MessageNotifier extends StateNotifier<MessageState> {
 
   // -- Internal --
 
+  /// Add a live message, placing it by `seq` if it arrived out of order.
+  void _insertInOrder(Message message) {
+    final messages = [...state.messages];
+    var at = messages.length;
+    while (message.seq > 0 &&
+        at > 0 &&
+        messages[at - 1].channelId == message.channelId &&
+        messages[at - 1].seq > message.seq) {
+      at--;
+    }
+    messages.insert(at, message);
+    state = state.copyWith(messages: messages);
+  }
+
   Future<List<Message>> _decryptMessages(List<dynamic> raw) async {
     final messages = <Message>[];
     for (final msg in raw) {
@@ -282,6 +300,7 @@ class MessageNotifier extends StateNotifier<MessageState> {
           authorUsername: map['author_username'] as String,
           content: parsed['content'] as String,
           timestamp: map['created_at'] as String,
+          seq: map['seq'] as int? ?? 0,
           reactions: (map['reactions'] as List<dynamic>?)
                   ?.map((r) =>
                       ReactionGroup.fromJson(r as Map<String, dynamic>))
@@ -301,6 +320,7 @@ class MessageNotifier extends StateNotifier<MessageState> {
           authorUsername: map['author_username'] as String,
           content: '[Unable to decrypt]',
           timestamp: map['created_at'] as String,
+          seq: map['seq'] as int? ?? 0,
           reactions: (map['reactions'] as List<dynamic>?)
                   ?.map((r) =>
                       ReactionGroup.fromJson(r as Map<String, dynamic>))
@@ -186,6 +186,9 @@ class _ChatScreenState extends ConsumerState<ChatScreen> {
         // Message input
         MessageInput(
           onSend: (content) async {
+            ref
+                .read(gatewayServiceProvider)
+                .stopTyping(HavenConstants.generalChannelId);
             await ref.read(messageProvider.notifier).sendMessage(content);
           },
           onTyping: () {
@@ -82,6 +82,11 @@ class _HomeScreenState extends ConsumerState<HomeScreen> {
       }
     });
 
+    gateway.on('TypingStop', (event) {
+      final data = event['data'] as Map<String, dynamic>;
+      ref.read(typingProvider.notifier).userStoppedTyping(data['user_id'] as String);
+    });
+
     gateway.on('ReactionAdd', (event) {
       ref.read(messageProvider.notifier).handleReactionAdd(event);
     });
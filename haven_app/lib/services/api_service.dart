@@ -1,6 +1,7 @@
 import 'dart:typed_data';
 
 import 'package:dio/dio.dart';
+import 'package:uuid/uuid.dart';
 
 import 'package:haven_app/config/constants.dart';
 
@@ -120,21 +121,38 @@ class ApiService {
     return response.data as List<dynamic>;
   }
 
+  /// Post a message. It's tagged with a fresh `client_nonce`, so when the
+  /// connection drops before the response arrives (or the first attempt is
+  /// still being stored) it can be resent without posting it twice; the
+  /// server also acks it over the gateway with `MessageAck`.
   Future<Map<String, dynamic>> sendMessage(
     String channelId,
     String ciphertext,
     String nonce,
   ) async {
-    final response = await _dio.post(
-      '/channels/$channelId/messages',
-      data: {
-        'ciphertext': ciphertext,
-        'nonce': nonce,
-      },
-    );
-    return response.data as Map<String, dynamic>;
+    final clientNonce = const Uuid().v4();
+    for (var attempt = 1;; attempt++) {
+      try {
+        final response = await _dio.post(
+          '/channels/$channelId/messages',
+          data: {
+            'ciphertext': ciphertext,
+            'nonce': nonce,
+            'client_nonce': clientNonce,
+          },
+        );
+        return response.data as Map<String, dynamic>;
+      } on DioException catch (e) {
+        final status = e.response?.statusCode;
+        final retryable = status == null || status == 409;
+        if (!retryable || attempt >= _sendAttempts) rethrow;
+        await Future.delayed(Duration(milliseconds: 500 * attempt));
+      }
+    }
   }
 
+  static const int _sendAttempts = 3;
+
   // -- Reactions --
 
   Future<Map<String, dynamic>> toggleReaction(
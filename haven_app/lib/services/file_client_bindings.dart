@@ -1,5 +1,6 @@
 import 'dart:ffi';
 import 'dart:io';
+import 'dart:typed_data';
 
 import 'package:ffi/ffi.dart';
 
@@ -21,6 +22,22 @@ final class TransferProgressResult extends Struct {
 
   @Uint8()
   external int state;
+
+  /// Smoothed throughput in bytes/s.
+  @Uint64()
+  external int rateBps;
+
+  /// Estimated seconds remaining, or 0 if unknown.
+  @Uint64()
+  external int etaSeconds;
+
+  /// Upload chunk waiting to be retried, or -1 ("retrying chunk N").
+  @Int64()
+  external int retryingChunk;
+
+  /// Chunk upload retries so far.
+  @Uint64()
+  external int retries;
 }
 
 // ── Transfer state constants (match Rust) ────────────────────────────────
@@ -32,6 +49,19 @@ class TransferState {
   static const int complete = 3;
   static const int error = 4;
   static const int cancelled = 5;
+  static const int paused = 6;
+}
+
+// ── Error codes (match Rust ErrorCode) ─────────────────────────────────
+
+class TransferErrorCode {
+  static const int none = 0;
+  static const int auth = 1;
+  static const int network = 2;
+  static const int hashMismatch = 3;
+  static const int fileIo = 4;
+  static const int cancelled = 5;
+  static const int protocol = 6;
 }
 
 // ── FFI typedefs ─────────────────────────────────────────────────────────
@@ -53,6 +83,25 @@ typedef _UploadFileDart = Pointer<Void> Function(
   Pointer<Utf8> salt,
 );
 
+typedef _UploadBufferNative = Pointer<Void> Function(
+  Pointer<Uint8> data,
+  Size dataLen,
+  Pointer<Utf8> serverUrl,
+  Pointer<Utf8> transferId,
+  Pointer<Utf8> jwtToken,
+  Pointer<Utf8> masterKey,
+  Pointer<Utf8> salt,
+);
+typedef _UploadBufferDart = Pointer<Void> Function(
+  Pointer<Uint8> data,
+  int dataLen,
+  Pointer<Utf8> serverUrl,
+  Pointer<Utf8> transferId,
+  Pointer<Utf8> jwtToken,
+  Pointer<Utf8> masterKey,
+  Pointer<Utf8> salt,
+);
+
 typedef _DownloadFileNative = Pointer<Void> Function(
   Pointer<Utf8> savePath,
   Pointer<Utf8> serverUrl,
@@ -74,12 +123,41 @@ typedef _DownloadFileDart = Pointer<Void> Function(
   Pointer<Utf8> chunkHashesJson,
 );
 
+typedef _VerifyFileNative = Pointer<Void> Function(
+  Pointer<Utf8> savePath,
+  Pointer<Utf8> masterKey,
+  Pointer<Utf8> salt,
+  Pointer<Utf8> fileSha256,
+  Pointer<Utf8> chunkHashesJson,
+);
+typedef _VerifyFileDart = _VerifyFileNative;
+
 typedef _CancelNative = Void Function(Pointer<Void> handle);
 typedef _CancelDart = void Function(Pointer<Void> handle);
 
 typedef _ProgressNative = TransferProgressResult Function(Pointer<Void> handle);
 typedef _ProgressDart = TransferProgressResult Function(Pointer<Void> handle);
 
+/// Native progress callback: `(bytesDone, bytesTotal, state, rateBps, etaSeconds)`.
+typedef ProgressCallbackNative = Void Function(
+  Uint64 bytesDone,
+  Uint64 bytesTotal,
+  Uint8 state,
+  Uint64 rateBps,
+  Uint64 etaSeconds,
+);
+
+typedef _SetCallbackNative = Void Function(
+  Pointer<Void> handle,
+  Pointer<NativeFunction<ProgressCallbackNative>> callback,
+  Uint32 deltaPermille,
+);
+typedef _SetCallbackDart = void Function(
+  Pointer<Void> handle,
+  Pointer<NativeFunction<ProgressCallbackNative>> callback,
+  int deltaPermille,
+);
+
 typedef _FreeNative = Void Function(Pointer<Void> handle);
 typedef _FreeDart = void Function(Pointer<Void> handle);
 
@@ -92,6 +170,27 @@ typedef _FreeStringDart = void Function(Pointer<Utf8> ptr);
 typedef _GetLastErrorNative = Pointer<Utf8> Function(Pointer<Void> handle);
 typedef _GetLastErrorDart = Pointer<Utf8> Function(Pointer<Void> handle);
 
+typedef _GetLastErrorCodeNative = Int32 Function(Pointer<Void> handle);
+typedef _GetLastErrorCodeDart = int Function(Pointer<Void> handle);
+
+typedef _GetLossHistogramJsonNative = Pointer<Utf8> Function(Pointer<Void> handle);
+typedef _GetLossHistogramJsonDart = Pointer<Utf8> Function(Pointer<Void> handle);
+
+typedef _DrainLogNative = Pointer<Utf8> Function(Pointer<Void> handle);
+typedef _DrainLogDart = Pointer<Utf8> Function(Pointer<Void> handle);
+
+typedef _GetPortNative = Uint16 Function(Pointer<Void> handle);
+typedef _GetPortDart = int Function(Pointer<Void> handle);
+
+typedef _FellBackNative = Uint8 Function(Pointer<Void> handle);
+typedef _FellBackDart = int Function(Pointer<Void> handle);
+
+typedef _SetRateCapNative = Void Function(Pointer<Void> handle, Uint64 bps);
+typedef _SetRateCapDart = void Function(Pointer<Void> handle, int bps);
+
+typedef _SetTokenNative = Void Function(Pointer<Void> handle, Pointer<Utf8> jwtToken);
+typedef _SetTokenDart = void Function(Pointer<Void> handle, Pointer<Utf8> jwtToken);
+
 typedef _ResumeUploadNative = Pointer<Void> Function(
   Pointer<Utf8> filePath,
   Pointer<Utf8> serverUrl,
@@ -116,22 +215,89 @@ typedef _ResumeUploadDart = Pointer<Void> Function(
 );
 
 // Fast transfer typedefs (same signatures as regular upload/download)
-typedef _FastUploadNative = _UploadFileNative;
-typedef _FastUploadDart = _UploadFileDart;
+typedef _FastUploadNative = Pointer<Void> Function(
+  Pointer<Utf8> filePath,
+  Pointer<Utf8> serverUrl,
+  Pointer<Utf8> transferId,
+  Pointer<Utf8> jwtToken,
+  Pointer<Utf8> masterKey,
+  Pointer<Utf8> salt,
+  Uint8 flags,
+  Uint32 chunkSize,
+);
+typedef _FastUploadDart = Pointer<Void> Function(
+  Pointer<Utf8> filePath,
+  Pointer<Utf8> serverUrl,
+  Pointer<Utf8> transferId,
+  Pointer<Utf8> jwtToken,
+  Pointer<Utf8> masterKey,
+  Pointer<Utf8> salt,
+  int flags,
+  int chunkSize,
+);
 typedef _FastDownloadNative = _DownloadFileNative;
+
+// haven_fast_upload flags (FAST_UPLOAD_* in the native crate)
+const _fastUploadCompress = 1;
+const _fastUploadSparse = 2;
 typedef _FastDownloadDart = _DownloadFileDart;
 
+typedef _SetRecipientsNative = Int32 Function(
+  Pointer<Utf8> serverUrl,
+  Pointer<Utf8> transferId,
+  Pointer<Utf8> jwtToken,
+  Uint32 recipients,
+);
+typedef _SetRecipientsDart = int Function(
+  Pointer<Utf8> serverUrl,
+  Pointer<Utf8> transferId,
+  Pointer<Utf8> jwtToken,
+  int recipients,
+);
+
+typedef _DeriveKeyArgon2idNative = Pointer<Utf8> Function(
+  Pointer<Utf8> password,
+  Pointer<Utf8> salt,
+  Uint32 memoryKib,
+  Uint32 iterations,
+  Uint32 parallelism,
+);
+typedef _DeriveKeyArgon2idDart = Pointer<Utf8> Function(
+  Pointer<Utf8> password,
+  Pointer<Utf8> salt,
+  int memoryKib,
+  int iterations,
+  int parallelism,
+);
+
+typedef _CryptoSelftestNative = Pointer<Utf8> Function();
+typedef _CryptoSelftestDart = Pointer<Utf8> Function();
+
 // ── Bindings class ───────────────────────────────────────────────────────
 
 class FileClientBindings {
   late final _UploadFileDart _uploadFile;
+  late final _UploadBufferDart _uploadBuffer;
   late final _DownloadFileDart _downloadFile;
+  late final _DownloadFileDart _downloadFileParallel;
+  late final _VerifyFileDart _verifyFile;
   late final _CancelDart _cancel;
+  late final _CancelDart _pause;
+  late final _CancelDart _resume;
+  late final _SetRateCapDart _setRateCap;
+  late final _SetTokenDart _setToken;
   late final _ProgressDart _progress;
+  late final _SetCallbackDart _setCallback;
   late final _FreeDart _free;
   late final _GetHashesJsonDart _getHashesJson;
   late final _FreeStringDart _freeString;
   late final _GetLastErrorDart _getLastError;
+  late final _GetLastErrorCodeDart _getLastErrorCode;
+  late final _GetLossHistogramJsonDart _getLossHistogramJson;
+  late final _DrainLogDart _drainLog;
+  late final _GetPortDart _getLocalPort;
+  late final _GetPortDart _getServerPort;
+  late final _FellBackDart _fellBack;
 
   // Resume upload
   late final _ResumeUploadDart _resumeUpload;
@@ -140,6 +306,13 @@ class FileClientBindings {
   late final _FastUploadDart _fastUpload;
   late final _FastDownloadDart _fastDownload;
 
+  // Transfer metadata
+  late final _SetRecipientsDart _setRecipients;
+
+  // Key derivation
+  late final _DeriveKeyArgon2idDart _deriveKeyArgon2id;
+  late final _CryptoSelftestDart _cryptoSelftest;
+
   static FileClientBindings? _instance;
 
   factory FileClientBindings() {
@@ -154,18 +327,50 @@ class FileClientBindings {
         .lookup<NativeFunction<_UploadFileNative>>('haven_upload_file')
         .asFunction<_UploadFileDart>();
 
+    _uploadBuffer = lib
+        .lookup<NativeFunction<_UploadBufferNative>>('haven_upload_buffer')
+        .asFunction<_UploadBufferDart>();
+
     _downloadFile = lib
         .lookup<NativeFunction<_DownloadFileNative>>('haven_download_file')
         .asFunction<_DownloadFileDart>();
+    _downloadFileParallel = lib
+        .lookup<NativeFunction<_DownloadFileNative>>(
+            'haven_download_file_parallel')
+        .asFunction<_DownloadFileDart>();
+
+    _verifyFile = lib
+        .lookup<NativeFunction<_VerifyFileNative>>('haven_verify_file')
+        .asFunction<_VerifyFileDart>();
 
     _cancel = lib
         .lookup<NativeFunction<_CancelNative>>('haven_transfer_cancel')
         .asFunction<_CancelDart>();
 
+    _pause = lib
+        .lookup<NativeFunction<_CancelNative>>('haven_transfer_pause')
+        .asFunction<_CancelDart>();
+
+    _resume = lib
+        .lookup<NativeFunction<_CancelNative>>('haven_transfer_resume')
+        .asFunction<_CancelDart>();
+
+    _setRateCap = lib
+        .lookup<NativeFunction<_SetRateCapNative>>('haven_transfer_set_rate_cap')
+        .asFunction<_SetRateCapDart>();
+
+    _setToken = lib
+        .lookup<NativeFunction<_SetTokenNative>>('haven_transfer_set_token')
+        .asFunction<_SetTokenDart>();
+
     _progress = lib
         .lookup<NativeFunction<_ProgressNative>>('haven_transfer_progress')
         .asFunction<_ProgressDart>();
 
+    _setCallback = lib
+        .lookup<NativeFunction<_SetCallbackNative>>('haven_transfer_set_callback')
+        .asFunction<_SetCallbackDart>();
+
     _free = lib
         .lookup<NativeFunction<_FreeNative>>('haven_transfer_free')
         .asFunction<_FreeDart>();
@@ -182,6 +387,30 @@ class FileClientBindings {
         .lookup<NativeFunction<_GetLastErrorNative>>('haven_get_last_error')
         .asFunction<_GetLastErrorDart>();
 
+    _getLastErrorCode = lib
+        .lookup<NativeFunction<_GetLastErrorCodeNative>>('haven_get_last_error_code')
+        .asFunction<_GetLastErrorCodeDart>();
+
+    _getLossHistogramJson = lib
+        .lookup<NativeFunction<_GetLossHistogramJsonNative>>('haven_transfer_loss_histogram_json')
+        .asFunction<_GetLossHistogramJsonDart>();
+
+    _drainLog = lib
+        .lookup<NativeFunction<_DrainLogNative>>('haven_transfer_drain_log')
+        .asFunction<_DrainLogDart>();
+
+    _getLocalPort = lib
+        .lookup<NativeFunction<_GetPortNative>>('haven_fast_transfer_local_port')
+        .asFunction<_GetPortDart>();
+
+    _getServerPort = lib
+        .lookup<NativeFunction<_GetPortNative>>('haven_fast_transfer_server_port')
+        .asFunction<_GetPortDart>();
+
+    _fellBack = lib
+        .lookup<NativeFunction<_FellBackNative>>('haven_fast_transfer_fell_back')
+        .asFunction<_FellBackDart>();
+
     _resumeUpload = lib
         .lookup<NativeFunction<_ResumeUploadNative>>('haven_resume_upload')
         .asFunction<_ResumeUploadDart>();
@@ -194,6 +423,18 @@ class FileClientBindings {
     _fastDownload = lib
         .lookup<NativeFunction<_FastDownloadNative>>('haven_fast_download')
         .asFunction<_FastDownloadDart>();
+
+    _setRecipients = lib
+        .lookup<NativeFunction<_SetRecipientsNative>>('haven_set_recipients')
+        .asFunction<_SetRecipientsDart>();
+
+    _deriveKeyArgon2id = lib
+        .lookup<NativeFunction<_DeriveKeyArgon2idNative>>('haven_derive_key_argon2id')
+        .asFunction<_DeriveKeyArgon2idDart>();
+
+    _cryptoSelftest = lib
+        .lookup<NativeFunction<_CryptoSelftestNative>>('haven_crypto_selftest')
+        .asFunction<_CryptoSelftestDart>();
   }
 
   static DynamicLibrary _loadLibrary() {
@@ -238,7 +479,43 @@ class FileClientBindings {
     }
   }
 
+  /// Start an upload from in-memory bytes (screenshots, clipboard blobs)
+  /// without writing a temp file. The native side copies [data] before
+  /// returning. Returns a native handle pointer.
+  Pointer<Void> uploadBuffer({
+    required Uint8List data,
+    required String serverUrl,
+    required String transferId,
+    required String jwtToken,
+    required String masterKey,
+    required String salt,
+  }) {
+    final pData = calloc<Uint8>(data.isEmpty ? 1 : data.length);
+    pData.asTypedList(data.length).setAll(0, data);
+    final pServerUrl = serverUrl.toNativeUtf8();
+    final pTransferId = transferId.toNativeUtf8();
+    final pJwtToken = jwtToken.toNativeUtf8();
+    final pMasterKey = masterKey.toNativeUtf8();
+    final pSalt = salt.toNativeUtf8();
+
+    try {
+      return _uploadBuffer(
+        pData, data.length, pServerUrl, pTransferId, pJwtToken, pMasterKey, pSalt,
+      );
+    } finally {
+      calloc.free(pData);
+      calloc.free(pServerUrl);
+      calloc.free(pTransferId);
+      calloc.free(pJwtToken);
+      calloc.free(pMasterKey);
+      calloc.free(pSalt);
+    }
+  }
+
   /// Start a download. Returns a native handle pointer.
+  ///
+  /// With [parallel], the file is fetched as independent chunks from the
+  /// server's manifest, several at once, each retried on its own.
   Pointer<Void> downloadFile({
     required String savePath,
     required String serverUrl,
@@ -248,6 +525,7 @@ class FileClientBindings {
     required String salt,
     required String fileSha256,
     required String chunkHashesJson,
+    bool parallel = false,
   }) {
     final pSavePath = savePath.toNativeUtf8();
     final pServerUrl = serverUrl.toNativeUtf8();
@@ -259,7 +537,7 @@ class FileClientBindings {
     final pChunkHashes = chunkHashesJson.toNativeUtf8();
 
     try {
-      return _downloadFile(
+      return (parallel ? _downloadFileParallel : _downloadFile)(
         pSavePath, pServerUrl, pTransferId, pJwtToken, pMasterKey, pSalt,
         pFileSha256, pChunkHashes,
       );
@@ -275,6 +553,34 @@ class FileClientBindings {
     }
   }
 
+  /// Re-verify a downloaded file against its chunk hashes without
+  /// contacting the server. Returns a native handle pointer that ends in
+  /// [TransferState.complete], or [TransferState.error] with the mismatched
+  /// chunk indices in [getLastError].
+  Pointer<Void> verifyFile({
+    required String savePath,
+    required String masterKey,
+    required String salt,
+    required String fileSha256,
+    required String chunkHashesJson,
+  }) {
+    final pSavePath = savePath.toNativeUtf8();
+    final pMasterKey = masterKey.toNativeUtf8();
+    final pSalt = salt.toNativeUtf8();
+    final pFileSha256 = fileSha256.toNativeUtf8();
+    final pChunkHashes = chunkHashesJson.toNativeUtf8();
+
+    try {
+      return _verifyFile(pSavePath, pMasterKey, pSalt, pFileSha256, pChunkHashes);
+    } finally {
+      calloc.free(pSavePath);
+      calloc.free(pMasterKey);
+      calloc.free(pSalt);
+      calloc.free(pFileSha256);
+      calloc.free(pChunkHashes);
+    }
+  }
+
   /// Resume an upload from a specific chunk. Skips hashing pass.
   Pointer<Void> resumeUpload({
     required String filePath,
@@ -313,7 +619,10 @@ class FileClientBindings {
     }
   }
 
-  /// Start a fast UDP blast upload. Same interface as uploadFile.
+  /// Start a fast UDP blast upload. Same interface as uploadFile, plus
+  /// [compress] to zstd-compress chunks before encryption, [sparse] to
+  /// skip sending all-zero chunks (ignored with [compress]) and [chunkSize]
+  /// in plaintext bytes (0 for the default; the server must accept it).
   Pointer<Void> fastUploadFile({
     required String filePath,
     required String serverUrl,
@@ -321,6 +630,9 @@ class FileClientBindings {
     required String jwtToken,
     required String masterKey,
     required String salt,
+    bool compress = false,
+    bool sparse = false,
+    int chunkSize = 0,
   }) {
     final pFilePath = filePath.toNativeUtf8();
     final pServerUrl = serverUrl.toNativeUtf8();
@@ -332,6 +644,8 @@ class FileClientBindings {
     try {
       return _fastUpload(
         pFilePath, pServerUrl, pTransferId, pJwtToken, pMasterKey, pSalt,
+        (compress ? _fastUploadCompress : 0) | (sparse ? _fastUploadSparse : 0),
+        chunkSize,
       );
     } finally {
       calloc.free(pFilePath);
@@ -383,10 +697,54 @@ class FileClientBindings {
   /// Cancel a transfer.
   void cancel(Pointer<Void> handle) => _cancel(handle);
 
+  /// Pause a transfer between chunks; state reads [TransferState.paused].
+  void pause(Pointer<Void> handle) => _pause(handle);
+
+  /// Resume a paused transfer from where it stopped.
+  void resume(Pointer<Void> handle) => _resume(handle);
+
+  /// Cap a fast upload at [bytesPerSecond], or lift the cap with 0. Can be
+  /// changed mid-transfer; downloads ignore it.
+  void setRateCap(Pointer<Void> handle, int bytesPerSecond) => _setRateCap(handle, bytesPerSecond);
+
+  /// Hand an upload a refreshed JWT so it outlives the one it started
+  /// with. Downloads ignore it.
+  void setToken(Pointer<Void> handle, String jwtToken) {
+    final pJwtToken = jwtToken.toNativeUtf8();
+    try {
+      _setToken(handle, pJwtToken);
+    } finally {
+      calloc.free(pJwtToken);
+    }
+  }
+
   /// Poll transfer progress.
   TransferProgressResult getProgress(Pointer<Void> handle) => _progress(handle);
 
-  /// Free a transfer handle. Must be called when done.
+  /// Push progress updates instead of polling [getProgress].
+  ///
+  /// The native side calls back from a worker thread, so this uses a
+  /// listener callable that posts to this isolate. Updates arrive every 50ms
+  /// or once progress moves by [deltaPermille] thousandths of the total (0
+  /// for the default 1%), and on every state change, with the smoothed rate
+  /// (bytes/s) and ETA (seconds, 0 if unknown) as in [getProgress]. The
+  /// caller owns the returned callable and must `close()` it after [free] or
+  /// [clearProgressCallback].
+  NativeCallable<ProgressCallbackNative> setProgressCallback(
+    Pointer<Void> handle,
+    void Function(int bytesDone, int bytesTotal, int state, int rateBps, int etaSeconds) onProgress, {
+    int deltaPermille = 0,
+  }) {
+    final callable = NativeCallable<ProgressCallbackNative>.listener(onProgress);
+    _setCallback(handle, callable.nativeFunction, deltaPermille);
+    return callable;
+  }
+
+  /// Stop push progress updates for a handle.
+  void clearProgressCallback(Pointer<Void> handle) => _setCallback(handle, nullptr, 0);
+
+  /// Free a transfer handle. Must be called when done. Also clears any
+  /// progress callback.
   void free(Pointer<Void> handle) => _free(handle);
 
   /// Returns the upload hashes JSON `{"file_sha256":"...","chunk_hashes":[...]}` once
@@ -411,4 +769,109 @@ class FileClientBindings {
       _freeString(ptr);
     }
   }
+
+  /// Returns the [TransferErrorCode] of the last error, or
+  /// [TransferErrorCode.none]. Branch and localize on this rather than
+  /// parsing [getLastError].
+  int getLastErrorCode(Pointer<Void> handle) => _getLastErrorCode(handle);
+
+  /// Returns the fast-download loss histogram JSON
+  /// `{"bucket_ms":N,"total":N,"peak":N,"lossy_fraction":F,"buckets":[...]}`
+  /// once the receiver has finished, or null if not available.
+  String? getLossHistogramJson(Pointer<Void> handle) {
+    final ptr = _getLossHistogramJson(handle);
+    if (ptr == nullptr) return null;
+    try {
+      return ptr.toDartString();
+    } finally {
+      _freeString(ptr);
+    }
+  }
+
+  /// Takes the fast-transfer events buffered since the last call as JSON
+  /// (`{"dropped":N,"events":[...]}`), clearing the buffer. Returns null if
+  /// the handle is null.
+  String? drainTransferLog(Pointer<Void> handle) {
+    final ptr = _drainLog(handle);
+    if (ptr == nullptr) return null;
+    try {
+      return ptr.toDartString();
+    } finally {
+      _freeString(ptr);
+    }
+  }
+
+  /// Returns the local UDP port a fast download receives on, or 0 before
+  /// its receiver has started (and for any other transfer).
+  int getLocalUdpPort(Pointer<Void> handle) => _getLocalPort(handle);
+
+  /// Returns the server UDP port a fast upload blasts to, or 0 before the
+  /// server has answered (and for any other transfer).
+  int getServerUdpPort(Pointer<Void> handle) => _getServerPort(handle);
+
+  /// Whether a fast transfer gave up on UDP and continued over plain HTTP.
+  bool fellBackToHttp(Pointer<Void> handle) => _fellBack(handle) != 0;
+
+  /// Tells the server a fan-out offer for [transferId] reached [recipients]
+  /// people, so it keeps the file until each has confirmed. Returns 0, or an
+  /// error code as for [getLastErrorCode]. Blocking: run it in an isolate.
+  int setRecipients({
+    required String serverUrl,
+    required String transferId,
+    required String jwtToken,
+    required int recipients,
+  }) {
+    final pServerUrl = serverUrl.toNativeUtf8();
+    final pTransferId = transferId.toNativeUtf8();
+    final pJwtToken = jwtToken.toNativeUtf8();
+    try {
+      return _setRecipients(pServerUrl, pTransferId, pJwtToken, recipients);
+    } finally {
+      calloc.free(pServerUrl);
+      calloc.free(pTransferId);
+      calloc.free(pJwtToken);
+    }
+  }
+
+  /// Derives a key from [password] with Argon2id. Returns hex of the
+  /// versioned key material (scheme byte 0x02 + 32-byte key), or null if the
+  /// parameters are invalid or [salt] is shorter than 8 bytes. Defaults
+  /// match the native `Argon2idParams::default()`. Blocking: run it in an
+  /// isolate.
+  String? deriveKeyArgon2id({
+    required String password,
+    required String salt,
+    int memoryKib = 19 * 1024,
+    int iterations = 2,
+    int parallelism = 1,
+  }) {
+    final pPassword = password.toNativeUtf8();
+    final pSalt = salt.toNativeUtf8();
+    try {
+      final ptr = _deriveKeyArgon2id(pPassword, pSalt, memoryKib, iterations, parallelism);
+      if (ptr == nullptr) return null;
+      try {
+        return ptr.toDartString();
+      } finally {
+        _freeString(ptr);
+      }
+    } finally {
+      calloc.free(pPassword);
+      calloc.free(pSalt);
+    }
+  }
+
+  /// Runs the native chunk-crypto self-test over pinned vectors. Returns
+  /// `{"ok":bool,"vectors":{"<name>":{"output":"<hex>","expected":"<hex>","ok":bool},...}}`
+  /// as JSON; `ok` false means this build's key or nonce derivation has
+  /// drifted from the server's.
+  String? cryptoSelftest() {
+    final ptr = _cryptoSelftest();
+    if (ptr == nullptr) return null;
+    try {
+      return ptr.toDartString();
+    } finally {
+      _freeString(ptr);
+    }
+  }
 }
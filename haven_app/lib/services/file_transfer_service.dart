@@ -2,6 +2,7 @@ import 'dart:async';
 import 'dart:convert';
 import 'dart:ffi';
 import 'dart:io';
+import 'dart:isolate';
 
 import 'package:dio/dio.dart';
 import 'package:uuid/uuid.dart';
@@ -41,6 +42,8 @@ class FileTransfer {
   bool uploadCompleteSent = false;
   // For error reporting: true once we've logged the error from the DLL
   bool errorLogged = false;
+  // For uploads: the JWT last handed to the native side
+  String? nativeToken;
 
   FileTransfer({
     required this.transferId,
@@ -183,6 +186,7 @@ class FileTransferService {
     try {
       _bindings = FileClientBindings();
       _log('INFO', 'FileClientBindings loaded successfully');
+      _checkCrypto(_bindings!);
       return _bindings;
     } catch (e) {
       _log('ERROR', 'FileClientBindings failed to load: $e');
@@ -190,6 +194,18 @@ class FileTransferService {
     }
   }
 
+  /// Log loudly if the native library's chunk crypto no longer matches the
+  /// pinned vectors, before a transfer fails on it.
+  void _checkCrypto(FileClientBindings bindings) {
+    final json = bindings.cryptoSelftest();
+    if (json == null) return;
+    final report = jsonDecode(json) as Map<String, dynamic>;
+    if (report['ok'] == true) return;
+    final vectors = report['vectors'] as Map<String, dynamic>;
+    final failed = vectors.entries.where((e) => e.value['ok'] != true).map((e) => e.key);
+    _log('ERROR', 'Crypto self-test failed: ${failed.join(', ')}');
+  }
+
   final Map<String, FileTransfer> _transfers = {};
   final Map<String, FolderTransfer> _folders = {};
   final Map<String, _PendingDownload> _pendingDownloads = {};
@@ -212,6 +228,7 @@ class FileTransferService {
     // Listen for file transfer events from gateway
     _gateway.on('FileOffer', _handleFileOffer);
     _gateway.on('FileAccept', _handleFileAccept);
+    _gateway.on('FileOfferBroadcastSent', _handleFileOfferBroadcastSent);
     _gateway.on('FileReject', _handleFileReject);
     _gateway.on('FileReady', _handleFileReady);
     _gateway.on('FastProgress', _handleFastProgress);
@@ -348,8 +365,9 @@ class FileTransferService {
             _log('INFO', '_resumeDownload: ${record.transferId} upload complete, starting download');
             _startDownload(transfer, record.savePath!, record.masterKey!, record.salt!);
           }
-        } else if (status == 'uploading') {
-          // Still uploading — poll for completion
+        } else if (status == 'uploading' || status == 'interrupted') {
+          // Still uploading (or cut off by a server restart and awaiting
+          // the uploader's resume) — poll for completion
           _log('INFO', '_resumeDownload: ${record.transferId} still uploading, polling');
           transfer.state = TransferState.hashing;
           _pendingDownloads[record.transferId] = _PendingDownload(record.savePath!, record.masterKey!, record.salt!);
@@ -847,7 +865,7 @@ class FileTransferService {
           } else {
             _log('ERROR', 'pollTransferStatus: transfer=$transferId complete but missing hashes or pending (sha256=${transfer.fileSha256}, chunks=${transfer.chunkHashes?.length}, pending=${p != null})');
           }
-        } else if (status != null && status != 'uploading') {
+        } else if (status != null && status != 'uploading' && status != 'interrupted') {
           // Unexpected status (e.g. failed, cancelled) — stop polling
           _log('WARN', 'pollTransferStatus: transfer=$transferId unexpected status=$status, stopping');
           _stopStatusPolling(transferId);
@@ -964,6 +982,16 @@ class FileTransferService {
 
       final bindings = _getBindings();
       if (bindings == null) continue;
+
+      // Keep long uploads on the current token as auth refreshes it.
+      if (transfer.isUpload) {
+        final token = _getToken();
+        if (token != transfer.nativeToken) {
+          bindings.setToken(transfer.nativeHandle!, token);
+          transfer.nativeToken = token;
+        }
+      }
+
       final result = bindings.getProgress(transfer.nativeHandle!);
       final prevState = transfer.state;
       transfer.bytesDone = result.bytesDone;
@@ -1246,6 +1274,28 @@ class FileTransferService {
     onProgressUpdate?.call();
   }
 
+  /// A channel-wide offer went out: tell the file server how many recipients
+  /// to wait for before it lets the file go.
+  void _handleFileOfferBroadcastSent(Map<String, dynamic> event) async {
+    final data = event['data'] as Map<String, dynamic>;
+    final transferId = data['transfer_id'] as String;
+    final recipients = (data['recipient_ids'] as List<dynamic>? ?? []).length;
+    _log('INFO', '_handleFileOfferBroadcastSent: transfer=$transferId recipients=$recipients');
+    if (recipients == 0 || _getBindings() == null) return;
+
+    final serverUrl = _getServerUrl();
+    final jwtToken = _getToken();
+    final code = await Isolate.run(() => FileClientBindings().setRecipients(
+          serverUrl: serverUrl,
+          transferId: transferId,
+          jwtToken: jwtToken,
+          recipients: recipients,
+        ));
+    if (code != 0) {
+      _log('ERROR', '_handleFileOfferBroadcastSent: transfer=$transferId setting recipients failed (code $code)');
+    }
+  }
+
   void _handleFileReject(Map<String, dynamic> event) {
     final data = event['data'] as Map<String, dynamic>;
     final transferId = data['transfer_id'] as String;
@@ -3,6 +3,7 @@ import 'dart:convert';
 import 'dart:math';
 import 'dart:typed_data';
 
+import 'package:web_socket_channel/io.dart';
 import 'package:web_socket_channel/web_socket_channel.dart';
 
 import 'package:haven_app/config/constants.dart';
@@ -41,6 +42,15 @@ class GatewayService {
   /// TURN server config received from the gateway Ready event.
   List<Map<String, dynamic>>? turnServers;
 
+  /// Single-use token from the last ReconnectToken event; the next connect
+  /// uses it instead of the JWT, falling back to the JWT if it's refused.
+  String? _reconnectToken;
+  DateTime? _reconnectTokenExpires;
+
+  /// Set when the server refused this client's protocol version; holds its
+  /// reason. Reconnecting can't help, so the app should prompt for an update.
+  String? incompatibleReason;
+
   GatewayService({
     required String Function() getToken,
     required String Function() getBaseUrl,
@@ -60,11 +70,23 @@ class GatewayService {
 
     try {
       final baseUrl = _getBaseUrl();
-      final token = _getToken();
       final wsUrl = HavenConstants.gatewayUrl(baseUrl);
-      final uri = Uri.parse('$wsUrl?token=$token');
-
-      _channel = WebSocketChannel.connect(uri);
+      final reconnectToken = _reconnectToken;
+      final expires = _reconnectTokenExpires;
+      _reconnectToken = null;
+      _reconnectTokenExpires = null;
+      const version = 'protocol_version=${HavenConstants.gatewayProtocolVersion}';
+      final WebSocketChannel channel;
+      if (reconnectToken != null && expires != null && DateTime.now().isBefore(expires)) {
+        // In a header rather than the URL, which servers and proxies log.
+        channel = IOWebSocketChannel.connect(
+          Uri.parse('$wsUrl?$version'),
+          headers: {'Authorization': 'Reconnect $reconnectToken'},
+        );
+      } else {
+        channel = WebSocketChannel.connect(Uri.parse('$wsUrl?token=${_getToken()}&$version'));
+      }
+      _channel = channel;
 
       _subscription = _channel!.stream.listen(
         (data) {
@@ -76,6 +98,10 @@ class GatewayService {
         },
         onDone: () {
           _isConnected = false;
+          if (channel.closeCode == HavenConstants.closeUnsupportedProtocol) {
+            incompatibleReason = channel.closeReason ?? 'Unsupported gateway protocol version';
+            _closed = true;
+          }
           for (final handler in _disconnectHandlers) {
             handler();
           }
@@ -116,6 +142,8 @@ class GatewayService {
     _channel = null;
     _sendQueue.clear();
     _isConnected = false;
+    _reconnectToken = null;
+    _reconnectTokenExpires = null;
   }
 
   /// Send a JSON command to the server.
@@ -172,6 +200,10 @@ class GatewayService {
     send({'type': 'StartTyping', 'data': {'channel_id': channelId}});
   }
 
+  void stopTyping(String channelId) {
+    send({'type': 'StopTyping', 'data': {'channel_id': channelId}});
+  }
+
   void voiceJoin(String channelId) {
     send({'type': 'VoiceJoin', 'data': {'channel_id': channelId}});
   }
@@ -252,6 +284,12 @@ class GatewayService {
           }
         }
 
+        if (type == 'ReconnectToken') {
+          final data = parsed['data'] as Map<String, dynamic>?;
+          _reconnectToken = data?['token'] as String?;
+          _reconnectTokenExpires = DateTime.tryParse(data?['expires_at'] as String? ?? '');
+        }
+
         final handlers = _handlers[type];
         if (handlers != null) {
           for (final handler in handlers) {
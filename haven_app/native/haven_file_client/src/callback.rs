@@ -0,0 +1,159 @@
+//! Push-based progress notifications for FFI callers.
+//!
+//! Instead of spinning on `haven_transfer_progress`, a caller can register a
+//! C function pointer per handle. A watcher task on the shared Tokio runtime
+//! samples the progress atomics and invokes the callback on a throttled
+//! cadence: every `CALLBACK_INTERVAL_MS`, or as soon as progress moves by
+//! the caller's delta (`CALLBACK_DELTA_PERMILLE` by default), whichever
+//! comes first, plus on every state change. Each call carries the smoothed rate and ETA alongside the byte
+//! counts. The watcher exits after reporting a terminal state.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::download::DownloadProgress;
+use crate::rate::{self, RateTracker};
+use crate::upload::{is_terminal, UploadProgress};
+
+/// `callback(bytes_done, bytes_total, state, rate_bps, eta_seconds)`, the
+/// last two as in `TransferProgressResult`. Runs on a Tokio worker thread.
+pub type ProgressCallback =
+    extern "C" fn(bytes_done: u64, bytes_total: u64, state: u8, rate_bps: u64, eta_seconds: u64);
+
+/// Fire at least this often while bytes are moving.
+pub const CALLBACK_INTERVAL_MS: u64 = 50;
+
+/// Fire early once progress moves by this much of the total (10‰ = 1%),
+/// unless the caller registers with a delta of its own.
+pub const CALLBACK_DELTA_PERMILLE: u64 = 10;
+
+/// How often the watcher samples the progress atomics.
+const CALLBACK_POLL_MS: u64 = 10;
+
+/// Per-transfer callback registration.
+#[derive(Default)]
+pub struct CallbackSlot {
+    callback: Mutex<Option<ProgressCallback>>,
+    /// Bumped on every set/clear so a superseded watcher exits.
+    generation: AtomicU64,
+}
+
+impl CallbackSlot {
+    /// Install (or with `None`, remove) the callback. Returns the generation
+    /// a new watcher should run under.
+    fn set(&self, callback: Option<ProgressCallback>) -> u64 {
+        let mut guard = self.callback.lock().unwrap();
+        *guard = callback;
+        self.generation.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Remove the callback. Blocks until an in-flight invocation returns, so
+    /// the callback is never called after this.
+    pub fn clear(&self) {
+        self.set(None);
+    }
+}
+
+/// Progress state that a watcher can sample.
+pub trait WatchedProgress: Send + Sync + 'static {
+    fn snapshot(&self) -> (u64, u64, u8);
+    fn callback_slot(&self) -> &CallbackSlot;
+    fn rate(&self) -> &RateTracker;
+}
+
+impl WatchedProgress for UploadProgress {
+    fn snapshot(&self) -> (u64, u64, u8) {
+        (
+            self.bytes_done.load(Ordering::Relaxed),
+            self.bytes_total.load(Ordering::Relaxed),
+            self.state.load(Ordering::Relaxed),
+        )
+    }
+
+    fn callback_slot(&self) -> &CallbackSlot {
+        &self.progress_callback
+    }
+
+    fn rate(&self) -> &RateTracker {
+        &self.rate
+    }
+}
+
+impl WatchedProgress for DownloadProgress {
+    fn snapshot(&self) -> (u64, u64, u8) {
+        (
+            self.bytes_done.load(Ordering::Relaxed),
+            self.bytes_total.load(Ordering::Relaxed),
+            self.state.load(Ordering::Relaxed),
+        )
+    }
+
+    fn callback_slot(&self) -> &CallbackSlot {
+        &self.progress_callback
+    }
+
+    fn rate(&self) -> &RateTracker {
+        &self.rate
+    }
+}
+
+/// Register `callback` on `progress` and start a watcher task for it, firing
+/// early on every `delta_permille` of progress (0 for the default).
+/// `None` clears any existing callback.
+pub fn set_callback<P: WatchedProgress>(
+    runtime: &tokio::runtime::Runtime,
+    progress: Arc<P>,
+    callback: Option<ProgressCallback>,
+    delta_permille: u64,
+) {
+    let generation = progress.callback_slot().set(callback);
+    if callback.is_some() {
+        let delta_permille = if delta_permille == 0 { CALLBACK_DELTA_PERMILLE } else { delta_permille };
+        runtime.spawn(watch(progress, generation, delta_permille));
+    }
+}
+
+async fn watch<P: WatchedProgress>(progress: Arc<P>, generation: u64, delta_permille: u64) {
+    let interval = Duration::from_millis(CALLBACK_INTERVAL_MS);
+    let mut ticker = tokio::time::interval(Duration::from_millis(CALLBACK_POLL_MS));
+    let mut last: Option<(u64, u8)> = None;
+    let mut last_at = Instant::now();
+
+    loop {
+        ticker.tick().await;
+
+        // Hold the lock across the call so `clear` can't return mid-call.
+        let slot = progress.callback_slot();
+        let guard = slot.callback.lock().unwrap();
+        if slot.generation.load(Ordering::Relaxed) != generation {
+            return;
+        }
+        let Some(callback) = *guard else { return };
+
+        let (done, total, state) = progress.snapshot();
+        let due = match last {
+            None => true,
+            Some((last_done, last_state)) => {
+                state != last_state
+                    || (done != last_done
+                        && (last_at.elapsed() >= interval
+                            || done.abs_diff(last_done) * 1000
+                                >= total.saturating_mul(delta_permille)))
+            }
+        };
+        if !due {
+            continue;
+        }
+
+        let rate_bps = progress.rate().sample(done);
+        callback(done, total, state, rate_bps, rate::eta_seconds(done, total, rate_bps));
+        drop(guard);
+        last = Some((done, state));
+        last_at = Instant::now();
+
+        if is_terminal(state) {
+            return;
+        }
+    }
+}
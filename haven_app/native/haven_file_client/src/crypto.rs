@@ -61,3 +61,103 @@ pub fn decrypt_chunk(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, String> {
         .decrypt(nonce, ciphertext)
         .map_err(|e| format!("Decryption failed: {}", e))
 }
+
+// ── Self-test ──────────────────────────────────────────────────────────
+
+/// Fixed inputs for [`selftest_report`].
+const SELFTEST_MASTER_KEY: &[u8] = b"haven selftest master key";
+const SELFTEST_SALT: &[u8] = b"haven selftest salt";
+const SELFTEST_TRANSFER_ID: [u8; 16] = *b"haven-selftest-1";
+const SELFTEST_CHUNK_INDEX: u32 = 3;
+const SELFTEST_PLAINTEXT: &[u8] = b"haven crypto selftest vector";
+
+/// What [`selftest_report`] must produce, as `(name, hex)`. Any other
+/// client or server deriving chunk keys and nonces has to match these.
+/// Cross-checked against an independent SHA-256/HKDF/AES-GCM; the HTTP and
+/// fast-transfer nonces agree at the default chunk size.
+pub const SELFTEST_EXPECTED: &[(&str, &str)] = &[
+    (
+        "derive_key",
+        "515294995e0f343b15c722c647aaddc0e49beef78d6d2128c36989dee02e7e78",
+    ),
+    ("chunk_nonce", "50716bb2d3c730230f3ee82c"),
+    (
+        "chunk_ciphertext",
+        "50716bb2d3c730230f3ee82c1230913ce6af726141428b50d49527d7f5f9ec76367fdd3141530b1f6ee2177d0d5bc2265311f80a9904eef6",
+    ),
+    ("fast_chunk_nonce", "50716bb2d3c730230f3ee82c"),
+    (
+        "transfer_key",
+        "0a17f9ec3240a45b346afab943388e0effa12fb9c70882f7ed0470a1e3010824",
+    ),
+];
+
+/// Run the key derivation, chunk nonce, chunk encryption and HKDF transfer
+/// key paths over fixed inputs and report each output against
+/// [`SELFTEST_EXPECTED`]:
+/// `{"ok":bool,"vectors":{"<name>":{"output":"<hex>","expected":"<hex>","ok":bool},...}}`.
+/// A mismatch means this build would seal chunks its peers can't open.
+pub fn selftest_report() -> serde_json::Value {
+    let key = derive_key(SELFTEST_MASTER_KEY, SELFTEST_SALT);
+    let nonce = derive_chunk_nonce(&key, SELFTEST_CHUNK_INDEX as u64);
+    let sealed = encrypt_chunk_with_nonce(&key, SELFTEST_PLAINTEXT, nonce).unwrap_or_default();
+    // Must round-trip, or the ciphertext is worthless even if it matches.
+    let opened = decrypt_chunk(&key, &sealed).is_ok_and(|p| p == SELFTEST_PLAINTEXT);
+    let fast_nonce = haven_fast_transfer::chunk_nonce(&key, SELFTEST_CHUNK_INDEX, haven_fast_transfer::CHUNK_SIZE);
+    let transfer_key = haven_crypto::keys::derive_transfer_key(&key, &SELFTEST_TRANSFER_ID);
+
+    let outputs = [
+        hex::encode(key),
+        hex::encode(nonce),
+        if opened { hex::encode(&sealed) } else { String::new() },
+        hex::encode(fast_nonce),
+        hex::encode(transfer_key),
+    ];
+    let mut vectors = serde_json::Map::new();
+    let mut all_ok = true;
+    for ((name, expected), output) in SELFTEST_EXPECTED.iter().zip(outputs) {
+        let ok = output == *expected;
+        all_ok &= ok;
+        vectors.insert(
+            name.to_string(),
+            serde_json::json!({ "output": output, "expected": expected, "ok": ok }),
+        );
+    }
+    serde_json::json!({ "ok": all_ok, "vectors": vectors })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selftest_vectors_are_pinned() {
+        let report = selftest_report();
+        for (name, expected) in SELFTEST_EXPECTED {
+            assert_eq!(report["vectors"][name]["output"], *expected, "{name}");
+        }
+        assert_eq!(report["ok"], true);
+    }
+
+    #[test]
+    fn decrypt_chunk_checks_the_tag() {
+        let key = derive_key(b"master", b"salt");
+        let sealed = encrypt_chunk_with_nonce(&key, b"chunk", [0; 12]).unwrap();
+        assert_eq!(decrypt_chunk(&key, &sealed).unwrap(), b"chunk");
+        assert!(decrypt_chunk(&derive_key(b"other", b"salt"), &sealed).is_err());
+        // An all-zero buffer carries no valid tag.
+        assert!(decrypt_chunk(&key, &[0; 64]).is_err());
+    }
+    #[test]
+    fn chunk_nonce_matches_the_transfer_crate() {
+        // Pre-hashed chunks are only valid if both sides derive identical
+        // nonces, whatever byte order either host uses.
+        let key = derive_key(b"master", b"salt");
+        for index in [0u32, 1, 0x0102_0304, u32::MAX - 1] {
+            assert_eq!(
+                derive_chunk_nonce(&key, index as u64),
+                haven_fast_transfer::chunk_nonce(&key, index, haven_fast_transfer::CHUNK_SIZE)
+            );
+        }
+    }
+}
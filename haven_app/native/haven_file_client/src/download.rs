@@ -1,15 +1,32 @@
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::atomic::{AtomicI32, AtomicU16, AtomicU64, AtomicU8, Ordering};
 
-use futures_util::StreamExt;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use haven_fast_transfer::{
+    AeadAlgorithm, ChunkCipher, ControlFields, RingBufferLogger, check_chunk_layout, open_compressed_chunk,
+    seal_compressed_chunk, slot_size, sparse,
+};
 use reqwest::Client;
+use serde::Deserialize;
 use sha2::{Sha256, Digest};
 use tokio::io::AsyncWriteExt;
 
-use crate::crypto::{derive_key, decrypt_chunk};
-use crate::upload::{STATE_IDLE, STATE_UPLOADING as STATE_DOWNLOADING, STATE_COMPLETE, STATE_ERROR, STATE_CANCELLED};
+use crate::callback::CallbackSlot;
+use crate::crypto::{derive_key, derive_chunk_nonce, encrypt_chunk_with_nonce};
+use crate::error::{ErrorCode, TransferError};
+use crate::fast_download::parse_transfer_id_bytes;
+use crate::rate::RateTracker;
+use crate::upload::{block_while_paused, wait_while_paused, STATE_IDLE, STATE_HASHING, STATE_UPLOADING as STATE_DOWNLOADING, STATE_COMPLETE, STATE_ERROR, STATE_CANCELLED};
 
 const CHUNK_SIZE: usize = 4 * 1024 * 1024; // 4 MB
+/// A full chunk on the wire: CHUNK_SIZE (plaintext) + 12 (nonce) + 16 (tag).
+#[cfg(test)]
+const ENCRYPTED_CHUNK_SIZE: usize = CHUNK_SIZE + 12 + 16;
+/// Ranged requests `download_file_parallel` keeps in flight.
+const PARALLEL_CHUNKS: usize = 4;
+/// Tries per chunk in `download_file_parallel` before the download fails.
+const CHUNK_ATTEMPTS: u32 = 3;
 
 /// Shared progress state for FFI polling.
 pub struct DownloadProgress {
@@ -17,8 +34,26 @@ pub struct DownloadProgress {
     pub bytes_total: AtomicU64,
     pub state: AtomicU8,
     pub cancelled: AtomicU8,
+    /// Non-zero while the caller has paused the transfer.
+    pub paused: AtomicU8,
     /// Last error message, readable from FFI after STATE_ERROR.
     pub last_error: std::sync::Mutex<Option<String>>,
+    /// `ErrorCode` of `last_error` as an i32, 0 if none.
+    pub last_error_code: AtomicI32,
+    /// Set at the end of a fast download: JSON loss histogram from the receiver.
+    pub loss_histogram_json: std::sync::Mutex<Option<String>>,
+    /// Local UDP port a fast download receives on, 0 until its receiver has
+    /// started.
+    pub local_udp_port: AtomicU16,
+    /// Recent fast-transfer events, for `haven_transfer_drain_log`.
+    pub transfer_log: Arc<RingBufferLogger>,
+    /// Optional push-based progress callback (see `callback`).
+    pub progress_callback: CallbackSlot,
+    /// Smoothed throughput, sampled as bytes are done and when progress is
+    /// read.
+    pub rate: RateTracker,
+    /// Set once a fast download gave up on UDP and fetched over HTTP.
+    pub fell_back_to_http: AtomicU8,
 }
 
 impl DownloadProgress {
@@ -28,175 +63,1001 @@ impl DownloadProgress {
             bytes_total: AtomicU64::new(0),
             state: AtomicU8::new(STATE_IDLE),
             cancelled: AtomicU8::new(0),
+            paused: AtomicU8::new(0),
             last_error: std::sync::Mutex::new(None),
+            last_error_code: AtomicI32::new(0),
+            loss_histogram_json: std::sync::Mutex::new(None),
+            local_udp_port: AtomicU16::new(0),
+            transfer_log: Arc::default(),
+            progress_callback: CallbackSlot::default(),
+            rate: RateTracker::default(),
+            fell_back_to_http: AtomicU8::new(0),
         }
     }
 
     pub fn is_cancelled(&self) -> bool {
         self.cancelled.load(Ordering::Relaxed) != 0
     }
+
+    /// Count `n` more bytes done, feeding the rate estimate.
+    pub fn add_bytes(&self, n: u64) {
+        let done = self.bytes_done.fetch_add(n, Ordering::Relaxed) + n;
+        self.rate.sample(done);
+    }
+
+    /// Set the bytes done, feeding the rate estimate.
+    pub fn set_bytes(&self, done: u64) {
+        self.bytes_done.store(done, Ordering::Relaxed);
+        self.rate.sample(done);
+    }
+
+    /// Record a failure for `haven_get_last_error` / `haven_get_last_error_code`.
+    pub fn set_error(&self, err: TransferError) {
+        self.last_error_code.store(err.code as i32, Ordering::Relaxed);
+        *self.last_error.lock().unwrap() = Some(err.message);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed) != 0
+    }
+
+    pub async fn wait_while_paused(&self) {
+        wait_while_paused(&self.paused, &self.cancelled, &self.state).await
+    }
+
+    pub fn block_while_paused(&self) {
+        block_while_paused(&self.paused, &self.cancelled, &self.state)
+    }
+}
+
+/// Which transfer a download fetches, and what its chunks must hash to.
+/// `file_sha256` and `chunk_hashes` come from the offer, not the server.
+#[derive(Clone, Copy)]
+pub struct DownloadSource<'a> {
+    pub server_url: &'a str,
+    pub transfer_id: &'a str,
+    pub jwt_token: &'a str,
+    pub master_key: &'a [u8],
+    pub salt: &'a [u8],
+    pub file_sha256: &'a str,
+    pub chunk_hashes: &'a [String],
+}
+
+/// How a transfer's chunks are stored, as `GET /transfers/{id}` reports it.
+/// Older servers report none of it: their transfers are all `CHUNK_SIZE`
+/// chunks sealed with AES-256-GCM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChunkLayout {
+    /// Plaintext bytes in every chunk but the last.
+    pub chunk_size: usize,
+    pub compressed: bool,
+    pub aead: AeadAlgorithm,
+    /// Chunks stored as zero slots, in ascending order, as the uploader's
+    /// control MAC lists them.
+    pub sparse_chunks: Vec<u32>,
+}
+
+/// What a downloader knows of a transfer from its offer rather than the
+/// server, to check the server's `ChunkLayout` against.
+pub(crate) struct Offered<'a> {
+    pub transfer_id: &'a str,
+    pub key: &'a [u8; 32],
+    pub file_sha256: &'a str,
+    pub chunk_count: u32,
+}
+
+impl ChunkLayout {
+    /// Read the layout from a transfer status. A reported chunk size the
+    /// file doesn't split into the offer's chunk count is ignored for the
+    /// default. A MAC the status carries is checked, and one is required
+    /// for any layout only a MAC-sending fast upload picks: the server could
+    /// otherwise drop it and describe the chunks however it liked.
+    pub(crate) fn from_status(status: &serde_json::Value, offered: &Offered) -> Result<Self, TransferError> {
+        let chunk_count = offered.chunk_count;
+        // Compressed uploads store padded slots; older servers don't report it.
+        let compressed = status["compressed"].as_bool().unwrap_or(false);
+        let aead_id = status["aead"].as_u64().unwrap_or(0);
+        let aead = u8::try_from(aead_id)
+            .ok()
+            .and_then(AeadAlgorithm::from_id)
+            .ok_or_else(|| ErrorCode::Protocol.err(format!("Unknown AEAD algorithm {}", aead_id)))?;
+        // Fast uploads may negotiate a non-default chunk size; transfers
+        // created without one record a default.
+        let file_size = status["file_size"].as_u64().unwrap_or(0);
+        let chunk_size = status["chunk_size"]
+            .as_u64()
+            .filter(|&cs| check_chunk_layout(file_size, chunk_count, cs, compressed, aead).is_ok())
+            .map_or(CHUNK_SIZE, |cs| cs as usize - slot_size(0, compressed, aead));
+
+        // A zero slot reads as zeros without any tag to check, so these
+        // are only taken under the MAC.
+        let sparse_chunks: Vec<u32> = serde_json::from_value(status["sparse_chunks"].clone()).unwrap_or_default();
+        if !sparse_chunks.is_sorted() {
+            return Err(ErrorCode::Protocol.err("Sparse chunks out of order"));
+        }
+        let layout = Self { chunk_size, compressed, aead, sparse_chunks };
+
+        let control_mac = status["control_mac"].as_str();
+        if control_mac.is_some() || layout.requires_mac() {
+            let fields = ControlFields {
+                transfer_id: &parse_transfer_id_bytes(offered.transfer_id),
+                file_size,
+                chunk_size: layout.slot_size() as u64,
+                chunk_count,
+                file_sha256: offered.file_sha256,
+                sparse_chunks: &layout.sparse_chunks,
+            };
+            if !control_mac.is_some_and(|mac| fields.verify(offered.key, mac)) {
+                return Err(ErrorCode::HashMismatch.err("Control message integrity check failed"));
+            }
+        }
+        Ok(layout)
+    }
+
+    /// Whether only a fast upload, which always sends a control MAC, could
+    /// have stored the chunks this way.
+    pub(crate) fn requires_mac(&self) -> bool {
+        self.chunk_size != CHUNK_SIZE
+            || self.compressed
+            || self.aead != AeadAlgorithm::Aes256Gcm
+            || !self.sparse_chunks.is_empty()
+    }
+
+    /// Stored bytes of every chunk but the last.
+    pub(crate) fn slot_size(&self) -> usize {
+        slot_size(self.chunk_size, self.compressed, self.aead)
+    }
+
+    pub(crate) fn cipher(&self, key: &[u8; 32]) -> ChunkCipher {
+        ChunkCipher::new(self.aead, key)
+    }
+
+    fn is_sparse(&self, idx: usize) -> bool {
+        self.sparse_chunks.binary_search(&(idx as u32)).is_ok()
+    }
+
+    /// Decrypt (and decompress) stored chunk `idx`.
+    pub(crate) fn open(&self, cipher: &ChunkCipher, idx: usize, slot: &[u8]) -> Result<Vec<u8>, String> {
+        if self.compressed {
+            open_compressed_chunk(cipher, slot, self.chunk_size)
+        } else {
+            sparse::open_chunk(cipher, slot, self.is_sparse(idx))
+        }
+    }
+
+    /// Seal chunk `idx` as its uploader did, to check it against its hash.
+    /// Compression is deterministic, so a compressed slot comes out the same.
+    fn seal(&self, cipher: &ChunkCipher, key: &[u8; 32], idx: usize, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        if self.compressed {
+            seal_compressed_chunk(cipher, key, idx as u32, self.chunk_size, plaintext).map(|sealed| sealed.slot)
+        } else {
+            cipher.seal_chunk(key, idx as u32, self.chunk_size, plaintext)
+        }
+    }
+}
+
+impl Default for ChunkLayout {
+    fn default() -> Self {
+        Self { chunk_size: CHUNK_SIZE, compressed: false, aead: AeadAlgorithm::Aes256Gcm, sparse_chunks: Vec::new() }
+    }
+}
+
+/// `GET /transfers/{id}`: the transfer's status, including its `ChunkLayout`.
+pub(crate) async fn fetch_status(
+    client: &Client,
+    server_url: &str,
+    transfer_id: &str,
+    jwt_token: &str,
+) -> Result<serde_json::Value, TransferError> {
+    let resp = client
+        .get(format!("{}/transfers/{}", server_url, transfer_id))
+        .header("Authorization", format!("Bearer {}", jwt_token))
+        .send()
+        .await
+        .map_err(|e| ErrorCode::Network.err(format!("Status query failed: {}", e)))?;
+
+    if !resp.status().is_success() {
+        return Err(ErrorCode::from_status(resp.status())
+            .err(format!("Transfer status query failed: {}", resp.status())));
+    }
+    resp.json()
+        .await
+        .map_err(|e| ErrorCode::Protocol.err(format!("Status parse failed: {}", e)))
+}
+
+/// The layout an HTTP download splits and opens the stored chunks by.
+async fn fetch_layout(
+    client: &Client,
+    source: &DownloadSource<'_>,
+    key: &[u8; 32],
+) -> Result<ChunkLayout, TransferError> {
+    let DownloadSource { server_url, transfer_id, jwt_token, file_sha256, chunk_hashes, .. } = *source;
+    let status = fetch_status(client, server_url, transfer_id, jwt_token).await?;
+    let offered = Offered { transfer_id, key, file_sha256, chunk_count: chunk_hashes.len() as u32 };
+    ChunkLayout::from_status(&status, &offered)
 }
 
 /// Download a file from the Haven file server, verify hashes, and decrypt.
 ///
 /// 1. GET /transfers/{id}/data with streaming response
 /// 2. Read encrypted chunks, verify per-chunk SHA-256
-/// 3. Decrypt each chunk and write to output file
+/// 3. Decrypt each chunk and write to `save_path.part`
 /// 4. Verify full file SHA-256
 /// 5. On hash mismatch: retry with Range header
+/// 6. Rename the `.part` file to `save_path`
+///
+/// Nothing appears at `save_path` until every check has passed. A network
+/// failure leaves the `.part` file for the next attempt, which checks its
+/// leading chunks against `chunk_hashes` and fetches only the rest by Range;
+/// progress then starts at the bytes already held, out of the full size. On
+/// any other failure (or cancel) the `.part` file is deleted.
 pub async fn download_file(
     save_path: &str,
-    server_url: &str,
-    transfer_id: &str,
-    jwt_token: &str,
-    master_key: &[u8],
-    salt: &[u8],
-    file_sha256: &str,
-    chunk_hashes: &[String],
+    source: &DownloadSource<'_>,
     progress: Arc<DownloadProgress>,
-) -> Result<(), String> {
+) -> Result<(), TransferError> {
+    let DownloadSource { server_url, transfer_id, jwt_token, master_key, salt, file_sha256, chunk_hashes } = *source;
     // Validate inputs before doing anything
     if chunk_hashes.is_empty() {
-        return Err("Download failed: chunk_hashes is empty (offer data missing or corrupted)".into());
+        return Err(ErrorCode::Protocol.err("Download failed: chunk_hashes is empty (offer data missing or corrupted)"));
     }
 
     let key = derive_key(master_key, salt);
     let client = Client::new();
 
     progress.state.store(STATE_DOWNLOADING, Ordering::Relaxed);
+    let layout = fetch_layout(&client, source, &key).await?;
+
+    let part_path = part_path(save_path);
+    let mut resume = prepare_part(save_path, &part_path, &key, chunk_hashes, &layout).await?;
+    let start_offset = resume.chunks as u64 * layout.slot_size() as u64;
+
+    let resp = start_download(&client, server_url, transfer_id, jwt_token, start_offset, &progress).await?;
+    if progress.bytes_done.load(Ordering::Relaxed) != start_offset {
+        // The server sent the whole file after all.
+        resume = ResumePoint::default();
+    }
+
+    let written = async {
+        let mut output_file = open_part(&part_path, resume.chunks * layout.chunk_size).await?;
+
+        let fetch = ChunkFetch {
+            client: &client,
+            server_url,
+            transfer_id,
+            jwt_token,
+            key: &key,
+            layout: &layout,
+            file_sha256,
+            chunk_hashes,
+        };
+        fetch.stream_plaintext(body_stream(resp), &progress, resume, &mut output_file).await?;
+
+        output_file.sync_all().await.map_err(|e| ErrorCode::FileIo.err(format!("Flush error: {}", e)))
+    }
+    .await;
+    finish(&client, server_url, transfer_id, jwt_token, written, &part_path, save_path, &progress).await
+}
+
+/// Download a file like `download_file`, but as independent chunks:
+/// `GET /transfers/{id}/manifest` lists each chunk's byte range, and up to
+/// `PARALLEL_CHUNKS` ranges are fetched at once. Each chunk is checked
+/// against `chunk_hashes` as it lands and fetched again (up to
+/// `CHUNK_ATTEMPTS` times) if it fails, so a dropped connection or a bad
+/// chunk costs that chunk rather than the rest of the stream.
+///
+/// Chunks are decrypted and written in order, so the `.part` file and its
+/// resume behave exactly as in `download_file`. The manifest must agree with
+/// `chunk_hashes` and `file_sha256`, which come from the offer: the server
+/// only says where the chunks are, not what they should hash to.
+pub async fn download_file_parallel(
+    save_path: &str,
+    source: &DownloadSource<'_>,
+    progress: Arc<DownloadProgress>,
+) -> Result<(), TransferError> {
+    let DownloadSource { server_url, transfer_id, jwt_token, master_key, salt, file_sha256, chunk_hashes } = *source;
+    if chunk_hashes.is_empty() {
+        return Err(ErrorCode::Protocol.err("Download failed: chunk_hashes is empty (offer data missing or corrupted)"));
+    }
+
+    let key = derive_key(master_key, salt);
+    let client = Client::new();
+    progress.state.store(STATE_DOWNLOADING, Ordering::Relaxed);
+
+    let manifest = fetch_manifest(&client, server_url, transfer_id, jwt_token, &progress).await?;
+    manifest.check(file_sha256, chunk_hashes)?;
+    let layout = fetch_layout(&client, source, &key).await?;
+    let cipher = layout.cipher(&key);
+
+    let part_path = part_path(save_path);
+    let resume = prepare_part(save_path, &part_path, &key, chunk_hashes, &layout).await?;
+    let skipped: u64 = manifest.chunks[..resume.chunks].iter().map(|c| c.length).sum();
+    progress.bytes_done.store(skipped, Ordering::Relaxed);
+    progress.bytes_total.store(manifest.chunks.iter().map(|c| c.length).sum(), Ordering::Relaxed);
+
+    let written = async {
+        let mut output_file = open_part(&part_path, resume.chunks * layout.chunk_size).await?;
+        let ResumePoint { chunks: first, mut full_hasher } = resume;
+
+        let fetches: Vec<_> = manifest.chunks[first..]
+            .iter()
+            .map(|chunk| {
+                fetch_range(&client, server_url, transfer_id, jwt_token, chunk.index, chunk.offset, chunk.length, &chunk.sha256)
+            })
+            .collect();
+        // `buffered` keeps the fetches running ahead but yields in order.
+        let mut chunks = futures_util::stream::iter(fetches).buffered(PARALLEL_CHUNKS);
+        while let Some(result) = chunks.next().await {
+            progress.wait_while_paused().await;
+            if progress.is_cancelled() {
+                progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
+                return Err(TransferError::cancelled());
+            }
+
+            let (index, encrypted) = result?;
+            progress.add_bytes(encrypted.len() as u64);
+            full_hasher.update(&encrypted);
+            let plaintext = layout
+                .open(&cipher, index, &encrypted)
+                .map_err(|e| ErrorCode::HashMismatch.err(format!("Decrypt failed on chunk {}: {}", index, e)))?;
+            output_file.put(&plaintext).await?;
+        }
+
+        let actual_full_hash = hex::encode(full_hasher.finalize());
+        if actual_full_hash != file_sha256 {
+            return Err(ErrorCode::HashMismatch.err(format!(
+                "Full file hash mismatch: expected {}, got {}",
+                file_sha256, actual_full_hash
+            )));
+        }
+        output_file.sync_all().await.map_err(|e| ErrorCode::FileIo.err(format!("Flush error: {}", e)))
+    }
+    .await;
+    finish(&client, server_url, transfer_id, jwt_token, written, &part_path, save_path, &progress).await
+}
 
+/// Make sure `save_path`'s directory exists and check what an earlier
+/// attempt left at `part_path`.
+async fn prepare_part(
+    save_path: &str,
+    part_path: &str,
+    key: &[u8; 32],
+    chunk_hashes: &[String],
+    layout: &ChunkLayout,
+) -> Result<ResumePoint, TransferError> {
     // Ensure the parent directory of save_path exists.
     // FilePicker may return a path whose parent hasn't been created yet.
     if let Some(parent) = std::path::Path::new(save_path).parent() {
         if !parent.as_os_str().is_empty() {
             tokio::fs::create_dir_all(parent)
                 .await
-                .map_err(|e| format!("Cannot create download directory '{}': {}", parent.display(), e))?;
+                .map_err(|e| ErrorCode::FileIo.err(format!("Cannot create download directory '{}': {}", parent.display(), e)))?;
+        }
+    }
+
+    let (path, key, hashes, layout) = (part_path.to_string(), *key, chunk_hashes.to_vec(), layout.clone());
+    tokio::task::spawn_blocking(move || verified_prefix(&path, &key, &hashes, &layout))
+        .await
+        .map_err(|e| ErrorCode::Protocol.err(format!("Resume check panicked: {}", e)))?
+        .map_err(|e| ErrorCode::FileIo.err(format!("Cannot read partial download '{}': {}", part_path, e)))
+}
+
+/// Open `part_path` for writing after its first `kept_bytes` bytes,
+/// creating it afresh if there are none to keep.
+async fn open_part(part_path: &str, kept_bytes: usize) -> Result<tokio::fs::File, TransferError> {
+    if kept_bytes == 0 {
+        return tokio::fs::File::create(part_path)
+            .await
+            .map_err(|e| ErrorCode::FileIo.err(format!("Cannot create output file '{}': {}", part_path, e)));
+    }
+    let file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(part_path)
+        .await
+        .map_err(|e| ErrorCode::FileIo.err(format!("Cannot reopen output file '{}': {}", part_path, e)))?;
+    file.set_len(kept_bytes as u64)
+        .await
+        .map_err(|e| ErrorCode::FileIo.err(format!("Cannot truncate output file '{}': {}", part_path, e)))?;
+    Ok(file)
+}
+
+/// End a download whose `.part` file has been `written`: promote it and
+/// confirm with the server, or on failure delete it unless the failure was
+/// the network's (the next attempt resumes from it).
+#[allow(clippy::too_many_arguments)]
+async fn finish(
+    client: &Client,
+    server_url: &str,
+    transfer_id: &str,
+    jwt_token: &str,
+    written: Result<(), TransferError>,
+    part_path: &str,
+    save_path: &str,
+    progress: &DownloadProgress,
+) -> Result<(), TransferError> {
+    let finished = match written {
+        Ok(()) => promote_part(part_path, save_path).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = finished {
+        if e.code != ErrorCode::Network {
+            let _ = tokio::fs::remove_file(part_path).await;
+        }
+        return Err(e);
+    }
+
+    // Confirm download with server
+    let _ = client
+        .post(format!("{}/transfers/{}/confirm", server_url, transfer_id))
+        .header("Authorization", format!("Bearer {}", jwt_token))
+        .send()
+        .await;
+
+    progress.state.store(STATE_COMPLETE, Ordering::Relaxed);
+    Ok(())
+}
+
+/// `GET /transfers/{id}/manifest`: where each chunk sits in the stored file.
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    file_sha256: String,
+    chunks: Vec<ManifestChunk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManifestChunk {
+    index: usize,
+    offset: u64,
+    length: u64,
+    sha256: String,
+}
+
+impl Manifest {
+    /// The manifest must describe the transfer the offer did, chunk for chunk.
+    fn check(&self, file_sha256: &str, chunk_hashes: &[String]) -> Result<(), TransferError> {
+        if self.file_sha256 != file_sha256 {
+            return Err(ErrorCode::Protocol.err(format!(
+                "Manifest is for a different file: expected {}, got {}",
+                file_sha256, self.file_sha256
+            )));
+        }
+        if self.chunks.len() != chunk_hashes.len() {
+            return Err(ErrorCode::Protocol.err(format!(
+                "Manifest lists {} chunks, the offer {}",
+                self.chunks.len(),
+                chunk_hashes.len()
+            )));
+        }
+        for (idx, (chunk, expected)) in self.chunks.iter().zip(chunk_hashes).enumerate() {
+            if chunk.index != idx || chunk.sha256 != *expected {
+                return Err(ErrorCode::Protocol.err(format!("Manifest chunk {} does not match the offer", idx)));
+            }
         }
+        Ok(())
     }
+}
 
-    // GET the streaming download
+async fn fetch_manifest(
+    client: &Client,
+    server_url: &str,
+    transfer_id: &str,
+    jwt_token: &str,
+    progress: &DownloadProgress,
+) -> Result<Manifest, TransferError> {
     let resp = client
-        .get(format!("{}/transfers/{}/data", server_url, transfer_id))
+        .get(format!("{}/transfers/{}/manifest", server_url, transfer_id))
         .header("Authorization", format!("Bearer {}", jwt_token))
         .send()
         .await
-        .map_err(|e| format!("Download request failed: {}", e))?;
+        .map_err(|e| ErrorCode::Network.err(format!("Manifest request failed: {}", e)))?;
 
     if !resp.status().is_success() {
         let status = resp.status();
         let body = resp.text().await.unwrap_or_default();
         progress.state.store(STATE_ERROR, Ordering::Relaxed);
-        return Err(format!("Download failed ({}): {}", status, body));
+        return Err(ErrorCode::from_status(status).err(format!("Manifest request failed ({}): {}", status, body)));
     }
+    resp.json()
+        .await
+        .map_err(|e| ErrorCode::Protocol.err(format!("Bad manifest: {}", e)))
+}
 
-    let content_length = resp.content_length().unwrap_or(0);
-    progress.bytes_total.store(content_length, Ordering::Relaxed);
+/// Fetch one chunk's byte range and check it against `expected_hash`,
+/// trying again up to `CHUNK_ATTEMPTS` times on a network error or a bad
+/// hash. Returns the chunk's index with its encrypted bytes.
+#[allow(clippy::too_many_arguments)]
+async fn fetch_range(
+    client: &Client,
+    server_url: &str,
+    transfer_id: &str,
+    jwt_token: &str,
+    index: usize,
+    offset: u64,
+    length: u64,
+    expected_hash: &str,
+) -> Result<(usize, Vec<u8>), TransferError> {
+    let mut last_err = None;
+    for _ in 0..CHUNK_ATTEMPTS {
+        let resp = client
+            .get(format!("{}/transfers/{}/data", server_url, transfer_id))
+            .header("Authorization", format!("Bearer {}", jwt_token))
+            .header("Range", format!("bytes={}-{}", offset, offset + length - 1))
+            .send()
+            .await;
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(e) => {
+                last_err = Some(ErrorCode::Network.err(format!("Chunk {} request failed: {}", index, e)));
+                continue;
+            }
+        };
+        if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            // A full body, or an error: either way not something a retry fixes.
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            return Err(ErrorCode::from_status(status).err(format!("Chunk {} request failed ({}): {}", index, status, body)));
+        }
+        match resp.bytes().await {
+            Ok(data) if chunk_hash(&data) == expected_hash => return Ok((index, data.to_vec())),
+            Ok(data) => {
+                last_err = Some(ErrorCode::HashMismatch.err(format!(
+                    "Chunk {} hash mismatch: expected {}, got {}",
+                    index,
+                    expected_hash,
+                    chunk_hash(&data)
+                )))
+            }
+            Err(e) => last_err = Some(ErrorCode::Network.err(format!("Chunk {} read failed: {}", index, e))),
+        }
+    }
+    Err(last_err.expect("CHUNK_ATTEMPTS is non-zero"))
+}
 
-    // Create output file
-    let mut output_file = tokio::fs::File::create(save_path)
+/// Where `download_file` writes until the download has passed its checks.
+pub fn part_path(save_path: &str) -> String {
+    format!("{}.part", save_path)
+}
+
+/// Move a finished `.part` file to `save_path`, replacing anything there.
+/// Where the rename is refused (e.g. `save_path` is on another volume or a
+/// share that doesn't support it) the bytes are copied instead and the
+/// `.part` file removed.
+async fn promote_part(part_path: &str, save_path: &str) -> Result<(), TransferError> {
+    if tokio::fs::rename(part_path, save_path).await.is_ok() {
+        return Ok(());
+    }
+    if let Err(e) = tokio::fs::copy(part_path, save_path).await {
+        let _ = tokio::fs::remove_file(save_path).await;
+        return Err(ErrorCode::FileIo.err(format!("Cannot move download into '{}': {}", save_path, e)));
+    }
+    let _ = tokio::fs::remove_file(part_path).await;
+    Ok(())
+}
+
+/// `callback(chunk_ptr, chunk_len)`: one decrypted chunk. Return false to
+/// stop the download. The bytes are only valid during the call.
+pub type ChunkCallback = extern "C" fn(chunk: *const u8, len: usize) -> bool;
+
+/// Download and decrypt a transfer like `download_file`, but hand each
+/// plaintext chunk to `callback` in order instead of writing a file, e.g. to
+/// decode a preview as it arrives. Every chunk is hash-checked before the
+/// callback sees it, so a tampered chunk ends the stream with
+/// `HashMismatch`. The full-file hash is checked after the last chunk.
+///
+/// Returning false from the callback stops the download; it still ends in
+/// `STATE_COMPLETE`. The transfer is not confirmed to the server, since a
+/// preview is not the recipient's copy.
+pub async fn download_to_callback(
+    source: &DownloadSource<'_>,
+    callback: ChunkCallback,
+    progress: Arc<DownloadProgress>,
+) -> Result<(), TransferError> {
+    let DownloadSource { server_url, transfer_id, jwt_token, master_key, salt, file_sha256, chunk_hashes } = *source;
+    if chunk_hashes.is_empty() {
+        return Err(ErrorCode::Protocol.err("Download failed: chunk_hashes is empty (offer data missing or corrupted)"));
+    }
+
+    let key = derive_key(master_key, salt);
+    let client = Client::new();
+    progress.state.store(STATE_DOWNLOADING, Ordering::Relaxed);
+    let layout = fetch_layout(&client, source, &key).await?;
+
+    let resp = start_download(&client, server_url, transfer_id, jwt_token, 0, &progress).await?;
+    let fetch = ChunkFetch {
+        client: &client,
+        server_url,
+        transfer_id,
+        jwt_token,
+        key: &key,
+        layout: &layout,
+        file_sha256,
+        chunk_hashes,
+    };
+    fetch.stream_plaintext(body_stream(resp), &progress, ResumePoint::default(), &mut CallbackSink(callback)).await?;
+
+    progress.state.store(STATE_COMPLETE, Ordering::Relaxed);
+    Ok(())
+}
+
+/// GET the transfer's data from `start_offset` on and record the progress:
+/// `bytes_done` at the offset the server honoured (0 if it ignored the
+/// Range) and `bytes_total` at the full encrypted size.
+async fn start_download(
+    client: &Client,
+    server_url: &str,
+    transfer_id: &str,
+    jwt_token: &str,
+    start_offset: u64,
+    progress: &DownloadProgress,
+) -> Result<reqwest::Response, TransferError> {
+    let mut req = client
+        .get(format!("{}/transfers/{}/data", server_url, transfer_id))
+        .header("Authorization", format!("Bearer {}", jwt_token));
+    if start_offset > 0 {
+        req = req.header("Range", format!("bytes={}-", start_offset));
+    }
+    let resp = req
+        .send()
         .await
-        .map_err(|e| format!("Cannot create output file '{}': {}", save_path, e))?;
+        .map_err(|e| ErrorCode::Network.err(format!("Download request failed: {}", e)))?;
 
-    // Stream the response body, splitting into encrypted chunks
-    let mut stream = resp.bytes_stream();
-    let mut buf = Vec::with_capacity(CHUNK_SIZE + 1024); // extra room for encryption overhead
-    let mut chunk_idx: usize = 0;
-    let mut full_hasher = Sha256::new();
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        progress.state.store(STATE_ERROR, Ordering::Relaxed);
+        return Err(ErrorCode::from_status(status).err(format!("Download failed ({}): {}", status, body)));
+    }
 
-    while let Some(result) = stream.next().await {
-        if progress.is_cancelled() {
-            progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
-            return Err("Cancelled".into());
-        }
+    let offset = if resp.status() == reqwest::StatusCode::PARTIAL_CONTENT { start_offset } else { 0 };
+    progress.bytes_done.store(offset, Ordering::Relaxed);
+    progress.bytes_total.store(offset + resp.content_length().unwrap_or(0), Ordering::Relaxed);
+    Ok(resp)
+}
 
-        let data = result.map_err(|e| format!("Stream error: {}", e))?;
-        buf.extend_from_slice(&data);
-        progress.bytes_done.fetch_add(data.len() as u64, Ordering::Relaxed);
+/// How much of an earlier attempt's `.part` file can be kept.
+#[derive(Default)]
+struct ResumePoint {
+    /// Leading chunks on disk whose re-encryption matches their hash.
+    chunks: usize,
+    /// Full-file hasher already fed those chunks' ciphertext.
+    full_hasher: Sha256,
+}
 
-        // Process all complete full-size encrypted chunks from the buffer.
-        // We deliberately skip the last chunk here — it may be smaller than a full
-        // chunk, so we let the post-stream handler deal with it once the stream ends.
-        // Each full encrypted chunk = CHUNK_SIZE (plaintext) + 12 (nonce) + 16 (tag).
-        while chunk_idx + 1 < chunk_hashes.len() {
-            let expected_encrypted_size = CHUNK_SIZE + 12 + 16;
+/// Check the `.part` file left at `part_path` chunk by chunk, stopping at
+/// the first short or mismatched one. The final chunk is always fetched
+/// again, since a `.part` holding all of it would already have been
+/// promoted. No file means nothing to keep.
+fn verified_prefix(
+    part_path: &str,
+    key: &[u8; 32],
+    chunk_hashes: &[String],
+    layout: &ChunkLayout,
+) -> std::io::Result<ResumePoint> {
+    use std::io::Read;
 
-            if buf.len() < expected_encrypted_size {
-                break; // Need more data
+    let mut resume = ResumePoint::default();
+    let mut file = match std::fs::File::open(part_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(resume),
+        Err(e) => return Err(e),
+    };
+    let cipher = layout.cipher(key);
+    let mut plaintext = Vec::with_capacity(layout.chunk_size);
+    for (idx, expected) in chunk_hashes.iter().enumerate().take(chunk_hashes.len().saturating_sub(1)) {
+        plaintext.clear();
+        (&mut file).take(layout.chunk_size as u64).read_to_end(&mut plaintext)?;
+        if plaintext.len() < layout.chunk_size {
+            break;
+        }
+        // A sparse upload stored an all-zero chunk as a zero slot.
+        let zero_slot = (layout.is_sparse(idx) && sparse::is_zero(&plaintext))
+            .then(|| sparse::zero_slot(plaintext.len()))
+            .filter(|slot| chunk_hash(slot) == *expected);
+        let encrypted = match zero_slot {
+            Some(slot) => slot,
+            None => {
+                let Ok(encrypted) = layout.seal(&cipher, key, idx, &plaintext) else {
+                    break;
+                };
+                encrypted
             }
+        };
+        if chunk_hash(&encrypted) != *expected {
+            break;
+        }
+        resume.full_hasher.update(&encrypted);
+        resume.chunks += 1;
+    }
+    Ok(resume)
+}
 
-            let encrypted_chunk: Vec<u8> = buf.drain(..expected_encrypted_size).collect();
+fn body_stream(resp: reqwest::Response) -> impl Stream<Item = Result<Bytes, TransferError>> {
+    resp.bytes_stream()
+        .map(|r| r.map_err(|e| ErrorCode::Network.err(format!("Stream error: {}", e))))
+}
 
-            // Verify chunk hash
-            let mut chunk_hasher = Sha256::new();
-            chunk_hasher.update(&encrypted_chunk);
-            let actual_hash = hex::encode(chunk_hasher.finalize());
+/// Where decrypted chunks go, in order.
+trait PlaintextSink {
+    /// Take the next chunk. `Ok(false)` stops the download early.
+    async fn put(&mut self, plaintext: &[u8]) -> Result<bool, TransferError>;
+}
 
-            if actual_hash != chunk_hashes[chunk_idx] {
-                // Try to re-download this specific chunk using Range header
-                let redownloaded = retry_chunk(
-                    &client, server_url, transfer_id, jwt_token,
-                    chunk_idx, &chunk_hashes[chunk_idx],
-                ).await?;
+impl PlaintextSink for tokio::fs::File {
+    async fn put(&mut self, plaintext: &[u8]) -> Result<bool, TransferError> {
+        self.write_all(plaintext).await
+            .map_err(|e| ErrorCode::FileIo.err(format!("Write error: {}", e)))?;
+        Ok(true)
+    }
+}
 
-                // Decrypt the re-downloaded chunk
-                let plaintext = decrypt_chunk(&key, &redownloaded)
-                    .map_err(|e| format!("Decrypt failed on retry chunk {}: {}", chunk_idx, e))?;
-                output_file.write_all(&plaintext).await
-                    .map_err(|e| format!("Write error: {}", e))?;
+struct CallbackSink(ChunkCallback);
 
-                full_hasher.update(&redownloaded);
-            } else {
-                // Hash matches, decrypt and write
+impl PlaintextSink for CallbackSink {
+    async fn put(&mut self, plaintext: &[u8]) -> Result<bool, TransferError> {
+        Ok((self.0)(plaintext.as_ptr(), plaintext.len()))
+    }
+}
+
+/// One transfer's download parameters, shared by the chunk loop and its
+/// Range retries.
+struct ChunkFetch<'a> {
+    client: &'a Client,
+    server_url: &'a str,
+    transfer_id: &'a str,
+    jwt_token: &'a str,
+    key: &'a [u8; 32],
+    layout: &'a ChunkLayout,
+    file_sha256: &'a str,
+    chunk_hashes: &'a [String],
+}
+
+impl ChunkFetch<'_> {
+    /// Split `stream` into encrypted chunks, verify and decrypt each, and
+    /// pass the plaintext to `sink`. A chunk with the wrong hash is fetched
+    /// again by Range before it reaches the sink. Returns once the sink
+    /// stops early or the whole file has passed its full-file hash check.
+    ///
+    /// `stream` starts at chunk `resume.chunks`; the chunks before it are
+    /// already in the sink.
+    async fn stream_plaintext(
+        &self,
+        stream: impl Stream<Item = Result<Bytes, TransferError>>,
+        progress: &DownloadProgress,
+        resume: ResumePoint,
+        sink: &mut impl PlaintextSink,
+    ) -> Result<(), TransferError> {
+        let chunk_hashes = self.chunk_hashes;
+        let slot_size = self.layout.slot_size();
+        let cipher = self.layout.cipher(self.key);
+        let mut stream = std::pin::pin!(stream);
+        let mut buf = Vec::with_capacity(slot_size + 1024);
+        let ResumePoint { chunks: mut chunk_idx, mut full_hasher } = resume;
+
+        while let Some(result) = stream.next().await {
+            // Holding off on the stream lets TCP backpressure pause the server.
+            progress.wait_while_paused().await;
+            if progress.is_cancelled() {
+                progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
+                return Err(TransferError::cancelled());
+            }
+
+            let data = result?;
+            buf.extend_from_slice(&data);
+            progress.add_bytes(data.len() as u64);
+
+            // Process all complete full-size encrypted chunks from the buffer.
+            // We deliberately skip the last chunk here — it may be smaller than a full
+            // chunk, so we let the post-stream handler deal with it once the stream ends.
+            while chunk_idx + 1 < chunk_hashes.len() {
+                if buf.len() < slot_size {
+                    break; // Need more data
+                }
+
+                let mut encrypted_chunk: Vec<u8> = buf.drain(..slot_size).collect();
+
+                // Verify chunk hash
+                if chunk_hash(&encrypted_chunk) != chunk_hashes[chunk_idx] {
+                    // Try to re-download this specific chunk using Range header
+                    encrypted_chunk = retry_chunk(
+                        self.client, self.server_url, self.transfer_id, self.jwt_token,
+                        chunk_idx, slot_size, &chunk_hashes[chunk_idx],
+                    ).await?;
+                }
                 full_hasher.update(&encrypted_chunk);
 
-                let plaintext = decrypt_chunk(&key, &encrypted_chunk)
-                    .map_err(|e| format!("Decrypt failed on chunk {}: {}", chunk_idx, e))?;
-                output_file.write_all(&plaintext).await
-                    .map_err(|e| format!("Write error: {}", e))?;
+                let plaintext = self.layout.open(&cipher, chunk_idx, &encrypted_chunk)
+                    .map_err(|e| ErrorCode::HashMismatch.err(format!("Decrypt failed on chunk {}: {}", chunk_idx, e)))?;
+                if !sink.put(&plaintext).await? {
+                    return Ok(());
+                }
+
+                chunk_idx += 1;
+            }
+        }
+
+        // Handle any remaining data in buffer (last chunk)
+        if !buf.is_empty() && chunk_idx < chunk_hashes.len() {
+            if chunk_hash(&buf) != chunk_hashes[chunk_idx] {
+                progress.state.store(STATE_ERROR, Ordering::Relaxed);
+                return Err(ErrorCode::HashMismatch.err(format!("Final chunk {} hash mismatch", chunk_idx)));
             }
 
-            chunk_idx += 1;
+            full_hasher.update(&buf);
+            let plaintext = self.layout.open(&cipher, chunk_idx, &buf)
+                .map_err(|e| ErrorCode::HashMismatch.err(format!("Decrypt failed on final chunk: {}", e)))?;
+            if !sink.put(&plaintext).await? {
+                return Ok(());
+            }
         }
+
+        // Verify full file hash
+        let actual_full_hash = hex::encode(full_hasher.finalize());
+        if actual_full_hash != self.file_sha256 {
+            progress.state.store(STATE_ERROR, Ordering::Relaxed);
+            return Err(ErrorCode::HashMismatch.err(format!(
+                "Full file hash mismatch: expected {}, got {}",
+                self.file_sha256, actual_full_hash
+            )));
+        }
+        Ok(())
     }
+}
 
-    // Handle any remaining data in buffer (last chunk)
-    if !buf.is_empty() && chunk_idx < chunk_hashes.len() {
-        let mut chunk_hasher = Sha256::new();
-        chunk_hasher.update(&buf);
-        let actual_hash = hex::encode(chunk_hasher.finalize());
+/// Fetch the stored (encrypted) bytes of `transfer_id` into `out_path`
+/// without decrypting them, checking each `encrypted_chunk_size` slot
+/// against `chunk_hashes` and the whole against `file_sha256`. This is what
+/// a fast download's receiver would have left there.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn fetch_encrypted(
+    client: &Client,
+    server_url: &str,
+    transfer_id: &str,
+    jwt_token: &str,
+    out_path: &str,
+    encrypted_chunk_size: u64,
+    file_sha256: &str,
+    chunk_hashes: &[String],
+    progress: &DownloadProgress,
+) -> Result<(), TransferError> {
+    let resp = start_download(client, server_url, transfer_id, jwt_token, 0, progress).await?;
+    let mut stream = Box::pin(body_stream(resp));
+    let mut out = tokio::fs::File::create(out_path)
+        .await
+        .map_err(|e| ErrorCode::FileIo.err(format!("Cannot create '{}': {}", out_path, e)))?;
 
-        if actual_hash != chunk_hashes[chunk_idx] {
-            progress.state.store(STATE_ERROR, Ordering::Relaxed);
-            return Err(format!("Final chunk {} hash mismatch", chunk_idx));
+    let mut full_hasher = Sha256::new();
+    let mut buf = Vec::with_capacity(encrypted_chunk_size as usize);
+    let mut idx = 0;
+    loop {
+        let next = stream.next().await.transpose()?;
+        if let Some(bytes) = &next {
+            buf.extend_from_slice(bytes);
         }
+        // Full slots as they arrive; whatever is left is the last one.
+        while idx < chunk_hashes.len() && (buf.len() as u64 >= encrypted_chunk_size || (next.is_none() && !buf.is_empty())) {
+            if progress.is_cancelled() {
+                return Err(TransferError::cancelled());
+            }
+            let take = buf.len().min(encrypted_chunk_size as usize);
+            let slot: Vec<u8> = buf.drain(..take).collect();
+            if chunk_hash(&slot) != chunk_hashes[idx] {
+                return Err(ErrorCode::HashMismatch.err(format!("Chunk {} hash mismatch", idx)));
+            }
+            full_hasher.update(&slot);
+            out.write_all(&slot)
+                .await
+                .map_err(|e| ErrorCode::FileIo.err(format!("Write chunk {}: {}", idx, e)))?;
+            progress.add_bytes(slot.len() as u64);
+            idx += 1;
+        }
+        if next.is_none() {
+            break;
+        }
+    }
+    out.flush().await.map_err(|e| ErrorCode::FileIo.err(format!("Flush error: {}", e)))?;
 
-        full_hasher.update(&buf);
-        let plaintext = decrypt_chunk(&key, &buf)
-            .map_err(|e| format!("Decrypt failed on final chunk: {}", e))?;
-        output_file.write_all(&plaintext).await
-            .map_err(|e| format!("Write error: {}", e))?;
+    if idx < chunk_hashes.len() || !buf.is_empty() {
+        return Err(ErrorCode::HashMismatch.err(format!(
+            "Got {} of {} chunks from the server",
+            idx,
+            chunk_hashes.len()
+        )));
     }
+    let actual_full_hash = hex::encode(full_hasher.finalize());
+    if actual_full_hash != file_sha256 {
+        return Err(ErrorCode::HashMismatch.err(format!(
+            "Full file hash mismatch: expected {}, got {}",
+            file_sha256, actual_full_hash
+        )));
+    }
+    Ok(())
+}
 
-    output_file.flush().await.map_err(|e| format!("Flush error: {}", e))?;
+/// Hex SHA-256 of an encrypted chunk, the form a transfer lists its chunk
+/// hashes in.
+pub fn chunk_hash(encrypted: &[u8]) -> String {
+    hex::encode(Sha256::digest(encrypted))
+}
 
-    // Verify full file hash
+/// Check a downloaded file against its transfer's hashes without fetching
+/// anything, e.g. for an integrity audit or to decide whether to resume.
+///
+/// The hashes cover the encrypted chunks, so each plaintext chunk is
+/// re-encrypted under its deterministic nonce (as the uploader did) and
+/// hashed. A mismatch fails with `HashMismatch` listing the bad chunk
+/// indices. Without the server to report a `ChunkLayout`, only transfers
+/// stored in the default one can be checked.
+///
+/// Blocking; reports `STATE_HASHING` while it runs, then `STATE_COMPLETE`.
+pub fn verify_file(
+    save_path: &str,
+    master_key: &[u8],
+    salt: &[u8],
+    file_sha256: &str,
+    chunk_hashes: &[String],
+    progress: &DownloadProgress,
+) -> Result<(), TransferError> {
+    use std::io::Read;
+
+    let key = derive_key(master_key, salt);
+    let mut file = std::fs::File::open(save_path)
+        .map_err(|e| ErrorCode::FileIo.err(format!("Cannot open '{}': {}", save_path, e)))?;
+    let file_len = file
+        .metadata()
+        .map_err(|e| ErrorCode::FileIo.err(format!("Cannot stat '{}': {}", save_path, e)))?
+        .len();
+    progress.bytes_total.store(file_len, Ordering::Relaxed);
+    progress.state.store(STATE_HASHING, Ordering::Relaxed);
+
+    let mut full_hasher = Sha256::new();
+    let mut mismatched = Vec::new();
+    let mut plaintext = Vec::with_capacity(CHUNK_SIZE);
+    for (idx, expected) in chunk_hashes.iter().enumerate() {
+        progress.block_while_paused();
+        if progress.is_cancelled() {
+            progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
+            return Err(TransferError::cancelled());
+        }
+
+        // A short or missing chunk just hashes differently.
+        plaintext.clear();
+        (&mut file)
+            .take(CHUNK_SIZE as u64)
+            .read_to_end(&mut plaintext)
+            .map_err(|e| ErrorCode::FileIo.err(format!("Read error at chunk {}: {}", idx, e)))?;
+        let encrypted = encrypt_chunk_with_nonce(&key, &plaintext, derive_chunk_nonce(&key, idx as u64))
+            .map_err(|e| ErrorCode::Protocol.err(format!("Chunk {}: {}", idx, e)))?;
+
+        full_hasher.update(&encrypted);
+        if chunk_hash(&encrypted) != *expected {
+            mismatched.push(idx);
+        }
+        progress.add_bytes(plaintext.len() as u64);
+    }
+
+    if !mismatched.is_empty() {
+        return Err(ErrorCode::HashMismatch.err(format!(
+            "{} of {} chunks do not match: {:?}",
+            mismatched.len(),
+            chunk_hashes.len(),
+            mismatched
+        )));
+    }
+    let covered = progress.bytes_done.load(Ordering::Relaxed);
+    if file_len > covered {
+        return Err(ErrorCode::HashMismatch.err(format!(
+            "Local file has {} bytes past the last chunk",
+            file_len - covered
+        )));
+    }
     let actual_full_hash = hex::encode(full_hasher.finalize());
     if actual_full_hash != file_sha256 {
-        progress.state.store(STATE_ERROR, Ordering::Relaxed);
-        return Err(format!(
+        return Err(ErrorCode::HashMismatch.err(format!(
             "Full file hash mismatch: expected {}, got {}",
             file_sha256, actual_full_hash
-        ));
+        )));
     }
 
-    // Confirm download with server
-    let _ = client
-        .post(format!("{}/transfers/{}/confirm", server_url, transfer_id))
-        .header("Authorization", format!("Bearer {}", jwt_token))
-        .send()
-        .await;
-
     progress.state.store(STATE_COMPLETE, Ordering::Relaxed);
     Ok(())
 }
@@ -208,36 +1069,603 @@ async fn retry_chunk(
     transfer_id: &str,
     jwt_token: &str,
     chunk_idx: usize,
+    slot_size: usize,
     expected_hash: &str,
-) -> Result<Vec<u8>, String> {
-    // We need to calculate the byte range for this chunk.
-    // Each encrypted chunk is CHUNK_SIZE + 28 bytes (12 nonce + 16 tag),
-    // except possibly the last one.
-    let encrypted_chunk_size = CHUNK_SIZE + 12 + 16;
-    let start = chunk_idx as u64 * encrypted_chunk_size as u64;
+) -> Result<Vec<u8>, TransferError> {
+    // Every chunk but possibly the last fills a whole slot.
+    let start = chunk_idx as u64 * slot_size as u64;
 
     let resp = client
         .get(format!("{}/transfers/{}/data", server_url, transfer_id))
         .header("Authorization", format!("Bearer {}", jwt_token))
-        .header("Range", format!("bytes={}-{}", start, start + encrypted_chunk_size as u64 - 1))
+        .header("Range", format!("bytes={}-{}", start, start + slot_size as u64 - 1))
         .send()
         .await
-        .map_err(|e| format!("Retry chunk {} failed: {}", chunk_idx, e))?;
+        .map_err(|e| ErrorCode::Network.err(format!("Retry chunk {} failed: {}", chunk_idx, e)))?;
 
     let data = resp.bytes().await
-        .map_err(|e| format!("Retry chunk {} read failed: {}", chunk_idx, e))?;
+        .map_err(|e| ErrorCode::Network.err(format!("Retry chunk {} read failed: {}", chunk_idx, e)))?;
 
     // Verify hash
-    let mut hasher = Sha256::new();
-    hasher.update(&data);
-    let actual_hash = hex::encode(hasher.finalize());
-
+    let actual_hash = chunk_hash(&data);
     if actual_hash != expected_hash {
-        return Err(format!(
+        return Err(ErrorCode::HashMismatch.err(format!(
             "Retry chunk {} hash still mismatches: expected {}, got {}",
             chunk_idx, expected_hash, actual_hash
-        ));
+        )));
     }
 
     Ok(data.to_vec())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where the tests' download of transfer "t" comes from.
+    fn source<'a>(
+        url: &'a str,
+        master_key: &'a [u8],
+        salt: &'a [u8],
+        file_sha256: &'a str,
+        chunk_hashes: &'a [String],
+    ) -> DownloadSource<'a> {
+        DownloadSource { server_url: url, transfer_id: "t", jwt_token: "jwt", master_key, salt, file_sha256, chunk_hashes }
+    }
+
+    #[test]
+    fn verify_file_reports_mismatched_chunks() {
+        let dir = std::env::temp_dir().join(format!("haven-verify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.bin");
+        let path_str = path.to_str().unwrap();
+        let (master_key, salt) = (b"master-key".as_slice(), b"salt".as_slice());
+
+        // Hashes as the uploader would have computed them: two chunks.
+        let mut data: Vec<u8> = (0..CHUNK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let key = derive_key(master_key, salt);
+        let mut full_hasher = Sha256::new();
+        let chunk_hashes: Vec<String> = data
+            .chunks(CHUNK_SIZE)
+            .enumerate()
+            .map(|(idx, chunk)| {
+                let encrypted = encrypt_chunk_with_nonce(&key, chunk, derive_chunk_nonce(&key, idx as u64)).unwrap();
+                full_hasher.update(&encrypted);
+                chunk_hash(&encrypted)
+            })
+            .collect();
+        let file_sha256 = hex::encode(full_hasher.finalize());
+
+        std::fs::write(&path, &data).unwrap();
+        let progress = DownloadProgress::new();
+        verify_file(path_str, master_key, salt, &file_sha256, &chunk_hashes, &progress).unwrap();
+        assert_eq!(progress.state.load(Ordering::Relaxed), STATE_COMPLETE);
+        assert_eq!(progress.bytes_done.load(Ordering::Relaxed), data.len() as u64);
+
+        data[CHUNK_SIZE + 5] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        let err = verify_file(path_str, master_key, salt, &file_sha256, &chunk_hashes, &DownloadProgress::new()).unwrap_err();
+        assert_eq!(err.code, ErrorCode::HashMismatch);
+        assert_eq!(err.message, "1 of 2 chunks do not match: [1]");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Collects what `stream_plaintext` delivers, stopping after `limit` chunks.
+    struct Collect {
+        chunks: Vec<Vec<u8>>,
+        limit: usize,
+    }
+
+    impl PlaintextSink for Collect {
+        async fn put(&mut self, plaintext: &[u8]) -> Result<bool, TransferError> {
+            self.chunks.push(plaintext.to_vec());
+            Ok(self.chunks.len() < self.limit)
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_plaintext_delivers_verified_chunks_in_order() {
+        let key = derive_key(b"master-key", b"salt");
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 300).map(|i| (i % 241) as u8).collect();
+        let mut full_hasher = Sha256::new();
+        let mut encrypted = Vec::new();
+        let mut chunk_hashes = Vec::new();
+        for (idx, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+            let sealed = encrypt_chunk_with_nonce(&key, chunk, derive_chunk_nonce(&key, idx as u64)).unwrap();
+            full_hasher.update(&sealed);
+            chunk_hashes.push(chunk_hash(&sealed));
+            encrypted.extend(sealed);
+        }
+        let file_sha256 = hex::encode(full_hasher.finalize());
+
+        let client = Client::new();
+        let fetch = ChunkFetch {
+            client: &client,
+            server_url: "http://127.0.0.1:9",
+            transfer_id: "preview",
+            jwt_token: "",
+            key: &key,
+            layout: &ChunkLayout::default(),
+            file_sha256: &file_sha256,
+            chunk_hashes: &chunk_hashes,
+        };
+        // The body arrives in pieces that don't line up with chunks.
+        let body = |bytes: Vec<u8>| {
+            let pieces: Vec<Result<Bytes, TransferError>> =
+                bytes.chunks(1_000_000).map(|p| Ok(Bytes::copy_from_slice(p))).collect();
+            futures_util::stream::iter(pieces)
+        };
+
+        let mut all = Collect { chunks: Vec::new(), limit: usize::MAX };
+        fetch.stream_plaintext(body(encrypted.clone()), &DownloadProgress::new(), ResumePoint::default(), &mut all).await.unwrap();
+        assert_eq!(all.chunks.len(), 3);
+        assert_eq!(all.chunks.concat(), data);
+
+        // The sink can stop after the first chunk.
+        let mut first = Collect { chunks: Vec::new(), limit: 1 };
+        fetch.stream_plaintext(body(encrypted.clone()), &DownloadProgress::new(), ResumePoint::default(), &mut first).await.unwrap();
+        assert_eq!(first.chunks, vec![data[..CHUNK_SIZE].to_vec()]);
+
+        // A tampered chunk is never delivered and aborts the stream.
+        let mut tampered = encrypted;
+        *tampered.last_mut().unwrap() ^= 1;
+        let mut sink = Collect { chunks: Vec::new(), limit: usize::MAX };
+        let err = fetch.stream_plaintext(body(tampered), &DownloadProgress::new(), ResumePoint::default(), &mut sink).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::HashMismatch);
+        assert_eq!(sink.chunks.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stream_plaintext_follows_the_transfer_layout() {
+        use haven_fast_transfer::MIN_CHUNK_SIZE;
+
+        // A fast upload's: small ChaCha20-Poly1305 chunks.
+        let data: Vec<u8> = (0..MIN_CHUNK_SIZE * 3 + 10).map(|i| (i % 227) as u8).collect();
+        let key = derive_key(b"master-key", b"salt");
+        let file_size = (data.len() + 4 * 28) as u64;
+        let control_mac = ControlFields {
+            transfer_id: &parse_transfer_id_bytes("t"),
+            file_size,
+            chunk_size: (MIN_CHUNK_SIZE + 28) as u64,
+            chunk_count: 4,
+            file_sha256: "",
+            sparse_chunks: &[],
+        }
+        .mac(&key);
+        let mut status = serde_json::json!({
+            "file_size": file_size,
+            "chunk_size": MIN_CHUNK_SIZE + 28,
+            "aead": AeadAlgorithm::ChaCha20Poly1305.id(),
+            "control_mac": control_mac,
+        });
+        let offered = |chunk_count| Offered { transfer_id: "t", key: &key, file_sha256: "", chunk_count };
+        let layout = ChunkLayout::from_status(&status, &offered(4)).unwrap();
+        assert_eq!(
+            layout,
+            ChunkLayout { chunk_size: MIN_CHUNK_SIZE, aead: AeadAlgorithm::ChaCha20Poly1305, ..Default::default() }
+        );
+        // The MAC covers the chunk count, and is required for a layout only
+        // a fast upload picks.
+        assert_eq!(ChunkLayout::from_status(&status, &offered(3)).unwrap_err().code, ErrorCode::HashMismatch);
+        status["control_mac"] = serde_json::Value::Null;
+        assert_eq!(ChunkLayout::from_status(&status, &offered(4)).unwrap_err().code, ErrorCode::HashMismatch);
+        assert_eq!(ChunkLayout::from_status(&serde_json::json!({}), &offered(4)).unwrap(), ChunkLayout::default());
+
+        let cipher = layout.cipher(&key);
+        let mut full_hasher = Sha256::new();
+        let mut encrypted = Vec::new();
+        let mut chunk_hashes = Vec::new();
+        for (idx, chunk) in data.chunks(MIN_CHUNK_SIZE).enumerate() {
+            let sealed = cipher.seal_chunk(&key, idx as u32, MIN_CHUNK_SIZE, chunk).unwrap();
+            full_hasher.update(&sealed);
+            chunk_hashes.push(chunk_hash(&sealed));
+            encrypted.extend(sealed);
+        }
+        let file_sha256 = hex::encode(full_hasher.finalize());
+
+        let client = Client::new();
+        let fetch = ChunkFetch {
+            client: &client,
+            server_url: "http://127.0.0.1:9",
+            transfer_id: "t",
+            jwt_token: "",
+            key: &key,
+            layout: &layout,
+            file_sha256: &file_sha256,
+            chunk_hashes: &chunk_hashes,
+        };
+        let body = futures_util::stream::iter([Ok(Bytes::from(encrypted))]);
+        let mut all = Collect { chunks: Vec::new(), limit: usize::MAX };
+        fetch.stream_plaintext(body, &DownloadProgress::new(), ResumePoint::default(), &mut all).await.unwrap();
+        assert_eq!(all.chunks.len(), 4);
+        assert_eq!(all.chunks.concat(), data);
+    }
+
+    #[tokio::test]
+    async fn compressed_slots_are_opened_and_resumed() {
+        use haven_fast_transfer::MIN_CHUNK_SIZE;
+
+        let layout = ChunkLayout { chunk_size: MIN_CHUNK_SIZE, compressed: true, ..Default::default() };
+        let key = derive_key(b"master-key", b"salt");
+        let cipher = layout.cipher(&key);
+        // Compressible text, then bytes zstd can't shrink.
+        let mut data = b"haven ".repeat(MIN_CHUNK_SIZE / 3);
+        data.extend((0..MIN_CHUNK_SIZE as u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8));
+        let mut full_hasher = Sha256::new();
+        let mut encrypted = Vec::new();
+        let mut chunk_hashes = Vec::new();
+        for (idx, chunk) in data.chunks(MIN_CHUNK_SIZE).enumerate() {
+            let sealed = layout.seal(&cipher, &key, idx, chunk).unwrap();
+            assert_eq!(sealed.len(), slot_size(chunk.len(), true, layout.aead));
+            full_hasher.update(&sealed);
+            chunk_hashes.push(chunk_hash(&sealed));
+            encrypted.extend(sealed);
+        }
+        let file_sha256 = hex::encode(full_hasher.finalize());
+
+        let client = Client::new();
+        let fetch = ChunkFetch {
+            client: &client,
+            server_url: "http://127.0.0.1:9",
+            transfer_id: "t",
+            jwt_token: "",
+            key: &key,
+            layout: &layout,
+            file_sha256: &file_sha256,
+            chunk_hashes: &chunk_hashes,
+        };
+        let body = futures_util::stream::iter([Ok(Bytes::from(encrypted))]);
+        let mut all = Collect { chunks: Vec::new(), limit: usize::MAX };
+        fetch.stream_plaintext(body, &DownloadProgress::new(), ResumePoint::default(), &mut all).await.unwrap();
+        assert_eq!(all.chunks.concat(), data);
+
+        // A `.part` holding the plaintext re-seals to the same slots.
+        let dir = std::env::temp_dir().join(format!("haven-zstd-part-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let part = dir.join("file.part");
+        std::fs::write(&part, &data).unwrap();
+        let resume = verified_prefix(part.to_str().unwrap(), &key, &chunk_hashes, &layout).unwrap();
+        assert_eq!(resume.chunks, chunk_hashes.len() - 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn zero_slots_open_only_for_chunks_the_control_mac_lists() {
+        use haven_fast_transfer::MIN_CHUNK_SIZE;
+
+        let key = derive_key(b"master-key", b"salt");
+        let cipher = ChunkLayout::default().cipher(&key);
+        let mut data: Vec<u8> = (0..MIN_CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+        data.extend(vec![0u8; MIN_CHUNK_SIZE]);
+        let mut full_hasher = Sha256::new();
+        let mut encrypted = Vec::new();
+        let mut chunk_hashes = Vec::new();
+        for (idx, chunk) in data.chunks(MIN_CHUNK_SIZE).enumerate() {
+            let sealed = if idx == 1 {
+                sparse::zero_slot(chunk.len())
+            } else {
+                cipher.seal_chunk(&key, idx as u32, MIN_CHUNK_SIZE, chunk).unwrap()
+            };
+            full_hasher.update(&sealed);
+            chunk_hashes.push(chunk_hash(&sealed));
+            encrypted.extend(sealed);
+        }
+        let file_sha256 = hex::encode(full_hasher.finalize());
+
+        let slot = slot_size(MIN_CHUNK_SIZE, false, AeadAlgorithm::Aes256Gcm) as u64;
+        let mac = |key: &[u8; 32]| {
+            ControlFields {
+                transfer_id: &parse_transfer_id_bytes("t"),
+                file_size: encrypted.len() as u64,
+                chunk_size: slot,
+                chunk_count: 2,
+                file_sha256: &file_sha256,
+                sparse_chunks: &[1],
+            }
+            .mac(key)
+        };
+        let status = |control_mac: Option<String>| {
+            serde_json::json!({
+                "file_size": encrypted.len(),
+                "chunk_size": slot,
+                "sparse_chunks": [1],
+                "control_mac": control_mac,
+            })
+        };
+        let offered = Offered { transfer_id: "t", key: &key, file_sha256: &file_sha256, chunk_count: 2 };
+        // A sparse list the uploader didn't MAC is refused.
+        for control_mac in [None, Some(mac(&derive_key(b"other", b"salt")))] {
+            let err = ChunkLayout::from_status(&status(control_mac), &offered).unwrap_err();
+            assert_eq!(err.code, ErrorCode::HashMismatch);
+        }
+        let listed = ChunkLayout::from_status(&status(Some(mac(&key))), &offered).unwrap();
+        assert_eq!(listed.sparse_chunks, [1]);
+        let unlisted = ChunkLayout { chunk_size: MIN_CHUNK_SIZE, ..Default::default() };
+
+        let client = Client::new();
+        for (layout, opens) in [(&listed, true), (&unlisted, false)] {
+            let fetch = ChunkFetch {
+                client: &client,
+                server_url: "http://127.0.0.1:9",
+                transfer_id: "t",
+                jwt_token: "",
+                key: &key,
+                layout,
+                file_sha256: &file_sha256,
+                chunk_hashes: &chunk_hashes,
+            };
+            let body = futures_util::stream::iter([Ok(Bytes::from(encrypted.clone()))]);
+            let mut all = Collect { chunks: Vec::new(), limit: usize::MAX };
+            let result = fetch.stream_plaintext(body, &DownloadProgress::new(), ResumePoint::default(), &mut all).await;
+            if opens {
+                result.unwrap();
+                assert_eq!(all.chunks.concat(), data);
+            } else {
+                assert_eq!(result.unwrap_err().code, ErrorCode::HashMismatch);
+                assert_eq!(all.chunks.len(), 1);
+            }
+        }
+    }
+
+    /// Answer the status request a download starts with: a transfer stored
+    /// in the default layout.
+    async fn serve_status(listener: &tokio::net::TcpListener) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("get /transfers/t "), "{}", head);
+        let reply = "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\n{}";
+        stream.write_all(reply.as_bytes()).await.unwrap();
+    }
+
+    /// Answer the status request and then `requests` more on `listener`, the
+    /// first with `body` and the rest (the confirm) empty, then stop
+    /// listening.
+    async fn serve(listener: tokio::net::TcpListener, body: Vec<u8>, requests: usize) {
+        serve_status(&listener).await;
+        for i in 0..requests {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_head(&mut stream).await;
+            let body = if i == 0 { body.as_slice() } else { &[] };
+            let reply = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n", body.len());
+            stream.write_all(reply.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+    }
+
+    /// Read a request up to the end of its headers, lowercased.
+    async fn read_head(stream: &mut tokio::net::TcpStream) -> String {
+        use tokio::io::AsyncReadExt;
+        let mut head = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await.unwrap();
+            head.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8_lossy(&head).to_ascii_lowercase()
+    }
+
+    #[tokio::test]
+    async fn download_only_appears_at_save_path_once_verified() {
+        let dir = std::env::temp_dir().join(format!("haven-part-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let save_path = dir.join("photo.jpg");
+        let save_str = save_path.to_str().unwrap();
+        let (master_key, salt) = (b"master-key".as_slice(), b"salt".as_slice());
+
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 253) as u8).collect();
+        let key = derive_key(master_key, salt);
+        let sealed = encrypt_chunk_with_nonce(&key, &data, derive_chunk_nonce(&key, 0)).unwrap();
+        let chunk_hashes = vec![chunk_hash(&sealed)];
+        let file_sha256 = chunk_hash(&sealed);
+
+        let (file_sha256, chunk_hashes) = (&file_sha256, &chunk_hashes);
+        let download = |body: Vec<u8>, requests| async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let server = tokio::spawn(serve(listener, body, requests));
+            let progress = Arc::new(DownloadProgress::new());
+            let result =
+                download_file(save_str, &source(&url, master_key, salt, file_sha256, chunk_hashes), progress).await;
+            server.await.unwrap();
+            result
+        };
+
+        // A corrupted body fails its hash check: nothing is left behind.
+        let mut tampered = sealed.clone();
+        tampered[40] ^= 1;
+        let err = download(tampered, 1).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::HashMismatch);
+        assert!(!save_path.exists());
+        assert!(!std::path::Path::new(&part_path(save_str)).exists());
+
+        download(sealed, 2).await.unwrap();
+        assert_eq!(std::fs::read(&save_path).unwrap(), data);
+        assert!(!std::path::Path::new(&part_path(save_str)).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn resumed_download_starts_at_the_verified_part() {
+        let dir = std::env::temp_dir().join(format!("haven-resume-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let save_path = dir.join("video.mp4");
+        let save_str = save_path.to_str().unwrap();
+        let (master_key, salt) = (b"master-key".as_slice(), b"salt".as_slice());
+
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2).map(|i| (i % 239) as u8).collect();
+        let key = derive_key(master_key, salt);
+        let mut full_hasher = Sha256::new();
+        let mut encrypted = Vec::new();
+        let mut chunk_hashes = Vec::new();
+        for (idx, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+            let sealed = encrypt_chunk_with_nonce(&key, chunk, derive_chunk_nonce(&key, idx as u64)).unwrap();
+            full_hasher.update(&sealed);
+            chunk_hashes.push(chunk_hash(&sealed));
+            encrypted.extend(sealed);
+        }
+        let file_sha256 = hex::encode(full_hasher.finalize());
+
+        // An earlier attempt got through chunk 0 and part of chunk 1.
+        std::fs::write(part_path(save_str), &data[..CHUNK_SIZE + 1234]).unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let rest = encrypted[ENCRYPTED_CHUNK_SIZE..].to_vec();
+        let (go_tx, go_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            serve_status(&listener).await;
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let head = read_head(&mut stream).await;
+            assert!(head.contains(&format!("range: bytes={}-", ENCRYPTED_CHUNK_SIZE)), "{}", head);
+            let reply = format!(
+                "HTTP/1.1 206 Partial Content\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                rest.len()
+            );
+            stream.write_all(reply.as_bytes()).await.unwrap();
+            // Hold the body back until the starting progress has been read.
+            go_rx.await.unwrap();
+            stream.write_all(&rest).await.unwrap();
+            drop(stream);
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_head(&mut stream).await;
+            stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await.unwrap();
+        });
+
+        let progress = Arc::new(DownloadProgress::new());
+        let starting_progress = async {
+            while progress.bytes_total.load(Ordering::Relaxed) == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+            let start = (progress.bytes_done.load(Ordering::Relaxed), progress.bytes_total.load(Ordering::Relaxed));
+            go_tx.send(()).unwrap();
+            start
+        };
+        let from = source(&url, master_key, salt, &file_sha256, &chunk_hashes);
+        let download = download_file(save_str, &from, progress.clone());
+        let (result, (done, total)) = tokio::join!(download, starting_progress);
+        result.unwrap();
+        server.await.unwrap();
+
+        assert_eq!(total, encrypted.len() as u64);
+        assert_eq!(done, ENCRYPTED_CHUNK_SIZE as u64);
+        assert_eq!(done * 2, total);
+        assert_eq!(progress.bytes_done.load(Ordering::Relaxed), total);
+        assert_eq!(std::fs::read(&save_path).unwrap(), data);
+        assert!(!std::path::Path::new(&part_path(save_str)).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn parallel_download_reassembles_chunks_from_the_manifest() {
+        let dir = std::env::temp_dir().join(format!("haven-parallel-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let save_path = dir.join("archive.tar");
+        let save_str = save_path.to_str().unwrap();
+        let (master_key, salt) = (b"master-key".as_slice(), b"salt".as_slice());
+
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 300).map(|i| (i % 233) as u8).collect();
+        let key = derive_key(master_key, salt);
+        let mut full_hasher = Sha256::new();
+        let mut encrypted = Vec::new();
+        let mut chunk_hashes = Vec::new();
+        let mut manifest = Vec::new();
+        for (idx, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+            let sealed = encrypt_chunk_with_nonce(&key, chunk, derive_chunk_nonce(&key, idx as u64)).unwrap();
+            full_hasher.update(&sealed);
+            chunk_hashes.push(chunk_hash(&sealed));
+            manifest.push(serde_json::json!({
+                "index": idx, "offset": encrypted.len(), "length": sealed.len(),
+                "sha256": chunk_hash(&sealed), "received": true,
+            }));
+            encrypted.extend(sealed);
+        }
+        let file_sha256 = hex::encode(full_hasher.finalize());
+        let manifest = serde_json::json!({
+            "transfer_id": "t", "status": "complete", "file_size": encrypted.len(),
+            "file_sha256": file_sha256, "chunks": manifest,
+        })
+        .to_string();
+
+        // Serves the manifest and byte ranges, one request per connection;
+        // the first fetch of chunk 1 comes back corrupted.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (encrypted, ranges) = (Arc::new(encrypted), Arc::new(std::sync::Mutex::new(Vec::new())));
+        let server = tokio::spawn({
+            let (encrypted, ranges) = (encrypted.clone(), ranges.clone());
+            async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let (encrypted, ranges, manifest) = (encrypted.clone(), ranges.clone(), manifest.clone());
+                    tokio::spawn(async move {
+                        let head = read_head(&mut stream).await;
+                        let (status, body) = if head.starts_with("get /transfers/t/manifest") {
+                            ("200 OK", manifest.into_bytes())
+                        } else if head.starts_with("get /transfers/t ") {
+                            ("200 OK", b"{}".to_vec())
+                        } else if let Some(range) = head.split("range: bytes=").nth(1) {
+                            let range = range.lines().next().unwrap();
+                            let (start, end) = range.split_once('-').unwrap();
+                            let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                            let mut body = encrypted[start..=end].to_vec();
+                            let mut ranges = ranges.lock().unwrap();
+                            if start == ENCRYPTED_CHUNK_SIZE && !ranges.contains(&start) {
+                                body[0] ^= 1;
+                            }
+                            ranges.push(start);
+                            ("206 Partial Content", body)
+                        } else {
+                            ("200 OK", Vec::new())
+                        };
+                        let reply = format!(
+                            "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                            status,
+                            body.len()
+                        );
+                        stream.write_all(reply.as_bytes()).await.unwrap();
+                        stream.write_all(&body).await.unwrap();
+                    });
+                }
+            }
+        });
+
+        let progress = Arc::new(DownloadProgress::new());
+        download_file_parallel(save_str, &source(&url, master_key, salt, &file_sha256, &chunk_hashes), progress.clone())
+            .await
+            .unwrap();
+        server.abort();
+
+        assert_eq!(std::fs::read(&save_path).unwrap(), data);
+        assert!(!std::path::Path::new(&part_path(save_str)).exists());
+        assert_eq!(progress.state.load(Ordering::Relaxed), STATE_COMPLETE);
+        assert_eq!(progress.bytes_done.load(Ordering::Relaxed), encrypted.len() as u64);
+        // Every chunk was fetched on its own, the corrupted one twice.
+        let mut ranges = ranges.lock().unwrap().clone();
+        ranges.sort();
+        assert_eq!(ranges, vec![0, ENCRYPTED_CHUNK_SIZE, ENCRYPTED_CHUNK_SIZE, ENCRYPTED_CHUNK_SIZE * 2]);
+
+        // A manifest that disagrees with the offer is refused.
+        let mut wrong = chunk_hashes.clone();
+        wrong[2] = chunk_hash(b"other");
+        let manifest: Manifest = serde_json::from_str(&format!(
+            r#"{{"file_sha256":"{}","chunks":[{}]}}"#,
+            file_sha256,
+            chunk_hashes
+                .iter()
+                .enumerate()
+                .map(|(i, h)| format!(r#"{{"index":{},"offset":0,"length":1,"sha256":"{}"}}"#, i, h))
+                .collect::<Vec<_>>()
+                .join(",")
+        ))
+        .unwrap();
+        manifest.check(&file_sha256, &chunk_hashes).unwrap();
+        assert_eq!(manifest.check(&file_sha256, &wrong).unwrap_err().code, ErrorCode::Protocol);
+        assert_eq!(manifest.check("other", &chunk_hashes).unwrap_err().code, ErrorCode::Protocol);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,107 @@
+//! Transfer errors with a stable numeric code for FFI callers.
+//!
+//! The message is for humans (logs, fallback UI text); the code is what Dart
+//! branches on and localizes from. Codes are part of the FFI contract — only
+//! ever add new ones.
+
+use std::fmt;
+
+/// Error category reported by `haven_get_last_error_code`. 0 means no error.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Rejected credentials (HTTP 401/403, expired JWT).
+    Auth = 1,
+    /// Connection failures, timeouts, dropped WebSockets.
+    Network = 2,
+    /// Chunk/file hash mismatch or failed decryption (tampered/corrupt data).
+    HashMismatch = 3,
+    /// Local file read/write/create failures.
+    FileIo = 4,
+    /// Cancelled by the caller.
+    Cancelled = 5,
+    /// Unexpected server response or malformed input.
+    Protocol = 6,
+}
+
+impl ErrorCode {
+    /// Build a `TransferError` with this code.
+    pub fn err(self, message: impl Into<String>) -> TransferError {
+        TransferError {
+            code: self,
+            message: message.into(),
+        }
+    }
+
+    /// Classify a non-success HTTP status.
+    pub fn from_status(status: reqwest::StatusCode) -> Self {
+        match status.as_u16() {
+            401 | 403 => ErrorCode::Auth,
+            _ => ErrorCode::Protocol,
+        }
+    }
+}
+
+/// A failed transfer: a code to branch on plus a human-readable message.
+#[derive(Debug)]
+pub struct TransferError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl TransferError {
+    pub fn cancelled() -> Self {
+        ErrorCode::Cancelled.err("Cancelled")
+    }
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<&haven_fast_transfer::TransferError> for ErrorCode {
+    fn from(e: &haven_fast_transfer::TransferError) -> Self {
+        use haven_fast_transfer::TransferError as Pipeline;
+        match e {
+            Pipeline::Io(_) | Pipeline::DiskFull { .. } => ErrorCode::FileIo,
+            Pipeline::Crypto(_) | Pipeline::HashMismatch { .. } => ErrorCode::HashMismatch,
+            // A stall is the path going quiet; retrying is the same as for
+            // any other network failure.
+            Pipeline::Network(_) | Pipeline::Stalled(_) => ErrorCode::Network,
+            Pipeline::Auth(_) => ErrorCode::Auth,
+            Pipeline::Protocol(_) => ErrorCode::Protocol,
+            Pipeline::Cancelled => ErrorCode::Cancelled,
+        }
+    }
+}
+
+/// A failure from the `haven-fast-transfer` sender or receiver.
+impl From<haven_fast_transfer::TransferError> for TransferError {
+    fn from(e: haven_fast_transfer::TransferError) -> Self {
+        ErrorCode::from(&e).err(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use haven_fast_transfer::TransferError as Pipeline;
+
+    #[test]
+    fn pipeline_errors_keep_their_category() {
+        let full: TransferError = Pipeline::DiskFull { needed: 10, available: 1 }.into();
+        assert_eq!(full.code, ErrorCode::FileIo);
+        let stalled: TransferError = Pipeline::Stalled("no frames for 2s".into()).into();
+        assert_eq!((stalled.code, stalled.message.as_str()), (ErrorCode::Network, "Transfer stalled: no frames for 2s"));
+        let mismatch = Pipeline::HashMismatch { chunk: 1, expected: "a".into(), actual: "b".into() };
+        assert_eq!(ErrorCode::from(&mismatch), ErrorCode::HashMismatch);
+        assert_eq!(ErrorCode::from(&Pipeline::Crypto("bad MAC".into())), ErrorCode::HashMismatch);
+        assert_eq!(TransferError::from(Pipeline::Cancelled).code, ErrorCode::Cancelled);
+        assert_eq!(ErrorCode::from(&Pipeline::Io("read".into())), ErrorCode::FileIo);
+        assert_eq!(ErrorCode::from(&Pipeline::Network("refused".into())), ErrorCode::Network);
+        assert_eq!(ErrorCode::from(&Pipeline::Auth("expired".into())), ErrorCode::Auth);
+        assert_eq!(ErrorCode::from(&Pipeline::Protocol("bad frame".into())), ErrorCode::Protocol);
+    }
+}
@@ -7,6 +7,13 @@
 /// 4. Server blasts encrypted chunks via UDP
 /// 5. Send NACKs for missing frames via WebSocket
 /// 6. Once complete: decrypt, verify, write
+///
+/// Pausing mid-receive sends FastPause so the server's blaster holds too
+/// (sending only keepalives) instead of filling the receiver meanwhile.
+///
+/// If no datagram arrives within `FAST_PROBE_WINDOW` (UDP blocked somewhere
+/// on the path), the client sends FastFallback and fetches the stored bytes
+/// over HTTP instead, then decrypts them the same way.
 
 use std::sync::Arc;
 use std::sync::atomic::Ordering;
@@ -14,11 +21,13 @@ use std::sync::atomic::Ordering;
 use crossbeam_channel::bounded;
 
 use haven_fast_transfer::{
-    ReceiverConfig, ReceiverProgress, run_receiver, TracingLogger,
+    ReceiverConfig, ReceiverProgress, run_receiver, SocketPool, SocketSpec, STALL_TIMEOUT_SECS,
 };
 
-use crate::crypto::{derive_key, decrypt_chunk};
-use crate::download::DownloadProgress;
+use crate::crypto::derive_key;
+use crate::error::{ErrorCode, TransferError};
+use crate::download::{fetch_encrypted, fetch_status, ChunkLayout, DownloadProgress, Offered};
+use crate::fast_upload::FAST_PROBE_WINDOW;
 use crate::upload::{STATE_UPLOADING as STATE_DOWNLOADING, STATE_COMPLETE, STATE_CANCELLED};
 
 /// Run a fast UDP blast download.
@@ -32,14 +41,13 @@ pub async fn fast_download_file(
     file_sha256: &str,
     chunk_hashes: &[String],
     progress: Arc<DownloadProgress>,
-) -> Result<(), String> {
+) -> Result<(), TransferError> {
     let key = derive_key(master_key, salt);
 
     progress.state.store(STATE_DOWNLOADING, Ordering::Relaxed);
 
     // Calculate expected encrypted file size
     let chunk_count = chunk_hashes.len() as u32;
-    let encrypted_chunk_size = haven_fast_transfer::ENCRYPTED_CHUNK_SIZE as u64;
 
     // We receive encrypted data, then decrypt. The receiver writes encrypted chunks
     // to a temp file, then we decrypt in a second pass.
@@ -55,27 +63,18 @@ pub async fn fast_download_file(
     // Actually, we should get file size from the file server.
     // Let's query the transfer status first.
     let client = reqwest::Client::new();
-    let status_resp = client
-        .get(format!("{}/transfers/{}", file_server_url, transfer_id))
-        .header("Authorization", format!("Bearer {}", jwt_token))
-        .send()
-        .await
-        .map_err(|e| format!("Status query failed: {}", e))?;
-
-    if !status_resp.status().is_success() {
-        return Err(format!("Transfer status query failed: {}", status_resp.status()));
-    }
-
-    let status_json: serde_json::Value = status_resp
-        .json()
-        .await
-        .map_err(|e| format!("Status parse failed: {}", e))?;
+    let status_json = fetch_status(&client, file_server_url, transfer_id, jwt_token).await?;
 
     let encrypted_file_size = status_json["file_size"].as_u64().unwrap_or(0);
-    let _chunk_size_from_server = status_json["chunk_count"].as_u64().unwrap_or(0);
+    let offered = Offered { transfer_id, key: &key, file_sha256, chunk_count };
+    let layout = ChunkLayout::from_status(&status_json, &offered)?;
+    let encrypted_chunk_size = layout.slot_size() as u64;
+    // Uploads from older clients carry no MAC, and only ever the default
+    // layout; `from_status` has required one for any other.
+    let control_mac = status_json["control_mac"].as_str().map(str::to_string);
 
     if encrypted_file_size == 0 {
-        return Err("Transfer has zero file size".into());
+        return Err(ErrorCode::Protocol.err("Transfer has zero file size"));
     }
 
     progress.bytes_total.store(encrypted_file_size, Ordering::Relaxed);
@@ -83,21 +82,31 @@ pub async fn fast_download_file(
     // Parse transfer ID bytes
     let transfer_id_bytes = parse_transfer_id_bytes(transfer_id);
 
-    // Bind UDP on port 0 (OS-assigned) so multiple downloads can run concurrently
-    let actual_bind_addr: std::net::SocketAddr = "0.0.0.0:0".parse().unwrap();
-    let udp_socket = {
-        use socket2::{Domain, Protocol, Socket, Type};
-        let sock = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
-            .map_err(|e| format!("UDP socket create: {}", e))?;
-        sock.set_recv_buffer_size(32 * 1024 * 1024)
-            .map_err(|e| format!("UDP recv buffer: {}", e))?;
-        sock.bind(&actual_bind_addr.into())
-            .map_err(|e| format!("UDP bind: {}", e))?;
-        let std_sock: std::net::UdpSocket = sock.into();
-        std_sock
+    // The server's UDP address, for hole-punching. Resolved up front so the
+    // local socket can match its address family.
+    let server_uri: url::Url = file_server_url.parse()
+        .map_err(|e| ErrorCode::Protocol.err(format!("Bad server URL: {}", e)))?;
+    let server_host = server_uri.host_str().ok_or_else(|| ErrorCode::Protocol.err("No host in server URL"))?;
+    let server_port = server_uri.port().unwrap_or(3211);
+    let server_udp_addr = {
+        use std::net::ToSocketAddrs;
+        // IPv6 hosts come back from the URL bracketed.
+        (server_host.trim_start_matches('[').trim_end_matches(']'), server_port)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| ErrorCode::Network.err(format!("Cannot resolve server host {}", server_host)))?
     };
+
+    // Bind UDP on port 0 (OS-assigned) so multiple downloads can run concurrently.
+    // Pooled, so back-to-back downloads reuse ports instead of churning them.
+    let actual_bind_addr = haven_fast_transfer::net::unspecified_for(server_udp_addr);
+    let recv_buffer = haven_fast_transfer::net::recv_buffer_from_env();
+    let udp_socket = SocketPool::global()
+        .checkout(SocketSpec { recv_buffer, ..SocketSpec::receiver(actual_bind_addr) })
+        .map_err(|e| ErrorCode::Network.err(format!("UDP bind: {}", e)))?;
     let udp_port = udp_socket.local_addr()
-        .map_err(|e| format!("Get local UDP port: {}", e))?.port();
+        .map_err(|e| ErrorCode::Network.err(format!("Get local UDP port: {}", e)))?.port();
 
     // Connect to file server WS
     let ws_url = format!(
@@ -108,22 +117,16 @@ pub async fn fast_download_file(
 
     let (ws_stream, _) = tokio_tungstenite::connect_async(&ws_url)
         .await
-        .map_err(|e| format!("WS connect failed: {}", e))?;
+        .map_err(|e| ErrorCode::Network.err(format!("WS connect failed: {}", e)))?;
 
     let (mut ws_tx, mut ws_rx) = futures_util::StreamExt::split(ws_stream);
 
     // Send UDP hole-punch packets to the server so NAT creates a mapping.
     // The server will read our actual external address from these packets.
-    let server_uri: url::Url = file_server_url.parse()
-        .map_err(|e| format!("Bad server URL: {}", e))?;
-    let server_host = server_uri.host_str().ok_or("No host in server URL")?;
-    let server_port = server_uri.port().unwrap_or(3211);
-    let server_udp_addr = format!("{}:{}", server_host, server_port);
-
     // Send a few punch packets (transfer_id as payload so server can match)
     let punch_payload = parse_transfer_id_bytes(transfer_id);
     for _ in 0..5 {
-        let _ = udp_socket.send_to(&punch_payload, &server_udp_addr);
+        let _ = udp_socket.send_to(&punch_payload, server_udp_addr);
         std::thread::sleep(std::time::Duration::from_millis(50));
     }
 
@@ -140,7 +143,7 @@ pub async fn fast_download_file(
     ws_tx
         .send(tokio_tungstenite::tungstenite::Message::Text(start_msg.to_string()))
         .await
-        .map_err(|e| format!("WS send error: {}", e))?;
+        .map_err(|e| ErrorCode::Network.err(format!("WS send error: {}", e)))?;
 
     // Wait for FastDownloadReady
     loop {
@@ -154,20 +157,20 @@ pub async fn fast_download_file(
                 }
             }
             Some(Ok(_)) => continue,
-            _ => return Err("WS connection lost waiting for FastDownloadReady".into()),
+            _ => return Err(ErrorCode::Network.err("WS connection lost waiting for FastDownloadReady")),
         }
     }
 
     // Channel to collect NACKs from receiver
-    let (nack_tx, _nack_rx) = bounded::<(u32, Vec<u16>)>(256);
+    let (nack_tx, _nack_rx) = bounded::<Vec<haven_fast_transfer::NackMessage>>(256);
 
     // Set up NACK callback that sends over WebSocket
     let ws_tx_arc = Arc::new(tokio::sync::Mutex::new(ws_tx));
     let _ws_tx_nack = ws_tx_arc.clone();
     let _tid_nack = transfer_id.to_string();
 
-    let nack_callback: haven_fast_transfer::NackCallback = Box::new(move |chunk_idx, missing| {
-        let _ = nack_tx.try_send((chunk_idx, missing.clone()));
+    let nack_callback: haven_fast_transfer::NackCallback = Box::new(move |nacks| {
+        let _ = nack_tx.try_send(nacks);
         // Also send via WS (best-effort, non-blocking)
         // Note: We can't easily await here since this is a sync callback.
         // The NACK is logged and will be sent in the WS read loop.
@@ -183,27 +186,57 @@ pub async fn fast_download_file(
         chunk_hashes: chunk_hashes.to_vec(),
         file_sha256: file_sha256.to_string(),
         bind_addr: format!("0.0.0.0:{}", udp_port).parse().unwrap(),
-        logger: Some(Arc::new(TracingLogger)),
+        logger: Some(progress.transfer_log.clone()),
         pre_bound_socket: Some(udp_socket),
+        extra_sockets: Vec::new(),
+        control_key: (control_mac.is_some() || layout.requires_mac()).then_some(key),
+        control_mac,
+        ack_callback: None,
+        // The server blasts whole stored slots, padding included.
+        compressed: false,
+        aead: layout.aead,
+        stall_timeout: std::time::Duration::from_secs(STALL_TIMEOUT_SECS),
+        // Sized above, when the socket was checked out.
+        recv_buffer: None,
+        resume: false,
+        sync: Default::default(),
+        sparse_chunks: layout.sparse_chunks.clone(),
     };
 
     let recv_progress = Arc::new(ReceiverProgress::new());
     let recv_progress_clone = recv_progress.clone();
+    let recv_progress_stats = recv_progress.clone();
     let progress_poll = progress.clone();
+    let ws_tx_pause = ws_tx_arc.clone();
+    let tid_pause = transfer_id.to_string();
 
     // Run receiver in blocking thread
     let receiver_handle = tokio::task::spawn_blocking(move || {
         run_receiver(receiver_config, recv_progress_clone, nack_callback)
     });
 
-    // Poll receiver progress
+    // Poll receiver progress, and tell the server when the caller pauses or
+    // resumes so its blaster follows.
     let poll_handle = tokio::spawn(async move {
+        let mut paused = false;
         loop {
+            if progress_poll.is_paused() != paused {
+                paused = !paused;
+                let pause_msg = serde_json::json!({
+                    "type": "FastPause",
+                    "data": { "transfer_id": tid_pause, "paused": paused },
+                });
+                let _ = ws_tx_pause
+                    .lock()
+                    .await
+                    .send(tokio_tungstenite::tungstenite::Message::Text(pause_msg.to_string()))
+                    .await;
+            }
             let state = recv_progress.state.load(Ordering::Relaxed);
-            progress_poll.bytes_done.store(
-                recv_progress.bytes_done.load(Ordering::Relaxed),
-                Ordering::Relaxed,
-            );
+            progress_poll.set_bytes(recv_progress.bytes_done.load(Ordering::Relaxed));
+            progress_poll
+                .local_udp_port
+                .store(recv_progress.bound_port.load(Ordering::Relaxed), Ordering::Relaxed);
 
             if state == haven_fast_transfer::receiver::STATE_COMPLETE
                 || state == haven_fast_transfer::receiver::STATE_ERROR
@@ -215,13 +248,67 @@ pub async fn fast_download_file(
         }
     });
 
-    let recv_result = receiver_handle
-        .await
-        .map_err(|e| format!("Receiver task panicked: {}", e))?;
+    let mut receiver_handle = receiver_handle;
+    let probe_deadline = tokio::time::Instant::now() + FAST_PROBE_WINDOW;
+    let mut probing = true;
+    let recv_result = loop {
+        tokio::select! {
+            joined = &mut receiver_handle => {
+                break Some(joined.map_err(|e| ErrorCode::Protocol.err(format!("Receiver task panicked: {}", e)))?);
+            }
+            _ = tokio::time::sleep_until(probe_deadline), if probing => {
+                if recv_progress_stats.frames_received.load(Ordering::Relaxed) > 0 || progress.is_cancelled() {
+                    probing = false;
+                } else {
+                    break None;
+                }
+            }
+        }
+    };
 
     poll_handle.abort();
 
-    recv_result.map_err(|e| format!("Receiver error: {}", e))?;
+    // Keep the loss histogram even if the receiver failed — that's when it matters most.
+    *progress.loss_histogram_json.lock().unwrap() =
+        Some(recv_progress_stats.loss_histogram.lock().unwrap().to_json());
+
+    match recv_result {
+        Some(recv_result) => {
+            let bound_addr = recv_result.map_err(|e| ErrorCode::from(&e).err(format!("Receiver error: {}", e)))?;
+            progress.local_udp_port.store(bound_addr.port(), Ordering::Relaxed);
+        }
+        None => {
+            recv_progress_stats.cancelled.store(1, Ordering::Relaxed);
+            let _ = receiver_handle.await;
+            let fallback_msg = serde_json::json!({
+                "type": "FastFallback",
+                "data": { "transfer_id": transfer_id },
+            });
+            ws_tx_arc
+                .lock()
+                .await
+                .send(tokio_tungstenite::tungstenite::Message::Text(fallback_msg.to_string()))
+                .await
+                .map_err(|e| ErrorCode::Network.err(format!("WS send error: {}", e)))?;
+            progress.fell_back_to_http.store(1, Ordering::Relaxed);
+            if let Err(e) = fetch_encrypted(
+                &client,
+                file_server_url,
+                transfer_id,
+                jwt_token,
+                &temp_path,
+                encrypted_chunk_size,
+                file_sha256,
+                chunk_hashes,
+                &progress,
+            )
+            .await
+            {
+                let _ = std::fs::remove_file(&temp_path);
+                return Err(e);
+            }
+        }
+    }
 
     // Now decrypt the received encrypted file
     // Read encrypted chunks, decrypt, write to final output
@@ -232,24 +319,27 @@ pub async fn fast_download_file(
         use std::io::{Read, Write};
 
         let mut enc_file = std::fs::File::open(&temp_path)
-            .map_err(|e| format!("Cannot open encrypted file: {}", e))?;
+            .map_err(|e| ErrorCode::FileIo.err(format!("Cannot open encrypted file: {}", e)))?;
 
         // Ensure parent dir exists
         if let Some(parent) = std::path::Path::new(save_path).parent() {
             if !parent.as_os_str().is_empty() {
                 std::fs::create_dir_all(parent)
-                    .map_err(|e| format!("Cannot create output dir: {}", e))?;
+                    .map_err(|e| ErrorCode::FileIo.err(format!("Cannot create output dir: {}", e)))?;
             }
         }
 
         let mut out_file = std::fs::File::create(save_path)
-            .map_err(|e| format!("Cannot create output file: {}", e))?;
+            .map_err(|e| ErrorCode::FileIo.err(format!("Cannot create output file: {}", e)))?;
+
+        let cipher = layout.cipher(&key);
 
         for idx in 0..chunk_count {
+            progress.block_while_paused();
             if progress.is_cancelled() {
                 progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
                 let _ = std::fs::remove_file(&temp_path);
-                return Err("Cancelled".into());
+                return Err(TransferError::cancelled());
             }
 
             // Calculate encrypted chunk size
@@ -262,18 +352,19 @@ pub async fn fast_download_file(
 
             let mut encrypted_chunk = vec![0u8; enc_chunk_size as usize];
             enc_file.read_exact(&mut encrypted_chunk)
-                .map_err(|e| format!("Read encrypted chunk {}: {}", idx, e))?;
+                .map_err(|e| ErrorCode::FileIo.err(format!("Read encrypted chunk {}: {}", idx, e)))?;
 
-            let plaintext = decrypt_chunk(&key, &encrypted_chunk)
-                .map_err(|e| format!("Decrypt chunk {}: {}", idx, e))?;
+            let plaintext = layout
+                .open(&cipher, idx as usize, &encrypted_chunk)
+                .map_err(|e| ErrorCode::HashMismatch.err(format!("Decrypt chunk {}: {}", idx, e)))?;
 
             out_file.write_all(&plaintext)
-                .map_err(|e| format!("Write chunk {}: {}", idx, e))?;
+                .map_err(|e| ErrorCode::FileIo.err(format!("Write chunk {}: {}", idx, e)))?;
 
-            progress.bytes_done.fetch_add(enc_chunk_size, Ordering::Relaxed);
+            progress.add_bytes(enc_chunk_size);
         }
 
-        out_file.flush().map_err(|e| format!("Flush error: {}", e))?;
+        out_file.flush().map_err(|e| ErrorCode::FileIo.err(format!("Flush error: {}", e)))?;
     }
 
     // Clean up temp file
@@ -290,7 +381,7 @@ pub async fn fast_download_file(
     Ok(())
 }
 
-fn parse_transfer_id_bytes(transfer_id: &str) -> [u8; 16] {
+pub(crate) fn parse_transfer_id_bytes(transfer_id: &str) -> [u8; 16] {
     let stripped = transfer_id.replace('-', "");
     if stripped.len() >= 32 {
         if let Ok(bytes) = hex::decode(&stripped[..32]) {
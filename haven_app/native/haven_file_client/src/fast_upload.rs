@@ -4,26 +4,69 @@
 /// 1. Start sender pipeline (reader → encryptor → blaster)
 /// 2. Connect to file server's fast-transfer WebSocket
 /// 3. Send FastUploadStart with metadata
-/// 4. Receive FastUploadReady with UDP port
+/// 4. Receive FastUploadReady with UDP port and the FEC parity to send
 /// 5. Blast encrypted chunks via UDP
 /// 6. Handle NACKs via WebSocket → crossbeam channel → sender retransmit
+///
+/// Before step 3 the client sends FastResume: if an earlier attempt at this
+/// transfer was cut off, the server answers with the chunks it still lacks
+/// and only those are blasted.
+///
+/// If the server reports nothing back within `FAST_PROBE_WINDOW` of the blast
+/// starting (UDP blocked somewhere on the path), the client sends
+/// FastFallback and uploads the same sealed chunks over HTTP instead.
 
 use std::sync::Arc;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bytes::Bytes;
 
 use crossbeam_channel::bounded;
 
 use haven_fast_transfer::{
-    SenderConfig, SenderProgress, run_sender,
-    NackMessage, ChunkAckMessage, TracingLogger,
+    AeadAlgorithm, ChunkCipher, CongestionConfig, FecRatio, Pacing, SenderConfig, SenderProgress, run_sender,
+    NackMessage, ChunkAckMessage, ControlFields, chunk_size_in_range, slot_size, MAX_CHUNK_RETRANSMITS,
+    MAX_CHUNK_SIZE, MIN_CHUNK_SIZE, SENDER_CACHE_SIZE, STALL_TIMEOUT_SECS,
 };
 
 use crate::crypto::derive_key;
-use crate::upload::{UploadProgress, STATE_HASHING, STATE_UPLOADING, STATE_COMPLETE, STATE_ERROR, STATE_CANCELLED};
+use crate::error::{ErrorCode, TransferError};
+use crate::sniff;
+use crate::upload::{check_transfer, put_chunk, FileDigest, UploadProgress, CHUNK_BACKOFF, UPLOAD_CONCURRENCY, STATE_HASHING, STATE_UPLOADING, STATE_PAUSED, STATE_COMPLETE, STATE_ERROR, STATE_CANCELLED};
+
+/// How a fast upload seals its chunks.
+#[derive(Debug, Clone, Copy)]
+pub struct FastUploadOptions {
+    /// zstd-compress chunks before encryption.
+    pub compress: bool,
+    /// Store all-zero chunks as zero slots instead of blasting them (see
+    /// `haven_fast_transfer::sparse`). Ignored with `compress`.
+    pub sparse: bool,
+    /// Plaintext bytes per chunk. Must be in the crate's range and, sealed,
+    /// one the server's `/transfers/check` lists.
+    pub chunk_size: usize,
+    /// Algorithm chunks are sealed with. Every client reads AES-256-GCM;
+    /// ChaCha20-Poly1305 (`AeadAlgorithm::preferred()` without hardware AES)
+    /// only suits recipients whose clients are known to read it.
+    pub aead: AeadAlgorithm,
+}
+
+impl Default for FastUploadOptions {
+    fn default() -> Self {
+        Self {
+            compress: false,
+            sparse: false,
+            chunk_size: haven_fast_transfer::CHUNK_SIZE,
+            aead: AeadAlgorithm::Aes256Gcm,
+        }
+    }
+}
 
 /// Run a fast UDP blast upload.
 ///
 /// This function is called from the FFI layer and runs on a Tokio runtime.
+/// The server creates the transfer for one recipient; a fan-out offer sets
+/// its real count afterwards with [`crate::upload::set_recipients`].
 pub async fn fast_upload_file(
     file_path: &str,
     file_server_url: &str,
@@ -31,18 +74,52 @@ pub async fn fast_upload_file(
     jwt_token: &str,
     master_key: &[u8],
     salt: &[u8],
+    options: FastUploadOptions,
     progress: Arc<UploadProgress>,
-) -> Result<(), String> {
+) -> Result<(), TransferError> {
+    let FastUploadOptions { compress, sparse, chunk_size, aead } = options;
+    let sparse = sparse && !compress;
     let key = derive_key(master_key, salt);
+    if !chunk_size_in_range(chunk_size) {
+        return Err(ErrorCode::Protocol.err(format!(
+            "Chunk size {} out of range ({}..={})",
+            chunk_size, MIN_CHUNK_SIZE, MAX_CHUNK_SIZE
+        )));
+    }
 
     let file_size = tokio::fs::metadata(file_path)
         .await
-        .map_err(|e| format!("Cannot read file: {}", e))?
+        .map_err(|e| ErrorCode::FileIo.err(format!("Cannot read file: {}", e)))?
         .len();
 
     progress.bytes_total.store(file_size, Ordering::Relaxed);
     progress.state.store(STATE_HASHING, Ordering::Relaxed);
 
+    // Compressed chunks occupy fixed slots a little larger than encrypted ones.
+    let encrypted_chunk_size = slot_size(chunk_size, compress, aead) as u64;
+    let chunk_count = haven_fast_transfer::chunk_count(file_size, chunk_size as u64);
+
+    // Refused uploads stop before hashing, as do chunk sizes the server
+    // won't take. Every chunk's slot is its plaintext plus a fixed overhead,
+    // so the stored size is known up front.
+    let check = check_transfer(
+        &reqwest::Client::new(),
+        file_server_url,
+        &progress.token(jwt_token),
+        file_size + chunk_count as u64 * slot_size(0, compress, aead) as u64,
+        None,
+    )
+    .await?;
+    let sealed_chunk_size = aead.encrypted_chunk_size(chunk_size) as u64;
+    if let Some(sizes) = check.chunk_sizes.filter(|sizes| !sizes.contains(&sealed_chunk_size)) {
+        return Err(ErrorCode::Protocol.err(format!(
+            "Server takes {}..={} byte chunks, not {}",
+            sizes.start(),
+            sizes.end(),
+            sealed_chunk_size
+        )));
+    }
+
     // Parse transfer_id to 16 bytes
     let transfer_id_bytes = parse_transfer_id_bytes(transfer_id);
 
@@ -57,24 +134,15 @@ pub async fn fast_upload_file(
     let ws_url = format!(
         "{}/fast-transfer?token={}",
         file_server_url.replace("http://", "ws://").replace("https://", "wss://"),
-        jwt_token
+        progress.token(jwt_token)
     );
 
     let (ws_stream, _) = tokio_tungstenite::connect_async(&ws_url)
         .await
-        .map_err(|e| format!("WS connect failed: {}", e))?;
+        .map_err(|e| ErrorCode::Network.err(format!("WS connect failed: {}", e)))?;
 
     let (mut ws_tx, mut ws_rx) = futures_util::StreamExt::split(ws_stream);
 
-    // Send FastUploadStart
-    let _chunk_size = haven_fast_transfer::CHUNK_SIZE as u64;
-    let encrypted_chunk_size = haven_fast_transfer::ENCRYPTED_CHUNK_SIZE as u64;
-    let chunk_count = if file_size == 0 {
-        1u32
-    } else {
-        ((file_size as usize + haven_fast_transfer::CHUNK_SIZE - 1) / haven_fast_transfer::CHUNK_SIZE) as u32
-    };
-
     // We need to compute hashes first (pass 1) before we can send FastUploadStart.
     // The sender pipeline does this, but we need hashes before blasting.
     // So we run the sender and it will produce hashes in progress.hashes_json.
@@ -105,45 +173,46 @@ pub async fn fast_upload_file(
     // Step 3: Run sender pipeline targeting that port
 
     // Pre-compute hashes (single pass, same as current upload.rs pass 1)
-    let (chunk_hashes, file_sha256, encrypted_size) = {
+    let FileDigest { chunk_hashes, file_sha256, encrypted_size, content_type, sparse_chunks } = {
         let file_path_hash = file_path_owned.clone();
         let progress_hash = progress.clone();
 
-        tokio::task::block_in_place(|| -> Result<(Vec<String>, String, u64), String> {
+        tokio::task::block_in_place(|| -> Result<FileDigest, TransferError> {
             use std::io::Read;
             use sha2::{Sha256, Digest};
-            use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead};
 
             let mut file = std::fs::File::open(&file_path_hash)
-                .map_err(|e| format!("Cannot open file: {}", e))?;
+                .map_err(|e| ErrorCode::FileIo.err(format!("Cannot open file: {}", e)))?;
 
-            let cipher = Aes256Gcm::new_from_slice(&key)
-                .map_err(|e| format!("Cipher init: {}", e))?;
+            let cipher = ChunkCipher::new(aead, &key);
 
             let mut full_hasher = Sha256::new();
             let mut chunk_hashes = Vec::with_capacity(chunk_count as usize);
             let mut encrypted_size: u64 = 0;
-            let mut buf = vec![0u8; haven_fast_transfer::CHUNK_SIZE];
+            let mut content_type = None;
+            let mut sparse_chunks = Vec::new();
+            let mut buf = vec![0u8; chunk_size];
 
             for idx in 0..chunk_count {
+                progress_hash.block_while_paused();
                 if progress_hash.is_cancelled() {
-                    return Err("Cancelled".into());
+                    return Err(TransferError::cancelled());
                 }
 
-                let remaining = file_size - idx as u64 * haven_fast_transfer::CHUNK_SIZE as u64;
-                let to_read = (remaining as usize).min(haven_fast_transfer::CHUNK_SIZE);
+                let remaining = file_size - idx as u64 * chunk_size as u64;
+                let to_read = (remaining as usize).min(chunk_size);
 
                 file.read_exact(&mut buf[..to_read])
-                    .map_err(|e| format!("Read error chunk {}: {}", idx, e))?;
-
-                let nonce = crate::crypto::derive_chunk_nonce(&key, idx as u64);
-                let ciphertext = cipher
-                    .encrypt(Nonce::from_slice(&nonce), &buf[..to_read])
-                    .map_err(|e| format!("Encrypt chunk {}: {}", idx, e))?;
+                    .map_err(|e| ErrorCode::FileIo.err(format!("Read error chunk {}: {}", idx, e)))?;
+                if idx == 0 {
+                    content_type = sniff::content_type(&buf[..to_read]);
+                }
 
-                let mut encrypted = Vec::with_capacity(12 + ciphertext.len());
-                encrypted.extend_from_slice(&nonce);
-                encrypted.extend_from_slice(&ciphertext);
+                // Same nonce as the sender pipeline, so these hashes match what it blasts
+                let (encrypted, zero) = seal_slot(&cipher, &key, idx, chunk_size, &buf[..to_read], compress, sparse)?;
+                if zero {
+                    sparse_chunks.push(idx);
+                }
 
                 let mut chunk_hasher = Sha256::new();
                 chunk_hasher.update(&encrypted);
@@ -153,13 +222,14 @@ pub async fn fast_upload_file(
                 encrypted_size += encrypted.len() as u64;
             }
 
-            Ok((chunk_hashes, hex::encode(full_hasher.finalize()), encrypted_size))
+            let file_sha256 = hex::encode(full_hasher.finalize());
+            Ok(FileDigest { chunk_hashes, file_sha256, encrypted_size, content_type, sparse_chunks })
         })?
     };
 
     if progress.is_cancelled() {
         progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
-        return Err("Cancelled".into());
+        return Err(TransferError::cancelled());
     }
 
     // Store hashes for Dart to read
@@ -171,11 +241,58 @@ pub async fn fast_upload_file(
         *progress.hashes_json.lock().unwrap() = Some(json);
     }
 
+    // The sniffed type only goes out to a server that filters by it.
+    let content_type = content_type.filter(|_| check.restricts_types);
+
     // Switch to uploading state
     progress.bytes_total.store(encrypted_size, Ordering::Relaxed);
     progress.bytes_done.store(0, Ordering::Relaxed);
     progress.state.store(STATE_UPLOADING, Ordering::Relaxed);
 
+    // Bind the control fields to the file key so a relay can't rewrite them
+    let control_mac = ControlFields {
+        transfer_id: &transfer_id_bytes,
+        file_size: encrypted_size,
+        chunk_size: encrypted_chunk_size,
+        chunk_count,
+        file_sha256: &file_sha256,
+        sparse_chunks: &sparse_chunks,
+    }
+    .mac(&key);
+
+    // Ask what an earlier, interrupted attempt already delivered
+    use futures_util::SinkExt;
+    let resume_msg = serde_json::json!({
+        "type": "FastResume",
+        "data": { "transfer_id": transfer_id_owned },
+    });
+    ws_tx
+        .send(tokio_tungstenite::tungstenite::Message::Text(resume_msg.to_string()))
+        .await
+        .map_err(|e| ErrorCode::Network.err(format!("WS send error: {}", e)))?;
+    let missing_chunks = tokio::time::timeout(RESUME_REPLY_TIMEOUT, async {
+        use futures_util::StreamExt;
+        loop {
+            match ws_rx.next().await {
+                Some(Ok(tokio_tungstenite::tungstenite::Message::Text(text))) => {
+                    if let Ok(msg) = serde_json::from_str::<serde_json::Value>(&text) {
+                        if msg["type"] == "FastResumeState" {
+                            return Ok(msg["data"]["missing_chunks"].as_array().map(|chunks| {
+                                chunks.iter().filter_map(|c| c.as_u64()).map(|c| c as u32).collect::<Vec<u32>>()
+                            }));
+                        }
+                    }
+                }
+                Some(Ok(_)) => continue,
+                _ => return Err(ErrorCode::Network.err("WS connection lost waiting for FastResumeState")),
+            }
+        }
+    })
+    .await
+    // Servers without FastResume never answer; upload everything.
+    .unwrap_or(Ok(None))?;
+    let skip_chunks = skipped_chunks(chunk_count, missing_chunks.as_deref());
+
     // Send FastUploadStart
     let start_msg = serde_json::json!({
         "type": "FastUploadStart",
@@ -186,17 +303,22 @@ pub async fn fast_upload_file(
             "chunk_size": encrypted_chunk_size,
             "chunk_hashes": chunk_hashes,
             "file_sha256": file_sha256,
+            "control_mac": control_mac,
+            "compression": compress,
+            "aead": aead.id(),
+            "content_type": content_type,
+            "sparse_chunks": sparse_chunks,
+            "nack_batch": true,
         }
     });
 
-    use futures_util::SinkExt;
     ws_tx
         .send(tokio_tungstenite::tungstenite::Message::Text(start_msg.to_string()))
         .await
-        .map_err(|e| format!("WS send error: {}", e))?;
+        .map_err(|e| ErrorCode::Network.err(format!("WS send error: {}", e)))?;
 
-    // Wait for FastUploadReady
-    let udp_port: u16 = loop {
+    // Wait for FastUploadReady, which carries the FEC parity the server asks for
+    let (udp_port, fec) = loop {
         use futures_util::StreamExt;
         match ws_rx.next().await {
             Some(Ok(tokio_tungstenite::tungstenite::Message::Text(text))) => {
@@ -204,24 +326,31 @@ pub async fn fast_upload_file(
                     if msg["type"] == "FastUploadReady" {
                         let port = msg["data"]["udp_port"].as_u64().unwrap_or(0) as u16;
                         if port > 0 {
-                            break port;
+                            progress.server_udp_port.store(port, Ordering::Relaxed);
+                            let fec = msg["data"]["fec"]
+                                .as_str()
+                                .and_then(|ratio| FecRatio::parse(ratio).ok())
+                                .unwrap_or(FecRatio::DISABLED);
+                            break (port, fec);
                         }
+                    } else if msg["type"] == "FastUploadRejected" {
+                        let reason = msg["data"]["reason"].as_str().unwrap_or("no reason given");
+                        return Err(ErrorCode::Protocol.err(format!("Server rejected fast upload: {}", reason)));
                     }
                 }
             }
             Some(Ok(_)) => continue,
-            _ => return Err("WS connection lost waiting for FastUploadReady".into()),
+            _ => return Err(ErrorCode::Network.err("WS connection lost waiting for FastUploadReady")),
         }
     };
 
     // Parse file server address and replace port with UDP port
     let server_addr: std::net::SocketAddr = {
         let url = url::Url::parse(file_server_url)
-            .map_err(|_| format!("Invalid server URL: {}", file_server_url))?;
+            .map_err(|_| ErrorCode::Protocol.err(format!("Invalid server URL: {}", file_server_url)))?;
         let host = url.host_str().unwrap_or("127.0.0.1");
-        format!("{}:{}", host, udp_port)
-            .parse()
-            .map_err(|e| format!("Cannot parse target addr: {}", e))?
+        haven_fast_transfer::net::host_port(host, udp_port)
+            .map_err(|e| ErrorCode::Protocol.err(format!("Cannot parse target addr: {}", e)))?
     };
 
     // Start the sender pipeline in a blocking thread
@@ -230,7 +359,21 @@ pub async fn fast_upload_file(
         target_addr: server_addr,
         transfer_id: transfer_id_bytes,
         encryption_key: key,
-        logger: Some(Arc::new(TracingLogger)),
+        chunk_size,
+        probe_mtu: true,
+        compress,
+        aead,
+        fec,
+        encrypt_workers: 0,
+        stall_timeout: std::time::Duration::from_secs(STALL_TIMEOUT_SECS),
+        cache_size: SENDER_CACHE_SIZE,
+        max_rate_bps: progress.rate_cap_bps.load(Ordering::Relaxed),
+        max_chunk_retransmits: MAX_CHUNK_RETRANSMITS,
+        skip_chunks,
+        sparse,
+        pacing: Pacing::default(),
+        congestion: CongestionConfig::default(),
+        logger: Some(progress.transfer_log.clone()),
     };
 
     let sender_progress = Arc::new(SenderProgress::new());
@@ -240,32 +383,56 @@ pub async fn fast_upload_file(
     // Spawn WS reader to feed NACKs and ACKs to sender
     let nack_tx_clone = nack_tx.clone();
     let ack_tx_clone = ack_tx.clone();
+    // Any NACK or ACK means datagrams are getting through.
+    let heard = Arc::new(AtomicBool::new(false));
+    let heard_ws = heard.clone();
     tokio::spawn(async move {
         use futures_util::StreamExt;
+        // ACKs only free cached chunks early, so a full queue drops them;
+        // count how many so a stalled sender shows up.
+        let mut acks_dropped = 0u64;
+        let mut queue_ack = |ack| {
+            if let Err(crossbeam_channel::TrySendError::Full(_)) = ack_tx_clone.try_send(ack) {
+                acks_dropped += 1;
+            }
+        };
         while let Some(Ok(msg)) = ws_rx.next().await {
             if let tokio_tungstenite::tungstenite::Message::Text(text) = msg {
                 if let Ok(v) = serde_json::from_str::<serde_json::Value>(&text) {
+                    if matches!(
+                        v["type"].as_str(),
+                        Some("FastNack" | "FastNackBatch" | "FastChunkAck" | "FastChunkAckBitmap" | "FastUploadDone")
+                    ) {
+                        heard_ws.store(true, Ordering::Relaxed);
+                    }
                     match v["type"].as_str() {
                         Some("FastNack") => {
-                            if let (Some(chunk_idx), Some(frames)) = (
-                                v["data"]["chunk_idx"].as_u64(),
-                                v["data"]["missing_frames"].as_array(),
-                            ) {
-                                let missing: Vec<u16> = frames
-                                    .iter()
-                                    .filter_map(|f| f.as_u64().map(|n| n as u16))
-                                    .collect();
-                                let _ = nack_tx_clone.try_send(NackMessage {
-                                    chunk_index: chunk_idx as u32,
-                                    missing_frames: missing,
-                                });
+                            if let Some(nack) = parse_nack(&v["data"]) {
+                                let _ = nack_tx_clone.try_send(nack);
+                            }
+                        }
+                        Some("FastNackBatch") => {
+                            for entry in v["data"]["nacks"].as_array().into_iter().flatten() {
+                                if let Some(nack) = parse_nack(entry) {
+                                    let _ = nack_tx_clone.try_send(nack);
+                                }
                             }
                         }
                         Some("FastChunkAck") => {
                             if let Some(chunk_idx) = v["data"]["chunk_idx"].as_u64() {
-                                let _ = ack_tx_clone.try_send(ChunkAckMessage {
-                                    chunk_index: chunk_idx as u32,
-                                });
+                                queue_ack(ChunkAckMessage::Chunk { chunk_index: chunk_idx as u32 });
+                            }
+                        }
+                        Some("FastChunkAckBitmap") => {
+                            if let (Some(base_chunk), Some(bits)) = (
+                                v["data"]["base_chunk"].as_u64(),
+                                v["data"]["bitmap"].as_array(),
+                            ) {
+                                let bitmap: Vec<u8> = bits
+                                    .iter()
+                                    .filter_map(|b| b.as_u64().map(|n| n as u8))
+                                    .collect();
+                                queue_ack(ChunkAckMessage::Bitmap { base_chunk: base_chunk as u32, bitmap });
                             }
                         }
                         Some("FastUploadDone") => {
@@ -276,6 +443,9 @@ pub async fn fast_upload_file(
                 }
             }
         }
+        if acks_dropped > 0 {
+            eprintln!("Fast upload: dropped {} chunk ACKs on a full queue", acks_dropped);
+        }
     });
 
     // Run sender in blocking thread, poll progress to update UploadProgress
@@ -284,13 +454,22 @@ pub async fn fast_upload_file(
     });
 
     // Poll sender progress and copy to upload progress
+    let sender_cancel = sender_progress.clone();
     let poll_handle = tokio::spawn(async move {
         loop {
             let state = sender_progress.state.load(Ordering::Relaxed);
-            progress_poll.bytes_done.store(
-                sender_progress.bytes_done.load(Ordering::Relaxed),
-                Ordering::Relaxed,
-            );
+            // Mirror pause/cancel and the rate cap into the blaster, which
+            // picks them up between chunks
+            sender_progress.set_rate_cap(progress_poll.rate_cap_bps.load(Ordering::Relaxed));
+            let paused = progress_poll.paused.load(Ordering::Relaxed);
+            sender_progress.paused.store(paused, Ordering::Relaxed);
+            sender_progress.cancelled.store(progress_poll.cancelled.load(Ordering::Relaxed), Ordering::Relaxed);
+            let _ = if paused != 0 {
+                progress_poll.state.compare_exchange(STATE_UPLOADING, STATE_PAUSED, Ordering::Relaxed, Ordering::Relaxed)
+            } else {
+                progress_poll.state.compare_exchange(STATE_PAUSED, STATE_UPLOADING, Ordering::Relaxed, Ordering::Relaxed)
+            };
+            progress_poll.set_bytes(sender_progress.bytes_done.load(Ordering::Relaxed));
 
             if state == haven_fast_transfer::sender::STATE_COMPLETE
                 || state == haven_fast_transfer::sender::STATE_ERROR
@@ -302,22 +481,235 @@ pub async fn fast_upload_file(
         }
     });
 
-    let result = sender_handle
-        .await
-        .map_err(|e| format!("Sender task panicked: {}", e))?;
+    // The WS token was only checked on connect; hand the server any token
+    // refreshed during the blast so the session stays tied to a live one.
+    let mut sender_handle = sender_handle;
+    let mut sent_token = progress.token(jwt_token);
+    let mut probe_deadline = tokio::time::Instant::now() + FAST_PROBE_WINDOW;
+    let mut probing = true;
+    let result = loop {
+        tokio::select! {
+            joined = &mut sender_handle => {
+                break Some(joined.map_err(|e| ErrorCode::Protocol.err(format!("Sender task panicked: {}", e)))?);
+            }
+            _ = tokio::time::sleep_until(probe_deadline), if probing => {
+                if progress.is_paused() {
+                    // Nothing is sent while paused; give the blast a fresh window.
+                    probe_deadline = tokio::time::Instant::now() + FAST_PROBE_WINDOW;
+                } else if heard.load(Ordering::Relaxed) || progress.is_cancelled() {
+                    probing = false;
+                } else {
+                    break None;
+                }
+            }
+            _ = tokio::time::sleep(REAUTH_POLL) => {
+                let token = progress.token(jwt_token);
+                if token != sent_token {
+                    let reauth_msg = serde_json::json!({ "type": "FastReauth", "data": { "token": token } });
+                    let _ = ws_tx
+                        .send(tokio_tungstenite::tungstenite::Message::Text(reauth_msg.to_string()))
+                        .await;
+                    sent_token = token;
+                }
+            }
+        }
+    };
 
     poll_handle.abort();
 
+    let Some(result) = result else {
+        // Stop the blaster before the server's receiver goes away.
+        sender_cancel.cancelled.store(1, Ordering::Relaxed);
+        let _ = sender_handle.await;
+        let fallback_msg = serde_json::json!({
+            "type": "FastFallback",
+            "data": { "transfer_id": transfer_id_owned },
+        });
+        ws_tx
+            .send(tokio_tungstenite::tungstenite::Message::Text(fallback_msg.to_string()))
+            .await
+            .map_err(|e| ErrorCode::Network.err(format!("WS send error: {}", e)))?;
+        progress.fell_back_to_http.store(1, Ordering::Relaxed);
+        progress.bytes_done.store(0, Ordering::Relaxed);
+
+        let slots = SlotSealer { key, aead, chunk_size, compress, sparse };
+        return match upload_over_http(&file_path_owned, file_server_url, &transfer_id_owned, jwt_token, file_size, chunk_count, slots, &progress).await {
+            Ok(()) => {
+                progress.state.store(STATE_COMPLETE, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(_) if progress.is_cancelled() => {
+                progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
+                Err(TransferError::cancelled())
+            }
+            Err(e) => {
+                progress.state.store(STATE_ERROR, Ordering::Relaxed);
+                Err(e)
+            }
+        };
+    };
+
     match result {
         Ok(_send_result) => {
             progress.state.store(STATE_COMPLETE, Ordering::Relaxed);
             Ok(())
         }
+        Err(_) if progress.is_cancelled() => {
+            // Let the server stop its receiver and drop the partial file now
+            // rather than waiting out the stall timeout
+            let cancel_msg = serde_json::json!({
+                "type": "FastCancel",
+                "data": { "transfer_id": transfer_id_owned },
+            });
+            let _ = ws_tx
+                .send(tokio_tungstenite::tungstenite::Message::Text(cancel_msg.to_string()))
+                .await;
+            progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
+            Err(TransferError::cancelled())
+        }
         Err(e) => {
             progress.state.store(STATE_ERROR, Ordering::Relaxed);
-            Err(e)
+            Err(e.into())
+        }
+    }
+}
+
+/// How often a running fast upload checks for a refreshed token.
+const REAUTH_POLL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How long a fast transfer may go without hearing from the other side
+/// (no NACK or ACK from the server for an upload, no datagram for a
+/// download) before it falls back to HTTP.
+pub(crate) const FAST_PROBE_WINDOW: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How long to wait for the server's answer to FastResume.
+const RESUME_REPLY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Chunk `idx` as it is blasted and stored: a compressed slot, a zero slot
+/// for an all-zero chunk of a sparse upload, or the sealed chunk. Also
+/// returns whether it became a zero slot.
+fn seal_slot(
+    cipher: &ChunkCipher,
+    key: &[u8; 32],
+    idx: u32,
+    chunk_size: usize,
+    plain: &[u8],
+    compress: bool,
+    sparse: bool,
+) -> Result<(Vec<u8>, bool), TransferError> {
+    if compress {
+        let sealed = haven_fast_transfer::seal_compressed_chunk(cipher, key, idx, chunk_size, plain)
+            .map_err(|e| ErrorCode::Protocol.err(e))?;
+        Ok((sealed.slot, false))
+    } else if sparse && haven_fast_transfer::sparse::is_zero(plain) {
+        Ok((haven_fast_transfer::sparse::zero_slot(plain.len()), true))
+    } else {
+        let sealed = cipher
+            .seal_chunk(key, idx, chunk_size, plain)
+            .map_err(|e| ErrorCode::Protocol.err(format!("Encrypt chunk {}: {}", idx, e)))?;
+        Ok((sealed, false))
+    }
+}
+
+/// Everything `seal_slot` needs besides the chunk itself.
+#[derive(Clone, Copy)]
+struct SlotSealer {
+    key: [u8; 32],
+    aead: AeadAlgorithm,
+    chunk_size: usize,
+    compress: bool,
+    sparse: bool,
+}
+
+/// Upload every chunk through `PUT /transfers/{id}/chunks/{idx}`, sealed
+/// exactly as the hash pass sealed it so the hashes in FastUploadStart
+/// still match. Chunks an earlier UDP attempt delivered are sent again: the
+/// server only counts chunks it received over HTTP.
+#[allow(clippy::too_many_arguments)]
+async fn upload_over_http(
+    file_path: &str,
+    server_url: &str,
+    transfer_id: &str,
+    jwt_token: &str,
+    file_size: u64,
+    chunk_count: u32,
+    slots: SlotSealer,
+    progress: &Arc<UploadProgress>,
+) -> Result<(), TransferError> {
+    use tokio::io::AsyncReadExt;
+
+    let client = reqwest::Client::new();
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(UPLOAD_CONCURRENCY));
+    let mut handles = Vec::with_capacity(chunk_count as usize);
+    let mut file = tokio::fs::File::open(file_path)
+        .await
+        .map_err(|e| ErrorCode::FileIo.err(format!("Cannot open file for upload: {}", e)))?;
+
+    for idx in 0..chunk_count {
+        progress.wait_while_paused().await;
+        if progress.is_cancelled() {
+            return Err(TransferError::cancelled());
         }
+
+        let remaining = file_size - idx as u64 * slots.chunk_size as u64;
+        let mut buf = vec![0u8; (remaining as usize).min(slots.chunk_size)];
+        file.read_exact(&mut buf)
+            .await
+            .map_err(|e| ErrorCode::FileIo.err(format!("Read error at chunk {}: {}", idx, e)))?;
+
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        let url = format!("{}/transfers/{}/chunks/{}", server_url, transfer_id, idx);
+        let jwt_token = jwt_token.to_string();
+        let client = client.clone();
+        let progress = progress.clone();
+        handles.push(tokio::spawn(async move {
+            let _permit = permit;
+            let slot = tokio::task::spawn_blocking(move || {
+                let cipher = ChunkCipher::new(slots.aead, &slots.key);
+                seal_slot(&cipher, &slots.key, idx, slots.chunk_size, &buf, slots.compress, slots.sparse)
+            })
+            .await
+            .map_err(|e| ErrorCode::Protocol.err(format!("Encryption task panicked at chunk {}: {}", idx, e)))??
+            .0;
+            let slot_len = slot.len() as u64;
+            put_chunk(&client, &url, &jwt_token, idx as usize, Bytes::from(slot), &progress, &CHUNK_BACKOFF).await?;
+            progress.add_bytes(slot_len);
+            Ok::<(), TransferError>(())
+        }));
+    }
+
+    for handle in handles {
+        handle
+            .await
+            .map_err(|e| ErrorCode::Protocol.err(format!("Upload task panicked: {}", e)))??;
     }
+    if progress.is_cancelled() {
+        return Err(TransferError::cancelled());
+    }
+    Ok(())
+}
+
+/// Chunks the sender can skip given FastResumeState's `missing_chunks`:
+/// every chunk not listed, or none if there is nothing to resume.
+fn skipped_chunks(chunk_count: u32, missing: Option<&[u32]>) -> Vec<u32> {
+    match missing {
+        Some(missing) => {
+            let missing: std::collections::HashSet<u32> = missing.iter().copied().collect();
+            (0..chunk_count).filter(|c| !missing.contains(c)).collect()
+        }
+        None => Vec::new(),
+    }
+}
+
+/// One chunk's NACK: a FastNack's `data`, or an entry of a FastNackBatch.
+fn parse_nack(v: &serde_json::Value) -> Option<NackMessage> {
+    let chunk_idx = v["chunk_idx"].as_u64()?;
+    let missing_frames = v["missing_frames"]
+        .as_array()?
+        .iter()
+        .filter_map(|f| f.as_u64().map(|n| n as u16))
+        .collect();
+    Some(NackMessage { chunk_index: chunk_idx as u32, missing_frames })
 }
 
 /// Parse transfer ID (UUID string) into 16 bytes.
@@ -336,3 +728,178 @@ fn parse_transfer_id_bytes(transfer_id: &str) -> [u8; 16] {
     arr.copy_from_slice(&hash[..16]);
     arr
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use sha2::{Digest, Sha256};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[derive(Default)]
+    struct Seen {
+        chunk_size: u64,
+        aead: u64,
+        chunk_hashes: Vec<String>,
+        fell_back: bool,
+        puts: std::collections::BTreeMap<u32, Vec<u8>>,
+    }
+
+    /// A file server whose UDP port swallows everything: the WebSocket and
+    /// HTTP routes work, but no datagram is ever answered.
+    async fn udp_blocked_server(listener: TcpListener, seen: Arc<std::sync::Mutex<Seen>>) {
+        let black_hole = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp_port = black_hole.local_addr().unwrap().port();
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let seen = seen.clone();
+            tokio::spawn(async move {
+                let mut head = [0u8; 64];
+                let n = stream.peek(&mut head).await.unwrap();
+                if head[..n].starts_with(b"GET /fast-transfer") {
+                    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    while let Some(Ok(Message::Text(text))) = ws.next().await {
+                        let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+                        let reply = match msg["type"].as_str() {
+                            Some("FastResume") => serde_json::json!({
+                                "type": "FastResumeState",
+                                "data": { "transfer_id": msg["data"]["transfer_id"], "missing_chunks": null },
+                            }),
+                            Some("FastUploadStart") => {
+                                let mut seen = seen.lock().unwrap();
+                                seen.chunk_size = msg["data"]["chunk_size"].as_u64().unwrap();
+                                seen.aead = msg["data"]["aead"].as_u64().unwrap();
+                                seen.chunk_hashes =
+                                    serde_json::from_value(msg["data"]["chunk_hashes"].clone()).unwrap();
+                                serde_json::json!({
+                                    "type": "FastUploadReady",
+                                    "data": { "transfer_id": msg["data"]["transfer_id"], "udp_port": udp_port },
+                                })
+                            }
+                            Some("FastFallback") => {
+                                seen.lock().unwrap().fell_back = true;
+                                continue;
+                            }
+                            _ => continue,
+                        };
+                        ws.send(Message::Text(reply.to_string())).await.unwrap();
+                    }
+                    return;
+                }
+
+                // One `POST /transfers/check` or `PUT /transfers/{id}/chunks/{idx}`
+                // per connection.
+                let mut buf = Vec::new();
+                let mut tmp = [0u8; 64 * 1024];
+                let body_at = loop {
+                    let n = stream.read(&mut tmp).await.unwrap();
+                    buf.extend_from_slice(&tmp[..n]);
+                    if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break pos + 4;
+                    }
+                };
+                let head = String::from_utf8_lossy(&buf[..body_at]).to_lowercase();
+                let len: usize = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length:"))
+                    .map(|v| v.trim().parse().unwrap())
+                    .unwrap_or(0);
+                while buf.len() < body_at + len {
+                    let n = stream.read(&mut tmp).await.unwrap();
+                    buf.extend_from_slice(&tmp[..n]);
+                }
+                if head.starts_with("post /transfers/check") {
+                    // Chunks from 64 KB up to 1 MB.
+                    let body = format!(
+                        r#"{{"accepted":true,"chunk_sizes":{{"min":{},"max":{}}}}}"#,
+                        haven_fast_transfer::encrypted_chunk_size(MIN_CHUNK_SIZE),
+                        haven_fast_transfer::encrypted_chunk_size(1 << 20)
+                    );
+                    let reply = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}", body.len(), body);
+                    let _ = stream.write_all(reply.as_bytes()).await;
+                    return;
+                }
+                let idx = head.split_whitespace().nth(1).unwrap().rsplit('/').next().unwrap().parse().unwrap();
+                seen.lock().unwrap().puts.insert(idx, buf[body_at..body_at + len].to_vec());
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await;
+            });
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn upload_falls_back_to_http_when_udp_is_blocked() {
+        let dir = std::env::temp_dir().join(format!("haven-fallback-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.bin");
+        let file: Vec<u8> = (0..(1 << 20) + 100).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &file).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let seen = Arc::new(std::sync::Mutex::new(Seen::default()));
+        tokio::spawn(udp_blocked_server(listener, seen.clone()));
+
+        let progress = Arc::new(UploadProgress::new());
+        let transfer_id = "7f0e4c1a-2b3d-4e5f-8a9b-0c1d2e3f4a5b";
+        fast_upload_file(
+            path.to_str().unwrap(),
+            &url,
+            transfer_id,
+            "jwt",
+            &[7u8; 32],
+            &[9u8; 16],
+            FastUploadOptions { chunk_size: 1 << 20, ..Default::default() },
+            progress.clone(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(progress.state.load(Ordering::Relaxed), STATE_COMPLETE);
+        assert_eq!(progress.fell_back_to_http.load(Ordering::Relaxed), 1);
+        let seen = seen.lock().unwrap();
+        assert!(seen.fell_back);
+        // AES-256-GCM unless asked otherwise, whatever this machine runs fastest.
+        assert_eq!(seen.aead, AeadAlgorithm::Aes256Gcm.id() as u64);
+        assert_eq!(seen.chunk_size, haven_fast_transfer::encrypted_chunk_size(1 << 20) as u64);
+        assert_eq!(seen.puts.len(), 2);
+        for (idx, slot) in &seen.puts {
+            assert_eq!(hex::encode(Sha256::digest(slot)), seen.chunk_hashes[*idx as usize]);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn upload_refuses_chunk_sizes_the_server_wont_take() {
+        let dir = std::env::temp_dir().join(format!("haven-chunk-size-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.bin");
+        std::fs::write(&path, b"small").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let seen = Arc::new(std::sync::Mutex::new(Seen::default()));
+        tokio::spawn(udp_blocked_server(listener, seen.clone()));
+
+        let upload = |chunk_size| {
+            let (path, url) = (path.clone(), url.clone());
+            async move {
+                let options = FastUploadOptions { chunk_size, ..Default::default() };
+                let progress = Arc::new(UploadProgress::new());
+                fast_upload_file(path.to_str().unwrap(), &url, "t", "jwt", &[7u8; 32], &[9u8; 16], options, progress)
+                    .await
+                    .unwrap_err()
+            }
+        };
+        // Past what this server lists, and past what any server could.
+        let err = upload(haven_fast_transfer::CHUNK_SIZE).await;
+        assert_eq!(err.code, ErrorCode::Protocol);
+        assert!(err.message.starts_with("Server takes"), "{}", err.message);
+        assert_eq!(upload(1000).await.code, ErrorCode::Protocol);
+        assert!(seen.lock().unwrap().chunk_hashes.is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
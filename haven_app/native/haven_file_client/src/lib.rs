@@ -1,10 +1,14 @@
 #![allow(private_interfaces)]
 
+pub mod callback;
 pub mod crypto;
 pub mod download;
+pub mod error;
 pub mod fast_download;
 pub mod fast_upload;
 pub mod loopback;
+pub mod rate;
+pub mod sniff;
 pub mod upload;
 
 use std::ffi::CStr;
@@ -12,6 +16,7 @@ use std::os::raw::c_char;
 use std::sync::Arc;
 use std::sync::atomic::Ordering;
 
+use error::ErrorCode;
 use upload::UploadProgress;
 use download::DownloadProgress;
 
@@ -68,6 +73,62 @@ pub unsafe extern "C" fn haven_upload_file(
     salt: *const c_char,
 ) -> Handle {
     let file_path = unsafe { cstr_to_str(file_path) }.to_string();
+    unsafe {
+        start_upload(
+            upload::Source::Path(file_path),
+            server_url,
+            transfer_id,
+            jwt_token,
+            master_key,
+            salt,
+        )
+    }
+}
+
+/// Start an upload from an in-memory buffer, without a temp file.
+///
+/// The buffer is copied before this returns, so the caller may free it
+/// immediately. Returns a handle for progress polling and cancellation.
+///
+/// # Safety
+/// `data_ptr` must point to `data_len` readable bytes (or be NULL with
+/// `data_len` 0). All string pointers must be valid null-terminated UTF-8 C strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_upload_buffer(
+    data_ptr: *const u8,
+    data_len: usize,
+    server_url: *const c_char,
+    transfer_id: *const c_char,
+    jwt_token: *const c_char,
+    master_key: *const c_char,
+    salt: *const c_char,
+) -> Handle {
+    let data = if data_ptr.is_null() || data_len == 0 {
+        Vec::new()
+    } else {
+        unsafe { std::slice::from_raw_parts(data_ptr, data_len) }.to_vec()
+    };
+    unsafe {
+        start_upload(
+            upload::Source::Bytes(data),
+            server_url,
+            transfer_id,
+            jwt_token,
+            master_key,
+            salt,
+        )
+    }
+}
+
+/// Copy the FFI arguments and spawn `upload::upload_file` for `source`.
+unsafe fn start_upload(
+    source: upload::Source,
+    server_url: *const c_char,
+    transfer_id: *const c_char,
+    jwt_token: *const c_char,
+    master_key: *const c_char,
+    salt: *const c_char,
+) -> Handle {
     let server_url = unsafe { cstr_to_str(server_url) }.to_string();
     let transfer_id = unsafe { cstr_to_str(transfer_id) }.to_string();
     let jwt_token = unsafe { cstr_to_str(jwt_token) }.to_string();
@@ -83,7 +144,7 @@ pub unsafe extern "C" fn haven_upload_file(
     let rt = get_or_create_runtime();
     rt.spawn(async move {
         let result = upload::upload_file(
-            &file_path,
+            source,
             &server_url,
             &transfer_id,
             &jwt_token,
@@ -95,7 +156,7 @@ pub unsafe extern "C" fn haven_upload_file(
 
         if let Err(e) = result {
             eprintln!("Upload error: {}", e);
-            *progress_clone.last_error.lock().unwrap() = Some(e);
+            progress_clone.set_error(e);
             // Only overwrite state if it hasn't already been set to a terminal state.
             let cur = progress_clone.state.load(std::sync::atomic::Ordering::Relaxed);
             if cur != upload::STATE_COMPLETE && cur != upload::STATE_CANCELLED {
@@ -159,7 +220,7 @@ pub unsafe extern "C" fn haven_resume_upload(
 
         if let Err(e) = result {
             eprintln!("Resume upload error: {}", e);
-            *progress_clone.last_error.lock().unwrap() = Some(e);
+            progress_clone.set_error(e);
             let cur = progress_clone.state.load(std::sync::atomic::Ordering::Relaxed);
             if cur != upload::STATE_COMPLETE && cur != upload::STATE_CANCELLED {
                 progress_clone.state.store(upload::STATE_ERROR, std::sync::atomic::Ordering::Relaxed);
@@ -185,6 +246,48 @@ pub unsafe extern "C" fn haven_download_file(
     salt: *const c_char,
     file_sha256: *const c_char,
     chunk_hashes_json: *const c_char,
+) -> Handle {
+    unsafe {
+        spawn_download(save_path, server_url, transfer_id, jwt_token, master_key, salt, file_sha256, chunk_hashes_json, false)
+    }
+}
+
+/// Start a download like `haven_download_file`, but fetch the file as
+/// independent chunks listed by the server's manifest, several at once.
+/// Each chunk is verified as it lands and refetched on its own if it fails,
+/// which suits very large files. Same arguments, handle and states.
+///
+/// # Safety
+/// All string pointers must be valid null-terminated UTF-8 C strings.
+/// chunk_hashes_json must be a JSON array of hex strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_download_file_parallel(
+    save_path: *const c_char,
+    server_url: *const c_char,
+    transfer_id: *const c_char,
+    jwt_token: *const c_char,
+    master_key: *const c_char,
+    salt: *const c_char,
+    file_sha256: *const c_char,
+    chunk_hashes_json: *const c_char,
+) -> Handle {
+    unsafe {
+        spawn_download(save_path, server_url, transfer_id, jwt_token, master_key, salt, file_sha256, chunk_hashes_json, true)
+    }
+}
+
+/// Body of `haven_download_file` and `haven_download_file_parallel`.
+#[allow(clippy::too_many_arguments)]
+unsafe fn spawn_download(
+    save_path: *const c_char,
+    server_url: *const c_char,
+    transfer_id: *const c_char,
+    jwt_token: *const c_char,
+    master_key: *const c_char,
+    salt: *const c_char,
+    file_sha256: *const c_char,
+    chunk_hashes_json: *const c_char,
+    parallel: bool,
 ) -> Handle {
     let save_path = unsafe { cstr_to_str(save_path) }.to_string();
     let server_url = unsafe { cstr_to_str(server_url) }.to_string();
@@ -208,7 +311,7 @@ pub unsafe extern "C" fn haven_download_file(
                 &hashes_json[..hashes_json.len().min(200)]
             );
             eprintln!("Download error: {}", err_msg);
-            *progress.last_error.lock().unwrap() = Some(err_msg);
+            progress.set_error(ErrorCode::Protocol.err(err_msg));
             progress.state.store(upload::STATE_ERROR, Ordering::Relaxed);
             let handle = Box::new(TransferHandle::Download(progress));
             return Box::into_raw(handle);
@@ -219,7 +322,7 @@ pub unsafe extern "C" fn haven_download_file(
         let progress = Arc::new(DownloadProgress::new());
         let err_msg = "chunk_hashes is empty — offer data was not received or was corrupted".to_string();
         eprintln!("Download error: {}", err_msg);
-        *progress.last_error.lock().unwrap() = Some(err_msg);
+        progress.set_error(ErrorCode::Protocol.err(err_msg));
         progress.state.store(upload::STATE_ERROR, Ordering::Relaxed);
         let handle = Box::new(TransferHandle::Download(progress));
         return Box::into_raw(handle);
@@ -229,7 +332,7 @@ pub unsafe extern "C" fn haven_download_file(
         let progress = Arc::new(DownloadProgress::new());
         let err_msg = "file_sha256 is empty — offer data was not received or was corrupted".to_string();
         eprintln!("Download error: {}", err_msg);
-        *progress.last_error.lock().unwrap() = Some(err_msg);
+        progress.set_error(ErrorCode::Protocol.err(err_msg));
         progress.state.store(upload::STATE_ERROR, Ordering::Relaxed);
         let handle = Box::new(TransferHandle::Download(progress));
         return Box::into_raw(handle);
@@ -243,22 +346,24 @@ pub unsafe extern "C" fn haven_download_file(
 
     let rt = get_or_create_runtime();
     rt.spawn(async move {
-        let result = download::download_file(
-            &save_path,
-            &server_url,
-            &transfer_id,
-            &jwt_token,
-            &master_key,
-            &salt,
-            &file_sha256,
-            &chunk_hashes,
-            progress_clone.clone(),
-        )
-        .await;
+        let source = download::DownloadSource {
+            server_url: &server_url,
+            transfer_id: &transfer_id,
+            jwt_token: &jwt_token,
+            master_key: &master_key,
+            salt: &salt,
+            file_sha256: &file_sha256,
+            chunk_hashes: &chunk_hashes,
+        };
+        let result = if parallel {
+            download::download_file_parallel(&save_path, &source, progress_clone.clone()).await
+        } else {
+            download::download_file(&save_path, &source, progress_clone.clone()).await
+        };
 
         if let Err(e) = result {
             eprintln!("Download error: {}", e);
-            *progress_clone.last_error.lock().unwrap() = Some(e);
+            progress_clone.set_error(e);
             let cur = progress_clone.state.load(std::sync::atomic::Ordering::Relaxed);
             if cur != upload::STATE_COMPLETE && cur != upload::STATE_CANCELLED {
                 progress_clone.state.store(upload::STATE_ERROR, std::sync::atomic::Ordering::Relaxed);
@@ -269,6 +374,140 @@ pub unsafe extern "C" fn haven_download_file(
     handle_ptr
 }
 
+/// Stream a download through `callback` instead of saving it, e.g. to
+/// decode a preview without writing the decrypted file to disk. Returns a
+/// download handle for progress polling and cancellation.
+///
+/// The callback is invoked as `callback(chunk_ptr, chunk_len)` once per
+/// decrypted chunk, in order, on a Tokio worker thread. `chunk_ptr` is only
+/// valid until the callback returns: copy out whatever you need to keep.
+/// Return false to stop the download early; the handle then reports
+/// STATE_COMPLETE without the rest of the file. Each chunk's hash is checked
+/// before the callback sees it, so a tampered chunk ends the stream in
+/// STATE_ERROR (code 3, hash mismatch) and is never delivered.
+///
+/// The transfer is not confirmed to the server, so it stays available for
+/// the real download.
+///
+/// # Safety
+/// All string pointers must be valid null-terminated UTF-8 C strings.
+/// chunk_hashes_json must be a JSON array of hex strings. `callback` must
+/// stay callable until the handle reaches a terminal state.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_download_to_callback(
+    server_url: *const c_char,
+    transfer_id: *const c_char,
+    jwt_token: *const c_char,
+    master_key: *const c_char,
+    salt: *const c_char,
+    file_sha256: *const c_char,
+    chunk_hashes_json: *const c_char,
+    callback: download::ChunkCallback,
+) -> Handle {
+    let server_url = unsafe { cstr_to_str(server_url) }.to_string();
+    let transfer_id = unsafe { cstr_to_str(transfer_id) }.to_string();
+    let jwt_token = unsafe { cstr_to_str(jwt_token) }.to_string();
+    let master_key = unsafe { cstr_to_bytes(master_key) }.to_vec();
+    let salt = unsafe { cstr_to_bytes(salt) }.to_vec();
+    let file_sha256 = unsafe { cstr_to_str(file_sha256) }.to_string();
+    let hashes_json = unsafe { cstr_to_str(chunk_hashes_json) }.to_string();
+
+    let progress = Arc::new(DownloadProgress::new());
+    let progress_clone = progress.clone();
+    let handle_ptr = Box::into_raw(Box::new(TransferHandle::Download(progress)));
+
+    let rt = get_or_create_runtime();
+    rt.spawn(async move {
+        let result = match serde_json::from_str::<Vec<String>>(&hashes_json) {
+            Ok(hashes) if !hashes.is_empty() && !file_sha256.is_empty() => {
+                let source = download::DownloadSource {
+                    server_url: &server_url,
+                    transfer_id: &transfer_id,
+                    jwt_token: &jwt_token,
+                    master_key: &master_key,
+                    salt: &salt,
+                    file_sha256: &file_sha256,
+                    chunk_hashes: &hashes,
+                };
+                download::download_to_callback(&source, callback, progress_clone.clone()).await
+            }
+            Ok(_) => Err(ErrorCode::Protocol.err("chunk_hashes or file_sha256 is empty")),
+            Err(e) => Err(ErrorCode::Protocol.err(format!("Failed to parse chunk_hashes JSON: {}", e))),
+        };
+
+        if let Err(e) = result {
+            eprintln!("Preview download error: {}", e);
+            progress_clone.set_error(e);
+            let cur = progress_clone.state.load(Ordering::Relaxed);
+            if cur != upload::STATE_COMPLETE && cur != upload::STATE_CANCELLED {
+                progress_clone.state.store(upload::STATE_ERROR, Ordering::Relaxed);
+            }
+        }
+    });
+
+    handle_ptr
+}
+
+/// Re-verify an already downloaded file against its transfer's hashes,
+/// without contacting the server. Returns a download handle.
+///
+/// The handle reports STATE_HASHING while it reads the file, then
+/// STATE_COMPLETE if every chunk and the full file match. Otherwise it ends
+/// in STATE_ERROR with error code 3 (hash mismatch), and `haven_get_last_error`
+/// lists the mismatched chunk indices, e.g. `2 of 9 chunks do not match: [3, 7]`.
+///
+/// The hashes cover the encrypted chunks, so the same `master_key` and
+/// `salt` as `haven_download_file` are needed to re-derive them.
+///
+/// # Safety
+/// All string pointers must be valid null-terminated UTF-8 C strings.
+/// chunk_hashes_json must be a JSON array of hex strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_verify_file(
+    save_path: *const c_char,
+    master_key: *const c_char,
+    salt: *const c_char,
+    file_sha256: *const c_char,
+    chunk_hashes_json: *const c_char,
+) -> Handle {
+    let save_path = unsafe { cstr_to_str(save_path) }.to_string();
+    let master_key = unsafe { cstr_to_bytes(master_key) }.to_vec();
+    let salt = unsafe { cstr_to_bytes(salt) }.to_vec();
+    let file_sha256 = unsafe { cstr_to_str(file_sha256) }.to_string();
+    let hashes_json = unsafe { cstr_to_str(chunk_hashes_json) }.to_string();
+
+    let progress = Arc::new(DownloadProgress::new());
+    let progress_clone = progress.clone();
+    let handle_ptr = Box::into_raw(Box::new(TransferHandle::Download(progress)));
+
+    let rt = get_or_create_runtime();
+    rt.spawn_blocking(move || {
+        let result = match serde_json::from_str::<Vec<String>>(&hashes_json) {
+            Ok(hashes) if !hashes.is_empty() && !file_sha256.is_empty() => download::verify_file(
+                &save_path,
+                &master_key,
+                &salt,
+                &file_sha256,
+                &hashes,
+                &progress_clone,
+            ),
+            Ok(_) => Err(ErrorCode::Protocol.err("chunk_hashes or file_sha256 is empty")),
+            Err(e) => Err(ErrorCode::Protocol.err(format!("Failed to parse chunk_hashes JSON: {}", e))),
+        };
+
+        if let Err(e) = result {
+            eprintln!("Verify error: {}", e);
+            progress_clone.set_error(e);
+            let cur = progress_clone.state.load(Ordering::Relaxed);
+            if cur != upload::STATE_COMPLETE && cur != upload::STATE_CANCELLED {
+                progress_clone.state.store(upload::STATE_ERROR, Ordering::Relaxed);
+            }
+        }
+    });
+
+    handle_ptr
+}
+
 /// Cancel a transfer (upload or download).
 ///
 /// # Safety
@@ -285,12 +524,114 @@ pub unsafe extern "C" fn haven_transfer_cancel(handle: Handle) {
     }
 }
 
+/// Pause a transfer between chunks without tearing down its connection.
+/// `haven_transfer_progress` reports `STATE_PAUSED` (6) until resumed.
+///
+/// Fast downloads only pause their local decrypt pass; the server keeps
+/// blasting until the receive completes.
+///
+/// # Safety
+/// Handle must be a valid pointer returned by haven_upload_file or haven_download_file.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_transfer_pause(handle: Handle) {
+    if handle.is_null() {
+        return;
+    }
+    match unsafe { &*handle } {
+        TransferHandle::Upload(p) => p.paused.store(1, Ordering::Relaxed),
+        TransferHandle::Download(p) => p.paused.store(1, Ordering::Relaxed),
+    }
+}
+
+/// Resume a paused transfer from the chunk it stopped at.
+///
+/// # Safety
+/// Handle must be a valid pointer returned by haven_upload_file or haven_download_file.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_transfer_resume(handle: Handle) {
+    if handle.is_null() {
+        return;
+    }
+    match unsafe { &*handle } {
+        TransferHandle::Upload(p) => p.paused.store(0, Ordering::Relaxed),
+        TransferHandle::Download(p) => p.paused.store(0, Ordering::Relaxed),
+    }
+}
+
+/// Cap a fast upload's send rate at `bps` bytes/s; 0 lifts the cap. Takes
+/// effect mid-transfer from the next chunk, so a transfer can be throttled
+/// on the fly to leave room for voice and chat.
+///
+/// Downloads are blasted by the server and ignore this.
+///
+/// # Safety
+/// Handle must be a valid pointer returned by haven_upload_file or haven_download_file.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_transfer_set_rate_cap(handle: Handle, bps: u64) {
+    if handle.is_null() {
+        return;
+    }
+    if let TransferHandle::Upload(p) = unsafe { &*handle } {
+        p.rate_cap_bps.store(bps, Ordering::Relaxed);
+    }
+}
+
+/// Hand an upload a refreshed JWT. Later chunk requests send it, a chunk
+/// that was refused with 401 is retried with it, and a fast upload passes it
+/// to the server (`FastReauth`), so an upload that outlives its first token
+/// completes without restarting.
+///
+/// Downloads authenticate once up front and ignore this.
+///
+/// # Safety
+/// Handle must be a valid pointer returned by haven_upload_file or haven_download_file.
+/// `jwt_token` must be a valid null-terminated UTF-8 C string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_transfer_set_token(handle: Handle, jwt_token: *const c_char) {
+    if handle.is_null() {
+        return;
+    }
+    let jwt_token = unsafe { cstr_to_str(jwt_token) };
+    if jwt_token.is_empty() {
+        return;
+    }
+    if let TransferHandle::Upload(p) = unsafe { &*handle } {
+        *p.refreshed_token.lock().unwrap() = Some(jwt_token.to_string());
+    }
+}
+
 /// Progress result returned by haven_transfer_progress.
+///
+/// New fields are only ever appended so older callers reading a prefix of
+/// the struct keep working.
 #[repr(C)]
 pub struct TransferProgressResult {
     pub bytes_done: u64,
     pub bytes_total: u64,
     pub state: u8,
+    /// Smoothed throughput in bytes/s (EWMA over the last few seconds).
+    pub rate_bps: u64,
+    /// Estimated seconds remaining, or 0 if unknown.
+    pub eta_seconds: u64,
+    /// Upload chunk waiting to be retried after a transient failure, or -1.
+    pub retrying_chunk: i64,
+    /// Chunk upload retries so far (uploads only).
+    pub retries: u64,
+}
+
+impl TransferProgressResult {
+    fn sample(bytes_done: u64, bytes_total: u64, state: u8, rate: &rate::RateTracker) -> Self {
+        let rate_bps = rate.sample(bytes_done);
+        Self {
+            bytes_done,
+            bytes_total,
+            state,
+            rate_bps,
+            eta_seconds: rate::eta_seconds(bytes_done, bytes_total, rate_bps),
+            retrying_chunk: -1,
+            retries: 0,
+        }
+    }
 }
 
 /// Poll transfer progress.
@@ -304,20 +645,30 @@ pub unsafe extern "C" fn haven_transfer_progress(handle: Handle) -> TransferProg
             bytes_done: 0,
             bytes_total: 0,
             state: 0,
+            rate_bps: 0,
+            eta_seconds: 0,
+            retrying_chunk: -1,
+            retries: 0,
         };
     }
     let transfer = unsafe { &*handle };
     match transfer {
         TransferHandle::Upload(p) => TransferProgressResult {
-            bytes_done: p.bytes_done.load(Ordering::Relaxed),
-            bytes_total: p.bytes_total.load(Ordering::Relaxed),
-            state: p.state.load(Ordering::Relaxed),
-        },
-        TransferHandle::Download(p) => TransferProgressResult {
-            bytes_done: p.bytes_done.load(Ordering::Relaxed),
-            bytes_total: p.bytes_total.load(Ordering::Relaxed),
-            state: p.state.load(Ordering::Relaxed),
+            retrying_chunk: p.retrying_chunk.load(Ordering::Relaxed),
+            retries: p.retries.load(Ordering::Relaxed),
+            ..TransferProgressResult::sample(
+                p.bytes_done.load(Ordering::Relaxed),
+                p.bytes_total.load(Ordering::Relaxed),
+                p.state.load(Ordering::Relaxed),
+                &p.rate,
+            )
         },
+        TransferHandle::Download(p) => TransferProgressResult::sample(
+            p.bytes_done.load(Ordering::Relaxed),
+            p.bytes_total.load(Ordering::Relaxed),
+            p.state.load(Ordering::Relaxed),
+            &p.rate,
+        ),
     }
 }
 
@@ -347,6 +698,59 @@ pub unsafe extern "C" fn haven_get_last_error(handle: Handle) -> *mut c_char {
     }
 }
 
+/// Register a push-based progress callback, replacing any previous one.
+/// Pass NULL to clear it.
+///
+/// The callback is invoked as
+/// `callback(bytes_done, bytes_total, state, rate_bps, eta_seconds)` on a
+/// Tokio worker thread, not the caller's thread; the rate and ETA are as in
+/// `haven_transfer_progress`. It fires every 50ms or once progress moves by
+/// `delta_permille` thousandths of the total (whichever comes first; 0 means
+/// the default of 10, i.e. 1%) and on every state change; the last call
+/// reports the terminal state.
+///
+/// `haven_transfer_free` clears the callback and waits for an in-flight call to
+/// return, so it is never invoked afterwards. Do not free the handle from inside
+/// the callback.
+///
+/// # Safety
+/// Handle must be a valid pointer returned by haven_upload_file or haven_download_file.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_transfer_set_callback(
+    handle: Handle,
+    callback: Option<callback::ProgressCallback>,
+    delta_permille: u32,
+) {
+    if handle.is_null() {
+        return;
+    }
+    let rt = get_or_create_runtime();
+    let delta_permille = delta_permille as u64;
+    match unsafe { &*handle } {
+        TransferHandle::Upload(p) => callback::set_callback(rt, p.clone(), callback, delta_permille),
+        TransferHandle::Download(p) => callback::set_callback(rt, p.clone(), callback, delta_permille),
+    }
+}
+
+/// Get the category of the last error, for callers to branch on and localize.
+///
+/// Returns 0 if there is no error, otherwise one of:
+/// 1 = auth, 2 = network, 3 = hash mismatch, 4 = file IO, 5 = cancelled,
+/// 6 = protocol. `haven_get_last_error` still has the message for display.
+///
+/// # Safety
+/// Handle must be a valid pointer returned by haven_upload_file or haven_download_file.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_get_last_error_code(handle: Handle) -> i32 {
+    if handle.is_null() {
+        return 0;
+    }
+    match unsafe { &*handle } {
+        TransferHandle::Upload(p) => p.last_error_code.load(Ordering::Relaxed),
+        TransferHandle::Download(p) => p.last_error_code.load(Ordering::Relaxed),
+    }
+}
+
 /// Free a transfer handle.
 ///
 /// # Safety
@@ -355,7 +759,11 @@ pub unsafe extern "C" fn haven_get_last_error(handle: Handle) -> *mut c_char {
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn haven_transfer_free(handle: Handle) {
     if !handle.is_null() {
-        let _ = unsafe { Box::from_raw(handle) };
+        let transfer = unsafe { Box::from_raw(handle) };
+        match *transfer {
+            TransferHandle::Upload(ref p) => p.progress_callback.clear(),
+            TransferHandle::Download(ref p) => p.progress_callback.clear(),
+        }
     }
 }
 
@@ -389,9 +797,129 @@ pub unsafe extern "C" fn haven_upload_hashes_json(handle: Handle) -> *mut std::o
     }
 }
 
+/// Return the receiver's loss histogram JSON once a fast download has finished.
+///
+/// Returns a heap-allocated C string containing
+/// `{"bucket_ms":N,"total":N,"peak":N,"lossy_fraction":F,"buckets":[...]}`,
+/// or NULL if not available (upload handle, HTTP download, or still running).
+///
+/// The caller must free the returned string with `haven_free_string`.
+///
+/// # Safety
+/// Handle must be a valid pointer returned by `haven_fast_download`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_transfer_loss_histogram_json(handle: Handle) -> *mut c_char {
+    if handle.is_null() {
+        return std::ptr::null_mut();
+    }
+    match unsafe { &*handle } {
+        TransferHandle::Download(p) => {
+            let guard = p.loss_histogram_json.lock().unwrap();
+            match guard.as_ref() {
+                Some(json) => match std::ffi::CString::new(json.as_str()) {
+                    Ok(s) => s.into_raw(),
+                    Err(_) => std::ptr::null_mut(),
+                },
+                None => std::ptr::null_mut(),
+            }
+        }
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Take the fast-transfer events buffered for this handle, for an in-app
+/// debug panel or a bug report.
+///
+/// Returns a heap-allocated C string containing
+/// `{"dropped":N,"events":[{"ts_ms":N,"component":"...","transfer_id":"<hex>","event":"..."},...]}`,
+/// oldest event first. Draining clears the buffer, so each event is returned
+/// once. The buffer keeps the last 1024 events; `dropped` counts older ones
+/// evicted since the previous drain. HTTP transfers log nothing here.
+///
+/// The caller must free the returned string with `haven_free_string`.
+///
+/// # Safety
+/// Handle must be a valid pointer returned by any of the transfer functions.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_transfer_drain_log(handle: Handle) -> *mut c_char {
+    if handle.is_null() {
+        return std::ptr::null_mut();
+    }
+    let json = match unsafe { &*handle } {
+        TransferHandle::Upload(p) => p.transfer_log.drain_json(),
+        TransferHandle::Download(p) => p.transfer_log.drain_json(),
+    };
+    match std::ffi::CString::new(json) {
+        Ok(s) => s.into_raw(),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Return the local UDP port a fast download is receiving on.
+///
+/// Returns 0 before the receiver has bound its socket, and for uploads and
+/// HTTP downloads.
+///
+/// # Safety
+/// Handle must be a valid pointer returned by `haven_fast_download`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_fast_transfer_local_port(handle: Handle) -> u16 {
+    if handle.is_null() {
+        return 0;
+    }
+    match unsafe { &*handle } {
+        TransferHandle::Download(p) => p.local_udp_port.load(Ordering::Relaxed),
+        _ => 0,
+    }
+}
+
+/// Return the server UDP port a fast upload is blasting to.
+///
+/// Returns 0 until the server has answered with its port, and for downloads
+/// and HTTP uploads.
+///
+/// # Safety
+/// Handle must be a valid pointer returned by `haven_fast_upload`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_fast_transfer_server_port(handle: Handle) -> u16 {
+    if handle.is_null() {
+        return 0;
+    }
+    match unsafe { &*handle } {
+        TransferHandle::Upload(p) => p.server_udp_port.load(Ordering::Relaxed),
+        _ => 0,
+    }
+}
+
+/// Return 1 if a fast transfer gave up on UDP and moved to plain HTTP,
+/// else 0.
+///
+/// # Safety
+/// Handle must be a valid pointer returned by a `haven_*` transfer function.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_fast_transfer_fell_back(handle: Handle) -> u8 {
+    if handle.is_null() {
+        return 0;
+    }
+    match unsafe { &*handle } {
+        TransferHandle::Upload(p) => p.fell_back_to_http.load(Ordering::Relaxed),
+        TransferHandle::Download(p) => p.fell_back_to_http.load(Ordering::Relaxed),
+    }
+}
+
 // ── Fast transfer FFI exports ──────────────────────────────────────────
 
+/// `haven_fast_upload` flag: zstd-compress chunks before encryption.
+pub const FAST_UPLOAD_COMPRESS: u8 = 1;
+/// `haven_fast_upload` flag: store all-zero chunks as zero slots instead
+/// of blasting them (see `haven_fast_transfer::sparse`). Ignored with
+/// [`FAST_UPLOAD_COMPRESS`].
+pub const FAST_UPLOAD_SPARSE: u8 = 2;
+
 /// Start a fast UDP blast upload. Returns a handle for progress polling.
+/// `flags` is a set of `FAST_UPLOAD_*` bits. `chunk_size` is the plaintext
+/// chunk size, 0 for the default; one the server won't take fails the upload
+/// before it starts.
 ///
 /// # Safety
 /// All string pointers must be valid null-terminated UTF-8 C strings.
@@ -403,6 +931,8 @@ pub unsafe extern "C" fn haven_fast_upload(
     jwt_token: *const c_char,
     master_key: *const c_char,
     salt: *const c_char,
+    flags: u8,
+    chunk_size: u32,
 ) -> Handle {
     let file_path = unsafe { cstr_to_str(file_path) }.to_string();
     let server_url = unsafe { cstr_to_str(server_url) }.to_string();
@@ -426,13 +956,22 @@ pub unsafe extern "C" fn haven_fast_upload(
             &jwt_token,
             &master_key,
             &salt,
+            fast_upload::FastUploadOptions {
+                compress: flags & FAST_UPLOAD_COMPRESS != 0,
+                sparse: flags & FAST_UPLOAD_SPARSE != 0,
+                chunk_size: match chunk_size {
+                    0 => haven_fast_transfer::CHUNK_SIZE,
+                    n => n as usize,
+                },
+                ..Default::default()
+            },
             progress_clone.clone(),
         )
         .await;
 
         if let Err(e) = result {
             eprintln!("Fast upload error: {}", e);
-            *progress_clone.last_error.lock().unwrap() = Some(e);
+            progress_clone.set_error(e);
             let cur = progress_clone.state.load(std::sync::atomic::Ordering::Relaxed);
             if cur != upload::STATE_COMPLETE && cur != upload::STATE_CANCELLED {
                 progress_clone.state.store(upload::STATE_ERROR, std::sync::atomic::Ordering::Relaxed);
@@ -473,7 +1012,7 @@ pub unsafe extern "C" fn haven_fast_download(
             let progress = Arc::new(DownloadProgress::new());
             let err_msg = format!("Failed to parse chunk_hashes: {}", e);
             eprintln!("Fast download error: {}", err_msg);
-            *progress.last_error.lock().unwrap() = Some(err_msg);
+            progress.set_error(ErrorCode::Protocol.err(err_msg));
             progress.state.store(upload::STATE_ERROR, Ordering::Relaxed);
             let handle = Box::new(TransferHandle::Download(progress));
             return Box::into_raw(handle);
@@ -482,7 +1021,7 @@ pub unsafe extern "C" fn haven_fast_download(
 
     if chunk_hashes.is_empty() || file_sha256.is_empty() {
         let progress = Arc::new(DownloadProgress::new());
-        *progress.last_error.lock().unwrap() = Some("Empty hashes or sha256".into());
+        progress.set_error(ErrorCode::Protocol.err("Empty hashes or sha256"));
         progress.state.store(upload::STATE_ERROR, Ordering::Relaxed);
         let handle = Box::new(TransferHandle::Download(progress));
         return Box::into_raw(handle);
@@ -511,7 +1050,7 @@ pub unsafe extern "C" fn haven_fast_download(
 
         if let Err(e) = result {
             eprintln!("Fast download error: {}", e);
-            *progress_clone.last_error.lock().unwrap() = Some(e);
+            progress_clone.set_error(e);
             let cur = progress_clone.state.load(std::sync::atomic::Ordering::Relaxed);
             if cur != upload::STATE_COMPLETE && cur != upload::STATE_CANCELLED {
                 progress_clone.state.store(upload::STATE_ERROR, std::sync::atomic::Ordering::Relaxed);
@@ -522,7 +1061,99 @@ pub unsafe extern "C" fn haven_fast_download(
     handle_ptr
 }
 
-/// Free a C string returned by `haven_upload_hashes_json` or `haven_get_last_error`.
+// ── Transfer metadata FFI ──────────────────────────────────────────────
+
+/// Set how many recipients a fan-out offer reached, so the server keeps the
+/// transfer until each has confirmed (see `upload::set_recipients`). Call it
+/// with the count from `FileOfferBroadcastSent`, for HTTP and fast uploads
+/// alike. Blocks until the server answers; call it off the UI isolate.
+///
+/// Returns 0 on success, otherwise an error code as for
+/// `haven_get_last_error_code`.
+///
+/// # Safety
+/// All string pointers must be valid null-terminated UTF-8 C strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_set_recipients(
+    server_url: *const c_char,
+    transfer_id: *const c_char,
+    jwt_token: *const c_char,
+    recipients: u32,
+) -> i32 {
+    let server_url = unsafe { cstr_to_str(server_url) };
+    let transfer_id = unsafe { cstr_to_str(transfer_id) };
+    let jwt_token = unsafe { cstr_to_str(jwt_token) };
+
+    let client = reqwest::Client::new();
+    let result = get_or_create_runtime()
+        .block_on(upload::set_recipients(&client, server_url, transfer_id, jwt_token, recipients));
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Set recipients error: {}", e);
+            e.code as i32
+        }
+    }
+}
+
+// ── Key derivation FFI ─────────────────────────────────────────────────
+
+/// Derive a key from a passphrase with Argon2id. Returns the versioned key
+/// material (scheme byte + 32-byte key) as hex, or null if the parameters
+/// are invalid or the salt is shorter than 8 bytes. Blocks for as long as
+/// the parameters demand; call it off the UI isolate.
+///
+/// The caller must free the returned string with `haven_free_string`.
+///
+/// # Safety
+/// `password` and `salt` must be valid null-terminated C strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn haven_derive_key_argon2id(
+    password: *const c_char,
+    salt: *const c_char,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+) -> *mut c_char {
+    let password = unsafe { cstr_to_bytes(password) };
+    let salt = unsafe { cstr_to_bytes(salt) };
+    let params = haven_crypto::keys::Argon2idParams { memory_kib, iterations, parallelism };
+
+    match haven_crypto::keys::derive_key_argon2id(password, salt, &params) {
+        Ok(key) => {
+            let material = haven_crypto::keys::versioned_key(haven_crypto::keys::KdfVersion::Argon2id, &key);
+            match std::ffi::CString::new(hex::encode(material)) {
+                Ok(s) => s.into_raw(),
+                Err(_) => std::ptr::null_mut(),
+            }
+        }
+        Err(e) => {
+            eprintln!("Argon2id derivation failed: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Check this build's chunk crypto against pinned vectors (see
+/// `crypto::selftest_report`), so a key derivation or nonce mismatch with
+/// the server shows up at startup rather than as a decrypt failure
+/// mid-transfer.
+///
+/// Returns a heap-allocated C string containing
+/// `{"ok":bool,"vectors":{"<name>":{"output":"<hex>","expected":"<hex>","ok":bool},...}}`.
+///
+/// The caller must free the returned string with `haven_free_string`.
+#[unsafe(no_mangle)]
+pub extern "C" fn haven_crypto_selftest() -> *mut c_char {
+    match std::ffi::CString::new(crypto::selftest_report().to_string()) {
+        Ok(s) => s.into_raw(),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Free a C string returned by `haven_upload_hashes_json`, `haven_get_last_error`,
+/// `haven_transfer_loss_histogram_json`, `haven_transfer_drain_log`,
+/// `haven_derive_key_argon2id`, or `haven_crypto_selftest`.
 ///
 /// # Safety
 /// `ptr` must be a non-null pointer previously returned by one of the string-returning FFI functions.
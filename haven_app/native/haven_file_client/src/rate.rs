@@ -0,0 +1,62 @@
+//! Smoothed transfer rate and ETA for progress reporting.
+//!
+//! The rate is an exponentially weighted moving average sampled whenever
+//! progress is read, so bursty writes (a whole 4 MB chunk landing at once)
+//! don't make the UI's throughput figure jump around.
+
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Time constant of the moving average, in seconds.
+const RATE_TAU_SECS: f64 = 3.0;
+
+/// Samples closer together than this reuse the previous estimate.
+const RATE_MIN_SAMPLE_SECS: f64 = 0.2;
+
+struct RateSample {
+    at: Instant,
+    bytes_done: u64,
+    rate_bps: f64,
+}
+
+/// EWMA of bytes per second, fed from `bytes_done` snapshots.
+#[derive(Default)]
+pub struct RateTracker {
+    last: Mutex<Option<RateSample>>,
+}
+
+impl RateTracker {
+    /// Feed the current `bytes_done` and return the smoothed rate in bytes/s.
+    pub fn sample(&self, bytes_done: u64) -> u64 {
+        let now = Instant::now();
+        let mut last = self.last.lock().unwrap();
+
+        let prev = match last.as_mut() {
+            // Counters reset between phases (hashing → uploading); start over.
+            Some(prev) if bytes_done >= prev.bytes_done => prev,
+            _ => {
+                *last = Some(RateSample { at: now, bytes_done, rate_bps: 0.0 });
+                return 0;
+            }
+        };
+
+        let dt = now.duration_since(prev.at).as_secs_f64();
+        if dt >= RATE_MIN_SAMPLE_SECS {
+            let instant = (bytes_done - prev.bytes_done) as f64 / dt;
+            let alpha = 1.0 - (-dt / RATE_TAU_SECS).exp();
+            prev.rate_bps += alpha * (instant - prev.rate_bps);
+            prev.at = now;
+            prev.bytes_done = bytes_done;
+        }
+        prev.rate_bps as u64
+    }
+}
+
+/// Seconds until `bytes_total` at `rate_bps`. 0 when unknown (no rate yet)
+/// or already done.
+pub fn eta_seconds(bytes_done: u64, bytes_total: u64, rate_bps: u64) -> u64 {
+    if rate_bps == 0 {
+        return 0;
+    }
+    bytes_total.saturating_sub(bytes_done).div_ceil(rate_bps)
+}
@@ -1,13 +1,22 @@
+use std::collections::HashSet;
 use std::path::Path;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::atomic::{AtomicI32, AtomicI64, AtomicU16, AtomicU64, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
 
+use bytes::Bytes;
+use haven_fast_transfer::RingBufferLogger;
+use rand::Rng;
 use reqwest::Client;
 use sha2::{Sha256, Digest};
-use tokio::io::AsyncReadExt;
-use tokio::sync::Semaphore;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
+use crate::callback::CallbackSlot;
 use crate::crypto::{derive_key, derive_chunk_nonce, encrypt_chunk_with_nonce};
+use crate::error::{ErrorCode, TransferError};
+use crate::rate::RateTracker;
+use crate::sniff;
 
 /// Transfer state constants.
 pub const STATE_IDLE: u8 = 0;
@@ -16,13 +25,81 @@ pub const STATE_UPLOADING: u8 = 2;
 pub const STATE_COMPLETE: u8 = 3;
 pub const STATE_ERROR: u8 = 4;
 pub const STATE_CANCELLED: u8 = 5;
+/// Reported while the caller has paused the transfer; the previous state is
+/// restored on resume.
+pub const STATE_PAUSED: u8 = 6;
+
+/// How often a paused transfer re-checks its flags.
+const PAUSE_POLL_MS: u64 = 50;
+
+/// True once a transfer can no longer make progress.
+pub fn is_terminal(state: u8) -> bool {
+    matches!(state, STATE_COMPLETE | STATE_ERROR | STATE_CANCELLED)
+}
+
+/// Wait between chunks while `paused` is set, reporting `STATE_PAUSED`.
+///
+/// Connections stay open; the loop simply stops pulling/pushing data.
+/// Returns early on cancellation so the caller's cancel check runs.
+pub async fn wait_while_paused(paused: &AtomicU8, cancelled: &AtomicU8, state: &AtomicU8) {
+    if paused.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let prev = state.swap(STATE_PAUSED, Ordering::Relaxed);
+    while paused.load(Ordering::Relaxed) != 0 && cancelled.load(Ordering::Relaxed) == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(PAUSE_POLL_MS)).await;
+    }
+    let _ = state.compare_exchange(STATE_PAUSED, prev, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/// Blocking-thread counterpart of [`wait_while_paused`].
+pub fn block_while_paused(paused: &AtomicU8, cancelled: &AtomicU8, state: &AtomicU8) {
+    if paused.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let prev = state.swap(STATE_PAUSED, Ordering::Relaxed);
+    while paused.load(Ordering::Relaxed) != 0 && cancelled.load(Ordering::Relaxed) == 0 {
+        std::thread::sleep(std::time::Duration::from_millis(PAUSE_POLL_MS));
+    }
+    let _ = state.compare_exchange(STATE_PAUSED, prev, Ordering::Relaxed, Ordering::Relaxed);
+}
 
 const CHUNK_SIZE: usize = 4 * 1024 * 1024; // 4 MB
 
 /// Max number of chunks in flight (encrypt + upload) simultaneously.
 /// On a gigabit LAN: 4 MB / 125 MB/s ≈ 32 ms per chunk.
 /// 8 in flight keeps the pipe full while the disk reads the next chunk.
-const UPLOAD_CONCURRENCY: usize = 8;
+pub(crate) const UPLOAD_CONCURRENCY: usize = 8;
+
+/// Server round-trip above which consecutive chunks share one batch PUT.
+const BATCH_RTT_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// Chunks per batch PUT on a high-latency link. At most
+/// `UPLOAD_CONCURRENCY` so a batch can always get its permits.
+const MAX_BATCH_CHUNKS: usize = 4;
+
+/// Bounded exponential backoff for retrying a failed request.
+pub struct Backoff {
+    /// Retries after the first attempt.
+    pub max_retries: u32,
+    /// Delay before the first retry; doubled for each one after, then
+    /// jittered by ±50% so parallel chunks don't retry in lockstep.
+    pub base_delay: Duration,
+}
+
+impl Backoff {
+    fn delay(&self, retry: u32) -> Duration {
+        let jitter = rand::thread_rng().gen_range(0.5..1.5);
+        self.base_delay.mul_f64(2f64.powi(retry as i32) * jitter)
+    }
+}
+
+/// Chunk PUTs: 3 retries over roughly 0.5 s, 1 s, 2 s.
+pub const CHUNK_BACKOFF: Backoff = Backoff { max_retries: 3, base_delay: Duration::from_millis(500) };
+
+/// How long a request rejected with 401 waits for a refreshed token
+/// (`haven_transfer_set_token`) before the upload fails.
+const TOKEN_REFRESH_WAIT: Duration = Duration::from_secs(30);
 
 /// Shared progress state for FFI polling.
 pub struct UploadProgress {
@@ -30,10 +107,36 @@ pub struct UploadProgress {
     pub bytes_total: AtomicU64,
     pub state: AtomicU8,
     pub cancelled: AtomicU8,
+    /// Non-zero while the caller has paused the transfer.
+    pub paused: AtomicU8,
     /// Set after pass 1 completes: JSON string `{"file_sha256":"...","chunk_hashes":[...]}`.
     pub hashes_json: std::sync::Mutex<Option<String>>,
     /// Last error message, readable from FFI after STATE_ERROR.
     pub last_error: std::sync::Mutex<Option<String>>,
+    /// `ErrorCode` of `last_error` as an i32, 0 if none.
+    pub last_error_code: AtomicI32,
+    /// Optional push-based progress callback (see `callback`).
+    pub progress_callback: CallbackSlot,
+    /// Smoothed throughput, sampled as bytes are done and when progress is
+    /// read.
+    pub rate: RateTracker,
+    /// Chunk currently waiting to be retried, or -1.
+    pub retrying_chunk: AtomicI64,
+    /// Chunk upload retries so far.
+    pub retries: AtomicU64,
+    /// Server UDP port a fast upload blasts to, 0 until negotiated.
+    pub server_udp_port: AtomicU16,
+    /// Recent fast-transfer events, for `haven_transfer_drain_log`.
+    pub transfer_log: Arc<RingBufferLogger>,
+    /// Fast-upload send rate cap in bytes/s, 0 for none. Set through
+    /// `haven_transfer_set_rate_cap`.
+    pub rate_cap_bps: AtomicU64,
+    /// JWT pushed through `haven_transfer_set_token`. Requests after that use
+    /// it instead of the token the upload started with, so an upload can
+    /// outlive its first token.
+    pub refreshed_token: std::sync::Mutex<Option<String>>,
+    /// Set once a fast upload gave up on UDP and moved to the HTTP chunk path.
+    pub fell_back_to_http: AtomicU8,
 }
 
 impl UploadProgress {
@@ -43,17 +146,131 @@ impl UploadProgress {
             bytes_total: AtomicU64::new(0),
             state: AtomicU8::new(STATE_IDLE),
             cancelled: AtomicU8::new(0),
+            paused: AtomicU8::new(0),
             hashes_json: std::sync::Mutex::new(None),
             last_error: std::sync::Mutex::new(None),
+            last_error_code: AtomicI32::new(0),
+            progress_callback: CallbackSlot::default(),
+            rate: RateTracker::default(),
+            retrying_chunk: AtomicI64::new(-1),
+            retries: AtomicU64::new(0),
+            server_udp_port: AtomicU16::new(0),
+            transfer_log: Arc::default(),
+            rate_cap_bps: AtomicU64::new(0),
+            refreshed_token: std::sync::Mutex::new(None),
+            fell_back_to_http: AtomicU8::new(0),
         }
     }
 
     pub fn is_cancelled(&self) -> bool {
         self.cancelled.load(Ordering::Relaxed) != 0
     }
+
+    /// Count `n` more bytes done, feeding the rate estimate.
+    pub fn add_bytes(&self, n: u64) {
+        let done = self.bytes_done.fetch_add(n, Ordering::Relaxed) + n;
+        self.rate.sample(done);
+    }
+
+    /// Set the bytes done, feeding the rate estimate.
+    pub fn set_bytes(&self, done: u64) {
+        self.bytes_done.store(done, Ordering::Relaxed);
+        self.rate.sample(done);
+    }
+
+    /// Record a failure for `haven_get_last_error` / `haven_get_last_error_code`.
+    pub fn set_error(&self, err: TransferError) {
+        self.last_error_code.store(err.code as i32, Ordering::Relaxed);
+        *self.last_error.lock().unwrap() = Some(err.message);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed) != 0
+    }
+
+    pub async fn wait_while_paused(&self) {
+        wait_while_paused(&self.paused, &self.cancelled, &self.state).await
+    }
+
+    pub fn block_while_paused(&self) {
+        block_while_paused(&self.paused, &self.cancelled, &self.state)
+    }
+
+    /// The token to send now: the latest refresh, else `initial`.
+    pub fn token(&self, initial: &str) -> String {
+        self.refreshed_token.lock().unwrap().clone().unwrap_or_else(|| initial.to_string())
+    }
+
+    /// Wait up to `timeout` for a token other than `rejected` to be pushed.
+    /// False on timeout or cancellation.
+    async fn wait_for_refresh(&self, initial: &str, rejected: &str, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.token(initial) != rejected {
+                return true;
+            }
+            if self.is_cancelled() || Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(PAUSE_POLL_MS)).await;
+        }
+    }
+}
+
+/// Where an upload's plaintext comes from.
+pub enum Source {
+    /// A file on disk.
+    Path(String),
+    /// Caller-supplied bytes, already copied into owned memory.
+    Bytes(Vec<u8>),
 }
 
-/// Upload a file to the Haven file server.
+impl Source {
+    async fn len(&self) -> Result<u64, TransferError> {
+        match self {
+            Source::Path(path) => Ok(tokio::fs::metadata(path)
+                .await
+                .map_err(|e| ErrorCode::FileIo.err(format!("Cannot read file: {}", e)))?
+                .len()),
+            Source::Bytes(data) => Ok(data.len() as u64),
+        }
+    }
+
+    /// Blocking sequential reader, for pass 1.
+    fn open_blocking(&self) -> Result<Box<dyn std::io::Read + '_>, TransferError> {
+        match self {
+            Source::Path(path) => Ok(Box::new(
+                std::fs::File::open(path).map_err(|e| ErrorCode::FileIo.err(format!("Cannot open file: {}", e)))?,
+            )),
+            Source::Bytes(data) => Ok(Box::new(data.as_slice())),
+        }
+    }
+
+    /// Async sequential reader, for pass 2.
+    async fn open(&self) -> Result<Box<dyn AsyncRead + Unpin + Send + '_>, TransferError> {
+        match self {
+            Source::Path(path) => Ok(Box::new(
+                tokio::fs::File::open(path)
+                    .await
+                    .map_err(|e| ErrorCode::FileIo.err(format!("Cannot open file for upload: {}", e)))?,
+            )),
+            Source::Bytes(data) => Ok(Box::new(data.as_slice())),
+        }
+    }
+}
+
+/// What pass 1 learns from reading a file through once.
+pub(crate) struct FileDigest {
+    pub chunk_hashes: Vec<String>,
+    pub file_sha256: String,
+    pub encrypted_size: u64,
+    /// Sniffed from the first chunk.
+    pub content_type: Option<&'static str>,
+    /// All-zero chunks a sparse fast upload sends as zero slots.
+    pub sparse_chunks: Vec<u32>,
+}
+
+/// Upload a file (or in-memory buffer) to the Haven file server.
 ///
 /// Pass 1: single sequential read — compute per-chunk encrypted hashes and
 ///         full-file hash in one pass. No random seeks, no rayon parallelism.
@@ -64,62 +281,71 @@ impl UploadProgress {
 ///         thread (spawn_blocking) then PUTs to /transfers/{id}/chunks/{index}
 ///         via an async reqwest client. Semaphore limits concurrency to 8 so
 ///         the pipe stays full without overwhelming the server.
+///
+/// If the transfer already exists on the server (an earlier attempt died
+/// mid-upload), it isn't recreated and chunks the server already holds are
+/// skipped in pass 2.
+///
+/// Otherwise `POST /transfers/check` asks, before the create, whether the
+/// server has room for the upload at all and whether it already stores
+/// identical bytes for another transfer. On such a dedup hit the create
+/// links them and pass 2 is skipped entirely. The sniffed content type is
+/// only sent when the check says the server has a type policy.
+///
+/// The transfer is created for one recipient; a fan-out offer sets its real
+/// count afterwards with [`set_recipients`].
 pub async fn upload_file(
-    file_path: &str,
+    source: Source,
     server_url: &str,
     transfer_id: &str,
     jwt_token: &str,
     master_key: &[u8],
     salt: &[u8],
     progress: Arc<UploadProgress>,
-) -> Result<(), String> {
+) -> Result<(), TransferError> {
     let key = derive_key(master_key, salt);
     let async_client = Client::new();
 
-    let path = Path::new(file_path);
-    let file_size = tokio::fs::metadata(path)
-        .await
-        .map_err(|e| format!("Cannot read file: {}", e))?
-        .len();
+    let file_size = source.len().await?;
 
     progress.bytes_total.store(file_size, Ordering::Relaxed);
     progress.state.store(STATE_HASHING, Ordering::Relaxed);
 
-    let chunk_count = if file_size == 0 {
-        1
-    } else {
-        (file_size as usize + CHUNK_SIZE - 1) / CHUNK_SIZE
-    };
+    let chunk_count = haven_fast_transfer::chunk_count(file_size, CHUNK_SIZE as u64) as usize;
 
     // ── Pass 1: single sequential read, compute per-chunk and full-file hashes ─
     // One file open, forward-only reads — no seek contention on HDD.
-    let (chunk_hashes, file_sha256, encrypted_size) = {
-        let file_path_owned = file_path.to_string();
+    let FileDigest { chunk_hashes, file_sha256, encrypted_size, content_type, .. } = {
         let progress_p1 = progress.clone();
 
-        tokio::task::block_in_place(|| -> Result<(Vec<String>, String, u64), String> {
+        tokio::task::block_in_place(|| -> Result<FileDigest, TransferError> {
             use std::io::Read;
-            let mut file = std::fs::File::open(&file_path_owned)
-                .map_err(|e| format!("Cannot open file: {}", e))?;
+            let mut file = source.open_blocking()?;
 
             let mut full_hasher = Sha256::new();
             let mut chunk_hashes = Vec::with_capacity(chunk_count);
             let mut encrypted_size: u64 = 0;
+            let mut content_type = None;
             let mut buf = vec![0u8; CHUNK_SIZE];
 
             for idx in 0..chunk_count {
+                progress_p1.block_while_paused();
                 if progress_p1.is_cancelled() {
-                    return Err("Cancelled".to_string());
+                    return Err(TransferError::cancelled());
                 }
 
                 let remaining = file_size - idx as u64 * CHUNK_SIZE as u64;
                 let to_read = (remaining as usize).min(CHUNK_SIZE);
 
                 file.read_exact(&mut buf[..to_read])
-                    .map_err(|e| format!("Read error at chunk {}: {}", idx, e))?;
+                    .map_err(|e| ErrorCode::FileIo.err(format!("Read error at chunk {}: {}", idx, e)))?;
+                if idx == 0 {
+                    content_type = sniff::content_type(&buf[..to_read]);
+                }
 
                 let nonce = derive_chunk_nonce(&key, idx as u64);
-                let encrypted = encrypt_chunk_with_nonce(&key, &buf[..to_read], nonce)?;
+                let encrypted = encrypt_chunk_with_nonce(&key, &buf[..to_read], nonce)
+                    .map_err(|e| ErrorCode::Protocol.err(e))?;
 
                 let mut chunk_hasher = Sha256::new();
                 chunk_hasher.update(&encrypted);
@@ -130,13 +356,13 @@ pub async fn upload_file(
             }
 
             let file_sha256 = hex::encode(full_hasher.finalize());
-            Ok((chunk_hashes, file_sha256, encrypted_size))
+            Ok(FileDigest { chunk_hashes, file_sha256, encrypted_size, content_type, sparse_chunks: Vec::new() })
         })?
     };
 
     if progress.is_cancelled() {
         progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
-        return Err("Cancelled".into());
+        return Err(TransferError::cancelled());
     }
 
     // Store hashes so Dart can read them via FFI and send the offer to the receiver.
@@ -156,129 +382,429 @@ pub async fn upload_file(
 
     let encrypted_chunk_size = CHUNK_SIZE + 28; // 12-byte nonce + 16-byte GCM tag
 
-    let create_body = serde_json::json!({
-        "id": transfer_id,
-        "file_size": encrypted_size,
-        "chunk_size": encrypted_chunk_size,
-        "file_sha256": file_sha256,
-        "chunk_hashes": chunk_hashes,
-    });
+    // An earlier attempt may have created the transfer and uploaded part of it.
+    // The status check doubles as a latency probe for batching.
+    let probe_started = Instant::now();
+    let received = existing_chunks(
+        &async_client, server_url, transfer_id, &progress.token(jwt_token), &file_sha256, encrypted_size,
+    )
+    .await?;
+    let batch_chunks = if probe_started.elapsed() >= BATCH_RTT_THRESHOLD { MAX_BATCH_CHUNKS } else { 1 };
+
+    if let Some(ref received) = received {
+        let already_done: u64 = received
+            .iter()
+            .filter(|&&idx| idx < chunk_count)
+            .map(|&idx| encrypted_chunk_len(idx, chunk_count, encrypted_size))
+            .sum();
+        progress.bytes_done.store(already_done, Ordering::Relaxed);
+    } else {
+        let check = check_transfer(
+            &async_client,
+            server_url,
+            &progress.token(jwt_token),
+            encrypted_size,
+            Some(&file_sha256),
+        )
+        .await?;
+
+        let create_body = serde_json::json!({
+            "id": transfer_id,
+            "file_size": encrypted_size,
+            "chunk_size": encrypted_chunk_size,
+            "file_sha256": file_sha256,
+            "chunk_hashes": chunk_hashes,
+            "content_type": content_type.filter(|_| check.restricts_types),
+        });
 
-    let resp = async_client
-        .post(format!("{}/transfers", server_url))
-        .header("Authorization", format!("Bearer {}", jwt_token))
-        .json(&create_body)
-        .send()
-        .await
-        .map_err(|e| format!("Create transfer failed: {}", e))?;
+        let resp = async_client
+            .post(format!("{}/transfers", server_url))
+            .header("Authorization", format!("Bearer {}", progress.token(jwt_token)))
+            .json(&create_body)
+            .send()
+            .await
+            .map_err(|e| ErrorCode::Network.err(format!("Create transfer failed: {}", e)))?;
 
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let body = resp.text().await.unwrap_or_default();
-        return Err(format!("Create transfer failed ({}): {}", status, body));
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            return Err(ErrorCode::from_status(status)
+                .err(format!("Create transfer failed ({}): {}", status, body)));
+        }
+
+        // The server already stores these exact bytes: nothing to upload.
+        // What the create says wins, since the stored copy may have gone
+        // since the check.
+        let created: serde_json::Value = resp.json().await.unwrap_or_default();
+        if created["already_present"].as_bool().unwrap_or(check.already_present) {
+            progress.bytes_done.store(encrypted_size, Ordering::Relaxed);
+            progress.state.store(STATE_COMPLETE, Ordering::Relaxed);
+            return Ok(());
+        }
     }
+    let received = received.unwrap_or_default();
 
     // ── Pass 2: sequential read → parallel encrypt + upload ──────────────────
-    // Read chunks one at a time (forward, no seeks) and dispatch each to a
-    // tokio task bounded by a semaphore. The task encrypts on a blocking thread
+    // Read chunks one at a time (forward, no seeks) and dispatch them to tokio
+    // tasks bounded by a semaphore. Each task encrypts on a blocking thread
     // then uploads asynchronously. While the network is busy sending N chunks,
-    // the disk is reading the next one — they overlap naturally.
+    // the disk is reading the next one — they overlap naturally. On a slow
+    // link a task carries up to `batch_chunks` consecutive chunks in one PUT.
     let semaphore = Arc::new(Semaphore::new(UPLOAD_CONCURRENCY));
     let mut handles = Vec::with_capacity(chunk_count);
+    let mut group: Vec<(usize, Vec<u8>)> = Vec::with_capacity(batch_chunks);
+    let mut permits: Vec<OwnedSemaphorePermit> = Vec::with_capacity(batch_chunks);
 
-    let mut file = tokio::fs::File::open(file_path)
-        .await
-        .map_err(|e| format!("Cannot open file for upload: {}", e))?;
+    let mut file = source.open().await?;
 
     for idx in 0..chunk_count {
+        progress.wait_while_paused().await;
         if progress.is_cancelled() {
             progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
-            return Err("Cancelled".into());
+            return Err(TransferError::cancelled());
         }
 
         let remaining = file_size - idx as u64 * CHUNK_SIZE as u64;
         let to_read = (remaining as usize).min(CHUNK_SIZE);
-        let mut buf = vec![0u8; to_read];
-
-        // Sequential read — single file handle, forward-only, no seek.
-        file.read_exact(&mut buf)
-            .await
-            .map_err(|e| format!("Read error at chunk {}: {}", idx, e))?;
 
-        // Acquire semaphore slot before spawning (backpressure: don't read
-        // ahead unboundedly if uploads can't keep up).
-        let permit = semaphore.clone().acquire_owned().await.unwrap();
-
-        let key_copy = key; // [u8; 32] is Copy
-        let server_url_clone = server_url.to_string();
-        let transfer_id_clone = transfer_id.to_string();
-        let jwt_clone = jwt_token.to_string();
-        let progress_clone = progress.clone();
-        let client_clone = async_client.clone();
-
-        let handle = tokio::spawn(async move {
-            let _permit = permit; // released when task completes
-
-            // Encrypt on a blocking thread — don't stall the async executor.
-            let nonce = derive_chunk_nonce(&key_copy, idx as u64);
-            let encrypted = tokio::task::spawn_blocking(move || {
-                encrypt_chunk_with_nonce(&key_copy, &buf, nonce)
-            })
-            .await
-            .map_err(|e| format!("Encryption task panicked at chunk {}: {}", idx, e))??;
-
-            let enc_len = encrypted.len() as u64;
-
-            let url = format!(
-                "{}/transfers/{}/chunks/{}",
-                server_url_clone, transfer_id_clone, idx
-            );
-
-            let resp = client_clone
-                .put(&url)
-                .header("Authorization", format!("Bearer {}", jwt_clone))
-                .header("Content-Type", "application/octet-stream")
-                .body(encrypted)
-                .send()
+        if received.contains(&idx) {
+            // Already on the server: step over it without encrypting.
+            tokio::io::copy(&mut (&mut file).take(to_read as u64), &mut tokio::io::sink())
                 .await
-                .map_err(|e| format!("Chunk {} upload failed: {}", idx, e))?;
+                .map_err(|e| ErrorCode::FileIo.err(format!("Read error at chunk {}: {}", idx, e)))?;
+        } else {
+            let mut buf = vec![0u8; to_read];
 
-            if !resp.status().is_success() {
-                let status = resp.status();
-                let body = resp.text().await.unwrap_or_default();
-                return Err(format!(
-                    "Chunk {} upload failed ({}): {}",
-                    idx, status, body
-                ));
-            }
+            // Sequential read — single file handle, forward-only, no seek.
+            file.read_exact(&mut buf)
+                .await
+                .map_err(|e| ErrorCode::FileIo.err(format!("Read error at chunk {}: {}", idx, e)))?;
 
-            progress_clone.bytes_done.fetch_add(enc_len, Ordering::Relaxed);
-            Ok::<(), String>(())
-        });
+            // Acquire a semaphore slot per chunk (backpressure: don't read
+            // ahead unboundedly if uploads can't keep up).
+            permits.push(semaphore.clone().acquire_owned().await.unwrap());
+            group.push((idx, buf));
+        }
 
-        handles.push(handle);
+        // Send the group once full, at a gap (batches are consecutive), or at the end.
+        let gap = group.last().is_some_and(|&(last, _)| last != idx);
+        if group.len() == batch_chunks || ((gap || idx + 1 == chunk_count) && !group.is_empty()) {
+            handles.push(spawn_chunk_upload(
+                std::mem::take(&mut group),
+                std::mem::take(&mut permits),
+                key,
+                server_url,
+                transfer_id,
+                jwt_token,
+                &async_client,
+                &progress,
+            ));
+        }
     }
 
     // Wait for all upload tasks to finish.
     for handle in handles {
         if progress.is_cancelled() {
             progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
-            return Err("Cancelled".into());
+            return Err(TransferError::cancelled());
         }
         handle
             .await
-            .map_err(|e| format!("Upload task panicked: {}", e))??;
+            .map_err(|e| ErrorCode::Protocol.err(format!("Upload task panicked: {}", e)))??;
     }
 
     if progress.is_cancelled() {
         progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
-        return Err("Cancelled".into());
+        return Err(TransferError::cancelled());
     }
 
     progress.state.store(STATE_COMPLETE, Ordering::Relaxed);
     Ok(())
 }
 
+/// What `POST /transfers/check` said about an upload it didn't refuse.
+#[derive(Debug, Default)]
+pub(crate) struct TransferCheck {
+    /// The server already stores these bytes.
+    pub already_present: bool,
+    /// The server has a file type policy, so the upload should name its
+    /// content type; otherwise it's left out.
+    pub restricts_types: bool,
+    /// Sealed (uncompressed) chunk sizes a fast upload may use, if the
+    /// server says.
+    pub chunk_sizes: Option<std::ops::RangeInclusive<u64>>,
+}
+
+/// Ask the server whether it would take `encrypted_size` more bytes from us
+/// (`POST /transfers/check`), and whether it already stores the bytes
+/// hashing to `file_sha256` if given. Only a definite no is an error: a server
+/// without the endpoint, or one that can't be reached yet, is left for the
+/// create to sort out and reports nothing.
+pub(crate) async fn check_transfer(
+    client: &Client,
+    server_url: &str,
+    jwt_token: &str,
+    encrypted_size: u64,
+    file_sha256: Option<&str>,
+) -> Result<TransferCheck, TransferError> {
+    let resp = client
+        .post(format!("{}/transfers/check", server_url))
+        .header("Authorization", format!("Bearer {}", jwt_token))
+        .json(&serde_json::json!({ "file_size": encrypted_size, "file_sha256": file_sha256 }))
+        .send()
+        .await;
+    let Ok(resp) = resp else {
+        return Ok(TransferCheck::default());
+    };
+    match resp.status().as_u16() {
+        401 | 403 => return Err(ErrorCode::Auth.err(format!("Capacity check failed ({})", resp.status()))),
+        200 => {}
+        _ => return Ok(TransferCheck::default()),
+    }
+    let checked: serde_json::Value = resp.json().await.unwrap_or_default();
+    if checked["accepted"].as_bool() == Some(false) {
+        let reason = checked["reason"].as_str().unwrap_or("rejected");
+        return Err(ErrorCode::Protocol.err(format!("Server won't take a {} byte upload: {}", encrypted_size, reason)));
+    }
+    Ok(TransferCheck {
+        already_present: checked["already_present"].as_bool() == Some(true),
+        restricts_types: checked["restricts_types"].as_bool() == Some(true),
+        chunk_sizes: checked["chunk_sizes"]["min"]
+            .as_u64()
+            .zip(checked["chunk_sizes"]["max"].as_u64())
+            .map(|(min, max)| min..=max),
+    })
+}
+
+/// Tell the server how many recipients a fan-out offer reached
+/// (`PUT /transfers/{id}/recipients`), so it keeps the file until each has
+/// confirmed. Goes for HTTP and fast uploads alike, once the gateway has
+/// reported who got the offer.
+pub async fn set_recipients(
+    client: &Client,
+    server_url: &str,
+    transfer_id: &str,
+    jwt_token: &str,
+    recipients: u32,
+) -> Result<(), TransferError> {
+    let resp = client
+        .put(format!("{}/transfers/{}/recipients", server_url, transfer_id))
+        .header("Authorization", format!("Bearer {}", jwt_token))
+        .json(&serde_json::json!({ "recipients": recipients }))
+        .send()
+        .await
+        .map_err(|e| ErrorCode::Network.err(format!("Setting recipients failed: {}", e)))?;
+    if !resp.status().is_success() {
+        let status = resp.status();
+        return Err(ErrorCode::from_status(status).err(format!("Setting recipients failed ({})", status)));
+    }
+    Ok(())
+}
+
+/// Encrypt `group` (consecutive chunks, in order) on a blocking thread and
+/// upload it: a single chunk to its own URL, several as one batch PUT. The
+/// permits are held until the upload finishes.
+#[allow(clippy::too_many_arguments)]
+fn spawn_chunk_upload(
+    group: Vec<(usize, Vec<u8>)>,
+    permits: Vec<OwnedSemaphorePermit>,
+    key: [u8; 32],
+    server_url: &str,
+    transfer_id: &str,
+    jwt_token: &str,
+    client: &Client,
+    progress: &Arc<UploadProgress>,
+) -> tokio::task::JoinHandle<Result<(), TransferError>> {
+    let server_url = server_url.to_string();
+    let transfer_id = transfer_id.to_string();
+    let jwt_token = jwt_token.to_string();
+    let client = client.clone();
+    let progress = progress.clone();
+
+    tokio::spawn(async move {
+        let _permits = permits; // released when task completes
+        let first = group[0].0;
+
+        // Encrypt on a blocking thread — don't stall the async executor.
+        let encrypted = tokio::task::spawn_blocking(move || {
+            group
+                .into_iter()
+                .map(|(idx, buf)| {
+                    let nonce = derive_chunk_nonce(&key, idx as u64);
+                    encrypt_chunk_with_nonce(&key, &buf, nonce)
+                        .map(|enc| (idx, enc))
+                        .map_err(|e| ErrorCode::Protocol.err(e))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        .map_err(|e| ErrorCode::Protocol.err(format!("Encryption task panicked at chunk {}: {}", first, e)))??;
+
+        let enc_len: u64 = encrypted.iter().map(|(_, enc)| enc.len() as u64).sum();
+        let (url, body) = if encrypted.len() == 1 {
+            let (idx, enc) = encrypted.into_iter().next().unwrap();
+            (format!("{}/transfers/{}/chunks/{}", server_url, transfer_id, idx), Bytes::from(enc))
+        } else {
+            (format!("{}/transfers/{}/chunks:batch", server_url, transfer_id), encode_chunk_batch(&encrypted))
+        };
+
+        put_chunk(&client, &url, &jwt_token, first, body, &progress, &CHUNK_BACKOFF).await?;
+
+        progress.add_bytes(enc_len);
+        Ok(())
+    })
+}
+
+/// Body for `PUT /transfers/{id}/chunks:batch`: each chunk as
+/// `[u32 LE index][u32 LE length][data]`.
+fn encode_chunk_batch(chunks: &[(usize, Vec<u8>)]) -> Bytes {
+    let len = chunks.iter().map(|(_, enc)| 8 + enc.len()).sum();
+    let mut body = Vec::with_capacity(len);
+    for (idx, enc) in chunks {
+        body.extend_from_slice(&(*idx as u32).to_le_bytes());
+        body.extend_from_slice(&(enc.len() as u32).to_le_bytes());
+        body.extend_from_slice(enc);
+    }
+    Bytes::from(body)
+}
+
+/// PUT one encrypted chunk (or a batch starting at chunk `idx`), retrying
+/// connection errors and 5xx responses with `backoff`. A 401 is retried once
+/// a refreshed token arrives (see `UploadProgress::refreshed_token`); any
+/// other 4xx is final. Safe to repeat: the server answers `200` for chunks it
+/// already holds.
+pub(crate) async fn put_chunk(
+    client: &Client,
+    url: &str,
+    jwt_token: &str,
+    idx: usize,
+    body: Bytes,
+    progress: &UploadProgress,
+    backoff: &Backoff,
+) -> Result<(), TransferError> {
+    let mut retry = 0;
+    loop {
+        let token = progress.token(jwt_token);
+        let err = match client
+            .put(url)
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", "application/octet-stream")
+            .body(body.clone())
+            .send()
+            .await
+        {
+            Ok(resp) if resp.status().is_success() => {
+                let _ = progress.retrying_chunk.compare_exchange(
+                    idx as i64, -1, Ordering::Relaxed, Ordering::Relaxed,
+                );
+                return Ok(());
+            }
+            Ok(resp) => {
+                let status = resp.status();
+                let body = resp.text().await.unwrap_or_default();
+                let err = ErrorCode::from_status(status)
+                    .err(format!("Chunk {} upload failed ({}): {}", idx, status, body));
+                // The token expired mid-upload: go again once a fresh one is in.
+                if status == reqwest::StatusCode::UNAUTHORIZED
+                    && progress.wait_for_refresh(jwt_token, &token, TOKEN_REFRESH_WAIT).await
+                {
+                    continue;
+                }
+                if !status.is_server_error() {
+                    return Err(err);
+                }
+                err
+            }
+            Err(e) => ErrorCode::Network.err(format!("Chunk {} upload failed: {}", idx, e)),
+        };
+
+        if retry == backoff.max_retries || progress.is_cancelled() {
+            return Err(err);
+        }
+        progress.retrying_chunk.store(idx as i64, Ordering::Relaxed);
+        progress.retries.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(backoff.delay(retry)).await;
+        retry += 1;
+        if progress.is_cancelled() {
+            return Err(TransferError::cancelled());
+        }
+    }
+}
+
+/// Chunk indices the server already holds for `transfer_id`, or `None` if the
+/// transfer hasn't been created yet. Fails if the existing transfer describes
+/// a different file, so a reused id can't splice two files together.
+async fn existing_chunks(
+    client: &Client,
+    server_url: &str,
+    transfer_id: &str,
+    jwt_token: &str,
+    file_sha256: &str,
+    encrypted_size: u64,
+) -> Result<Option<HashSet<usize>>, TransferError> {
+    let resp = client
+        .get(format!("{}/transfers/{}", server_url, transfer_id))
+        .header("Authorization", format!("Bearer {}", jwt_token))
+        .send()
+        .await
+        .map_err(|e| ErrorCode::Network.err(format!("Transfer status query failed: {}", e)))?;
+
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !resp.status().is_success() {
+        let status = resp.status();
+        return Err(ErrorCode::from_status(status).err(format!("Transfer status query failed ({})", status)));
+    }
+
+    let status: serde_json::Value = resp
+        .json()
+        .await
+        .map_err(|e| ErrorCode::Protocol.err(format!("Transfer status parse failed: {}", e)))?;
+    // Older servers don't report file_sha256; the size check still applies.
+    let other_hash = status["file_sha256"].as_str().is_some_and(|h| h != file_sha256);
+    if other_hash || status["file_size"].as_u64() != Some(encrypted_size) {
+        return Err(ErrorCode::Protocol.err(format!(
+            "Transfer {} already exists for a different file",
+            transfer_id
+        )));
+    }
+
+    let resp = client
+        .get(format!("{}/transfers/{}/chunks", server_url, transfer_id))
+        .header("Authorization", format!("Bearer {}", jwt_token))
+        .send()
+        .await
+        .map_err(|e| ErrorCode::Network.err(format!("Chunk status query failed: {}", e)))?;
+
+    if !resp.status().is_success() {
+        let status = resp.status();
+        return Err(ErrorCode::from_status(status).err(format!("Chunk status query failed ({})", status)));
+    }
+
+    let chunks: serde_json::Value = resp
+        .json()
+        .await
+        .map_err(|e| ErrorCode::Protocol.err(format!("Chunk status parse failed: {}", e)))?;
+    let received = chunks["received_chunks"]
+        .as_array()
+        .map(|list| list.iter().filter_map(|v| v.as_u64()).map(|v| v as usize).collect())
+        .unwrap_or_default();
+
+    Ok(Some(received))
+}
+
+/// Encrypted length of chunk `idx`: full chunks are CHUNK_SIZE + 28, the last
+/// holds the remainder.
+fn encrypted_chunk_len(idx: usize, chunk_count: usize, encrypted_size: u64) -> u64 {
+    let full = (CHUNK_SIZE + 28) as u64;
+    if idx + 1 == chunk_count {
+        encrypted_size - full * (chunk_count as u64 - 1)
+    } else {
+        full
+    }
+}
+
 /// Resume an upload from a specific chunk. The transfer must already exist on the server.
 ///
 /// Skips pass 1 (hashing) entirely — the caller provides pre-computed hashes.
@@ -295,24 +821,20 @@ pub async fn resume_upload(
     chunk_hashes_json: &str,
     start_chunk: u32,
     progress: Arc<UploadProgress>,
-) -> Result<(), String> {
+) -> Result<(), TransferError> {
     let key = derive_key(master_key, salt);
     let async_client = Client::new();
 
     let path = Path::new(file_path);
     let file_size = tokio::fs::metadata(path)
         .await
-        .map_err(|e| format!("Cannot read file: {}", e))?
+        .map_err(|e| ErrorCode::FileIo.err(format!("Cannot read file: {}", e)))?
         .len();
 
-    let chunk_count = if file_size == 0 {
-        1
-    } else {
-        (file_size as usize + CHUNK_SIZE - 1) / CHUNK_SIZE
-    };
+    let chunk_count = haven_fast_transfer::chunk_count(file_size, CHUNK_SIZE as u64) as usize;
 
     let chunk_hashes: Vec<String> = serde_json::from_str(chunk_hashes_json)
-        .map_err(|e| format!("Failed to parse chunk_hashes: {}", e))?;
+        .map_err(|e| ErrorCode::Protocol.err(format!("Failed to parse chunk_hashes: {}", e)))?;
 
     // Store hashes so Dart can read them via FFI (same as fresh upload)
     {
@@ -344,7 +866,7 @@ pub async fn resume_upload(
 
     let mut file = tokio::fs::File::open(file_path)
         .await
-        .map_err(|e| format!("Cannot open file for upload: {}", e))?;
+        .map_err(|e| ErrorCode::FileIo.err(format!("Cannot open file for upload: {}", e)))?;
 
     // Seek past already-uploaded chunks
     if start_chunk > 0 {
@@ -352,13 +874,14 @@ pub async fn resume_upload(
         let skip_bytes = start_chunk as u64 * CHUNK_SIZE as u64;
         file.seek(std::io::SeekFrom::Start(skip_bytes))
             .await
-            .map_err(|e| format!("Failed to seek to chunk {}: {}", start_chunk, e))?;
+            .map_err(|e| ErrorCode::FileIo.err(format!("Failed to seek to chunk {}: {}", start_chunk, e)))?;
     }
 
     for idx in (start_chunk as usize)..chunk_count {
+        progress.wait_while_paused().await;
         if progress.is_cancelled() {
             progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
-            return Err("Cancelled".into());
+            return Err(TransferError::cancelled());
         }
 
         let remaining = file_size - idx as u64 * CHUNK_SIZE as u64;
@@ -367,7 +890,7 @@ pub async fn resume_upload(
 
         file.read_exact(&mut buf)
             .await
-            .map_err(|e| format!("Read error at chunk {}: {}", idx, e))?;
+            .map_err(|e| ErrorCode::FileIo.err(format!("Read error at chunk {}: {}", idx, e)))?;
 
         let permit = semaphore.clone().acquire_owned().await.unwrap();
 
@@ -384,9 +907,10 @@ pub async fn resume_upload(
             let nonce = derive_chunk_nonce(&key_copy, idx as u64);
             let encrypted = tokio::task::spawn_blocking(move || {
                 encrypt_chunk_with_nonce(&key_copy, &buf, nonce)
+                    .map_err(|e| ErrorCode::Protocol.err(e))
             })
             .await
-            .map_err(|e| format!("Encryption task panicked at chunk {}: {}", idx, e))??;
+            .map_err(|e| ErrorCode::Protocol.err(format!("Encryption task panicked at chunk {}: {}", idx, e)))??;
 
             let enc_len = encrypted.len() as u64;
 
@@ -395,26 +919,11 @@ pub async fn resume_upload(
                 server_url_clone, transfer_id_clone, idx
             );
 
-            let resp = client_clone
-                .put(&url)
-                .header("Authorization", format!("Bearer {}", jwt_clone))
-                .header("Content-Type", "application/octet-stream")
-                .body(encrypted)
-                .send()
-                .await
-                .map_err(|e| format!("Chunk {} upload failed: {}", idx, e))?;
+            put_chunk(&client_clone, &url, &jwt_clone, idx, Bytes::from(encrypted), &progress_clone, &CHUNK_BACKOFF)
+                .await?;
 
-            if !resp.status().is_success() {
-                let status = resp.status();
-                let body = resp.text().await.unwrap_or_default();
-                return Err(format!(
-                    "Chunk {} upload failed ({}): {}",
-                    idx, status, body
-                ));
-            }
-
-            progress_clone.bytes_done.fetch_add(enc_len, Ordering::Relaxed);
-            Ok::<(), String>(())
+            progress_clone.add_bytes(enc_len);
+            Ok::<(), TransferError>(())
         });
 
         handles.push(handle);
@@ -423,18 +932,227 @@ pub async fn resume_upload(
     for handle in handles {
         if progress.is_cancelled() {
             progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
-            return Err("Cancelled".into());
+            return Err(TransferError::cancelled());
         }
         handle
             .await
-            .map_err(|e| format!("Upload task panicked: {}", e))??;
+            .map_err(|e| ErrorCode::Protocol.err(format!("Upload task panicked: {}", e)))??;
     }
 
     if progress.is_cancelled() {
         progress.state.store(STATE_CANCELLED, Ordering::Relaxed);
-        return Err("Cancelled".into());
+        return Err(TransferError::cancelled());
     }
 
     progress.state.store(STATE_COMPLETE, Ordering::Relaxed);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    const FAST: Backoff = Backoff { max_retries: 3, base_delay: Duration::from_millis(5) };
+
+    /// Read one request off `stream`: its lowercased head and its body.
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> (String, Vec<u8>) {
+        let mut raw = Vec::new();
+        let mut buf = [0u8; 4096];
+        let body_start = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            raw.extend_from_slice(&buf[..n]);
+            if let Some(pos) = raw.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&raw[..body_start]).to_ascii_lowercase();
+        let len: usize = head
+            .lines()
+            .find_map(|l| l.strip_prefix("content-length:"))
+            .map_or(0, |v| v.trim().parse().unwrap());
+        while raw.len() < body_start + len {
+            let n = stream.read(&mut buf).await.unwrap();
+            raw.extend_from_slice(&buf[..n]);
+        }
+        (head, raw[body_start..].to_vec())
+    }
+
+    async fn reply(stream: &mut tokio::net::TcpStream, status: u16) {
+        let reply = format!("HTTP/1.1 {} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+        stream.write_all(reply.as_bytes()).await.unwrap();
+    }
+
+    async fn reply_json(stream: &mut tokio::net::TcpStream, body: &str) {
+        let reply = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(reply.as_bytes()).await.unwrap();
+    }
+
+    /// Answer each request on `listener` with the next status in `statuses`,
+    /// one connection per request. Returns the bodies received.
+    async fn mock_server(listener: TcpListener, statuses: Vec<u16>) -> Vec<Vec<u8>> {
+        let mut bodies = Vec::new();
+        for status in statuses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            bodies.push(read_request(&mut stream).await.1);
+            reply(&mut stream, status).await;
+        }
+        bodies
+    }
+
+    #[tokio::test]
+    async fn chunk_put_retries_server_errors_then_succeeds() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/transfers/t/chunks/7", listener.local_addr().unwrap());
+        let server = tokio::spawn(mock_server(listener, vec![500, 503, 200]));
+
+        let progress = UploadProgress::new();
+        let body = Bytes::from_static(b"encrypted chunk");
+        put_chunk(&Client::new(), &url, "jwt", 7, body.clone(), &progress, &FAST).await.unwrap();
+
+        let bodies = server.await.unwrap();
+        assert_eq!(bodies, vec![body.to_vec(); 3]);
+        assert_eq!(progress.retries.load(Ordering::Relaxed), 2);
+        assert_eq!(progress.retrying_chunk.load(Ordering::Relaxed), -1);
+    }
+
+    #[tokio::test]
+    async fn transfer_check_reports_stored_bytes_and_type_policy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut bodies = Vec::new();
+            for answer in [r#"{"accepted":true,"already_present":true,"restricts_types":true,"chunk_sizes":{"default":28,"min":1,"max":2}}"#, r#"{"accepted":false,"reason":"quota_exceeded"}"#] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                bodies.push(read_request(&mut stream).await.1);
+                reply_json(&mut stream, answer).await;
+            }
+            bodies
+        });
+
+        let client = Client::new();
+        let check = check_transfer(&client, &url, "jwt", 1024, Some("ab")).await.unwrap();
+        assert!(check.already_present && check.restricts_types);
+        assert_eq!(check.chunk_sizes, Some(1..=2));
+        let err = check_transfer(&client, &url, "jwt", 1024, None).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::Protocol);
+
+        let sent: Vec<serde_json::Value> =
+            server.await.unwrap().iter().map(|body| serde_json::from_slice(body).unwrap()).collect();
+        assert_eq!(sent[0], serde_json::json!({ "file_size": 1024, "file_sha256": "ab" }));
+        assert_eq!(sent[1], serde_json::json!({ "file_size": 1024, "file_sha256": null }));
+    }
+
+    #[tokio::test]
+    async fn recipients_are_set_on_the_transfer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(mock_server(listener, vec![204, 403]));
+
+        let client = Client::new();
+        set_recipients(&client, &url, "t", "jwt", 3).await.unwrap();
+        let err = set_recipients(&client, &url, "t", "jwt", 3).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::Auth);
+
+        let bodies = server.await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&bodies[0]).unwrap(), serde_json::json!({ "recipients": 3 }));
+    }
+
+    // Multi-threaded: pass 1 hashes in `block_in_place`.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn upload_resumes_past_chunks_the_server_has() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        // Two chunks, the first of which an earlier attempt got through.
+        let data = vec![7u8; CHUNK_SIZE + 100];
+        let encrypted_size = (CHUNK_SIZE + 28 + 100 + 28) as u64;
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            let status = serde_json::json!({ "file_size": encrypted_size }).to_string();
+            for answer in [Some(status.as_str()), Some(r#"{"received_chunks":[0]}"#), None] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (head, body) = read_request(&mut stream).await;
+                match answer {
+                    Some(json) => reply_json(&mut stream, json).await,
+                    None => reply(&mut stream, 200).await,
+                }
+                requests.push((head.lines().next().unwrap().to_string(), body.len()));
+            }
+            // Anything more (a create, or chunk 0 again) finds no server.
+            requests
+        });
+
+        let progress = Arc::new(UploadProgress::new());
+        upload_file(Source::Bytes(data), &url, "t", "jwt", &[1; 32], &[2; 16], progress.clone()).await.unwrap();
+
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                ("get /transfers/t http/1.1".to_string(), 0),
+                ("get /transfers/t/chunks http/1.1".to_string(), 0),
+                ("put /transfers/t/chunks/1 http/1.1".to_string(), 128),
+            ]
+        );
+        assert_eq!(progress.state.load(Ordering::Relaxed), STATE_COMPLETE);
+        assert_eq!(progress.bytes_done.load(Ordering::Relaxed), encrypted_size);
+    }
+
+    #[test]
+    fn chunk_batch_is_length_prefixed() {
+        let body = encode_chunk_batch(&[(3, b"abc".to_vec()), (4, b"de".to_vec())]);
+        assert_eq!(&body[..], b"\x03\0\0\0\x03\0\0\0abc\x04\0\0\0\x02\0\0\0de");
+    }
+
+    #[tokio::test]
+    async fn chunk_put_gives_up_on_client_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/transfers/t/chunks/0", listener.local_addr().unwrap());
+        let server = tokio::spawn(mock_server(listener, vec![403]));
+
+        let progress = UploadProgress::new();
+        let err = put_chunk(&Client::new(), &url, "jwt", 0, Bytes::new(), &progress, &FAST).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::Auth);
+        assert_eq!(server.await.unwrap().len(), 1);
+        assert_eq!(progress.retries.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn chunk_put_picks_up_refreshed_token_after_expiry() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // The first token is good for one chunk, then expires; only the
+        // refreshed one works after that.
+        let server = tokio::spawn(async move {
+            let mut seen = Vec::new();
+            for valid in ["bearer short-lived", "bearer refreshed", "bearer refreshed"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (head, _) = read_request(&mut stream).await;
+                let auth = head.lines().find_map(|l| l.strip_prefix("authorization: ")).unwrap().to_string();
+                reply(&mut stream, if auth == valid { 200 } else { 401 }).await;
+                seen.push(auth);
+            }
+            seen
+        });
+
+        let progress = Arc::new(UploadProgress::new());
+        let client = Client::new();
+        let url = |idx| format!("http://{}/transfers/t/chunks/{}", addr, idx);
+        put_chunk(&client, &url(0), "short-lived", 0, Bytes::from_static(b"c0"), &progress, &FAST).await.unwrap();
+
+        // The app refreshes while chunk 1 is bouncing off the server.
+        let refresher = progress.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            *refresher.refreshed_token.lock().unwrap() = Some("refreshed".into());
+        });
+        put_chunk(&client, &url(1), "short-lived", 1, Bytes::from_static(b"c1"), &progress, &FAST).await.unwrap();
+
+        assert_eq!(server.await.unwrap(), ["bearer short-lived", "bearer short-lived", "bearer refreshed"]);
+        assert_eq!(progress.retries.load(Ordering::Relaxed), 0);
+    }
+}